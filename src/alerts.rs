@@ -0,0 +1,355 @@
+use crate::backups::{add_backup_listener, BackupEventKind};
+use crate::config::{get_config, set_config, ConfigScope};
+use crate::crash_detection::add_crash_listener;
+use crate::disk_quota::{get_disk_quota, refresh_quota_usage};
+use crate::metrics_history::latest_tps;
+use crate::notifications::{notify, Notification, NotificationEvent, NotificationSeverity};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_ping::get_live_status;
+use crate::server_process::ServerProcess;
+use crate::server_status::ServerStatus;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use sysinfo::Disks;
+
+/// The setting holding the alert rules of a server.
+const CONFIG_KEY: &str = "alert_rules";
+
+/// How often the alert rules of the servers are evaluated.
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often the disk usage is measured, which walks the whole server directory for a quota.
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+static EVALUATOR: Once = Once::new();
+
+lazy_static! {
+    /// The state of every rule of every server, by server and position of the rule.
+    static ref ALERT_STATES: Mutex<HashMap<(u64, usize), AlertState>> = Mutex::new(HashMap::new());
+    /// The last disk usage measured per server, in percent, with when it was measured.
+    static ref DISK_USAGE: Mutex<HashMap<u64, (Instant, Option<f64>)>> = Mutex::new(HashMap::new());
+}
+
+/// What an alert rule watches for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// The server crashed and was not restarted, or its process runs but it has not answered pings.
+    ServerDown { minutes: u64 },
+    /// The tick rate stayed below `threshold`.
+    LowTps { threshold: f64, minutes: u64 },
+    /// The server uses more than `percent` of its disk quota, or of its disk if it has no quota.
+    DiskUsage { percent: u8 },
+    /// A backup of the server failed. Resolved by the next backup that completes.
+    BackupFailed,
+    /// The server crashed. Resolved once it is online again.
+    CrashDetected,
+}
+
+impl AlertCondition {
+    /// How long the condition has to hold before the alert fires.
+    fn duration(&self) -> Duration {
+        match self {
+            AlertCondition::ServerDown { minutes } | AlertCondition::LowTps { minutes, .. } => {
+                Duration::from_secs(minutes * 60)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            AlertCondition::ServerDown { minutes } => format!("down for {} minutes", minutes),
+            AlertCondition::LowTps { threshold, minutes } => {
+                format!("below {} TPS for {} minutes", threshold, minutes)
+            }
+            AlertCondition::DiskUsage { percent } => format!("using over {}% of its disk space", percent),
+            AlertCondition::BackupFailed => "failing to back up".to_string(),
+            AlertCondition::CrashDetected => "crashed".to_string(),
+        }
+    }
+}
+
+/// A condition that notifies when it starts and stops holding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub condition: AlertCondition,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// How long after a notification the rule notifies again, so a flapping condition or a crash
+    /// loop does not flood the targets.
+    #[serde(default = "default_cooldown")]
+    pub cooldown_minutes: u64,
+    #[serde(default = "default_severity")]
+    pub severity: NotificationSeverity,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_cooldown() -> u64 {
+    30
+}
+
+fn default_severity() -> NotificationSeverity {
+    NotificationSeverity::Warning
+}
+
+impl AlertRule {
+    fn new(condition: AlertCondition, severity: NotificationSeverity) -> Self {
+        AlertRule {
+            condition,
+            enabled: true,
+            cooldown_minutes: default_cooldown(),
+            severity,
+        }
+    }
+}
+
+/// The rules of a server that has none set.
+pub fn default_alert_rules() -> Vec<AlertRule> {
+    vec![
+        AlertRule::new(AlertCondition::ServerDown { minutes: 2 }, NotificationSeverity::Critical),
+        AlertRule::new(
+            AlertCondition::LowTps {
+                threshold: 15.0,
+                minutes: 5,
+            },
+            NotificationSeverity::Warning,
+        ),
+        AlertRule::new(AlertCondition::DiskUsage { percent: 90 }, NotificationSeverity::Warning),
+        AlertRule::new(AlertCondition::BackupFailed, NotificationSeverity::Critical),
+        AlertRule::new(AlertCondition::CrashDetected, NotificationSeverity::Critical),
+    ]
+}
+
+#[derive(Debug, Default)]
+struct AlertState {
+    /// Since when the condition holds without interruption.
+    matching_since: Option<Instant>,
+    /// Whether the rule fired and was not resolved yet.
+    firing: bool,
+    /// When the rule last sent a notification.
+    notified_at: Option<Instant>,
+}
+
+/// Reads the alert rules of a server, the default rules if none were set.
+pub fn get_alert_rules(server_id: u64) -> Result<Vec<AlertRule>, Box<dyn Error>> {
+    Ok(get_config(ConfigScope::Server(server_id), CONFIG_KEY)?.unwrap_or_else(default_alert_rules))
+}
+
+/// Replaces the alert rules of a server. Alerts firing under the old rules are dropped without a
+/// resolution notice.
+///
+/// # Errors
+/// Returns an error if the acting user may not control the server, for rules that can never match,
+/// or if the rules cannot be stored.
+pub fn set_alert_rules(server_id: u64, rules: &[AlertRule]) -> Result<(), Box<dyn Error>> {
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::ControlServer)?;
+    for rule in rules {
+        if let AlertCondition::DiskUsage { percent } = rule.condition {
+            if percent == 0 || percent > 100 {
+                return Err(format!("A disk usage of {}% cannot be alerted on", percent).into());
+            }
+        }
+    }
+    set_config(ConfigScope::Server(server_id), CONFIG_KEY, rules)?;
+    if let Ok(mut states) = ALERT_STATES.lock() {
+        states.retain(|(id, _), _| *id != server_id);
+    }
+    info!("Set {} alert rules for server {}", rules.len(), server_id);
+    Ok(())
+}
+
+/// Starts evaluating the alert rules of every server. Calling it again has no effect.
+///
+/// Rules on crashes and backups fire as soon as the event happens, the others are checked every
+/// 30 seconds.
+pub fn start_alert_evaluation() {
+    EVALUATOR.call_once(|| {
+        add_crash_listener(|report| {
+            trigger(report.server_id, |condition| *condition == AlertCondition::CrashDetected, true);
+        });
+        add_backup_listener(|event| match event.kind {
+            BackupEventKind::Failed => {
+                trigger(event.server_id, |condition| *condition == AlertCondition::BackupFailed, true)
+            }
+            BackupEventKind::Completed => {
+                trigger(event.server_id, |condition| *condition == AlertCondition::BackupFailed, false)
+            }
+            _ => {}
+        });
+        thread::spawn(|| loop {
+            match Server::<u64>::get_list_of_servers() {
+                Ok(servers) => servers.iter().for_each(evaluate_server),
+                Err(e) => error!("Failed to list the servers to evaluate alerts for: {}", e),
+            }
+            thread::sleep(EVALUATION_INTERVAL);
+        });
+        info!("Started evaluating alert rules");
+    });
+}
+
+/// Sets the state of the event driven rules of a server matching `selects`.
+fn trigger(server_id: u64, selects: impl Fn(&AlertCondition) -> bool, matching: bool) {
+    let rules = match get_alert_rules(server_id) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Failed to read the alert rules of server {}: {}", server_id, e);
+            return;
+        }
+    };
+    let name = Server::<u64>::get_server(server_id)
+        .map(|server| server.name)
+        .unwrap_or_else(|_| format!("#{}", server_id));
+    for (index, rule) in rules.iter().enumerate() {
+        if rule.enabled && selects(&rule.condition) {
+            // Every crash and failed backup is worth a notification, within the cooldown
+            if matching {
+                reset(server_id, index);
+            }
+            update_state(server_id, &name, index, rule, matching);
+        }
+    }
+}
+
+fn reset(server_id: u64, index: usize) {
+    if let Ok(mut states) = ALERT_STATES.lock() {
+        if let Some(state) = states.get_mut(&(server_id, index)) {
+            state.matching_since = None;
+            state.firing = false;
+        }
+    }
+}
+
+/// Checks the polled rules of a server.
+fn evaluate_server(server: &Server<u64>) {
+    let rules = match get_alert_rules(server.id) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Failed to read the alert rules of server {}: {}", server.id, e);
+            return;
+        }
+    };
+    for (index, rule) in rules.iter().enumerate() {
+        if !rule.enabled {
+            continue;
+        }
+        let matching = match &rule.condition {
+            AlertCondition::ServerDown { .. } => is_down(server),
+            AlertCondition::LowTps { threshold, minutes } => {
+                // A measurement from the last two minutes, so a stopped server does not stay slow
+                let tps = latest_tps(server.id, Duration::from_secs(2 * 60)).ok().flatten();
+                *minutes > 0 && tps.is_some_and(|tps| tps < *threshold)
+            }
+            AlertCondition::DiskUsage { percent } => disk_usage(server).is_some_and(|used| used > f64::from(*percent)),
+            // Resolved once the server is back, fired by the crash listener
+            AlertCondition::CrashDetected if server.status == Some(ServerStatus::Online) => false,
+            AlertCondition::CrashDetected | AlertCondition::BackupFailed => continue,
+        };
+        update_state(server.id, &server.name, index, rule, matching);
+    }
+}
+
+/// Whether a server is down: crashed and not restarted, or running without answering pings.
+fn is_down(server: &Server<u64>) -> bool {
+    if server.status == Some(ServerStatus::Crashed) {
+        return true;
+    }
+    if server.status != Some(ServerStatus::Online) || !server.is_server_running() {
+        return false;
+    }
+    get_live_status(server.id).is_some_and(|status| status.ping.is_none())
+}
+
+/// The disk usage of a server in percent, of its quota if it has one and of its disk otherwise.
+/// Measured at most every ten minutes.
+fn disk_usage(server: &Server<u64>) -> Option<f64> {
+    if let Ok(usage) = DISK_USAGE.lock() {
+        if let Some((measured, percent)) = usage.get(&server.id) {
+            if measured.elapsed() < DISK_CHECK_INTERVAL {
+                return *percent;
+            }
+        }
+    }
+    let percent = match get_disk_quota(server.id) {
+        Ok(Some(limit)) if limit > 0 => refresh_quota_usage(server.id, &server.directory)
+            .ok()
+            .map(|usage| usage.used as f64 / limit as f64 * 100.0),
+        _ => filesystem_usage(&server.directory),
+    };
+    if let Ok(mut usage) = DISK_USAGE.lock() {
+        usage.insert(server.id, (Instant::now(), percent));
+    }
+    percent
+}
+
+/// The usage of the disk holding a directory, in percent.
+fn filesystem_usage(directory: &Path) -> Option<f64> {
+    let directory = directory.canonicalize().ok()?;
+    let disks = Disks::new_with_refreshed_list();
+    let disk = disks
+        .iter()
+        .filter(|disk| directory.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())?;
+    if disk.total_space() == 0 {
+        return None;
+    }
+    let used = disk.total_space().saturating_sub(disk.available_space());
+    Some(used as f64 / disk.total_space() as f64 * 100.0)
+}
+
+/// Advances the state of a rule and sends the notifications it calls for.
+fn update_state(server_id: u64, name: &str, index: usize, rule: &AlertRule, matching: bool) {
+    let Ok(mut states) = ALERT_STATES.lock() else {
+        return;
+    };
+    let state = states.entry((server_id, index)).or_default();
+    let now = Instant::now();
+    if !matching {
+        state.matching_since = None;
+        if state.firing {
+            state.firing = false;
+            notify(Notification {
+                event: NotificationEvent::AlertResolved,
+                server_id: Some(server_id),
+                severity: NotificationSeverity::Info,
+                title: format!("Resolved: server {} is no longer {}", name, rule.condition.describe()),
+                message: format!("The server {} recovered from being {}.", name, rule.condition.describe()),
+                at: SystemTime::now(),
+            });
+        }
+        return;
+    }
+
+    let since = *state.matching_since.get_or_insert(now);
+    if state.firing || now.duration_since(since) < rule.condition.duration() {
+        return;
+    }
+    // An alert held back by the cooldown is not firing, so it fires once the cooldown ends and
+    // no one is told it resolved when nobody was told it fired
+    let cooldown = Duration::from_secs(rule.cooldown_minutes * 60);
+    if state.notified_at.is_some_and(|notified| now.duration_since(notified) < cooldown) {
+        return;
+    }
+    state.firing = true;
+    state.notified_at = Some(now);
+    notify(Notification {
+        event: NotificationEvent::AlertFired,
+        server_id: Some(server_id),
+        severity: rule.severity,
+        title: format!("Server {} is {}", name, rule.condition.describe()),
+        message: format!("The alert rule for server {} being {} fired.", name, rule.condition.describe()),
+        at: SystemTime::now(),
+    });
+}
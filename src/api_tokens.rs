@@ -0,0 +1,317 @@
+use crate::audit_log::{act_as, acting_user, ActingUser};
+use crate::permissions::{require_capability, Capability};
+use crate::server::Server;
+use crate::users::{get_user, hash_token, random_token, User};
+use log::info;
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::cell::RefCell;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What every API token starts with, so leaked tokens are easy to find in logs and repositories.
+pub const API_TOKEN_PREFIX: &str = "obs_";
+
+/// The most tokens a user can have at once.
+const MAX_TOKENS_PER_USER: usize = 50;
+
+thread_local! {
+    static ACTING_TOKEN: RefCell<Option<ApiToken>> = const { RefCell::new(None) };
+}
+
+/// A token for scripts to use the panel as a user, limited to some capabilities and servers.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiToken {
+    pub id: u64,
+    pub user_id: u64,
+    pub name: String,
+    /// What the token may be used for. It is never more than the user may do.
+    pub scopes: Vec<Capability>,
+    /// The servers the token may be used on, every server of the user if empty.
+    pub servers: Vec<u64>,
+    /// In seconds since the Unix epoch.
+    pub created_at: u64,
+    /// When the token stops working, `None` if it only stops when it is revoked.
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+}
+
+impl ApiToken {
+    /// Whether the token may be used to do something on a server.
+    pub fn allows(&self, server: &Server<u64>, capability: Capability) -> bool {
+        self.scopes.contains(&capability) && (self.servers.is_empty() || self.servers.contains(&server.id))
+    }
+
+    /// Fails unless both the token and its user may do something on a server.
+    ///
+    /// # Errors
+    /// Returns an error if the token lacks the scope or server, or the user the capability.
+    pub fn require(&self, server: &Server<u64>, capability: Capability) -> Result<(), Box<dyn Error>> {
+        if !self.allows(server, capability) {
+            return Err(self.denied(server, capability));
+        }
+        require_capability(self.user_id, server, capability)
+    }
+
+    fn denied(&self, server: &Server<u64>, capability: Capability) -> Box<dyn Error> {
+        format!("The API token {:?} may not be used for {:?} on the server {:?}", self.name, capability, server.name)
+            .into()
+    }
+}
+
+/// A token to create.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scopes: Vec<Capability>,
+    #[serde(default)]
+    pub servers: Vec<u64>,
+    /// How many days the token works for, forever if `None`.
+    #[serde(default)]
+    pub expires_in_days: Option<u64>,
+}
+
+/// A token that was just created, the only time its secret is known.
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedApiToken {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    /// The value to send in the `Authorization: Bearer` header.
+    pub secret: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the table holding the API tokens.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_api_token_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `api_tokens` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the token
+            user_id INTEGER NOT NULL,                                   -- The user the token acts as
+            name TEXT NOT NULL,                                         -- What the token is for
+            token_hash TEXT NOT NULL UNIQUE,                            -- The SHA-256 of the secret
+            scopes TEXT NOT NULL,                                       -- The capabilities as a JSON array
+            servers TEXT NOT NULL,                                      -- The server ids as a JSON array, empty for all
+            created_at INTEGER NOT NULL,                                -- Unix time of the creation
+            expires_at INTEGER,                                         -- Unix time the token stops working, if ever
+            last_used_at INTEGER                                        -- Unix time of the last request with it
+        );
+        CREATE INDEX IF NOT EXISTS `api_tokens_user` ON `api_tokens` (user_id);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Fails unless the acting user is the user themselves or an administrator. Tokens cannot manage
+/// tokens, or a token could create another one with more scopes than it has.
+fn authorize_token_management(user_id: u64) -> Result<(), Box<dyn Error>> {
+    if ACTING_TOKEN.with(|acting| acting.borrow().is_some()) {
+        return Err("API tokens cannot be managed with an API token".into());
+    }
+    match acting_user() {
+        Some(acting) if acting != user_id && !get_user(acting)?.is_admin => {
+            Err(format!("Only administrators can manage the API tokens of user {}", user_id).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Creates an API token for a user.
+///
+/// # Errors
+/// Returns an error if the user does not exist, the acting user may not manage their tokens, the
+/// token has no name or scopes, the expiry is out of range, or the user has too many tokens.
+pub fn create_api_token(user_id: u64, token: &NewApiToken) -> Result<CreatedApiToken, Box<dyn Error>> {
+    authorize_token_management(user_id)?;
+    let user = get_user(user_id)?;
+    let name = token.name.trim();
+    if name.is_empty() {
+        return Err("An API token needs a name".into());
+    }
+    if token.scopes.is_empty() {
+        return Err("An API token needs at least one scope".into());
+    }
+    if user_tokens(user_id)?.len() >= MAX_TOKENS_PER_USER {
+        return Err(format!("A user can have at most {} API tokens", MAX_TOKENS_PER_USER).into());
+    }
+    let mut scopes = token.scopes.clone();
+    scopes.sort_unstable();
+    scopes.dedup();
+
+    let secret = format!("{}{}", API_TOKEN_PREFIX, random_token(32));
+    let created_at = now();
+    let expires_at = match token.expires_in_days {
+        Some(days) => Some(
+            days.checked_mul(24 * 60 * 60)
+                .and_then(|seconds| created_at.checked_add(seconds))
+                .filter(|at| *at <= i64::MAX as u64)
+                .ok_or_else(|| format!("An API token cannot expire in {} days", days))?,
+        ),
+        None => None,
+    };
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO api_tokens (user_id, name, token_hash, scopes, servers, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+    )?;
+    statement.bind((1, user_id as i64))?;
+    statement.bind((2, name))?;
+    statement.bind((3, hash_token(&secret).as_str()))?;
+    statement.bind((4, serde_json::to_string(&scopes)?.as_str()))?;
+    statement.bind((5, serde_json::to_string(&token.servers)?.as_str()))?;
+    statement.bind((6, created_at as i64))?;
+    statement.bind((7, expires_at.map(|at| at as i64)))?;
+    statement.next()?;
+    let id = last_inserted_id("api_tokens")?;
+    info!("User {:?} created the API token {:?} for {:?}", user.username, name, scopes);
+
+    Ok(CreatedApiToken {
+        token: ApiToken {
+            id,
+            user_id,
+            name: name.to_string(),
+            scopes,
+            servers: token.servers.clone(),
+            created_at,
+            expires_at,
+            last_used_at: None,
+        },
+        secret,
+    })
+}
+
+/// Lists the API tokens of a user, newest first, expired ones included.
+///
+/// # Errors
+/// Returns an error if the acting user may not manage the tokens of the user.
+pub fn list_api_tokens(user_id: u64) -> Result<Vec<ApiToken>, Box<dyn Error>> {
+    authorize_token_management(user_id)?;
+    user_tokens(user_id)
+}
+
+fn user_tokens(user_id: u64) -> Result<Vec<ApiToken>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM api_tokens WHERE user_id = ? ORDER BY id DESC")?;
+    statement.bind((1, user_id as i64))?;
+    let mut tokens = Vec::new();
+    while let State::Row = statement.next()? {
+        tokens.push(read_token(&statement)?);
+    }
+    Ok(tokens)
+}
+
+/// Revokes an API token of a user, it stops working at once.
+///
+/// # Errors
+/// Returns an error if the acting user may not manage the tokens of the user, or the user has no
+/// such token.
+pub fn revoke_api_token(user_id: u64, token_id: u64) -> Result<(), Box<dyn Error>> {
+    authorize_token_management(user_id)?;
+    let token = user_tokens(user_id)?
+        .into_iter()
+        .find(|token| token.id == token_id)
+        .ok_or_else(|| format!("No API token with the id {}", token_id))?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM api_tokens WHERE id = ?")?;
+    statement.bind((1, token_id as i64))?;
+    statement.next()?;
+    info!("Revoked the API token {:?} of user {}", token.name, user_id);
+    Ok(())
+}
+
+/// Revokes every API token of a user, like when the user is deleted.
+pub fn revoke_all_api_tokens(user_id: u64) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM api_tokens WHERE user_id = ?")?;
+    statement.bind((1, user_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Returns the token with a secret and its user, recording that it was used.
+///
+/// # Errors
+/// Returns an error if the secret is unknown or the token expired.
+pub fn authenticate_api_token(secret: &str) -> Result<(ApiToken, User), Box<dyn Error>> {
+    let secret = secret.trim();
+    if !secret.starts_with(API_TOKEN_PREFIX) {
+        return Err("Not an API token".into());
+    }
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM api_tokens WHERE token_hash = ?")?;
+    statement.bind((1, hash_token(secret).as_str()))?;
+    let State::Row = statement.next()? else {
+        return Err("Unknown or revoked API token".into());
+    };
+    let token = read_token(&statement)?;
+    if token.expires_at.is_some_and(|expires_at| expires_at <= now()) {
+        return Err(format!("The API token {:?} expired", token.name).into());
+    }
+
+    let mut statement = conn.prepare("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")?;
+    statement.bind((1, now() as i64))?;
+    statement.bind((2, token.id as i64))?;
+    statement.next()?;
+    let user = get_user(token.user_id)?;
+    Ok((token, user))
+}
+
+/// Finds the secret in the value of an `Authorization` header.
+pub fn api_token_from_header(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    (scheme.eq_ignore_ascii_case("bearer") && token.starts_with(API_TOKEN_PREFIX)).then_some(token)
+}
+
+/// Attributes the actions done on this thread to the user of a token and limits them to its
+/// scopes, until the returned guard is dropped. It is the counterpart of
+/// [`crate::audit_log::act_as`] for requests made with a token.
+pub fn act_as_token(token: &ApiToken) -> ActingToken {
+    let previous = ACTING_TOKEN.with(|acting| acting.replace(Some(token.clone())));
+    ActingToken {
+        previous,
+        _user: act_as(token.user_id),
+    }
+}
+
+/// Attributes the actions on a thread to the user of a token while it lives, see [`act_as_token`].
+#[must_use = "the actions are only limited to the token while the guard lives"]
+pub struct ActingToken {
+    previous: Option<ApiToken>,
+    _user: ActingUser,
+}
+
+impl Drop for ActingToken {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        ACTING_TOKEN.with(|acting| *acting.borrow_mut() = previous);
+    }
+}
+
+/// Fails if the actions on this thread are done with a token that does not allow something on a
+/// server. Without a token there is nothing to check here.
+pub(crate) fn check_token_scope(server: &Server<u64>, capability: Capability) -> Result<(), Box<dyn Error>> {
+    ACTING_TOKEN.with(|acting| match acting.borrow().as_ref() {
+        Some(token) if !token.allows(server, capability) => Err(token.denied(server, capability)),
+        _ => Ok(()),
+    })
+}
+
+fn read_token(statement: &sqlite::Statement) -> Result<ApiToken, Box<dyn Error>> {
+    Ok(ApiToken {
+        id: statement.read::<i64, _>("id")? as u64,
+        user_id: statement.read::<i64, _>("user_id")? as u64,
+        name: statement.read::<String, _>("name")?,
+        scopes: serde_json::from_str(&statement.read::<String, _>("scopes")?)?,
+        servers: serde_json::from_str(&statement.read::<String, _>("servers")?)?,
+        created_at: statement.read::<i64, _>("created_at")? as u64,
+        expires_at: statement.read::<Option<i64>, _>("expires_at")?.map(|at| at as u64),
+        last_used_at: statement.read::<Option<i64>, _>("last_used_at")?.map(|at| at as u64),
+    })
+}
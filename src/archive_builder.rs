@@ -0,0 +1,349 @@
+use crate::archive_entries::ArchiveFormat;
+use crate::jobs::check_cancelled;
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Options controlling how an archive is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArchiveOptions {
+    /// The format of the archive. Only `Zip` and `TarGz` can be created.
+    pub format: ArchiveFormat,
+    /// The compression level from `0` (store only) to `9` (smallest), or `None` for the format's default.
+    pub compression_level: Option<u32>,
+    /// Glob patterns matched against the entry names, such as `world/session.lock`. Matching files
+    /// are left out, and matching directories with everything in them.
+    pub exclude: Vec<String>,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self {
+            format: ArchiveFormat::Zip,
+            compression_level: None,
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// A snapshot of the progress of an archive being created, passed to the progress callback after every entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveProgress {
+    /// The name of the entry that was just added to the archive.
+    pub current_entry: String,
+    /// The number of entries added so far.
+    pub entries_processed: u64,
+    /// The number of entries that will be added in total.
+    pub total_entries: u64,
+    /// The number of uncompressed bytes added so far.
+    pub bytes_processed: u64,
+    /// The number of uncompressed bytes that will be added in total.
+    pub total_bytes: u64,
+}
+
+impl ArchiveProgress {
+    /// Returns the progress as a percentage between `0` and `100`, based on the bytes processed.
+    pub fn percentage(&self) -> f64 {
+        if self.total_bytes == 0 {
+            if self.total_entries == 0 {
+                return 100.0;
+            }
+            return self.entries_processed as f64 / self.total_entries as f64 * 100.0;
+        }
+        self.bytes_processed as f64 / self.total_bytes as f64 * 100.0
+    }
+}
+
+/// The outcome of a finished archive creation.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchiveSummary {
+    /// The number of files and directories added to the archive.
+    pub entries: u64,
+    /// The number of uncompressed bytes added to the archive.
+    pub bytes: u64,
+}
+
+/// A file or directory that will be written into the archive.
+struct PlannedEntry {
+    source: PathBuf,
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+/// Creates an archive file from a list of files and directories.
+///
+/// Entries are named relative to the parent of each source, so archiving `world` produces
+/// entries like `world/level.dat` and `world/region/r.0.0.mca`.
+///
+/// # Arguments
+/// * `sources` - The files and directories to add. Directories are added recursively.
+/// * `destination` - The archive file to create. An existing file is overwritten.
+/// * `options` - The format and compression level.
+/// * `on_progress` - Invoked after every added entry.
+///
+/// # Errors
+/// Returns an error if the format cannot be created, or if reading a source or writing the archive fails.
+pub fn create_archive(
+    sources: &[SandboxedPath],
+    destination: &SandboxedPath,
+    options: &ArchiveOptions,
+    on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    create_archive_relative_to(None, sources, destination, options, on_progress)
+}
+
+/// Creates an archive file like [`create_archive`], but names the entries relative to a common base
+/// directory, so archiving `world` and `config/server.toml` of a server keeps both of their paths.
+///
+/// Sources outside of the base are named relative to their parent, as [`create_archive`] does.
+///
+/// # Arguments
+/// * `base` - The directory entry names are relative to, the parent of each source if `None`.
+/// * `sources` - The files and directories to add. Directories are added recursively.
+/// * `destination` - The archive file to create. An existing file is overwritten.
+/// * `options` - The format and compression level.
+/// * `on_progress` - Invoked after every added entry.
+///
+/// # Errors
+/// Returns an error if the format cannot be created, or if reading a source or writing the archive fails.
+pub fn create_archive_relative_to(
+    base: Option<&Path>,
+    sources: &[SandboxedPath],
+    destination: &SandboxedPath,
+    options: &ArchiveOptions,
+    on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    let destination_path = destination.path();
+    info!("Creating {:?} archive at {:?}", options.format, destination_path);
+
+    if let Some(parent) = destination_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let plan = plan_entries(sources, base, Some(&destination_path), &options.exclude)?;
+    let file = BufWriter::new(File::create(&destination_path)?);
+
+    let result = match options.format {
+        // A file is seekable, so the zip can be written with precise local headers
+        ArchiveFormat::Zip => write_zip(zip::ZipWriter::new(file), &plan, options, on_progress),
+        _ => write_planned_archive(file, &plan, options, on_progress),
+    };
+
+    // Do not leave a truncated archive behind
+    if result.is_err() {
+        if let Err(err) = fs::remove_file(&destination_path) {
+            warn!("Failed to remove incomplete archive {:?}: {}", destination_path, err);
+        }
+    }
+    result
+}
+
+/// Writes an archive of a list of files and directories into an arbitrary writer, such as an HTTP response body.
+///
+/// The writer does not need to be seekable.
+///
+/// # Arguments
+/// * `sources` - The files and directories to add. Directories are added recursively.
+/// * `writer` - The destination of the archive bytes.
+/// * `options` - The format and compression level.
+/// * `on_progress` - Invoked after every added entry.
+///
+/// # Errors
+/// Returns an error if the format cannot be created, or if reading a source or writing fails.
+pub fn write_archive(
+    sources: &[SandboxedPath],
+    writer: impl Write,
+    options: &ArchiveOptions,
+    on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    let plan = plan_entries(sources, None, None, &options.exclude)?;
+    write_planned_archive(writer, &plan, options, on_progress)
+}
+
+/// Estimates the uncompressed size and number of entries of an archive before creating it.
+///
+/// # Errors
+/// Returns an error if one of the sources cannot be read.
+pub fn estimate_archive(sources: &[SandboxedPath]) -> Result<ArchiveSummary, Box<dyn Error>> {
+    estimate_archive_excluding(sources, &[])
+}
+
+/// Estimates an archive like [`estimate_archive`], leaving out the entries matching the glob
+/// patterns of [`ArchiveOptions::exclude`].
+///
+/// # Errors
+/// Returns an error if one of the sources cannot be read or a pattern is invalid.
+pub fn estimate_archive_excluding(
+    sources: &[SandboxedPath],
+    exclude: &[String],
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    let plan = plan_entries(sources, None, None, exclude)?;
+    Ok(ArchiveSummary {
+        entries: plan.len() as u64,
+        bytes: plan.iter().map(|entry| entry.size).sum(),
+    })
+}
+
+fn write_planned_archive(
+    writer: impl Write,
+    plan: &[PlannedEntry],
+    options: &ArchiveOptions,
+    on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    match options.format {
+        ArchiveFormat::Zip => write_zip(zip::ZipWriter::new_stream(writer), plan, options, on_progress),
+        ArchiveFormat::TarGz => write_tar_gz(writer, plan, options, on_progress),
+        format => Err(format!("Creating {:?} archives is not supported", format).into()),
+    }
+}
+
+/// Walks all sources and collects the entries to archive, so that the totals for progress reporting are known.
+fn plan_entries(
+    sources: &[SandboxedPath],
+    base: Option<&Path>,
+    destination: Option<&Path>,
+    exclude: &[String],
+) -> Result<Vec<PlannedEntry>, Box<dyn Error>> {
+    let exclude = exclude
+        .iter()
+        .map(|pattern| glob::Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut plan = Vec::new();
+    for source in sources {
+        let source_path = source.path();
+        let base = match base {
+            Some(base) if source_path.starts_with(base) => base.to_path_buf(),
+            _ => source_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+
+        let mut entries = walkdir::WalkDir::new(&source_path).follow_links(false).into_iter();
+        while let Some(entry) = entries.next() {
+            let entry = entry?;
+            let path = entry.path();
+            if destination == Some(path) {
+                continue;
+            }
+
+            let file_type = entry.file_type();
+            if !file_type.is_dir() && !file_type.is_file() {
+                warn!("Skipping {:?}, only files and directories can be archived", path);
+                continue;
+            }
+
+            // Archive entry names always use forward slashes
+            let name = path
+                .strip_prefix(&base)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if name.is_empty() {
+                continue;
+            }
+            if exclude.iter().any(|pattern| pattern.matches(&name)) {
+                if file_type.is_dir() {
+                    entries.skip_current_dir();
+                }
+                continue;
+            }
+
+            plan.push(PlannedEntry {
+                source: path.to_path_buf(),
+                name,
+                is_dir: file_type.is_dir(),
+                size: if file_type.is_file() { entry.metadata()?.len() } else { 0 },
+            });
+        }
+    }
+    debug!("Planned {} archive entries", plan.len());
+    Ok(plan)
+}
+
+fn new_progress(plan: &[PlannedEntry]) -> ArchiveProgress {
+    ArchiveProgress {
+        current_entry: String::new(),
+        entries_processed: 0,
+        total_entries: plan.len() as u64,
+        bytes_processed: 0,
+        total_bytes: plan.iter().map(|entry| entry.size).sum(),
+    }
+}
+
+fn write_zip<W: Write + std::io::Seek>(
+    mut zip: zip::ZipWriter<W>,
+    plan: &[PlannedEntry],
+    options: &ArchiveOptions,
+    mut on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    let mut progress = new_progress(plan);
+    // Stored entries take no compression level, zip refuses to write them with one
+    let (method, level) = match options.compression_level {
+        Some(0) => (zip::CompressionMethod::Stored, None),
+        level => (zip::CompressionMethod::Deflated, level.map(|level| level.min(9) as i64)),
+    };
+    let file_options = zip::write::SimpleFileOptions::default()
+        .compression_method(method)
+        .compression_level(level);
+
+    for entry in plan {
+        if entry.is_dir {
+            zip.add_directory(entry.name.clone(), file_options)?;
+        } else {
+            let entry_options = file_options.large_file(entry.size >= u32::MAX as u64);
+            zip.start_file(entry.name.clone(), entry_options)?;
+            std::io::copy(&mut File::open(&entry.source)?, &mut zip)?;
+        }
+
+        progress.current_entry = entry.name.clone();
+        progress.entries_processed += 1;
+        progress.bytes_processed += entry.size;
+        on_progress(&progress);
+        check_cancelled()?;
+    }
+
+    zip.finish()?;
+    Ok(ArchiveSummary {
+        entries: progress.entries_processed,
+        bytes: progress.bytes_processed,
+    })
+}
+
+fn write_tar_gz(
+    writer: impl Write,
+    plan: &[PlannedEntry],
+    options: &ArchiveOptions,
+    mut on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<ArchiveSummary, Box<dyn Error>> {
+    let mut progress = new_progress(plan);
+    let compression = options
+        .compression_level
+        .map(|level| flate2::Compression::new(level.min(9)))
+        .unwrap_or_default();
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(writer, compression));
+
+    for entry in plan {
+        if entry.is_dir {
+            builder.append_dir(&entry.name, &entry.source)?;
+        } else {
+            builder.append_path_with_name(&entry.source, &entry.name)?;
+        }
+
+        progress.current_entry = entry.name.clone();
+        progress.entries_processed += 1;
+        progress.bytes_processed += entry.size;
+        on_progress(&progress);
+        check_cancelled()?;
+    }
+
+    builder.into_inner()?.finish()?.flush()?;
+    Ok(ArchiveSummary {
+        entries: progress.entries_processed,
+        bytes: progress.bytes_processed,
+    })
+}
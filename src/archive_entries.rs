@@ -0,0 +1,244 @@
+use crate::file_system_entry::{get_file_type, get_mime};
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+/// The archive formats the file manager can look into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// Zip archives, including Java archives (`.jar`) and Minecraft packs that use the zip layout.
+    Zip,
+    /// Uncompressed tar archives.
+    Tar,
+    /// Gzip compressed tar archives.
+    TarGz,
+    /// Bzip2 compressed tar archives.
+    TarBz2,
+    /// XZ compressed tar archives.
+    TarXz,
+    /// 7-Zip archives.
+    SevenZip,
+}
+
+impl ArchiveFormat {
+    /// Determines the archive format from a file name.
+    ///
+    /// # Arguments
+    /// * `path` - The path or file name of the archive.
+    ///
+    /// # Returns
+    /// The detected format, or `None` if the file is not a supported archive.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_string_lossy().to_lowercase();
+
+        // Compound extensions have to be checked before their single extension counterparts
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar.bz2") || name.ends_with(".tbz2") {
+            Some(Self::TarBz2)
+        } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Some(Self::TarXz)
+        } else {
+            match Path::new(&name).extension().and_then(OsStr::to_str)? {
+                "zip" | "jar" | "war" | "ear" | "mrpack" | "mcpack" | "mcworld" | "mcaddon" | "mctemplate" => {
+                    Some(Self::Zip)
+                }
+                "tar" => Some(Self::Tar),
+                "7z" => Some(Self::SevenZip),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// A single file or directory inside an archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    /// The path of the entry inside the archive.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// The uncompressed size in bytes. For directories this is the combined size of their contents.
+    pub size: u64,
+    /// The compressed size in bytes, if the archive format stores it per entry.
+    pub compressed_size: Option<u64>,
+    pub r#type: String,
+    pub mime: Option<String>,
+}
+
+/// The contents of a single directory inside an archive, mirroring `FileSystemEntries`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveEntries {
+    /// The format of the archive being browsed.
+    pub format: ArchiveFormat,
+    /// The directory inside the archive that is being listed, `""` for the archive root.
+    pub path: PathBuf,
+    /// The parent directory inside the archive, or `None` when listing the archive root.
+    pub parent: Option<PathBuf>,
+    pub entries: Vec<ArchiveEntry>,
+}
+
+/// An entry as stored in the archive, before it is placed into the directory hierarchy.
+struct RawArchiveEntry {
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    compressed_size: Option<u64>,
+}
+
+impl ArchiveEntries {
+    /// Lists the contents of a directory inside an archive without extracting it.
+    ///
+    /// Archives do not always contain explicit entries for their directories, so directories
+    /// implied by nested paths are listed as well, reporting the combined size of their contents.
+    ///
+    /// # Arguments
+    /// * `archive` - The archive to browse.
+    /// * `inner_path` - The directory inside the archive to list, `""` for the archive root.
+    ///
+    /// # Errors
+    /// Returns an error if the file is not a supported archive or cannot be read.
+    pub fn read(archive: &SandboxedPath, inner_path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let archive_path = archive.path();
+        let format = ArchiveFormat::from_path(&archive_path)
+            .ok_or_else(|| format!("{} is not a supported archive", archive))?;
+        let inner_path = normalize_inner_path(inner_path.as_ref());
+        debug!("Browsing {:?} archive {:?} at {:?}", format, archive_path, inner_path);
+
+        let raw_entries = read_raw_entries(&archive_path, format)?;
+
+        // Group everything below the requested directory by the first component after it
+        let mut children: BTreeMap<String, ArchiveEntry> = BTreeMap::new();
+        for raw in raw_entries {
+            let Ok(remainder) = raw.path.strip_prefix(&inner_path) else {
+                continue;
+            };
+            let mut components = remainder.components();
+            let Some(first) = components.next() else {
+                // This is the entry for the requested directory itself
+                continue;
+            };
+            let name = first.as_os_str().to_string_lossy().to_string();
+            let is_nested = components.next().is_some();
+            let child_path = inner_path.join(&name);
+
+            let child = children.entry(name.clone()).or_insert_with(|| ArchiveEntry {
+                r#type: if is_nested || raw.is_dir {
+                    "Directory".to_string()
+                } else {
+                    get_file_type(
+                        child_path
+                            .extension()
+                            .unwrap_or(OsStr::new(""))
+                            .to_string_lossy()
+                            .to_string(),
+                    )
+                },
+                mime: if is_nested || raw.is_dir { None } else { get_mime(&child_path) },
+                name,
+                path: child_path,
+                is_dir: is_nested || raw.is_dir,
+                size: 0,
+                compressed_size: None,
+            });
+
+            child.size += raw.size;
+            if let Some(compressed_size) = raw.compressed_size {
+                child.compressed_size = Some(child.compressed_size.unwrap_or(0) + compressed_size);
+            }
+        }
+
+        info!("Read {} entries from archive {:?}", children.len(), archive_path);
+        Ok(Self {
+            format,
+            parent: if inner_path.as_os_str().is_empty() {
+                None
+            } else {
+                Some(inner_path.parent().map(Path::to_path_buf).unwrap_or_default())
+            },
+            path: inner_path,
+            entries: children.into_values().collect(),
+        })
+    }
+}
+
+/// Strips leading separators and `.`/`..` components from a path inside an archive.
+fn normalize_inner_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reads the flat list of entries stored in an archive.
+fn read_raw_entries(path: &Path, format: ArchiveFormat) -> Result<Vec<RawArchiveEntry>, Box<dyn Error>> {
+    match format {
+        ArchiveFormat::Zip => read_zip_entries(path),
+        ArchiveFormat::Tar => read_tar_entries(BufReader::new(File::open(path)?)),
+        ArchiveFormat::TarGz => read_tar_entries(flate2::read::GzDecoder::new(BufReader::new(File::open(path)?))),
+        ArchiveFormat::TarBz2 => read_tar_entries(bzip2::read::BzDecoder::new(BufReader::new(File::open(path)?))),
+        ArchiveFormat::TarXz => read_tar_entries(xz2::read::XzDecoder::new(BufReader::new(File::open(path)?))),
+        ArchiveFormat::SevenZip => read_seven_zip_entries(path),
+    }
+}
+
+fn read_zip_entries(path: &Path) -> Result<Vec<RawArchiveEntry>, Box<dyn Error>> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let mut entries = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let file = archive.by_index(index)?;
+
+        // Entries with unsafe names (absolute paths, `..`) are not listed
+        let Some(entry_path) = file.enclosed_name() else {
+            continue;
+        };
+        entries.push(RawArchiveEntry {
+            path: normalize_inner_path(&entry_path),
+            is_dir: file.is_dir(),
+            size: file.size(),
+            compressed_size: Some(file.compressed_size()),
+        });
+    }
+    Ok(entries)
+}
+
+fn read_tar_entries(reader: impl Read) -> Result<Vec<RawArchiveEntry>, Box<dyn Error>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        entries.push(RawArchiveEntry {
+            path: normalize_inner_path(&entry.path()?),
+            is_dir: header.entry_type().is_dir(),
+            size: header.size()?,
+            // Tar archives are compressed as a whole, there is no per-entry compressed size
+            compressed_size: None,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_seven_zip_entries(path: &Path) -> Result<Vec<RawArchiveEntry>, Box<dyn Error>> {
+    let reader = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())?;
+    Ok(reader
+        .archive()
+        .files
+        .iter()
+        .map(|file| RawArchiveEntry {
+            path: normalize_inner_path(Path::new(file.name())),
+            is_dir: file.is_directory(),
+            size: file.size(),
+            compressed_size: Some(file.compressed_size),
+        })
+        .collect())
+}
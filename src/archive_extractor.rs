@@ -0,0 +1,394 @@
+use crate::archive_entries::ArchiveFormat;
+use crate::jobs::check_cancelled;
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Determines what happens when an extracted file already exists at its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverwritePolicy {
+    /// Keep the existing file and do not extract the entry.
+    #[default]
+    Skip,
+    /// Overwrite the existing file with the extracted entry.
+    Replace,
+    /// Extract the entry next to the existing file, as `name (1).ext`.
+    Rename,
+}
+
+/// Options controlling how an archive is extracted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractionOptions {
+    /// What to do when a file already exists at the destination.
+    pub overwrite: OverwritePolicy,
+    /// Only extracts the entries at or below these paths inside the archive, every entry if it is empty.
+    pub include: Vec<PathBuf>,
+}
+
+/// A snapshot of the progress of an extraction, passed to the progress callback after every entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtractionProgress {
+    /// The path of the entry that was just processed, relative to the destination.
+    pub current_entry: PathBuf,
+    /// The number of entries processed so far, including skipped ones.
+    pub entries_processed: u64,
+    /// The total number of entries, if the archive format stores it up front.
+    pub total_entries: Option<u64>,
+    /// The number of uncompressed bytes written so far.
+    pub bytes_written: u64,
+    /// The number of bytes read from the archive file so far.
+    pub archive_bytes_read: u64,
+    /// The size of the archive file.
+    pub archive_size: u64,
+}
+
+impl ExtractionProgress {
+    /// Returns the progress as a percentage between `0` and `100`.
+    pub fn percentage(&self) -> f64 {
+        match self.total_entries {
+            Some(total) if total > 0 => self.entries_processed as f64 / total as f64 * 100.0,
+            _ if self.archive_size > 0 => {
+                (self.archive_bytes_read as f64 / self.archive_size as f64 * 100.0).min(100.0)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// The outcome of a finished extraction.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExtractionSummary {
+    /// The number of files and directories written to the destination.
+    pub extracted: u64,
+    /// The number of entries skipped because they already existed or are not regular files.
+    pub skipped: u64,
+    /// The number of files that were extracted under a new name due to a conflict.
+    pub renamed: u64,
+    /// The number of uncompressed bytes written.
+    pub bytes_written: u64,
+}
+
+/// Extracts an archive into a directory.
+///
+/// Entries with absolute paths, Windows drives or `..` components (the so called "zip slip") abort
+/// the extraction.
+/// The paths of a zip archive are all validated from its central directory before anything is
+/// written, while tar archives can only be read once and are validated entry by entry, so the
+/// entries before an unsafe one are already extracted. The destination of each entry is resolved
+/// through the sandbox so symbolic links inside the destination cannot redirect writes outside of
+/// the server directory. Symbolic and hard link entries in tar archives are skipped.
+///
+/// # Arguments
+/// * `archive` - The archive to extract. Zip (and jar), tar, tar.gz, tar.bz2 and tar.xz are supported.
+/// * `destination` - The directory to extract into. It is created if it does not exist.
+/// * `options` - The overwrite policy for existing files.
+/// * `on_progress` - Invoked after every processed entry.
+///
+/// # Errors
+/// Returns an error if the archive cannot be read, contains unsafe paths, or if writing fails.
+pub fn extract_archive(
+    archive: &SandboxedPath,
+    destination: &SandboxedPath,
+    options: &ExtractionOptions,
+    mut on_progress: impl FnMut(&ExtractionProgress),
+) -> Result<ExtractionSummary, Box<dyn Error>> {
+    let archive_path = archive.path();
+    let format =
+        ArchiveFormat::from_path(&archive_path).ok_or_else(|| format!("{} is not a supported archive", archive))?;
+    info!("Extracting {:?} archive {:?} into {:?}", format, archive_path, destination.path());
+
+    fs::create_dir_all(destination.path())?;
+
+    let archive_size = fs::metadata(&archive_path)?.len();
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let reader = CountingReader {
+        inner: BufReader::new(File::open(&archive_path)?),
+        count: Arc::clone(&bytes_read),
+    };
+
+    let mut extractor = Extractor {
+        destination,
+        options,
+        summary: ExtractionSummary::default(),
+        progress: ExtractionProgress {
+            current_entry: PathBuf::new(),
+            entries_processed: 0,
+            total_entries: None,
+            bytes_written: 0,
+            archive_bytes_read: 0,
+            archive_size,
+        },
+        bytes_read,
+    };
+
+    match format {
+        ArchiveFormat::Zip => extractor.extract_zip(reader, &mut on_progress)?,
+        ArchiveFormat::Tar => extractor.extract_tar(reader, &mut on_progress)?,
+        ArchiveFormat::TarGz => extractor.extract_tar(flate2::read::GzDecoder::new(reader), &mut on_progress)?,
+        ArchiveFormat::TarBz2 => extractor.extract_tar(bzip2::read::BzDecoder::new(reader), &mut on_progress)?,
+        ArchiveFormat::TarXz => extractor.extract_tar(xz2::read::XzDecoder::new(reader), &mut on_progress)?,
+        ArchiveFormat::SevenZip => return Err("Extracting 7-Zip archives is not supported".into()),
+    }
+
+    info!(
+        "Extracted {} entries ({} bytes) from {:?}, skipped {}, renamed {}",
+        extractor.summary.extracted,
+        extractor.summary.bytes_written,
+        archive_path,
+        extractor.summary.skipped,
+        extractor.summary.renamed
+    );
+    Ok(extractor.summary)
+}
+
+/// Validates the path of an archive entry, rejecting anything that could escape the destination.
+///
+/// Archives made on Windows may separate the components with backslashes and start with a drive,
+/// which only Windows would parse as such, so both are handled on every platform.
+pub(crate) fn safe_entry_path(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let separated = path.to_str().map(|text| PathBuf::from(text.replace('\\', "/")));
+    let path = separated.as_deref().unwrap_or(path);
+    let drive = path.to_str().is_some_and(|text| {
+        let mut chars = text.chars();
+        chars.next().is_some_and(|letter| letter.is_ascii_alphabetic()) && chars.next() == Some(':')
+    });
+    if drive {
+        return Err(format!("Archive entry {:?} would be extracted outside of the destination", path).into());
+    }
+    let mut safe = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => safe.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(format!("Archive entry {:?} would be extracted outside of the destination", path).into());
+            }
+        }
+    }
+    Ok(safe)
+}
+
+/// Wraps a reader and counts the number of bytes read through it.
+struct CountingReader<R: Read> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<R: Read + std::io::Seek> std::io::Seek for CountingReader<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// The state shared by the format specific extraction loops.
+struct Extractor<'a> {
+    destination: &'a SandboxedPath,
+    options: &'a ExtractionOptions,
+    summary: ExtractionSummary,
+    progress: ExtractionProgress,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl Extractor<'_> {
+    fn extract_zip<R: Read + std::io::Seek>(
+        &mut self,
+        reader: R,
+        on_progress: &mut impl FnMut(&ExtractionProgress),
+    ) -> Result<(), Box<dyn Error>> {
+        let mut archive = zip::ZipArchive::new(reader)?;
+        for name in archive.file_names() {
+            safe_entry_path(Path::new(name))?;
+        }
+        // Filtered entries are not processed, the percentage falls back to the bytes read then
+        self.progress.total_entries = Some(archive.len() as u64).filter(|_| self.options.include.is_empty());
+
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index)?;
+            let entry_path = safe_entry_path(Path::new(file.name()))?;
+            if !self.is_included(&entry_path) {
+                continue;
+            }
+            let mode = file.unix_mode();
+            if file.is_dir() {
+                self.write_directory(&entry_path)?;
+            } else {
+                self.write_file(&entry_path, &mut file, mode)?;
+            }
+            self.report(entry_path, on_progress);
+            check_cancelled()?;
+        }
+        Ok(())
+    }
+
+    fn extract_tar(
+        &mut self,
+        reader: impl Read,
+        on_progress: &mut impl FnMut(&ExtractionProgress),
+    ) -> Result<(), Box<dyn Error>> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let entry_path = safe_entry_path(&entry.path()?)?;
+            if !self.is_included(&entry_path) {
+                continue;
+            }
+            let entry_type = entry.header().entry_type();
+            let mode = entry.header().mode().ok();
+
+            if entry_type.is_dir() {
+                self.write_directory(&entry_path)?;
+            } else if entry_type.is_file() {
+                self.write_file(&entry_path, &mut entry, mode)?;
+            } else {
+                // Links could point anywhere on the system, so they are never recreated
+                warn!("Skipping unsupported tar entry {:?} of type {:?}", entry_path, entry_type);
+                self.summary.skipped += 1;
+            }
+            self.report(entry_path, on_progress);
+            check_cancelled()?;
+        }
+        Ok(())
+    }
+
+    fn is_included(&self, entry_path: &Path) -> bool {
+        self.options.include.is_empty() || self.options.include.iter().any(|path| entry_path.starts_with(path))
+    }
+
+    fn write_directory(&mut self, entry_path: &Path) -> Result<(), Box<dyn Error>> {
+        let target = self.destination.join(entry_path)?;
+        fs::create_dir_all(target.path())?;
+        self.summary.extracted += 1;
+        Ok(())
+    }
+
+    fn write_file(&mut self, entry_path: &Path, contents: &mut impl Read, mode: Option<u32>) -> Result<(), Box<dyn Error>> {
+        let mut target = self.destination.join(entry_path)?;
+
+        if fs::symlink_metadata(target.path()).is_ok() {
+            match self.options.overwrite {
+                OverwritePolicy::Skip => {
+                    debug!("Skipping existing file {:?}", target.path());
+                    self.summary.skipped += 1;
+                    return Ok(());
+                }
+                OverwritePolicy::Replace => {}
+                OverwritePolicy::Rename => {
+                    target = target.next_available()?;
+                    self.summary.renamed += 1;
+                }
+            }
+        }
+
+        let target_path = target.path();
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&target_path)?;
+        let written = std::io::copy(contents, &mut file)?;
+        set_mode(&target_path, mode);
+
+        self.summary.extracted += 1;
+        self.summary.bytes_written += written;
+        self.progress.bytes_written += written;
+        Ok(())
+    }
+
+    fn report(&mut self, entry_path: PathBuf, on_progress: &mut impl FnMut(&ExtractionProgress)) {
+        self.progress.current_entry = entry_path;
+        self.progress.entries_processed += 1;
+        self.progress.archive_bytes_read = self.bytes_read.load(Ordering::Relaxed);
+        on_progress(&self.progress);
+    }
+}
+
+/// Restores the permission bits stored in the archive, so extracted start scripts stay executable.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = mode {
+        // Only keep the permission bits, never setuid/setgid
+        if let Err(err) = fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777)) {
+            warn!("Failed to set permissions of {:?}: {}", path, err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn safe_entry_paths_stay_relative() -> Result<(), Box<dyn Error>> {
+        assert_eq!(safe_entry_path(Path::new("world/region/r.0.0.mca"))?, Path::new("world/region/r.0.0.mca"));
+        assert_eq!(safe_entry_path(Path::new("./world/./level.dat"))?, Path::new("world/level.dat"));
+        assert_eq!(safe_entry_path(Path::new("world\\level.dat"))?, Path::new("world/level.dat"));
+        Ok(())
+    }
+
+    #[test]
+    fn escaping_entry_paths_are_refused() {
+        for path in [
+            "../x",
+            "/etc/x",
+            "a/../../x",
+            "a/../b",
+            "..\\x",
+            "\\x",
+            "C:\\Windows\\x",
+            "C:/x",
+            "c:x",
+            "\\\\server\\share\\x",
+        ] {
+            assert!(safe_entry_path(Path::new(path)).is_err(), "{}", path);
+        }
+    }
+
+    fn extract_zip_slip(directory: &Path) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(directory.join("root"))?;
+        let mut zip = zip::ZipWriter::new(File::create(directory.join("root/slip.zip"))?);
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("good.txt", options)?;
+        zip.write_all(b"good")?;
+        zip.start_file("../evil.txt", options)?;
+        zip.write_all(b"evil")?;
+        zip.finish()?;
+
+        let archive = SandboxedPath::new(directory.join("root"), "slip.zip")?;
+        let destination = SandboxedPath::new(directory.join("root"), "extracted")?;
+        assert!(extract_archive(&archive, &destination, &ExtractionOptions::default(), |_| {}).is_err());
+        assert!(!destination.path().join("good.txt").exists());
+        assert!(!directory.join("root/evil.txt").exists());
+        assert!(!directory.join("evil.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn unsafe_zip_entries_fail_before_anything_is_written() -> Result<(), Box<dyn Error>> {
+        let directory = std::env::temp_dir().join(format!("archive-extractor-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let result = extract_zip_slip(&directory);
+        let _ = fs::remove_dir_all(&directory);
+        result
+    }
+}
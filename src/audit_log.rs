@@ -0,0 +1,332 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::users::get_user;
+use log::{error, info};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::cell::Cell;
+use std::error::Error;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most entries a single query of the audit log returns.
+pub const MAX_AUDIT_ENTRIES: u32 = 1000;
+
+/// The longest before or after summary kept, longer ones are cut off.
+const MAX_SUMMARY_LENGTH: usize = 2000;
+
+thread_local! {
+    static ACTING_USER: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// A management action recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    FileEdited,
+    FileDeleted,
+    FileMoved,
+    FileUploaded,
+    CommandSent,
+    ServerStarted,
+    ServerStopped,
+    ServerKilled,
+    ConfigChanged,
+    BackupRestored,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::FileEdited => "file_edited",
+            AuditAction::FileDeleted => "file_deleted",
+            AuditAction::FileMoved => "file_moved",
+            AuditAction::FileUploaded => "file_uploaded",
+            AuditAction::CommandSent => "command_sent",
+            AuditAction::ServerStarted => "server_started",
+            AuditAction::ServerStopped => "server_stopped",
+            AuditAction::ServerKilled => "server_killed",
+            AuditAction::ConfigChanged => "config_changed",
+            AuditAction::BackupRestored => "backup_restored",
+        }
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = Box<dyn Error>;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "file_edited" => Ok(AuditAction::FileEdited),
+            "file_deleted" => Ok(AuditAction::FileDeleted),
+            "file_moved" => Ok(AuditAction::FileMoved),
+            "file_uploaded" => Ok(AuditAction::FileUploaded),
+            "command_sent" => Ok(AuditAction::CommandSent),
+            "server_started" => Ok(AuditAction::ServerStarted),
+            "server_stopped" => Ok(AuditAction::ServerStopped),
+            "server_killed" => Ok(AuditAction::ServerKilled),
+            "config_changed" => Ok(AuditAction::ConfigChanged),
+            "backup_restored" => Ok(AuditAction::BackupRestored),
+            other => Err(format!("Unknown audit action {:?}", other).into()),
+        }
+    }
+}
+
+/// An action to record, see [`record_audit`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// The user who did it, `None` for the manager itself, like a scheduled restart.
+    pub user_id: Option<u64>,
+    pub server_id: Option<u64>,
+    pub action: AuditAction,
+    /// What the action was done to, like a path relative to the server directory or a command.
+    pub target: String,
+    /// A summary of the state before the action.
+    pub before: Option<String>,
+    /// A summary of the state after the action.
+    pub after: Option<String>,
+}
+
+/// A recorded action.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    /// When it was done, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// Which entries of the audit log to return. Every filter that is set has to match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditQuery {
+    pub user_id: Option<u64>,
+    pub server_id: Option<u64>,
+    pub action: Option<AuditAction>,
+    /// Only entries whose target contains this text, case insensitively.
+    pub target: Option<String>,
+    /// The earliest time, in seconds since the Unix epoch.
+    pub from: Option<u64>,
+    /// The latest time, in seconds since the Unix epoch.
+    pub to: Option<u64>,
+    /// Only entries older than this one, to get the next page.
+    pub before_id: Option<u64>,
+    /// The most entries to return, at most [`MAX_AUDIT_ENTRIES`].
+    pub limit: u32,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        Self {
+            user_id: None,
+            server_id: None,
+            action: None,
+            target: None,
+            from: None,
+            to: None,
+            before_id: None,
+            limit: 100,
+        }
+    }
+}
+
+/// Attributes the actions done on this thread to a user, until the returned guard is dropped.
+///
+/// The operations that are audited, like writing a file of a server or starting it, record the
+/// user set here. Handlers set it once they know who made the request:
+///
+/// ```ignore
+/// let _user = act_as(user_id);
+/// server.write_text_file("server.properties", &content, &options)?;
+/// ```
+pub fn act_as(user_id: u64) -> ActingUser {
+    let previous = ACTING_USER.with(|user| user.replace(Some(user_id)));
+    ActingUser { previous }
+}
+
+/// The user the actions on this thread are attributed to, see [`act_as`].
+pub fn acting_user() -> Option<u64> {
+    ACTING_USER.with(Cell::get)
+}
+
+/// Attributes the actions on a thread to a user while it lives, see [`act_as`].
+#[must_use = "the actions are only attributed to the user while the guard lives"]
+pub struct ActingUser {
+    previous: Option<u64>,
+}
+
+impl Drop for ActingUser {
+    fn drop(&mut self) {
+        ACTING_USER.with(|user| user.set(self.previous));
+    }
+}
+
+/// Creates the table holding the audit log.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_audit_log_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `audit_log` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the entry
+            timestamp INTEGER NOT NULL,                                 -- Unix time of the action
+            user_id INTEGER,                                            -- The user who did it, NULL for the manager
+            server_id INTEGER,                                          -- The server it was done on, if any
+            action TEXT NOT NULL,                                       -- What was done, like `file_edited`
+            target TEXT NOT NULL,                                       -- What it was done to
+            before_summary TEXT,                                        -- A summary of the state before
+            after_summary TEXT                                          -- A summary of the state after
+        );
+        CREATE INDEX IF NOT EXISTS `audit_log_server` ON `audit_log` (server_id, timestamp);
+        CREATE INDEX IF NOT EXISTS `audit_log_user` ON `audit_log` (user_id, timestamp);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Records an action in the audit log.
+///
+/// # Returns
+/// The id of the new entry.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the entry cannot be stored.
+pub fn record_audit(event: &AuditEvent) -> Result<u64, Box<dyn Error>> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        "INSERT INTO audit_log (timestamp, user_id, server_id, action, target, before_summary, after_summary) \
+        VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )?;
+    statement.bind((1, timestamp as i64))?;
+    statement.bind((2, event.user_id.map(|id| id as i64)))?;
+    statement.bind((3, event.server_id.map(|id| id as i64)))?;
+    statement.bind((4, event.action.as_str()))?;
+    statement.bind((5, event.target.as_str()))?;
+    statement.bind((6, event.before.as_deref().map(truncate_summary)))?;
+    statement.bind((7, event.after.as_deref().map(truncate_summary)))?;
+    statement.next()?;
+    let id = last_inserted_id("audit_log")?;
+    info!(
+        "Audit: {} by {} on {:?}: {}",
+        event.action.as_str(),
+        event.user_id.map_or_else(|| "the manager".to_string(), |id| format!("user {}", id)),
+        event.server_id,
+        event.target
+    );
+    Ok(id)
+}
+
+/// Records an action by the acting user, logging instead of failing if it cannot be stored, so
+/// the action itself is never undone by the audit log.
+pub(crate) fn audit(
+    server_id: u64,
+    action: AuditAction,
+    target: impl Into<String>,
+    before: Option<String>,
+    after: Option<String>,
+) {
+    let event = AuditEvent {
+        user_id: acting_user(),
+        server_id: Some(server_id),
+        action,
+        target: target.into(),
+        before,
+        after,
+    };
+    if let Err(e) = record_audit(&event) {
+        error!("Failed to record {} on server {} in the audit log: {}", action.as_str(), server_id, e);
+    }
+}
+
+/// The servers whose entries the acting user may read, `None` for every entry.
+///
+/// Administrators and the manager itself read the whole log. Other users only read the entries of
+/// the servers whose console they can view, and not those of the panel itself.
+fn readable_servers() -> Result<Option<Vec<u64>>, Box<dyn Error>> {
+    let Some(user_id) = acting_user() else {
+        return Ok(None);
+    };
+    if get_user(user_id)?.is_admin {
+        return Ok(None);
+    }
+    Ok(Some(
+        Server::<u64>::get_list_of_servers()?
+            .into_iter()
+            .filter(|server| authorize(server, Capability::ViewConsole).is_ok())
+            .map(|server| server.id)
+            .collect(),
+    ))
+}
+
+/// Returns the entries of the audit log matching a query, newest first. Users who are not
+/// administrators only get the entries of the servers whose console they can view.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the entries cannot be read.
+pub fn query_audit_log(query: &AuditQuery) -> Result<Vec<AuditEntry>, Box<dyn Error>> {
+    // The ids are numbers, so they can be part of the query
+    let readable = match readable_servers()? {
+        Some(ids) => format!("AND server_id IN ({})", ids.iter().map(u64::to_string).collect::<Vec<_>>().join(", ")),
+        None => String::new(),
+    };
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(format!(
+        r#"SELECT * FROM audit_log
+        WHERE (?1 IS NULL OR user_id = ?1) AND (?2 IS NULL OR server_id = ?2) AND (?3 IS NULL OR action = ?3)
+        AND (?4 IS NULL OR instr(lower(target), lower(?4)) > 0)
+        AND (?5 IS NULL OR timestamp >= ?5) AND (?6 IS NULL OR timestamp <= ?6) AND (?7 IS NULL OR id < ?7)
+        {}
+        ORDER BY id DESC LIMIT ?8"#,
+        readable
+    ))?;
+    statement.bind((1, query.user_id.map(|id| id as i64)))?;
+    statement.bind((2, query.server_id.map(|id| id as i64)))?;
+    statement.bind((3, query.action.map(|action| action.as_str())))?;
+    statement.bind((4, query.target.as_deref()))?;
+    statement.bind((5, query.from.map(|from| from as i64)))?;
+    statement.bind((6, query.to.map(|to| to as i64)))?;
+    statement.bind((7, query.before_id.map(|id| id as i64)))?;
+    statement.bind((8, query.limit.clamp(1, MAX_AUDIT_ENTRIES) as i64))?;
+
+    let mut entries = Vec::new();
+    while let State::Row = statement.next()? {
+        // Entries of actions this version does not know are skipped rather than failing the query
+        let Ok(action) = statement.read::<String, _>("action")?.parse::<AuditAction>() else {
+            continue;
+        };
+        entries.push(AuditEntry {
+            id: statement.read::<i64, _>("id")? as u64,
+            timestamp: statement.read::<i64, _>("timestamp")? as u64,
+            event: AuditEvent {
+                user_id: statement.read::<Option<i64>, _>("user_id")?.map(|id| id as u64),
+                server_id: statement.read::<Option<i64>, _>("server_id")?.map(|id| id as u64),
+                action,
+                target: statement.read::<String, _>("target")?,
+                before: statement.read::<Option<String>, _>("before_summary")?,
+                after: statement.read::<Option<String>, _>("after_summary")?,
+            },
+        });
+    }
+    Ok(entries)
+}
+
+/// Summarizes the size of a text, like `12 lines, 340 bytes`.
+pub(crate) fn summarize_text(text: &str) -> String {
+    format!("{} lines, {} bytes", text.lines().count(), text.len())
+}
+
+fn truncate_summary(summary: &str) -> String {
+    if summary.len() <= MAX_SUMMARY_LENGTH {
+        return summary.to_string();
+    }
+    let mut end = MAX_SUMMARY_LENGTH;
+    while !summary.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &summary[..end])
+}
@@ -0,0 +1,697 @@
+use crate::backup_restore::{restore_backup, RestoreOptions};
+use crate::backups::{add_backup_listener, list_backups, BackupEventKind, BackupOptions, BackupTrigger};
+use crate::crash_detection::add_crash_listener;
+use crate::jobs::{get_job, start_backup_job, JobState};
+use crate::metrics_history::latest_tps;
+use crate::notifications::{add_notification_listener, notify_server, NotificationEvent, NotificationSeverity};
+use crate::online_players::{add_player_listener, online_player_count, PlayerEventKind};
+use crate::permissions::{authorize, Capability};
+use crate::scheduled_tasks::{expand_variables, get_scheduled_task, run_task_now, variables, COMMAND_VARIABLES};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::{send_server_command, ServerProcess};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the rules watching the tick rate are checked.
+const TPS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often a rule waits on the backup job it started to finish.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The longest a single wait action may pause the actions after it.
+const MAX_WAIT_SECONDS: u64 = 60 * 60;
+
+static ENGINE: Once = Once::new();
+
+lazy_static! {
+    /// Since when the tick rate is below the threshold of a rule, and whether the rule fired for it.
+    static ref TPS_STATES: Mutex<HashMap<u64, (Option<Instant>, bool)>> = Mutex::new(HashMap::new());
+    /// When each rule last fired, to hold it back for its cooldown.
+    static ref FIRED_AT: Mutex<HashMap<u64, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// What makes a rule fire.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AutomationTrigger {
+    /// A player joined, their name is the `{player}` variable of the commands.
+    PlayerJoined,
+    /// A player left, their name is the `{player}` variable of the commands.
+    PlayerLeft,
+    /// The server finished starting.
+    ServerStarted,
+    /// The server exited without crashing.
+    ServerStopped,
+    /// The server crashed. The actions run before the restart policy restarts it, so a restore
+    /// is done by the time the server comes back.
+    CrashDetected,
+    BackupFailed,
+    /// The tick rate stayed below `threshold` for `minutes`. Fires once, and again only after the
+    /// tick rate recovered.
+    LowTps { threshold: f64, minutes: u64 },
+}
+
+impl AutomationTrigger {
+    fn has_player(&self) -> bool {
+        matches!(self, AutomationTrigger::PlayerJoined | AutomationTrigger::PlayerLeft)
+    }
+}
+
+/// What has to hold when the trigger fires for the actions to run. Conditions nest with `all`,
+/// `any` and `not`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AutomationCondition {
+    /// Every one of the conditions holds, also when there are none.
+    All { conditions: Vec<AutomationCondition> },
+    /// At least one of the conditions holds.
+    Any { conditions: Vec<AutomationCondition> },
+    Not { condition: Box<AutomationCondition> },
+    /// The number of players online is within the bounds, both inclusive.
+    PlayersOnline {
+        #[serde(default)]
+        at_least: Option<usize>,
+        #[serde(default)]
+        at_most: Option<usize>,
+    },
+    /// The player of a join or leave is one of the names, compared without case.
+    Player { names: Vec<String> },
+    /// The last measured tick rate is below `threshold`.
+    TpsBelow { threshold: f64 },
+    /// The time in UTC is between `from` and `to`, as `HH:MM`. Wraps around midnight when `to`
+    /// comes before `from`.
+    TimeBetween { from: String, to: String },
+}
+
+impl AutomationCondition {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            AutomationCondition::All { conditions } | AutomationCondition::Any { conditions } => {
+                conditions.iter().try_for_each(AutomationCondition::validate)
+            }
+            AutomationCondition::Not { condition } => condition.validate(),
+            AutomationCondition::PlayersOnline {
+                at_least: Some(at_least),
+                at_most: Some(at_most),
+            } if at_least > at_most => {
+                Err(format!("No player count is both at least {} and at most {}", at_least, at_most).into())
+            }
+            AutomationCondition::TimeBetween { from, to } => {
+                parse_time_of_day(from).and(parse_time_of_day(to)).map(|_| ())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn holds(&self, event: &AutomationEvent) -> bool {
+        match self {
+            AutomationCondition::All { conditions } => conditions.iter().all(|condition| condition.holds(event)),
+            AutomationCondition::Any { conditions } => conditions.iter().any(|condition| condition.holds(event)),
+            AutomationCondition::Not { condition } => !condition.holds(event),
+            AutomationCondition::PlayersOnline { at_least, at_most } => {
+                let count = online_player_count(event.server_id);
+                at_least.is_none_or(|at_least| count >= at_least) && at_most.is_none_or(|at_most| count <= at_most)
+            }
+            AutomationCondition::Player { names } => event
+                .player
+                .as_ref()
+                .is_some_and(|player| names.iter().any(|name| name.eq_ignore_ascii_case(player))),
+            AutomationCondition::TpsBelow { threshold } => latest_tps(event.server_id, Duration::from_secs(2 * 60))
+                .ok()
+                .flatten()
+                .is_some_and(|tps| tps < *threshold),
+            AutomationCondition::TimeBetween { from, to } => {
+                let (Ok(from), Ok(to)) = (parse_time_of_day(from), parse_time_of_day(to)) else {
+                    return false;
+                };
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60 % (24 * 60);
+                if from <= to {
+                    (from..to).contains(&now)
+                } else {
+                    now >= from || now < to
+                }
+            }
+        }
+    }
+}
+
+/// The minute of the day of a time written as `HH:MM`.
+fn parse_time_of_day(time: &str) -> Result<u64, Box<dyn Error>> {
+    let invalid = || format!("{:?} is not a time of day like 06:30", time);
+    let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    if hours >= 24 || minutes >= 60 {
+        return Err(invalid().into());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// One step of what a rule does. The steps run in order, and a failing step stops the rest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AutomationAction {
+    /// Types a command into the console of the running server. Besides the variables of
+    /// scheduled tasks, see [`COMMAND_VARIABLES`], `{player}` is the player that joined or left.
+    Command { command: String },
+    /// Sends a notification to the notification targets.
+    Notify {
+        #[serde(default = "default_severity")]
+        severity: NotificationSeverity,
+        message: String,
+    },
+    Start,
+    Stop,
+    Restart,
+    /// Creates a backup and waits for it to finish.
+    Backup {
+        #[serde(default)]
+        options: BackupOptions,
+    },
+    /// Restores the newest manual or scheduled backup in full. A running server is stopped for
+    /// it and started again afterwards.
+    RestoreLatestBackup,
+    /// Runs a scheduled task of the same server in the background.
+    RunTask { task_id: u64 },
+    /// Waits before the next step, like to give a server time to settle after a restart.
+    Wait { seconds: u64 },
+}
+
+fn default_severity() -> NotificationSeverity {
+    NotificationSeverity::Warning
+}
+
+impl AutomationAction {
+    /// What the acting user needs to set up the action.
+    fn capability(&self) -> Capability {
+        match self {
+            AutomationAction::Command { .. } => Capability::SendCommands,
+            AutomationAction::Start | AutomationAction::Stop | AutomationAction::Restart => Capability::ControlServer,
+            AutomationAction::Backup { .. } | AutomationAction::RestoreLatestBackup => Capability::ManageBackups,
+            AutomationAction::Notify { .. } | AutomationAction::RunTask { .. } | AutomationAction::Wait { .. } => {
+                Capability::ViewConsole
+            }
+        }
+    }
+}
+
+/// A rule as it is created or changed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AutomationRuleDefinition {
+    pub name: String,
+    pub trigger: AutomationTrigger,
+    /// Checked when the trigger fires, the actions always run if there is none.
+    #[serde(default)]
+    pub condition: Option<AutomationCondition>,
+    pub actions: Vec<AutomationAction>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// How long after firing the rule ignores its trigger, so a crash loop does not restore a
+    /// backup on every start.
+    #[serde(default)]
+    pub cooldown_minutes: u64,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// A "when this happens, do that" rule of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct AutomationRule {
+    pub id: u64,
+    pub server_id: u64,
+    #[serde(flatten)]
+    pub definition: AutomationRuleDefinition,
+    /// In seconds since the Unix epoch.
+    pub last_fired_at: Option<u64>,
+    /// Why the actions failed the last time the rule fired, `None` if they all succeeded.
+    pub last_error: Option<String>,
+}
+
+/// What happened to trigger a rule.
+#[derive(Debug, Clone)]
+struct AutomationEvent {
+    server_id: u64,
+    player: Option<String>,
+}
+
+fn to_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the table holding the automation rules of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_automation_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_automation_rules` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the rule
+            server_id INTEGER NOT NULL,                                 -- The server the rule watches
+            name TEXT NOT NULL,                                         -- What the rule is called
+            enabled INTEGER NOT NULL DEFAULT 1,                         -- Whether the rule fires
+            rule_trigger TEXT NOT NULL,                                 -- What makes it fire, as JSON
+            rule_condition TEXT,                                        -- What has to hold, as JSON
+            rule_actions TEXT NOT NULL,                                 -- What it does, as JSON
+            cooldown_minutes INTEGER NOT NULL DEFAULT 0,                -- How long it waits to fire again
+            last_fired_at INTEGER,                                      -- Unix time it last fired
+            last_error TEXT                                             -- Why its actions last failed
+        );
+        CREATE INDEX IF NOT EXISTS `server_automation_rules_server` ON `server_automation_rules` (server_id);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Lists the automation rules of a server.
+pub fn list_automation_rules(server_id: u64) -> Result<Vec<AutomationRule>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_automation_rules WHERE server_id = ? ORDER BY id")?;
+    statement.bind((1, server_id as i64))?;
+    let mut rules = Vec::new();
+    while let State::Row = statement.next()? {
+        rules.push(read_rule(&statement)?);
+    }
+    Ok(rules)
+}
+
+/// Returns an automation rule.
+///
+/// # Errors
+/// Returns an error if there is no rule with the id.
+pub fn get_automation_rule(rule_id: u64) -> Result<AutomationRule, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_automation_rules WHERE id = ?")?;
+    statement.bind((1, rule_id as i64))?;
+    let State::Row = statement.next()? else {
+        return Err(format!("No automation rule with the id {}", rule_id).into());
+    };
+    read_rule(&statement)
+}
+
+/// Adds an automation rule to a server.
+///
+/// # Errors
+/// Returns an error if the rule is invalid, or the acting user may not do what its actions do on
+/// the server.
+pub fn create_automation_rule(
+    server_id: u64,
+    definition: &AutomationRuleDefinition,
+) -> Result<AutomationRule, Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    validate_rule(&server, definition)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO server_automation_rules
+        (server_id, name, enabled, rule_trigger, rule_condition, rule_actions, cooldown_minutes)
+        VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    bind_definition(&mut statement, 2, definition)?;
+    statement.next()?;
+    let rule = get_automation_rule(last_inserted_id("server_automation_rules")?)?;
+    info!("Added the automation rule {:?} to server {:?}", rule.definition.name, server.name);
+    Ok(rule)
+}
+
+/// Changes an automation rule. A rule watching the tick rate starts watching again from scratch.
+///
+/// # Errors
+/// Returns an error if there is no such rule, or the new definition is invalid or not allowed.
+pub fn update_automation_rule(
+    rule_id: u64,
+    definition: &AutomationRuleDefinition,
+) -> Result<AutomationRule, Box<dyn Error>> {
+    let rule = get_automation_rule(rule_id)?;
+    let server = Server::<u64>::get_server(rule.server_id)?;
+    validate_rule(&server, definition)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"UPDATE server_automation_rules SET name = ?, enabled = ?, rule_trigger = ?, rule_condition = ?,
+        rule_actions = ?, cooldown_minutes = ? WHERE id = ?"#,
+    )?;
+    bind_definition(&mut statement, 1, definition)?;
+    statement.bind((7, rule_id as i64))?;
+    statement.next()?;
+    if let Ok(mut states) = TPS_STATES.lock() {
+        states.remove(&rule_id);
+    }
+    get_automation_rule(rule_id)
+}
+
+/// Turns an automation rule on or off.
+pub fn set_automation_rule_enabled(rule_id: u64, enabled: bool) -> Result<AutomationRule, Box<dyn Error>> {
+    let rule = get_automation_rule(rule_id)?;
+    update_automation_rule(
+        rule_id,
+        &AutomationRuleDefinition {
+            enabled,
+            ..rule.definition
+        },
+    )
+}
+
+/// Removes an automation rule. Actions that are running are not stopped.
+pub fn delete_automation_rule(rule_id: u64) -> Result<(), Box<dyn Error>> {
+    let rule = get_automation_rule(rule_id)?;
+    let server = Server::<u64>::get_server(rule.server_id)?;
+    for action in &rule.definition.actions {
+        authorize(&server, action.capability())?;
+    }
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM server_automation_rules WHERE id = ?")?;
+    statement.bind((1, rule_id as i64))?;
+    statement.next()?;
+    if let Ok(mut states) = TPS_STATES.lock() {
+        states.remove(&rule_id);
+    }
+    info!("Removed the automation rule {:?} of server {}", rule.definition.name, rule.server_id);
+    Ok(())
+}
+
+/// Starts firing the automation rules of every server. Calling it again has no effect.
+///
+/// Rules on players, starts, stops and backups run their actions in the background. Rules on
+/// crashes run them before the server is restarted, and rules on the tick rate are checked every
+/// 30 seconds.
+pub fn start_automation() {
+    ENGINE.call_once(|| {
+        add_player_listener(|event| {
+            let trigger = match event.kind {
+                PlayerEventKind::Joined => AutomationTrigger::PlayerJoined,
+                PlayerEventKind::Left => AutomationTrigger::PlayerLeft,
+            };
+            let event = AutomationEvent {
+                server_id: event.server_id,
+                player: Some(event.player.name.clone()),
+            };
+            thread::spawn(move || fire(&event, |candidate| *candidate == trigger));
+        });
+        add_notification_listener(|notification| {
+            let trigger = match notification.event {
+                NotificationEvent::ServerStarted => AutomationTrigger::ServerStarted,
+                NotificationEvent::ServerStopped => AutomationTrigger::ServerStopped,
+                _ => return,
+            };
+            let Some(server_id) = notification.server_id else {
+                return;
+            };
+            let event = AutomationEvent { server_id, player: None };
+            thread::spawn(move || fire(&event, |candidate| *candidate == trigger));
+        });
+        add_backup_listener(|event| {
+            if event.kind == BackupEventKind::Failed {
+                let event = AutomationEvent {
+                    server_id: event.server_id,
+                    player: None,
+                };
+                thread::spawn(move || fire(&event, |candidate| *candidate == AutomationTrigger::BackupFailed));
+            }
+        });
+        add_crash_listener(|report| {
+            let event = AutomationEvent {
+                server_id: report.server_id,
+                player: None,
+            };
+            fire(&event, |candidate| *candidate == AutomationTrigger::CrashDetected);
+        });
+        thread::spawn(|| loop {
+            if let Err(e) = check_tps_rules() {
+                error!("Failed to check the automation rules on the tick rate: {}", e);
+            }
+            thread::sleep(TPS_CHECK_INTERVAL);
+        });
+        info!("Started firing automation rules");
+    });
+}
+
+/// Runs the actions of the enabled rules of a server whose trigger `selects` accepts and whose
+/// condition holds.
+fn fire(event: &AutomationEvent, selects: impl Fn(&AutomationTrigger) -> bool) {
+    let rules = match list_automation_rules(event.server_id) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Failed to read the automation rules of server {}: {}", event.server_id, e);
+            return;
+        }
+    };
+    for rule in rules {
+        if rule.definition.enabled && selects(&rule.definition.trigger) {
+            run_rule(&rule, event);
+        }
+    }
+}
+
+fn check_tps_rules() -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_automation_rules WHERE enabled = 1")?;
+    let mut rules = Vec::new();
+    while let State::Row = statement.next()? {
+        let rule = read_rule(&statement)?;
+        if let AutomationTrigger::LowTps { threshold, minutes } = rule.definition.trigger {
+            rules.push((threshold, minutes, rule));
+        }
+    }
+
+    for (threshold, minutes, rule) in rules {
+        // A measurement from the last two minutes, so a stopped server does not stay slow
+        let tps = latest_tps(rule.server_id, Duration::from_secs(2 * 60)).ok().flatten();
+        let slow = tps.is_some_and(|tps| tps < threshold);
+        let due = {
+            let Ok(mut states) = TPS_STATES.lock() else {
+                continue;
+            };
+            let (since, fired) = states.entry(rule.id).or_default();
+            if !slow {
+                *since = None;
+                *fired = false;
+                false
+            } else {
+                let since = *since.get_or_insert_with(Instant::now);
+                let due = !*fired && since.elapsed() >= Duration::from_secs(minutes * 60);
+                *fired |= due;
+                due
+            }
+        };
+        if due {
+            let event = AutomationEvent {
+                server_id: rule.server_id,
+                player: None,
+            };
+            thread::spawn(move || run_rule(&rule, &event));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the actions of a rule unless it is in its cooldown or its condition does not hold, and
+/// records how they went.
+fn run_rule(rule: &AutomationRule, event: &AutomationEvent) {
+    if !rule.definition.condition.as_ref().is_none_or(|condition| condition.holds(event)) {
+        return;
+    }
+    {
+        let Ok(mut fired_at) = FIRED_AT.lock() else {
+            return;
+        };
+        let cooldown = Duration::from_secs(rule.definition.cooldown_minutes * 60);
+        if fired_at.get(&rule.id).is_some_and(|at| at.elapsed() < cooldown) {
+            return;
+        }
+        fired_at.insert(rule.id, Instant::now());
+    }
+    info!("Running the automation rule {:?} of server {}", rule.definition.name, rule.server_id);
+    let result = Server::<u64>::get_server(rule.server_id).and_then(|server| {
+        rule.definition
+            .actions
+            .iter()
+            .try_for_each(|action| run_action(server.clone(), rule, action, event))
+    });
+    let error = result.err().map(|e| {
+        warn!("The automation rule {:?} of server {} failed: {}", rule.definition.name, rule.server_id, e);
+        e.to_string()
+    });
+    if let Err(e) = record_firing(rule.id, error) {
+        error!("Failed to record the firing of the automation rule {:?}: {}", rule.definition.name, e);
+    }
+}
+
+fn run_action(
+    mut server: Server<u64>,
+    rule: &AutomationRule,
+    action: &AutomationAction,
+    event: &AutomationEvent,
+) -> Result<(), Box<dyn Error>> {
+    match action {
+        AutomationAction::Command { command } => {
+            let command = command.replace("{player}", event.player.as_deref().unwrap_or_default());
+            send_server_command(&server, expand_variables(&server, &command)?)
+        }
+        AutomationAction::Notify { severity, message } => {
+            let message = message.replace("{player}", event.player.as_deref().unwrap_or_default());
+            let what = format!("matched the automation rule {:?}", rule.definition.name);
+            let message = expand_variables(&server, &message)?;
+            notify_server(server.id, NotificationEvent::AlertFired, *severity, &what, message);
+            Ok(())
+        }
+        AutomationAction::Start if server.is_server_running() => Ok(()),
+        AutomationAction::Start => server.start_server().map(|_| ()),
+        AutomationAction::Stop if !server.is_server_running() => Ok(()),
+        AutomationAction::Stop => server.stop_server().map(|_| ()),
+        AutomationAction::Restart => server.restart_server().map(|_| ()),
+        AutomationAction::Backup { options } => {
+            let job = start_backup_job(&server, options.clone())?;
+            loop {
+                thread::sleep(JOB_POLL_INTERVAL);
+                let job = get_job(job.id)?;
+                match job.state {
+                    JobState::Completed => return Ok(()),
+                    JobState::Failed => return Err(job.error.unwrap_or_default().into()),
+                    JobState::Cancelled => return Err(format!("The backup job {} was cancelled", job.id).into()),
+                    JobState::Queued | JobState::Running => {}
+                }
+            }
+        }
+        AutomationAction::RestoreLatestBackup => {
+            let backup = list_backups(server.id)?
+                .into_iter()
+                .find(|backup| matches!(backup.trigger, BackupTrigger::Manual | BackupTrigger::Scheduled))
+                .ok_or_else(|| format!("Server {:?} has no backup to restore", server.name))?;
+            let running = server.is_server_running();
+            if running {
+                server.stop_server()?;
+            }
+            restore_backup(&server, &backup.id, &RestoreOptions::default())?;
+            if running {
+                server.start_server()?;
+            }
+            Ok(())
+        }
+        AutomationAction::RunTask { task_id } => run_task_now(*task_id),
+        AutomationAction::Wait { seconds } => {
+            thread::sleep(Duration::from_secs(*seconds));
+            Ok(())
+        }
+    }
+}
+
+/// Fails unless a rule can be set up on a server by the acting user.
+fn validate_rule(server: &Server<u64>, definition: &AutomationRuleDefinition) -> Result<(), Box<dyn Error>> {
+    if definition.name.trim().is_empty() {
+        return Err("An automation rule needs a name".into());
+    }
+    if definition.actions.is_empty() {
+        return Err("An automation rule needs at least one action".into());
+    }
+    if let AutomationTrigger::LowTps { threshold, .. } = definition.trigger {
+        if !(threshold > 0.0 && threshold <= 20.0) {
+            return Err(format!("A tick rate of {} cannot be watched for, it is between 0 and 20", threshold).into());
+        }
+    }
+    if let Some(condition) = &definition.condition {
+        condition.validate()?;
+    }
+    for action in &definition.actions {
+        match action {
+            AutomationAction::Command { command } if command.trim().is_empty() => {
+                return Err("The command of an automation rule cannot be empty".into());
+            }
+            AutomationAction::Command { command: text } | AutomationAction::Notify { message: text, .. } => {
+                let unknown = variables(text).into_iter().find(|name| {
+                    !(COMMAND_VARIABLES.contains(name) || *name == "player" && definition.trigger.has_player())
+                });
+                if let Some(name) = unknown {
+                    return Err(format!("Unknown variable {{{}}} in the action", name).into());
+                }
+            }
+            AutomationAction::RunTask { task_id } => {
+                let task = get_scheduled_task(*task_id)?;
+                if task.server_id != server.id {
+                    return Err(format!("The task {:?} belongs to another server", task.definition.name).into());
+                }
+                authorize(server, task.definition.action.capability())?;
+            }
+            AutomationAction::Wait { seconds } if *seconds > MAX_WAIT_SECONDS => {
+                return Err(format!("A rule can wait at most {} seconds at a time", MAX_WAIT_SECONDS).into());
+            }
+            _ => {}
+        }
+        authorize(server, action.capability())?;
+    }
+    Ok(())
+}
+
+fn bind_definition(
+    statement: &mut sqlite::Statement,
+    first: usize,
+    definition: &AutomationRuleDefinition,
+) -> Result<(), Box<dyn Error>> {
+    let condition = definition.condition.as_ref().map(serde_json::to_string).transpose()?;
+    statement.bind((first, definition.name.trim()))?;
+    statement.bind((first + 1, definition.enabled as i64))?;
+    statement.bind((first + 2, serde_json::to_string(&definition.trigger)?.as_str()))?;
+    statement.bind((first + 3, condition.as_deref()))?;
+    statement.bind((first + 4, serde_json::to_string(&definition.actions)?.as_str()))?;
+    statement.bind((first + 5, definition.cooldown_minutes as i64))?;
+    Ok(())
+}
+
+fn record_firing(rule_id: u64, error: Option<String>) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare("UPDATE server_automation_rules SET last_fired_at = ?, last_error = ? WHERE id = ?")?;
+    statement.bind((1, to_seconds(SystemTime::now()) as i64))?;
+    statement.bind((2, error.as_deref()))?;
+    statement.bind((3, rule_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn read_rule(statement: &sqlite::Statement) -> Result<AutomationRule, Box<dyn Error>> {
+    Ok(AutomationRule {
+        id: statement.read::<i64, _>("id")? as u64,
+        server_id: statement.read::<i64, _>("server_id")? as u64,
+        definition: AutomationRuleDefinition {
+            name: statement.read::<String, _>("name")?,
+            trigger: serde_json::from_str(&statement.read::<String, _>("rule_trigger")?)?,
+            condition: statement
+                .read::<Option<String>, _>("rule_condition")?
+                .map(|condition| serde_json::from_str(&condition))
+                .transpose()?,
+            actions: serde_json::from_str(&statement.read::<String, _>("rule_actions")?)?,
+            enabled: statement.read::<i64, _>("enabled")? != 0,
+            cooldown_minutes: statement.read::<i64, _>("cooldown_minutes")? as u64,
+        },
+        last_fired_at: statement.read::<Option<i64>, _>("last_fired_at")?.map(|at| at as u64),
+        last_error: statement.read::<Option<String>, _>("last_error")?,
+    })
+}
+
+pub trait ServerAutomation {
+    /// Returns the automation rules of the server.
+    fn list_automation_rules(&self) -> Result<Vec<AutomationRule>, Box<dyn Error>>;
+
+    /// Adds an automation rule to the server, validating its condition and actions.
+    fn create_automation_rule(&self, definition: &AutomationRuleDefinition) -> Result<AutomationRule, Box<dyn Error>>;
+}
+
+impl ServerAutomation for Server<u64> {
+    fn list_automation_rules(&self) -> Result<Vec<AutomationRule>, Box<dyn Error>> {
+        list_automation_rules(self.id)
+    }
+
+    fn create_automation_rule(&self, definition: &AutomationRuleDefinition) -> Result<AutomationRule, Box<dyn Error>> {
+        create_automation_rule(self.id, definition)
+    }
+}
@@ -0,0 +1,305 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use log::info;
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlite::State;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Starts every encrypted archive, followed by the format version.
+const MAGIC: &[u8; 8] = b"OBSDBAK\0";
+
+const FORMAT_VERSION: u8 = 1;
+
+/// The PBKDF2-HMAC-SHA256 rounds a passphrase or key file is stretched with.
+const KEY_DERIVATION_ROUNDS: u32 = 600_000;
+
+/// The most rounds the header of a backup may ask for. Newer versions can raise the rounds within
+/// this, while a crafted header cannot keep a restore busy for hours.
+const MAX_KEY_DERIVATION_ROUNDS: u32 = KEY_DERIVATION_ROUNDS * 4;
+
+const SALT_SIZE: usize = 16;
+
+/// The random part of the nonces, the remaining five bytes count the segments and mark the last one.
+const NONCE_PREFIX_SIZE: usize = 7;
+
+/// Archives are encrypted in segments of this size, each with its own authentication tag, so they
+/// can be streamed without ever holding a whole archive in memory.
+const SEGMENT_SIZE: usize = 1024 * 1024;
+
+const TAG_SIZE: usize = 16;
+
+const HEADER_SIZE: usize = MAGIC.len() + 1 + SALT_SIZE + 4 + NONCE_PREFIX_SIZE;
+
+/// What the encryption key of the backups of a server is derived from.
+///
+/// The key is kept with the backup settings of the server, so the manager can restore its own
+/// backups without asking for it. Only the archives, and with them every copy uploaded to a remote
+/// target, are encrypted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EncryptionKey {
+    Passphrase { passphrase: String },
+    /// A file whose whole contents are the secret, on the machine running the manager.
+    Keyfile { path: PathBuf },
+}
+
+impl EncryptionKey {
+    fn material(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let material = match self {
+            EncryptionKey::Passphrase { passphrase } => passphrase.as_bytes().to_vec(),
+            EncryptionKey::Keyfile { path } => {
+                fs::read(path).map_err(|e| format!("Failed to read the key file {:?}: {}", path, e))?
+            }
+        };
+        if material.is_empty() {
+            return Err("The backup encryption key is empty".into());
+        }
+        Ok(material)
+    }
+
+    fn cipher(&self, salt: &[u8], rounds: u32) -> Result<Aes256Gcm, Box<dyn Error>> {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&self.material()?, salt, rounds, &mut key);
+        Ok(Aes256Gcm::new_from_slice(&key).map_err(|e| e.to_string())?)
+    }
+}
+
+/// The nonce of a segment. The last segment is marked, so an archive cut off after a whole segment
+/// does not decrypt as a shorter, valid one.
+fn segment_nonce(prefix: &[u8], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = u8::from(last);
+    nonce
+}
+
+/// Reads until the buffer is full or the reader is exhausted.
+fn read_segment(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Encrypts a file with AES-256-GCM under a key derived from `key` and a random salt.
+///
+/// # Errors
+/// Returns an error if the key cannot be read, or the files cannot be read or written.
+pub(crate) fn encrypt_file(source: &Path, destination: &Path, key: &EncryptionKey) -> Result<(), Box<dyn Error>> {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut prefix);
+    let cipher = key.cipher(&salt, KEY_DERIVATION_ROUNDS)?;
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.push(FORMAT_VERSION);
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&KEY_DERIVATION_ROUNDS.to_be_bytes());
+    header.extend_from_slice(&prefix);
+
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut writer = BufWriter::new(File::create(destination)?);
+    writer.write_all(&header)?;
+    let mut buffer = vec![0u8; SEGMENT_SIZE];
+    let mut counter = 0u32;
+    // The last segment is always shorter than a full one, empty if the file fills the segments exactly
+    loop {
+        let read = read_segment(&mut reader, &mut buffer)?;
+        let last = read < SEGMENT_SIZE;
+        let payload = Payload {
+            msg: &buffer[..read],
+            aad: &header,
+        };
+        let nonce = segment_nonce(&prefix, counter, last);
+        let segment = cipher
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| "Failed to encrypt the backup")?;
+        writer.write_all(&segment)?;
+        if last {
+            break;
+        }
+        counter = counter.checked_add(1).ok_or("The backup is too large to encrypt")?;
+    }
+    writer.into_inner().map_err(|e| e.to_string())?.sync_all()?;
+    Ok(())
+}
+
+/// Decrypts a file written by [`encrypt_file`].
+///
+/// # Errors
+/// Returns an error if the file is not an encrypted backup, the key is wrong, or the file was
+/// corrupted or cut off.
+pub(crate) fn decrypt_file(source: &Path, destination: &Path, key: &EncryptionKey) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut header = [0u8; HEADER_SIZE];
+    if read_segment(&mut reader, &mut header)? < HEADER_SIZE || !header.starts_with(MAGIC) {
+        return Err(format!("{:?} is not an encrypted backup", source).into());
+    }
+    let version = header[MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(format!("Encrypted backups of version {} are not supported", version).into());
+    }
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_SIZE];
+    let prefix = &header[HEADER_SIZE - NONCE_PREFIX_SIZE..];
+    let rounds = header_rounds(&header).map_err(|e| format!("{:?} is not an encrypted backup: {}", source, e))?;
+    let cipher = key.cipher(salt, rounds)?;
+
+    let mut writer = BufWriter::new(File::create(destination)?);
+    let mut buffer = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+    let mut counter = 0u32;
+    loop {
+        let read = read_segment(&mut reader, &mut buffer)?;
+        let last = read < buffer.len();
+        let payload = Payload {
+            msg: &buffer[..read],
+            aad: &header,
+        };
+        let nonce = segment_nonce(prefix, counter, last);
+        let segment = cipher.decrypt(Nonce::from_slice(&nonce), payload).map_err(|_| match counter {
+            0 => "The backup cannot be decrypted, the key is wrong or the archive is corrupted",
+            _ => "The encrypted backup is corrupted or incomplete",
+        })?;
+        writer.write_all(&segment)?;
+        if last {
+            break;
+        }
+        counter = counter.checked_add(1).ok_or("The encrypted backup has too many segments")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the key derivation rounds of the header of an encrypted backup.
+fn header_rounds(header: &[u8; HEADER_SIZE]) -> Result<u32, Box<dyn Error>> {
+    let mut rounds = [0u8; 4];
+    rounds.copy_from_slice(&header[MAGIC.len() + 1 + SALT_SIZE..HEADER_SIZE - NONCE_PREFIX_SIZE]);
+    match u32::from_be_bytes(rounds) {
+        0 => Err("The key is derived with no rounds".into()),
+        rounds if rounds > MAX_KEY_DERIVATION_ROUNDS => Err(format!(
+            "The key is derived with {} rounds, more than the {} a backup may use",
+            rounds, MAX_KEY_DERIVATION_ROUNDS
+        )
+        .into()),
+        rounds => Ok(rounds),
+    }
+}
+
+/// Creates the table holding the encryption keys of the backups of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_backup_encryption_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_backup_encryption` (
+            server_id INTEGER PRIMARY KEY,                              -- The server whose backups are encrypted
+            encryption_key TEXT NOT NULL                                -- The passphrase or key file, as JSON
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Reads the key the backups of a server are encrypted with, `None` if none was set.
+pub fn get_backup_encryption(server_id: u64) -> Result<Option<EncryptionKey>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT encryption_key FROM server_backup_encryption WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(Some(serde_json::from_str(&statement.read::<String, _>("encryption_key")?)?));
+    }
+    Ok(None)
+}
+
+/// Sets the key new encrypted backups of a server are made with, or removes it with `None`.
+///
+/// Backups keep the key they were made with. Restoring those made with an earlier key needs that
+/// key in the restore options.
+///
+/// # Errors
+/// Returns an error if the key is empty or its key file cannot be read, or the key cannot be stored.
+pub fn set_backup_encryption(server_id: u64, key: Option<&EncryptionKey>) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    match key {
+        Some(key) => {
+            key.material()?;
+            let mut statement = conn.prepare(
+                r#"INSERT INTO server_backup_encryption (server_id, encryption_key) VALUES (?, ?)
+                ON CONFLICT(server_id) DO UPDATE SET encryption_key = excluded.encryption_key"#,
+            )?;
+            statement.bind((1, server_id as i64))?;
+            statement.bind((2, serde_json::to_string(key)?.as_str()))?;
+            statement.next()?;
+            info!("Set the backup encryption key of server {}", server_id);
+        }
+        None => {
+            let mut statement = conn.prepare(r#"DELETE FROM server_backup_encryption WHERE server_id = ?"#)?;
+            statement.bind((1, server_id as i64))?;
+            statement.next()?;
+            info!("Removed the backup encryption key of server {}", server_id);
+        }
+    }
+    Ok(())
+}
+
+pub trait ServerBackupEncryption {
+    /// Returns the key the backups of the server are encrypted with.
+    fn get_backup_encryption(&self) -> Result<Option<EncryptionKey>, Box<dyn Error>>;
+
+    /// Sets or removes the key the backups of the server are encrypted with.
+    fn set_backup_encryption(&self, key: Option<&EncryptionKey>) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerBackupEncryption for Server<u64> {
+    fn get_backup_encryption(&self) -> Result<Option<EncryptionKey>, Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        get_backup_encryption(self.id)
+    }
+
+    fn set_backup_encryption(&self, key: Option<&EncryptionKey>) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        set_backup_encryption(self.id, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(rounds: u32) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()] = FORMAT_VERSION;
+        header[MAGIC.len() + 1 + SALT_SIZE..HEADER_SIZE - NONCE_PREFIX_SIZE].copy_from_slice(&rounds.to_be_bytes());
+        header
+    }
+
+    #[test]
+    fn headers_keep_their_rounds() -> Result<(), Box<dyn Error>> {
+        assert_eq!(header_rounds(&header(KEY_DERIVATION_ROUNDS))?, KEY_DERIVATION_ROUNDS);
+        assert_eq!(header_rounds(&header(MAX_KEY_DERIVATION_ROUNDS))?, MAX_KEY_DERIVATION_ROUNDS);
+        Ok(())
+    }
+
+    #[test]
+    fn headers_with_no_or_too_many_rounds_are_refused() {
+        assert!(header_rounds(&header(0)).is_err());
+        assert!(header_rounds(&header(MAX_KEY_DERIVATION_ROUNDS + 1)).is_err());
+        assert!(header_rounds(&header(u32::MAX)).is_err());
+    }
+}
@@ -0,0 +1,910 @@
+use crate::backup_snapshots::chunk_path;
+use crate::backups::{
+    archive_name, backup_directory, chunk_store, emit_kind, get_backup, read_snapshot_index, BackupEventKind,
+    BackupManifest, BackupMode, BackupTrigger, CHUNK_DIRECTORY, MANIFEST_FILE, SNAPSHOT_INDEX_FILE,
+};
+use crate::extensions::backup_backend;
+use crate::file_hash::{hash_reader, to_hex, HashAlgorithm};
+use crate::http_client::agent;
+use crate::permissions::{authorize, Capability};
+use crate::permissions::{authorize, Capability};
+use crate::restart_schedule::civil_from_days;
+use crate::server::Server;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlite::State;
+use ssh2::{HashType, RenameFlags, Session, Sftp};
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long an SFTP server gets to accept the connection or answer a request.
+const SFTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before the first retry, doubled for every further one.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest wait between two retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(120);
+
+/// Sent instead of the hash of the body, so archives can be streamed to S3 instead of being read twice.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The largest object S3 accepts in a single upload.
+const MAX_S3_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// The storage service a remote target uploads to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RemoteBackend {
+    /// Amazon S3 or a compatible object storage, such as MinIO, Backblaze B2 or Cloudflare R2.
+    S3 {
+        /// The endpoint with its scheme, like `https://s3.eu-central-1.amazonaws.com`.
+        endpoint: String,
+        region: String,
+        bucket: String,
+        access_key: String,
+        secret_key: String,
+        /// Addresses the bucket in the path instead of the host name, which most self-hosted stores need.
+        #[serde(default)]
+        path_style: bool,
+    },
+    Sftp {
+        host: String,
+        port: u16,
+        username: String,
+        /// The password, or the passphrase of the private key if one is given.
+        #[serde(default)]
+        password: Option<String>,
+        /// The private key to log in with, on the machine running the manager.
+        #[serde(default)]
+        private_key: Option<PathBuf>,
+        /// The SHA-256 fingerprint of the host key as `ssh-keygen -l` prints it, `SHA256:...`. Any host
+        /// key is accepted without it.
+        #[serde(default)]
+        host_key_fingerprint: Option<String>,
+    },
+    WebDav {
+        /// The collection the backups are uploaded into, like `https://cloud.example.com/remote.php/dav/files/me`.
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// A storage service added by an extension, see [`crate::extensions::BackupBackend`].
+    Extension {
+        /// The name the backend was registered under.
+        backend: String,
+        /// Whatever the backend needs to connect, it checks them itself.
+        #[serde(default)]
+        settings: Value,
+    },
+}
+
+/// Where the backups of a server are copied to after they are created.
+///
+/// Remote copies are laid out like the local backups, `<prefix>/<server id>/<backup id>/...`, with
+/// the chunks of incremental backups shared in `<prefix>/<server id>/.chunks`. Deleting or pruning
+/// a local backup leaves its remote copy alone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteTarget {
+    /// Identifies the target, assigned when it is added.
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    /// Whether new manual and scheduled backups are uploaded automatically.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub backend: RemoteBackend,
+    /// The directory or key prefix the backups are stored under, empty for the top of the storage.
+    #[serde(default)]
+    pub prefix: String,
+    /// The most bytes per second to upload, `None` for no limit. Verification downloads count too.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+    /// How often a failed upload is retried, with the wait doubling between attempts.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Downloads every uploaded file again and compares its size and SHA-256 hash with the local file.
+    #[serde(default = "default_enabled")]
+    pub verify: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl RemoteTarget {
+    /// The key of a file of the backups of a server on the remote.
+    fn key(&self, server_id: u64, parts: &[&str]) -> String {
+        let server = server_id.to_string();
+        let mut key = self.prefix.trim_end_matches('/').to_string();
+        for part in [server.as_str()].iter().chain(parts) {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(part);
+        }
+        key
+    }
+}
+
+/// A backup that was copied to a remote target.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupUpload {
+    pub backup_id: String,
+    pub remote_id: u64,
+    /// When the upload finished, in seconds since the Unix epoch.
+    pub uploaded_at: u64,
+    /// The bytes that were uploaded, not counting chunks the remote already had.
+    pub bytes: u64,
+    pub verified: bool,
+}
+
+/// What an upload of a backup transferred.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadSummary {
+    pub files: u64,
+    pub bytes: u64,
+    /// The chunks of an incremental backup the remote had from earlier uploads.
+    pub skipped_chunks: u64,
+    pub verified: bool,
+}
+
+/// Creates the tables holding the remote targets and the uploads to them.
+///
+/// # Errors
+/// Returns an error if the database connection fails or a table cannot be created.
+pub fn initialize_backup_remote_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_backup_remotes` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the remote target
+            server_id INTEGER NOT NULL,                                 -- The server whose backups are uploaded
+            settings TEXT NOT NULL                                      -- The backend, credentials and limits, as JSON
+        );
+        CREATE TABLE IF NOT EXISTS `server_backup_uploads` (
+            server_id INTEGER NOT NULL,                                 -- The server the backup belongs to
+            backup_id TEXT NOT NULL,                                    -- The uploaded backup
+            remote_id INTEGER NOT NULL,                                 -- The remote target it was uploaded to
+            uploaded_at INTEGER NOT NULL,                               -- Unix time the upload finished
+            bytes INTEGER NOT NULL,                                     -- The bytes transferred
+            verified INTEGER NOT NULL,                                  -- Whether the remote copy was checked
+            PRIMARY KEY (remote_id, backup_id)
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+fn validate_remote(remote: &RemoteTarget) -> Result<(), Box<dyn Error>> {
+    if remote.name.trim().is_empty() {
+        return Err("A remote target needs a name".into());
+    }
+    match &remote.backend {
+        RemoteBackend::S3 { endpoint, bucket, .. } => {
+            if !endpoint.starts_with("https://") && !endpoint.starts_with("http://") {
+                return Err(format!("The S3 endpoint {:?} needs an http:// or https:// scheme", endpoint).into());
+            }
+            if bucket.is_empty() || bucket.contains('/') {
+                return Err(format!("{:?} is not a bucket name", bucket).into());
+            }
+        }
+        RemoteBackend::Sftp {
+            host,
+            password,
+            private_key,
+            ..
+        } => {
+            if host.is_empty() {
+                return Err("An SFTP target needs a host".into());
+            }
+            if password.is_none() && private_key.is_none() {
+                return Err("An SFTP target needs a password or a private key".into());
+            }
+        }
+        RemoteBackend::WebDav { url, .. } => {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err(format!("The WebDAV URL {:?} needs an http:// or https:// scheme", url).into());
+            }
+        }
+        RemoteBackend::Extension { backend, settings } => backup_backend(backend)?.validate(settings)?,
+    }
+    Ok(())
+}
+
+/// Lists the remote targets of a server.
+pub fn list_backup_remotes(server_id: u64) -> Result<Vec<RemoteTarget>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_backup_remotes WHERE server_id = ? ORDER BY id"#)?;
+    statement.bind((1, server_id as i64))?;
+    let mut remotes = Vec::new();
+    while let State::Row = statement.next()? {
+        remotes.push(read_remote(&mut statement)?);
+    }
+    Ok(remotes)
+}
+
+/// Reads a remote target of a server.
+pub fn get_backup_remote(server_id: u64, remote_id: u64) -> Result<RemoteTarget, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_backup_remotes WHERE server_id = ? AND id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, remote_id as i64))?;
+    if let State::Row = statement.next()? {
+        return read_remote(&mut statement);
+    }
+    Err(format!("Server {} has no remote target {}", server_id, remote_id).into())
+}
+
+fn read_remote(statement: &mut sqlite::Statement) -> Result<RemoteTarget, Box<dyn Error>> {
+    let mut remote: RemoteTarget = serde_json::from_str(&statement.read::<String, _>("settings")?)?;
+    remote.id = statement.read::<i64, _>("id")? as u64;
+    Ok(remote)
+}
+
+/// Adds a remote target to a server.
+///
+/// # Returns
+/// The identifier of the new target.
+///
+/// # Errors
+/// Returns an error if the target is incomplete or cannot be stored.
+pub fn add_backup_remote(server_id: u64, remote: &RemoteTarget) -> Result<u64, Box<dyn Error>> {
+    validate_remote(remote)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"INSERT INTO server_backup_remotes (server_id, settings) VALUES (?, ?)"#)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, serde_json::to_string(remote)?.as_str()))?;
+    statement.next()?;
+    let id = last_inserted_id("server_backup_remotes")?;
+    info!("Added the remote backup target {:?} to server {}", remote.name, server_id);
+    Ok(id)
+}
+
+/// Replaces the settings of the remote target of a server with the identifier of `remote`.
+pub fn update_backup_remote(server_id: u64, remote: &RemoteTarget) -> Result<(), Box<dyn Error>> {
+    validate_remote(remote)?;
+    get_backup_remote(server_id, remote.id)?;
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare(r#"UPDATE server_backup_remotes SET settings = ? WHERE server_id = ? AND id = ?"#)?;
+    statement.bind((1, serde_json::to_string(remote)?.as_str()))?;
+    statement.bind((2, server_id as i64))?;
+    statement.bind((3, remote.id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Removes a remote target from a server. The backups already uploaded to it stay there.
+pub fn remove_backup_remote(server_id: u64, remote_id: u64) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    for query in [
+        r#"DELETE FROM server_backup_remotes WHERE server_id = ? AND id = ?"#,
+        r#"DELETE FROM server_backup_uploads WHERE server_id = ? AND remote_id = ?"#,
+    ] {
+        let mut statement = conn.prepare(query)?;
+        statement.bind((1, server_id as i64))?;
+        statement.bind((2, remote_id as i64))?;
+        statement.next()?;
+    }
+    info!("Removed the remote backup target {} of server {}", remote_id, server_id);
+    Ok(())
+}
+
+/// Lists the remote targets a backup was uploaded to.
+pub fn list_backup_uploads(server_id: u64, backup_id: &str) -> Result<Vec<BackupUpload>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"SELECT * FROM server_backup_uploads WHERE server_id = ? AND backup_id = ? ORDER BY remote_id"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, backup_id))?;
+    let mut uploads = Vec::new();
+    while let State::Row = statement.next()? {
+        uploads.push(BackupUpload {
+            backup_id: statement.read::<String, _>("backup_id")?,
+            remote_id: statement.read::<i64, _>("remote_id")? as u64,
+            uploaded_at: statement.read::<i64, _>("uploaded_at")?.max(0) as u64,
+            bytes: statement.read::<i64, _>("bytes")?.max(0) as u64,
+            verified: statement.read::<i64, _>("verified")? != 0,
+        });
+    }
+    Ok(uploads)
+}
+
+fn record_upload(
+    server_id: u64,
+    backup_id: &str,
+    remote_id: u64,
+    summary: &UploadSummary,
+) -> Result<(), Box<dyn Error>> {
+    let query = r#"
+INSERT INTO server_backup_uploads (server_id, backup_id, remote_id, uploaded_at, bytes, verified)
+VALUES (?, ?, ?, ?, ?, ?)
+ON CONFLICT(remote_id, backup_id) DO UPDATE SET
+    uploaded_at = excluded.uploaded_at,
+    bytes = excluded.bytes,
+    verified = excluded.verified
+"#;
+    let uploaded_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, backup_id))?;
+    statement.bind((3, remote_id as i64))?;
+    statement.bind((4, uploaded_at as i64))?;
+    statement.bind((5, summary.bytes as i64))?;
+    statement.bind((6, summary.verified as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// The operations uploading a backup needs from a storage service. Keys are `/` separated paths.
+pub trait RemoteStorage {
+    fn put(&mut self, key: &str, body: &mut dyn Read, size: u64) -> Result<(), Box<dyn Error>>;
+
+    /// The size of a stored file, `None` if there is none under the key.
+    fn size(&mut self, key: &str) -> Result<Option<u64>, Box<dyn Error>>;
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>, Box<dyn Error>>;
+}
+
+fn connect(backend: &RemoteBackend) -> Result<Box<dyn RemoteStorage>, Box<dyn Error>> {
+    Ok(match backend {
+        RemoteBackend::S3 {
+            endpoint,
+            region,
+            bucket,
+            access_key,
+            secret_key,
+            path_style,
+        } => Box::new(S3Storage {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            region: region.clone(),
+            bucket: bucket.clone(),
+            access_key: access_key.clone(),
+            secret_key: secret_key.clone(),
+            path_style: *path_style,
+        }),
+        RemoteBackend::Sftp {
+            host,
+            port,
+            username,
+            password,
+            private_key,
+            host_key_fingerprint,
+        } => Box::new(SftpStorage::connect(
+            host,
+            *port,
+            username,
+            password.as_deref(),
+            private_key.as_deref(),
+            host_key_fingerprint.as_deref(),
+        )?),
+        RemoteBackend::WebDav { url, username, password } => Box::new(WebDavStorage {
+            url: url.trim_end_matches('/').to_string(),
+            authorization: username.as_ref().map(|username| {
+                let credentials = format!("{}:{}", username, password.as_deref().unwrap_or_default());
+                format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+            }),
+            collections: HashSet::new(),
+        }),
+        RemoteBackend::Extension { backend, settings } => backup_backend(backend)?.connect(settings)?,
+    })
+}
+
+/// Percent-encodes a key for a URL, keeping the `/` between its parts.
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Turns an error status into an error carrying the body, where S3 and WebDAV servers explain what went wrong.
+fn describe(error: ureq::Error) -> Box<dyn Error> {
+    match error {
+        ureq::Error::Status(status, response) => {
+            format!("HTTP {}: {}", status, response.into_string().unwrap_or_default().trim()).into()
+        }
+        e => e.into(),
+    }
+}
+
+fn content_length(response: &ureq::Response) -> Option<u64> {
+    response.header("Content-Length").and_then(|length| length.parse().ok())
+}
+
+struct S3Storage {
+    endpoint: String,
+    region: String,
+    bucket: String,
+    access_key: String,
+    secret_key: String,
+    path_style: bool,
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| e.to_string())?;
+    mac.update(data.as_bytes());
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+impl S3Storage {
+    /// Builds a request signed with AWS Signature Version 4.
+    fn request(&self, method: &str, key: &str) -> Result<ureq::Request, Box<dyn Error>> {
+        let (scheme, authority) = self
+            .endpoint
+            .split_once("://")
+            .ok_or_else(|| format!("The S3 endpoint {:?} has no scheme", self.endpoint))?;
+        let key = encode_key(key.trim_start_matches('/'));
+        let (host, path) = if self.path_style {
+            (authority.to_string(), format!("/{}/{}", self.bucket, key))
+        } else {
+            (format!("{}.{}", self.bucket, authority), format!("/{}", key))
+        };
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let (year, month, day) = civil_from_days(now / 86_400);
+        let date = format!("{:04}{:02}{:02}", year, month, day);
+        let seconds = now % 86_400;
+        let timestamp = format!("{}T{:02}{:02}{:02}Z", date, seconds / 3600, seconds % 3600 / 60, seconds % 60);
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, UNSIGNED_PAYLOAD, timestamp, signed_headers, UNSIGNED_PAYLOAD
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            to_hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in [date.as_str(), self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part)?;
+        }
+        let signature = to_hex(&hmac_sha256(&signing_key, &string_to_sign)?);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        Ok(agent()
+            .request(method, &format!("{}://{}{}", scheme, host, path))
+            .set("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .set("x-amz-date", &timestamp)
+            .set("Authorization", &authorization))
+    }
+}
+
+impl RemoteStorage for S3Storage {
+    fn put(&mut self, key: &str, body: &mut dyn Read, size: u64) -> Result<(), Box<dyn Error>> {
+        if size > MAX_S3_OBJECT_SIZE {
+            return Err(format!("{} is {} bytes, larger than S3 accepts in one upload", key, size).into());
+        }
+        self.request("PUT", key)?
+            .set("Content-Length", &size.to_string())
+            .send(body)
+            .map_err(describe)?;
+        Ok(())
+    }
+
+    fn size(&mut self, key: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.request("HEAD", key)?.call() {
+            Ok(response) => Ok(content_length(&response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(describe(e)),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        Ok(self.request("GET", key)?.call().map_err(describe)?.into_reader())
+    }
+}
+
+struct WebDavStorage {
+    url: String,
+    authorization: Option<String>,
+    /// The collections known to exist, so they are not created again for every file.
+    collections: HashSet<String>,
+}
+
+impl WebDavStorage {
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let url = format!("{}/{}", self.url, encode_key(key.trim_start_matches('/')));
+        let request = agent().request(method, &url);
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    /// Creates the collections a key is in, WebDAV servers do not create them on upload.
+    fn create_collections(&mut self, key: &str) -> Result<(), Box<dyn Error>> {
+        let parts: Vec<&str> = key.trim_start_matches('/').split('/').collect();
+        for end in 1..parts.len() {
+            let collection = parts[..end].join("/");
+            if self.collections.contains(&collection) {
+                continue;
+            }
+            match self.request("MKCOL", &format!("{}/", collection)).call() {
+                // An existing collection is answered with 405 Method Not Allowed
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => return Err(describe(e)),
+            }
+            self.collections.insert(collection);
+        }
+        Ok(())
+    }
+}
+
+impl RemoteStorage for WebDavStorage {
+    fn put(&mut self, key: &str, body: &mut dyn Read, size: u64) -> Result<(), Box<dyn Error>> {
+        self.create_collections(key)?;
+        self.request("PUT", key)
+            .set("Content-Length", &size.to_string())
+            .send(body)
+            .map_err(describe)?;
+        Ok(())
+    }
+
+    fn size(&mut self, key: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.request("HEAD", key).call() {
+            Ok(response) => Ok(content_length(&response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(describe(e)),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        Ok(self.request("GET", key).call().map_err(describe)?.into_reader())
+    }
+}
+
+struct SftpStorage {
+    /// Kept for the lifetime of the SFTP channel that runs on it.
+    _session: Session,
+    sftp: Sftp,
+    directories: HashSet<PathBuf>,
+}
+
+impl SftpStorage {
+    fn connect(
+        host: &str,
+        port: u16,
+        username: &str,
+        password: Option<&str>,
+        private_key: Option<&Path>,
+        host_key_fingerprint: Option<&str>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let address = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("{} did not resolve to any address", host))?;
+        let stream = TcpStream::connect_timeout(&address, SFTP_TIMEOUT)?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(stream);
+        session.set_timeout(SFTP_TIMEOUT.as_millis() as u32);
+        session.handshake()?;
+
+        let fingerprint = session
+            .host_key_hash(HashType::Sha256)
+            .map(|hash| base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash))
+            .ok_or("The SFTP server sent no host key")?;
+        match host_key_fingerprint {
+            Some(expected) if expected.trim().trim_start_matches("SHA256:") != fingerprint => {
+                return Err(format!("The host key of {} is SHA256:{}, not {}", host, fingerprint, expected).into());
+            }
+            Some(_) => {}
+            None => warn!("Accepting the host key SHA256:{} of {} without a fingerprint to check", fingerprint, host),
+        }
+
+        match private_key {
+            Some(key) => session.userauth_pubkey_file(username, None, key, password)?,
+            None => session.userauth_password(username, password.unwrap_or_default())?,
+        }
+        if !session.authenticated() {
+            return Err(format!("{} rejected the login of {}", host, username).into());
+        }
+        let sftp = session.sftp()?;
+        Ok(Self {
+            _session: session,
+            sftp,
+            directories: HashSet::new(),
+        })
+    }
+}
+
+impl RemoteStorage for SftpStorage {
+    fn put(&mut self, key: &str, body: &mut dyn Read, _size: u64) -> Result<(), Box<dyn Error>> {
+        let path = Path::new(key);
+        if let Some(parent) = path.parent() {
+            let mut directory = PathBuf::new();
+            for component in parent.components() {
+                directory.push(component);
+                // Creating a directory that exists fails, only a failed upload is an error
+                if self.directories.insert(directory.clone()) && self.sftp.stat(&directory).is_err() {
+                    let _ = self.sftp.mkdir(&directory, 0o755);
+                }
+            }
+        }
+        // Uploaded under a temporary name, so an interrupted upload is never mistaken for a complete file
+        let partial = PathBuf::from(format!("{}.partial", key));
+        let mut file = self.sftp.create(&partial)?;
+        io::copy(body, &mut file)?;
+        drop(file);
+        self.sftp.rename(&partial, path, Some(RenameFlags::OVERWRITE))?;
+        Ok(())
+    }
+
+    fn size(&mut self, key: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.sftp.stat(Path::new(key)) {
+            Ok(stat) => Ok(stat.size),
+            // LIBSSH2_FX_NO_SUCH_FILE
+            Err(e) if matches!(e.code(), ssh2::ErrorCode::SFTP(2)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        Ok(Box::new(self.sftp.open(Path::new(key))?))
+    }
+}
+
+/// Holds transfers to at most the bandwidth limit by sleeping whenever they get ahead of it.
+struct Throttled<R> {
+    inner: R,
+    limit: Option<u64>,
+    started: Instant,
+    transferred: u64,
+}
+
+impl<R: Read> Throttled<R> {
+    fn new(inner: R, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            limit: limit.filter(|limit| *limit > 0),
+            started: Instant::now(),
+            transferred: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.transferred += read as u64;
+        if let Some(limit) = self.limit {
+            let due = Duration::from_secs_f64(self.transferred as f64 / limit as f64);
+            if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// A connection to a remote target that retries failed operations on a fresh connection.
+struct Connection<'a> {
+    remote: &'a RemoteTarget,
+    storage: Option<Box<dyn RemoteStorage>>,
+}
+
+impl Connection<'_> {
+    fn connected(&mut self) -> Result<&mut dyn RemoteStorage, Box<dyn Error>> {
+        if self.storage.is_none() {
+            self.storage = Some(connect(&self.remote.backend)?);
+        }
+        self.storage.as_deref_mut().ok_or_else(|| "Not connected to the remote target".into())
+    }
+
+    fn run<T>(
+        &mut self,
+        operation: &str,
+        mut attempt: impl FnMut(&mut dyn RemoteStorage) -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut failures = 0;
+        loop {
+            match self.connected().and_then(&mut attempt) {
+                Ok(value) => return Ok(value),
+                Err(e) if failures < self.remote.retries => {
+                    let delay = RETRY_DELAY.saturating_mul(2u32.saturating_pow(failures)).min(MAX_RETRY_DELAY);
+                    failures += 1;
+                    warn!(
+                        "Failed to {} on {:?}, retrying in {:?} ({} of {}): {}",
+                        operation, self.remote.name, delay, failures, self.remote.retries, e
+                    );
+                    self.storage = None;
+                    thread::sleep(delay);
+                }
+                Err(e) => return Err(format!("Failed to {} on {:?}: {}", operation, self.remote.name, e).into()),
+            }
+        }
+    }
+}
+
+/// Uploads a file and, if the target verifies uploads, checks that the remote copy matches it.
+/// A copy that does not match counts as a failed attempt and is uploaded again.
+fn upload_file(connection: &mut Connection, key: &str, local: &Path) -> Result<u64, Box<dyn Error>> {
+    let size = fs::metadata(local)?.len();
+    let remote = connection.remote;
+    let expected = if remote.verify {
+        Some(hash_reader(File::open(local)?, HashAlgorithm::Sha256)?)
+    } else {
+        None
+    };
+    connection.run(&format!("upload {}", key), |storage| {
+        debug!("Uploading {:?} to {} on {:?}", local, key, remote.name);
+        storage.put(key, &mut Throttled::new(File::open(local)?, remote.bandwidth_limit), size)?;
+        let Some(expected) = &expected else {
+            return Ok(());
+        };
+        let stored = storage.size(key)?.ok_or("the uploaded file is missing")?;
+        if stored != size {
+            return Err(format!("the uploaded file has {} bytes instead of {}", stored, size).into());
+        }
+        let actual = hash_reader(Throttled::new(storage.get(key)?, remote.bandwidth_limit), HashAlgorithm::Sha256)?;
+        if actual != *expected {
+            return Err(format!("the uploaded file has the SHA-256 hash {} instead of {}", actual, expected).into());
+        }
+        Ok(())
+    })?;
+    Ok(size)
+}
+
+/// Uploads a backup of a server to a remote target, blocking until it is uploaded and verified.
+///
+/// The manifest is uploaded last, so a remote backup with a `backup.json` is complete. Incremental
+/// backups upload the chunks the remote does not have yet, from uploads of earlier backups.
+///
+/// # Errors
+/// Returns an error if the backup does not exist, or a file could not be uploaded or verified
+/// within the retries of the target.
+pub fn upload_backup(server_id: u64, backup_id: &str, remote: &RemoteTarget) -> Result<UploadSummary, Box<dyn Error>> {
+    let manifest = get_backup(server_id, backup_id)?;
+    let directory = backup_directory(server_id).join(&manifest.id);
+    let mut connection = Connection { remote, storage: None };
+    let mut summary = UploadSummary {
+        files: 0,
+        bytes: 0,
+        skipped_chunks: 0,
+        verified: remote.verify,
+    };
+    info!("Uploading backup {} of server {} to {:?}", backup_id, server_id, remote.name);
+
+    let mut files = Vec::new();
+    match manifest.mode {
+        BackupMode::Full => files.push(archive_name(manifest.format, manifest.encrypted)),
+        BackupMode::Incremental => {
+            let index = read_snapshot_index(server_id, backup_id)?;
+            let hashes: BTreeSet<&String> = index.files.iter().flat_map(|file| &file.chunks).collect();
+            let store = chunk_store(server_id);
+            for hash in hashes {
+                let local = chunk_path(&store, hash);
+                let key = remote.key(server_id, &[CHUNK_DIRECTORY, hash.get(..2).unwrap_or_default(), hash.as_str()]);
+                // Chunks are named by their contents, one with the right size is the same chunk
+                let size = fs::metadata(&local)?.len();
+                if connection.run(&format!("check {}", key), |storage| storage.size(&key))? == Some(size) {
+                    summary.skipped_chunks += 1;
+                    continue;
+                }
+                summary.bytes += upload_file(&mut connection, &key, &local)?;
+                summary.files += 1;
+            }
+            files.push(SNAPSHOT_INDEX_FILE);
+        }
+    }
+    files.push(MANIFEST_FILE);
+    for name in files {
+        let key = remote.key(server_id, &[backup_id, name]);
+        summary.bytes += upload_file(&mut connection, &key, &directory.join(name))?;
+        summary.files += 1;
+    }
+
+    record_upload(server_id, backup_id, remote.id, &summary)?;
+    info!(
+        "Uploaded backup {} of server {} to {:?}, {} bytes in {} files",
+        backup_id, server_id, remote.name, summary.bytes, summary.files
+    );
+    Ok(summary)
+}
+
+/// Uploads a backup to every enabled remote target of a server, one after another in the background.
+/// Backups made before a restore stay local, they only exist to undo the restore.
+pub(crate) fn upload_to_remotes(manifest: &BackupManifest) {
+    if manifest.trigger == BackupTrigger::BeforeRestore {
+        return;
+    }
+    let (server_id, backup_id) = (manifest.server_id, manifest.id.clone());
+    thread::spawn(move || {
+        let remotes = match list_backup_remotes(server_id) {
+            Ok(remotes) => remotes,
+            Err(e) => {
+                error!("Failed to read the remote backup targets of server {}: {}", server_id, e);
+                return;
+            }
+        };
+        for remote in remotes.iter().filter(|remote| remote.enabled) {
+            emit_kind(server_id, &backup_id, BackupEventKind::Uploading, Some(remote.name.clone()));
+            match upload_backup(server_id, &backup_id, remote) {
+                Ok(_) => emit_kind(server_id, &backup_id, BackupEventKind::Uploaded, Some(remote.name.clone())),
+                Err(e) => {
+                    error!("Failed to upload backup {} of server {}: {}", backup_id, server_id, e);
+                    let message = format!("{}: {}", remote.name, e);
+                    emit_kind(server_id, &backup_id, BackupEventKind::UploadFailed, Some(message));
+                }
+            }
+        }
+    });
+}
+
+pub trait ServerBackupRemotes {
+    /// Lists the remote targets the backups of the server are uploaded to.
+    fn list_backup_remotes(&self) -> Result<Vec<RemoteTarget>, Box<dyn Error>>;
+
+    /// Adds a remote target, returning its identifier.
+    fn add_backup_remote(&self, remote: &RemoteTarget) -> Result<u64, Box<dyn Error>>;
+
+    /// Replaces the settings of a remote target.
+    fn update_backup_remote(&self, remote: &RemoteTarget) -> Result<(), Box<dyn Error>>;
+
+    /// Removes a remote target, leaving the backups uploaded to it in place.
+    fn remove_backup_remote(&self, remote_id: u64) -> Result<(), Box<dyn Error>>;
+
+    /// Uploads a backup of the server to one of its remote targets now.
+    fn upload_backup(&self, backup_id: &str, remote_id: u64) -> Result<UploadSummary, Box<dyn Error>>;
+
+    /// Lists the remote targets a backup of the server was uploaded to.
+    fn list_backup_uploads(&self, backup_id: &str) -> Result<Vec<BackupUpload>, Box<dyn Error>>;
+}
+
+impl ServerBackupRemotes for Server<u64> {
+    fn list_backup_remotes(&self) -> Result<Vec<RemoteTarget>, Box<dyn Error>> {
+        list_backup_remotes(self.id)
+    }
+
+    fn add_backup_remote(&self, remote: &RemoteTarget) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        add_backup_remote(self.id, remote)
+    }
+
+    fn update_backup_remote(&self, remote: &RemoteTarget) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        update_backup_remote(self.id, remote)
+    }
+
+    fn remove_backup_remote(&self, remote_id: u64) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        remove_backup_remote(self.id, remote_id)
+    }
+
+    fn upload_backup(&self, backup_id: &str, remote_id: u64) -> Result<UploadSummary, Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        upload_backup(self.id, backup_id, &get_backup_remote(self.id, remote_id)?)
+    }
+
+    fn list_backup_uploads(&self, backup_id: &str) -> Result<Vec<BackupUpload>, Box<dyn Error>> {
+        list_backup_uploads(self.id, backup_id)
+    }
+}
@@ -0,0 +1,348 @@
+use crate::archive_entries::ArchiveEntries;
+use crate::archive_extractor::{extract_archive, safe_entry_path, ExtractionOptions, OverwritePolicy};
+use crate::audit_log::{audit, AuditAction};
+use crate::backup_encryption::{decrypt_file, get_backup_encryption, EncryptionKey};
+use crate::backup_snapshots::{restore_snapshot, SnapshotIndex};
+use crate::backups::{
+    archive_name, chunk_store, claim_backups, create_backup, get_backup, get_backup_archive, read_snapshot_index,
+    release_backups, BackupManifest, BackupMode, BackupOptions, BackupTarget, BackupTrigger, MANIFEST_FILE,
+};
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_filesystem::ServerFilesystem;
+use crate::server_ping::get_live_status;
+use crate::server_process::ServerProcess;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A file or directory inside a backup.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupEntry {
+    pub name: String,
+    /// The path inside the backup, which is also the path relative to the server directory.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// The uncompressed size, the combined size of the contents for directories.
+    pub size: u64,
+}
+
+/// The contents of a single directory inside a backup.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupContents {
+    pub backup_id: String,
+    /// The directory being listed, `""` for the top of the backup.
+    pub path: PathBuf,
+    pub parent: Option<PathBuf>,
+    pub entries: Vec<BackupEntry>,
+}
+
+/// What a restore brings back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestoreOptions {
+    /// The files and directories to restore, like `world` or `server.properties`. Everything in
+    /// the backup is restored if it is empty.
+    pub paths: Vec<PathBuf>,
+    /// Removes the current files at the restored paths first, so nothing created after the backup
+    /// is left mixed into a restored world.
+    pub clean: bool,
+    /// Backs up the files the restore is about to replace first, so the restore can be undone.
+    pub safety_backup: bool,
+    /// The key an encrypted backup was made with, if the server has a different key by now.
+    pub key: Option<EncryptionKey>,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        Self {
+            paths: Vec::new(),
+            clean: true,
+            safety_backup: true,
+            key: None,
+        }
+    }
+}
+
+/// The outcome of a restore.
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreSummary {
+    /// The paths that were restored, relative to the server directory.
+    pub paths: Vec<PathBuf>,
+    /// The number of files and directories written.
+    pub entries: u64,
+    pub bytes: u64,
+    /// The backup of the replaced files, `None` if none was made or there was nothing to replace.
+    pub safety_backup: Option<String>,
+}
+
+/// Lists the direct children of a directory in a snapshot, summing up the sizes of directories.
+fn browse_snapshot(index: &SnapshotIndex, path: &Path) -> Vec<BackupEntry> {
+    let mut children: BTreeMap<String, BackupEntry> = BTreeMap::new();
+    let directories = index.directories.iter().map(|directory| (directory, true, 0));
+    let files = index.files.iter().map(|file| (&file.path, false, file.size));
+    for (entry_path, is_dir, size) in directories.chain(files) {
+        let Ok(remainder) = Path::new(entry_path).strip_prefix(path) else {
+            continue;
+        };
+        let mut components = remainder.components();
+        let Some(first) = components.next() else {
+            continue;
+        };
+        let name = first.as_os_str().to_string_lossy().to_string();
+        let nested = components.next().is_some();
+        let child = children.entry(name.clone()).or_insert_with(|| BackupEntry {
+            name: name.clone(),
+            path: path.join(&name),
+            is_dir: false,
+            size: 0,
+        });
+        child.is_dir |= is_dir || nested;
+        child.size += size;
+    }
+    children.into_values().collect()
+}
+
+/// The archive of a full backup, sandboxed to the directory of the backup for the archive readers.
+///
+/// Encrypted archives are decrypted into a temporary file next to the archive, which is removed
+/// again when this is dropped.
+struct OpenedArchive {
+    archive: SandboxedPath,
+    decrypted: Option<PathBuf>,
+}
+
+impl Drop for OpenedArchive {
+    fn drop(&mut self) {
+        if let Some(decrypted) = &self.decrypted {
+            let _ = fs::remove_file(decrypted);
+        }
+    }
+}
+
+fn open_archive(manifest: &BackupManifest, key: Option<&EncryptionKey>) -> Result<OpenedArchive, Box<dyn Error>> {
+    let archive = get_backup_archive(manifest.server_id, &manifest.id)?;
+    let directory = archive.parent().ok_or("The backup archive has no directory")?;
+    if !manifest.encrypted {
+        return Ok(OpenedArchive {
+            archive: SandboxedPath::new(directory, archive.file_name().unwrap_or_default())?,
+            decrypted: None,
+        });
+    }
+
+    let key = match key {
+        Some(key) => key.clone(),
+        None => get_backup_encryption(manifest.server_id)?
+            .ok_or_else(|| format!("Backup {} is encrypted, but its server has no encryption key", manifest.id))?,
+    };
+    // Named after the plain archive, which is how the archive readers tell the format
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let name = format!("decrypted-{}-{}", nanos, archive_name(manifest.format, false));
+    let opened = OpenedArchive {
+        archive: SandboxedPath::new(directory, &name)?,
+        decrypted: Some(directory.join(&name)),
+    };
+    decrypt_file(&archive, &opened.archive.path(), &key)?;
+    Ok(opened)
+}
+
+/// Lists a directory inside a backup without restoring anything.
+///
+/// Encrypted backups are decrypted with the current key of the server for every listing.
+///
+/// # Arguments
+/// * `server_id` - The server the backup belongs to.
+/// * `backup_id` - The backup to browse.
+/// * `path` - The directory inside the backup, `""` for the top.
+///
+/// # Errors
+/// Returns an error if the acting user may not manage the backups of the server, or the backup
+/// cannot be read.
+pub fn browse_backup(
+    server_id: u64,
+    backup_id: &str,
+    path: impl AsRef<Path>,
+) -> Result<BackupContents, Box<dyn Error>> {
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::ManageBackups)?;
+    let manifest = get_backup(server_id, backup_id)?;
+    let path = safe_entry_path(path.as_ref())?;
+    let mut entries = match manifest.mode {
+        BackupMode::Full => {
+            ArchiveEntries::read(&open_archive(&manifest, None)?.archive, &path)?
+                .entries
+                .into_iter()
+                .map(|entry| BackupEntry {
+                    name: entry.name,
+                    path: entry.path,
+                    is_dir: entry.is_dir,
+                    size: entry.size,
+                })
+                .collect()
+        }
+        BackupMode::Incremental => browse_snapshot(&read_snapshot_index(server_id, backup_id)?, &path),
+    };
+    // The manifest inside full backups is not a file of the server
+    if path.as_os_str().is_empty() && manifest.mode == BackupMode::Full {
+        entries.retain(|entry| entry.path.as_path() != Path::new(MANIFEST_FILE));
+    }
+    Ok(BackupContents {
+        backup_id: backup_id.to_string(),
+        parent: path.parent().map(Path::to_path_buf),
+        path,
+        entries,
+    })
+}
+
+/// Checks that every requested path is in the backup, and picks everything in it if none were requested.
+fn resolve_restore_paths(manifest: &BackupManifest, paths: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if paths.is_empty() {
+        let top = browse_backup(manifest.server_id, &manifest.id, "")?;
+        return Ok(top.entries.into_iter().map(|entry| entry.path).collect());
+    }
+    let mut resolved = Vec::new();
+    for path in paths {
+        let path = safe_entry_path(path)?;
+        let name = path.file_name().ok_or("The whole backup is restored by leaving the paths empty")?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let listing = browse_backup(manifest.server_id, &manifest.id, parent)?;
+        if !listing.entries.iter().any(|entry| entry.name == name.to_string_lossy()) {
+            return Err(format!("Backup {} does not contain {:?}", manifest.id, path).into());
+        }
+        // A path inside another requested path is restored with it
+        if !resolved.iter().any(|other: &PathBuf| path.starts_with(other)) {
+            resolved.retain(|other: &PathBuf| !other.starts_with(&path));
+            resolved.push(path);
+        }
+    }
+    Ok(resolved)
+}
+
+/// Restores a backup of a server, or only some of its files and directories.
+///
+/// The server has to be stopped, a running server would overwrite the restored world with the
+/// one it has loaded. Before anything is replaced, the current state of the restored paths is
+/// backed up with the `before_restore` trigger, unless `safety_backup` is turned off.
+///
+/// Encrypted backups are decrypted with the key in the options, or else the current key of the server.
+///
+/// # Errors
+/// Returns an error if the server is running, a path is not in the backup, the safety backup
+/// fails, or the backup cannot be extracted.
+pub fn restore_backup(
+    server: &Server<u64>,
+    backup_id: &str,
+    options: &RestoreOptions,
+) -> Result<RestoreSummary, Box<dyn Error>> {
+    authorize(server, Capability::ManageBackups)?;
+    let online = get_live_status(server.id).is_some_and(|status| status.ping.is_some());
+    if server.is_server_running() || online {
+        return Err(format!("Server {:?} has to be stopped before a backup can be restored", server.name).into());
+    }
+    let manifest = get_backup(server.id, backup_id)?;
+    let paths = resolve_restore_paths(&manifest, &options.paths)?;
+    let root = server.sandbox("")?;
+
+    let existing: Vec<PathBuf> = paths
+        .iter()
+        .filter(|path| root.join(path).is_ok_and(|current| fs::symlink_metadata(current.path()).is_ok()))
+        .cloned()
+        .collect();
+    let safety_backup = if options.safety_backup && !existing.is_empty() {
+        let safety_options = BackupOptions {
+            targets: existing.iter().map(|path| BackupTarget::Path { path: path.clone() }).collect(),
+            mode: BackupMode::Full,
+            ..BackupOptions::default()
+        };
+        Some(create_backup(server, &safety_options, BackupTrigger::BeforeRestore)?.id)
+    } else {
+        None
+    };
+
+    if !claim_backups(server.id)? {
+        return Err(format!("A backup of server {:?} is running, try again once it finished", server.name).into());
+    }
+    let result = extract_paths(&root, &manifest, &paths, &existing, options);
+    release_backups(server.id);
+    let (entries, bytes) = result?;
+
+    info!("Restored {:?} of server {:?} from backup {}", paths, server.name, backup_id);
+    let before = safety_backup.as_ref().map(|backup| format!("Kept in safety backup {}", backup));
+    let after = format!("Restored {:?}, {} entries, {} bytes", paths, entries, bytes);
+    audit(server.id, AuditAction::BackupRestored, backup_id, before, Some(after));
+    Ok(RestoreSummary {
+        paths,
+        entries,
+        bytes,
+        safety_backup,
+    })
+}
+
+fn extract_paths(
+    root: &SandboxedPath,
+    manifest: &BackupManifest,
+    paths: &[PathBuf],
+    existing: &[PathBuf],
+    options: &RestoreOptions,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    // Decrypted before anything is removed, so a wrong key leaves the current files alone
+    let archive = match manifest.mode {
+        BackupMode::Full => Some(open_archive(manifest, options.key.as_ref())?),
+        BackupMode::Incremental => None,
+    };
+    if options.clean {
+        for path in existing {
+            let current = root.join(path)?;
+            if current.path().is_dir() {
+                fs::remove_dir_all(current.path())?;
+            } else {
+                fs::remove_file(current.path())?;
+            }
+        }
+    }
+
+    match archive {
+        Some(opened) => {
+            let options = ExtractionOptions {
+                overwrite: OverwritePolicy::Replace,
+                include: paths.to_vec(),
+            };
+            let summary = extract_archive(&opened.archive, root, &options, |_| {})?;
+            Ok((summary.extracted, summary.bytes_written))
+        }
+        None => {
+            let mut index = read_snapshot_index(manifest.server_id, &manifest.id)?;
+            let included = |entry: &str| paths.iter().any(|path| Path::new(entry).starts_with(path));
+            index.directories.retain(|directory| included(directory));
+            index.files.retain(|file| included(&file.path));
+            let summary = restore_snapshot(&index, &chunk_store(manifest.server_id), root, |_| {})?;
+            Ok((summary.entries, summary.bytes))
+        }
+    }
+}
+
+pub trait ServerBackupRestore {
+    /// Lists a directory inside a backup of the server, `""` for the top of the backup.
+    fn browse_backup(&self, backup_id: &str, path: impl AsRef<Path>) -> Result<BackupContents, Box<dyn Error>>;
+
+    /// Restores a backup of the server, either whole or just the paths in the options.
+    ///
+    /// # Errors
+    /// Returns an error if the server is running or the backup cannot be restored.
+    fn restore_backup(&self, backup_id: &str, options: &RestoreOptions) -> Result<RestoreSummary, Box<dyn Error>>;
+}
+
+impl ServerBackupRestore for Server<u64> {
+    fn browse_backup(&self, backup_id: &str, path: impl AsRef<Path>) -> Result<BackupContents, Box<dyn Error>> {
+        browse_backup(self.id, backup_id, path)
+    }
+
+    fn restore_backup(&self, backup_id: &str, options: &RestoreOptions) -> Result<RestoreSummary, Box<dyn Error>> {
+        restore_backup(self, backup_id, options)
+    }
+}
@@ -0,0 +1,328 @@
+use crate::archive_builder::ArchiveProgress;
+use crate::file_hash::to_hex;
+use crate::jobs::check_cancelled;
+use crate::sandboxed_path::SandboxedPath;
+use crate::text_file::write_file_atomically;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Chunks are never cut shorter than this, except at the end of a file.
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Chunks are cut at this size even without a boundary in the content.
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// A chunk ends where the top 20 bits of the rolling hash are zero, about every 1 MiB.
+const BOUNDARY_MASK: u64 = ((1 << 20) - 1) << 44;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The random values of the gear rolling hash. Changing them moves every chunk boundary, which
+/// would make the next snapshot store every file again.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 from a fixed seed
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0;
+    let mut index = 0;
+    while index < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = state;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[index] = value ^ (value >> 31);
+        index += 1;
+    }
+    table
+}
+
+/// A file of a snapshot, as the list of the chunks its contents are made of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// The path relative to the snapshotted directory, with forward slashes.
+    pub path: String,
+    pub size: u64,
+    /// The modification time in milliseconds since the Unix epoch.
+    pub modified: u64,
+    /// The SHA-256 hashes of the chunks, in order.
+    pub chunks: Vec<String>,
+}
+
+/// Everything a snapshot contains, enough to reassemble it from the chunk store.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotIndex {
+    pub directories: Vec<String>,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// The outcome of creating or restoring a snapshot.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SnapshotSummary {
+    /// The number of files and directories.
+    pub entries: u64,
+    /// The uncompressed size of the files.
+    pub bytes: u64,
+    /// The number of chunks that were not in the store yet.
+    pub new_chunks: u64,
+    /// The compressed size of the new chunks, what the snapshot added to the store.
+    pub stored_bytes: u64,
+}
+
+struct PlannedFile {
+    source: PathBuf,
+    name: String,
+    size: u64,
+    modified: u64,
+}
+
+/// Where a chunk is kept in a store, spread over directories named by the first two digits of its hash.
+pub(crate) fn chunk_path(store: &Path, hash: &str) -> PathBuf {
+    store.join(hash.get(..2).unwrap_or_default()).join(hash)
+}
+
+/// Adds a chunk to the store unless it is there already, and returns its hash.
+fn store_chunk(store: &Path, data: &[u8], summary: &mut SnapshotSummary) -> Result<String, Box<dyn Error>> {
+    let hash = to_hex(&Sha256::digest(data));
+    let path = chunk_path(store, &hash);
+    if !path.exists() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        write_file_atomically(&path, &compressed)?;
+        summary.new_chunks += 1;
+        summary.stored_bytes += compressed.len() as u64;
+    }
+    Ok(hash)
+}
+
+/// Splits a file into content defined chunks and stores them.
+///
+/// Boundaries depend on the bytes around them rather than on their offset, so inserting or
+/// rewriting data only changes the chunks it touches, and the rest of the file deduplicates.
+fn chunk_file(path: &Path, store: &Path, summary: &mut SnapshotSummary) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut chunk = Vec::with_capacity(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    let mut chunks = Vec::new();
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for &byte in &buffer[..read] {
+            chunk.push(byte);
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if (chunk.len() >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == 0) || chunk.len() >= MAX_CHUNK_SIZE {
+                chunks.push(store_chunk(store, &chunk, summary)?);
+                chunk.clear();
+                hash = 0;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(store_chunk(store, &chunk, summary)?);
+    }
+    Ok(chunks)
+}
+
+/// Walks the sources and collects the directories and files of the snapshot, named relative to `base`.
+fn plan_snapshot(base: &Path, sources: &[SandboxedPath]) -> Result<(Vec<String>, Vec<PlannedFile>), Box<dyn Error>> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    for source in sources {
+        for entry in walkdir::WalkDir::new(source.path()).follow_links(false) {
+            let entry = entry?;
+            let name = entry
+                .path()
+                .strip_prefix(base)?
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if name.is_empty() {
+                continue;
+            }
+            let file_type = entry.file_type();
+            if file_type.is_dir() {
+                directories.push(name);
+            } else if file_type.is_file() {
+                let metadata = entry.metadata()?;
+                let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+                files.push(PlannedFile {
+                    source: entry.path().to_path_buf(),
+                    name,
+                    size: metadata.len(),
+                    modified: modified.as_millis() as u64,
+                });
+            } else {
+                warn!("Skipping {:?}, only files and directories can be snapshotted", entry.path());
+            }
+        }
+    }
+    Ok((directories, files))
+}
+
+/// Snapshots files and directories into a chunk store.
+///
+/// Files whose size and modification time match the previous snapshot reuse its chunks without
+/// being read, unless `rescan` is set.
+///
+/// # Arguments
+/// * `base` - The directory the paths in the snapshot are relative to, every source has to be inside it.
+/// * `sources` - The files and directories to snapshot. Directories are added recursively.
+/// * `store` - The directory holding the chunks.
+/// * `previous` - The previous snapshot of the same sources.
+/// * `rescan` - Reads every file, to pick up changes that kept the size and modification time.
+/// * `on_progress` - Invoked after every file.
+pub fn create_snapshot(
+    base: &Path,
+    sources: &[SandboxedPath],
+    store: &Path,
+    previous: Option<&SnapshotIndex>,
+    rescan: bool,
+    mut on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<(SnapshotIndex, SnapshotSummary), Box<dyn Error>> {
+    let (directories, planned) = plan_snapshot(base, sources)?;
+    let previous: HashMap<&str, &SnapshotFile> = previous
+        .filter(|_| !rescan)
+        .map(|previous| previous.files.iter().map(|file| (file.path.as_str(), file)).collect())
+        .unwrap_or_default();
+
+    let mut summary = SnapshotSummary::default();
+    let mut progress = ArchiveProgress {
+        current_entry: String::new(),
+        entries_processed: directories.len() as u64,
+        total_entries: (directories.len() + planned.len()) as u64,
+        bytes_processed: 0,
+        total_bytes: planned.iter().map(|file| file.size).sum(),
+    };
+    let mut files = Vec::with_capacity(planned.len());
+    for file in planned {
+        let chunks = match previous.get(file.name.as_str()) {
+            Some(unchanged) if unchanged.size == file.size && unchanged.modified == file.modified => {
+                unchanged.chunks.clone()
+            }
+            _ => chunk_file(&file.source, store, &mut summary)?,
+        };
+        progress.current_entry = file.name.clone();
+        progress.entries_processed += 1;
+        progress.bytes_processed += file.size;
+        on_progress(&progress);
+        check_cancelled()?;
+        files.push(SnapshotFile {
+            path: file.name,
+            size: file.size,
+            modified: file.modified,
+            chunks,
+        });
+    }
+
+    summary.entries = progress.entries_processed;
+    summary.bytes = progress.bytes_processed;
+    debug!("Snapshotted {} entries, {} new chunks", summary.entries, summary.new_chunks);
+    Ok((SnapshotIndex { directories, files }, summary))
+}
+
+/// Reassembles the files of a snapshot from the chunk store, verifying every chunk against its hash.
+///
+/// Existing files are overwritten, and restored files get back their modification times.
+///
+/// # Arguments
+/// * `index` - The snapshot to restore.
+/// * `store` - The directory holding the chunks.
+/// * `destination` - The directory to restore into, paths of the snapshot are resolved through its sandbox.
+/// * `on_progress` - Invoked after every file.
+///
+/// # Errors
+/// Returns an error if a chunk is missing or corrupted, or writing fails.
+pub fn restore_snapshot(
+    index: &SnapshotIndex,
+    store: &Path,
+    destination: &SandboxedPath,
+    mut on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<SnapshotSummary, Box<dyn Error>> {
+    for directory in &index.directories {
+        fs::create_dir_all(destination.join(directory)?.path())?;
+    }
+    let mut progress = ArchiveProgress {
+        current_entry: String::new(),
+        entries_processed: index.directories.len() as u64,
+        total_entries: (index.directories.len() + index.files.len()) as u64,
+        bytes_processed: 0,
+        total_bytes: index.files.iter().map(|file| file.size).sum(),
+    };
+    for file in &index.files {
+        let target = destination.join(&file.path)?;
+        if let Some(parent) = target.path().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = BufWriter::new(File::create(target.path())?);
+        for hash in &file.chunks {
+            let chunk = File::open(chunk_path(store, hash)).map_err(|e| format!("Chunk {} is missing: {}", hash, e))?;
+            let mut data = Vec::new();
+            GzDecoder::new(chunk).read_to_end(&mut data)?;
+            if to_hex(&Sha256::digest(&data)) != *hash {
+                return Err(format!("Chunk {} of {} is corrupted", hash, file.path).into());
+            }
+            writer.write_all(&data)?;
+        }
+        let restored = writer.into_inner().map_err(|e| e.into_error())?;
+        restored.set_modified(UNIX_EPOCH + Duration::from_millis(file.modified))?;
+
+        progress.current_entry = file.path.clone();
+        progress.entries_processed += 1;
+        progress.bytes_processed += file.size;
+        on_progress(&progress);
+    }
+    Ok(SnapshotSummary {
+        entries: progress.entries_processed,
+        bytes: progress.bytes_processed,
+        new_chunks: 0,
+        stored_bytes: 0,
+    })
+}
+
+/// Deletes the chunks none of the snapshots refers to anymore.
+///
+/// Must not run while a snapshot is written into the same store, its new chunks are not referenced yet.
+///
+/// # Returns
+/// The number of deleted chunks and the bytes they took up.
+pub fn collect_garbage(store: &Path, snapshots: &[SnapshotIndex]) -> Result<(u64, u64), Box<dyn Error>> {
+    if !store.exists() {
+        return Ok((0, 0));
+    }
+    let referenced: HashSet<&str> = snapshots
+        .iter()
+        .flat_map(|snapshot| snapshot.files.iter())
+        .flat_map(|file| file.chunks.iter().map(String::as_str))
+        .collect();
+    let mut removed = 0;
+    let mut freed = 0;
+    for entry in walkdir::WalkDir::new(store).min_depth(2).max_depth(2) {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy();
+        if entry.file_type().is_file() && !referenced.contains(name.as_ref()) {
+            freed += entry.metadata()?.len();
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    if removed > 0 {
+        info!("Removed {} unused chunks from {:?}, freeing {} bytes", removed, store, freed);
+    }
+    Ok((removed, freed))
+}
@@ -0,0 +1,1092 @@
+use crate::archive_builder::{create_archive_relative_to, ArchiveOptions, ArchiveProgress};
+use crate::archive_entries::ArchiveFormat;
+use crate::backup_encryption::{encrypt_file, get_backup_encryption};
+use crate::backup_remotes::upload_to_remotes;
+use crate::backup_snapshots::{collect_garbage, create_snapshot, restore_snapshot, SnapshotIndex, SnapshotSummary};
+use crate::datapacks::level_name;
+use crate::disk_quota::{available_quota, refresh_quota_usage};
+use crate::permissions::{authorize, Capability};
+use crate::rcon::rcon_command;
+use crate::restart_schedule::CronSchedule;
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_filesystem::ServerFilesystem;
+use crate::server_ping::get_live_status;
+use crate::server_process::{save_worlds, send_server_command, ServerProcess};
+use crate::server_trash::TRASH_DIRECTORY;
+use crate::text_file::write_file_atomically;
+use crate::two_factor::require_reverification;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The directory the backups are stored in, relative to the working directory like `servers`.
+///
+/// Every server has its own `backups/<server id>` directory, with one directory per backup holding
+/// the archive and its `backup.json` manifest. Keeping them outside of the server directory means a
+/// backup of the whole server never contains the previous backups.
+pub const BACKUP_DIRECTORY: &str = "backups";
+
+/// The name of the manifest, stored next to the archive and as the first entry inside it.
+pub(crate) const MANIFEST_FILE: &str = "backup.json";
+
+/// The name of the index listing the files and chunks of an incremental backup.
+pub(crate) const SNAPSHOT_INDEX_FILE: &str = "snapshot.json";
+
+/// The directory in the backups of a server holding the chunks its incremental backups share.
+pub(crate) const CHUNK_DIRECTORY: &str = ".chunks";
+
+/// The directory in the backups of a server that exported worlds are saved to. They are plain world
+/// archives, not backups.
+pub const EXPORT_DIRECTORY: &str = "exports";
+
+/// How long a running server gets to confirm writing its worlds before it is backed up anyway.
+const SAVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often progress events are sent while an archive is written.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Directories of mod loaders that hold configuration files.
+const CONFIG_DIRECTORIES: &[&str] = &["config", "defaultconfigs"];
+
+/// The extensions of the files in the server directory that count as configuration.
+const CONFIG_EXTENSIONS: &[&str] = &["properties", "json", "yml", "yaml", "toml", "txt", "conf", "cfg"];
+
+static SCHEDULER: Once = Once::new();
+
+type BackupListener = Box<dyn Fn(&BackupEvent) + Send>;
+
+lazy_static! {
+    static ref BACKUP_LISTENERS: Mutex<Vec<BackupListener>> = Mutex::new(Vec::new());
+    /// The servers whose backups are being written or cleaned up, only one of those runs per server at a time.
+    static ref RUNNING_BACKUPS: Mutex<HashSet<u64>> = Mutex::new(HashSet::new());
+}
+
+/// A part of a server to back up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BackupTarget {
+    /// The world named by `level-name`, with the `_nether` and `_the_end` directories Bukkit splits off.
+    World,
+    /// The configuration files in the server directory and the `config` directories of mod loaders.
+    Configs,
+    /// The whole server directory, except for the trash.
+    Server,
+    /// A file or directory, relative to the server directory.
+    Path { path: PathBuf },
+}
+
+/// What started a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupTrigger {
+    Manual,
+    Scheduled,
+    /// The state a restore was about to overwrite.
+    BeforeRestore,
+    /// A world that was moved out of the server directory, see [`crate::worlds::WorldManager::archive_world`].
+    WorldArchive,
+}
+
+/// How a backup stores the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupMode {
+    /// A self-contained archive of every file.
+    #[default]
+    Full,
+    /// A snapshot in the chunk store the incremental backups of a server share. Files are split
+    /// into content defined chunks, so a snapshot only adds the chunks that changed since any
+    /// earlier one, even inside region files that were partly rewritten.
+    Incremental,
+}
+
+/// What a backup contains and how it is compressed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupOptions {
+    pub targets: Vec<BackupTarget>,
+    pub mode: BackupMode,
+    /// The format of the archive of a full backup, `Zip` or `TarGz`.
+    pub format: ArchiveFormat,
+    /// The compression level of a full backup from `0` to `9`, `None` for the default of the format.
+    pub compression_level: Option<u32>,
+    /// Every this many incremental backups, one is consolidated: it reads every file again instead
+    /// of trusting unchanged sizes and modification times, and the chunks no backup refers to
+    /// anymore are removed from the store. `0` only reads every file for the first snapshot.
+    pub consolidate_every: u32,
+    /// Encrypts the archive of a full backup with the backup encryption key of the server.
+    pub encrypt: bool,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            targets: vec![BackupTarget::World],
+            mode: BackupMode::Full,
+            format: ArchiveFormat::Zip,
+            compression_level: None,
+            consolidate_every: 12,
+            encrypt: false,
+        }
+    }
+}
+
+/// Which scheduled backups survive pruning. A backup is kept if any of the rules keeps it.
+///
+/// Manual backups are never pruned, they stay until they are deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Keeps the newest backups.
+    pub keep_last: u32,
+    /// Keeps the newest backup of each of the most recent days with a backup, in UTC.
+    pub keep_daily: u32,
+    /// Keeps the newest backup of each of the most recent weeks with a backup, starting on Monday.
+    pub keep_weekly: u32,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_last: 3,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+/// When a server is backed up automatically, and how many of those backups are kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackupSchedule {
+    pub enabled: bool,
+    /// A cron expression with the five fields `minute hour day-of-month month day-of-week`, in UTC.
+    pub cron: String,
+    pub options: BackupOptions,
+    pub retention: RetentionPolicy,
+}
+
+impl Default for BackupSchedule {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cron: "0 */6 * * *".to_string(),
+            options: BackupOptions::default(),
+            retention: RetentionPolicy::default(),
+        }
+    }
+}
+
+/// The description of a backup, written to `backup.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Identifies the backup among those of its server.
+    pub id: String,
+    pub server_id: u64,
+    pub server_name: String,
+    pub minecraft_version: String,
+    pub loader_type: u8,
+    pub loader_version: Option<String>,
+    pub trigger: BackupTrigger,
+    pub targets: Vec<BackupTarget>,
+    #[serde(default)]
+    pub mode: BackupMode,
+    pub format: ArchiveFormat,
+    pub created_at: SystemTime,
+    /// Whether the worlds were flushed to disk and saving was paused while they were archived, or
+    /// the server was not running. Other backups may contain chunks the server was writing.
+    #[serde(default)]
+    pub consistent: bool,
+    /// The number of files and directories in the archive, `0` in the copy inside the archive.
+    #[serde(default)]
+    pub entries: u64,
+    /// The uncompressed size of the backed up files.
+    #[serde(default)]
+    pub uncompressed_size: u64,
+    /// The size of the archive, or the compressed size of the chunks an incremental backup added.
+    #[serde(default)]
+    pub size: u64,
+    /// Whether an incremental backup read every file, rather than reusing the chunks of unchanged ones.
+    #[serde(default)]
+    pub full_scan: bool,
+    /// Whether the archive is encrypted, it is then stored with an additional `.enc` extension.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupEventKind {
+    Started,
+    Progress,
+    Completed,
+    Failed,
+    /// A finished backup is being copied to a remote target, named in the message.
+    Uploading,
+    Uploaded,
+    UploadFailed,
+}
+
+/// The progress of a backup, sent to the backup listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupEvent {
+    pub server_id: u64,
+    pub backup_id: String,
+    pub kind: BackupEventKind,
+    pub entries_processed: u64,
+    pub total_entries: u64,
+    pub bytes_processed: u64,
+    pub total_bytes: u64,
+    /// The estimated seconds until the archive is written, from the throughput so far.
+    pub eta_seconds: Option<u64>,
+    /// The error of a failed backup.
+    pub message: Option<String>,
+}
+
+/// Registers a listener that is invoked when a backup starts, progresses, completes or fails.
+pub fn add_backup_listener(listener: impl Fn(&BackupEvent) + Send + 'static) {
+    if let Ok(mut listeners) = BACKUP_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn emit(event: BackupEvent) {
+    match BACKUP_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(&event)),
+        Err(err) => error!("Failed to notify backup listeners: {}", err),
+    }
+}
+
+pub(crate) fn emit_kind(server_id: u64, backup_id: &str, kind: BackupEventKind, message: Option<String>) {
+    emit(BackupEvent {
+        server_id,
+        backup_id: backup_id.to_string(),
+        kind,
+        entries_processed: 0,
+        total_entries: 0,
+        bytes_processed: 0,
+        total_bytes: 0,
+        eta_seconds: None,
+        message,
+    });
+}
+
+/// Creates the table holding the backup schedules of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_backup_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_backup_schedule` (
+            server_id INTEGER PRIMARY KEY,                              -- The server backed up on the schedule
+            enabled INTEGER NOT NULL DEFAULT 0,                         -- Whether the schedule is active
+            cron TEXT NOT NULL,                                         -- The cron expression, in UTC
+            options TEXT NOT NULL,                                      -- The targets and format, as JSON
+            keep_last INTEGER NOT NULL,                                 -- The newest backups to keep
+            keep_daily INTEGER NOT NULL,                                -- The days to keep a backup of
+            keep_weekly INTEGER NOT NULL                                -- The weeks to keep a backup of
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Reads the backup schedule of a server, the disabled default schedule if none was set.
+pub fn get_backup_schedule(server_id: u64) -> Result<BackupSchedule, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_backup_schedule WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        return read_schedule(&mut statement);
+    }
+    Ok(BackupSchedule::default())
+}
+
+/// Sets the backup schedule of a server.
+///
+/// # Errors
+/// Returns an error if the cron expression or the options are invalid, or the schedule cannot be stored.
+pub fn set_backup_schedule(server_id: u64, schedule: &BackupSchedule) -> Result<(), Box<dyn Error>> {
+    CronSchedule::from_str(&schedule.cron)?;
+    validate_options(&schedule.options)?;
+    let query = r#"
+INSERT INTO server_backup_schedule (server_id, enabled, cron, options, keep_last, keep_daily, keep_weekly)
+VALUES (?, ?, ?, ?, ?, ?, ?)
+ON CONFLICT(server_id) DO UPDATE SET
+    enabled = excluded.enabled,
+    cron = excluded.cron,
+    options = excluded.options,
+    keep_last = excluded.keep_last,
+    keep_daily = excluded.keep_daily,
+    keep_weekly = excluded.keep_weekly
+"#;
+    let options = serde_json::to_string(&schedule.options)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, schedule.enabled as i64))?;
+    statement.bind((3, schedule.cron.trim()))?;
+    statement.bind((4, options.as_str()))?;
+    statement.bind((5, schedule.retention.keep_last as i64))?;
+    statement.bind((6, schedule.retention.keep_daily as i64))?;
+    statement.bind((7, schedule.retention.keep_weekly as i64))?;
+    statement.next()?;
+    info!("Updated the backup schedule of server {}: {:?}", server_id, schedule);
+    Ok(())
+}
+
+fn read_schedule(statement: &mut sqlite::Statement) -> Result<BackupSchedule, Box<dyn Error>> {
+    Ok(BackupSchedule {
+        enabled: statement.read::<i64, _>("enabled")? != 0,
+        cron: statement.read::<String, _>("cron")?,
+        options: serde_json::from_str(&statement.read::<String, _>("options")?)?,
+        retention: RetentionPolicy {
+            keep_last: statement.read::<i64, _>("keep_last")?.max(0) as u32,
+            keep_daily: statement.read::<i64, _>("keep_daily")?.max(0) as u32,
+            keep_weekly: statement.read::<i64, _>("keep_weekly")?.max(0) as u32,
+        },
+    })
+}
+
+fn validate_options(options: &BackupOptions) -> Result<(), Box<dyn Error>> {
+    if options.targets.is_empty() {
+        return Err("A backup needs at least one target".into());
+    }
+    if !matches!(options.format, ArchiveFormat::Zip | ArchiveFormat::TarGz) {
+        return Err(format!("Backups cannot be created as {:?} archives", options.format).into());
+    }
+    if options.encrypt && options.mode == BackupMode::Incremental {
+        return Err("Only full backups can be encrypted, incremental ones share their chunks".into());
+    }
+    Ok(())
+}
+
+/// The directory holding the backups of a server.
+pub fn backup_directory(server_id: u64) -> PathBuf {
+    Path::new(BACKUP_DIRECTORY).join(server_id.to_string())
+}
+
+pub(crate) fn archive_name(format: ArchiveFormat, encrypted: bool) -> &'static str {
+    match (format, encrypted) {
+        (ArchiveFormat::TarGz, false) => "backup.tar.gz",
+        (ArchiveFormat::TarGz, true) => "backup.tar.gz.enc",
+        (_, false) => "backup.zip",
+        (_, true) => "backup.zip.enc",
+    }
+}
+
+/// Resolves the directory of a backup, validating its identifier on the way.
+fn existing_backup_directory(server_id: u64, backup_id: &str) -> Result<PathBuf, Box<dyn Error>> {
+    if backup_id.is_empty() || !backup_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(format!("Invalid backup id: {:?}", backup_id).into());
+    }
+    let directory = backup_directory(server_id).join(backup_id);
+    if !directory.join(MANIFEST_FILE).is_file() {
+        return Err(format!("Backup {} of server {} does not exist", backup_id, server_id).into());
+    }
+    Ok(directory)
+}
+
+/// Reads the manifest of a backup of a server.
+pub fn get_backup(server_id: u64, backup_id: &str) -> Result<BackupManifest, Box<dyn Error>> {
+    let directory = existing_backup_directory(server_id, backup_id)?;
+    Ok(serde_json::from_slice(&fs::read(directory.join(MANIFEST_FILE))?)?)
+}
+
+/// Returns the path of the archive of a full backup, to download or restore it.
+///
+/// The archive of an encrypted backup is returned as it is stored, still encrypted.
+///
+/// # Errors
+/// Returns an error if the backup does not exist or is incremental, and has no archive of its own.
+pub fn get_backup_archive(server_id: u64, backup_id: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let manifest = get_backup(server_id, backup_id)?;
+    if manifest.mode == BackupMode::Incremental {
+        return Err(format!("Backup {} is incremental, it has no archive to download", backup_id).into());
+    }
+    Ok(backup_directory(server_id).join(backup_id).join(archive_name(manifest.format, manifest.encrypted)))
+}
+
+/// Whether a backup is complete, the archive or snapshot index is only written under its final name at the end.
+fn is_finished(directory: &Path, manifest: &BackupManifest) -> bool {
+    match manifest.mode {
+        BackupMode::Full => directory.join(archive_name(manifest.format, manifest.encrypted)).is_file(),
+        BackupMode::Incremental => directory.join(SNAPSHOT_INDEX_FILE).is_file(),
+    }
+}
+
+pub(crate) fn read_snapshot_index(server_id: u64, backup_id: &str) -> Result<SnapshotIndex, Box<dyn Error>> {
+    let directory = existing_backup_directory(server_id, backup_id)?;
+    Ok(serde_json::from_slice(&fs::read(directory.join(SNAPSHOT_INDEX_FILE))?)?)
+}
+
+/// The directory holding the chunks of the incremental backups of a server.
+pub(crate) fn chunk_store(server_id: u64) -> PathBuf {
+    backup_directory(server_id).join(CHUNK_DIRECTORY)
+}
+
+/// Reassembles an incremental backup into a directory, as the files were at the time of the backup.
+///
+/// Files of the backup overwrite existing ones, other files in the destination are left alone.
+///
+/// # Errors
+/// Returns an error if the backup is not incremental, or one of its chunks is missing or corrupted.
+pub fn extract_snapshot(
+    server_id: u64,
+    backup_id: &str,
+    destination: &SandboxedPath,
+    on_progress: impl FnMut(&ArchiveProgress),
+) -> Result<SnapshotSummary, Box<dyn Error>> {
+    if get_backup(server_id, backup_id)?.mode != BackupMode::Incremental {
+        return Err(format!("Backup {} is a full backup, extract its archive instead", backup_id).into());
+    }
+    let index = read_snapshot_index(server_id, backup_id)?;
+    let store = chunk_store(server_id);
+    let summary = restore_snapshot(&index, &store, destination, on_progress)?;
+    info!("Extracted backup {} of server {} into {:?}", backup_id, server_id, destination.path());
+    Ok(summary)
+}
+
+/// Lists the finished backups of a server, newest first.
+///
+/// # Errors
+/// Returns an error if the backup directory of the server exists but cannot be read.
+pub fn list_backups(server_id: u64) -> Result<Vec<BackupManifest>, Box<dyn Error>> {
+    let directory = backup_directory(server_id);
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&directory)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') || name == EXPORT_DIRECTORY {
+            continue;
+        }
+        let manifest = fs::read(entry.path().join(MANIFEST_FILE))
+            .map_err(|e| e.to_string())
+            .and_then(|contents| serde_json::from_slice::<BackupManifest>(&contents).map_err(|e| e.to_string()));
+        match manifest {
+            Ok(manifest) if is_finished(&entry.path(), &manifest) => backups.push(manifest),
+            Ok(_) => {}
+            Err(err) => warn!("Ignoring unreadable backup {:?}: {}", entry.path(), err),
+        }
+    }
+    backups.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    Ok(backups)
+}
+
+/// Permanently deletes a backup of a server.
+///
+/// # Errors
+/// Returns an error if the backup does not exist or cannot be deleted.
+pub fn delete_backup(server_id: u64, backup_id: &str) -> Result<(), Box<dyn Error>> {
+    let incremental = get_backup(server_id, backup_id)?.mode == BackupMode::Incremental;
+    fs::remove_dir_all(existing_backup_directory(server_id, backup_id)?)?;
+    info!("Deleted backup {} of server {}", backup_id, server_id);
+    if incremental {
+        remove_unused_chunks(server_id)?;
+    }
+    Ok(())
+}
+
+/// Claims the backups of a server for a backup or a cleanup, `false` if something else has them.
+pub(crate) fn claim_backups(server_id: u64) -> Result<bool, Box<dyn Error>> {
+    Ok(RUNNING_BACKUPS
+        .lock()
+        .map(|mut running| running.insert(server_id))
+        .map_err(|e| e.to_string())?)
+}
+
+pub(crate) fn release_backups(server_id: u64) {
+    if let Ok(mut running) = RUNNING_BACKUPS.lock() {
+        running.remove(&server_id);
+    }
+}
+
+/// Removes the chunks of deleted incremental backups, unless a backup of the server is running.
+/// Those are removed by the next consolidation instead.
+fn remove_unused_chunks(server_id: u64) -> Result<(), Box<dyn Error>> {
+    if !claim_backups(server_id)? {
+        debug!("A backup of server {} is running, its unused chunks are removed later", server_id);
+        return Ok(());
+    }
+    let result = collect_unused_chunks(server_id);
+    release_backups(server_id);
+    result
+}
+
+/// Deletes the chunks no incremental backup of a server refers to. The backups of the server have to be claimed.
+fn collect_unused_chunks(server_id: u64) -> Result<(), Box<dyn Error>> {
+    let snapshots = list_backups(server_id)?
+        .iter()
+        .filter(|backup| backup.mode == BackupMode::Incremental)
+        .map(|backup| read_snapshot_index(server_id, &backup.id))
+        .collect::<Result<Vec<_>, _>>()?;
+    collect_garbage(&chunk_store(server_id), &snapshots)?;
+    Ok(())
+}
+
+/// Deletes the scheduled backups of a server the retention policy does not keep.
+///
+/// # Returns
+/// The number of deleted backups.
+pub fn prune_backups(server_id: u64, retention: &RetentionPolicy) -> Result<usize, Box<dyn Error>> {
+    let scheduled: Vec<BackupManifest> = list_backups(server_id)?
+        .into_iter()
+        .filter(|backup| backup.trigger == BackupTrigger::Scheduled)
+        .collect();
+
+    let mut kept = HashSet::new();
+    kept.extend(scheduled.iter().take(retention.keep_last as usize).map(|backup| backup.id.clone()));
+    // Backups are listed newest first, so the first backup of every period is its newest
+    let mut keep_newest_per_period = |limit: u32, period: fn(u64) -> u64| {
+        let mut periods = HashSet::new();
+        for backup in &scheduled {
+            let days = backup.created_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / (24 * 60 * 60);
+            if periods.len() < limit as usize && periods.insert(period(days)) {
+                kept.insert(backup.id.clone());
+            }
+        }
+    };
+    keep_newest_per_period(retention.keep_daily, |days| days);
+    // 1970-01-01 was a Thursday, shifting by three days makes weeks start on Monday
+    keep_newest_per_period(retention.keep_weekly, |days| (days + 3) / 7);
+
+    let mut pruned = 0;
+    let mut incremental_pruned = false;
+    for backup in scheduled.iter().filter(|backup| !kept.contains(&backup.id)) {
+        debug!("Backup {} of server {} is not kept by the retention policy", backup.id, server_id);
+        fs::remove_dir_all(existing_backup_directory(server_id, &backup.id)?)?;
+        incremental_pruned |= backup.mode == BackupMode::Incremental;
+        pruned += 1;
+    }
+    if pruned > 0 {
+        info!("Pruned {} backups of server {}", pruned, server_id);
+    }
+    if incremental_pruned {
+        remove_unused_chunks(server_id)?;
+    }
+    Ok(pruned)
+}
+
+/// Collects the files and directories of a server the targets cover, dropping those inside others.
+fn resolve_sources(server: &Server<u64>, targets: &[BackupTarget]) -> Result<Vec<SandboxedPath>, Box<dyn Error>> {
+    let root = server.sandbox("")?;
+    let mut sources = Vec::new();
+    for target in targets {
+        match target {
+            BackupTarget::World => {
+                let level = level_name(server);
+                for name in [level.clone(), format!("{}_nether", level), format!("{}_the_end", level)] {
+                    let world = root.join(name)?;
+                    if world.path().is_dir() {
+                        sources.push(world);
+                    }
+                }
+            }
+            BackupTarget::Configs => {
+                for entry in fs::read_dir(root.path())?.flatten() {
+                    let path = entry.path();
+                    let is_config_file = path.is_file()
+                        && path
+                            .extension()
+                            .and_then(|extension| extension.to_str())
+                            .is_some_and(|extension| CONFIG_EXTENSIONS.contains(&extension));
+                    let is_config_directory = path.is_dir()
+                        && CONFIG_DIRECTORIES.iter().any(|directory| entry.file_name() == *directory);
+                    if is_config_file || is_config_directory {
+                        sources.push(root.join(entry.file_name())?);
+                    }
+                }
+            }
+            BackupTarget::Server => {
+                for entry in fs::read_dir(root.path())?.flatten() {
+                    if entry.file_name() != TRASH_DIRECTORY {
+                        sources.push(root.join(entry.file_name())?);
+                    }
+                }
+            }
+            BackupTarget::Path { path } => {
+                let source = root.join(path)?;
+                if source.is_root() {
+                    return Err("Back up the whole server with the server target".into());
+                }
+                if fs::symlink_metadata(source.path()).is_err() {
+                    return Err(format!("{} does not exist", source).into());
+                }
+                sources.push(source);
+            }
+        }
+    }
+
+    // Paths sort before the paths inside them
+    sources.sort_by(|a, b| a.relative_path().cmp(b.relative_path()));
+    let mut outermost: Vec<SandboxedPath> = Vec::new();
+    for source in sources {
+        if !outermost.iter().any(|other| source.relative_path().starts_with(other.relative_path())) {
+            outermost.push(source);
+        }
+    }
+    if outermost.is_empty() {
+        return Err(format!("Server {:?} has nothing to back up for {:?}", server.name, targets).into());
+    }
+    Ok(outermost)
+}
+
+/// Picks an unused backup identifier based on the creation time.
+fn new_backup_id(server_id: u64, created_at: SystemTime) -> String {
+    let millis = created_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let mut id = millis.to_string();
+    let mut suffix = 0;
+    while backup_directory(server_id).join(&id).exists() {
+        suffix += 1;
+        id = format!("{}-{}", millis, suffix);
+    }
+    id
+}
+
+/// Creates a backup of a server, blocking until the archive is written.
+///
+/// The progress is reported to the backup listeners, at most twice a second. Only one backup of a
+/// server runs at a time, and a failed backup leaves nothing behind.
+///
+/// A running server has saving turned off and its worlds flushed while they are archived, with
+/// `save-off` and `save-all flush` through the console, or over RCON for servers started elsewhere.
+/// Saving is turned back on afterwards, whether the backup succeeded or not.
+///
+/// A finished backup is then uploaded to the enabled remote targets of the server in the background.
+///
+/// Backups count towards the disk quota of the server. A backup is refused once the quota is used
+/// up, and removed again if it pushed the server over its quota.
+///
+/// # Errors
+/// Returns an error if a backup of the server is already running, the options are invalid, the
+/// archive cannot be written, or it does not fit the disk quota.
+pub fn create_backup(
+    server: &Server<u64>,
+    options: &BackupOptions,
+    trigger: BackupTrigger,
+) -> Result<BackupManifest, Box<dyn Error>> {
+    validate_options(options)?;
+    if available_quota(server.id, &server.directory)? == Some(0) {
+        return Err(format!("The disk quota of server {:?} is used up, no backup can be created", server.name).into());
+    }
+    if !claim_backups(server.id)? {
+        return Err(format!("A backup of server {:?} is already running", server.name).into());
+    }
+    let result = run_backup(server, options, trigger);
+    release_backups(server.id);
+    if let Ok(manifest) = &result {
+        upload_to_remotes(manifest);
+    }
+    result
+}
+
+fn run_backup(
+    server: &Server<u64>,
+    options: &BackupOptions,
+    trigger: BackupTrigger,
+) -> Result<BackupManifest, Box<dyn Error>> {
+    let sources = resolve_sources(server, &options.targets)?;
+    let created_at = SystemTime::now();
+    let id = new_backup_id(server.id, created_at);
+    let mut manifest = BackupManifest {
+        id: id.clone(),
+        server_id: server.id,
+        server_name: server.name.clone(),
+        minecraft_version: server.minecraft_version.clone(),
+        loader_type: server.loader_type,
+        loader_version: server.loader_version.clone(),
+        trigger,
+        targets: options.targets.clone(),
+        mode: options.mode,
+        format: options.format,
+        created_at,
+        consistent: false,
+        entries: 0,
+        uncompressed_size: 0,
+        size: 0,
+        full_scan: false,
+        encrypted: options.encrypt,
+    };
+
+    let directory = backup_directory(server.id).join(&id);
+    fs::create_dir_all(&directory)?;
+    info!("Backing up {:?} of server {:?} as {}", options.targets, server.name, id);
+    emit_kind(server.id, &id, BackupEventKind::Started, None);
+
+    let saving = pause_saving(server, &options.targets);
+    manifest.consistent = saving != SavePause::Failed;
+    let result = write_backup(server, &directory, sources, options, &mut manifest);
+    resume_saving(server, saving);
+    // The size of a backup is only known once it is written
+    let result = result.and_then(|()| ensure_within_quota(server));
+    match result {
+        Ok(()) => {
+            info!("Backed up server {:?} as {}, {} bytes", server.name, id, manifest.size);
+            emit_kind(server.id, &id, BackupEventKind::Completed, None);
+            Ok(manifest)
+        }
+        Err(e) => {
+            error!("Failed to back up server {:?}: {}", server.name, e);
+            if let Err(cleanup) = fs::remove_dir_all(&directory) {
+                warn!("Failed to remove the failed backup {:?}: {}", directory, cleanup);
+            }
+            // The backups of the server are still claimed, so the chunks only the failed backup stored can go
+            if options.mode == BackupMode::Incremental {
+                if let Err(cleanup) = collect_unused_chunks(server.id) {
+                    warn!("Failed to remove the chunks of the failed backup {}: {}", id, cleanup);
+                }
+            }
+            if let Err(e) = refresh_quota_usage(server.id, &server.directory) {
+                warn!("Failed to refresh the disk usage of server {:?}: {}", server.name, e);
+            }
+            emit_kind(server.id, &id, BackupEventKind::Failed, Some(e.to_string()));
+            Err(e)
+        }
+    }
+}
+
+/// Fails if a server is over its disk quota, which its backups count towards.
+fn ensure_within_quota(server: &Server<u64>) -> Result<(), Box<dyn Error>> {
+    let usage = refresh_quota_usage(server.id, &server.directory)?;
+    match usage.limit.filter(|limit| usage.used > *limit) {
+        Some(limit) => Err(format!("The backup would exceed the disk quota of {} bytes", limit).into()),
+        None => Ok(()),
+    }
+}
+
+/// How the server was kept from writing its worlds during a backup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SavePause {
+    /// The server is stopped, or only its configuration is backed up.
+    NotNeeded,
+    /// Saving was turned off through the console of a server the manager started.
+    Console,
+    /// Saving was turned off over RCON, for a server started elsewhere.
+    Rcon,
+    /// The server is running, but saving could not be turned off.
+    Failed,
+}
+
+/// Turns off saving on a running server and writes its worlds to disk, so the backup sees a
+/// consistent world instead of region files the server is writing chunks into.
+pub(crate) fn pause_saving(server: &Server<u64>, targets: &[BackupTarget]) -> SavePause {
+    if targets.iter().all(|target| *target == BackupTarget::Configs) {
+        return SavePause::NotNeeded;
+    }
+    if server.is_server_running() {
+        // Saving is turned off first, so nothing is written between the flush and the backup
+        if let Err(e) = send_server_command(server, "save-off") {
+            warn!("Failed to turn off saving on server {:?} for the backup: {}", server.name, e);
+            return SavePause::Failed;
+        }
+        return match save_worlds(server, SAVE_TIMEOUT) {
+            Ok(true) => SavePause::Console,
+            Ok(false) => {
+                warn!("Server {:?} did not confirm saving its worlds, backing up anyway", server.name);
+                SavePause::Console
+            }
+            Err(e) => {
+                warn!("Failed to save the worlds of server {:?} for the backup: {}", server.name, e);
+                resume_saving(server, SavePause::Console);
+                SavePause::Failed
+            }
+        };
+    }
+
+    let online = get_live_status(server.id).is_some_and(|status| status.ping.is_some());
+    if !online {
+        return SavePause::NotNeeded;
+    }
+    // RCON runs commands on the main thread of the server, so the flush is done once the reply arrives
+    match rcon_command(server, "save-off").and_then(|_| rcon_command(server, "save-all flush")) {
+        Ok(_) => SavePause::Rcon,
+        Err(e) => {
+            warn!(
+                "Server {:?} was not started by the manager and cannot pause saving over RCON, \
+                the backup may contain partially written chunks: {}",
+                server.name, e
+            );
+            let _ = rcon_command(server, "save-on");
+            SavePause::Failed
+        }
+    }
+}
+
+pub(crate) fn resume_saving(server: &Server<u64>, pause: SavePause) {
+    let result = match pause {
+        SavePause::Console => send_server_command(server, "save-on"),
+        SavePause::Rcon => rcon_command(server, "save-on").map(|_| ()),
+        SavePause::NotNeeded | SavePause::Failed => return,
+    };
+    if let Err(e) = result {
+        error!("Failed to turn saving back on for server {:?} after the backup: {}", server.name, e);
+    }
+}
+
+/// Turns the progress of writing a backup into progress events, throttled and with the estimated time left.
+struct ProgressReporter {
+    server_id: u64,
+    backup_id: String,
+    started: Instant,
+    last_event: Option<Instant>,
+}
+
+impl ProgressReporter {
+    fn report(&mut self, progress: &ArchiveProgress) {
+        if self.last_event.is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        self.last_event = Some(Instant::now());
+        let eta_seconds = (progress.bytes_processed > 0).then(|| {
+            let remaining = progress.total_bytes.saturating_sub(progress.bytes_processed) as f64;
+            (self.started.elapsed().as_secs_f64() * remaining / progress.bytes_processed as f64).ceil() as u64
+        });
+        emit(BackupEvent {
+            server_id: self.server_id,
+            backup_id: self.backup_id.clone(),
+            kind: BackupEventKind::Progress,
+            entries_processed: progress.entries_processed,
+            total_entries: progress.total_entries,
+            bytes_processed: progress.bytes_processed,
+            total_bytes: progress.total_bytes,
+            eta_seconds,
+            message: None,
+        });
+    }
+}
+
+fn write_backup(
+    server: &Server<u64>,
+    directory: &Path,
+    sources: Vec<SandboxedPath>,
+    options: &BackupOptions,
+    manifest: &mut BackupManifest,
+) -> Result<(), Box<dyn Error>> {
+    let manifest_path = directory.join(MANIFEST_FILE);
+    fs::write(&manifest_path, serde_json::to_vec_pretty(&manifest)?)?;
+    let mut reporter = ProgressReporter {
+        server_id: server.id,
+        backup_id: manifest.id.clone(),
+        started: Instant::now(),
+        last_event: None,
+    };
+    match options.mode {
+        BackupMode::Full => write_archive(server, directory, sources, options, manifest, &mut reporter)?,
+        BackupMode::Incremental => write_snapshot(server, directory, &sources, options, manifest, &mut reporter)?,
+    }
+    write_file_atomically(&manifest_path, &serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(())
+}
+
+fn write_archive(
+    server: &Server<u64>,
+    directory: &Path,
+    mut sources: Vec<SandboxedPath>,
+    options: &BackupOptions,
+    manifest: &mut BackupManifest,
+    reporter: &mut ProgressReporter,
+) -> Result<(), Box<dyn Error>> {
+    let key = if options.encrypt {
+        let key = get_backup_encryption(server.id)?;
+        Some(key.ok_or_else(|| format!("Server {:?} has no backup encryption key set", server.name))?)
+    } else {
+        None
+    };
+    let backup_root = SandboxedPath::root(directory)?;
+    sources.insert(0, backup_root.join(MANIFEST_FILE)?);
+
+    // The archive only gets its final name once it is complete, which is what marks a finished backup
+    let archive = backup_root.join(archive_name(options.format, options.encrypt))?;
+    let partial = backup_root.join(format!("{}.partial", archive_name(options.format, false)))?;
+    let archive_options = ArchiveOptions {
+        format: options.format,
+        compression_level: options.compression_level,
+        exclude: Vec::new(),
+    };
+    let root = server.sandbox("")?.path();
+    let summary = create_archive_relative_to(Some(&root), &sources, &partial, &archive_options, |progress| {
+        reporter.report(progress)
+    })?;
+    match key {
+        Some(key) => {
+            let encrypted = backup_root.join(format!("{}.partial", archive_name(options.format, true)))?;
+            let result = encrypt_file(&partial.path(), &encrypted.path(), &key);
+            fs::remove_file(partial.path())?;
+            result?;
+            fs::rename(encrypted.path(), archive.path())?;
+        }
+        None => fs::rename(partial.path(), archive.path())?,
+    }
+
+    manifest.entries = summary.entries;
+    manifest.uncompressed_size = summary.bytes;
+    manifest.size = fs::metadata(archive.path())?.len();
+    Ok(())
+}
+
+fn write_snapshot(
+    server: &Server<u64>,
+    directory: &Path,
+    sources: &[SandboxedPath],
+    options: &BackupOptions,
+    manifest: &mut BackupManifest,
+    reporter: &mut ProgressReporter,
+) -> Result<(), Box<dyn Error>> {
+    let earlier: Vec<BackupManifest> = list_backups(server.id)?
+        .into_iter()
+        .filter(|backup| backup.mode == BackupMode::Incremental)
+        .collect();
+    let since_consolidation = earlier.iter().take_while(|backup| !backup.full_scan).count() as u32;
+    let previous = earlier.first().and_then(|backup| match read_snapshot_index(server.id, &backup.id) {
+        Ok(index) => Some(index),
+        Err(e) => {
+            warn!("Failed to read the snapshot of backup {}, reading every file: {}", backup.id, e);
+            None
+        }
+    });
+    manifest.full_scan = previous.is_none()
+        || (options.consolidate_every > 0 && since_consolidation + 1 >= options.consolidate_every);
+
+    let store = chunk_store(server.id);
+    let root = server.sandbox("")?.path();
+    let (index, summary) = create_snapshot(&root, sources, &store, previous.as_ref(), manifest.full_scan, |progress| {
+        reporter.report(progress)
+    })?;
+    write_file_atomically(&directory.join(SNAPSHOT_INDEX_FILE), &serde_json::to_vec(&index)?)?;
+    manifest.entries = summary.entries;
+    manifest.uncompressed_size = summary.bytes;
+    manifest.size = summary.stored_bytes;
+
+    // The backups of the server are claimed by this backup, so nothing else writes chunks meanwhile
+    if manifest.full_scan {
+        if let Err(e) = collect_unused_chunks(server.id) {
+            warn!("Failed to remove the unused chunks of server {:?}: {}", server.name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Starts the thread creating the scheduled backups. Calling it again has no effect.
+///
+/// The scheduled backups of a server are pruned by its retention policy after every new one.
+pub fn start_backup_scheduler() {
+    SCHEDULER.call_once(|| {
+        thread::spawn(|| loop {
+            let now = SystemTime::now();
+            let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let minute = UNIX_EPOCH + Duration::from_secs(seconds / 60 * 60);
+            if let Err(e) = run_due_backups(minute) {
+                error!("Failed to run the backup schedules: {}", e);
+            }
+            let next_minute = minute + Duration::from_secs(60);
+            thread::sleep(next_minute.duration_since(SystemTime::now()).unwrap_or_default());
+        });
+        info!("Started the backup scheduler");
+    });
+}
+
+/// Starts the backups due in the given minute.
+fn run_due_backups(minute: SystemTime) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_backup_schedule WHERE enabled = 1"#)?;
+    let mut schedules = Vec::new();
+    while let State::Row = statement.next()? {
+        schedules.push((statement.read::<i64, _>("server_id")? as u64, read_schedule(&mut statement)?));
+    }
+
+    for (server_id, schedule) in schedules {
+        match CronSchedule::from_str(&schedule.cron) {
+            Ok(cron) if cron.matches(minute) => {}
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Ignoring the backup schedule of server {}: {}", server_id, e);
+                continue;
+            }
+        }
+        thread::spawn(move || {
+            let server = match Server::<u64>::get_server(server_id) {
+                Ok(server) => server,
+                Err(e) => {
+                    warn!("Failed to load server {} for its scheduled backup: {}", server_id, e);
+                    return;
+                }
+            };
+            if create_backup(&server, &schedule.options, BackupTrigger::Scheduled).is_ok() {
+                if let Err(e) = prune_backups(server_id, &schedule.retention) {
+                    error!("Failed to prune the backups of server {:?}: {}", server.name, e);
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+pub trait ServerBackups {
+    /// Backs up the server now, blocking until the archive is written.
+    fn create_backup(&self, options: &BackupOptions) -> Result<BackupManifest, Box<dyn Error>>;
+
+    /// Lists the finished backups of the server, newest first.
+    fn list_backups(&self) -> Result<Vec<BackupManifest>, Box<dyn Error>>;
+
+    /// Permanently deletes a backup of the server.
+    fn delete_backup(&self, backup_id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Returns the schedule the server is backed up on.
+    fn get_backup_schedule(&self) -> Result<BackupSchedule, Box<dyn Error>>;
+
+    /// Sets the schedule the server is backed up on, validating its cron expression and options.
+    fn set_backup_schedule(&self, schedule: &BackupSchedule) -> Result<(), Box<dyn Error>>;
+
+    /// Returns when the server is backed up next, `None` if its schedule is disabled.
+    fn get_next_scheduled_backup(&self) -> Result<Option<SystemTime>, Box<dyn Error>>;
+}
+
+impl ServerBackups for Server<u64> {
+    fn create_backup(&self, options: &BackupOptions) -> Result<BackupManifest, Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        create_backup(self, options, BackupTrigger::Manual)
+    }
+
+    fn list_backups(&self) -> Result<Vec<BackupManifest>, Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        list_backups(self.id)
+    }
+
+    fn delete_backup(&self, backup_id: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        require_reverification()?;
+        delete_backup(self.id, backup_id)
+    }
+
+    fn get_backup_schedule(&self) -> Result<BackupSchedule, Box<dyn Error>> {
+        get_backup_schedule(self.id)
+    }
+
+    fn set_backup_schedule(&self, schedule: &BackupSchedule) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ManageBackups)?;
+        set_backup_schedule(self.id, schedule)
+    }
+
+    fn get_next_scheduled_backup(&self) -> Result<Option<SystemTime>, Box<dyn Error>> {
+        let schedule = get_backup_schedule(self.id)?;
+        if !schedule.enabled {
+            return Ok(None);
+        }
+        Ok(CronSchedule::from_str(&schedule.cron)?.next_after(SystemTime::now()))
+    }
+}
@@ -0,0 +1,649 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::{send_server_command, watch_console, ServerProcess};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often a task checks on the server and its progress.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the tick rate of the server is measured.
+const TPS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the server gets to answer a tick rate query.
+const TPS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A throttled task resumes once the tick rate is this far above the minimum, so it does not flip
+/// between pausing and resuming on every measurement.
+const TPS_HYSTERESIS: f64 = 1.0;
+
+/// The side of the squares of chunks force loaded at once, `16 * 16` is the most `forceload add` takes.
+const FORCELOAD_BATCH_SIDE: i64 = 16;
+
+/// How long a batch of force loaded chunks is kept loaded at least, for the server to generate it.
+const FORCELOAD_SETTLE_TIME: Duration = Duration::from_secs(10);
+
+static RESUMER: Once = Once::new();
+
+type PregenerationListener = Box<dyn Fn(&PregenerationTask) + Send>;
+
+lazy_static! {
+    static ref PREGENERATION_LISTENERS: Mutex<Vec<PregenerationListener>> = Mutex::new(Vec::new());
+    /// The cancellation flags of the tasks that have a thread driving them, by server.
+    static ref ACTIVE_TASKS: Mutex<HashMap<u64, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+/// How the chunks are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PregenerationMethod {
+    /// Chunky if the server has it installed, force loading otherwise.
+    Auto,
+    /// Drives the Chunky plugin or mod, which generates chunks far faster than force loading.
+    Chunky,
+    /// Force loads the area in squares of 256 chunks with the vanilla `forceload` command. Chunks
+    /// someone else force loaded in the area are no longer force loaded afterwards.
+    Forceload,
+}
+
+/// The area around a point that is generated, in one dimension.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PregenerationOptions {
+    /// The dimension, like `minecraft:overworld` or `minecraft:the_nether`.
+    pub dimension: String,
+    /// The center of the area, in blocks.
+    pub center_x: i32,
+    pub center_z: i32,
+    /// Half the side of the square that is generated, in blocks.
+    pub radius: u32,
+    pub method: PregenerationMethod,
+    /// Generation pauses while the server runs at fewer ticks per second than this.
+    pub min_tps: f64,
+}
+
+impl Default for PregenerationOptions {
+    fn default() -> Self {
+        Self {
+            dimension: "minecraft:overworld".to_string(),
+            center_x: 0,
+            center_z: 0,
+            radius: 5000,
+            method: PregenerationMethod::Auto,
+            min_tps: 18.0,
+        }
+    }
+}
+
+impl PregenerationOptions {
+    /// The first and last chunk coordinate covered on each axis.
+    fn chunk_bounds(&self) -> ((i64, i64), (i64, i64)) {
+        let radius = i64::from(self.radius);
+        let span = |center: i32| {
+            let center = i64::from(center);
+            ((center - radius).div_euclid(16), (center + radius).div_euclid(16))
+        };
+        (span(self.center_x), span(self.center_z))
+    }
+
+    fn total_chunks(&self) -> u64 {
+        let ((min_x, max_x), (min_z, max_z)) = self.chunk_bounds();
+        ((max_x - min_x + 1) * (max_z - min_z + 1)) as u64
+    }
+
+    /// The squares of chunks force loaded one after another, row by row, as the first and last
+    /// chunk on both axes.
+    fn forceload_batches(&self) -> Vec<(i64, i64, i64, i64)> {
+        let ((min_x, max_x), (min_z, max_z)) = self.chunk_bounds();
+        let mut batches = Vec::new();
+        let mut z = min_z;
+        while z <= max_z {
+            let mut x = min_x;
+            while x <= max_x {
+                let last_x = (x + FORCELOAD_BATCH_SIDE - 1).min(max_x);
+                let last_z = (z + FORCELOAD_BATCH_SIDE - 1).min(max_z);
+                batches.push((x, z, last_x, last_z));
+                x += FORCELOAD_BATCH_SIDE;
+            }
+            z += FORCELOAD_BATCH_SIDE;
+        }
+        batches
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let valid_dimension = !self.dimension.is_empty()
+            && self
+                .dimension
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | ':' | '/' | '.' | '-'));
+        if !valid_dimension {
+            return Err(format!("{:?} is not a dimension", self.dimension).into());
+        }
+        if self.radius == 0 || self.radius > 29_999_984 {
+            return Err(format!("A radius of {} blocks is outside of the world", self.radius).into());
+        }
+        if !(0.0..=20.0).contains(&self.min_tps) {
+            return Err(format!("The minimum TPS must be between 0 and 20, not {}", self.min_tps).into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PregenerationState {
+    Running,
+    /// Paused because the server runs below the minimum tick rate.
+    Throttled,
+    /// The server is stopped, the task carries on once it runs again.
+    WaitingForServer,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+impl PregenerationState {
+    fn is_active(&self) -> bool {
+        matches!(
+            self,
+            PregenerationState::Running | PregenerationState::Throttled | PregenerationState::WaitingForServer
+        )
+    }
+}
+
+/// A pregeneration task of a server and how far it got.
+///
+/// Tasks are stored, so one that was interrupted by stopping the server or the manager carries on
+/// where it left off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PregenerationTask {
+    pub server_id: u64,
+    pub options: PregenerationOptions,
+    /// The method the task uses, never `Auto`.
+    pub method: PregenerationMethod,
+    pub state: PregenerationState,
+    pub chunks_done: u64,
+    pub chunks_total: u64,
+    /// The ticks per second the server last ran at.
+    pub tps: Option<f64>,
+    /// Whether Chunky was given the task, a resumed task is continued instead of started again.
+    pub started: bool,
+    /// The squares of force loaded chunks that are done, a resumed task carries on with the next one.
+    pub batches_done: u64,
+    /// The error of a failed task.
+    pub message: Option<String>,
+    /// When the task last changed, in seconds since the Unix epoch.
+    pub updated_at: u64,
+}
+
+impl PregenerationTask {
+    pub fn percentage(&self) -> f64 {
+        if self.chunks_total == 0 {
+            return 100.0;
+        }
+        (self.chunks_done as f64 / self.chunks_total as f64 * 100.0).min(100.0)
+    }
+}
+
+/// Registers a listener that is invoked whenever a pregeneration task progresses or changes state.
+pub fn add_pregeneration_listener(listener: impl Fn(&PregenerationTask) + Send + 'static) {
+    if let Ok(mut listeners) = PREGENERATION_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn emit(task: &PregenerationTask) {
+    match PREGENERATION_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(task)),
+        Err(err) => error!("Failed to notify pregeneration listeners: {}", err),
+    }
+}
+
+/// Creates the table holding the pregeneration tasks of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_pregeneration_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_pregeneration` (
+            server_id INTEGER PRIMARY KEY,                              -- The server generating chunks
+            task TEXT NOT NULL                                          -- The task and its progress, as JSON
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Reads the last pregeneration task of a server, finished or not.
+pub fn get_pregeneration(server_id: u64) -> Result<Option<PregenerationTask>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT task FROM server_pregeneration WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(Some(serde_json::from_str(&statement.read::<String, _>("task")?)?));
+    }
+    Ok(None)
+}
+
+/// Stores a task and reports it to the listeners. A task that cannot be stored keeps running, it
+/// only cannot be resumed from where it is.
+fn save_task(task: &mut PregenerationTask) {
+    task.updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if let Err(e) = store_task(task) {
+        warn!("Failed to store the pregeneration task of server {}: {}", task.server_id, e);
+    }
+    emit(task);
+}
+
+fn store_task(task: &PregenerationTask) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO server_pregeneration (server_id, task) VALUES (?, ?)
+        ON CONFLICT(server_id) DO UPDATE SET task = excluded.task"#,
+    )?;
+    statement.bind((1, task.server_id as i64))?;
+    statement.bind((2, serde_json::to_string(task)?.as_str()))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Starts generating the chunks of an area of a server in the background.
+///
+/// The task waits for the server to run, so it can be started while the server is stopped, and
+/// pauses while the server lags. Progress is reported to the pregeneration listeners.
+///
+/// # Errors
+/// Returns an error if the acting user may not send commands, the options are invalid, the server
+/// already runs a pregeneration task, or Chunky was asked for and is not installed.
+pub fn start_pregeneration(
+    server: &Server<u64>,
+    options: &PregenerationOptions,
+) -> Result<PregenerationTask, Box<dyn Error>> {
+    authorize(server, Capability::SendCommands)?;
+    options.validate()?;
+    if ACTIVE_TASKS.lock().map_err(|e| e.to_string())?.contains_key(&server.id) {
+        return Err(format!("Server {:?} is already generating chunks", server.name).into());
+    }
+    let chunky = has_chunky(server);
+    let method = match options.method {
+        PregenerationMethod::Auto if chunky => PregenerationMethod::Chunky,
+        PregenerationMethod::Auto => PregenerationMethod::Forceload,
+        PregenerationMethod::Chunky if !chunky => {
+            return Err(format!("Chunky is not installed on server {:?}", server.name).into());
+        }
+        method => method,
+    };
+    let mut task = PregenerationTask {
+        server_id: server.id,
+        options: options.clone(),
+        method,
+        state: PregenerationState::WaitingForServer,
+        chunks_done: 0,
+        chunks_total: options.total_chunks(),
+        tps: None,
+        started: false,
+        batches_done: 0,
+        message: None,
+        updated_at: 0,
+    };
+    save_task(&mut task);
+    spawn_task(task.clone())?;
+    info!(
+        "Started generating {} chunks around {}, {} on server {:?} with {:?}",
+        task.chunks_total, options.center_x, options.center_z, server.name, method
+    );
+    Ok(task)
+}
+
+/// Stops the pregeneration task of a server. The chunks generated so far are kept.
+///
+/// # Errors
+/// Returns an error if the acting user may not send commands, or the server runs no pregeneration task.
+pub fn cancel_pregeneration(server_id: u64) -> Result<(), Box<dyn Error>> {
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::SendCommands)?;
+    let tasks = ACTIVE_TASKS.lock().map_err(|e| e.to_string())?;
+    let cancel = tasks
+        .get(&server_id)
+        .ok_or_else(|| format!("Server {} is not generating chunks", server_id))?;
+    cancel.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Resumes the pregeneration tasks that were not finished when the manager stopped. Calling it
+/// again has no effect.
+pub fn start_pregeneration_tasks() {
+    RESUMER.call_once(|| {
+        let tasks = match unfinished_tasks() {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                error!("Failed to read the unfinished pregeneration tasks: {}", e);
+                return;
+            }
+        };
+        for task in tasks {
+            let server_id = task.server_id;
+            if let Err(e) = spawn_task(task) {
+                error!("Failed to resume the pregeneration task of server {}: {}", server_id, e);
+            }
+        }
+    });
+}
+
+fn unfinished_tasks() -> Result<Vec<PregenerationTask>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT task FROM server_pregeneration"#)?;
+    let mut tasks = Vec::new();
+    while let State::Row = statement.next()? {
+        match serde_json::from_str::<PregenerationTask>(&statement.read::<String, _>("task")?) {
+            Ok(task) if task.state.is_active() => tasks.push(task),
+            Ok(_) => {}
+            Err(e) => warn!("Ignoring an unreadable pregeneration task: {}", e),
+        }
+    }
+    Ok(tasks)
+}
+
+fn spawn_task(mut task: PregenerationTask) -> Result<(), Box<dyn Error>> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    ACTIVE_TASKS
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert(task.server_id, Arc::clone(&cancel));
+    thread::spawn(move || {
+        let result =
+            Server::<u64>::get_server(task.server_id).and_then(|server| run_task(&server, &mut task, &cancel));
+        match result {
+            Ok(()) if cancel.load(Ordering::SeqCst) => task.state = PregenerationState::Cancelled,
+            Ok(()) => {
+                task.state = PregenerationState::Completed;
+                task.chunks_done = task.chunks_total;
+            }
+            Err(e) => {
+                error!("Pregeneration on server {} failed: {}", task.server_id, e);
+                task.state = PregenerationState::Failed;
+                task.message = Some(e.to_string());
+            }
+        }
+        save_task(&mut task);
+        if let Ok(mut tasks) = ACTIVE_TASKS.lock() {
+            tasks.remove(&task.server_id);
+        }
+    });
+    Ok(())
+}
+
+/// What the console of a server says about its Chunky task.
+enum ChunkyUpdate {
+    Progress(u64),
+    Finished,
+}
+
+/// The state of the server a task drives, reset whenever the server starts again.
+#[derive(Default)]
+struct Session {
+    /// The Chunky messages, while the server runs.
+    chunky: Option<Receiver<ChunkyUpdate>>,
+    /// The square of chunks that is force loaded and when it was.
+    loaded: Option<((i64, i64, i64, i64), Instant)>,
+    last_tps: Option<Instant>,
+    /// The command the server answered with its tick rate.
+    tps_command: Option<&'static str>,
+}
+
+fn run_task(server: &Server<u64>, task: &mut PregenerationTask, cancel: &AtomicBool) -> Result<(), Box<dyn Error>> {
+    let batches = task.options.forceload_batches();
+    let mut session: Option<Session> = None;
+    loop {
+        if !server.is_server_running() {
+            session = None;
+            if task.state != PregenerationState::WaitingForServer {
+                task.state = PregenerationState::WaitingForServer;
+                save_task(task);
+            }
+            if cancel.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        let current = match session.as_mut() {
+            Some(current) => current,
+            None => {
+                let mut current = Session::default();
+                if task.method == PregenerationMethod::Chunky {
+                    current.chunky = Some(start_chunky(server, task)?);
+                }
+                task.state = PregenerationState::Running;
+                save_task(task);
+                session.insert(current)
+            }
+        };
+
+        if cancel.load(Ordering::SeqCst) {
+            if task.method == PregenerationMethod::Chunky {
+                send_server_command(server, "chunky cancel")?;
+            }
+            if let Some((batch, _)) = current.loaded.take() {
+                forceload(server, &task.options.dimension, batch, false)?;
+            }
+            return Ok(());
+        }
+
+        if current.last_tps.is_none_or(|measured| measured.elapsed() >= TPS_INTERVAL) {
+            current.last_tps = Some(Instant::now());
+            task.tps = measure_tps(server, &mut current.tps_command);
+            throttle(server, task)?;
+        }
+
+        if task.method == PregenerationMethod::Chunky {
+            let before = task.chunks_done;
+            let mut finished = false;
+            if let Some(updates) = &current.chunky {
+                for update in updates.try_iter() {
+                    match update {
+                        ChunkyUpdate::Progress(chunks) => task.chunks_done = chunks.min(task.chunks_total),
+                        ChunkyUpdate::Finished => finished = true,
+                    }
+                }
+            }
+            if finished {
+                return Ok(());
+            }
+            if task.chunks_done != before {
+                save_task(task);
+            }
+        } else {
+            if let Some((batch, loaded_at)) = current.loaded {
+                if task.state == PregenerationState::Throttled || loaded_at.elapsed() < FORCELOAD_SETTLE_TIME {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                forceload(server, &task.options.dimension, batch, false)?;
+                current.loaded = None;
+                task.batches_done += 1;
+                task.chunks_done = (task.chunks_done + batch_chunks(batch)).min(task.chunks_total);
+                save_task(task);
+            }
+            let Some(&batch) = batches.get(task.batches_done as usize) else {
+                return Ok(());
+            };
+            if task.state != PregenerationState::Throttled {
+                forceload(server, &task.options.dimension, batch, true)?;
+                current.loaded = Some((batch, Instant::now()));
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Pauses a task whose server runs below the minimum tick rate, and resumes it once it recovered.
+fn throttle(server: &Server<u64>, task: &mut PregenerationTask) -> Result<(), Box<dyn Error>> {
+    let Some(tps) = task.tps else {
+        return Ok(());
+    };
+    let throttled = task.state == PregenerationState::Throttled;
+    if !throttled && tps < task.options.min_tps {
+        if task.method == PregenerationMethod::Chunky {
+            send_server_command(server, "chunky pause")?;
+        }
+        task.state = PregenerationState::Throttled;
+        info!("Paused generating chunks on server {:?}, it runs at {:.1} TPS", server.name, tps);
+        save_task(task);
+    } else if throttled && tps >= (task.options.min_tps + TPS_HYSTERESIS).min(20.0) {
+        if task.method == PregenerationMethod::Chunky {
+            send_server_command(server, "chunky continue")?;
+        }
+        task.state = PregenerationState::Running;
+        info!("Resumed generating chunks on server {:?}, it runs at {:.1} TPS", server.name, tps);
+        save_task(task);
+    }
+    Ok(())
+}
+
+/// Hands the area to Chunky, or has it continue the task it stored before the server stopped.
+fn start_chunky(
+    server: &Server<u64>,
+    task: &mut PregenerationTask,
+) -> Result<Receiver<ChunkyUpdate>, Box<dyn Error>> {
+    let (sender, receiver) = mpsc::channel();
+    watch_console(server.id, move |line| {
+        if !line.contains("[Chunky]") {
+            return true;
+        }
+        let update = if line.contains("Task finished") {
+            Some(ChunkyUpdate::Finished)
+        } else {
+            processed_chunks(line).map(ChunkyUpdate::Progress)
+        };
+        update.is_none_or(|update| sender.send(update).is_ok())
+    });
+
+    if task.started {
+        send_server_command(server, "chunky continue")?;
+        return Ok(receiver);
+    }
+    let options = &task.options;
+    for command in [
+        format!("chunky world {}", options.dimension),
+        "chunky shape square".to_string(),
+        format!("chunky center {} {}", options.center_x, options.center_z),
+        format!("chunky radius {}", options.radius),
+        "chunky start".to_string(),
+        // Chunky asks before replacing a task it has for the dimension already
+        "chunky confirm".to_string(),
+    ] {
+        send_server_command(server, command)?;
+    }
+    task.started = true;
+    save_task(task);
+    Ok(receiver)
+}
+
+/// Reads the chunks done from a Chunky message like `Processed: 1,234 chunks (5.67%)`.
+fn processed_chunks(line: &str) -> Option<u64> {
+    let (_, rest) = line.split_once("Processed: ")?;
+    let count: String = rest
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || matches!(c, ',' | '.'))
+        .filter(char::is_ascii_digit)
+        .collect();
+    count.parse().ok()
+}
+
+fn forceload(
+    server: &Server<u64>,
+    dimension: &str,
+    batch: (i64, i64, i64, i64),
+    add: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (from_x, from_z, to_x, to_z) = batch;
+    let command = format!(
+        "execute in {} run forceload {} {} {} {} {}",
+        dimension,
+        if add { "add" } else { "remove" },
+        from_x * 16,
+        from_z * 16,
+        to_x * 16,
+        to_z * 16
+    );
+    send_server_command(server, command)
+}
+
+fn batch_chunks((from_x, from_z, to_x, to_z): (i64, i64, i64, i64)) -> u64 {
+    ((to_x - from_x + 1) * (to_z - from_z + 1)) as u64
+}
+
+/// Asks the server for its ticks per second, with `tps` on Paper and its forks and `tick query` on
+/// vanilla 1.20.3 and later. The command that was answered is remembered for the next time.
+pub(crate) fn measure_tps(server: &Server<u64>, command: &mut Option<&'static str>) -> Option<f64> {
+    let commands: Vec<&'static str> = match command {
+        Some(command) => vec![*command],
+        None => vec!["tps", "tick query"],
+    };
+    for candidate in commands {
+        let (sender, receiver) = mpsc::channel();
+        let deadline = Instant::now() + TPS_TIMEOUT;
+        watch_console(server.id, move |line| match parse_tps(line) {
+            Some(tps) => {
+                let _ = sender.send(tps);
+                false
+            }
+            None => Instant::now() < deadline,
+        });
+        if send_server_command(server, candidate).is_err() {
+            return None;
+        }
+        if let Ok(tps) = receiver.recv_timeout(TPS_TIMEOUT) {
+            *command = Some(candidate);
+            return Some(tps);
+        }
+    }
+    None
+}
+
+/// Reads the tick rate from `TPS from last 1m, 5m, 15m: 19.9, 20.0, 20.0` of Paper, or from the
+/// `Average time per tick: 12.3ms` of `tick query`. Color codes around the numbers are skipped.
+fn parse_tps(line: &str) -> Option<f64> {
+    let number = |text: &str| {
+        text.chars()
+            .skip_while(|c| !c.is_ascii_digit())
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect::<String>()
+            .parse::<f64>()
+            .ok()
+    };
+    if let Some((_, averages)) = line.split_once("15m:") {
+        return number(averages.split(',').next()?).map(|tps| tps.min(20.0));
+    }
+    let (_, rest) = line.split_once("Average time per tick: ")?;
+    let milliseconds = number(rest)?;
+    Some(if milliseconds <= 50.0 { 20.0 } else { 1000.0 / milliseconds })
+}
+
+/// Whether Chunky is in the plugins or mods folder of a server.
+fn has_chunky(server: &Server<u64>) -> bool {
+    ["plugins", "mods"].iter().any(|folder| {
+        fs::read_dir(server.directory.join(folder))
+            .map(|entries| {
+                entries.flatten().any(|entry| {
+                    let name = entry.file_name().to_string_lossy().to_lowercase();
+                    name.starts_with("chunky") && name.ends_with(".jar")
+                })
+            })
+            .unwrap_or(false)
+    })
+}
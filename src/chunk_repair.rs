@@ -0,0 +1,237 @@
+use crate::file_system_entry::{invalidate_directory_size_cache, is_managed_path};
+use crate::region_file::{parse_region_coordinates, ChunkProblem, RegionFile};
+use crate::sandboxed_path::SandboxedPath;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// What is done with a corrupted chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepairAction {
+    /// Removes the chunk, the game generates it again the next time it is loaded.
+    Delete,
+    /// Replaces the chunk with its copy from a backup of the world.
+    Restore,
+}
+
+/// Options controlling a chunk repair.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkRepairOptions {
+    pub action: RepairAction,
+    /// Only reports what would be done, without changing any file.
+    pub dry_run: bool,
+    /// Decompresses every chunk to find corrupted chunk data, not only broken headers.
+    pub verify: bool,
+    /// Deletes chunks that cannot be restored because the backup has no healthy copy of them.
+    pub delete_unrestorable: bool,
+    /// Copies every region file to `<name>.bak` before changing it.
+    pub keep_backup: bool,
+}
+
+impl Default for ChunkRepairOptions {
+    fn default() -> Self {
+        Self {
+            action: RepairAction::Delete,
+            dry_run: true,
+            verify: true,
+            delete_unrestorable: false,
+            keep_backup: true,
+        }
+    }
+}
+
+/// What happened, or would happen in a dry run, to a corrupted chunk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RepairOutcome {
+    Deleted,
+    Restored,
+    WouldDelete,
+    WouldRestore,
+    /// The chunk was left alone.
+    Skipped { reason: String },
+    Failed { message: String },
+}
+
+/// A corrupted chunk and what was done with it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkRepair {
+    /// The region file containing the chunk, relative to the world directory.
+    pub region: PathBuf,
+    pub x: i32,
+    pub z: i32,
+    pub problems: Vec<ChunkProblem>,
+    pub outcome: RepairOutcome,
+}
+
+/// The result of scanning a world for corrupted chunks.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChunkRepairReport {
+    pub dry_run: bool,
+    pub regions_scanned: usize,
+    pub chunks_scanned: usize,
+    /// Every corrupted chunk found, in the order the region files were scanned.
+    pub repairs: Vec<ChunkRepair>,
+    /// Region files whose header could not be read, relative to the world directory. These have
+    /// to be deleted or restored as a whole.
+    pub unreadable_regions: Vec<PathBuf>,
+}
+
+/// Scans a world for corrupted chunks and deletes them or restores them from a backup.
+///
+/// The region, entity and POI files of every dimension are scanned. Run it with
+/// `dry_run` first to review the report, and only while the server is stopped, since the game
+/// keeps region files open and would overwrite the repair.
+///
+/// # Arguments
+/// * `world` - The world directory, the one containing `level.dat`.
+/// * `backup` - A copy of the world to restore chunks from, such as an extracted backup.
+///   Region files are looked up at the same relative path. Required to restore chunks.
+/// * `options` - The action to take and whether to only report it.
+///
+/// # Errors
+/// Returns an error if the world is not a directory, or chunks should be restored without a backup.
+/// Failures to repair single chunks are reported in the returned report instead.
+pub fn repair_chunks(
+    world: &SandboxedPath,
+    backup: Option<&SandboxedPath>,
+    options: &ChunkRepairOptions,
+) -> Result<ChunkRepairReport, Box<dyn Error>> {
+    let world_path = world.path();
+    if !world_path.is_dir() {
+        return Err(format!("{} is not a directory", world).into());
+    }
+    if options.action == RepairAction::Restore && backup.is_none() {
+        return Err("Restoring chunks requires a backup of the world".into());
+    }
+
+    let mut report = ChunkRepairReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    for path in find_region_files(world.root_path(), &world_path) {
+        let relative = path.strip_prefix(&world_path).unwrap_or(&path).to_path_buf();
+        let mut region = match RegionFile::open_path(&path) {
+            Ok(region) => region,
+            Err(err) => {
+                warn!("Failed to read region file {:?}: {}", path, err);
+                report.unreadable_regions.push(relative);
+                continue;
+            }
+        };
+        let info = region.inspect(options.verify);
+        report.regions_scanned += 1;
+        report.chunks_scanned += info.chunks.len();
+        if info.corrupted_chunks == 0 {
+            continue;
+        }
+
+        let restorable = match (options.action, backup) {
+            (RepairAction::Restore, Some(backup)) => healthy_chunks(&backup.path().join(&relative), options.verify),
+            _ => HashSet::new(),
+        };
+        let mut backed_up = !options.keep_backup;
+        for chunk in info.chunks.into_iter().filter(|chunk| chunk.is_corrupted()) {
+            let restore = options.action == RepairAction::Restore && restorable.contains(&(chunk.x, chunk.z));
+            let delete = options.action == RepairAction::Delete || (!restore && options.delete_unrestorable);
+            let outcome = if !restore && !delete {
+                RepairOutcome::Skipped {
+                    reason: "The backup has no healthy copy of the chunk".to_string(),
+                }
+            } else if options.dry_run {
+                if restore {
+                    RepairOutcome::WouldRestore
+                } else {
+                    RepairOutcome::WouldDelete
+                }
+            } else {
+                let result = back_up_region(&path, &mut backed_up).and_then(|_| match backup {
+                    Some(backup) if restore => {
+                        restore_chunk(&mut region, &backup.path().join(&relative), chunk.x, chunk.z)
+                            .map(|_| RepairOutcome::Restored)
+                    }
+                    _ => region.delete_chunk(chunk.x, chunk.z).map(|_| RepairOutcome::Deleted),
+                });
+                result.unwrap_or_else(|err| RepairOutcome::Failed {
+                    message: err.to_string(),
+                })
+            };
+            report.repairs.push(ChunkRepair {
+                region: relative.clone(),
+                x: chunk.x,
+                z: chunk.z,
+                problems: chunk.problems,
+                outcome,
+            });
+        }
+    }
+
+    if !options.dry_run && !report.repairs.is_empty() {
+        invalidate_directory_size_cache(&world_path);
+    }
+    info!(
+        "Scanned {} chunks in {} region files of {}, {} corrupted",
+        report.chunks_scanned,
+        report.regions_scanned,
+        world,
+        report.repairs.len()
+    );
+    Ok(report)
+}
+
+/// Finds the region files of every dimension of a world, skipping the manager's own directories.
+fn find_region_files(root: &Path, world: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = WalkDir::new(world)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| !is_managed_path(root, entry.path()))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && parse_region_coordinates(entry.path()).is_some())
+        .map(|entry| entry.into_path())
+        .collect();
+    files.sort();
+    files
+}
+
+/// The chunks of a backup region file that are populated and not corrupted.
+fn healthy_chunks(path: &Path, verify: bool) -> HashSet<(i32, i32)> {
+    if !path.is_file() {
+        return HashSet::new();
+    }
+    match RegionFile::open_path(path) {
+        Ok(region) => region
+            .inspect(verify)
+            .chunks
+            .into_iter()
+            .filter(|chunk| !chunk.is_corrupted())
+            .map(|chunk| (chunk.x, chunk.z))
+            .collect(),
+        Err(err) => {
+            warn!("Failed to read backup region file {:?}: {}", path, err);
+            HashSet::new()
+        }
+    }
+}
+
+/// Copies a region file to `<name>.bak` the first time one of its chunks is changed.
+fn back_up_region(path: &Path, backed_up: &mut bool) -> Result<(), Box<dyn Error>> {
+    if *backed_up {
+        return Ok(());
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    fs::copy(path, path.with_file_name(name))?;
+    *backed_up = true;
+    Ok(())
+}
+
+fn restore_chunk(region: &mut RegionFile, backup: &Path, x: i32, z: i32) -> Result<(), Box<dyn Error>> {
+    let (compression, data, timestamp) = RegionFile::open_path(backup)?.read_compressed_chunk(x, z)?;
+    region.write_compressed_chunk(x, z, compression, &data, timestamp)
+}
@@ -0,0 +1,349 @@
+use log::info;
+use obsidian_sqlite::create_appdb_connection;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use sqlite::{Connection, State};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A change of the database schema, applied once and recorded in `schema_migrations`.
+struct Migration {
+    version: u32,
+    name: &'static str,
+    apply: fn(&Connection) -> Result<(), Box<dyn Error>>,
+}
+
+/// Every migration, in the order they are applied. Append new ones with the next version, an
+/// applied migration is never changed.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create the config store",
+        apply: create_config_store,
+    },
+    Migration {
+        version: 2,
+        name: "move the settings tables into the config store",
+        apply: move_settings_into_config_store,
+    },
+];
+
+/// The per-server settings that had a table of their own, with the column holding their JSON
+/// and the key they are stored under now.
+const LEGACY_SERVER_SETTINGS: [(&str, &str, &str); 4] = [
+    ("server_alert_rules", "rules", "alert_rules"),
+    ("server_discord_bridge", "settings", "discord_bridge"),
+    ("server_jvm_flags", "settings", "jvm_flags"),
+    ("server_launch_template", "template", "launch_template"),
+];
+
+/// Whose settings a value belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigScope {
+    /// The settings of the panel itself, like its TLS certificate.
+    Panel,
+    /// The settings of one server, removed along with the server.
+    Server(u64),
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64
+}
+
+/// The version of the database schema, the highest migration applied to it.
+pub fn schema_version() -> Result<u32, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    create_migration_table(&conn)?;
+    current_version(&conn)
+}
+
+/// Applies the migrations the database is missing, each in a transaction of its own, and returns
+/// how many were applied. Run on every start, before anything reads the settings.
+///
+/// # Errors
+/// Returns an error if a migration fails, which leaves the database at the version before it, or
+/// if the database was migrated by a newer version of the manager.
+pub fn run_migrations() -> Result<usize, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    create_migration_table(&conn)?;
+    let current = current_version(&conn)?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        return Err(format!(
+            "The database schema is at version {}, newer than the version {} this manager knows",
+            current, latest
+        )
+        .into());
+    }
+
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|migration| migration.version > current).collect();
+    for migration in &pending {
+        info!("Migrating the database to version {}: {}", migration.version, migration.name);
+        conn.execute("BEGIN IMMEDIATE")?;
+        let result = (migration.apply)(&conn).and_then(|_| {
+            let mut statement =
+                conn.prepare("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?)")?;
+            statement.bind((1, i64::from(migration.version)))?;
+            statement.bind((2, migration.name))?;
+            statement.bind((3, now()))?;
+            statement.next()?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => conn.execute("COMMIT")?,
+            Err(e) => {
+                let _ = conn.execute("ROLLBACK");
+                return Err(format!("Failed to migrate the database to version {}: {}", migration.version, e).into());
+            }
+        }
+    }
+    Ok(pending.len())
+}
+
+/// Reads a setting, `None` if it was never set.
+///
+/// # Errors
+/// Returns an error if the database cannot be read, or the stored value does not fit `T`.
+pub fn get_config<T: DeserializeOwned>(scope: ConfigScope, key: &str) -> Result<Option<T>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = match scope {
+        ConfigScope::Panel => {
+            let mut statement = conn.prepare("SELECT value FROM panel_config WHERE config_key = ?")?;
+            statement.bind((1, key))?;
+            statement
+        }
+        ConfigScope::Server(server_id) => {
+            let mut statement =
+                conn.prepare("SELECT value FROM server_config WHERE server_id = ? AND config_key = ?")?;
+            statement.bind((1, server_id as i64))?;
+            statement.bind((2, key))?;
+            statement
+        }
+    };
+    if let State::Row = statement.next()? {
+        let value = statement.read::<String, _>("value")?;
+        return Ok(Some(
+            serde_json::from_str(&value).map_err(|e| format!("The setting {:?} is invalid: {}", key, e))?,
+        ));
+    }
+    Ok(None)
+}
+
+/// Stores a setting, replacing its previous value.
+///
+/// # Errors
+/// Returns an error if the value cannot be serialized, or the database cannot be written.
+pub fn set_config<T: Serialize + ?Sized>(scope: ConfigScope, key: &str, value: &T) -> Result<(), Box<dyn Error>> {
+    let value = serde_json::to_string(value)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = match scope {
+        ConfigScope::Panel => {
+            let mut statement = conn.prepare(
+                r#"INSERT INTO panel_config (config_key, value, updated_at) VALUES (?, ?, ?)
+                ON CONFLICT(config_key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at"#,
+            )?;
+            statement.bind((1, key))?;
+            statement.bind((2, value.as_str()))?;
+            statement.bind((3, now()))?;
+            statement
+        }
+        ConfigScope::Server(server_id) => {
+            let mut statement = conn.prepare(
+                r#"INSERT INTO server_config (server_id, config_key, value, updated_at) VALUES (?, ?, ?, ?)
+                ON CONFLICT(server_id, config_key)
+                DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at"#,
+            )?;
+            statement.bind((1, server_id as i64))?;
+            statement.bind((2, key))?;
+            statement.bind((3, value.as_str()))?;
+            statement.bind((4, now()))?;
+            statement
+        }
+    };
+    statement.next()?;
+    Ok(())
+}
+
+/// Removes a setting, so it reads as never set.
+///
+/// # Errors
+/// Returns an error if the database cannot be written.
+pub fn remove_config(scope: ConfigScope, key: &str) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = match scope {
+        ConfigScope::Panel => {
+            let mut statement = conn.prepare("DELETE FROM panel_config WHERE config_key = ?")?;
+            statement.bind((1, key))?;
+            statement
+        }
+        ConfigScope::Server(server_id) => {
+            let mut statement = conn.prepare("DELETE FROM server_config WHERE server_id = ? AND config_key = ?")?;
+            statement.bind((1, server_id as i64))?;
+            statement.bind((2, key))?;
+            statement
+        }
+    };
+    statement.next()?;
+    Ok(())
+}
+
+/// Lists every setting of a scope by key, like to export or show them.
+pub fn list_config(scope: ConfigScope) -> Result<BTreeMap<String, Value>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = match scope {
+        ConfigScope::Panel => conn.prepare("SELECT config_key, value FROM panel_config")?,
+        ConfigScope::Server(server_id) => {
+            let mut statement = conn.prepare("SELECT config_key, value FROM server_config WHERE server_id = ?")?;
+            statement.bind((1, server_id as i64))?;
+            statement
+        }
+    };
+    let mut settings = BTreeMap::new();
+    while let State::Row = statement.next()? {
+        let value = serde_json::from_str(&statement.read::<String, _>("value")?)?;
+        settings.insert(statement.read::<String, _>("config_key")?, value);
+    }
+    Ok(settings)
+}
+
+fn create_migration_table(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS `schema_migrations` (
+            version INTEGER PRIMARY KEY,                                -- The schema version it migrates to
+            name TEXT NOT NULL,                                         -- What the migration does
+            applied_at INTEGER NOT NULL                                 -- Unix time it was applied
+        );
+"#,
+    )?;
+    Ok(())
+}
+
+fn current_version(conn: &Connection) -> Result<u32, Box<dyn Error>> {
+    let mut statement = conn.prepare("SELECT MAX(version) AS version FROM schema_migrations")?;
+    statement.next()?;
+    Ok(statement.read::<Option<i64>, _>("version")?.unwrap_or_default() as u32)
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool, Box<dyn Error>> {
+    let mut statement = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?")?;
+    statement.bind((1, table))?;
+    Ok(matches!(statement.next()?, State::Row))
+}
+
+fn create_config_store(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        r#"
+        CREATE TABLE IF NOT EXISTS `panel_config` (
+            config_key TEXT PRIMARY KEY,                                -- The name of the setting
+            value TEXT NOT NULL,                                        -- The setting, as JSON
+            updated_at INTEGER NOT NULL                                 -- Unix time it was last changed
+        );
+        CREATE TABLE IF NOT EXISTS `server_config` (
+            server_id INTEGER NOT NULL,                                 -- The server the setting belongs to
+            config_key TEXT NOT NULL,                                   -- The name of the setting
+            value TEXT NOT NULL,                                        -- The setting, as JSON
+            updated_at INTEGER NOT NULL,                                -- Unix time it was last changed
+            PRIMARY KEY (server_id, config_key)
+        );
+"#,
+    )?;
+    Ok(())
+}
+
+/// Copies the settings from the tables each feature used to keep them in, and drops those.
+fn move_settings_into_config_store(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    for (table, column, key) in LEGACY_SERVER_SETTINGS {
+        if !table_exists(conn, table)? {
+            continue;
+        }
+        let mut statement = conn.prepare(format!(
+            r#"INSERT OR IGNORE INTO server_config (server_id, config_key, value, updated_at)
+            SELECT server_id, ?, {}, ? FROM {}"#,
+            column, table
+        ))?;
+        statement.bind((1, key))?;
+        statement.bind((2, now()))?;
+        statement.next()?;
+        conn.execute(format!("DROP TABLE {}", table))?;
+    }
+    if table_exists(conn, "panel_tls")? {
+        let mut statement = conn.prepare(
+            r#"INSERT OR IGNORE INTO panel_config (config_key, value, updated_at)
+            SELECT 'tls', settings, ? FROM panel_tls"#,
+        )?;
+        statement.bind((1, now()))?;
+        statement.next()?;
+        conn.execute("DROP TABLE panel_tls")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_value(conn: &Connection, query: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let mut statement = conn.prepare(query)?;
+        if let State::Row = statement.next()? {
+            return Ok(Some(statement.read::<String, _>("value")?));
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn migrations_are_numbered_in_order() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, index + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn settings_tables_move_into_the_config_store() -> Result<(), Box<dyn Error>> {
+        let conn = sqlite::open(":memory:")?;
+        conn.execute(
+            r#"
+            CREATE TABLE server_jvm_flags (server_id INTEGER PRIMARY KEY, settings TEXT NOT NULL);
+            INSERT INTO server_jvm_flags VALUES (7, '{"preset":"aikar"}');
+            CREATE TABLE server_alert_rules (server_id INTEGER PRIMARY KEY, rules TEXT NOT NULL);
+            INSERT INTO server_alert_rules VALUES (7, '[]');
+            CREATE TABLE panel_tls (id INTEGER PRIMARY KEY, settings TEXT NOT NULL);
+            INSERT INTO panel_tls VALUES (1, '{"enabled":true}');
+"#,
+        )?;
+        create_config_store(&conn)?;
+        move_settings_into_config_store(&conn)?;
+
+        let jvm_flags = "SELECT value FROM server_config WHERE server_id = 7 AND config_key = 'jvm_flags'";
+        assert_eq!(read_value(&conn, jvm_flags)?.as_deref(), Some(r#"{"preset":"aikar"}"#));
+        let alert_rules = "SELECT value FROM server_config WHERE server_id = 7 AND config_key = 'alert_rules'";
+        assert_eq!(read_value(&conn, alert_rules)?.as_deref(), Some("[]"));
+        let tls = "SELECT value FROM panel_config WHERE config_key = 'tls'";
+        assert_eq!(read_value(&conn, tls)?.as_deref(), Some(r#"{"enabled":true}"#));
+        assert!(!table_exists(&conn, "server_jvm_flags")?);
+        assert!(!table_exists(&conn, "panel_tls")?);
+        Ok(())
+    }
+
+    #[test]
+    fn moving_settings_without_the_old_tables_changes_nothing() -> Result<(), Box<dyn Error>> {
+        let conn = sqlite::open(":memory:")?;
+        create_config_store(&conn)?;
+        move_settings_into_config_store(&conn)?;
+        assert_eq!(read_value(&conn, "SELECT value FROM server_config")?, None);
+        assert_eq!(read_value(&conn, "SELECT value FROM panel_config")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn the_version_is_the_highest_applied_migration() -> Result<(), Box<dyn Error>> {
+        let conn = sqlite::open(":memory:")?;
+        create_migration_table(&conn)?;
+        assert_eq!(current_version(&conn)?, 0);
+        conn.execute("INSERT INTO schema_migrations VALUES (1, 'first', 0), (2, 'second', 0)")?;
+        assert_eq!(current_version(&conn)?, 2);
+        Ok(())
+    }
+}
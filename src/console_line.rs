@@ -0,0 +1,282 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+lazy_static! {
+    /// `[12:34:56] [Server thread/INFO]: ...` as written by vanilla, Paper's log files and Fabric, and
+    /// `[14Oct2026 12:34:56.789] [main/INFO] [net.minecraftforge.fml.loading.FMLLoader/]: ...` as
+    /// written by Forge and NeoForge. Older Fabric versions put the logger in parentheses instead.
+    static ref THREAD_FORMAT: Option<Regex> = Regex::new(concat!(
+        r"^\[(?P<time>[^\]]+)\] \[(?P<thread>[^\]]*)/(?P<level>[A-Z]+)\]",
+        r"(?: \[(?P<logger>[^\]]*)\]| \((?P<fabric_logger>[^)]*)\))?:? ?(?P<message>.*)$"
+    ))
+    .ok();
+    /// `[12:34:56 INFO]: ...` as printed by the console of Paper, Spigot and Velocity.
+    static ref LEVEL_FORMAT: Option<Regex> =
+        Regex::new(r"^\[(?P<time>\d{1,2}:\d{2}:\d{2}) (?P<level>[A-Z]+)\]:? ?(?P<message>.*)$").ok();
+    static ref CHAT_MESSAGE: Option<Regex> = Regex::new(r"^(?:\[Not Secure\] )?<(?P<player>[^>]+)> ").ok();
+    static ref JOIN_MESSAGE: Option<Regex> = Regex::new(r"^(?P<player>[A-Za-z0-9_.]+) joined the game").ok();
+    static ref LEAVE_MESSAGE: Option<Regex> = Regex::new(r"^(?P<player>[A-Za-z0-9_.]+) left the game").ok();
+}
+
+/// The severity of a console line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl LogLevel {
+    fn parse(level: &str) -> Option<Self> {
+        match level {
+            "TRACE" | "FINEST" | "FINER" => Some(LogLevel::Trace),
+            "DEBUG" | "FINE" => Some(LogLevel::Debug),
+            "INFO" | "CONFIG" => Some(LogLevel::Info),
+            "WARN" | "WARNING" => Some(LogLevel::Warn),
+            "ERROR" | "SEVERE" => Some(LogLevel::Error),
+            "FATAL" => Some(LogLevel::Fatal),
+            _ => None,
+        }
+    }
+}
+
+/// What a console line is about, for filtering the console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleTag {
+    /// A chat message of a player.
+    Chat,
+    /// A player joined the server.
+    Join,
+    /// A player left the server.
+    Leave,
+    Warning,
+    /// An error, including the lines of a stack trace.
+    Error,
+}
+
+/// A piece of text printed in the same style.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ColoredSpan {
+    pub text: String,
+    /// The foreground color as `#rrggbb`, or `None` for the default color.
+    pub color: Option<String>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+/// A console line split into its parts.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsoleLine {
+    /// The time as printed by the server, the formats differ between server software.
+    pub timestamp: Option<String>,
+    pub thread: Option<String>,
+    /// The level, `None` for lines without a prefix such as the lines of a stack trace.
+    pub level: Option<LogLevel>,
+    /// The class or plugin that logged the line.
+    pub logger: Option<String>,
+    /// The message without the prefix and without ANSI codes.
+    pub message: String,
+    /// The player a chat, join or leave message is about.
+    pub player: Option<String>,
+    pub tags: Vec<ConsoleTag>,
+    /// The whole line in its colors, for rendering it like a terminal would.
+    pub spans: Vec<ColoredSpan>,
+}
+
+/// Parses a line of the server console or of a log file.
+///
+/// Lines in an unknown format are kept as a message without timestamp, thread or level.
+pub fn parse_console_line(line: &str) -> ConsoleLine {
+    let spans = ansi_spans(line);
+    let plain: String = spans.iter().map(|span| span.text.as_str()).collect();
+    let plain = plain.trim_end_matches(['\r', '\n']);
+
+    let mut parsed = ConsoleLine {
+        timestamp: None,
+        thread: None,
+        level: None,
+        logger: None,
+        message: plain.to_string(),
+        player: None,
+        tags: Vec::new(),
+        spans,
+    };
+    if let Some(captures) = THREAD_FORMAT.as_ref().and_then(|format| format.captures(plain)) {
+        parsed.timestamp = captures.name("time").map(|time| time.as_str().to_string());
+        parsed.thread = captures.name("thread").map(|thread| thread.as_str().to_string());
+        parsed.level = captures.name("level").and_then(|level| LogLevel::parse(level.as_str()));
+        parsed.logger = captures
+            .name("logger")
+            .or_else(|| captures.name("fabric_logger"))
+            .map(|logger| logger.as_str().trim_end_matches('/').to_string())
+            .filter(|logger| !logger.is_empty());
+        parsed.message = captures.name("message").map_or("", |message| message.as_str()).to_string();
+    } else if let Some(captures) = LEVEL_FORMAT.as_ref().and_then(|format| format.captures(plain)) {
+        parsed.timestamp = captures.name("time").map(|time| time.as_str().to_string());
+        parsed.level = captures.name("level").and_then(|level| LogLevel::parse(level.as_str()));
+        let message = captures.name("message").map_or("", |message| message.as_str());
+        // Plugins log through a logger named after them, which Paper prints in brackets
+        match message.strip_prefix('[').and_then(|rest| rest.split_once("] ")) {
+            Some((logger, rest)) if !logger.contains(' ') => {
+                parsed.logger = Some(logger.to_string());
+                parsed.message = rest.to_string();
+            }
+            _ => parsed.message = message.to_string(),
+        }
+    }
+
+    tag_line(&mut parsed);
+    parsed
+}
+
+fn tag_line(line: &mut ConsoleLine) {
+    match line.level {
+        Some(LogLevel::Warn) => line.tags.push(ConsoleTag::Warning),
+        Some(LogLevel::Error | LogLevel::Fatal) => line.tags.push(ConsoleTag::Error),
+        Some(_) => {}
+        None => {
+            let trimmed = line.message.trim_start();
+            let indented = line.message.starts_with(char::is_whitespace);
+            let is_stack_trace = (indented && trimmed.starts_with("at "))
+                || (indented && trimmed.starts_with("... ") && trimmed.ends_with(" more"))
+                || trimmed.starts_with("Caused by: ");
+            if is_stack_trace {
+                line.tags.push(ConsoleTag::Error);
+            }
+        }
+    }
+
+    if line.level == Some(LogLevel::Info) {
+        let message = line.message.as_str();
+        let player_message = [
+            (&*CHAT_MESSAGE, ConsoleTag::Chat),
+            (&*JOIN_MESSAGE, ConsoleTag::Join),
+            (&*LEAVE_MESSAGE, ConsoleTag::Leave),
+        ]
+        .into_iter()
+        .find_map(|(pattern, tag)| {
+            let player = pattern.as_ref()?.captures(message)?.name("player")?;
+            Some((player.as_str().to_string(), tag))
+        });
+        if let Some((player, tag)) = player_message {
+            line.player = Some(player);
+            line.tags.push(tag);
+        }
+    }
+}
+
+/// Removes all ANSI escape sequences from a line.
+pub fn strip_ansi(line: &str) -> String {
+    ansi_spans(line).into_iter().map(|span| span.text).collect()
+}
+
+/// Splits a line into spans of text using the colors its ANSI escape sequences select.
+///
+/// Only the text styles of SGR sequences (`ESC [ ... m`) are kept, every other escape sequence,
+/// such as those moving the cursor, is dropped.
+pub fn ansi_spans(line: &str) -> Vec<ColoredSpan> {
+    let mut spans: Vec<ColoredSpan> = Vec::new();
+    let mut style = ColoredSpan::default();
+    let mut text = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            text.push(c);
+            continue;
+        }
+        if chars.peek() != Some(&'[') {
+            // A two character escape sequence
+            chars.next();
+            continue;
+        }
+        chars.next();
+        let mut parameters = String::new();
+        let mut command = None;
+        for c in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&c) {
+                command = Some(c);
+                break;
+            }
+            parameters.push(c);
+        }
+        if command != Some('m') {
+            continue;
+        }
+
+        if !text.is_empty() {
+            spans.push(ColoredSpan {
+                text: std::mem::take(&mut text),
+                ..style.clone()
+            });
+        }
+        apply_sgr(&mut style, &parameters);
+    }
+    if !text.is_empty() || spans.is_empty() {
+        spans.push(ColoredSpan { text, ..style });
+    }
+    spans
+}
+
+/// Applies the parameters of an SGR sequence to the current style.
+fn apply_sgr(style: &mut ColoredSpan, parameters: &str) {
+    let codes: Vec<u32> = if parameters.is_empty() {
+        vec![0]
+    } else {
+        parameters.split(';').map(|code| code.parse().unwrap_or(0)).collect()
+    };
+    let mut codes = codes.into_iter();
+    while let Some(code) = codes.next() {
+        match code {
+            0 => *style = ColoredSpan::default(),
+            1 => style.bold = true,
+            3 => style.italic = true,
+            4 => style.underline = true,
+            22 => style.bold = false,
+            23 => style.italic = false,
+            24 => style.underline = false,
+            30..=37 => style.color = Some(palette_color(code - 30)),
+            39 => style.color = None,
+            90..=97 => style.color = Some(palette_color(code - 90 + 8)),
+            38 => match codes.next() {
+                Some(5) => style.color = codes.next().map(palette_color),
+                Some(2) => {
+                    let (r, g, b) = (codes.next(), codes.next(), codes.next());
+                    if let (Some(r), Some(g), Some(b)) = (r, g, b) {
+                        style.color = Some(format!("#{:02x}{:02x}{:02x}", r.min(255), g.min(255), b.min(255)));
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// The color of an entry of the 256 color palette. The first 16 entries use the colors of
+/// Minecraft's formatting codes, which is what servers map them from.
+fn palette_color(index: u32) -> String {
+    const BASE_COLORS: [&str; 16] = [
+        "#000000", "#aa0000", "#00aa00", "#ffaa00", "#0000aa", "#aa00aa", "#00aaaa", "#aaaaaa", "#555555", "#ff5555",
+        "#55ff55", "#ffff55", "#5555ff", "#ff55ff", "#55ffff", "#ffffff",
+    ];
+    match index {
+        0..=15 => BASE_COLORS[index as usize].to_string(),
+        16..=231 => {
+            let index = index - 16;
+            let level = |value: u32| if value == 0 { 0 } else { 55 + value * 40 };
+            format!("#{:02x}{:02x}{:02x}", level(index / 36), level(index / 6 % 6), level(index % 6))
+        }
+        _ => {
+            let gray = 8 + (index.min(255) - 232) * 10;
+            format!("#{:02x}{:02x}{:02x}", gray, gray, gray)
+        }
+    }
+}
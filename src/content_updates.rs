@@ -0,0 +1,543 @@
+use crate::file_hash::{hash_reader, HashAlgorithm};
+use crate::http_client::{download_file, get_json, post_json, ExpectedHash};
+use crate::minecraft_file::ModLoader;
+use crate::mod_inventory::{scan_inventory, InventoryEntry, InventoryKind, PluginPlatform};
+use crate::modrinth::{record_installed_content, ContentFolder, InstalledContent, ModrinthVersion};
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_process::ServerProcess;
+use log::{info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const MODRINTH_API: &str = "https://api.modrinth.com/v2";
+const CURSEFORGE_API: &str = "https://api.curseforge.com/v1";
+const SPIGET_API: &str = "https://api.spiget.org/v2";
+
+/// The id of Minecraft on CurseForge.
+const CURSEFORGE_MINECRAFT: u64 = 432;
+
+/// The suffix the replaced jar is kept under until the update is rolled back or replaced again.
+const ROLLBACK_SUFFIX: &str = ".rollback";
+
+/// Where an update was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateSource {
+    Modrinth,
+    CurseForge,
+    /// SpigotMC, looked up through the Spiget API.
+    Spigot,
+}
+
+/// A newer version of an installed mod or plugin.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentUpdate {
+    /// The installed jar, relative to the server directory.
+    pub file: PathBuf,
+    pub name: String,
+    pub current_version: Option<String>,
+    pub source: UpdateSource,
+    pub project_id: String,
+    pub version_id: String,
+    pub version: String,
+    pub file_name: String,
+    pub url: String,
+    pub sha1: Option<String>,
+    pub md5: Option<String>,
+}
+
+/// Which sources an update check asks besides Modrinth.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpdateCheckOptions {
+    /// The key of the CurseForge API, CurseForge is skipped without one.
+    #[serde(skip_serializing)]
+    pub curseforge_api_key: Option<String>,
+    /// Whether to look up plugins found neither on Modrinth nor CurseForge on SpigotMC by their name.
+    pub spigot: bool,
+}
+
+/// The loaders Modrinth lists versions of an inventory entry under.
+fn modrinth_loaders(kind: &InventoryKind) -> Vec<String> {
+    let loaders: &[&str] = match kind {
+        InventoryKind::Mod { loader } => match loader {
+            ModLoader::Forge => &["forge"],
+            ModLoader::NeoForge => &["neoforge"],
+            ModLoader::Fabric => &["fabric"],
+            ModLoader::Quilt => &["quilt", "fabric"],
+            ModLoader::Unknown => &[],
+        },
+        InventoryKind::Plugin { platform } => match platform {
+            Some(PluginPlatform::BungeeCord) => &["bungeecord", "waterfall"],
+            Some(PluginPlatform::Velocity) => &["velocity"],
+            _ => &["paper", "spigot", "bukkit", "purpur", "folia"],
+        },
+    };
+    loaders.iter().map(|loader| loader.to_string()).collect()
+}
+
+/// The mod loader ids of the CurseForge API.
+fn curseforge_loader(kind: &InventoryKind) -> Option<u64> {
+    match kind {
+        InventoryKind::Mod { loader: ModLoader::Forge } => Some(1),
+        InventoryKind::Mod { loader: ModLoader::Fabric } => Some(4),
+        InventoryKind::Mod { loader: ModLoader::Quilt } => Some(5),
+        InventoryKind::Mod { loader: ModLoader::NeoForge } => Some(6),
+        _ => None,
+    }
+}
+
+/// Computes the fingerprint CurseForge identifies files by: MurmurHash2 with seed 1 over the file
+/// without its whitespace bytes.
+fn curseforge_fingerprint(bytes: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let data: Vec<u8> = bytes.iter().copied().filter(|byte| !matches!(byte, 9 | 10 | 13 | 32)).collect();
+    let mut hash: u32 = 1 ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).wrapping_mul(M);
+        k ^= k >> 24;
+        hash = hash.wrapping_mul(M) ^ k.wrapping_mul(M);
+    }
+    let rest = chunks.remainder();
+    if rest.len() >= 3 {
+        hash ^= (rest[2] as u32) << 16;
+    }
+    if rest.len() >= 2 {
+        hash ^= (rest[1] as u32) << 8;
+    }
+    if !rest.is_empty() {
+        hash = (hash ^ rest[0] as u32).wrapping_mul(M);
+    }
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(M);
+    hash ^ (hash >> 15)
+}
+
+/// Looks up updates on Modrinth by the SHA-1 of the installed jars, in one request per set of loaders.
+fn check_modrinth(
+    entries: &[(&InventoryEntry, String)],
+    game_version: &str,
+) -> Result<Vec<(PathBuf, ContentUpdate)>, Box<dyn Error>> {
+    if entries.is_empty() {
+        return Ok(Vec::new());
+    }
+    let hashes: Vec<&str> = entries.iter().map(|(_, sha1)| sha1.as_str()).collect();
+    let current = post_json(
+        &format!("{}/version_files", MODRINTH_API),
+        &[],
+        &json!({ "hashes": hashes, "algorithm": "sha1" }),
+    )?;
+
+    let mut groups: HashMap<Vec<String>, Vec<&(&InventoryEntry, String)>> = HashMap::new();
+    for entry in entries.iter().filter(|(_, sha1)| current.get(sha1).is_some()) {
+        groups.entry(modrinth_loaders(&entry.0.kind)).or_default().push(entry);
+    }
+
+    let mut updates = Vec::new();
+    for (loaders, entries) in groups {
+        let hashes: Vec<&str> = entries.iter().map(|(_, sha1)| sha1.as_str()).collect();
+        let latest = post_json(
+            &format!("{}/version_files/update", MODRINTH_API),
+            &[],
+            &json!({ "hashes": hashes, "algorithm": "sha1", "loaders": loaders, "game_versions": [game_version] }),
+        )?;
+        for (entry, sha1) in entries {
+            let installed = &current[sha1.as_str()];
+            let Ok(latest) = serde_json::from_value::<ModrinthVersion>(latest[sha1.as_str()].clone()) else {
+                continue;
+            };
+            if installed["id"].as_str() == Some(latest.id.as_str()) {
+                continue;
+            }
+            let Some(file) = latest.primary_file() else {
+                continue;
+            };
+            updates.push((
+                entry.file.clone(),
+                ContentUpdate {
+                    file: entry.file.clone(),
+                    name: entry.metadata.name.clone(),
+                    current_version: installed["version_number"].as_str().map(str::to_string),
+                    source: UpdateSource::Modrinth,
+                    project_id: latest.project_id.clone(),
+                    version_id: latest.id.clone(),
+                    version: latest.version_number.clone(),
+                    file_name: file.filename.clone(),
+                    url: file.url.clone(),
+                    sha1: file.hashes.sha1.clone(),
+                    md5: None,
+                },
+            ));
+        }
+    }
+    Ok(updates)
+}
+
+/// Looks up updates on CurseForge by the fingerprints of the installed mods.
+fn check_curseforge(
+    entries: &[(&InventoryEntry, u32)],
+    game_version: &str,
+    api_key: &str,
+) -> Result<Vec<(PathBuf, ContentUpdate)>, Box<dyn Error>> {
+    let headers = [("x-api-key", api_key)];
+    let fingerprints: Vec<u32> = entries.iter().map(|(_, fingerprint)| *fingerprint).collect();
+    let matches = post_json(
+        &format!("{}/fingerprints/{}", CURSEFORGE_API, CURSEFORGE_MINECRAFT),
+        &headers,
+        &json!({ "fingerprints": fingerprints }),
+    )?;
+    let matches: Vec<&Value> = matches["data"]["exactMatches"]
+        .as_array()
+        .map(|matches| matches.iter().collect())
+        .unwrap_or_default();
+    if matches.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mod_ids: Vec<u64> = matches.iter().filter_map(|found| found["id"].as_u64()).collect();
+    let mods = post_json(&format!("{}/mods", CURSEFORGE_API), &headers, &json!({ "modIds": mod_ids }))?;
+    let mods: HashMap<u64, &Value> = mods["data"]
+        .as_array()
+        .map(|mods| mods.iter().filter_map(|project| Some((project["id"].as_u64()?, project))).collect())
+        .unwrap_or_default();
+
+    // Pick the newest release for the game version and loader from the index of every mod
+    let mut candidates = Vec::new();
+    for found in &matches {
+        let installed = &found["file"];
+        let fingerprint = installed["fileFingerprint"].as_u64();
+        let Some((entry, _)) = entries.iter().find(|(_, print)| Some(*print as u64) == fingerprint) else {
+            continue;
+        };
+        let Some(project) = found["id"].as_u64().and_then(|id| mods.get(&id)) else {
+            continue;
+        };
+        let loader = curseforge_loader(&entry.kind);
+        let newest = project["latestFilesIndexes"].as_array().and_then(|indexes| {
+            indexes
+                .iter()
+                .filter(|index| index["gameVersion"].as_str() == Some(game_version))
+                .filter(|index| index["releaseType"].as_u64() == Some(1))
+                .filter(|index| loader.is_none() || index["modLoader"].as_u64() == loader)
+                .filter_map(|index| index["fileId"].as_u64())
+                .max()
+        });
+        match newest {
+            Some(newest) if Some(newest) != installed["id"].as_u64() => candidates.push((*entry, installed, newest)),
+            _ => {}
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let file_ids: Vec<u64> = candidates.iter().map(|(_, _, id)| *id).collect();
+    let files = post_json(&format!("{}/mods/files", CURSEFORGE_API), &headers, &json!({ "fileIds": file_ids }))?;
+    let files: HashMap<u64, &Value> = files["data"]
+        .as_array()
+        .map(|files| files.iter().filter_map(|file| Some((file["id"].as_u64()?, file))).collect())
+        .unwrap_or_default();
+
+    let hash = |file: &Value, algo: u64| -> Option<String> {
+        file["hashes"]
+            .as_array()?
+            .iter()
+            .find(|hash| hash["algo"].as_u64() == Some(algo))
+            .and_then(|hash| hash["value"].as_str().map(str::to_string))
+    };
+    Ok(candidates
+        .into_iter()
+        .filter_map(|(entry, installed, id)| {
+            let file = files.get(&id)?;
+            let file_name = file["fileName"].as_str()?.to_string();
+            // Authors can opt out of third party downloads, such files are still served by the CDN
+            let url = file["downloadUrl"].as_str().map(str::to_string).unwrap_or_else(|| {
+                format!("https://edge.forgecdn.net/files/{}/{}/{}", id / 1000, id % 1000, file_name)
+            });
+            Some((
+                entry.file.clone(),
+                ContentUpdate {
+                    file: entry.file.clone(),
+                    name: entry.metadata.name.clone(),
+                    current_version: installed["displayName"].as_str().map(str::to_string),
+                    source: UpdateSource::CurseForge,
+                    project_id: file["modId"].as_u64()?.to_string(),
+                    version_id: id.to_string(),
+                    version: file["displayName"].as_str().unwrap_or(&file_name).to_string(),
+                    file_name,
+                    url,
+                    sha1: hash(file, 1),
+                    md5: hash(file, 2),
+                },
+            ))
+        })
+        .collect())
+}
+
+fn is_spigot_plugin(kind: &InventoryKind) -> bool {
+    matches!(
+        kind,
+        InventoryKind::Plugin {
+            platform: Some(PluginPlatform::Bukkit | PluginPlatform::Paper)
+        }
+    )
+}
+
+/// Looks up a plugin on SpigotMC by its name, only resources hosted on SpigotMC itself can be updated.
+fn check_spigot(entry: &InventoryEntry) -> Result<Option<ContentUpdate>, Box<dyn Error>> {
+    let name = &entry.metadata.name;
+    // The name is put in the path of the search, so only plain names are looked up
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Ok(None);
+    }
+    let results = get_json(&format!("{}/search/resources/{}?field=name&size=10", SPIGET_API, name))?;
+    let Some(resource) = results.as_array().and_then(|results| {
+        results
+            .iter()
+            .find(|resource| resource["name"].as_str().is_some_and(|found| found.eq_ignore_ascii_case(name)))
+    }) else {
+        return Ok(None);
+    };
+    if resource["external"].as_bool().unwrap_or(false) || resource["premium"].as_bool().unwrap_or(false) {
+        return Ok(None);
+    }
+    let id = resource["id"].as_u64().ok_or("A SpigotMC resource has no id")?;
+    let latest = get_json(&format!("{}/resources/{}/versions/latest", SPIGET_API, id))?;
+    let Some(version) = latest["name"].as_str() else {
+        return Ok(None);
+    };
+    if entry.metadata.version.as_deref() == Some(version) {
+        return Ok(None);
+    }
+    Ok(Some(ContentUpdate {
+        file: entry.file.clone(),
+        name: name.clone(),
+        current_version: entry.metadata.version.clone(),
+        source: UpdateSource::Spigot,
+        project_id: id.to_string(),
+        version_id: latest["id"].as_u64().map(|id| id.to_string()).unwrap_or_default(),
+        version: version.to_string(),
+        file_name: format!("{}-{}.jar", name, version),
+        url: format!("{}/resources/{}/download", SPIGET_API, id),
+        sha1: None,
+        md5: None,
+    }))
+}
+
+/// Checks the enabled mods and plugins of a server directory for newer versions for a version of
+/// the game, asking Modrinth first and the other sources for the jars Modrinth does not know.
+pub fn check_for_content_updates(
+    directory: &Path,
+    game_version: &str,
+    options: &UpdateCheckOptions,
+) -> Result<Vec<ContentUpdate>, Box<dyn Error>> {
+    let inventory: Vec<InventoryEntry> = scan_inventory(directory)?.into_iter().filter(|entry| entry.enabled).collect();
+    let mut hashed = Vec::new();
+    for entry in &inventory {
+        let mut bytes = Vec::new();
+        File::open(directory.join(&entry.file))?.read_to_end(&mut bytes)?;
+        let sha1 = hash_reader(bytes.as_slice(), HashAlgorithm::Sha1)?;
+        hashed.push((entry, sha1, curseforge_fingerprint(&bytes)));
+    }
+
+    let mut updates: HashMap<PathBuf, ContentUpdate> = HashMap::new();
+    let mut modrinth: Vec<(&InventoryEntry, String)> =
+        hashed.iter().map(|(entry, sha1, _)| (*entry, sha1.clone())).collect();
+    modrinth.retain(|(entry, _)| !modrinth_loaders(&entry.kind).is_empty());
+    match check_modrinth(&modrinth, game_version) {
+        Ok(found) => updates.extend(found),
+        Err(e) => warn!("Failed to check Modrinth for updates: {}", e),
+    }
+
+    if let Some(api_key) = options.curseforge_api_key.as_deref() {
+        let curseforge: Vec<(&InventoryEntry, u32)> = hashed
+            .iter()
+            .filter(|(entry, _, _)| !updates.contains_key(&entry.file))
+            .map(|(entry, _, fingerprint)| (*entry, *fingerprint))
+            .collect();
+        if !curseforge.is_empty() {
+            match check_curseforge(&curseforge, game_version, api_key) {
+                Ok(found) => updates.extend(found),
+                Err(e) => warn!("Failed to check CurseForge for updates: {}", e),
+            }
+        }
+    }
+
+    if options.spigot {
+        let plugins = inventory
+            .iter()
+            .filter(|entry| is_spigot_plugin(&entry.kind) && !updates.contains_key(&entry.file));
+        for entry in plugins.collect::<Vec<_>>() {
+            match check_spigot(entry) {
+                Ok(Some(update)) => {
+                    updates.insert(entry.file.clone(), update);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to check SpigotMC for updates of {:?}: {}", entry.metadata.name, e),
+            }
+        }
+    }
+
+    let mut updates: Vec<ContentUpdate> = updates.into_values().collect();
+    updates.sort_by_key(|update| update.name.to_lowercase());
+    Ok(updates)
+}
+
+/// Creates the table remembering the jars replaced by updates, so an update can be rolled back.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_content_rollback_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_content_rollback` (
+            server_id INTEGER NOT NULL,                                 -- The server the jar belongs to
+            file TEXT NOT NULL,                                         -- The updated jar
+            original TEXT NOT NULL,                                     -- The path of the jar before the update
+            rollback TEXT NOT NULL,                                     -- Where the replaced jar is kept
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,    -- When the update was applied
+            PRIMARY KEY (server_id, file)
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+pub trait ServerContentUpdates {
+    /// Checks the mods and plugins of the server for newer versions for its version of the game.
+    fn check_for_content_updates(&self, options: &UpdateCheckOptions) -> Result<Vec<ContentUpdate>, Box<dyn Error>>;
+
+    /// Replaces a jar with its update, keeping the old jar next to it with a `.rollback` suffix.
+    ///
+    /// # Returns
+    /// The new jar, relative to the server directory.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not edit the files of the server, the server is
+    /// running, or the download fails or does not match its checksum, in which case the old jar is
+    /// put back.
+    fn apply_content_update(&self, update: &ContentUpdate) -> Result<PathBuf, Box<dyn Error>>;
+
+    /// Puts back the jar an update replaced.
+    ///
+    /// # Arguments
+    /// * `file` - The updated jar, relative to the server directory.
+    fn rollback_content_update(&self, file: &Path) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerContentUpdates for Server<u64> {
+    fn check_for_content_updates(&self, options: &UpdateCheckOptions) -> Result<Vec<ContentUpdate>, Box<dyn Error>> {
+        check_for_content_updates(&self.directory, &self.minecraft_version, options)
+    }
+
+    fn apply_content_update(&self, update: &ContentUpdate) -> Result<PathBuf, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        if self.is_server_running() {
+            return Err("The server has to be stopped before updating its mods and plugins".into());
+        }
+        // The name comes from an API, it must not reach outside the folder of the old jar
+        if Path::new(&update.file_name).file_name() != Some(OsStr::new(&update.file_name)) {
+            return Err(format!("Invalid file name {:?}", update.file_name).into());
+        }
+        let old = SandboxedPath::new(&self.directory, &update.file)?;
+        if !old.path().is_file() {
+            return Err(format!("{:?} does not exist", update.file).into());
+        }
+        let folder = update.file.parent().unwrap_or(Path::new(""));
+        let new = SandboxedPath::new(&self.directory, folder.join(&update.file_name))?;
+        let mut rollback_name = update.file.as_os_str().to_os_string();
+        rollback_name.push(ROLLBACK_SUFFIX);
+        let rollback = SandboxedPath::new(&self.directory, PathBuf::from(rollback_name))?;
+
+        // The old jar is moved away first, the new one may have the same name
+        fs::rename(old.path(), rollback.path())?;
+        let expected = [(HashAlgorithm::Sha1, &update.sha1), (HashAlgorithm::Md5, &update.md5)]
+            .into_iter()
+            .find_map(|(algorithm, hash)| hash.clone().map(|hash| ExpectedHash { algorithm, hash }));
+        if let Err(e) = download_file(&update.url, &new.path(), expected.as_ref(), &mut |_, _| {}) {
+            fs::rename(rollback.path(), old.path())?;
+            return Err(e);
+        }
+
+        let query = r#"
+INSERT INTO server_content_rollback (server_id, file, original, rollback, updated_at)
+VALUES (?, ?, ?, ?, CURRENT_TIMESTAMP)
+ON CONFLICT(server_id, file) DO UPDATE SET
+    original = excluded.original,
+    rollback = excluded.rollback,
+    updated_at = excluded.updated_at
+"#;
+        let conn = create_appdb_connection()?;
+        let mut statement = conn.prepare(query)?;
+        statement.bind((1, self.id as i64))?;
+        statement.bind((2, new.relative_path().to_string_lossy().as_ref()))?;
+        statement.bind((3, old.relative_path().to_string_lossy().as_ref()))?;
+        statement.bind((4, rollback.relative_path().to_string_lossy().as_ref()))?;
+        statement.next()?;
+
+        if update.source == UpdateSource::Modrinth {
+            if let Some(sha1) = update.sha1.clone() {
+                record_installed_content(
+                    self.id,
+                    &InstalledContent {
+                        project_id: update.project_id.clone(),
+                        version_id: update.version_id.clone(),
+                        version_number: update.version.clone(),
+                        folder: folder.to_string_lossy().parse().unwrap_or(ContentFolder::Mods),
+                        file: new.relative_path().to_path_buf(),
+                        sha1,
+                        installed_at: String::new(),
+                    },
+                )?;
+            }
+        }
+        info!(
+            "Updated {} to {} from {:?} on server {:?}",
+            update.name, update.version, update.source, self.name
+        );
+        Ok(new.relative_path().to_path_buf())
+    }
+
+    fn rollback_content_update(&self, file: &Path) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        if self.is_server_running() {
+            return Err("The server has to be stopped before rolling back an update".into());
+        }
+        let conn = create_appdb_connection()?;
+        let mut statement =
+            conn.prepare(r#"SELECT original, rollback FROM server_content_rollback WHERE server_id = ? AND file = ?"#)?;
+        statement.bind((1, self.id as i64))?;
+        statement.bind((2, file.to_string_lossy().as_ref()))?;
+        let State::Row = statement.next()? else {
+            return Err(format!("No update of {:?} to roll back", file).into());
+        };
+        let original = SandboxedPath::new(&self.directory, statement.read::<String, _>("original")?)?;
+        let rollback = SandboxedPath::new(&self.directory, statement.read::<String, _>("rollback")?)?;
+        if !rollback.path().is_file() {
+            return Err(format!("The jar replaced by the update of {:?} is gone", file).into());
+        }
+
+        let updated = SandboxedPath::new(&self.directory, file)?;
+        if updated.path().exists() {
+            fs::remove_file(updated.path())?;
+        }
+        fs::rename(rollback.path(), original.path())?;
+        let mut statement = conn.prepare(r#"DELETE FROM server_content_rollback WHERE server_id = ? AND file = ?"#)?;
+        statement.bind((1, self.id as i64))?;
+        statement.bind((2, file.to_string_lossy().as_ref()))?;
+        statement.next()?;
+        info!("Rolled back the update of {:?} on server {:?}", file, self.name);
+        Ok(())
+    }
+}
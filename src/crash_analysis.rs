@@ -0,0 +1,603 @@
+use crate::mod_inventory::{scan_inventory, InventoryEntry};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// How much of the end of `logs/latest.log` is read, the start of a long run is of no use for a crash.
+const LOG_TAIL_BYTES: u64 = 512 * 1024;
+
+/// The most frames kept per exception.
+const MAX_FRAMES: usize = 20;
+
+/// The packages of Java, the game, the loaders and their libraries, which are never the culprit.
+const PLATFORM_PACKAGES: &[&str] = &[
+    "java.",
+    "javax.",
+    "jdk.",
+    "sun.",
+    "com.sun.",
+    "net.minecraft.",
+    "com.mojang.",
+    "net.minecraftforge.",
+    "net.neoforged.",
+    "cpw.mods.",
+    "net.fabricmc.",
+    "org.quiltmc.",
+    "org.spongepowered.",
+    "org.bukkit.",
+    "org.spigotmc.",
+    "io.papermc.",
+    "com.destroystokyo.",
+    "io.netty.",
+    "org.apache.",
+    "com.google.",
+    "it.unimi.",
+    "org.objectweb.",
+    "org.slf4j.",
+    "oshi.",
+];
+
+lazy_static! {
+    static ref EXCEPTION_LINE: Option<Regex> = Regex::new(concat!(
+        r"^(?:Caused by: |Exception in thread .*? )?",
+        r"(?P<class>(?:[a-z_$][\w$]*\.)+[A-Z][\w$]*(?:Exception|Error|Throwable)[\w$]*)(?:: (?P<message>.*))?$"
+    ))
+    .ok();
+    static ref FRAME: Option<Regex> = Regex::new(r"^\s+at (?:[^/]+/)?(?P<frame>[\w$.<>/]+)\(").ok();
+    /// The jar Forge and NeoForge print after a frame, `~[create-1.20.1-0.5.1.jar%23123!/:0.5.1]`.
+    static ref FRAME_JAR: Option<Regex> = Regex::new(r"\[(?P<jar>[^\[\]%!:]+\.jar)").ok();
+    /// The methods mixins add to the classes of the game, `handler$zza000$sodium$onRender`.
+    static ref MIXIN_HANDLER: Option<Regex> = Regex::new(r"\$[a-z]{3}\d{3}\$(?P<mod>[a-z0-9_]+)\$").ok();
+    static ref FABRIC_MISSING_DEPENDENCY: Option<Regex> = Regex::new(concat!(
+        r"Mod '(?P<mod>[^']+)' \((?P<mod_id>[^)]+)\) \S+ requires (?P<version>.+?) of ",
+        r"(?:mod )?'?(?P<dependency>[^',(]+?)'?(?: \((?P<dependency_id>[^)]+)\))?, which is missing"
+    ))
+    .ok();
+    static ref FORGE_MISSING_DEPENDENCY: Option<Regex> = Regex::new(concat!(
+        r"Mod ID: '(?P<dependency>[^']+)', Requested by: '(?P<mod>[^']+)', ",
+        r"Expected range: '(?P<version>[^']*)', Actual version: '(?P<actual>[^']*)'"
+    ))
+    .ok();
+    static ref PLUGIN_MISSING_DEPENDENCY: Option<Regex> =
+        Regex::new(r"Unknown/missing dependency plugins: \[(?P<dependencies>[^\]]*)\]").ok();
+    static ref PLUGIN_LOAD_FAILURE: Option<Regex> = Regex::new(r"Could not load '(?P<file>[^']+)'").ok();
+    static ref PLUGIN_EVENT_FAILURE: Option<Regex> =
+        Regex::new(r"Could not pass event \w+ to (?P<plugin>[^\s]+) v").ok();
+    static ref SUSPECTED_MOD: Option<Regex> = Regex::new(r"Suspected Mods?: (?P<mods>.+)$").ok();
+    static ref MIXIN_FAILURE: Option<Regex> = Regex::new(r"Mixin apply for mod (?P<mod>[\w\-]+) failed").ok();
+    static ref CLASS_VERSION: Option<Regex> = Regex::new(concat!(
+        r"class file version (?P<needed>\d+)\.\d+\), ",
+        r"this version of the Java Runtime only recognizes class file versions up to (?P<supported>\d+)"
+    ))
+    .ok();
+    static ref TICK_TIME: Option<Regex> = Regex::new(r"A single server tick took (?P<seconds>[\d.]+) seconds").ok();
+    static ref TICKING_LOCATION: Option<Regex> =
+        Regex::new(r"(?:Entity's Exact location|Block location): (?P<location>.+)$").ok();
+}
+
+/// What most likely made a server crash.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CrashCause {
+    /// The heap, metaspace or the memory of the machine ran out.
+    OutOfMemory { kind: String },
+    /// The port of the server is taken by another process.
+    PortInUse,
+    /// A mod or plugin needs another that is not installed, or not in the version it needs.
+    MissingDependency {
+        /// The mod or plugin that cannot load.
+        required_by: Option<String>,
+        dependency: String,
+        version: Option<String>,
+    },
+    /// A jar was built for a newer Java than the one running the server.
+    JavaTooOld { required: u32, running: u32 },
+    /// A tick took so long that the watchdog stopped the server.
+    TickTimeout { seconds: f64 },
+    /// An entity or block entity threw while it was ticked.
+    TickingEntity { location: Option<String> },
+    /// A mod changes the code of the game in a way that no longer applies.
+    MixinFailure { mod_id: Option<String> },
+    /// An exception without a more specific diagnosis.
+    Exception,
+}
+
+/// A diagnosis of a crash, in words for the owner of the server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnosis {
+    #[serde(flatten)]
+    pub cause: CrashCause,
+    pub summary: String,
+    pub suggestions: Vec<String>,
+}
+
+/// An exception of the chain that made the game crash, the outermost first.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashException {
+    pub class: String,
+    pub message: Option<String>,
+    /// The innermost frames, as `package.Class.method`.
+    pub frames: Vec<String>,
+}
+
+/// A mod or plugin that probably caused a crash.
+#[derive(Debug, Clone, Serialize)]
+pub struct SuspectedMod {
+    /// The id or name the crash mentions.
+    pub name: String,
+    /// The jar of the mod in `mods/` or `plugins/`, when it could be matched.
+    pub file: Option<PathBuf>,
+    /// Why it is suspected, like `stack trace` or `crash report`.
+    pub reason: String,
+}
+
+/// A crash report or log read into its parts.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashAnalysis {
+    /// The file the analysis is of, relative to the server directory.
+    pub file: Option<PathBuf>,
+    /// The time the crash report gives, in its own format.
+    pub time: Option<String>,
+    /// The description of the crash report, like `Exception in server tick loop`.
+    pub description: Option<String>,
+    pub exceptions: Vec<CrashException>,
+    pub suspected_mods: Vec<SuspectedMod>,
+    /// The likely causes, the most certain one first.
+    pub diagnoses: Vec<Diagnosis>,
+}
+
+/// A file in `crash-reports/`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReportFile {
+    /// The name of the file in `crash-reports/`.
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Analyzes the text of a crash report or log.
+///
+/// # Arguments
+/// * `text` - The crash report, the log, or both one after another.
+/// * `inventory` - The mods and plugins of the server, to match the suspects to jars.
+pub fn analyze_crash_text(text: &str, inventory: &[InventoryEntry]) -> CrashAnalysis {
+    let mut analysis = CrashAnalysis {
+        file: None,
+        time: None,
+        description: None,
+        exceptions: Vec::new(),
+        suspected_mods: Vec::new(),
+        diagnoses: Vec::new(),
+    };
+    let lines: Vec<&str> = text.lines().map(|line| line.trim_end()).collect();
+    for line in &lines {
+        if let Some(time) = line.strip_prefix("Time: ") {
+            analysis.time.get_or_insert_with(|| time.to_string());
+        }
+        if let Some(description) = line.strip_prefix("Description: ") {
+            analysis.description.get_or_insert_with(|| description.to_string());
+        }
+    }
+    analysis.exceptions = parse_exceptions(&lines);
+    analysis.suspected_mods = find_suspects(&lines, inventory);
+    analysis.diagnoses = diagnose(text, &lines, &analysis);
+    analysis
+}
+
+/// Reads the first exception chain of the text, with its `Caused by:` exceptions.
+fn parse_exceptions(lines: &[&str]) -> Vec<CrashException> {
+    let mut exceptions: Vec<CrashException> = Vec::new();
+    let mut in_chain = false;
+    for line in lines {
+        let frame = FRAME.as_ref().and_then(|frame| frame.captures(line));
+        if let Some(frame) = frame {
+            if let (true, Some(exception)) = (in_chain, exceptions.last_mut()) {
+                if exception.frames.len() < MAX_FRAMES {
+                    exception.frames.push(frame["frame"].to_string());
+                }
+            }
+            continue;
+        }
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("...") && trimmed.ends_with("more") {
+            continue;
+        }
+        let is_cause = trimmed.starts_with("Caused by: ");
+        if in_chain && !is_cause {
+            // The chain ended, later exceptions are usually consequences of the first
+            if !exceptions.is_empty() && !trimmed.is_empty() {
+                break;
+            }
+            continue;
+        }
+        if !in_chain && is_cause {
+            continue;
+        }
+        if let Some(captures) = EXCEPTION_LINE.as_ref().and_then(|exception| exception.captures(trimmed)) {
+            in_chain = true;
+            exceptions.push(CrashException {
+                class: captures["class"].to_string(),
+                message: captures.name("message").map(|message| message.as_str().to_string()),
+                frames: Vec::new(),
+            });
+        }
+    }
+    exceptions
+}
+
+/// Finds the jar of a mod or plugin by its id, name or file name.
+fn find_jar(inventory: &[InventoryEntry], name: &str) -> Option<PathBuf> {
+    let name = name.to_lowercase();
+    inventory
+        .iter()
+        .find(|entry| {
+            entry.metadata.id.as_deref().is_some_and(|id| id.to_lowercase() == name)
+                || entry.metadata.name.to_lowercase() == name
+                || entry.file.file_name().is_some_and(|file| file.to_string_lossy().to_lowercase() == name)
+        })
+        .map(|entry| entry.file.clone())
+}
+
+fn find_suspects(lines: &[&str], inventory: &[InventoryEntry]) -> Vec<SuspectedMod> {
+    let mut suspects: Vec<SuspectedMod> = Vec::new();
+    let mut suspect = |name: &str, reason: &str| {
+        let name = name.trim();
+        if name.is_empty() || suspects.iter().any(|suspect| suspect.name.eq_ignore_ascii_case(name)) {
+            return;
+        }
+        suspects.push(SuspectedMod {
+            name: name.to_string(),
+            file: find_jar(inventory, name),
+            reason: reason.to_string(),
+        });
+    };
+
+    for line in lines {
+        // Forge lists the mods it blames as `Suspected Mods: Create (create), Version: 0.5.1`
+        if let Some(captures) = SUSPECTED_MOD.as_ref().and_then(|pattern| pattern.captures(line)) {
+            let mods = &captures["mods"];
+            if mods.trim() != "NONE" {
+                for name in mods.split(", Version:").next().unwrap_or(mods).split(',') {
+                    let name = name.split(" (").nth(1).and_then(|id| id.strip_suffix(')')).unwrap_or(name);
+                    suspect(name, "crash report");
+                }
+            }
+        }
+        for (pattern, group, reason) in [
+            (&*MIXIN_FAILURE, "mod", "failed mixin"),
+            (&*PLUGIN_EVENT_FAILURE, "plugin", "event handler"),
+            (&*MIXIN_HANDLER, "mod", "mixin in the stack trace"),
+        ] {
+            if let Some(captures) = pattern.as_ref().and_then(|pattern| pattern.captures(line)) {
+                suspect(&captures[group], reason);
+            }
+        }
+        if let Some(captures) = PLUGIN_LOAD_FAILURE.as_ref().and_then(|pattern| pattern.captures(line)) {
+            let file = captures["file"].rsplit('/').next().unwrap_or_default().to_string();
+            suspect(&file, "failed to load");
+        }
+        if FRAME.as_ref().is_some_and(|frame| frame.is_match(line)) {
+            if let Some(captures) = FRAME_JAR.as_ref().and_then(|pattern| pattern.captures(line)) {
+                let jar = &captures["jar"];
+                if inventory.iter().any(|entry| entry.file.ends_with(jar)) {
+                    suspect(jar, "stack trace");
+                }
+            }
+        }
+    }
+
+    // The packages of the frames often carry the id of the mod, `com.simibubi.create.Foo`
+    for line in lines {
+        let Some(frame) = FRAME.as_ref().and_then(|frame| frame.captures(line)) else {
+            continue;
+        };
+        let frame = &frame["frame"];
+        if PLATFORM_PACKAGES.iter().any(|package| frame.starts_with(package)) {
+            continue;
+        }
+        let packages: Vec<&str> = frame.split('.').collect();
+        let owner = inventory.iter().find(|entry| {
+            entry
+                .metadata
+                .id
+                .as_deref()
+                .is_some_and(|id| id.len() > 2 && packages.iter().rev().skip(2).any(|package| *package == id))
+        });
+        if let Some(owner) = owner {
+            suspect(owner.metadata.id.as_deref().unwrap_or(&owner.metadata.name), "stack trace");
+        }
+    }
+    suspects
+}
+
+fn diagnose(text: &str, lines: &[&str], analysis: &CrashAnalysis) -> Vec<Diagnosis> {
+    let mut diagnoses = Vec::new();
+
+    if let Some(kind) = out_of_memory_kind(text) {
+        let suggestions = match kind {
+            "heap" => vec![
+                "Raise the maximum RAM of the server".to_string(),
+                "Pregenerate the world and lower the view distance, exploring new chunks is what uses the most"
+                    .to_string(),
+            ],
+            "metaspace" => vec!["Raise or remove -XX:MaxMetaspaceSize from the Java arguments".to_string()],
+            "native threads" => vec![
+                "Raise the limit of processes of the user running the server (ulimit -u)".to_string(),
+                "Look for a mod or plugin starting threads without end".to_string(),
+            ],
+            _ => vec![
+                "Lower the maximum RAM of the server, or of the other servers on the machine".to_string(),
+                "Add memory or swap to the machine".to_string(),
+            ],
+        };
+        diagnoses.push(Diagnosis {
+            cause: CrashCause::OutOfMemory { kind: kind.to_string() },
+            summary: format!("The server ran out of memory ({})", kind),
+            suggestions,
+        });
+    }
+
+    if text.contains("FAILED TO BIND TO PORT") || text.contains("Address already in use") {
+        diagnoses.push(Diagnosis {
+            cause: CrashCause::PortInUse,
+            summary: "The port of the server is used by another process".to_string(),
+            suggestions: vec![
+                "Stop the other server using the port, or an earlier run of this one that did not exit".to_string(),
+                "Change server-port in server.properties".to_string(),
+            ],
+        });
+    }
+
+    for line in lines {
+        if let Some(captures) = FABRIC_MISSING_DEPENDENCY.as_ref().and_then(|pattern| pattern.captures(line)) {
+            let dependency = captures.name("dependency_id").map_or(&captures["dependency"], |id| id.as_str());
+            push_missing_dependency(
+                &mut diagnoses,
+                Some(captures["mod_id"].to_string()),
+                dependency.trim().to_string(),
+                Some(captures["version"].to_string()),
+            );
+        }
+        if let Some(captures) = FORGE_MISSING_DEPENDENCY.as_ref().and_then(|pattern| pattern.captures(line)) {
+            push_missing_dependency(
+                &mut diagnoses,
+                Some(captures["mod"].to_string()),
+                captures["dependency"].to_string(),
+                Some(captures["version"].to_string()).filter(|version| !version.is_empty()),
+            );
+        }
+        if let Some(captures) = PLUGIN_MISSING_DEPENDENCY.as_ref().and_then(|pattern| pattern.captures(line)) {
+            let plugin = analysis
+                .suspected_mods
+                .iter()
+                .find(|suspect| suspect.reason == "failed to load")
+                .map(|suspect| suspect.name.clone());
+            for dependency in captures["dependencies"].split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                push_missing_dependency(&mut diagnoses, plugin.clone(), dependency.to_string(), None);
+            }
+        }
+    }
+
+    if let Some(captures) = CLASS_VERSION.as_ref().and_then(|pattern| pattern.captures(text)) {
+        // Class file version 52 is Java 8, and every release after adds one
+        let required = captures["needed"].parse::<u32>().unwrap_or(52).saturating_sub(44);
+        let running = captures["supported"].parse::<u32>().unwrap_or(52).saturating_sub(44);
+        diagnoses.push(Diagnosis {
+            cause: CrashCause::JavaTooOld { required, running },
+            summary: format!("A jar needs Java {} but the server runs on Java {}", required, running),
+            suggestions: vec![format!(
+                "Select a Java {} runtime for the server, or let the manager install one",
+                required
+            )],
+        });
+    }
+
+    if let Some(captures) = TICK_TIME.as_ref().and_then(|pattern| pattern.captures(text)) {
+        let seconds = captures["seconds"].parse().unwrap_or_default();
+        diagnoses.push(Diagnosis {
+            cause: CrashCause::TickTimeout { seconds },
+            summary: format!("A single tick took {} seconds and the watchdog stopped the server", seconds),
+            suggestions: vec![
+                "Check the thread dump in the crash report for what the server thread was doing".to_string(),
+                "Pregenerate the world so players do not wait on chunk generation".to_string(),
+                "Raise max-tick-time in server.properties, or set it to -1 to turn the watchdog off".to_string(),
+            ],
+        });
+    }
+
+    let description = analysis.description.as_deref().unwrap_or_default();
+    if description.starts_with("Ticking entity") || description.starts_with("Ticking block entity") {
+        let location = lines.iter().find_map(|line| {
+            let captures = TICKING_LOCATION.as_ref()?.captures(line.trim())?;
+            Some(captures["location"].to_string())
+        });
+        diagnoses.push(Diagnosis {
+            cause: CrashCause::TickingEntity { location: location.clone() },
+            summary: match &location {
+                Some(location) => format!("An entity or block entity at {} crashes when it is ticked", location),
+                None => "An entity or block entity crashes when it is ticked".to_string(),
+            },
+            suggestions: vec![
+                "Update or remove the mod the entity belongs to".to_string(),
+                "Remove the entity or block from the world with an editor such as MCA Selector, after a backup"
+                    .to_string(),
+            ],
+        });
+    }
+
+    let mixin_failed = MIXIN_FAILURE.as_ref().and_then(|pattern| pattern.captures(text));
+    if mixin_failed.is_some() || text.contains("MixinApplyError") || text.contains("MixinTransformerError") {
+        let mod_id = mixin_failed.map(|captures| captures["mod"].to_string());
+        diagnoses.push(Diagnosis {
+            cause: CrashCause::MixinFailure { mod_id: mod_id.clone() },
+            summary: match &mod_id {
+                Some(mod_id) => format!("The mod {} does not fit this version of the game or of another mod", mod_id),
+                None => "A mod does not fit this version of the game or of another mod".to_string(),
+            },
+            suggestions: vec![
+                "Install the version of the mod made for this Minecraft version and loader".to_string(),
+                "Look for two mods changing the same part of the game".to_string(),
+            ],
+        });
+    }
+
+    if diagnoses.is_empty() {
+        if let Some(exception) = analysis.exceptions.last() {
+            let mut suggestions: Vec<String> = analysis
+                .suspected_mods
+                .iter()
+                .take(3)
+                .map(|suspect| format!("Update or remove {}, which the {} points to", suspect.name, suspect.reason))
+                .collect();
+            suggestions.push("Search the issue tracker of the suspected mod for the exception".to_string());
+            diagnoses.push(Diagnosis {
+                cause: CrashCause::Exception,
+                summary: match &exception.message {
+                    Some(message) => format!("The server crashed with {}: {}", exception.class, message),
+                    None => format!("The server crashed with {}", exception.class),
+                },
+                suggestions,
+            });
+        }
+    }
+    diagnoses
+}
+
+fn push_missing_dependency(
+    diagnoses: &mut Vec<Diagnosis>,
+    required_by: Option<String>,
+    dependency: String,
+    version: Option<String>,
+) {
+    let cause = CrashCause::MissingDependency {
+        required_by: required_by.clone(),
+        dependency: dependency.clone(),
+        version: version.clone(),
+    };
+    if diagnoses.iter().any(|diagnosis| diagnosis.cause == cause) {
+        return;
+    }
+    let wanted = match &version {
+        Some(version) => format!("{} ({})", dependency, version),
+        None => dependency.clone(),
+    };
+    diagnoses.push(Diagnosis {
+        cause,
+        summary: match &required_by {
+            Some(required_by) => format!("{} needs {}, which is not installed", required_by, wanted),
+            None => format!("{} is needed but not installed", wanted),
+        },
+        suggestions: vec![format!("Install {}", wanted)],
+    });
+}
+
+/// What ran out in an out of memory crash, `None` if memory did not run out.
+fn out_of_memory_kind(text: &str) -> Option<&'static str> {
+    if text.contains("OutOfMemoryError: Java heap space") || text.contains("OutOfMemoryError: GC overhead") {
+        Some("heap")
+    } else if text.contains("OutOfMemoryError: Metaspace") {
+        Some("metaspace")
+    } else if text.contains("unable to create native thread") {
+        Some("native threads")
+    } else if text.contains("There is insufficient memory for the Java Runtime Environment")
+        || text.contains("Native memory allocation")
+    {
+        Some("system memory")
+    } else if text.contains("java.lang.OutOfMemoryError") {
+        Some("heap")
+    } else {
+        None
+    }
+}
+
+/// Lists the crash reports of a server, newest first.
+pub fn list_crash_reports(directory: &Path) -> Result<Vec<CrashReportFile>, Box<dyn Error>> {
+    let folder = directory.join("crash-reports");
+    if !folder.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut reports: Vec<CrashReportFile> = fs::read_dir(folder)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| {
+            let metadata = entry.metadata().ok();
+            CrashReportFile {
+                name: entry.file_name().to_string_lossy().to_string(),
+                size: metadata.as_ref().map(|metadata| metadata.len()).unwrap_or_default(),
+                modified: metadata.and_then(|metadata| metadata.modified().ok()),
+            }
+        })
+        .collect();
+    reports.sort_by(|a, b| b.modified.cmp(&a.modified).then_with(|| b.name.cmp(&a.name)));
+    Ok(reports)
+}
+
+/// Reads the end of a file, lossily, for logs that may have grown large.
+fn read_tail(path: &Path, bytes: u64) -> Result<String, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let length = file.metadata()?.len();
+    file.seek(SeekFrom::Start(length.saturating_sub(bytes)))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).to_string())
+}
+
+/// Analyzes a crash of a server from a crash report and the end of `logs/latest.log`.
+///
+/// # Arguments
+/// * `directory` - The server directory.
+/// * `report` - The name of the file in `crash-reports/`, the newest one if `None`. Without any
+///   crash report only the log is analyzed, which is where a server that failed to start leaves
+///   its missing dependencies and bind errors.
+pub fn analyze_crash(directory: &Path, report: Option<&str>) -> Result<CrashAnalysis, Box<dyn Error>> {
+    let report = match report {
+        Some(name) => Some(name.to_string()),
+        None => list_crash_reports(directory)?.into_iter().next().map(|report| report.name),
+    };
+    let mut text = String::new();
+    let mut file = None;
+    if let Some(name) = report {
+        let path = SandboxedPath::new(directory.join("crash-reports"), &name)?;
+        text.push_str(&String::from_utf8_lossy(&fs::read(path.path())?));
+        file = Some(PathBuf::from("crash-reports").join(name));
+    }
+    let log = directory.join("logs").join("latest.log");
+    if log.is_file() {
+        text.push('\n');
+        text.push_str(&read_tail(&log, LOG_TAIL_BYTES)?);
+        file.get_or_insert_with(|| PathBuf::from("logs").join("latest.log"));
+    }
+    if text.trim().is_empty() {
+        return Err("The server has no crash reports or logs to analyze".into());
+    }
+    let inventory = scan_inventory(directory).unwrap_or_default();
+    let mut analysis = analyze_crash_text(&text, &inventory);
+    analysis.file = file;
+    Ok(analysis)
+}
+
+/// Crash report analysis for servers.
+pub trait ServerCrashAnalysis {
+    /// Lists the crash reports of the server, newest first.
+    fn list_crash_reports(&self) -> Result<Vec<CrashReportFile>, Box<dyn Error>>;
+
+    /// Analyzes a crash report of the server, the newest one if `report` is `None`.
+    fn analyze_crash(&self, report: Option<&str>) -> Result<CrashAnalysis, Box<dyn Error>>;
+}
+
+impl ServerCrashAnalysis for Server<u64> {
+    fn list_crash_reports(&self) -> Result<Vec<CrashReportFile>, Box<dyn Error>> {
+        list_crash_reports(&self.directory)
+    }
+
+    fn analyze_crash(&self, report: Option<&str>) -> Result<CrashAnalysis, Box<dyn Error>> {
+        analyze_crash(&self.directory, report)
+    }
+}
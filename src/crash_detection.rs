@@ -0,0 +1,339 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// A listener invoked whenever a server crashes.
+type CrashListener = Box<dyn Fn(&CrashReport) + Send>;
+
+/// The number of console lines kept for a crash report.
+const LOG_TAIL_LINES: usize = 100;
+
+/// The number of crash reports kept in memory per server.
+const MAX_RECENT_CRASHES: usize = 10;
+
+/// Console output announcing a crash, even if the process exits with a success code afterwards.
+const CRASH_PATTERNS: &[&str] = &[
+    "---- Minecraft Crash Report ----",
+    "Encountered an unexpected exception",
+    "Exception in server tick loop",
+    "A single server tick took",
+    "A fatal error has been detected by the Java Runtime Environment",
+    "java.lang.OutOfMemoryError",
+    "Failed to start the minecraft server",
+];
+
+lazy_static! {
+    static ref CRASH_LISTENERS: Mutex<Vec<CrashListener>> = Mutex::new(Vec::new());
+    static ref RECENT_CRASHES: Mutex<HashMap<u64, VecDeque<CrashReport>>> = Mutex::new(HashMap::new());
+    /// The times servers were restarted automatically, used to detect crash loops.
+    static ref RESTART_HISTORY: Mutex<HashMap<u64, Vec<SystemTime>>> = Mutex::new(HashMap::new());
+}
+
+/// Whether and how a crashed server is restarted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestartPolicy {
+    pub enabled: bool,
+    /// The number of automatic restarts within `window_seconds` after which the server is left
+    /// crashed, so a server failing on every start does not restart forever.
+    pub max_restarts: u32,
+    pub window_seconds: u64,
+    /// The delay before the first restart, doubled for every further restart within the window.
+    pub initial_backoff_seconds: u64,
+    pub max_backoff_seconds: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_restarts: 3,
+            window_seconds: 600,
+            initial_backoff_seconds: 5,
+            max_backoff_seconds: 300,
+        }
+    }
+}
+
+/// What is known about a crash of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    pub server_id: u64,
+    pub crashed_at: SystemTime,
+    /// The exit code of the process, `None` if it was terminated by a signal.
+    pub exit_code: Option<i32>,
+    /// The console output that identified the crash.
+    pub detected_patterns: Vec<String>,
+    /// The last lines the server printed.
+    pub log_tail: Vec<String>,
+    /// Crash reports the game wrote to `crash-reports/` during the run, relative to the server directory.
+    pub crash_report_files: Vec<PathBuf>,
+    /// The delay before the server is restarted, `None` if it is not restarted.
+    pub restart_delay_seconds: Option<u64>,
+    /// The number of this restart within the policy's window.
+    pub restart_attempt: u32,
+}
+
+/// The last lines a running server printed, along with the crash patterns seen in them.
+#[derive(Debug, Default)]
+pub(crate) struct ConsoleTail {
+    lines: VecDeque<String>,
+    detected_patterns: Vec<String>,
+}
+
+impl ConsoleTail {
+    pub(crate) fn push(&mut self, line: &str) {
+        if let Some(pattern) = CRASH_PATTERNS.iter().find(|pattern| line.contains(*pattern)) {
+            if !self.detected_patterns.iter().any(|detected| detected == pattern) {
+                self.detected_patterns.push(pattern.to_string());
+            }
+        }
+        if self.lines.len() == LOG_TAIL_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line.to_string());
+    }
+
+    /// Whether the server crashed, judging by its exit and by its output.
+    pub(crate) fn indicates_crash(&self, exit_success: bool) -> bool {
+        !exit_success || !self.detected_patterns.is_empty()
+    }
+}
+
+/// Creates the table holding the restart policies of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_restart_policy_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_restart_policy` (
+            server_id INTEGER PRIMARY KEY,                              -- The server the policy applies to
+            enabled INTEGER NOT NULL DEFAULT 0,                         -- Whether crashed servers are restarted
+            max_restarts INTEGER NOT NULL,                              -- Restarts within the window before giving up
+            window_seconds INTEGER NOT NULL,                            -- The window restarts are counted in
+            initial_backoff_seconds INTEGER NOT NULL,                   -- The delay before the first restart
+            max_backoff_seconds INTEGER NOT NULL                        -- The longest delay between restarts
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Registers a listener that is invoked whenever a server crashes, before it is restarted.
+pub fn add_crash_listener(listener: impl Fn(&CrashReport) + Send + 'static) {
+    if let Ok(mut listeners) = CRASH_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+/// Reads the restart policy of a server, the default policy if none was set.
+pub fn get_restart_policy(server_id: u64) -> Result<RestartPolicy, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_restart_policy WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(RestartPolicy {
+            enabled: statement.read::<i64, _>("enabled")? != 0,
+            max_restarts: statement.read::<i64, _>("max_restarts")? as u32,
+            window_seconds: statement.read::<i64, _>("window_seconds")? as u64,
+            initial_backoff_seconds: statement.read::<i64, _>("initial_backoff_seconds")? as u64,
+            max_backoff_seconds: statement.read::<i64, _>("max_backoff_seconds")? as u64,
+        });
+    }
+    Ok(RestartPolicy::default())
+}
+
+/// Sets the restart policy of a server.
+pub fn set_restart_policy(server_id: u64, policy: &RestartPolicy) -> Result<(), Box<dyn Error>> {
+    let query = r#"
+INSERT INTO server_restart_policy
+    (server_id, enabled, max_restarts, window_seconds, initial_backoff_seconds, max_backoff_seconds)
+VALUES (?, ?, ?, ?, ?, ?)
+ON CONFLICT(server_id) DO UPDATE SET
+    enabled = excluded.enabled,
+    max_restarts = excluded.max_restarts,
+    window_seconds = excluded.window_seconds,
+    initial_backoff_seconds = excluded.initial_backoff_seconds,
+    max_backoff_seconds = excluded.max_backoff_seconds
+"#;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, policy.enabled as i64))?;
+    statement.bind((3, policy.max_restarts as i64))?;
+    statement.bind((4, policy.window_seconds as i64))?;
+    statement.bind((5, policy.initial_backoff_seconds as i64))?;
+    statement.bind((6, policy.max_backoff_seconds as i64))?;
+    statement.next()?;
+    info!("Updated the restart policy of server {}: {:?}", server_id, policy);
+    Ok(())
+}
+
+/// Returns the crashes of a server since the manager started, newest first.
+pub fn get_recent_crashes(server_id: u64) -> Vec<CrashReport> {
+    RECENT_CRASHES
+        .lock()
+        .ok()
+        .and_then(|crashes| crashes.get(&server_id).map(|crashes| crashes.iter().rev().cloned().collect()))
+        .unwrap_or_default()
+}
+
+/// Builds the report of a crash, decides whether the server is restarted and notifies the listeners.
+///
+/// # Arguments
+/// * `server_id` - The server that crashed.
+/// * `directory` - The server directory, searched for new crash reports.
+/// * `started_at` - When the crashed process was started.
+/// * `exit_code` - The exit code of the process.
+/// * `tail` - The output of the process.
+pub(crate) fn record_crash(
+    server_id: u64,
+    directory: &Path,
+    started_at: SystemTime,
+    exit_code: Option<i32>,
+    tail: &ConsoleTail,
+) -> CrashReport {
+    let policy = get_restart_policy(server_id).unwrap_or_else(|err| {
+        warn!("Failed to read the restart policy of server {}: {}", server_id, err);
+        RestartPolicy::default()
+    });
+    let crashed_at = SystemTime::now();
+    let (restart_delay, restart_attempt) = schedule_restart(server_id, &policy, crashed_at);
+
+    let report = CrashReport {
+        server_id,
+        crashed_at,
+        exit_code,
+        detected_patterns: tail.detected_patterns.clone(),
+        log_tail: tail.lines.iter().cloned().collect(),
+        crash_report_files: find_crash_reports(directory, started_at),
+        restart_delay_seconds: restart_delay.map(|delay| delay.as_secs()),
+        restart_attempt,
+    };
+    error!(
+        "Server {} crashed with exit code {:?}, {} crash report(s) written",
+        server_id,
+        exit_code,
+        report.crash_report_files.len()
+    );
+
+    if let Ok(mut crashes) = RECENT_CRASHES.lock() {
+        let crashes = crashes.entry(server_id).or_default();
+        if crashes.len() == MAX_RECENT_CRASHES {
+            crashes.pop_front();
+        }
+        crashes.push_back(report.clone());
+    }
+    match CRASH_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(&report)),
+        Err(err) => error!("Failed to notify crash listeners: {}", err),
+    }
+    report
+}
+
+/// Decides the delay before the next automatic restart, counting the restarts within the window.
+///
+/// # Returns
+/// The delay, or `None` if the server is not restarted, along with the number of the restart.
+fn schedule_restart(server_id: u64, policy: &RestartPolicy, now: SystemTime) -> (Option<Duration>, u32) {
+    if !policy.enabled {
+        return (None, 0);
+    }
+    let Ok(mut history) = RESTART_HISTORY.lock() else {
+        return (None, 0);
+    };
+    let restarts = history.entry(server_id).or_default();
+    let window = Duration::from_secs(policy.window_seconds);
+    restarts.retain(|restart| now.duration_since(*restart).is_ok_and(|elapsed| elapsed < window));
+
+    let attempt = restarts.len() as u32 + 1;
+    if attempt > policy.max_restarts {
+        warn!(
+            "Server {} crashed {} times within {} seconds, it is not restarted again",
+            server_id,
+            restarts.len(),
+            policy.window_seconds
+        );
+        return (None, attempt);
+    }
+    restarts.push(now);
+    let backoff = policy
+        .initial_backoff_seconds
+        .saturating_mul(1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX))
+        .min(policy.max_backoff_seconds);
+    info!("Restarting server {} in {} seconds (attempt {})", server_id, backoff, attempt);
+    (Some(Duration::from_secs(backoff)), attempt)
+}
+
+/// Finds the crash reports written since a server started.
+fn find_crash_reports(directory: &Path, since: SystemTime) -> Vec<PathBuf> {
+    let mut reports: Vec<PathBuf> = fs::read_dir(directory.join("crash-reports"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    entry
+                        .metadata()
+                        .and_then(|metadata| metadata.modified())
+                        .is_ok_and(|modified| modified >= since)
+                })
+                .filter_map(|entry| entry.path().strip_prefix(directory).ok().map(Path::to_path_buf))
+                .collect()
+        })
+        .unwrap_or_default();
+    // The JVM writes its fatal error logs into the working directory instead
+    if let Ok(entries) = fs::read_dir(directory) {
+        reports.extend(
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("hs_err_pid"))
+                .filter(|entry| {
+                    entry
+                        .metadata()
+                        .and_then(|metadata| metadata.modified())
+                        .is_ok_and(|modified| modified >= since)
+                })
+                .map(|entry| PathBuf::from(entry.file_name())),
+        );
+    }
+    reports.sort();
+    reports
+}
+
+/// Crash tracking and automatic restarts for servers.
+pub trait ServerCrashRecovery {
+    /// Returns how the server is restarted after a crash.
+    fn get_restart_policy(&self) -> Result<RestartPolicy, Box<dyn Error>>;
+
+    /// Sets how the server is restarted after a crash.
+    fn set_restart_policy(&self, policy: &RestartPolicy) -> Result<(), Box<dyn Error>>;
+
+    /// Returns the crashes of the server since the manager started, newest first.
+    fn get_recent_crashes(&self) -> Vec<CrashReport>;
+}
+
+impl ServerCrashRecovery for Server<u64> {
+    fn get_restart_policy(&self) -> Result<RestartPolicy, Box<dyn Error>> {
+        get_restart_policy(self.id)
+    }
+
+    fn set_restart_policy(&self, policy: &RestartPolicy) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        set_restart_policy(self.id, policy)
+    }
+
+    fn get_recent_crashes(&self) -> Vec<CrashReport> {
+        get_recent_crashes(self.id)
+    }
+}
@@ -0,0 +1,400 @@
+use crate::archive_extractor::safe_entry_path;
+use crate::eula::write_accepted_eula;
+use crate::file_hash::HashAlgorithm;
+use crate::http_client::{download_file, post_json, ExpectedHash};
+use crate::jobs::check_cancelled;
+use crate::loader_installer::ServerLoaderInstaller;
+use crate::minecraft_file::ModLoader;
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_registry::{create_server, delete_server, NewServer};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const CURSEFORGE_API: &str = "https://api.curseforge.com/v1";
+
+/// How often a mod download is attempted before the import fails.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// How long to wait before retrying a failed download, multiplied by the attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// CurseForge hash algorithm ids.
+const CURSEFORGE_SHA1: u64 = 1;
+const CURSEFORGE_MD5: u64 = 2;
+
+/// A listener invoked for every step of a modpack import.
+type ModpackImportListener = Box<dyn Fn(&ModpackImportEvent) + Send>;
+
+lazy_static! {
+    static ref MODPACK_IMPORT_LISTENERS: Mutex<Vec<ModpackImportListener>> = Mutex::new(Vec::new());
+}
+
+/// The `manifest.json` of a CurseForge modpack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModpackManifest {
+    pub minecraft: ManifestMinecraft,
+    /// `minecraftModpack` for modpacks.
+    pub manifest_type: String,
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub author: String,
+    pub files: Vec<ManifestFile>,
+    /// The folder of the zip whose contents are copied over the server.
+    #[serde(default = "default_overrides")]
+    pub overrides: String,
+}
+
+fn default_overrides() -> String {
+    "overrides".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestMinecraft {
+    pub version: String,
+    #[serde(default)]
+    pub mod_loaders: Vec<ManifestModLoader>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestModLoader {
+    /// The loader and its version, such as `forge-47.2.0` or `fabric-0.15.11`.
+    pub id: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestFile {
+    #[serde(rename = "projectID")]
+    pub project_id: u64,
+    #[serde(rename = "fileID")]
+    pub file_id: u64,
+    #[serde(default = "default_required")]
+    pub required: bool,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+impl ModpackManifest {
+    /// The mod loader of the modpack and its version, from the primary loader of the manifest.
+    pub fn loader(&self) -> Result<(ModLoader, String), Box<dyn Error>> {
+        let loaders = &self.minecraft.mod_loaders;
+        let loader = loaders
+            .iter()
+            .find(|loader| loader.primary)
+            .or_else(|| loaders.first())
+            .ok_or("The modpack does not name a mod loader")?;
+        let (name, version) = loader
+            .id
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid mod loader {:?}", loader.id))?;
+        let loader = match name {
+            "forge" => ModLoader::Forge,
+            "neoforge" => ModLoader::NeoForge,
+            "fabric" => ModLoader::Fabric,
+            "quilt" => ModLoader::Quilt,
+            other => return Err(format!("Unsupported mod loader {:?}", other).into()),
+        };
+        // NeoForge for 1.20.1 is named with the version of the game, like Forge
+        let version = version.strip_prefix(&format!("{}-", self.minecraft.version)).unwrap_or(version);
+        Ok((loader, version.to_string()))
+    }
+}
+
+/// What to import and the server to create for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModpackImportOptions {
+    /// The modpack zip exported from CurseForge.
+    pub archive: PathBuf,
+    /// The server to create, its game and loader versions are taken from the modpack.
+    pub server: NewServer,
+    /// The key of the CurseForge API, which does not allow anonymous access.
+    #[serde(skip_serializing)]
+    pub api_key: String,
+    /// Whether the user agreed to the Minecraft EULA, which the server needs to start.
+    #[serde(default)]
+    pub accept_eula: bool,
+    /// Whether to also download the files the modpack marks as optional.
+    #[serde(default)]
+    pub include_optional: bool,
+}
+
+/// The steps of a modpack import, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModpackImportStep {
+    ReadingManifest,
+    /// Registering the server and installing its mod loader.
+    InstallingLoader,
+    DownloadingMods,
+    ApplyingOverrides,
+    Completed,
+    Failed,
+}
+
+/// The progress of a modpack import, sent to the modpack import listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModpackImportEvent {
+    /// The new server, `None` before it was registered.
+    pub server_id: Option<u64>,
+    pub step: ModpackImportStep,
+    /// The number of files of the step that are done.
+    pub current: usize,
+    pub total: usize,
+    /// The file the event is about, while downloading mods.
+    pub file: Option<String>,
+    pub message: String,
+}
+
+/// Registers a listener that is invoked for every step and file of every modpack import.
+pub fn add_modpack_import_listener(listener: impl Fn(&ModpackImportEvent) + Send + 'static) {
+    if let Ok(mut listeners) = MODPACK_IMPORT_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn emit(event: ModpackImportEvent) {
+    match MODPACK_IMPORT_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(&event)),
+        Err(err) => error!("Failed to notify modpack import listeners: {}", err),
+    }
+}
+
+fn emit_step(server_id: Option<u64>, step: ModpackImportStep, message: impl Into<String>) {
+    emit(ModpackImportEvent {
+        server_id,
+        step,
+        current: 0,
+        total: 0,
+        file: None,
+        message: message.into(),
+    });
+}
+
+/// Reads the `manifest.json` of a CurseForge modpack zip.
+pub fn read_modpack_manifest(archive: &Path) -> Result<ModpackManifest, Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+    let manifest = zip
+        .by_name("manifest.json")
+        .map_err(|_| "The archive is not a CurseForge modpack, it has no manifest.json")?;
+    let manifest: ModpackManifest = serde_json::from_reader(manifest)?;
+    if manifest.manifest_type != "minecraftModpack" {
+        return Err(format!("Unsupported manifest type {:?}", manifest.manifest_type).into());
+    }
+    Ok(manifest)
+}
+
+/// Creates a ready to start server from a CurseForge modpack zip: installs the mod loader of the
+/// modpack, downloads all of its mods through the CurseForge API and copies its overrides.
+///
+/// Blocks until the import finished, reporting the progress of every file to the modpack import
+/// listeners. Downloads are retried a few times before the import fails, and a server whose import
+/// fails is removed again.
+pub fn import_curseforge_modpack(options: &ModpackImportOptions) -> Result<Server<u64>, Box<dyn Error>> {
+    emit_step(None, ModpackImportStep::ReadingManifest, "Reading manifest.json");
+    let prepared = read_modpack_manifest(&options.archive).and_then(|manifest| {
+        let loader = manifest.loader()?;
+        Ok((manifest, loader))
+    });
+    let (manifest, (loader, loader_version)) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            emit_step(None, ModpackImportStep::Failed, e.to_string());
+            return Err(e);
+        }
+    };
+
+    let new_server = NewServer {
+        minecraft_version: manifest.minecraft.version.clone(),
+        loader_version: Some(loader_version.clone()),
+        ..options.server.clone()
+    };
+    let mut server = match create_server(&new_server) {
+        Ok(server) => server,
+        Err(e) => {
+            emit_step(None, ModpackImportStep::Failed, e.to_string());
+            return Err(e);
+        }
+    };
+
+    match set_up_modpack(&mut server, &manifest, loader, &loader_version, options) {
+        Ok(()) => {
+            emit_step(Some(server.id), ModpackImportStep::Completed, "The server is ready");
+            info!("Imported modpack {:?} {} as server {:?}", manifest.name, manifest.version, server.name);
+            Ok(server)
+        }
+        Err(e) => {
+            emit_step(Some(server.id), ModpackImportStep::Failed, e.to_string());
+            if let Err(cleanup) = delete_server(server.id, true) {
+                warn!("Failed to remove the server {:?} that failed to import: {}", server.name, cleanup);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn set_up_modpack(
+    server: &mut Server<u64>,
+    manifest: &ModpackManifest,
+    loader: ModLoader,
+    loader_version: &str,
+    options: &ModpackImportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let id = Some(server.id);
+    emit_step(
+        id,
+        ModpackImportStep::InstallingLoader,
+        format!("Installing {:?} {} for Minecraft {}", loader, loader_version, manifest.minecraft.version),
+    );
+    server.install_mod_loader(loader, &manifest.minecraft.version, Some(loader_version))?;
+
+    let files: Vec<&ManifestFile> =
+        manifest.files.iter().filter(|file| file.required || options.include_optional).collect();
+    let details = get_files(&options.api_key, &files)?;
+    let mods = SandboxedPath::new(&server.directory, "mods")?;
+    fs::create_dir_all(mods.path())?;
+
+    for (index, file) in files.iter().enumerate() {
+        check_cancelled()?;
+        let detail = details
+            .get(&file.file_id)
+            .ok_or_else(|| format!("CurseForge has no file {} of project {}", file.file_id, file.project_id))?;
+        let name = detail["fileName"].as_str().ok_or("A modpack file has no name")?;
+        // The name comes from the API, it must not reach outside mods/
+        if Path::new(name).file_name() != Some(OsStr::new(name)) {
+            return Err(format!("Invalid file name {:?}", name).into());
+        }
+        emit(ModpackImportEvent {
+            server_id: id,
+            step: ModpackImportStep::DownloadingMods,
+            current: index,
+            total: files.len(),
+            file: Some(name.to_string()),
+            message: format!("Downloading {}", name),
+        });
+        download_with_retry(id, detail, name, &mods.join(name)?.path(), index, files.len())?;
+    }
+
+    emit_step(id, ModpackImportStep::ApplyingOverrides, format!("Copying {}/", manifest.overrides));
+    apply_overrides(&options.archive, &manifest.overrides, &server.directory)?;
+    if options.accept_eula {
+        write_accepted_eula(&server.directory)?;
+    }
+    Ok(())
+}
+
+/// Looks up the files of the modpack in one request, keyed by their file id.
+fn get_files(api_key: &str, files: &[&ManifestFile]) -> Result<HashMap<u64, Value>, Box<dyn Error>> {
+    if files.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let ids: Vec<u64> = files.iter().map(|file| file.file_id).collect();
+    let response = post_json(
+        &format!("{}/mods/files", CURSEFORGE_API),
+        &[("x-api-key", api_key)],
+        &json!({ "fileIds": ids }),
+    )?;
+    Ok(response["data"]
+        .as_array()
+        .map(|files| {
+            files
+                .iter()
+                .filter_map(|file| Some((file["id"].as_u64()?, file.clone())))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+fn download_with_retry(
+    server_id: Option<u64>,
+    detail: &Value,
+    name: &str,
+    destination: &Path,
+    index: usize,
+    total: usize,
+) -> Result<(), Box<dyn Error>> {
+    let file_id = detail["id"].as_u64().unwrap_or_default();
+    // Authors can opt out of third party downloads, such files are still served by the CDN
+    let url = detail["downloadUrl"]
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("https://edge.forgecdn.net/files/{}/{}/{}", file_id / 1000, file_id % 1000, name));
+    let expected = expected_hash(detail);
+
+    let mut attempt = 1;
+    loop {
+        match download_file(&url, destination, expected.as_ref(), &mut |_, _| {}) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS => {
+                warn!("Attempt {} to download {} failed: {}", attempt, name, e);
+                emit(ModpackImportEvent {
+                    server_id,
+                    step: ModpackImportStep::DownloadingMods,
+                    current: index,
+                    total,
+                    file: Some(name.to_string()),
+                    message: format!("Retrying {} after: {}", name, e),
+                });
+                thread::sleep(RETRY_DELAY * attempt);
+                attempt += 1;
+            }
+            Err(e) => return Err(format!("Failed to download {} after {} attempts: {}", name, attempt, e).into()),
+        }
+    }
+}
+
+fn expected_hash(detail: &Value) -> Option<ExpectedHash> {
+    let hashes = detail["hashes"].as_array()?;
+    [(CURSEFORGE_SHA1, HashAlgorithm::Sha1), (CURSEFORGE_MD5, HashAlgorithm::Md5)]
+        .into_iter()
+        .find_map(|(algo, algorithm)| {
+            let hash = hashes.iter().find(|hash| hash["algo"].as_u64() == Some(algo))?;
+            Some(ExpectedHash {
+                algorithm,
+                hash: hash["value"].as_str()?.to_string(),
+            })
+        })
+}
+
+/// Copies the contents of the overrides folder of the modpack over the server directory.
+fn apply_overrides(archive: &Path, overrides: &str, directory: &Path) -> Result<(), Box<dyn Error>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+    let prefix = format!("{}/", overrides.trim_end_matches('/'));
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index)?;
+        let Some(relative) = entry.name().strip_prefix(&prefix) else {
+            continue;
+        };
+        if relative.is_empty() {
+            continue;
+        }
+        let target = SandboxedPath::new(directory, safe_entry_path(Path::new(relative))?)?;
+        if entry.is_dir() {
+            fs::create_dir_all(target.path())?;
+            continue;
+        }
+        if let Some(parent) = target.path().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut entry, &mut File::create(target.path())?)?;
+    }
+    Ok(())
+}
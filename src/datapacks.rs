@@ -0,0 +1,310 @@
+use crate::archive_extractor::safe_entry_path;
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_process::{send_server_command, ServerProcess};
+use crate::server_properties::ServerProperties;
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The folder disabled datapacks are moved to, next to `datapacks` in the world directory.
+const DISABLED_DATAPACKS: &str = "datapacks-disabled";
+
+/// The first version of the game of every data pack format, oldest first.
+const PACK_FORMATS: &[(&str, u32)] = &[
+    ("1.13", 4),
+    ("1.15", 5),
+    ("1.16.2", 6),
+    ("1.17", 7),
+    ("1.18", 8),
+    ("1.18.2", 9),
+    ("1.19", 10),
+    ("1.19.4", 12),
+    ("1.20", 15),
+    ("1.20.2", 18),
+    ("1.20.3", 26),
+    ("1.20.5", 41),
+    ("1.21", 48),
+    ("1.21.2", 57),
+    ("1.21.4", 61),
+    ("1.21.5", 71),
+    ("1.21.6", 80),
+    ("1.21.7", 81),
+    ("1.21.9", 88),
+];
+
+/// How the format of a datapack relates to the one the server expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackCompatibility {
+    Compatible,
+    /// Made for an older version of the game, it may still work.
+    Outdated,
+    /// Made for a newer version of the game.
+    TooNew,
+    /// The format or the version of the server is not known.
+    Unknown,
+}
+
+/// A datapack of the world of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Datapack {
+    /// The file or directory name, which the game refers to as `file/<name>`.
+    pub name: String,
+    /// The pack, relative to the server directory.
+    pub path: PathBuf,
+    /// Whether the pack is a zip rather than a directory.
+    pub archive: bool,
+    pub enabled: bool,
+    /// The description of `pack.mcmeta`, flattened from a text component to plain text.
+    pub description: String,
+    pub pack_format: Option<u32>,
+    /// The range of formats the pack declares to support, besides its own.
+    pub supported_formats: Option<(u32, u32)>,
+    pub compatibility: PackCompatibility,
+}
+
+/// The name of the world of a server, from `level-name` of `server.properties`.
+pub(crate) fn level_name(server: &Server<u64>) -> String {
+    server
+        .load_properties_file()
+        .ok()
+        .and_then(|properties| properties.get("level-name").map(str::to_string))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "world".to_string())
+}
+
+/// The data pack format a version of the game expects, `None` for versions before datapacks or snapshots.
+pub fn pack_format_for(game_version: &str) -> Option<u32> {
+    let numbers = |version: &str| -> Option<Vec<u64>> { version.split('.').map(|n| n.parse().ok()).collect() };
+    let game = numbers(game_version)?;
+    PACK_FORMATS
+        .iter()
+        .rev()
+        .find(|(first, _)| numbers(first).is_some_and(|first| game >= first))
+        .map(|(_, format)| *format)
+}
+
+/// Flattens a text component, which may be a string, an object with `text` and `extra` or a list.
+fn flatten_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().map(flatten_text).collect(),
+        Value::Object(_) => {
+            let mut text = component["text"].as_str().unwrap_or_default().to_string();
+            if let Some(extra) = component["extra"].as_array() {
+                text.extend(extra.iter().map(flatten_text));
+            }
+            text
+        }
+        Value::Number(number) => number.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Reads the supported format range, given as a number, a `[min, max]` list or an object.
+fn supported_formats(value: &Value) -> Option<(u32, u32)> {
+    let number = |value: &Value| value.as_u64().map(|number| number as u32);
+    match value {
+        Value::Number(_) => number(value).map(|format| (format, format)),
+        Value::Array(range) => Some((number(range.first()?)?, number(range.get(1)?)?)),
+        Value::Object(_) => Some((number(&value["min_inclusive"])?, number(&value["max_inclusive"])?)),
+        _ => None,
+    }
+}
+
+/// Reads `pack.mcmeta` from the root of a pack zip or directory.
+pub(crate) fn read_pack_mcmeta(path: &Path, archive: bool) -> Result<Value, Box<dyn Error>> {
+    let mut contents = String::new();
+    if archive {
+        let mut zip = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+        let file = zip.by_name("pack.mcmeta").map_err(|_| "The pack has no pack.mcmeta")?;
+        file.take(1024 * 1024).read_to_string(&mut contents)?;
+    } else {
+        File::open(path.join("pack.mcmeta"))?.take(1024 * 1024).read_to_string(&mut contents)?;
+    }
+    // Some editors save the file with a byte order mark, which the game ignores
+    Ok(serde_json::from_str(contents.trim_start_matches('\u{feff}'))?)
+}
+
+fn read_datapack(path: &Path, relative: PathBuf, enabled: bool, expected: Option<u32>) -> Option<Datapack> {
+    let name = path.file_name()?.to_string_lossy().to_string();
+    let archive = path.is_file();
+    if archive && !name.to_lowercase().ends_with(".zip") {
+        return None;
+    }
+    let meta = match read_pack_mcmeta(path, archive) {
+        Ok(meta) => meta,
+        Err(e) => {
+            debug!("Skipping {:?}, it is not a datapack: {}", path, e);
+            return None;
+        }
+    };
+    let pack = &meta["pack"];
+    let pack_format = pack["pack_format"].as_u64().map(|format| format as u32);
+    let supported = supported_formats(&pack["supported_formats"]);
+    let compatibility = match (pack_format, expected) {
+        (Some(format), Some(expected)) => {
+            let (min, max) = supported.unwrap_or((format, format));
+            if format == expected || (min..=max).contains(&expected) {
+                PackCompatibility::Compatible
+            } else if format.max(max) < expected {
+                PackCompatibility::Outdated
+            } else {
+                PackCompatibility::TooNew
+            }
+        }
+        _ => PackCompatibility::Unknown,
+    };
+    Some(Datapack {
+        name,
+        path: relative,
+        archive,
+        enabled,
+        description: flatten_text(&pack["description"]),
+        pack_format,
+        supported_formats: supported,
+        compatibility,
+    })
+}
+
+/// Copies a datapack directory recursively.
+fn copy_directory(source: &Path, destination: &Path) -> Result<(), Box<dyn Error>> {
+    for entry in WalkDir::new(source).follow_links(false) {
+        let entry = entry?;
+        let relative = safe_entry_path(entry.path().strip_prefix(source)?)?;
+        let target = destination.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_file() {
+            io::copy(&mut File::open(entry.path())?, &mut File::create(&target)?)?;
+        }
+    }
+    Ok(())
+}
+
+pub trait ServerDatapacks {
+    /// Lists the enabled and disabled datapacks of the world, checking their format against the
+    /// version of the game of the server.
+    fn list_datapacks(&self) -> Result<Vec<Datapack>, Box<dyn Error>>;
+
+    /// Copies a datapack zip or directory into the world. A running server picks it up on the next
+    /// [`reload_datapacks`](ServerDatapacks::reload_datapacks).
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not edit the files of the server, the source is not
+    /// a datapack, or a datapack of the same name exists.
+    fn install_datapack(&self, source: &Path) -> Result<Datapack, Box<dyn Error>>;
+
+    /// Enables or disables a datapack of the world.
+    ///
+    /// Disabled packs are moved out of `datapacks` so the game does not load them on the next start.
+    /// On a running server, the change also takes effect right away through `/datapack`.
+    fn set_datapack_enabled(&self, name: &str, enabled: bool) -> Result<Datapack, Box<dyn Error>>;
+
+    /// Reloads the datapacks of a running server with `/reload`.
+    fn reload_datapacks(&self) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerDatapacks for Server<u64> {
+    fn list_datapacks(&self) -> Result<Vec<Datapack>, Box<dyn Error>> {
+        let world = PathBuf::from(level_name(self));
+        let expected = pack_format_for(&self.minecraft_version);
+        let mut datapacks = Vec::new();
+        for (folder, enabled) in [("datapacks", true), (DISABLED_DATAPACKS, false)] {
+            let directory = SandboxedPath::new(&self.directory, world.join(folder))?;
+            let Ok(entries) = fs::read_dir(directory.path()) else {
+                continue;
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let relative = directory.relative_path().join(entry.file_name());
+                if let Some(datapack) = read_datapack(&entry.path(), relative, enabled, expected) {
+                    datapacks.push(datapack);
+                }
+            }
+        }
+        datapacks.sort_by_key(|datapack| datapack.name.to_lowercase());
+        Ok(datapacks)
+    }
+
+    fn install_datapack(&self, source: &Path) -> Result<Datapack, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let name = source.file_name().ok_or("The datapack has no file name")?;
+        let directory = SandboxedPath::new(&self.directory, PathBuf::from(level_name(self)).join("datapacks"))?;
+        let target = directory.join(name)?;
+        if target.path().exists() {
+            return Err(format!("A datapack named {:?} already exists", name).into());
+        }
+        // Validates the pack before anything is copied
+        read_pack_mcmeta(source, source.is_file())?;
+
+        fs::create_dir_all(directory.path())?;
+        if source.is_file() {
+            fs::copy(source, target.path())?;
+        } else {
+            copy_directory(source, &target.path())?;
+        }
+        info!("Installed datapack {:?} on server {:?}", name, self.name);
+        read_datapack(
+            &target.path(),
+            target.relative_path().to_path_buf(),
+            true,
+            pack_format_for(&self.minecraft_version),
+        )
+        .ok_or_else(|| "The installed datapack could not be read".into())
+    }
+
+    fn set_datapack_enabled(&self, name: &str, enabled: bool) -> Result<Datapack, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let world = PathBuf::from(level_name(self));
+        let (from, to) = if enabled {
+            (DISABLED_DATAPACKS, "datapacks")
+        } else {
+            ("datapacks", DISABLED_DATAPACKS)
+        };
+        let source = SandboxedPath::new(&self.directory, world.join(from).join(name))?;
+        let target = SandboxedPath::new(&self.directory, world.join(to).join(name))?;
+        if !source.path().exists() {
+            return Err(format!("No {} datapack named {:?}", if enabled { "disabled" } else { "enabled" }, name).into());
+        }
+        if target.path().exists() {
+            return Err(format!("A datapack named {:?} already exists in {}", name, to).into());
+        }
+
+        let running = self.is_server_running();
+        if running && !enabled {
+            send_server_command(self, format!("datapack disable \"file/{}\"", name))?;
+        }
+        if let Some(parent) = target.path().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(source.path(), target.path())?;
+        if running && enabled {
+            // The game only discovers packs added to the folder when it reloads
+            send_server_command(self, "reload")?;
+            send_server_command(self, format!("datapack enable \"file/{}\"", name))?;
+        }
+        info!("{} datapack {:?} on server {:?}", if enabled { "Enabled" } else { "Disabled" }, name, self.name);
+        read_datapack(
+            &target.path(),
+            target.relative_path().to_path_buf(),
+            enabled,
+            pack_format_for(&self.minecraft_version),
+        )
+        .ok_or_else(|| "The datapack could not be read".into())
+    }
+
+    fn reload_datapacks(&self) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        if !self.is_server_running() {
+            return Err("The server has to be running to reload its datapacks".into());
+        }
+        send_server_command(self, "reload")
+    }
+}
@@ -0,0 +1,392 @@
+use crate::config::{get_config, remove_config, set_config, ConfigScope};
+use crate::console_line::{parse_console_line, ConsoleTag};
+use crate::http_client::agent;
+use crate::online_players::{add_player_listener, PlayerEventKind};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::{running_server_pids, send_server_command, watch_console};
+use log::{debug, error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The setting holding the bridge of a server to its Discord channel.
+const CONFIG_KEY: &str = "discord_bridge";
+
+const DISCORD_API: &str = "https://discord.com/api/v10";
+
+/// How often the bridged channels are polled for new messages.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long the console output of a command run from Discord is collected for the reply.
+const COMMAND_OUTPUT_WAIT: Duration = Duration::from_millis(1500);
+
+/// The longest message Discord accepts.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+static BRIDGE: Once = Once::new();
+
+/// What is posted to a channel from the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mirrored {
+    Chat,
+    Join,
+}
+
+/// How messages from Discord are shown in the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayFormat {
+    /// `say`, which every server understands but prefixes with `[Server]`.
+    Say,
+    /// `tellraw @a`, with the name of the Discord user colored like a chat message.
+    Tellraw,
+}
+
+/// Connects a server to a Discord channel through a bot.
+///
+/// The bot has to be in the guild, be allowed to read and send messages in the channel and have the
+/// message content intent enabled in the developer portal, without which Discord sends it empty
+/// messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscordBridgeSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The token of the bot. It is never read back, [`get_discord_bridge`] returns it empty and
+    /// [`set_discord_bridge`] keeps the stored token when it is given an empty one.
+    pub bot_token: String,
+    pub guild_id: String,
+    pub channel_id: String,
+    /// Posts the chat of the players to the channel.
+    #[serde(default = "default_true")]
+    pub mirror_chat: bool,
+    /// Posts when players join and leave.
+    #[serde(default = "default_true")]
+    pub mirror_joins: bool,
+    /// Sends the messages of the channel into the game.
+    #[serde(default = "default_true")]
+    pub relay_messages: bool,
+    #[serde(default = "default_relay_format")]
+    pub relay_format: RelayFormat,
+    /// Messages starting with this run the rest as a console command.
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    /// The ids of the roles whose members may run console commands, nobody if empty.
+    #[serde(default)]
+    pub command_roles: Vec<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_relay_format() -> RelayFormat {
+    RelayFormat::Tellraw
+}
+
+fn default_command_prefix() -> String {
+    "!".to_string()
+}
+
+impl DiscordBridgeSettings {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.bot_token.trim().is_empty() {
+            return Err("The Discord bridge needs a bot token".into());
+        }
+        for (what, id) in [("guild", &self.guild_id), ("channel", &self.channel_id)] {
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+                return Err(format!("The Discord {} id {:?} is not a snowflake", what, id).into());
+            }
+        }
+        if self.command_prefix.trim().is_empty() {
+            return Err("The command prefix cannot be empty".into());
+        }
+        Ok(())
+    }
+}
+
+/// What is known about the bridge of a running server.
+struct ActiveBridge {
+    pid: u64,
+    settings: DiscordBridgeSettings,
+    /// The newest message of the channel that was handled, so only later ones are fetched.
+    last_message_id: Option<String>,
+}
+
+/// Reads the Discord bridge of a server, `None` if it has none. The bot token is left out.
+pub fn get_discord_bridge(server_id: u64) -> Result<Option<DiscordBridgeSettings>, Box<dyn Error>> {
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::SendCommands)?;
+    Ok(read_bridge(server_id)?.map(|settings| DiscordBridgeSettings {
+        bot_token: String::new(),
+        ..settings
+    }))
+}
+
+fn read_bridge(server_id: u64) -> Result<Option<DiscordBridgeSettings>, Box<dyn Error>> {
+    get_config(ConfigScope::Server(server_id), CONFIG_KEY)
+}
+
+/// Sets up the Discord bridge of a server. A running server is bridged with the new settings
+/// within a few seconds. An empty bot token keeps the one of the current bridge.
+///
+/// Members of the command roles run console commands through the bridge, so setting it up takes
+/// both sending commands and editing the files of the server.
+///
+/// # Errors
+/// Returns an error if the token or ids are missing or the settings cannot be stored.
+pub fn set_discord_bridge(server_id: u64, settings: &DiscordBridgeSettings) -> Result<(), Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    authorize(&server, Capability::SendCommands)?;
+    authorize(&server, Capability::EditFiles)?;
+    let mut settings = settings.clone();
+    if settings.bot_token.is_empty() {
+        if let Some(current) = read_bridge(server_id)? {
+            settings.bot_token = current.bot_token;
+        }
+    }
+    settings.validate()?;
+    set_config(ConfigScope::Server(server_id), CONFIG_KEY, &settings)?;
+    info!("Bridged server {} to the Discord channel {}", server_id, settings.channel_id);
+    Ok(())
+}
+
+/// Removes the Discord bridge of a server.
+pub fn remove_discord_bridge(server_id: u64) -> Result<(), Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    authorize(&server, Capability::SendCommands)?;
+    authorize(&server, Capability::EditFiles)?;
+    remove_config(ConfigScope::Server(server_id), CONFIG_KEY)
+}
+
+/// Starts bridging the running servers to their Discord channels. Calling it again has no effect.
+pub fn start_discord_bridges() {
+    BRIDGE.call_once(|| {
+        let (sender, receiver) = mpsc::channel::<(u64, Mirrored, String)>();
+        let joins = sender.clone();
+        add_player_listener(move |event| {
+            let action = match event.kind {
+                PlayerEventKind::Joined => "joined",
+                PlayerEventKind::Left => "left",
+            };
+            let message = format!("**{}** {} the server", event.player.name, action);
+            let _ = joins.send((event.server_id, Mirrored::Join, message));
+        });
+        thread::spawn(move || run_bridges(sender, receiver));
+        info!("Started the Discord bridges");
+    });
+}
+
+fn run_bridges(sender: Sender<(u64, Mirrored, String)>, outgoing: Receiver<(u64, Mirrored, String)>) {
+    let mut bridges: HashMap<u64, ActiveBridge> = HashMap::new();
+    // The processes whose chat is watched, which outlives a bridge that is turned off and on again
+    let mut watched: HashSet<(u64, u64)> = HashSet::new();
+    loop {
+        let running = running_server_pids();
+        bridges.retain(|server_id, bridge| running.contains(&(*server_id, bridge.pid)));
+        watched.retain(|process| running.contains(process));
+        for (server_id, pid) in running {
+            let settings = match read_bridge(server_id) {
+                Ok(Some(settings)) if settings.enabled => settings,
+                Ok(_) => {
+                    bridges.remove(&server_id);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to read the Discord bridge of server {}: {}", server_id, e);
+                    continue;
+                }
+            };
+            match bridges.get_mut(&server_id) {
+                Some(bridge) => bridge.settings = settings,
+                None => {
+                    // The console watchers are dropped when the server exits, so every run is watched anew
+                    if watched.insert((server_id, pid)) {
+                        watch_chat(server_id, sender.clone());
+                    }
+                    bridges.insert(
+                        server_id,
+                        ActiveBridge {
+                            pid,
+                            settings,
+                            last_message_id: None,
+                        },
+                    );
+                }
+            }
+        }
+
+        for (server_id, kind, message) in outgoing.try_iter() {
+            let Some(bridge) = bridges.get(&server_id) else {
+                continue;
+            };
+            let mirrored = match kind {
+                Mirrored::Chat => bridge.settings.mirror_chat,
+                Mirrored::Join => bridge.settings.mirror_joins,
+            };
+            if mirrored {
+                if let Err(e) = send_message(&bridge.settings, &message) {
+                    warn!("Failed to post to the Discord channel of server {}: {}", server_id, e);
+                }
+            }
+        }
+
+        for (server_id, bridge) in bridges.iter_mut() {
+            if let Err(e) = poll_channel(*server_id, bridge) {
+                warn!("Failed to read the Discord channel of server {}: {}", server_id, e);
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Forwards the chat messages of a running server to the bridge thread.
+fn watch_chat(server_id: u64, chat: Sender<(u64, Mirrored, String)>) {
+    watch_console(server_id, move |line| {
+        let line = parse_console_line(line);
+        match (&line.player, line.tags.contains(&ConsoleTag::Chat)) {
+            (Some(player), true) => {
+                let text = line.message.split_once("> ").map_or("", |(_, text)| text);
+                chat.send((server_id, Mirrored::Chat, format!("**<{}>** {}", player, text))).is_ok()
+            }
+            _ => true,
+        }
+    });
+}
+
+fn discord_get(settings: &DiscordBridgeSettings, path: &str) -> Result<Value, Box<dyn Error>> {
+    let response = agent()
+        .get(&format!("{}{}", DISCORD_API, path))
+        .set("Authorization", &format!("Bot {}", settings.bot_token))
+        .call()?;
+    Ok(serde_json::from_reader(response.into_reader())?)
+}
+
+/// Posts a message to the channel of a bridge. Mentions in it do not ping anyone, so players
+/// cannot mention `@everyone` from the game.
+fn send_message(settings: &DiscordBridgeSettings, content: &str) -> Result<(), Box<dyn Error>> {
+    let content: String = content.chars().take(MAX_MESSAGE_LENGTH).collect();
+    let body = json!({ "content": content, "allowed_mentions": { "parse": [] } });
+    agent()
+        .post(&format!("{}/channels/{}/messages", DISCORD_API, settings.channel_id))
+        .set("Authorization", &format!("Bot {}", settings.bot_token))
+        .set("Content-Type", "application/json")
+        .send_string(&body.to_string())?;
+    Ok(())
+}
+
+/// Handles the messages posted to the channel of a bridge since the last poll.
+fn poll_channel(server_id: u64, bridge: &mut ActiveBridge) -> Result<(), Box<dyn Error>> {
+    let Some(after) = bridge.last_message_id.clone() else {
+        // The messages from before the server started are not replayed
+        let path = format!("/channels/{}/messages?limit=1", bridge.settings.channel_id);
+        let latest = discord_get(&bridge.settings, &path)?;
+        bridge.last_message_id = Some(
+            latest
+                .get(0)
+                .and_then(|message| message["id"].as_str())
+                .unwrap_or("0")
+                .to_string(),
+        );
+        return Ok(());
+    };
+    let path = format!("/channels/{}/messages?after={}&limit=50", bridge.settings.channel_id, after);
+    let messages = discord_get(&bridge.settings, &path)?;
+    let mut messages: Vec<&Value> = messages.as_array().map(|messages| messages.iter().collect()).unwrap_or_default();
+    // Discord returns the newest message first
+    messages.reverse();
+    for message in messages {
+        if let Some(id) = message["id"].as_str() {
+            bridge.last_message_id = Some(id.to_string());
+        }
+        let author = &message["author"];
+        if author["bot"].as_bool().unwrap_or(false) {
+            continue;
+        }
+        let content = message["content"].as_str().unwrap_or_default().trim();
+        let user_id = author["id"].as_str().unwrap_or_default();
+        let name = author["global_name"]
+            .as_str()
+            .or_else(|| author["username"].as_str())
+            .unwrap_or("unknown");
+        if content.is_empty() {
+            continue;
+        }
+        if let Some(command) = content.strip_prefix(&bridge.settings.command_prefix) {
+            run_discord_command(server_id, &bridge.settings, user_id, name, command.trim())?;
+        } else if bridge.settings.relay_messages {
+            relay_to_game(server_id, &bridge.settings, name, content)?;
+        }
+    }
+    Ok(())
+}
+
+/// Shows a message from Discord to the players.
+fn relay_to_game(
+    server_id: u64,
+    settings: &DiscordBridgeSettings,
+    name: &str,
+    content: &str,
+) -> Result<(), Box<dyn Error>> {
+    // A line break would end the command
+    let content = content.replace(['\r', '\n'], " ");
+    let command = match settings.relay_format {
+        RelayFormat::Say => format!("say [Discord] {}: {}", name, content),
+        RelayFormat::Tellraw => format!(
+            "tellraw @a {}",
+            json!([
+                { "text": "[Discord] ", "color": "blue" },
+                { "text": format!("<{}> ", name), "color": "aqua" },
+                { "text": content, "color": "white" },
+            ])
+        ),
+    };
+    send_server_command(&Server::<u64>::get_server(server_id)?, command)
+}
+
+/// Runs a console command for a member of the channel, replying with what the server printed.
+fn run_discord_command(
+    server_id: u64,
+    settings: &DiscordBridgeSettings,
+    user_id: &str,
+    name: &str,
+    command: &str,
+) -> Result<(), Box<dyn Error>> {
+    if command.is_empty() {
+        return Ok(());
+    }
+    let member = discord_get(settings, &format!("/guilds/{}/members/{}", settings.guild_id, user_id))?;
+    let allowed = member["roles"].as_array().is_some_and(|roles| {
+        roles
+            .iter()
+            .filter_map(|role| role.as_str())
+            .any(|role| settings.command_roles.iter().any(|allowed| allowed == role))
+    });
+    if !allowed {
+        debug!("Discord user {} may not run commands on server {}", name, server_id);
+        return send_message(settings, &format!("{} may not run console commands", name));
+    }
+
+    info!("Discord user {} ran {:?} on server {}", name, command, server_id);
+    let (output, lines) = mpsc::channel::<String>();
+    let started = Instant::now();
+    watch_console(server_id, move |line| {
+        started.elapsed() < COMMAND_OUTPUT_WAIT && output.send(parse_console_line(line).message).is_ok()
+    });
+    send_server_command(&Server::<u64>::get_server(server_id)?, command.replace(['\r', '\n'], " "))?;
+    thread::sleep(COMMAND_OUTPUT_WAIT);
+    let output: Vec<String> = lines.try_iter().collect();
+    let reply = if output.is_empty() {
+        format!("Ran `{}`", command)
+    } else {
+        // Leaves room for the command and the code block around the output
+        let output: String = output.join("\n").replace("```", "'''").chars().take(MAX_MESSAGE_LENGTH - 200).collect();
+        format!("Ran `{}`\n```\n{}\n```", command, output)
+    };
+    send_message(settings, &reply)
+}
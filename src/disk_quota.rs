@@ -0,0 +1,285 @@
+use crate::backups::backup_directory;
+use crate::file_system_entry::calculate_directory_size;
+use crate::server::Server;
+use crate::users::require_admin;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// A listener invoked whenever a server crosses a quota threshold.
+type QuotaListener = Box<dyn Fn(&QuotaEvent) + Send>;
+
+lazy_static! {
+    static ref QUOTA_LISTENERS: Mutex<Vec<QuotaListener>> = Mutex::new(Vec::new());
+}
+
+/// The usage levels at which quota events are emitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaThreshold {
+    /// 80% of the quota is used.
+    Warning,
+    /// 90% of the quota is used.
+    Critical,
+    /// The quota is used up, uploads and backups are refused.
+    Exceeded,
+}
+
+impl QuotaThreshold {
+    /// The percentage of the quota at which the threshold is reached.
+    pub fn percent(&self) -> u8 {
+        match self {
+            QuotaThreshold::Warning => 80,
+            QuotaThreshold::Critical => 90,
+            QuotaThreshold::Exceeded => 100,
+        }
+    }
+
+    /// Returns the highest threshold reached by a usage, if any.
+    pub fn for_usage(used: u64, limit: u64) -> Option<Self> {
+        [QuotaThreshold::Exceeded, QuotaThreshold::Critical, QuotaThreshold::Warning]
+            .into_iter()
+            .find(|threshold| used as u128 * 100 >= limit as u128 * threshold.percent() as u128)
+    }
+
+    fn from_percent(percent: i64) -> Option<Self> {
+        match percent {
+            80 => Some(QuotaThreshold::Warning),
+            90 => Some(QuotaThreshold::Critical),
+            100 => Some(QuotaThreshold::Exceeded),
+            _ => None,
+        }
+    }
+}
+
+/// The disk usage of a server compared to its quota.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskQuotaUsage {
+    pub server_id: u64,
+    /// The quota in bytes, `None` if the server has no quota.
+    pub limit: Option<u64>,
+    /// The bytes used by the server directory and the backups of the server.
+    pub used: u64,
+    /// The bytes that can still be written, `None` if the server has no quota.
+    pub available: Option<u64>,
+    /// The highest threshold reached by the current usage.
+    pub threshold: Option<QuotaThreshold>,
+}
+
+/// Emitted when the usage of a server rises to a new threshold.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaEvent {
+    pub threshold: QuotaThreshold,
+    pub usage: DiskQuotaUsage,
+}
+
+/// Creates the table holding the disk quotas of the servers.
+///
+/// Quotas are kept apart from the `server` table, since most servers do not have one.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_disk_quota_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_disk_quota` (
+            server_id INTEGER PRIMARY KEY,                              -- The server the quota applies to
+            limit_bytes INTEGER NOT NULL,                               -- The quota in bytes
+            warned_percent INTEGER NOT NULL DEFAULT 0                   -- The last threshold an event was emitted for, 0 for none
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Registers a listener that is invoked whenever a server reaches 80%, 90% or 100% of its quota.
+///
+/// Every threshold is reported once when it is reached. It is reported again only after the usage
+/// dropped below it in the meantime.
+pub fn add_quota_listener(listener: impl Fn(&QuotaEvent) + Send + 'static) {
+    if let Ok(mut listeners) = QUOTA_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+/// Reads the quota of a server.
+///
+/// # Returns
+/// The quota in bytes, or `None` if the server has no quota.
+pub fn get_disk_quota(server_id: u64) -> Result<Option<u64>, Box<dyn Error>> {
+    Ok(read_quota(server_id)?.map(|(limit, _)| limit))
+}
+
+/// Sets or removes the quota of a server.
+///
+/// # Arguments
+/// * `server_id` - The server to configure.
+/// * `limit` - The quota in bytes, or `None` to remove the quota.
+pub fn set_disk_quota(server_id: u64, limit: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    match limit {
+        Some(limit) => {
+            let query = r#"
+INSERT INTO server_disk_quota (server_id, limit_bytes, warned_percent) VALUES (?, ?, 0)
+ON CONFLICT(server_id) DO UPDATE SET limit_bytes = excluded.limit_bytes, warned_percent = 0
+"#;
+            let mut statement = conn.prepare(query)?;
+            statement.bind((1, server_id as i64))?;
+            statement.bind((2, limit as i64))?;
+            statement.next()?;
+            info!("Set the disk quota of server {} to {} bytes", server_id, limit);
+        }
+        None => {
+            let mut statement = conn.prepare(r#"DELETE FROM server_disk_quota WHERE server_id = ?"#)?;
+            statement.bind((1, server_id as i64))?;
+            statement.next()?;
+            info!("Removed the disk quota of server {}", server_id);
+        }
+    }
+    Ok(())
+}
+
+/// The bytes a server uses. Its backups are kept outside of the server directory, but count towards
+/// the quota as well.
+fn used_space(server_id: u64, directory: &Path) -> u64 {
+    let backups = backup_directory(server_id);
+    let backups_size = if backups.is_dir() { calculate_directory_size(backups) } else { 0 };
+    calculate_directory_size(directory) + backups_size
+}
+
+/// Returns how many bytes can still be written to a server directory or the backups of the server,
+/// or `None` if the server has no quota.
+pub(crate) fn available_quota(server_id: u64, directory: &Path) -> Result<Option<u64>, Box<dyn Error>> {
+    Ok(read_quota(server_id)?.map(|(limit, _)| limit.saturating_sub(used_space(server_id, directory))))
+}
+
+/// Refuses a write of `additional` bytes to a server directory if it would exceed the server's quota.
+///
+/// # Errors
+/// Returns an error if the write would exceed the quota or the quota cannot be read.
+pub(crate) fn ensure_quota_allows(server_id: u64, directory: &Path, additional: u64) -> Result<(), Box<dyn Error>> {
+    match available_quota(server_id, directory)? {
+        Some(available) if additional > available => Err(format!(
+            "Writing {} bytes would exceed the disk quota, only {} bytes are available",
+            additional, available
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Measures the usage of a server directory and its backups, emitting a quota event if a new threshold was reached.
+///
+/// # Errors
+/// Returns an error if the quota cannot be read or updated.
+pub(crate) fn refresh_quota_usage(server_id: u64, directory: &Path) -> Result<DiskQuotaUsage, Box<dyn Error>> {
+    let used = used_space(server_id, directory);
+    let Some((limit, warned)) = read_quota(server_id)? else {
+        return Ok(DiskQuotaUsage {
+            server_id,
+            limit: None,
+            used,
+            available: None,
+            threshold: None,
+        });
+    };
+
+    let threshold = QuotaThreshold::for_usage(used, limit);
+    let usage = DiskQuotaUsage {
+        server_id,
+        limit: Some(limit),
+        used,
+        available: Some(limit.saturating_sub(used)),
+        threshold,
+    };
+
+    if threshold != warned {
+        let conn = create_appdb_connection()?;
+        let mut statement = conn.prepare(r#"UPDATE server_disk_quota SET warned_percent = ? WHERE server_id = ?"#)?;
+        statement.bind((1, threshold.map_or(0, |threshold| threshold.percent()) as i64))?;
+        statement.bind((2, server_id as i64))?;
+        statement.next()?;
+    }
+
+    // Only rising usage is reported, dropping below a threshold merely re-arms it
+    if let Some(threshold) = threshold.filter(|threshold| warned.map_or(true, |warned| *threshold > warned)) {
+        warn!(
+            "Server {} reached {}% of its disk quota ({} of {} bytes)",
+            server_id,
+            threshold.percent(),
+            used,
+            limit
+        );
+        emit(&QuotaEvent {
+            threshold,
+            usage: usage.clone(),
+        });
+    }
+    Ok(usage)
+}
+
+fn read_quota(server_id: u64) -> Result<Option<(u64, Option<QuotaThreshold>)>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare(r#"SELECT limit_bytes, warned_percent FROM server_disk_quota WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        let limit = statement.read::<i64, _>("limit_bytes")? as u64;
+        let warned = QuotaThreshold::from_percent(statement.read::<i64, _>("warned_percent")?);
+        return Ok(Some((limit, warned)));
+    }
+    Ok(None)
+}
+
+fn emit(event: &QuotaEvent) {
+    match QUOTA_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(event)),
+        Err(err) => error!("Failed to notify quota listeners: {}", err),
+    }
+}
+
+/// Disk quota management for servers.
+pub trait ServerDiskQuota {
+    /// Returns the quota of the server in bytes, or `None` if it has no quota.
+    fn get_disk_quota(&self) -> Result<Option<u64>, Box<dyn Error>>;
+
+    /// Sets the quota of the server in bytes, or removes it with `None`. Only administrators can
+    /// change it, so nobody lifts the limit of their own server.
+    fn set_disk_quota(&self, limit: Option<u64>) -> Result<(), Box<dyn Error>>;
+
+    /// Measures the current usage of the server against its quota.
+    ///
+    /// Reaching a new threshold notifies the listeners registered with [`add_quota_listener`].
+    fn get_disk_quota_usage(&self) -> Result<DiskQuotaUsage, Box<dyn Error>>;
+
+    /// Checks whether `additional` bytes can be written to the server without exceeding its quota.
+    ///
+    /// # Errors
+    /// Returns an error describing the available space if the write would exceed the quota.
+    fn ensure_disk_quota(&self, additional: u64) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerDiskQuota for Server<u64> {
+    fn get_disk_quota(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        get_disk_quota(self.id)
+    }
+
+    fn set_disk_quota(&self, limit: Option<u64>) -> Result<(), Box<dyn Error>> {
+        require_admin()?;
+        set_disk_quota(self.id, limit)?;
+        refresh_quota_usage(self.id, &self.directory).map(|_| ())
+    }
+
+    fn get_disk_quota_usage(&self) -> Result<DiskQuotaUsage, Box<dyn Error>> {
+        refresh_quota_usage(self.id, &self.directory)
+    }
+
+    fn ensure_disk_quota(&self, additional: u64) -> Result<(), Box<dyn Error>> {
+        ensure_quota_allows(self.id, &self.directory, additional)
+    }
+}
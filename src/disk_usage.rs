@@ -0,0 +1,296 @@
+use crate::sandboxed_path::SandboxedPath;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Options controlling a disk usage analysis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiskUsageOptions {
+    /// How many levels of subdirectories are included in the tree. Deeper directories are
+    /// still counted towards the sizes of their ancestors.
+    pub max_depth: usize,
+    /// Directories and files smaller than this are left out of the tree.
+    pub min_size: u64,
+    /// The number of largest files to report.
+    pub top_files: usize,
+}
+
+impl Default for DiskUsageOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 3,
+            min_size: 0,
+            top_files: 25,
+        }
+    }
+}
+
+/// What the space of a server is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageCategory {
+    /// Directories containing a `level.dat`, such as `world` and `world_nether`.
+    Worlds,
+    Backups,
+    /// Server logs and crash reports.
+    Logs,
+    /// Mods and plugins.
+    Mods,
+    Other,
+}
+
+/// A file or directory in the disk usage tree, with the aggregated size of everything below it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsageNode {
+    pub name: String,
+    /// The path relative to the server's root directory.
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// The size in bytes, including all subdirectories.
+    pub size: u64,
+    /// The number of files below the directory, `1` for files.
+    pub file_count: u64,
+    /// The children, largest first. Empty for files and for directories beyond `max_depth`.
+    pub children: Vec<DiskUsageNode>,
+}
+
+/// One of the largest files found during an analysis.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct LargeFile {
+    pub size: u64,
+    /// The path relative to the server's root directory.
+    pub path: PathBuf,
+    pub category: UsageCategory,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// The space used by one category.
+#[derive(Debug, Clone, Serialize)]
+pub struct CategoryUsage {
+    pub category: UsageCategory,
+    pub size: u64,
+    pub file_count: u64,
+}
+
+/// The result of a disk usage analysis.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiskUsageReport {
+    pub tree: DiskUsageNode,
+    /// The largest files, largest first.
+    pub largest_files: Vec<LargeFile>,
+    /// The totals per category, largest first. Categories without files are left out.
+    pub categories: Vec<CategoryUsage>,
+    pub total_size: u64,
+    pub file_count: u64,
+    pub directory_count: u64,
+}
+
+/// Analyzes where the space below a directory goes, like `du` does.
+///
+/// Symbolic links are counted with their own size and never followed, so a world linked in from
+/// another disk does not inflate the numbers.
+///
+/// # Arguments
+/// * `directory` - The directory to analyze.
+/// * `options` - The depth of the tree and the number of largest files.
+///
+/// # Errors
+/// Returns an error if `directory` is not a readable directory. Unreadable entries below it are skipped.
+pub fn analyze_disk_usage(
+    directory: &SandboxedPath,
+    options: &DiskUsageOptions,
+) -> Result<DiskUsageReport, Box<dyn Error>> {
+    let path = directory.path();
+    if !fs::metadata(&path)?.is_dir() {
+        return Err(format!("{} is not a directory", directory).into());
+    }
+    info!("Analyzing disk usage of {:?}", path);
+
+    let mut analyzer = Analyzer {
+        root: directory.root_path().to_path_buf(),
+        options,
+        categories: HashMap::new(),
+        top_categories: HashMap::new(),
+        largest: BinaryHeap::new(),
+        directory_count: 0,
+    };
+    let tree = analyzer.visit_directory(&path, 0)?;
+
+    let mut largest_files: Vec<LargeFile> = analyzer.largest.into_iter().map(|Reverse(file)| file).collect();
+    largest_files.sort_by(|a, b| b.cmp(a));
+
+    let mut categories: Vec<CategoryUsage> = analyzer
+        .categories
+        .into_iter()
+        .map(|(category, (size, file_count))| CategoryUsage {
+            category,
+            size,
+            file_count,
+        })
+        .collect();
+    categories.sort_by(|a, b| b.size.cmp(&a.size).then(a.category.cmp(&b.category)));
+
+    info!(
+        "{:?} uses {} bytes in {} files and {} directories",
+        path, tree.size, tree.file_count, analyzer.directory_count
+    );
+    Ok(DiskUsageReport {
+        total_size: tree.size,
+        file_count: tree.file_count,
+        directory_count: analyzer.directory_count,
+        tree,
+        largest_files,
+        categories,
+    })
+}
+
+struct Analyzer<'a> {
+    root: PathBuf,
+    options: &'a DiskUsageOptions,
+    /// The size and number of files per category.
+    categories: HashMap<UsageCategory, (u64, u64)>,
+    /// The category of every top level entry of the server directory seen so far.
+    top_categories: HashMap<OsString, UsageCategory>,
+    /// The largest files so far, as a min-heap so the smallest one can be replaced cheaply.
+    largest: BinaryHeap<Reverse<LargeFile>>,
+    directory_count: u64,
+}
+
+impl Analyzer<'_> {
+    fn visit_directory(&mut self, path: &Path, depth: usize) -> Result<DiskUsageNode, Box<dyn Error>> {
+        self.directory_count += 1;
+        let mut node = DiskUsageNode {
+            name: file_name(path),
+            path: self.relative(path),
+            is_dir: true,
+            size: 0,
+            file_count: 0,
+            children: Vec::new(),
+        };
+
+        for entry in fs::read_dir(path)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Skipping unreadable entry in {:?}: {}", path, err);
+                    continue;
+                }
+            };
+            let child_path = entry.path();
+            let metadata = match fs::symlink_metadata(&child_path) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    warn!("Failed to read metadata of {:?}: {}", child_path, err);
+                    continue;
+                }
+            };
+
+            let child = if metadata.is_dir() {
+                match self.visit_directory(&child_path, depth + 1) {
+                    Ok(child) => child,
+                    Err(err) => {
+                        warn!("Skipping unreadable directory {:?}: {}", child_path, err);
+                        continue;
+                    }
+                }
+            } else {
+                self.visit_file(&child_path, &metadata)
+            };
+
+            node.size += child.size;
+            node.file_count += child.file_count;
+            if depth < self.options.max_depth && child.size >= self.options.min_size {
+                node.children.push(child);
+            }
+        }
+
+        node.children
+            .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        Ok(node)
+    }
+
+    fn visit_file(&mut self, path: &Path, metadata: &fs::Metadata) -> DiskUsageNode {
+        let size = metadata.len();
+        let relative = self.relative(path);
+        let category = self.categorize(&relative);
+
+        let totals = self.categories.entry(category).or_insert((0, 0));
+        totals.0 += size;
+        totals.1 += 1;
+
+        if self.options.top_files > 0 {
+            let is_larger = self
+                .largest
+                .peek()
+                .map_or(true, |Reverse(smallest)| size > smallest.size);
+            if self.largest.len() < self.options.top_files || is_larger {
+                self.largest.push(Reverse(LargeFile {
+                    size,
+                    path: relative.clone(),
+                    category,
+                    last_modified: metadata.modified().ok(),
+                }));
+                if self.largest.len() > self.options.top_files {
+                    self.largest.pop();
+                }
+            }
+        }
+
+        DiskUsageNode {
+            name: file_name(path),
+            path: relative,
+            is_dir: false,
+            size,
+            file_count: 1,
+            children: Vec::new(),
+        }
+    }
+
+    /// Determines the category of a file from the top level directory of the server it is in.
+    fn categorize(&mut self, relative: &Path) -> UsageCategory {
+        let mut components = relative.components();
+        let Some(Component::Normal(top)) = components.next() else {
+            return UsageCategory::Other;
+        };
+        if components.next().is_none() {
+            // Files directly in the server directory
+            return if relative.extension().is_some_and(|extension| extension == "log") {
+                UsageCategory::Logs
+            } else {
+                UsageCategory::Other
+            };
+        }
+
+        if let Some(category) = self.top_categories.get(top) {
+            return *category;
+        }
+        let category = match top.to_string_lossy().to_lowercase().as_str() {
+            "logs" | "crash-reports" => UsageCategory::Logs,
+            "backups" => UsageCategory::Backups,
+            "mods" | "plugins" => UsageCategory::Mods,
+            _ if self.root.join(top).join("level.dat").is_file() => UsageCategory::Worlds,
+            _ => UsageCategory::Other,
+        };
+        self.top_categories.insert(top.to_os_string(), category);
+        category
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root)
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,355 @@
+use crate::notifications::{add_notification_listener, Notification, NotificationEvent, NotificationSeverity};
+use crate::restart_schedule::civil_from_days;
+use crate::users::require_admin;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, Message};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{SmtpTransport, Transport};
+use log::{error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// How long the SMTP server gets to answer.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the digests waiting to be sent are checked.
+const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+static DELIVERY: Once = Once::new();
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// A plain connection upgraded with `STARTTLS`, usually on port 587. An SMTP server that does not
+    /// offer it is refused.
+    StartTls,
+    /// No encryption, only for a relay on the same machine or network.
+    None,
+}
+
+/// A mailbox the notifications are mailed to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailTarget {
+    /// Identifies the target, assigned when it is added.
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    /// The user to log in as, no login if `None`.
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The sender, like `Minecraft <minecraft@example.com>`.
+    pub from: String,
+    pub to: Vec<String>,
+    /// The events mailed, every event if empty.
+    #[serde(default)]
+    pub events: Vec<NotificationEvent>,
+    /// The servers whose notifications are mailed, every server if empty.
+    #[serde(default)]
+    pub servers: Vec<u64>,
+    /// Notifications below this severity are not mailed, by default only the critical ones such as
+    /// crashes and failed backups.
+    #[serde(default = "default_min_severity")]
+    pub min_severity: NotificationSeverity,
+    /// After a mail, the notifications of the next this many minutes are collected into a single
+    /// digest, so a crash loop sends a mail every few minutes instead of one per crash. `0` mails
+    /// every notification on its own.
+    #[serde(default = "default_digest_minutes")]
+    pub digest_minutes: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_min_severity() -> NotificationSeverity {
+    NotificationSeverity::Critical
+}
+
+fn default_digest_minutes() -> u64 {
+    15
+}
+
+impl EmailTarget {
+    fn accepts(&self, notification: &Notification) -> bool {
+        self.enabled && notification.matches(&self.events, &self.servers, self.min_severity)
+    }
+
+    fn mailer(&self) -> Result<SmtpTransport, Box<dyn Error>> {
+        let builder = match self.security {
+            SmtpSecurity::Tls => SmtpTransport::relay(&self.host)?,
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&self.host)?,
+            SmtpSecurity::None => SmtpTransport::builder_dangerous(&self.host),
+        };
+        let builder = builder.port(self.port).timeout(Some(SMTP_TIMEOUT));
+        Ok(match &self.username {
+            Some(username) => builder
+                .credentials(Credentials::new(username.clone(), self.password.clone().unwrap_or_default()))
+                .build(),
+            None => builder.build(),
+        })
+    }
+
+    /// Mails a message to every recipient of the target.
+    fn send(&self, subject: &str, body: String) -> Result<(), Box<dyn Error>> {
+        let mut message = Message::builder()
+            .from(self.from.parse::<Mailbox>()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.parse::<Mailbox>()?);
+        }
+        self.mailer()?.send(&message.body(body)?)?;
+        Ok(())
+    }
+}
+
+fn validate_target(target: &EmailTarget) -> Result<(), Box<dyn Error>> {
+    if target.host.trim().is_empty() {
+        return Err("An email target needs the host of an SMTP server".into());
+    }
+    if target.to.is_empty() {
+        return Err("An email target needs at least one recipient".into());
+    }
+    target.from.parse::<Mailbox>().map_err(|_| format!("The sender {:?} is not a mail address", target.from))?;
+    for to in &target.to {
+        to.parse::<Mailbox>().map_err(|_| format!("The recipient {:?} is not a mail address", to))?;
+    }
+    if target.username.is_some() && target.security == SmtpSecurity::None {
+        return Err("A password cannot be sent to an SMTP server without TLS".into());
+    }
+    Ok(())
+}
+
+/// The notifications a target collects into its next digest.
+#[derive(Default)]
+struct Digest {
+    /// Until when notifications are collected instead of mailed, `None` if the next one is mailed
+    /// right away.
+    collecting_until: Option<Instant>,
+    pending: Vec<Notification>,
+}
+
+/// Creates the table holding the email targets.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_email_target_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `notification_email_targets` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the email target
+            settings TEXT NOT NULL                                      -- The SMTP server and recipients, as JSON
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Lists the email targets.
+pub fn list_email_targets() -> Result<Vec<EmailTarget>, Box<dyn Error>> {
+    require_admin()?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM notification_email_targets ORDER BY id"#)?;
+    let mut targets = Vec::new();
+    while let State::Row = statement.next()? {
+        targets.push(read_target(&mut statement)?);
+    }
+    Ok(targets)
+}
+
+/// Reads an email target.
+pub fn get_email_target(target_id: u64) -> Result<EmailTarget, Box<dyn Error>> {
+    require_admin()?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM notification_email_targets WHERE id = ?"#)?;
+    statement.bind((1, target_id as i64))?;
+    if let State::Row = statement.next()? {
+        return read_target(&mut statement);
+    }
+    Err(format!("There is no email target {}", target_id).into())
+}
+
+fn read_target(statement: &mut sqlite::Statement) -> Result<EmailTarget, Box<dyn Error>> {
+    let mut target: EmailTarget = serde_json::from_str(&statement.read::<String, _>("settings")?)?;
+    target.id = statement.read::<i64, _>("id")? as u64;
+    Ok(target)
+}
+
+/// Adds an email target.
+///
+/// # Returns
+/// The identifier of the new target.
+///
+/// # Errors
+/// Returns an error if the server or an address is missing or invalid, or the target cannot be stored.
+pub fn add_email_target(target: &EmailTarget) -> Result<u64, Box<dyn Error>> {
+    require_admin()?;
+    validate_target(target)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"INSERT INTO notification_email_targets (settings) VALUES (?)"#)?;
+    statement.bind((1, serde_json::to_string(target)?.as_str()))?;
+    statement.next()?;
+    let id = last_inserted_id("notification_email_targets")?;
+    info!("Added the email target {:?} mailing {}", target.name, target.to.join(", "));
+    Ok(id)
+}
+
+/// Replaces the settings of the email target with the identifier of `target`.
+pub fn update_email_target(target: &EmailTarget) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    validate_target(target)?;
+    get_email_target(target.id)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"UPDATE notification_email_targets SET settings = ? WHERE id = ?"#)?;
+    statement.bind((1, serde_json::to_string(target)?.as_str()))?;
+    statement.bind((2, target.id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Removes an email target. A digest it was collecting is dropped.
+pub fn remove_email_target(target_id: u64) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"DELETE FROM notification_email_targets WHERE id = ?"#)?;
+    statement.bind((1, target_id as i64))?;
+    statement.next()?;
+    info!("Removed the email target {}", target_id);
+    Ok(())
+}
+
+/// Mails a test message through an email target, to check the SMTP settings.
+pub fn test_email_target(target_id: u64) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    let target = get_email_target(target_id)?;
+    target.send("Test notification", format!("The email target {} is set up.\n", target.name))
+}
+
+/// Starts mailing the notifications to the email targets. Calling it again has no effect.
+pub fn start_email_delivery() {
+    DELIVERY.call_once(|| {
+        let (sender, receiver) = mpsc::channel::<Notification>();
+        let sender = Mutex::new(sender);
+        add_notification_listener(move |notification| {
+            if let Ok(sender) = sender.lock() {
+                let _ = sender.send(notification.clone());
+            }
+        });
+        thread::spawn(move || {
+            let mut digests: HashMap<u64, Digest> = HashMap::new();
+            loop {
+                match receiver.recv_timeout(DIGEST_CHECK_INTERVAL) {
+                    Ok(notification) => match list_email_targets() {
+                        Ok(targets) => {
+                            for target in targets.iter().filter(|target| target.accepts(&notification)) {
+                                let digest = digests.entry(target.id).or_default();
+                                digest.pending.push(notification.clone());
+                                if digest.collecting_until.is_none() {
+                                    flush(target, digest);
+                                }
+                            }
+                        }
+                        Err(e) => error!("Failed to read the email targets: {}", e),
+                    },
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                send_due_digests(&mut digests);
+            }
+        });
+        info!("Started mailing notifications");
+    });
+}
+
+/// Mails the digests whose collection window is over.
+fn send_due_digests(digests: &mut HashMap<u64, Digest>) {
+    let now = Instant::now();
+    let due = digests.values().any(|digest| digest.collecting_until.is_some_and(|until| until <= now));
+    if !due {
+        return;
+    }
+    let targets = match list_email_targets() {
+        Ok(targets) => targets,
+        Err(e) => {
+            error!("Failed to read the email targets: {}", e);
+            return;
+        }
+    };
+    digests.retain(|id, _| targets.iter().any(|target| target.id == *id));
+    for target in &targets {
+        let Some(digest) = digests.get_mut(&target.id) else {
+            continue;
+        };
+        if !digest.collecting_until.is_some_and(|until| until <= now) {
+            continue;
+        }
+        if digest.pending.is_empty() {
+            // Nothing happened during the window, the next notification is mailed right away
+            digest.collecting_until = None;
+        } else {
+            flush(target, digest);
+        }
+    }
+}
+
+/// Mails the pending notifications of a target, as a single mail if there is one and as a digest
+/// otherwise, and starts collecting the next digest.
+fn flush(target: &EmailTarget, digest: &mut Digest) {
+    let notifications = std::mem::take(&mut digest.pending);
+    let (subject, body) = match notifications.as_slice() {
+        [] => return,
+        [notification] => {
+            let body = format!("{}\n\n{}\n", notification.message, timestamp(notification));
+            (notification.title.clone(), body)
+        }
+        notifications => {
+            let worst = notifications
+                .iter()
+                .map(|notification| notification.severity)
+                .max()
+                .unwrap_or(NotificationSeverity::Info);
+            let mut body =
+                format!("{} notifications in the last {} minutes:\n", notifications.len(), target.digest_minutes);
+            for notification in notifications {
+                body.push_str(&format!(
+                    "\n[{:?}] {}\n{}\n{}\n",
+                    notification.severity,
+                    notification.title,
+                    notification.message,
+                    timestamp(notification)
+                ));
+            }
+            (format!("{} notifications ({:?})", notifications.len(), worst), body)
+        }
+    };
+    if let Err(e) = target.send(&subject, body) {
+        warn!("Failed to mail {} notifications to {:?}: {}", notifications.len(), target.name, e);
+    }
+    digest.collecting_until = (target.digest_minutes > 0)
+        .then(|| Instant::now() + Duration::from_secs(target.digest_minutes * 60));
+}
+
+/// When a notification happened, in UTC.
+fn timestamp(notification: &Notification) -> String {
+    let seconds = notification.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (year, month, day) = civil_from_days(seconds / 86_400);
+    let time = seconds % 86_400;
+    format!("{}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
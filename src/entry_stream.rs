@@ -0,0 +1,183 @@
+use crate::file_system_entry::{child_entry, FileSystemEntries, FileSystemEntry};
+use crate::fs_error::FsError;
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, error, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Options controlling a streamed directory listing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamOptions {
+    /// The maximum number of entries whose metadata is read at the same time.
+    pub concurrency: usize,
+    /// Skips opening files to sniff their contents. Files without a known extension are then
+    /// reported with the `UNKNOWN` category, which keeps listings of huge directories cheap.
+    pub fast: bool,
+    /// Whether hidden entries are included.
+    pub show_hidden: bool,
+    /// Whether symbolic links inside the server directory are resolved.
+    pub follow_symlinks: bool,
+    /// How many finished entries are buffered before producing more waits for the consumer.
+    pub buffer: usize,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            fast: false,
+            show_hidden: false,
+            follow_symlinks: true,
+            buffer: 64,
+        }
+    }
+}
+
+/// The entries of a directory, delivered as they are read instead of all at once.
+///
+/// Reading metadata and sniffing contents happens on tokio's blocking thread pool, so the async
+/// runtime is never stalled by a large directory. Entries arrive in no particular order, since
+/// each one is delivered as soon as it is ready. Dropping the stream stops the listing.
+pub struct EntryStream {
+    receiver: mpsc::Receiver<Result<FileSystemEntry, FsError>>,
+    parent: Option<PathBuf>,
+    relative_to: Option<PathBuf>,
+    producer: JoinHandle<()>,
+}
+
+impl EntryStream {
+    /// Starts listing a directory.
+    ///
+    /// Has to be called from within a tokio runtime.
+    ///
+    /// # Arguments
+    /// * `directory` - The directory to list.
+    /// * `options` - The concurrency and the kind of listing.
+    pub fn open(directory: &SandboxedPath, options: &StreamOptions) -> Self {
+        let (sender, receiver) = mpsc::channel(options.buffer.max(1));
+        let producer = tokio::spawn(produce(directory.clone(), options.clone(), sender));
+        Self {
+            receiver,
+            parent: directory.parent().map(|parent| parent.path()),
+            relative_to: None,
+            producer,
+        }
+    }
+
+    /// Reports the paths of all entries relative to the server's root directory.
+    pub(crate) fn relative_to(mut self, root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        self.parent = self
+            .parent
+            .take()
+            .and_then(|parent| parent.strip_prefix(&root).ok().map(|parent| parent.to_path_buf()));
+        self.relative_to = Some(root);
+        self
+    }
+
+    /// The parent of the listed directory, `None` for the server's root directory.
+    pub fn parent(&self) -> Option<&PathBuf> {
+        self.parent.as_ref()
+    }
+
+    /// Waits for the next entry.
+    ///
+    /// # Returns
+    /// The next entry or the error reading it, or `None` once every entry was delivered.
+    /// A directory that cannot be read is reported as a single error.
+    pub async fn next(&mut self) -> Option<Result<FileSystemEntry, FsError>> {
+        let mut result = self.receiver.recv().await?;
+        if let Some(root) = &self.relative_to {
+            match &mut result {
+                Ok(entry) => entry.relativize(root),
+                Err(err) => err.relativize(root),
+            }
+        }
+        Some(result)
+    }
+
+    /// Waits for all remaining entries and collects them into a listing sorted by name.
+    pub async fn collect(mut self) -> FileSystemEntries {
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        while let Some(result) = self.next().await {
+            match result {
+                Ok(entry) => entries.push(entry),
+                Err(err) => errors.push(err),
+            }
+        }
+        entries.sort_by_key(|entry| entry.name.to_lowercase());
+        FileSystemEntries {
+            parent: self.parent.take(),
+            total: entries.len(),
+            offset: 0,
+            entries,
+            errors,
+        }
+    }
+}
+
+impl Drop for EntryStream {
+    fn drop(&mut self) {
+        self.producer.abort();
+    }
+}
+
+/// Reads the directory and hands every entry to the blocking pool, at most `concurrency` at a time.
+async fn produce(
+    directory: SandboxedPath,
+    options: StreamOptions,
+    sender: mpsc::Sender<Result<FileSystemEntry, FsError>>,
+) {
+    let path = directory.path();
+    let mut read_dir = match tokio::fs::read_dir(&path).await {
+        Ok(read_dir) => read_dir,
+        Err(err) => {
+            error!("Failed to read directory: {:?}. Error: {:?}", path, err);
+            if sender.send(Err(FsError::from_io(&path, &err))).await.is_err() {
+                debug!("Entry stream for {} was closed", directory);
+            }
+            return;
+        }
+    };
+
+    let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    loop {
+        let file_name = match read_dir.next_entry().await {
+            Ok(Some(entry)) => entry.file_name(),
+            Ok(None) => break,
+            Err(err) => {
+                warn!("Stopped listing {:?} early: {}", path, err);
+                if sender.send(Err(FsError::from_io(&path, &err))).await.is_err() {
+                    debug!("Entry stream for {} was closed", directory);
+                }
+                break;
+            }
+        };
+        let Ok(permit) = Arc::clone(&permits).acquire_owned().await else {
+            break;
+        };
+
+        let directory = directory.clone();
+        let sender = sender.clone();
+        let follow_symlinks = options.follow_symlinks;
+        let sniff_content = !options.fast;
+        let show_hidden = options.show_hidden;
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let result = match child_entry(&directory, &file_name, follow_symlinks, sniff_content) {
+                Some(Ok(entry)) if !show_hidden && entry.is_hidden => return,
+                Some(result) => result,
+                None => return,
+            };
+            if sender.blocking_send(result).is_err() {
+                debug!("Entry stream for {} was closed", directory);
+            }
+        });
+    }
+    // The stream ends once the last blocking task drops its sender
+}
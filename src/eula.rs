@@ -0,0 +1,103 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_status::ServerStatus;
+use crate::text_file::write_file_atomically;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// The file the server reads to check whether its operator agreed to the Minecraft EULA.
+const EULA_FILE: &str = "eula.txt";
+
+/// The comment the game writes above the setting, kept so the file looks like one the game wrote.
+const EULA_COMMENT: &str =
+    "#By changing the setting below to TRUE you are indicating your agreement to our EULA (https://aka.ms/MinecraftEULA).";
+
+/// Whether the Minecraft EULA was accepted for a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EulaState {
+    Accepted,
+    /// `eula.txt` exists, but does not set `eula=true`. The game writes it like this on its first start.
+    NotAccepted,
+    /// `eula.txt` does not exist, the server has never been started.
+    Missing,
+}
+
+/// Reads the EULA state from the `eula.txt` of a server directory.
+///
+/// Like the game, only `eula=true` counts as accepted, case insensitively.
+pub fn read_eula_state(directory: &Path) -> Result<EulaState, Box<dyn Error>> {
+    let content = match fs::read_to_string(directory.join(EULA_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(EulaState::Missing),
+        Err(err) => return Err(err.into()),
+    };
+    let accepted = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .rev()
+        .find(|(key, _)| key.trim() == "eula")
+        .is_some_and(|(_, value)| value.trim().eq_ignore_ascii_case("true"));
+    Ok(if accepted { EulaState::Accepted } else { EulaState::NotAccepted })
+}
+
+/// Writes an `eula.txt` accepting the Minecraft EULA, keeping comments of an existing file.
+pub fn write_accepted_eula(directory: &Path) -> Result<(), Box<dyn Error>> {
+    let path = directory.join(EULA_FILE);
+    let existing = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => format!("{}\n", EULA_COMMENT),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut lines: Vec<String> = existing
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("eula=") && !line.trim_start().starts_with("eula ="))
+        .map(str::to_string)
+        .collect();
+    lines.push("eula=true".to_string());
+    write_file_atomically(&path, format!("{}\n", lines.join("\n")).as_bytes())?;
+    info!("Accepted the Minecraft EULA in {:?}", path);
+    Ok(())
+}
+
+pub trait ServerEula {
+    /// Checks whether the Minecraft EULA was accepted for the server.
+    ///
+    /// # Returns
+    /// - `Ok(EulaState)` telling whether `eula.txt` accepts the EULA, rejects it, or is missing.
+    /// - `Err(Box<dyn Error>)` if `eula.txt` exists but cannot be read.
+    fn get_eula_state(&self) -> Result<EulaState, Box<dyn Error>>;
+
+    /// Accepts the Minecraft EULA on behalf of the server's operator by writing `eula.txt`.
+    ///
+    /// Only call this after the user explicitly agreed to the EULA. A server waiting for the
+    /// EULA is set back to offline, so it can be started.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not control the server, `eula.txt` cannot be
+    /// written or the server cannot be saved.
+    fn accept_eula(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerEula for Server<u64> {
+    fn get_eula_state(&self) -> Result<EulaState, Box<dyn Error>> {
+        read_eula_state(&self.directory)
+    }
+
+    fn accept_eula(&mut self) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        write_accepted_eula(&self.directory)?;
+        if self.status == Some(ServerStatus::EulaRequired) {
+            self.status = Some(ServerStatus::Offline);
+            self.update()?;
+        }
+        Ok(())
+    }
+}
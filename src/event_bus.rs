@@ -0,0 +1,395 @@
+use crate::backups::{add_backup_listener, BackupEvent};
+use crate::file_watcher::{FileChangeEvent, FileWatcher, WatchOptions};
+use crate::jobs::Job;
+use crate::online_players::{add_player_listener, PlayerEvent};
+use crate::permissions::{has_capability, Capability};
+use crate::resource_usage::{add_resource_listener, ResourceSample};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_status::ServerStatus;
+use crate::users::get_user;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+
+/// How many events a subscriber can fall behind before it misses some.
+const EVENT_CAPACITY: usize = 1024;
+
+static FORWARDER: Once = Once::new();
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref EVENTS: broadcast::Sender<Arc<EventEnvelope>> = broadcast::channel(EVENT_CAPACITY).0;
+    /// The file watchers of the servers someone subscribed to the file changes of, with the number
+    /// of subscriptions using each.
+    static ref FILE_WATCHERS: Mutex<HashMap<u64, (FileWatcher, usize)>> = Mutex::new(HashMap::new());
+}
+
+/// A kind of event a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTopic {
+    /// A server started, stopped, crashed or changed its status otherwise.
+    ServerStatus,
+    /// Files of a server were created, changed or removed, by the panel or the game.
+    Files,
+    Backups,
+    /// Players joined or left.
+    Players,
+    /// The resource usage of a running server, sampled every few seconds.
+    Metrics,
+    /// Background jobs were queued, progressed or finished.
+    Jobs,
+}
+
+impl EventTopic {
+    /// What a user needs on a server to receive its events of the topic.
+    fn capability(&self) -> Capability {
+        match self {
+            EventTopic::Files => Capability::EditFiles,
+            EventTopic::Backups => Capability::ManageBackups,
+            _ => Capability::ViewConsole,
+        }
+    }
+}
+
+/// Something that changed on a server.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PanelEvent {
+    ServerStatus { server_id: u64, status: ServerStatus },
+    FilesChanged { server_id: u64, changes: Vec<FileChangeEvent> },
+    Backup(BackupEvent),
+    Player(PlayerEvent),
+    Metrics { server_id: u64, sample: ResourceSample },
+    Job(Job),
+}
+
+impl PanelEvent {
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            PanelEvent::ServerStatus { .. } => EventTopic::ServerStatus,
+            PanelEvent::FilesChanged { .. } => EventTopic::Files,
+            PanelEvent::Backup(_) => EventTopic::Backups,
+            PanelEvent::Player(_) => EventTopic::Players,
+            PanelEvent::Metrics { .. } => EventTopic::Metrics,
+            PanelEvent::Job(_) => EventTopic::Jobs,
+        }
+    }
+
+    /// The server the event is about, `None` for jobs not tied to a server yet.
+    pub fn server_id(&self) -> Option<u64> {
+        match self {
+            PanelEvent::ServerStatus { server_id, .. }
+            | PanelEvent::FilesChanged { server_id, .. }
+            | PanelEvent::Metrics { server_id, .. } => Some(*server_id),
+            PanelEvent::Backup(event) => Some(event.server_id),
+            PanelEvent::Player(event) => Some(event.server_id),
+            PanelEvent::Job(job) => job.server_id,
+        }
+    }
+}
+
+/// An event as it is sent over the WebSocket.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventEnvelope {
+    /// Increases by one with every event, across all topics, so clients can tell they missed some.
+    pub sequence: u64,
+    pub topic: EventTopic,
+    pub event: PanelEvent,
+}
+
+/// What a client sends over the WebSocket.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Replaces the topics and servers the client receives events of. An empty list of servers
+    /// means every server the user may see.
+    Subscribe {
+        topics: Vec<EventTopic>,
+        #[serde(default)]
+        servers: Vec<u64>,
+    },
+    /// Stops receiving the events of some topics.
+    Unsubscribe { topics: Vec<EventTopic> },
+    Ping,
+}
+
+/// What the panel sends over the WebSocket besides events.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Subscribed {
+        topics: &'a HashSet<EventTopic>,
+        servers: &'a [u64],
+    },
+    Pong,
+    /// The client fell behind and missed events, it should fetch the current state again.
+    Lagged { missed: u64 },
+    Error { message: String },
+}
+
+/// Sends an event to everyone subscribed to it.
+pub(crate) fn publish(event: PanelEvent) {
+    // Without subscribers there is nobody to tell, and sending would fail
+    if EVENTS.receiver_count() == 0 {
+        return;
+    }
+    let envelope = EventEnvelope {
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1,
+        topic: event.topic(),
+        event,
+    };
+    let _ = EVENTS.send(Arc::new(envelope));
+}
+
+/// Tells the subscribers that a server has a new status.
+pub(crate) fn publish_status(server: &Server<u64>) {
+    publish(PanelEvent::ServerStatus {
+        server_id: server.id,
+        status: server.status.clone().unwrap_or_default(),
+    });
+}
+
+/// Forwards the backups, player joins and leaves and resource samples of the servers to the
+/// event subscribers. Server status changes and file changes are published as they happen.
+/// Calling it again has no effect.
+pub fn start_event_bus() {
+    FORWARDER.call_once(|| {
+        add_backup_listener(|event| publish(PanelEvent::Backup(event.clone())));
+        add_player_listener(|event| publish(PanelEvent::Player(event.clone())));
+        add_resource_listener(|server_id, sample| {
+            publish(PanelEvent::Metrics {
+                server_id,
+                sample: sample.clone(),
+            })
+        });
+        info!("Started publishing server events");
+    });
+}
+
+/// The events a WebSocket client receives, filtered by its topics, servers and what its user may
+/// see. The panel's WebSocket handler feeds it the messages of the client with
+/// [`EventSubscription::handle_message`] and sends it the frames from [`EventSubscription::next`].
+pub struct EventSubscription {
+    /// `None` for the manager itself, which sees everything.
+    user_id: Option<u64>,
+    topics: HashSet<EventTopic>,
+    servers: Vec<u64>,
+    receiver: broadcast::Receiver<Arc<EventEnvelope>>,
+    /// Whether the user may see the events of a topic on a server, looked up once per pair.
+    allowed: HashMap<(u64, EventTopic), bool>,
+    is_admin: Option<bool>,
+    watched: Vec<u64>,
+}
+
+impl EventSubscription {
+    /// Subscribes a user to the events of some topics on some servers, every server the user may
+    /// see if `servers` is empty.
+    ///
+    /// # Errors
+    /// Returns an error if the user may not see the file changes of a server asked for.
+    pub fn new(user_id: Option<u64>, topics: &[EventTopic], servers: &[u64]) -> Result<Self, Box<dyn Error>> {
+        let mut subscription = Self {
+            user_id,
+            topics: HashSet::new(),
+            servers: Vec::new(),
+            receiver: EVENTS.subscribe(),
+            allowed: HashMap::new(),
+            is_admin: None,
+            watched: Vec::new(),
+        };
+        subscription.subscribe(topics, servers)?;
+        Ok(subscription)
+    }
+
+    /// Replaces the topics and servers the subscription receives events of.
+    ///
+    /// File changes are only watched on the servers named, watching every server for them would
+    /// be too expensive, so subscribing to [`EventTopic::Files`] without any fails.
+    ///
+    /// # Errors
+    /// Returns an error if the file changes are asked for without servers, or on a server the
+    /// user may not see the files of. The subscription is left as it was.
+    pub fn subscribe(&mut self, topics: &[EventTopic], servers: &[u64]) -> Result<(), Box<dyn Error>> {
+        let topics: HashSet<EventTopic> = topics.iter().copied().collect();
+        let mut servers = servers.to_vec();
+        servers.sort_unstable();
+        servers.dedup();
+
+        let watched = if topics.contains(&EventTopic::Files) {
+            if servers.is_empty() {
+                return Err("File changes can only be subscribed to for specific servers".into());
+            }
+            for server_id in &servers {
+                if !self.may_see(*server_id, EventTopic::Files) {
+                    return Err(format!("You are not allowed to see the files of the server {}", server_id).into());
+                }
+            }
+            servers.clone()
+        } else {
+            Vec::new()
+        };
+        for server_id in watched.iter().filter(|id| !self.watched.contains(id)) {
+            if let Err(e) = watch_files(*server_id) {
+                release_watchers(watched.iter().filter(|id| *id < server_id && !self.watched.contains(id)));
+                return Err(e);
+            }
+        }
+        release_watchers(self.watched.iter().filter(|id| !watched.contains(id)));
+
+        debug!("Subscribed user {:?} to {:?} on servers {:?}", self.user_id, topics, servers);
+        self.topics = topics;
+        self.servers = servers;
+        self.watched = watched;
+        Ok(())
+    }
+
+    /// Handles a message the client sent.
+    ///
+    /// # Returns
+    /// The frame to send back, which is an error message if the client sent something invalid.
+    pub fn handle_message(&mut self, message: &str) -> String {
+        let reply = match serde_json::from_str::<ClientMessage>(message) {
+            Ok(ClientMessage::Subscribe { topics, servers }) => self.subscribe(&topics, &servers).map(|()| None),
+            Ok(ClientMessage::Unsubscribe { topics }) => {
+                let remaining: Vec<EventTopic> =
+                    self.topics.iter().copied().filter(|topic| !topics.contains(topic)).collect();
+                let servers = self.servers.clone();
+                self.subscribe(&remaining, &servers).map(|()| None)
+            }
+            Ok(ClientMessage::Ping) => Ok(Some(ServerMessage::Pong)),
+            Err(e) => Err(format!("Invalid message: {}", e).into()),
+        };
+        let reply = match reply {
+            Ok(Some(reply)) => reply,
+            Ok(None) => ServerMessage::Subscribed {
+                topics: &self.topics,
+                servers: &self.servers,
+            },
+            Err(e) => ServerMessage::Error { message: e.to_string() },
+        };
+        serde_json::to_string(&reply).unwrap_or_default()
+    }
+
+    /// Waits for the next frame to send to the client.
+    ///
+    /// # Returns
+    /// `None` once the manager shuts down and there will be no further events.
+    pub async fn next(&mut self) -> Option<String> {
+        loop {
+            let received = self.receiver.recv().await;
+            if let Some(frame) = self.frame(received)? {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Returns the next frame to send to the client if there is one, for handlers polling it.
+    pub fn try_next(&mut self) -> Option<String> {
+        loop {
+            let received = match self.receiver.try_recv() {
+                Ok(envelope) => Ok(envelope),
+                Err(TryRecvError::Lagged(missed)) => Err(RecvError::Lagged(missed)),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return None,
+            };
+            if let Some(frame) = self.frame(received)? {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Turns what was received into a frame, `Some(None)` for events the client does not get.
+    fn frame(&mut self, received: Result<Arc<EventEnvelope>, RecvError>) -> Option<Option<String>> {
+        let envelope = match received {
+            Ok(envelope) => envelope,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("A subscriber of user {:?} missed {} events", self.user_id, missed);
+                return Some(serde_json::to_string(&ServerMessage::Lagged { missed }).ok());
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        let visible = match envelope.event.server_id() {
+            Some(server_id) => {
+                (self.servers.is_empty() || self.servers.contains(&server_id))
+                    && self.may_see(server_id, envelope.topic)
+            }
+            // Jobs of the manager as a whole are only shown to administrators
+            None => self.servers.is_empty() && self.is_admin(),
+        };
+        if !self.topics.contains(&envelope.topic) || !visible {
+            return Some(None);
+        }
+        Some(serde_json::to_string(envelope.as_ref()).ok())
+    }
+
+    fn is_admin(&mut self) -> bool {
+        let Some(user_id) = self.user_id else {
+            return true;
+        };
+        *self.is_admin.get_or_insert_with(|| get_user(user_id).is_ok_and(|user| user.is_admin))
+    }
+
+    /// Whether the user may see the events of a topic on a server. It is looked up once, a client
+    /// that lost access keeps receiving events until it subscribes again.
+    fn may_see(&mut self, server_id: u64, topic: EventTopic) -> bool {
+        let Some(user_id) = self.user_id else {
+            return true;
+        };
+        *self.allowed.entry((server_id, topic)).or_insert_with(|| {
+            Server::<u64>::get_server(server_id)
+                .and_then(|server| has_capability(user_id, &server, topic.capability()))
+                .unwrap_or(false)
+        })
+    }
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        release_watchers(self.watched.iter());
+    }
+}
+
+/// Starts watching the files of a server for the subscribers, or counts one more subscriber if
+/// they are already watched.
+fn watch_files(server_id: u64) -> Result<(), Box<dyn Error>> {
+    let mut watchers = FILE_WATCHERS.lock().map_err(|_| "Failed to lock the file watchers")?;
+    if let Some((_, subscribers)) = watchers.get_mut(&server_id) {
+        *subscribers += 1;
+        return Ok(());
+    }
+    let server = Server::<u64>::get_server(server_id)?;
+    let directory = SandboxedPath::root(&server.directory)?;
+    let watcher = FileWatcher::start(&directory, &WatchOptions::default(), move |changes| {
+        publish(PanelEvent::FilesChanged {
+            server_id,
+            changes: changes.to_vec(),
+        });
+        true
+    })?;
+    watchers.insert(server_id, (watcher, 1));
+    Ok(())
+}
+
+/// Stops watching the files of servers once nobody is subscribed to them anymore.
+fn release_watchers<'a>(server_ids: impl Iterator<Item = &'a u64>) {
+    let Ok(mut watchers) = FILE_WATCHERS.lock() else {
+        return;
+    };
+    for server_id in server_ids {
+        if let Some((_, subscribers)) = watchers.get_mut(server_id) {
+            *subscribers -= 1;
+            if *subscribers == 0 {
+                if let Some((watcher, _)) = watchers.remove(server_id) {
+                    watcher.stop();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::backup_remotes::RemoteStorage;
+use crate::notifications::{add_notification_listener, Notification};
+use crate::provisioning::JarDownload;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+
+static NOTIFICATION_DELIVERY: Once = Once::new();
+
+lazy_static! {
+    static ref EXTENSIONS: Mutex<Vec<ExtensionInfo>> = Mutex::new(Vec::new());
+    static ref NOTIFICATION_TARGETS: Mutex<Vec<Arc<dyn NotificationTarget>>> = Mutex::new(Vec::new());
+    static ref BACKUP_BACKENDS: Mutex<Vec<Arc<dyn BackupBackend>>> = Mutex::new(Vec::new());
+    static ref JAR_PROVIDERS: Mutex<Vec<Arc<dyn JarProvider>>> = Mutex::new(Vec::new());
+}
+
+/// An integration added to the manager from outside its core modules, like a crate of the panel or
+/// a build with extra features. It hands its parts to the [`ExtensionRegistrar`] when registered.
+///
+/// ```ignore
+/// struct Matrix;
+///
+/// impl Extension for Matrix {
+///     fn name(&self) -> &str {
+///         "matrix"
+///     }
+///
+///     fn register(&self, registrar: &mut ExtensionRegistrar) {
+///         registrar.notification_target(MatrixRoom::from_env());
+///     }
+/// }
+///
+/// register_extension(Matrix)?;
+/// ```
+pub trait Extension: Send + Sync {
+    /// Identifies the extension, unique among the registered extensions.
+    fn name(&self) -> &str;
+
+    fn version(&self) -> &str {
+        "0.0.0"
+    }
+
+    fn register(&self, registrar: &mut ExtensionRegistrar);
+}
+
+/// Receives every notification, besides the webhooks, email and Discord targets of the manager.
+pub trait NotificationTarget: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether the target wants a notification, every one by default.
+    fn accepts(&self, _notification: &Notification) -> bool {
+        true
+    }
+
+    /// Sends a notification. Runs on a thread of its own, so it may block on the network.
+    fn deliver(&self, notification: &Notification) -> Result<(), Box<dyn Error>>;
+}
+
+/// A storage service backups can be uploaded to, used by remote targets with an
+/// [`Extension`](crate::backup_remotes::RemoteBackend::Extension) backend.
+pub trait BackupBackend: Send + Sync {
+    /// The name remote targets refer to the backend by.
+    fn name(&self) -> &str;
+
+    /// Checks the settings of a remote target before it is stored.
+    fn validate(&self, _settings: &Value) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Opens a connection to the storage for one upload.
+    fn connect(&self, settings: &Value) -> Result<Box<dyn RemoteStorage>, Box<dyn Error>>;
+}
+
+/// A source of server jars, used by new servers with an
+/// [`Extension`](crate::provisioning::JarSource::Extension) jar.
+pub trait JarProvider: Send + Sync {
+    /// The name jar sources refer to the provider by.
+    fn name(&self) -> &str;
+
+    /// The versions of the game the provider has jars for, newest first.
+    fn list_versions(&self) -> Result<Vec<String>, Box<dyn Error>>;
+
+    /// Looks up the download of the jar for a version, the newest build if `build` is not set.
+    fn resolve(&self, version: &str, build: Option<&str>) -> Result<JarDownload, Box<dyn Error>>;
+}
+
+/// Collects the parts of an extension while it registers.
+#[derive(Default)]
+pub struct ExtensionRegistrar {
+    notification_targets: Vec<Arc<dyn NotificationTarget>>,
+    backup_backends: Vec<Arc<dyn BackupBackend>>,
+    jar_providers: Vec<Arc<dyn JarProvider>>,
+}
+
+impl ExtensionRegistrar {
+    pub fn notification_target(&mut self, target: impl NotificationTarget + 'static) -> &mut Self {
+        self.notification_targets.push(Arc::new(target));
+        self
+    }
+
+    pub fn backup_backend(&mut self, backend: impl BackupBackend + 'static) -> &mut Self {
+        self.backup_backends.push(Arc::new(backend));
+        self
+    }
+
+    pub fn jar_provider(&mut self, provider: impl JarProvider + 'static) -> &mut Self {
+        self.jar_providers.push(Arc::new(provider));
+        self
+    }
+}
+
+/// A registered extension and what it added.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtensionInfo {
+    pub name: String,
+    pub version: String,
+    pub notification_targets: Vec<String>,
+    pub backup_backends: Vec<String>,
+    pub jar_providers: Vec<String>,
+}
+
+/// Registers an extension and the targets, backends and providers it brings.
+///
+/// # Errors
+/// Returns an error if an extension with the same name is registered, or a backend or provider
+/// takes a name another one already has. Nothing of the extension is registered then.
+pub fn register_extension(extension: impl Extension + 'static) -> Result<(), Box<dyn Error>> {
+    let mut registrar = ExtensionRegistrar::default();
+    extension.register(&mut registrar);
+    let info = ExtensionInfo {
+        name: extension.name().to_string(),
+        version: extension.version().to_string(),
+        notification_targets: names(&registrar.notification_targets, |target| target.name()),
+        backup_backends: names(&registrar.backup_backends, |backend| backend.name()),
+        jar_providers: names(&registrar.jar_providers, |provider| provider.name()),
+    };
+
+    let mut extensions = EXTENSIONS.lock().map_err(|e| e.to_string())?;
+    if extensions.iter().any(|registered| registered.name == info.name) {
+        return Err(format!("An extension named {:?} is already registered", info.name).into());
+    }
+    let mut backends = BACKUP_BACKENDS.lock().map_err(|e| e.to_string())?;
+    let taken = |name: &&String| backends.iter().any(|backend| backend.name() == name.as_str());
+    if let Some(name) = info.backup_backends.iter().find(taken) {
+        return Err(format!("A backup backend named {:?} is already registered", name).into());
+    }
+    let mut providers = JAR_PROVIDERS.lock().map_err(|e| e.to_string())?;
+    let taken = |name: &&String| providers.iter().any(|provider| provider.name() == name.as_str());
+    if let Some(name) = info.jar_providers.iter().find(taken) {
+        return Err(format!("A jar provider named {:?} is already registered", name).into());
+    }
+    if !registrar.notification_targets.is_empty() {
+        NOTIFICATION_TARGETS.lock().map_err(|e| e.to_string())?.extend(registrar.notification_targets);
+        start_notification_delivery();
+    }
+    backends.extend(registrar.backup_backends);
+    providers.extend(registrar.jar_providers);
+    info!("Registered the extension {} {}", info.name, info.version);
+    extensions.push(info);
+    Ok(())
+}
+
+fn names<T: ?Sized>(parts: &[Arc<T>], name: impl Fn(&T) -> &str) -> Vec<String> {
+    parts.iter().map(|part| name(part).to_string()).collect()
+}
+
+/// Lists the registered extensions, in the order they were registered.
+pub fn list_extensions() -> Vec<ExtensionInfo> {
+    EXTENSIONS.lock().map(|extensions| extensions.clone()).unwrap_or_default()
+}
+
+/// The registered backup backend with a name.
+pub fn backup_backend(name: &str) -> Result<Arc<dyn BackupBackend>, Box<dyn Error>> {
+    let backends = BACKUP_BACKENDS.lock().map_err(|e| e.to_string())?;
+    backends
+        .iter()
+        .find(|backend| backend.name() == name)
+        .cloned()
+        .ok_or_else(|| format!("No backup backend named {:?} is registered", name).into())
+}
+
+/// The registered jar provider with a name.
+pub fn jar_provider(name: &str) -> Result<Arc<dyn JarProvider>, Box<dyn Error>> {
+    let providers = JAR_PROVIDERS.lock().map_err(|e| e.to_string())?;
+    providers
+        .iter()
+        .find(|provider| provider.name() == name)
+        .cloned()
+        .ok_or_else(|| format!("No jar provider named {:?} is registered", name).into())
+}
+
+/// Passes the notifications to the targets of the extensions, once the first one is registered.
+fn start_notification_delivery() {
+    NOTIFICATION_DELIVERY.call_once(|| {
+        add_notification_listener(|notification| {
+            let targets: Vec<Arc<dyn NotificationTarget>> = match NOTIFICATION_TARGETS.lock() {
+                Ok(targets) => targets.iter().filter(|target| target.accepts(notification)).cloned().collect(),
+                Err(_) => return,
+            };
+            if targets.is_empty() {
+                return;
+            }
+            let notification = notification.clone();
+            thread::spawn(move || {
+                for target in targets {
+                    if let Err(e) = target.deliver(&notification) {
+                        warn!("Failed to deliver a notification to {}: {}", target.name(), e);
+                    }
+                }
+            });
+        });
+    });
+}
@@ -0,0 +1,187 @@
+use crate::file_hash::{hash_file, HashAlgorithm};
+use crate::file_system_entry::is_text_file;
+use crate::sandboxed_path::SandboxedPath;
+use crate::text_file::{read_text_file, DEFAULT_MAX_TEXT_FILE_SIZE};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use similar::{Algorithm, ChangeTag, TextDiff};
+use std::error::Error;
+use std::fs;
+
+/// Options controlling how a diff is computed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiffOptions {
+    /// The number of unchanged lines shown around every change.
+    pub context_lines: usize,
+    /// Files larger than this are not diffed, since a line diff of huge files takes a long time.
+    pub max_file_size: u64,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            context_lines: 3,
+            max_file_size: DEFAULT_MAX_TEXT_FILE_SIZE,
+        }
+    }
+}
+
+/// Whether a line of a diff was kept, removed or added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffLineKind {
+    Context,
+    Delete,
+    Insert,
+}
+
+/// A single line of a diff hunk.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffLine {
+    pub kind: DiffLineKind,
+    /// The one based line number in the old file, `None` for inserted lines.
+    pub old_line: Option<usize>,
+    /// The one based line number in the new file, `None` for deleted lines.
+    pub new_line: Option<usize>,
+    /// The line without its line ending.
+    pub content: String,
+}
+
+/// A group of nearby changes along with their context, like a `@@` section of a unified diff.
+#[derive(Debug, Clone, Serialize)]
+pub struct DiffHunk {
+    pub old_start: usize,
+    pub old_lines: usize,
+    pub new_start: usize,
+    pub new_lines: usize,
+    pub lines: Vec<DiffLine>,
+}
+
+/// The differences between two files.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileDiff {
+    pub old_name: String,
+    pub new_name: String,
+    /// Whether both files have the same contents.
+    pub identical: bool,
+    /// Whether one of the files is binary. Binary files are only compared by hash, without hunks.
+    pub binary: bool,
+    /// The number of added lines.
+    pub insertions: usize,
+    /// The number of removed lines.
+    pub deletions: usize,
+    pub hunks: Vec<DiffHunk>,
+    /// The diff in unified format, as `diff -u` or `git diff` would print it.
+    pub unified: String,
+}
+
+/// Computes the line diff between two files.
+///
+/// Both files are decoded with their detected encoding and line endings are normalized, so a config
+/// that was only converted from CRLF to LF shows up as identical.
+///
+/// # Arguments
+/// * `old` - The original file, for example a backup copy.
+/// * `new` - The changed file.
+/// * `options` - The context size and file size limit.
+///
+/// # Errors
+/// Returns an error if a file cannot be read or exceeds the size limit.
+pub fn diff_files(old: &SandboxedPath, new: &SandboxedPath, options: &DiffOptions) -> Result<FileDiff, Box<dyn Error>> {
+    let old_name = old.to_string();
+    let new_name = new.to_string();
+
+    let is_empty = |path: &SandboxedPath| fs::metadata(path.path()).is_ok_and(|metadata| metadata.len() == 0);
+    let old_is_text = is_empty(old) || is_text_file(old.path());
+    let new_is_text = is_empty(new) || is_text_file(new.path());
+    if !old_is_text || !new_is_text {
+        debug!("Comparing binary files {} and {} by hash", old_name, new_name);
+        let identical = hash_file(old, HashAlgorithm::Sha256)? == hash_file(new, HashAlgorithm::Sha256)?;
+        return Ok(FileDiff {
+            old_name,
+            new_name,
+            identical,
+            binary: true,
+            ..Default::default()
+        });
+    }
+
+    let old_text = read_text_file(old, options.max_file_size)?.content;
+    let new_text = read_text_file(new, options.max_file_size)?.content;
+    Ok(diff_text(&old_name, &old_text, &new_name, &new_text, options))
+}
+
+/// Computes the line diff between two texts.
+///
+/// # Arguments
+/// * `old_name` - The name shown for the original text in the unified diff header.
+/// * `old_text` - The original text.
+/// * `new_name` - The name shown for the changed text.
+/// * `new_text` - The changed text.
+/// * `options` - The number of context lines.
+pub fn diff_text(old_name: &str, old_text: &str, new_name: &str, new_text: &str, options: &DiffOptions) -> FileDiff {
+    // Patience produces more readable hunks for configs with many similar lines
+    let diff = TextDiff::configure()
+        .algorithm(Algorithm::Patience)
+        .diff_lines(old_text, new_text);
+
+    let mut insertions = 0;
+    let mut deletions = 0;
+    let mut hunks = Vec::new();
+    for group in diff.grouped_ops(options.context_lines) {
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            continue;
+        };
+        let old_range = first.old_range().start..last.old_range().end;
+        let new_range = first.new_range().start..last.new_range().end;
+
+        let mut lines = Vec::new();
+        for op in &group {
+            for change in diff.iter_changes(op) {
+                let kind = match change.tag() {
+                    ChangeTag::Equal => DiffLineKind::Context,
+                    ChangeTag::Delete => {
+                        deletions += 1;
+                        DiffLineKind::Delete
+                    }
+                    ChangeTag::Insert => {
+                        insertions += 1;
+                        DiffLineKind::Insert
+                    }
+                };
+                lines.push(DiffLine {
+                    kind,
+                    old_line: change.old_index().map(|index| index + 1),
+                    new_line: change.new_index().map(|index| index + 1),
+                    content: change.value().trim_end_matches(['\r', '\n']).to_string(),
+                });
+            }
+        }
+
+        hunks.push(DiffHunk {
+            old_start: old_range.start + 1,
+            old_lines: old_range.len(),
+            new_start: new_range.start + 1,
+            new_lines: new_range.len(),
+            lines,
+        });
+    }
+
+    let unified = diff
+        .unified_diff()
+        .context_radius(options.context_lines)
+        .header(old_name, new_name)
+        .to_string();
+
+    FileDiff {
+        old_name: old_name.to_string(),
+        new_name: new_name.to_string(),
+        identical: hunks.is_empty(),
+        binary: false,
+        insertions,
+        deletions,
+        hunks,
+        unified,
+    }
+}
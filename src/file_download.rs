@@ -0,0 +1,209 @@
+use crate::file_system_entry::get_mime;
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, warn};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Take};
+use std::time::UNIX_EPOCH;
+
+/// A file prepared for download, containing everything an HTTP handler needs to build the response.
+///
+/// The body is positioned and limited to the requested byte range, so it can be streamed
+/// straight into the response without loading the file into memory.
+#[derive(Debug)]
+pub struct FileDownload {
+    /// The HTTP status code: `200` for the full file, `206` for a range, `416` for an unsatisfiable range.
+    pub status: u16,
+    /// The value of the `Content-Type` header.
+    pub content_type: String,
+    /// The value of the `Content-Length` header, the number of bytes in `body`.
+    pub content_length: u64,
+    /// The value of the `Content-Range` header, set for `206` and `416` responses.
+    pub content_range: Option<String>,
+    /// The value of the `Content-Disposition` header.
+    pub content_disposition: String,
+    /// The value of the `ETag` header, derived from the file's size and modification time.
+    pub etag: String,
+    /// The total size of the file.
+    pub file_size: u64,
+    /// The file contents to send.
+    pub body: Take<File>,
+}
+
+/// An inclusive byte range within a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// The number of bytes covered by this range.
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Always `false`, since a range covers at least one byte.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+/// The result of interpreting a `Range` header against a file of a known size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// No usable range was requested, the whole file should be sent.
+    Full,
+    /// A single satisfiable range was requested.
+    Partial(ByteRange),
+    /// The requested range lies outside of the file.
+    Unsatisfiable,
+}
+
+impl FileDownload {
+    /// Opens a file for download, honoring an optional `Range` request header.
+    ///
+    /// Only single `bytes=` ranges are served as partial content, requests for multiple ranges
+    /// receive the full file, which is permitted by RFC 9110.
+    ///
+    /// # Arguments
+    /// * `file` - The file to download.
+    /// * `range` - The value of the `Range` request header, if present.
+    /// * `if_range` - The value of the `If-Range` request header, if present. When it does not match
+    ///   the current `ETag`, the range is ignored so a resumed download never mixes two file versions.
+    ///
+    /// # Errors
+    /// Returns an error if the path is a directory or the file cannot be opened.
+    pub fn open(file: &SandboxedPath, range: Option<&str>, if_range: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let path = file.path();
+        let mut handle = File::open(&path)?;
+        let metadata = handle.metadata()?;
+        if metadata.is_dir() {
+            return Err(format!("{} is a directory", file).into());
+        }
+
+        let file_size = metadata.len();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}\"", file_size, modified);
+
+        let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let content_type = get_mime(&path).unwrap_or_else(|| "application/octet-stream".to_string());
+        let content_disposition = content_disposition(&file_name);
+
+        let range_request = match (range, if_range) {
+            (Some(_), Some(if_range)) if if_range != etag => RangeRequest::Full,
+            (Some(range), _) => parse_range(range, file_size),
+            (None, _) => RangeRequest::Full,
+        };
+        debug!("Preparing download of {:?} with range {:?}", path, range_request);
+
+        let (status, content_range, start, length) = match range_request {
+            RangeRequest::Full => (200, None, 0, file_size),
+            RangeRequest::Partial(range) => (
+                206,
+                Some(format!("bytes {}-{}/{}", range.start, range.end, file_size)),
+                range.start,
+                range.len(),
+            ),
+            RangeRequest::Unsatisfiable => {
+                warn!("Unsatisfiable range {:?} requested for {:?}", range, path);
+                (416, Some(format!("bytes */{}", file_size)), 0, 0)
+            }
+        };
+
+        handle.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            status,
+            content_type,
+            content_length: length,
+            content_range,
+            content_disposition,
+            etag,
+            file_size,
+            body: handle.take(length),
+        })
+    }
+
+    /// Returns the response headers as name/value pairs.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("Content-Type", self.content_type.clone()),
+            ("Content-Length", self.content_length.to_string()),
+            ("Content-Disposition", self.content_disposition.clone()),
+            ("Accept-Ranges", "bytes".to_string()),
+            ("ETag", self.etag.clone()),
+        ];
+        if let Some(content_range) = &self.content_range {
+            headers.push(("Content-Range", content_range.clone()));
+        }
+        headers
+    }
+}
+
+/// Interprets the value of a `Range` header for a file of the given size.
+///
+/// Supports `bytes=start-end`, `bytes=start-` and suffix ranges (`bytes=-length`).
+pub fn parse_range(header: &str, file_size: u64) -> RangeRequest {
+    let Some(ranges) = header.trim().strip_prefix("bytes=") else {
+        return RangeRequest::Full;
+    };
+    if ranges.contains(',') {
+        return RangeRequest::Full;
+    }
+    let Some((start, end)) = ranges.trim().split_once('-') else {
+        return RangeRequest::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range: the last `end` bytes of the file
+        match end.parse::<u64>() {
+            Ok(0) => return RangeRequest::Unsatisfiable,
+            Ok(suffix) if file_size > 0 => ByteRange {
+                start: file_size.saturating_sub(suffix),
+                end: file_size - 1,
+            },
+            Ok(_) => return RangeRequest::Unsatisfiable,
+            Err(_) => return RangeRequest::Full,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return RangeRequest::Full;
+        };
+        if start >= file_size {
+            return RangeRequest::Unsatisfiable;
+        }
+        let end = if end.is_empty() {
+            file_size - 1
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end.min(file_size - 1),
+                Ok(_) => return RangeRequest::Full,
+                Err(_) => return RangeRequest::Full,
+            }
+        };
+        ByteRange { start, end }
+    };
+    RangeRequest::Partial(range)
+}
+
+/// Builds an `attachment` content disposition with both an ASCII fallback and an RFC 5987 encoded file name.
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = file_name
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
@@ -0,0 +1,179 @@
+use crate::file_system_entry::{is_managed_path, FileSystemEntry};
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use md5::Md5;
+use serde_derive::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The size of the buffer files are streamed through while hashing.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The hash algorithms supported for files.
+///
+/// Mojang and Modrinth publish SHA-1 checksums and CurseForge publishes MD5 hashes, while SHA-256
+/// is the usual choice for everything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha1,
+    #[default]
+    Sha256,
+    Md5,
+}
+
+/// A set of files with identical contents.
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    /// The hex encoded hash shared by all files in the group.
+    pub hash: String,
+    /// The size of each file in the group.
+    pub size: u64,
+    /// The paths of the identical files.
+    pub paths: Vec<PathBuf>,
+}
+
+impl DuplicateGroup {
+    /// The number of bytes that would be freed by keeping only one of the files.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64).saturating_sub(1)
+    }
+}
+
+/// Computes the hex encoded hash of a file, reading it in small chunks so large backups
+/// or server jars are never loaded into memory.
+///
+/// # Errors
+/// Returns an error if the file cannot be opened or read.
+pub fn hash_file(file: &SandboxedPath, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>> {
+    hash_path(&file.path(), algorithm)
+}
+
+/// Checks whether a file matches an expected hex encoded hash. The comparison ignores case.
+///
+/// # Errors
+/// Returns an error if the file cannot be read.
+pub fn verify_file(file: &SandboxedPath, algorithm: HashAlgorithm, expected: &str) -> Result<bool, Box<dyn Error>> {
+    let actual = hash_file(file, algorithm)?;
+    let matches = actual.eq_ignore_ascii_case(expected.trim());
+    if !matches {
+        warn!("{:?} hash mismatch for {}: expected {}, got {}", algorithm, file, expected, actual);
+    }
+    Ok(matches)
+}
+
+/// Hashes a reader until it is exhausted.
+pub(crate) fn hash_reader(mut reader: impl Read, algorithm: HashAlgorithm) -> std::io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha1 => digest_reader::<Sha1>(&mut reader),
+        HashAlgorithm::Sha256 => digest_reader::<Sha256>(&mut reader),
+        HashAlgorithm::Md5 => digest_reader::<Md5>(&mut reader),
+    }
+}
+
+fn hash_path(path: &Path, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>> {
+    debug!("Calculating {:?} hash of {:?}", algorithm, path);
+    Ok(hash_reader(File::open(path)?, algorithm)?)
+}
+
+fn digest_reader<D: Digest>(reader: &mut impl Read) -> std::io::Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl FileSystemEntry {
+    /// Computes the hash of the file this entry points to.
+    ///
+    /// This only works on entries whose `path` is still absolute, such as the ones produced by
+    /// `FileSystemEntries::list`, and not on entries relativized for the API.
+    ///
+    /// # Errors
+    /// Returns an error if the entry is a directory or cannot be read.
+    pub fn hash(&self, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>> {
+        if self.is_dir {
+            return Err(format!("{:?} is a directory", self.path).into());
+        }
+        hash_path(&self.path, algorithm)
+    }
+}
+
+/// Finds files with identical contents in one or more directories.
+///
+/// Files are first grouped by size, and only files sharing a size with another file are hashed,
+/// so a directory full of unique files costs little more than a directory listing. Empty files,
+/// symbolic links, the server's trash and stored file versions are ignored.
+///
+/// # Arguments
+/// * `directories` - The directories to scan recursively, for example the `mods` folders of several servers.
+/// * `algorithm` - The hash used to compare files of the same size.
+///
+/// # Returns
+/// The groups of duplicates, the ones wasting the most space first.
+pub fn find_duplicates(
+    directories: &[SandboxedPath],
+    algorithm: HashAlgorithm,
+) -> Result<Vec<DuplicateGroup>, Box<dyn Error>> {
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for directory in directories {
+        let root = directory.root_path();
+        let walker = walkdir::WalkDir::new(directory.path()).follow_links(false);
+        for entry in walker.into_iter().filter_entry(|entry| !is_managed_path(root, entry.path())).flatten() {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(metadata) if metadata.len() > 0 => {
+                    by_size.entry(metadata.len()).or_default().push(entry.into_path());
+                }
+                Ok(_) => {}
+                Err(err) => warn!("Failed to read metadata during duplicate search: {}", err),
+            }
+        }
+    }
+
+    let mut groups = Vec::new();
+    for (size, mut paths) in by_size.into_iter().filter(|(_, paths)| paths.len() > 1) {
+        // The same directory may have been passed twice or nested in another one
+        paths.sort();
+        paths.dedup();
+
+        let mut by_hash: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for path in paths {
+            match hash_path(&path, algorithm) {
+                Ok(hash) => by_hash.entry(hash).or_default().push(path),
+                Err(err) => warn!("Failed to hash {:?} during duplicate search: {}", path, err),
+            }
+        }
+        groups.extend(
+            by_hash
+                .into_iter()
+                .filter(|(_, paths)| paths.len() > 1)
+                .map(|(hash, paths)| DuplicateGroup { hash, size, paths }),
+        );
+    }
+
+    groups.sort_by(|a, b| b.wasted_bytes().cmp(&a.wasted_bytes()).then_with(|| a.hash.cmp(&b.hash)));
+    info!(
+        "Found {} groups of duplicate files wasting {} bytes",
+        groups.len(),
+        groups.iter().map(DuplicateGroup::wasted_bytes).sum::<u64>()
+    );
+    Ok(groups)
+}
@@ -0,0 +1,398 @@
+use crate::file_system_entry::invalidate_directory_size_cache;
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Determines what happens when the target of an operation already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    /// Abort the operation with an error.
+    #[default]
+    Fail,
+    /// Leave the existing target alone and skip the operation.
+    Skip,
+    /// Remove the existing target and replace it.
+    Overwrite,
+    /// Use the next free name next to the target, such as `name (1).ext`.
+    Rename,
+}
+
+/// Options shared by all file operations.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileOperationOptions {
+    /// When set, the operation is only planned and reported, nothing is changed on disk.
+    pub dry_run: bool,
+    /// What to do when the target already exists.
+    pub conflict: ConflictStrategy,
+}
+
+/// A single step performed (or planned, in a dry run) by a file operation.
+///
+/// All paths are relative to the server's root directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum FileAction {
+    CreateDirectory { path: PathBuf },
+    CopyFile { from: PathBuf, to: PathBuf, size: u64 },
+    Move { from: PathBuf, to: PathBuf },
+    Delete { path: PathBuf },
+    Skip { path: PathBuf, reason: String },
+}
+
+/// A snapshot of the progress of a file operation, passed to the progress callback after every step.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileOperationProgress {
+    /// The path relative to the server's root directory that was just processed.
+    pub current: PathBuf,
+    /// The number of steps completed so far.
+    pub items_processed: u64,
+    /// The total number of steps in the operation.
+    pub total_items: u64,
+    /// The number of bytes copied so far.
+    pub bytes_processed: u64,
+    /// The total number of bytes that will be copied.
+    pub total_bytes: u64,
+}
+
+/// The outcome of a file operation.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileOperationReport {
+    /// Whether this report describes a dry run.
+    pub dry_run: bool,
+    /// The steps that were performed, or would be performed in a dry run.
+    pub actions: Vec<FileAction>,
+    /// The number of bytes copied.
+    pub bytes: u64,
+}
+
+/// File manipulation on top of sandboxed paths: copy, move, rename and delete.
+///
+/// Every operation is planned first, which gives accurate totals for progress reporting and
+/// allows a dry run to report the exact steps without touching anything.
+#[derive(Debug, Clone, Default)]
+pub struct FileOps {
+    options: FileOperationOptions,
+}
+
+/// A planned step with absolute paths.
+enum Step {
+    CreateDirectory { path: PathBuf },
+    CopyFile { from: PathBuf, to: PathBuf, size: u64 },
+    Move { from: PathBuf, to: PathBuf },
+    RemoveFile { path: PathBuf },
+    RemoveDirectory { path: PathBuf },
+}
+
+impl FileOps {
+    pub fn new(options: FileOperationOptions) -> Self {
+        Self { options }
+    }
+
+    /// Copies a file or directory (recursively) to a new location.
+    ///
+    /// # Arguments
+    /// * `source` - The file or directory to copy.
+    /// * `destination` - The full path of the copy, not the directory to copy into.
+    /// * `on_progress` - Invoked after every copied file or created directory.
+    ///
+    /// # Errors
+    /// Returns an error if the source does not exist, the destination is inside the source, the
+    /// destination exists and the conflict strategy is `Fail`, or copying fails.
+    pub fn copy(
+        &self,
+        source: &SandboxedPath,
+        destination: &SandboxedPath,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        ensure_not_nested(source, destination)?;
+        let Some((destination, mut steps)) = self.resolve_conflict(destination)? else {
+            return Ok(self.skipped(destination));
+        };
+        plan_copy(&source.path(), &destination.path(), &mut steps)?;
+        info!("Copying {:?} to {:?}", source.path(), destination.path());
+        self.execute(source.root_path(), steps, on_progress)
+    }
+
+    /// Moves a file or directory to a new location.
+    ///
+    /// Moves within the same file system are a single rename. Moves across file systems fall
+    /// back to copying and deleting the source.
+    ///
+    /// # Arguments
+    /// * `source` - The file or directory to move.
+    /// * `destination` - The full new path, not the directory to move into.
+    /// * `on_progress` - Invoked after every step.
+    ///
+    /// # Errors
+    /// Returns an error if the source does not exist, the destination is inside the source, the
+    /// destination exists and the conflict strategy is `Fail`, or moving fails.
+    pub fn move_to(
+        &self,
+        source: &SandboxedPath,
+        destination: &SandboxedPath,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        ensure_not_nested(source, destination)?;
+        fs::symlink_metadata(source.path())?;
+        let Some((destination, mut steps)) = self.resolve_conflict(destination)? else {
+            return Ok(self.skipped(destination));
+        };
+        steps.push(Step::Move {
+            from: source.path(),
+            to: destination.path(),
+        });
+        info!("Moving {:?} to {:?}", source.path(), destination.path());
+        self.execute(source.root_path(), steps, on_progress)
+    }
+
+    /// Renames a file or directory within its current directory.
+    ///
+    /// # Arguments
+    /// * `path` - The file or directory to rename.
+    /// * `new_name` - The new file name. It may not contain path separators.
+    ///
+    /// # Errors
+    /// Returns an error if the new name is not a plain file name, or the move fails.
+    pub fn rename(&self, path: &SandboxedPath, new_name: &str) -> Result<FileOperationReport, Box<dyn Error>> {
+        let mut components = Path::new(new_name).components();
+        let is_plain_name = matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        );
+        if !is_plain_name {
+            return Err(format!("{:?} is not a valid file name", new_name).into());
+        }
+
+        let parent = path.parent().ok_or("The server directory itself cannot be renamed")?;
+        self.move_to(path, &parent.join(new_name)?, |_| {})
+    }
+
+    /// Deletes a file or directory (recursively).
+    ///
+    /// # Arguments
+    /// * `path` - The file or directory to delete.
+    /// * `on_progress` - Invoked after every removed file or directory.
+    ///
+    /// # Errors
+    /// Returns an error if the path is the server directory itself, or removal fails.
+    pub fn delete(
+        &self,
+        path: &SandboxedPath,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        if path.is_root() {
+            return Err("The server directory itself cannot be deleted".into());
+        }
+        let mut steps = Vec::new();
+        plan_delete(&path.path(), &mut steps)?;
+        info!("Deleting {:?}", path.path());
+        self.execute(path.root_path(), steps, on_progress)
+    }
+
+    /// Applies the conflict strategy to a destination.
+    ///
+    /// Returns the (possibly renamed) destination along with any steps needed to clear it,
+    /// or `None` if the operation should be skipped.
+    fn resolve_conflict(
+        &self,
+        destination: &SandboxedPath,
+    ) -> Result<Option<(SandboxedPath, Vec<Step>)>, Box<dyn Error>> {
+        let mut steps = Vec::new();
+        if fs::symlink_metadata(destination.path()).is_err() {
+            return Ok(Some((destination.clone(), steps)));
+        }
+
+        match self.options.conflict {
+            ConflictStrategy::Fail => Err(format!("{} already exists", destination).into()),
+            ConflictStrategy::Skip => Ok(None),
+            ConflictStrategy::Overwrite => {
+                plan_delete(&destination.path(), &mut steps)?;
+                Ok(Some((destination.clone(), steps)))
+            }
+            ConflictStrategy::Rename => Ok(Some((destination.next_available()?, steps))),
+        }
+    }
+
+    fn skipped(&self, destination: &SandboxedPath) -> FileOperationReport {
+        debug!("Skipping operation, {:?} already exists", destination.path());
+        FileOperationReport {
+            dry_run: self.options.dry_run,
+            actions: vec![FileAction::Skip {
+                path: destination.relative_path().to_path_buf(),
+                reason: "already exists".to_string(),
+            }],
+            bytes: 0,
+        }
+    }
+
+    /// Runs (or, in a dry run, only reports) the planned steps.
+    fn execute(
+        &self,
+        root: &Path,
+        steps: Vec<Step>,
+        mut on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        let relative = |path: &Path| path.strip_prefix(root).map(Path::to_path_buf).unwrap_or_default();
+        let mut progress = FileOperationProgress {
+            current: PathBuf::new(),
+            items_processed: 0,
+            total_items: steps.len() as u64,
+            bytes_processed: 0,
+            total_bytes: steps
+                .iter()
+                .map(|step| match step {
+                    Step::CopyFile { size, .. } => *size,
+                    _ => 0,
+                })
+                .sum(),
+        };
+        let mut report = FileOperationReport {
+            dry_run: self.options.dry_run,
+            actions: Vec::with_capacity(steps.len()),
+            bytes: 0,
+        };
+
+        for step in steps {
+            let (action, current) = match step {
+                Step::CreateDirectory { path } => {
+                    if !self.options.dry_run {
+                        fs::create_dir_all(&path)?;
+                    }
+                    (FileAction::CreateDirectory { path: relative(&path) }, path)
+                }
+                Step::CopyFile { from, to, size } => {
+                    if !self.options.dry_run {
+                        if let Some(parent) = to.parent() {
+                            fs::create_dir_all(parent)?;
+                        }
+                        fs::copy(&from, &to)?;
+                    }
+                    progress.bytes_processed += size;
+                    report.bytes += size;
+                    (
+                        FileAction::CopyFile {
+                            from: relative(&from),
+                            to: relative(&to),
+                            size,
+                        },
+                        to,
+                    )
+                }
+                Step::Move { from, to } => {
+                    if !self.options.dry_run {
+                        move_path(&from, &to)?;
+                    }
+                    (
+                        FileAction::Move {
+                            from: relative(&from),
+                            to: relative(&to),
+                        },
+                        to,
+                    )
+                }
+                Step::RemoveFile { path } => {
+                    if !self.options.dry_run {
+                        fs::remove_file(&path)?;
+                    }
+                    (FileAction::Delete { path: relative(&path) }, path)
+                }
+                Step::RemoveDirectory { path } => {
+                    if !self.options.dry_run {
+                        fs::remove_dir(&path)?;
+                    }
+                    (FileAction::Delete { path: relative(&path) }, path)
+                }
+            };
+
+            if !self.options.dry_run {
+                if let Some(parent) = current.parent() {
+                    invalidate_directory_size_cache(parent);
+                }
+            }
+            progress.current = relative(&current);
+            progress.items_processed += 1;
+            on_progress(&progress);
+            report.actions.push(action);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Rejects operations whose destination lies inside the source, such as copying `world` into `world/backup`.
+fn ensure_not_nested(source: &SandboxedPath, destination: &SandboxedPath) -> Result<(), Box<dyn Error>> {
+    if source.is_root() || destination.relative_path().starts_with(source.relative_path()) {
+        return Err(format!("Cannot copy or move {} into itself", source).into());
+    }
+    Ok(())
+}
+
+/// Plans a recursive copy. Symbolic links are skipped rather than followed.
+fn plan_copy(from: &Path, to: &Path, steps: &mut Vec<Step>) -> Result<(), Box<dyn Error>> {
+    let metadata = fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        steps.push(Step::CreateDirectory { path: to.to_path_buf() });
+        for entry in fs::read_dir(from)?.flatten() {
+            plan_copy(&entry.path(), &to.join(entry.file_name()), steps)?;
+        }
+    } else if metadata.is_file() {
+        steps.push(Step::CopyFile {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            size: metadata.len(),
+        });
+    } else {
+        warn!("Skipping {:?} while copying, it is not a regular file or directory", from);
+    }
+    Ok(())
+}
+
+/// Plans a recursive delete, children before their parent directory.
+fn plan_delete(path: &Path, steps: &mut Vec<Step>) -> Result<(), Box<dyn Error>> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)?.flatten() {
+            plan_delete(&entry.path(), steps)?;
+        }
+        steps.push(Step::RemoveDirectory { path: path.to_path_buf() });
+    } else {
+        // Symbolic links are removed themselves, never their targets
+        steps.push(Step::RemoveFile { path: path.to_path_buf() });
+    }
+    Ok(())
+}
+
+/// Moves a path, falling back to copy and delete when a rename across file systems is not possible.
+pub(crate) fn move_path(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            debug!("Falling back to copy and delete to move {:?} to {:?}", from, to);
+            let mut steps = Vec::new();
+            plan_copy(from, to, &mut steps)?;
+            plan_delete(from, &mut steps)?;
+            for step in steps {
+                match step {
+                    Step::CreateDirectory { path } => fs::create_dir_all(path)?,
+                    Step::CopyFile { from, to, .. } => {
+                        fs::copy(from, to)?;
+                    }
+                    Step::RemoveFile { path } => fs::remove_file(path)?,
+                    Step::RemoveDirectory { path } => fs::remove_dir(path)?,
+                    Step::Move { from, to } => fs::rename(from, to)?,
+                }
+            }
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
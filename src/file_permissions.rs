@@ -0,0 +1,338 @@
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// The ownership and permission metadata of a file, as reported in `FileSystemEntry`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EntryPermissions {
+    /// Whether the file is read-only. On Unix this means nobody has write permission.
+    pub readonly: bool,
+    /// The Unix permission bits, such as `0o755`.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// The name of the owning user, if it can be resolved.
+    pub owner: Option<String>,
+    /// The name of the owning group, if it can be resolved.
+    pub group: Option<String>,
+}
+
+impl EntryPermissions {
+    /// Reads the permission metadata from the metadata of a file.
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        let mut permissions = Self {
+            readonly: metadata.permissions().readonly(),
+            ..Default::default()
+        };
+        read_unix_ownership(metadata, &mut permissions);
+        permissions
+    }
+
+    /// Formats the mode like `ls -l` does, for example `rwxr-xr-x`.
+    pub fn symbolic_mode(&self) -> Option<String> {
+        self.mode.map(|mode| {
+            (0..9)
+                .map(|bit| {
+                    let set = mode & (0o400 >> bit) != 0;
+                    match (set, bit % 3) {
+                        (false, _) => '-',
+                        (true, 0) => 'r',
+                        (true, 1) => 'w',
+                        (true, _) => 'x',
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+#[cfg(unix)]
+fn read_unix_ownership(metadata: &fs::Metadata, permissions: &mut EntryPermissions) {
+    use nix::unistd::{Gid, Group, Uid, User};
+    use std::os::unix::fs::MetadataExt;
+
+    permissions.mode = Some(metadata.mode() & 0o7777);
+    permissions.uid = Some(metadata.uid());
+    permissions.gid = Some(metadata.gid());
+    permissions.owner = User::from_uid(Uid::from_raw(metadata.uid())).ok().flatten().map(|user| user.name);
+    permissions.group = Group::from_gid(Gid::from_raw(metadata.gid())).ok().flatten().map(|group| group.name);
+}
+
+#[cfg(not(unix))]
+fn read_unix_ownership(_metadata: &fs::Metadata, _permissions: &mut EntryPermissions) {}
+
+/// A change to the permission bits of a file, parsed from the notation `chmod` accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModeChange {
+    /// Sets the permission bits to an absolute value, for example `755`.
+    Absolute(u32),
+    /// Adds, removes or sets bits for some of the user classes, for example `u+x,go-w`.
+    Symbolic(Vec<SymbolicClause>),
+}
+
+/// A single clause of a symbolic mode such as `go-w`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolicClause {
+    /// The mask of the user classes (`u`, `g`, `o`) the clause applies to.
+    who: u32,
+    operator: char,
+    /// The permission bits named in the clause, for all user classes.
+    permissions: u32,
+}
+
+impl ModeChange {
+    /// Parses an octal (`644`, `0755`) or symbolic (`+x`, `u=rw,go=r`, `u+x-w`) mode.
+    ///
+    /// # Errors
+    /// Returns an error if the mode is not valid.
+    pub fn parse(mode: &str) -> Result<Self, Box<dyn Error>> {
+        let mode = mode.trim();
+        if !mode.is_empty() && mode.chars().all(|c| c.is_ascii_digit()) {
+            let bits = u32::from_str_radix(mode, 8).map_err(|_| format!("Invalid octal mode: {}", mode))?;
+            if bits > 0o777 {
+                return Err(format!("Mode {} is out of range, only permission bits up to 777 can be set", mode).into());
+            }
+            return Ok(Self::Absolute(bits));
+        }
+
+        let mut clauses = Vec::new();
+        for clause in mode.split(',') {
+            let operator_index = clause
+                .find(['+', '-', '='])
+                .ok_or_else(|| format!("Invalid symbolic mode: {:?}", clause))?;
+            let (who, actions) = clause.split_at(operator_index);
+
+            let mut who_mask = 0;
+            for c in who.chars() {
+                who_mask |= match c {
+                    'u' => 0o700,
+                    'g' => 0o070,
+                    'o' => 0o007,
+                    'a' => 0o777,
+                    _ => return Err(format!("Invalid user class {:?} in mode {:?}", c, clause).into()),
+                };
+            }
+            if who_mask == 0 {
+                who_mask = 0o777;
+            }
+
+            // A clause can chain several operators for the same classes, like `u+x-w`
+            for c in actions.chars() {
+                let permissions = match c {
+                    '+' | '-' | '=' => {
+                        clauses.push(SymbolicClause {
+                            who: who_mask,
+                            operator: c,
+                            permissions: 0,
+                        });
+                        continue;
+                    }
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    _ => return Err(format!("Invalid permission {:?} in mode {:?}", c, clause).into()),
+                };
+                if let Some(action) = clauses.last_mut() {
+                    action.permissions |= permissions;
+                }
+            }
+        }
+        Ok(Self::Symbolic(clauses))
+    }
+
+    /// Applies the change to the current permission bits of a file.
+    pub fn apply(&self, current: u32) -> u32 {
+        match self {
+            Self::Absolute(bits) => *bits,
+            Self::Symbolic(clauses) => clauses.iter().fold(current & 0o777, |mode, clause| {
+                let bits = clause.permissions & clause.who;
+                match clause.operator {
+                    '+' => mode | bits,
+                    '-' => mode & !bits,
+                    _ => (mode & !clause.who) | bits,
+                }
+            }),
+        }
+    }
+}
+
+/// Changes the permission bits of a file or directory, like `chmod`.
+///
+/// Setuid, setgid and sticky bits can not be set. When recursing, symbolic links are skipped
+/// so the change cannot reach outside of the server directory.
+///
+/// # Arguments
+/// * `path` - The file or directory to change.
+/// * `mode` - The change to apply, see [`ModeChange::parse`].
+/// * `recursive` - Whether to apply the change to everything inside a directory as well.
+///
+/// # Returns
+/// The number of changed files and directories.
+///
+/// # Errors
+/// Returns an error on platforms without Unix permissions, or if a change fails.
+pub fn change_mode(path: &SandboxedPath, mode: &ModeChange, recursive: bool) -> Result<u64, Box<dyn Error>> {
+    let mut changed = 0;
+    for target in targets(path, recursive)? {
+        set_mode(&target, mode)?;
+        changed += 1;
+    }
+    info!("Changed the mode of {} entries below {}", changed, path);
+    Ok(changed)
+}
+
+/// Changes the owning user and/or group of a file or directory, like `chown`.
+///
+/// # Arguments
+/// * `path` - The file or directory to change.
+/// * `owner` - The new owner as a user name or numeric id, or `None` to keep it.
+/// * `group` - The new group as a group name or numeric id, or `None` to keep it.
+/// * `recursive` - Whether to change everything inside a directory as well. Symbolic links are skipped.
+///
+/// # Returns
+/// The number of changed files and directories.
+///
+/// # Errors
+/// Returns an error on platforms without Unix ownership, if a user or group does not exist,
+/// or if the process is not allowed to change ownership.
+pub fn change_owner(
+    path: &SandboxedPath,
+    owner: Option<&str>,
+    group: Option<&str>,
+    recursive: bool,
+) -> Result<u64, Box<dyn Error>> {
+    let (uid, gid) = resolve_owner(owner, group)?;
+    let mut changed = 0;
+    for target in targets(path, recursive)? {
+        set_owner(&target, uid, gid)?;
+        changed += 1;
+    }
+    info!("Changed the owner of {} entries below {}", changed, path);
+    Ok(changed)
+}
+
+/// Marks a file as read-only or writable. This works on every platform.
+///
+/// # Errors
+/// Returns an error if the permissions cannot be changed.
+pub fn set_readonly(path: &SandboxedPath, readonly: bool) -> Result<(), Box<dyn Error>> {
+    let path = path.path();
+    let mut permissions = fs::metadata(&path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(readonly);
+    fs::set_permissions(&path, permissions)?;
+    debug!("Set read-only of {:?} to {}", path, readonly);
+    Ok(())
+}
+
+/// Collects the paths a permission change applies to.
+fn targets(path: &SandboxedPath, recursive: bool) -> Result<Vec<std::path::PathBuf>, Box<dyn Error>> {
+    let root = path.path();
+    if !recursive || !root.is_dir() {
+        return Ok(vec![root]);
+    }
+    let mut targets = Vec::new();
+    for entry in walkdir::WalkDir::new(&root).follow_links(false) {
+        let entry = entry?;
+        if entry.file_type().is_symlink() {
+            debug!("Skipping symbolic link {:?}", entry.path());
+            continue;
+        }
+        targets.push(entry.into_path());
+    }
+    Ok(targets)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: &ModeChange) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    let current = fs::metadata(path)?.permissions().mode();
+    let new_mode = mode.apply(current);
+    // Keep the file type bits out of it and never set setuid/setgid/sticky
+    fs::set_permissions(path, fs::Permissions::from_mode(new_mode & 0o777))?;
+    debug!("Changed mode of {:?} from {:o} to {:o}", path, current & 0o777, new_mode & 0o777);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: &ModeChange) -> Result<(), Box<dyn Error>> {
+    Err("Unix permissions are not supported on this platform, use set_readonly instead".into())
+}
+
+#[cfg(unix)]
+fn resolve_owner(owner: Option<&str>, group: Option<&str>) -> Result<(Option<u32>, Option<u32>), Box<dyn Error>> {
+    use nix::unistd::{Group, User};
+    let uid = match owner {
+        Some(owner) => Some(match owner.parse::<u32>() {
+            Ok(uid) => uid,
+            Err(_) => User::from_name(owner)?.ok_or_else(|| format!("User {} does not exist", owner))?.uid.as_raw(),
+        }),
+        None => None,
+    };
+    let gid = match group {
+        Some(group) => Some(match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => Group::from_name(group)?.ok_or_else(|| format!("Group {} does not exist", group))?.gid.as_raw(),
+        }),
+        None => None,
+    };
+    if uid.is_none() && gid.is_none() {
+        warn!("Ownership change requested without an owner or a group");
+    }
+    Ok((uid, gid))
+}
+
+#[cfg(not(unix))]
+fn resolve_owner(_owner: Option<&str>, _group: Option<&str>) -> Result<(Option<u32>, Option<u32>), Box<dyn Error>> {
+    Err("File ownership is not supported on this platform".into())
+}
+
+#[cfg(unix)]
+fn set_owner(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<(), Box<dyn Error>> {
+    std::os::unix::fs::chown(path, uid, gid)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> Result<(), Box<dyn Error>> {
+    Err("File ownership is not supported on this platform".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_octal_modes() -> Result<(), Box<dyn Error>> {
+        assert_eq!(ModeChange::parse("755")?.apply(0o600), 0o755);
+        assert_eq!(ModeChange::parse("0644")?.apply(0o777), 0o644);
+        assert!(ModeChange::parse("1777").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn applies_symbolic_clauses() -> Result<(), Box<dyn Error>> {
+        assert_eq!(ModeChange::parse("+x")?.apply(0o644), 0o755);
+        assert_eq!(ModeChange::parse("u=rw,go=r")?.apply(0o777), 0o644);
+        assert_eq!(ModeChange::parse("go-w")?.apply(0o666), 0o644);
+        Ok(())
+    }
+
+    #[test]
+    fn chains_operators_in_one_clause() -> Result<(), Box<dyn Error>> {
+        assert_eq!(ModeChange::parse("u+x-w")?.apply(0o644), 0o544);
+        assert_eq!(ModeChange::parse("a=r+x")?.apply(0o600), 0o555);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_modes() {
+        assert!(ModeChange::parse("").is_err());
+        assert!(ModeChange::parse("rw").is_err());
+        assert!(ModeChange::parse("q+x").is_err());
+        assert!(ModeChange::parse("u+z").is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::file_system_entry::{is_managed_path, FileSystemEntry};
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use regex::{Regex, RegexBuilder};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// How file names are matched during a search.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "pattern", rename_all = "lowercase")]
+pub enum NamePattern {
+    /// A shell style glob such as `*.yml` or `plugins/**/config.yml`.
+    ///
+    /// Patterns without a `/` are matched against the file name only, patterns containing
+    /// a `/` are matched against the path relative to the searched directory.
+    Glob(String),
+    /// A regular expression matched against the file name.
+    Regex(String),
+}
+
+/// Options controlling a file search.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    /// The pattern file names have to match, or `None` to consider every file.
+    pub name: Option<NamePattern>,
+    /// A regular expression that has to match at least one line of the file's contents.
+    /// When set, only text files are considered.
+    pub content: Option<String>,
+    /// Whether the name and content patterns are case sensitive.
+    pub case_sensitive: bool,
+    /// Whether directories can be returned as name matches.
+    pub include_directories: bool,
+    /// The number of lines to include before and after every content match.
+    pub context_lines: usize,
+    /// The maximum number of files to return.
+    pub max_results: usize,
+    /// The maximum number of content matches to return per file.
+    pub max_matches_per_file: usize,
+    /// Files larger than this are never searched for content.
+    pub max_file_size: u64,
+    /// How deep to descend into subdirectories, or `None` for no limit.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            name: None,
+            content: None,
+            case_sensitive: false,
+            include_directories: false,
+            context_lines: 2,
+            max_results: 500,
+            max_matches_per_file: 50,
+            max_file_size: 10 * 1024 * 1024,
+            max_depth: None,
+        }
+    }
+}
+
+/// A single line matching the content pattern.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentMatch {
+    /// The one based line number of the match.
+    pub line_number: usize,
+    /// The matching line, without its line ending.
+    pub line: String,
+    /// The byte offsets of every match within the line.
+    pub ranges: Vec<(usize, usize)>,
+    /// The lines directly before the match.
+    pub before: Vec<String>,
+    /// The lines directly after the match.
+    pub after: Vec<String>,
+}
+
+/// A file or directory found by a search.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub entry: FileSystemEntry,
+    /// The matching lines, empty unless a content pattern was given.
+    pub matches: Vec<ContentMatch>,
+}
+
+/// The results of a search.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    /// The number of files that were inspected.
+    pub files_scanned: u64,
+    /// Whether the search stopped early because `max_results` was reached.
+    pub truncated: bool,
+}
+
+/// The compiled form of a name pattern.
+enum NameMatcher {
+    Any,
+    FileNameGlob(glob::Pattern),
+    PathGlob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl NameMatcher {
+    fn new(pattern: &Option<NamePattern>, case_sensitive: bool) -> Result<Self, Box<dyn Error>> {
+        Ok(match pattern {
+            None => Self::Any,
+            Some(NamePattern::Glob(glob)) if glob.contains('/') => Self::PathGlob(glob::Pattern::new(glob)?),
+            Some(NamePattern::Glob(glob)) => Self::FileNameGlob(glob::Pattern::new(glob)?),
+            Some(NamePattern::Regex(regex)) => {
+                Self::Regex(RegexBuilder::new(regex).case_insensitive(!case_sensitive).build()?)
+            }
+        })
+    }
+
+    fn matches(&self, file_name: &str, relative_path: &str, case_sensitive: bool) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        match self {
+            Self::Any => true,
+            Self::FileNameGlob(pattern) => pattern.matches_with(file_name, options),
+            Self::PathGlob(pattern) => pattern.matches_with(relative_path, options),
+            Self::Regex(regex) => regex.is_match(file_name),
+        }
+    }
+}
+
+/// Recursively searches a directory for files matching a name pattern and, optionally, a content pattern.
+///
+/// Symbolic links are not followed, so the search never leaves the sandbox, and the server's
+/// trash and file versions are skipped.
+///
+/// # Arguments
+/// * `directory` - The directory to search.
+/// * `options` - The patterns and limits of the search.
+///
+/// # Errors
+/// Returns an error if one of the patterns is invalid.
+pub fn search_files(directory: &SandboxedPath, options: &SearchOptions) -> Result<SearchResults, Box<dyn Error>> {
+    let name_matcher = NameMatcher::new(&options.name, options.case_sensitive)?;
+    let content_matcher = match &options.content {
+        Some(content) => Some(RegexBuilder::new(content).case_insensitive(!options.case_sensitive).build()?),
+        None => None,
+    };
+
+    let base = directory.path();
+    let root = directory.root_path();
+    info!("Searching {:?} with {:?}", base, options);
+
+    let mut walker = walkdir::WalkDir::new(&base).follow_links(false).min_depth(1);
+    if let Some(max_depth) = options.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+
+    let mut results = SearchResults::default();
+    for entry in walker.into_iter().filter_entry(|entry| !is_managed_path(root, entry.path())) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Skipping unreadable path during search: {}", err);
+                continue;
+            }
+        };
+
+        let file_type = entry.file_type();
+        if file_type.is_symlink() || (file_type.is_dir() && (!options.include_directories || content_matcher.is_some()))
+        {
+            continue;
+        }
+        results.files_scanned += 1;
+
+        let file_name = entry.file_name().to_string_lossy();
+        let relative_path = entry
+            .path()
+            .strip_prefix(&base)
+            .unwrap_or(entry.path())
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if !name_matcher.matches(&file_name, &relative_path, options.case_sensitive) {
+            continue;
+        }
+
+        let matches = match &content_matcher {
+            Some(regex) => {
+                let matches = search_contents(entry.path(), regex, options);
+                if matches.is_empty() {
+                    continue;
+                }
+                matches
+            }
+            None => Vec::new(),
+        };
+
+        let entry = match directory.join(&relative_path) {
+            Ok(sandboxed) => FileSystemEntry::try_from(&sandboxed),
+            Err(_) => continue,
+        };
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Skipping search result: {}", err);
+                continue;
+            }
+        };
+        results.results.push(SearchResult { entry, matches });
+
+        if results.results.len() >= options.max_results {
+            debug!("Search result limit of {} reached", options.max_results);
+            results.truncated = true;
+            break;
+        }
+    }
+
+    info!(
+        "Search in {:?} found {} results in {} files",
+        base,
+        results.results.len(),
+        results.files_scanned
+    );
+    Ok(results)
+}
+
+/// Finds the lines of a text file matching a regular expression, with surrounding context.
+///
+/// Binary files and files over the size limit yield no matches.
+fn search_contents(path: &Path, regex: &Regex, options: &SearchOptions) -> Vec<ContentMatch> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() <= options.max_file_size => {}
+        Ok(_) => {
+            debug!("Skipping content search of large file {:?}", path);
+            return Vec::new();
+        }
+        Err(_) => return Vec::new(),
+    }
+
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!("Failed to read {:?} during search: {}", path, err);
+            return Vec::new();
+        }
+    };
+    // A NUL byte is a reliable sign of a binary file such as a jar or a region file
+    if contents.iter().take(8192).any(|&byte| byte == 0) {
+        return Vec::new();
+    }
+
+    let text = String::from_utf8_lossy(&contents);
+    let lines: Vec<&str> = text.lines().collect();
+    let mut matches = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let ranges: Vec<(usize, usize)> = regex.find_iter(line).map(|m| (m.start(), m.end())).collect();
+        if ranges.is_empty() {
+            continue;
+        }
+
+        let before_start = index.saturating_sub(options.context_lines);
+        let after_end = (index + 1 + options.context_lines).min(lines.len());
+        matches.push(ContentMatch {
+            line_number: index + 1,
+            line: line.to_string(),
+            ranges,
+            before: lines[before_start..index].iter().map(|line| line.to_string()).collect(),
+            after: lines[index + 1..after_end].iter().map(|line| line.to_string()).collect(),
+        });
+
+        if matches.len() >= options.max_matches_per_file {
+            break;
+        }
+    }
+    matches
+}
@@ -1,13 +1,72 @@
+use crate::file_permissions::EntryPermissions;
+use crate::file_versions::VERSIONS_DIRECTORY;
+use crate::fs_error::FsError;
+use crate::minecraft_file::{classify_minecraft_file, MinecraftCategory};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server_trash::TRASH_DIRECTORY;
+use lazy_static::lazy_static;
 use log::{debug, error, info, warn};
+use lru::LruCache;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
 use std::time::SystemTime;
 
+/// The number of files whose detected type is remembered by the type detection cache.
+const TYPE_CACHE_CAPACITY: NonZeroUsize = match NonZeroUsize::new(4096) {
+    Some(capacity) => capacity,
+    None => NonZeroUsize::MIN,
+};
+
+lazy_static! {
+    /// Cache of directory scans used by the recursive size calculation, keyed by directory path.
+    static ref DIRECTORY_SIZE_CACHE: Mutex<HashMap<PathBuf, CachedDirectoryScan>> = Mutex::new(HashMap::new());
+
+    /// Cache of detected MIME types and categories, so listing the same directory again does not reopen its files.
+    static ref TYPE_CACHE: Mutex<LruCache<TypeCacheKey, DetectedType>> =
+        Mutex::new(LruCache::new(TYPE_CACHE_CAPACITY));
+}
+
+/// Identifies one version of a file. A file that is written to gets a new size or modification
+/// time, so stale detection results are never returned and nothing has to be invalidated.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TypeCacheKey {
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// The result of detecting the type of a file.
+#[derive(Debug, Clone)]
+struct DetectedType {
+    mime: Option<String>,
+    category: FileMimeCategory,
+    minecraft: Option<MinecraftCategory>,
+}
+
+/// The result of scanning a single directory level.
+///
+/// Only the direct children are recorded, so a change deep inside the tree only invalidates
+/// the directory that actually changed instead of every ancestor above it.
+#[derive(Debug, Clone)]
+struct CachedDirectoryScan {
+    /// The modification time of the directory when it was scanned.
+    modified: SystemTime,
+    /// The combined size of the regular files directly inside the directory.
+    files_size: u64,
+    /// The subdirectories directly inside the directory.
+    subdirectories: Vec<PathBuf>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileSystemEntry {
     pub name: String,
@@ -17,17 +76,119 @@ pub struct FileSystemEntry {
     pub r#type: String,
     pub mime: Option<String>,
     pub category: FileMimeCategory,
+    /// What the entry means to a Minecraft server, such as a region file or a Fabric mod.
+    pub minecraft: Option<MinecraftCategory>,
     pub created: SystemTime,
     pub last_modified: SystemTime,
+    /// Whether the entry is a dotfile, a known junk file, or carries the hidden attribute on Windows.
+    pub is_hidden: bool,
+    /// Whether the entry is a symbolic link.
+    pub is_symlink: bool,
+    /// The path the symbolic link points to, as stored in the link.
+    pub link_target: Option<PathBuf>,
+    /// Whether the symbolic link points to a path that does not exist.
+    pub link_broken: bool,
+    /// The permission bits and ownership on Unix, and the read-only attribute everywhere.
+    pub permissions: EntryPermissions,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileSystemEntries {
     pub parent: Option<PathBuf>,
     pub entries: Vec<FileSystemEntry>,
+    /// The number of entries in the directory before pagination was applied.
+    pub total: usize,
+    /// The index of the first entry in `entries` within the full, sorted listing.
+    pub offset: usize,
+    /// The entries that exist but could not be read, for example because of their permissions.
+    #[serde(default)]
+    pub errors: Vec<FsError>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The attribute used to order the entries of a directory listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    /// Sorts by file name, ignoring case.
+    #[default]
+    Name,
+    /// Sorts by size in bytes.
+    Size,
+    /// Sorts by last modification time.
+    Modified,
+    /// Sorts by the human-readable file type, then by name.
+    Type,
+}
+
+/// The direction in which a directory listing is sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Options controlling how a directory listing is sorted and paginated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListingOptions {
+    /// The number of entries to skip from the start of the sorted listing.
+    pub offset: usize,
+    /// The maximum number of entries to return, or `None` to return everything after `offset`.
+    pub limit: Option<usize>,
+    /// The attribute to sort by.
+    pub sort_by: SortKey,
+    /// The direction to sort in.
+    pub direction: SortDirection,
+    /// Whether directories are always listed before files, regardless of the sort key.
+    pub directories_first: bool,
+    /// Whether hidden entries (see [`is_hidden_entry`]) are included in the listing.
+    pub show_hidden: bool,
+    /// Whether symbolic links are resolved, so a linked world folder is listed with the size and
+    /// type of its target. Links are never resolved when their target lies outside of the server
+    /// directory, and broken links are always listed as links.
+    pub follow_symlinks: bool,
+}
+
+impl Default for ListingOptions {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            limit: None,
+            sort_by: SortKey::Name,
+            direction: SortDirection::Ascending,
+            directories_first: true,
+            show_hidden: false,
+            follow_symlinks: true,
+        }
+    }
+}
+
+/// The cheap-to-obtain attributes of a directory entry, used for sorting before the
+/// full `FileSystemEntry` (including MIME detection) is built for the requested page.
+struct ListingCandidate {
+    file_name: OsString,
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+    r#type: String,
+    is_hidden: bool,
+}
+
+/// Files created by operating systems, file managers or a running server that are of no interest
+/// when browsing a server, and are hidden just like dotfiles.
+pub const HIDDEN_FILE_NAMES: &[&str] = &[
+    ".DS_Store",
+    "__MACOSX",
+    "Thumbs.db",
+    "ehthumbs.db",
+    "desktop.ini",
+    "session.lock",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FileMimeCategory {
     TEXT,
     IMAGE,
@@ -36,7 +197,7 @@ pub enum FileMimeCategory {
     VIDEO,
     UNKNOWN,
 }
-fn get_file_type(extension: String) -> String {
+pub(crate) fn get_file_type(extension: String) -> String {
     let types: HashMap<&str, &str> = HashMap::from([
         ("zip", "Zip Archive"),
         ("tar", "Tar Archive"),
@@ -156,7 +317,10 @@ fn get_file_type(extension: String) -> String {
         .to_string()
 }
 
-fn get_mime_category(path: impl AsRef<Path>) -> FileMimeCategory {
+/// Determines the category of a file from its extension, falling back to sniffing its contents.
+///
+/// With `sniff_content` unset, files without a known extension are reported as `UNKNOWN` instead of being opened.
+fn get_mime_category(path: impl AsRef<Path>, sniff_content: bool) -> FileMimeCategory {
     let path_ref = path.as_ref();
 
     // Debug: Check input path
@@ -194,6 +358,9 @@ fn get_mime_category(path: impl AsRef<Path>) -> FileMimeCategory {
                 FileMimeCategory::UNKNOWN
             }
         }
+    } else if !sniff_content {
+        debug!("No MIME type could be identified for path: {:?}, skipping content analysis", path_ref);
+        FileMimeCategory::UNKNOWN
     } else {
         warn!("No MIME type could be identified for path: {:?}", path_ref);
 
@@ -213,7 +380,11 @@ fn get_mime_category(path: impl AsRef<Path>) -> FileMimeCategory {
     }
 }
 
-fn is_text_file(file_path: impl AsRef<Path>) -> bool {
+/// Sniffs the start of a file to decide whether it contains text.
+///
+/// Printable ASCII, tabs and line breaks are accepted, as is non-ASCII content that forms valid UTF-8,
+/// so configs containing translated messages still count as text.
+pub(crate) fn is_text_file(file_path: impl AsRef<Path>) -> bool {
     let path = file_path.as_ref();
     const BUFFER_SIZE: usize = 1024;
 
@@ -225,11 +396,13 @@ fn is_text_file(file_path: impl AsRef<Path>) -> bool {
             match file.read(&mut buffer) {
                 Ok(bytes_read) => {
                     debug!("Read {} bytes from file: {:?}", bytes_read, path);
-                    for &byte in &buffer[..bytes_read] {
+                    let sample = &buffer[..bytes_read];
+                    for &byte in sample {
                         if !(byte == 0x09
                             || byte == 0x0A
                             || byte == 0x0D
-                            || (0x20..=0x7E).contains(&byte))
+                            || (0x20..=0x7E).contains(&byte)
+                            || byte >= 0x80)
                         {
                             debug!(
                                 "Non-text byte identified in file: {:?}. It is not a text file.",
@@ -238,6 +411,13 @@ fn is_text_file(file_path: impl AsRef<Path>) -> bool {
                             return false;
                         }
                     }
+                    // A multi-byte character may be cut off at the end of the sample, which is fine
+                    if let Err(err) = std::str::from_utf8(sample) {
+                        if err.error_len().is_some() {
+                            debug!("File contains invalid UTF-8: {:?}. It is not a text file.", path);
+                            return false;
+                        }
+                    }
                     debug!("File appears to be a text file: {:?}", path);
                     true
                 }
@@ -254,7 +434,76 @@ fn is_text_file(file_path: impl AsRef<Path>) -> bool {
     }
 }
 
-fn get_mime(path: impl AsRef<Path>) -> Option<String> {
+/// Checks whether a path lies inside one of the directories the manager keeps for its own
+/// bookkeeping, the trash and the file version store. Searches, duplicate detection and
+/// watchers skip them.
+///
+/// # Arguments
+/// * `root` - The server's root directory.
+/// * `path` - The absolute path to check.
+pub(crate) fn is_managed_path(root: &Path, path: &Path) -> bool {
+    [TRASH_DIRECTORY, VERSIONS_DIRECTORY]
+        .iter()
+        .any(|directory| path.starts_with(root.join(directory)))
+}
+
+/// Determines whether a directory entry should be hidden from listings by default.
+///
+/// Dotfiles such as `.fabric` or `.trash` and the names in [`HIDDEN_FILE_NAMES`] are hidden
+/// everywhere, on Windows entries with the hidden attribute are hidden as well.
+///
+/// # Arguments
+/// * `name` - The file name of the entry.
+/// * `metadata` - The metadata of the entry, if it could be read.
+pub fn is_hidden_entry(name: &str, metadata: Option<&fs::Metadata>) -> bool {
+    if name.starts_with('.') || HIDDEN_FILE_NAMES.iter().any(|hidden| hidden.eq_ignore_ascii_case(name)) {
+        return true;
+    }
+    has_hidden_attribute(metadata)
+}
+
+#[cfg(windows)]
+fn has_hidden_attribute(metadata: Option<&fs::Metadata>) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    metadata.is_some_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_metadata: Option<&fs::Metadata>) -> bool {
+    false
+}
+
+/// Detects the MIME type and category of a file, reusing earlier results for unchanged files.
+///
+/// Only the results of a detection with content sniffing are cached, so a fast listing never
+/// hides what a full listing would have found, while still reusing what a full listing found.
+fn detect_type(path: &Path, metadata: &fs::Metadata, sniff_content: bool) -> DetectedType {
+    let key = TypeCacheKey {
+        path: path.to_path_buf(),
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+    };
+    if let Ok(mut cache) = TYPE_CACHE.lock() {
+        if let Some(detected) = cache.get(&key) {
+            return detected.clone();
+        }
+    }
+
+    let detected = DetectedType {
+        mime: get_mime(path),
+        category: get_mime_category(path, sniff_content),
+        minecraft: classify_minecraft_file(path, metadata.is_dir(), sniff_content),
+    };
+    if sniff_content {
+        if let Ok(mut cache) = TYPE_CACHE.lock() {
+            cache.put(key, detected.clone());
+        }
+    }
+    detected
+}
+
+pub(crate) fn get_mime(path: impl AsRef<Path>) -> Option<String> {
     let path_ref = path.as_ref();
     debug!("Getting MIME type for path: {:?}", path_ref);
 
@@ -276,8 +525,14 @@ impl Default for FileSystemEntry {
             r#type: "".to_string(),
             mime: None,
             category: FileMimeCategory::TEXT,
+            minecraft: None,
             created: SystemTime::now(),
             last_modified: SystemTime::now(),
+            is_hidden: false,
+            is_symlink: false,
+            link_target: None,
+            link_broken: false,
+            permissions: EntryPermissions::default(),
         }
     }
 }
@@ -288,78 +543,445 @@ impl Default for FileSystemEntries {
         Self {
             parent: None,
             entries: Vec::new(),
+            total: 0,
+            offset: 0,
+            errors: Vec::new(),
         }
     }
 }
 
-impl From<PathBuf> for FileSystemEntry {
-    fn from(value: PathBuf) -> Self {
-        debug!(
-            "Converting PathBuf to FileSystemEntry for path: {:?}",
-            value
-        );
-        match value.metadata() {
-            Ok(metadata) => {
-                debug!("Metadata retrieved for path: {:?}", value);
-
-                Self {
-                    name: value
-                        .file_name()
-                        .unwrap_or(OsStr::new(""))
-                        .to_string_lossy()
-                        .to_string(),
-
-                    path: value.clone(),
-                    is_dir: metadata.is_dir(),
-                    size: metadata.len(),
+impl FileSystemEntry {
+    /// Replaces the reported size of a directory entry with the aggregate size of its contents.
+    ///
+    /// Directory entries report `0` by default because `metadata.len()` does not describe their
+    /// contents. Calling this walks the directory (reusing cached scans where possible) and stores
+    /// the total size of all files below it. File entries are left untouched.
+    pub fn calculate_recursive_size(&mut self) {
+        if self.is_dir {
+            self.size = calculate_directory_size(&self.path);
+        }
+    }
+}
+
+impl FileSystemEntries {
+    /// Lists a single page of a directory, sorted according to the provided options.
+    ///
+    /// Sorting only needs the entry name and metadata, so the expensive parts of building a
+    /// `FileSystemEntry` (MIME guessing and content sniffing) are limited to the entries that
+    /// end up in the requested page. This keeps listings of region folders with tens of
+    /// thousands of files responsive.
+    ///
+    /// # Arguments
+    /// * `directory` - The directory to list.
+    /// * `options` - The sort order and the page to return.
+    ///
+    /// # Returns
+    /// The requested page, with `total` set to the number of entries in the whole directory.
+    /// Hidden entries are only counted when `show_hidden` is set. Entries of the page that could
+    /// not be read are reported in `errors` instead of `entries`.
+    ///
+    /// # Errors
+    /// Returns an error if the directory itself cannot be read.
+    pub fn list(directory: &SandboxedPath, options: &ListingOptions) -> Result<Self, FsError> {
+        let path = directory.path();
+        debug!("Listing directory {:?} with options {:?}", path, options);
+
+        let directory_entries = fs::read_dir(&path).map_err(|err| {
+            error!("Failed to read directory: {:?}. Error: {:?}", path, err);
+            FsError::from_io(&path, &err)
+        })?;
+
+        let mut candidates: Vec<ListingCandidate> = directory_entries
+            .flatten()
+            .map(|entry| {
+                let entry_path = entry.path();
+                let metadata = if options.follow_symlinks {
+                    fs::metadata(&entry_path).or_else(|_| fs::symlink_metadata(&entry_path))
+                } else {
+                    fs::symlink_metadata(&entry_path)
+                }
+                .ok();
+                let name = entry.file_name().to_string_lossy().to_string();
+                ListingCandidate {
+                    is_hidden: is_hidden_entry(&name, metadata.as_ref()),
+                    name,
+                    is_dir: metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false),
+                    size: metadata.as_ref().map(|m| m.len()).unwrap_or(0),
+                    modified: metadata
+                        .as_ref()
+                        .and_then(|m| m.modified().ok())
+                        .unwrap_or(SystemTime::UNIX_EPOCH),
                     r#type: get_file_type(
-                        value
+                        entry_path
                             .extension()
                             .unwrap_or(OsStr::new(""))
                             .to_string_lossy()
                             .to_string(),
                     ),
-                    mime: get_mime(&value),
-                    category: get_mime_category(&value),
-                    created: metadata.created().unwrap_or(SystemTime::UNIX_EPOCH),
-                    last_modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    file_name: entry.file_name(),
                 }
+            })
+            .filter(|candidate| options.show_hidden || !candidate.is_hidden)
+            .collect();
+
+        candidates.sort_by(|a, b| compare_candidates(a, b, options));
+
+        let total = candidates.len();
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        for candidate in candidates.into_iter().skip(options.offset).take(options.limit.unwrap_or(usize::MAX)) {
+            match child_entry(directory, &candidate.file_name, options.follow_symlinks, true) {
+                Some(Ok(entry)) => entries.push(entry),
+                Some(Err(err)) => errors.push(err),
+                None => {}
+            }
+        }
+
+        Ok(Self {
+            parent: directory.parent().map(|p| p.path()),
+            entries,
+            total,
+            offset: options.offset,
+            errors,
+        })
+    }
+
+    /// Calculates the recursive size of every directory entry in this listing.
+    ///
+    /// This is opt-in since it may have to walk large trees such as `world/` or `backups/`.
+    pub fn calculate_directory_sizes(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.calculate_recursive_size();
+        }
+    }
+}
+
+/// Calculates the total size in bytes of all files inside a directory and its subdirectories.
+///
+/// Every directory level is cached together with its modification time, and a cached scan is
+/// reused for as long as the modification time is unchanged. Adding, removing or renaming a
+/// file changes the modification time of its parent directory, so only that level is scanned
+/// again. Files that grow in place do not touch their parent directory, use
+/// [`invalidate_directory_size_cache`] when an accurate value is required after such writes.
+///
+/// Symbolic links inside the directory are not followed, and every directory is counted at most
+/// once, so bind mounts that loop back to an ancestor cannot cause endless recursion.
+///
+/// # Arguments
+/// * `path` - The directory to measure.
+///
+/// # Returns
+/// The aggregate size of the directory, or `0` if the directory could not be read.
+pub fn calculate_directory_size(path: impl AsRef<Path>) -> u64 {
+    directory_size(path.as_ref(), &mut HashSet::new())
+}
+
+fn directory_size(path: &Path, visited: &mut HashSet<(u64, u64)>) -> u64 {
+    if let Some(id) = directory_id(path) {
+        if !visited.insert(id) {
+            warn!("Directory {:?} was already counted, skipping it to avoid a loop", path);
+            return 0;
+        }
+    }
+    match scan_directory(path) {
+        Ok(scan) => {
+            let mut size = scan.files_size;
+            for subdirectory in &scan.subdirectories {
+                size += directory_size(subdirectory, visited);
+            }
+            size
+        }
+        Err(err) => {
+            warn!("Failed to calculate directory size for {:?}: {:?}", path, err);
+            0
+        }
+    }
+}
+
+/// Identifies a directory independently of the path it was reached through.
+#[cfg(unix)]
+fn directory_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn directory_id(path: &Path) -> Option<(u64, u64)> {
+    use std::hash::{Hash, Hasher};
+    let canonical = fs::canonicalize(path).ok()?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    canonical.hash(&mut hasher);
+    Some((0, hasher.finish()))
+}
+
+/// Calculates the size of a directory on a background thread.
+///
+/// # Arguments
+/// * `path` - The directory to measure.
+/// * `on_complete` - Invoked with the aggregate size once the calculation has finished.
+///
+/// # Returns
+/// The handle of the spawned thread.
+pub fn calculate_directory_size_in_background(
+    path: impl AsRef<Path>,
+    on_complete: impl FnOnce(u64) + Send + 'static,
+) -> JoinHandle<()> {
+    let path = path.as_ref().to_path_buf();
+    thread::spawn(move || {
+        let size = calculate_directory_size(&path);
+        debug!("Background size calculation finished for {:?}: {} bytes", path, size);
+        on_complete(size);
+    })
+}
+
+/// Removes the cached scans for a directory and everything below it.
+///
+/// # Arguments
+/// * `path` - The directory whose cached sizes should be discarded.
+pub fn invalidate_directory_size_cache(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    if let Ok(mut cache) = DIRECTORY_SIZE_CACHE.lock() {
+        cache.retain(|cached_path, _| !cached_path.starts_with(path));
+    }
+}
+
+/// Builds the entry for a child of a directory, resolving it through the sandbox.
+///
+/// Symbolic links pointing outside of the server directory (for example a world on another disk)
+/// are still listed, but as unresolved links, so nothing about their target is exposed.
+///
+/// # Returns
+/// The entry or the error reading it, or `None` if the entry is not listed at all.
+pub(crate) fn child_entry(
+    directory: &SandboxedPath,
+    file_name: &OsStr,
+    follow_symlinks: bool,
+    sniff_content: bool,
+) -> Option<Result<FileSystemEntry, FsError>> {
+    match directory.join(file_name) {
+        Ok(child) => Some(FileSystemEntry::from_path_with(&child.path(), follow_symlinks, sniff_content)),
+        Err(err) => {
+            let path = directory.path().join(file_name);
+            if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                debug!("Listing {:?} as an unresolved link: {}", path, err);
+                Some(FileSystemEntry::from_path_with(&path, false, sniff_content))
+            } else {
+                warn!("Skipping directory entry {:?}: {}", file_name, err);
+                None
+            }
+        }
+    }
+}
+
+/// Orders two listing candidates according to the listing options.
+fn compare_candidates(a: &ListingCandidate, b: &ListingCandidate, options: &ListingOptions) -> Ordering {
+    // Directories stay on top regardless of the sort direction
+    if options.directories_first && a.is_dir != b.is_dir {
+        return if a.is_dir { Ordering::Less } else { Ordering::Greater };
+    }
+
+    let by_name = || a.name.to_lowercase().cmp(&b.name.to_lowercase());
+    let ordering = match options.sort_by {
+        SortKey::Name => by_name(),
+        SortKey::Size => a.size.cmp(&b.size).then_with(by_name),
+        SortKey::Modified => a.modified.cmp(&b.modified).then_with(by_name),
+        SortKey::Type => a.r#type.to_lowercase().cmp(&b.r#type.to_lowercase()).then_with(by_name),
+    };
+
+    match options.direction {
+        SortDirection::Ascending => ordering,
+        SortDirection::Descending => ordering.reverse(),
+    }
+}
+
+/// Returns the scan of a single directory level, either from the cache or from disk.
+fn scan_directory(path: &Path) -> std::io::Result<CachedDirectoryScan> {
+    let modified = fs::metadata(path)?.modified()?;
+
+    // Reuse the previous scan if the directory has not changed since.
+    if let Ok(cache) = DIRECTORY_SIZE_CACHE.lock() {
+        if let Some(scan) = cache.get(path).filter(|scan| scan.modified == modified) {
+            return Ok(scan.clone());
+        }
+    }
+
+    debug!("Scanning directory for size calculation: {:?}", path);
+    let mut files_size = 0;
+    let mut subdirectories = Vec::new();
+    for entry in fs::read_dir(path)?.flatten() {
+        // `DirEntry::file_type` does not follow symbolic links.
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => subdirectories.push(entry.path()),
+            Ok(file_type) if file_type.is_file() => {
+                files_size += entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
             }
+            _ => {}
+        }
+    }
+
+    let scan = CachedDirectoryScan {
+        modified,
+        files_size,
+        subdirectories,
+    };
+    if let Ok(mut cache) = DIRECTORY_SIZE_CACHE.lock() {
+        cache.insert(path.to_path_buf(), scan.clone());
+    }
+    Ok(scan)
+}
+
+impl FileSystemEntry {
+    /// Builds the entry for a path without checking it against a sandbox.
+    ///
+    /// # Arguments
+    /// * `value` - The path of the entry.
+    /// * `follow_symlinks` - Whether a symbolic link reports the metadata of its target or of the link itself.
+    ///
+    /// # Errors
+    /// Returns an error if the metadata of the path cannot be read.
+    pub(crate) fn from_path(value: &Path, follow_symlinks: bool) -> Result<Self, FsError> {
+        Self::from_path_with(value, follow_symlinks, true)
+    }
+
+    /// Builds the entry for a path, optionally without opening files to sniff their contents.
+    ///
+    /// # Arguments
+    /// * `value` - The path of the entry.
+    /// * `follow_symlinks` - Whether a symbolic link reports the metadata of its target or of the link itself.
+    /// * `sniff_content` - Whether files without a known extension are opened to check for text.
+    ///
+    /// # Errors
+    /// Returns an error if the metadata of the path cannot be read.
+    pub(crate) fn from_path_with(value: &Path, follow_symlinks: bool, sniff_content: bool) -> Result<Self, FsError> {
+        debug!(
+            "Converting path to FileSystemEntry for path: {:?}",
+            value
+        );
+        let link_metadata = match fs::symlink_metadata(value) {
+            Ok(metadata) => metadata,
             Err(err) => {
                 error!(
                     "Failed to retrieve metadata for path: {:?}. Error: {:?}",
                     value, err
                 );
-                Self::default()
+                return Err(FsError::from_io(value, &err));
             }
+        };
+
+        let is_symlink = link_metadata.file_type().is_symlink();
+        let target_metadata = if is_symlink { fs::metadata(value).ok() } else { None };
+        let link_broken = is_symlink && target_metadata.is_none();
+        let (metadata, resolved) = match target_metadata {
+            Some(target_metadata) if follow_symlinks => (target_metadata, true),
+            _ => (link_metadata, !is_symlink),
+        };
+        debug!("Metadata retrieved for path: {:?}", value);
+
+        // Sniffing the contents of an unresolved link would read its target
+        let detected = if resolved {
+            detect_type(value, &metadata, sniff_content)
+        } else {
+            DetectedType {
+                mime: get_mime(value),
+                category: FileMimeCategory::UNKNOWN,
+                minecraft: None,
+            }
+        };
+
+        let name = value
+            .file_name()
+            .unwrap_or(OsStr::new(""))
+            .to_string_lossy()
+            .to_string();
+        Ok(Self {
+            is_hidden: is_hidden_entry(&name, Some(&metadata)),
+            name,
+            path: value.to_path_buf(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            r#type: get_file_type(
+                value
+                    .extension()
+                    .unwrap_or(OsStr::new(""))
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            mime: detected.mime,
+            category: detected.category,
+            minecraft: detected.minecraft,
+            created: metadata.created().unwrap_or(SystemTime::UNIX_EPOCH),
+            last_modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            is_symlink,
+            link_target: if is_symlink { fs::read_link(value).ok() } else { None },
+            link_broken,
+            permissions: EntryPermissions::from_metadata(&metadata),
+        })
+    }
+
+    /// Makes the path and the link target of the entry relative to a server's root directory.
+    ///
+    /// Link targets outside of the root directory are left as they are.
+    pub(crate) fn relativize(&mut self, root: &Path) {
+        if let Ok(path) = self.path.strip_prefix(root) {
+            self.path = path.to_path_buf();
+        }
+        if let Some(target) = self.link_target.as_ref().and_then(|target| target.strip_prefix(root).ok()) {
+            self.link_target = Some(target.to_path_buf());
         }
     }
 }
 
-impl From<PathBuf> for FileSystemEntries {
-    fn from(value: PathBuf) -> Self {
+impl TryFrom<&SandboxedPath> for FileSystemEntry {
+    type Error = FsError;
+
+    fn try_from(value: &SandboxedPath) -> Result<Self, Self::Error> {
+        Self::from_path(&value.path(), true)
+    }
+}
+
+impl TryFrom<&SandboxedPath> for FileSystemEntries {
+    type Error = FsError;
+
+    /// Lists every entry of a directory, in the order the file system returns them.
+    ///
+    /// Entries that cannot be read are reported in `errors`, only an unreadable directory fails the listing.
+    fn try_from(directory: &SandboxedPath) -> Result<Self, Self::Error> {
+        let value = directory.path();
         debug!(
-            "Converting PathBuf to FileSystemEntries for directory: {:?}",
+            "Converting sandboxed path to FileSystemEntries for directory: {:?}",
             value
         );
-        match fs::read_dir(&value) {
-            Ok(directory_entries) => {
-                let mut entries: Vec<FileSystemEntry> = Vec::new();
-                for entry in directory_entries.flatten() {
-                    debug!("Processing directory entry: {:?}", entry.path());
-                    entries.push(entry.path().into());
-                }
-                info!("Directory processed successfully: {:?}", value);
+        let directory_entries = fs::read_dir(&value).map_err(|err| {
+            error!("Failed to read directory: {:?}. Error: {:?}", value, err);
+            FsError::from_io(&value, &err)
+        })?;
 
-                Self {
-                    parent: value.parent().map(|p| p.to_path_buf()),
-                    entries,
+        let mut entries: Vec<FileSystemEntry> = Vec::new();
+        let mut errors: Vec<FsError> = Vec::new();
+        for entry in directory_entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    errors.push(FsError::from_io(&value, &err));
+                    continue;
                 }
-            }
-            Err(err) => {
-                error!("Failed to read directory: {:?}. Error: {:?}", value, err);
-                Self::default()
+            };
+            debug!("Processing directory entry: {:?}", entry.path());
+            match child_entry(directory, &entry.file_name(), true, true) {
+                // Matches the default of `ListingOptions`, use `list` to include hidden entries
+                Some(Ok(entry)) if !entry.is_hidden => entries.push(entry),
+                Some(Err(err)) if !is_hidden_entry(&entry.file_name().to_string_lossy(), None) => errors.push(err),
+                _ => {}
             }
         }
+        info!("Directory processed successfully: {:?}", value);
+
+        Ok(Self {
+            parent: directory.parent().map(|p| p.path()),
+            total: entries.len(),
+            offset: 0,
+            entries,
+            errors,
+        })
     }
 }
@@ -0,0 +1,70 @@
+use crate::file_system_entry::invalidate_directory_size_cache;
+use crate::sandboxed_path::SandboxedPath;
+use log::{info, warn};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+/// Writes an uploaded file from a stream.
+///
+/// The upload is streamed into a hidden temporary file next to the target, which only replaces the
+/// target once the whole body was received. An interrupted or rejected upload leaves the previous
+/// file untouched.
+///
+/// # Arguments
+/// * `file` - The file to create or replace. Missing parent directories are created.
+/// * `reader` - The body of the upload.
+/// * `max_size` - The largest number of bytes accepted, or `None` for no limit.
+///
+/// # Returns
+/// The number of bytes written.
+///
+/// # Errors
+/// Returns an error if the target is a directory, the upload is larger than `max_size`, or writing fails.
+pub fn receive_upload(file: &SandboxedPath, reader: impl Read, max_size: Option<u64>) -> Result<u64, Box<dyn Error>> {
+    let path = file.path();
+    if path.is_dir() {
+        return Err(format!("{} is a directory", file).into());
+    }
+    let parent = path.parent().ok_or_else(|| format!("{} has no parent directory", file))?;
+    fs::create_dir_all(parent)?;
+
+    let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.{}.upload", file_name, std::process::id()));
+    let result = write_limited(&temp_path, reader, max_size).and_then(|written| {
+        fs::rename(&temp_path, &path)?;
+        Ok(written)
+    });
+    if result.is_err() {
+        if let Err(err) = fs::remove_file(&temp_path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove incomplete upload {:?}: {}", temp_path, err);
+            }
+        }
+    }
+    let written = result?;
+
+    invalidate_directory_size_cache(parent);
+    info!("Received upload of {} bytes to {:?}", written, path);
+    Ok(written)
+}
+
+fn write_limited(path: &Path, mut reader: impl Read, max_size: Option<u64>) -> Result<u64, Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    // Reading one byte past the limit tells an upload of exactly `max_size` bytes apart from a larger one
+    let written = match max_size {
+        Some(max_size) => {
+            let written = std::io::copy(&mut reader.take(max_size.saturating_add(1)), &mut writer)?;
+            if written > max_size {
+                return Err(format!("The upload exceeds the limit of {} bytes", max_size).into());
+            }
+            written
+        }
+        None => std::io::copy(&mut reader, &mut writer)?,
+    };
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(written)
+}
@@ -0,0 +1,197 @@
+use crate::file_diff::{diff_files, DiffOptions, FileDiff};
+use crate::sandboxed_path::SandboxedPath;
+use crate::text_file::{write_file_atomically, DEFAULT_MAX_TEXT_FILE_SIZE};
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the version store directory inside a server's root directory.
+pub const VERSIONS_DIRECTORY: &str = ".versions";
+
+/// The number of previous versions kept per file by default.
+pub const DEFAULT_MAX_VERSIONS: usize = 10;
+
+/// A previous version of a file.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileVersion {
+    /// The identifier used to diff or restore the version, the time it was stored in milliseconds.
+    pub id: String,
+    /// The path of the versioned file, relative to the server's root directory.
+    pub path: PathBuf,
+    /// When the version was stored, which is when the file was about to be overwritten.
+    pub created: SystemTime,
+    pub size: u64,
+}
+
+/// The previous versions of the files of a server.
+///
+/// Versions are plain copies stored in `.versions/<path of the file>/<id>`, so a version of
+/// `config/paper-global.yml` lives in `.versions/config/paper-global.yml/`. Only files the manager
+/// writes itself are versioned, and files larger than `DEFAULT_MAX_TEXT_FILE_SIZE` are skipped.
+#[derive(Debug, Clone)]
+pub struct VersionStore {
+    root: SandboxedPath,
+    max_versions: usize,
+}
+
+impl VersionStore {
+    /// Opens the version store of the server a path belongs to, keeping `DEFAULT_MAX_VERSIONS` versions per file.
+    pub fn new(server_path: &SandboxedPath) -> Self {
+        Self {
+            root: SandboxedPath::root(server_path.root_path()).unwrap_or_else(|_| server_path.clone()),
+            max_versions: DEFAULT_MAX_VERSIONS,
+        }
+    }
+
+    /// Sets how many versions are kept per file, older versions are removed on the next snapshot.
+    pub fn with_max_versions(mut self, max_versions: usize) -> Self {
+        self.max_versions = max_versions.max(1);
+        self
+    }
+
+    /// Stores the current contents of a file as a new version.
+    ///
+    /// Nothing is stored if the file does not exist, is too large, or is identical to the latest version.
+    ///
+    /// # Returns
+    /// The stored version, if one was stored.
+    ///
+    /// # Errors
+    /// Returns an error if the file is inside the version store or cannot be copied.
+    pub fn snapshot(&self, file: &SandboxedPath) -> Result<Option<FileVersion>, Box<dyn Error>> {
+        let source = file.path();
+        let metadata = match fs::metadata(&source) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Ok(None),
+        };
+        if metadata.len() > DEFAULT_MAX_TEXT_FILE_SIZE {
+            debug!("Not storing a version of {}, it is too large", file);
+            return Ok(None);
+        }
+
+        let directory = self.version_directory(file)?;
+        let contents = fs::read(&source)?;
+        if let Some(latest) = self.list(file)?.first() {
+            if fs::read(directory.path().join(&latest.id)).is_ok_and(|latest| latest == contents) {
+                debug!("{} is unchanged since version {}", file, latest.id);
+                return Ok(None);
+            }
+        }
+
+        fs::create_dir_all(directory.path())?;
+        let created = SystemTime::now();
+        let mut millis = created.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        // Two saves within the same millisecond must not overwrite each other
+        while directory.path().join(millis.to_string()).exists() {
+            millis += 1;
+        }
+        let id = millis.to_string();
+        fs::write(directory.path().join(&id), &contents)?;
+        info!("Stored version {} of {}", id, file);
+
+        self.prune(file)?;
+        Ok(Some(FileVersion {
+            id,
+            path: file.relative_path().to_path_buf(),
+            created,
+            size: contents.len() as u64,
+        }))
+    }
+
+    /// Lists the stored versions of a file, newest first.
+    ///
+    /// # Errors
+    /// Returns an error if the version directory exists but cannot be read.
+    pub fn list(&self, file: &SandboxedPath) -> Result<Vec<FileVersion>, Box<dyn Error>> {
+        let directory = self.version_directory(file)?.path();
+        if !directory.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut versions: Vec<FileVersion> = fs::read_dir(&directory)?
+            .flatten()
+            .filter_map(|entry| {
+                let id = entry.file_name().to_string_lossy().to_string();
+                let millis = id.parse::<u64>().ok()?;
+                let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+                Some(FileVersion {
+                    id,
+                    path: file.relative_path().to_path_buf(),
+                    created: UNIX_EPOCH + Duration::from_millis(millis),
+                    size: metadata.len(),
+                })
+            })
+            .collect();
+        versions.sort_by(|a, b| b.created.cmp(&a.created));
+        Ok(versions)
+    }
+
+    /// Compares a stored version with the current contents of the file.
+    ///
+    /// # Errors
+    /// Returns an error if the version does not exist or a file cannot be read.
+    pub fn diff(&self, file: &SandboxedPath, id: &str, options: &DiffOptions) -> Result<FileDiff, Box<dyn Error>> {
+        let version = self.version_path(file, id)?;
+        let mut diff = diff_files(&version, file, options)?;
+        diff.old_name = format!("{} (version {})", file, id);
+        Ok(diff)
+    }
+
+    /// Restores a stored version of a file.
+    ///
+    /// The current contents are stored as a new version first, so a restore can be undone as well.
+    ///
+    /// # Errors
+    /// Returns an error if the version does not exist or the file cannot be written.
+    pub fn restore(&self, file: &SandboxedPath, id: &str) -> Result<(), Box<dyn Error>> {
+        let version = self.version_path(file, id)?;
+        let contents = fs::read(version.path())?;
+        if let Err(err) = self.snapshot(file) {
+            warn!("Failed to store the current contents of {} before restoring: {}", file, err);
+        }
+        write_file_atomically(&file.path(), &contents)?;
+        info!("Restored version {} of {}", id, file);
+        Ok(())
+    }
+
+    /// Removes every stored version of a file.
+    pub fn clear(&self, file: &SandboxedPath) -> Result<(), Box<dyn Error>> {
+        let directory = self.version_directory(file)?.path();
+        if directory.is_dir() {
+            fs::remove_dir_all(directory)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the oldest versions of a file beyond the configured maximum.
+    fn prune(&self, file: &SandboxedPath) -> Result<(), Box<dyn Error>> {
+        let directory = self.version_directory(file)?.path();
+        for version in self.list(file)?.into_iter().skip(self.max_versions) {
+            debug!("Removing old version {} of {}", version.id, file);
+            fs::remove_file(directory.join(&version.id))?;
+        }
+        Ok(())
+    }
+
+    fn version_directory(&self, file: &SandboxedPath) -> Result<SandboxedPath, Box<dyn Error>> {
+        let relative = file.relative_path();
+        if relative.as_os_str().is_empty() || relative.starts_with(VERSIONS_DIRECTORY) {
+            return Err(format!("{} cannot be versioned", file).into());
+        }
+        self.root.join(Path::new(VERSIONS_DIRECTORY).join(relative))
+    }
+
+    fn version_path(&self, file: &SandboxedPath, id: &str) -> Result<SandboxedPath, Box<dyn Error>> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("Invalid version id: {:?}", id).into());
+        }
+        let version = self.version_directory(file)?.join(id)?;
+        if !version.path().is_file() {
+            return Err(format!("Version {} of {} does not exist", id, file).into());
+        }
+        Ok(version)
+    }
+}
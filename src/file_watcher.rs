@@ -0,0 +1,288 @@
+use crate::file_system_entry::{invalidate_directory_size_cache, is_managed_path};
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, error, info, warn};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The kind of change that happened to a watched path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+/// A debounced change to a file or directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChangeEvent {
+    pub kind: FileChangeKind,
+    /// The changed path, relative to the server's root directory.
+    pub path: PathBuf,
+    /// Whether the path is a directory. Always `false` for removed paths, since they can no longer be inspected.
+    pub is_dir: bool,
+}
+
+/// Options controlling which changes a watcher reports and how often.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchOptions {
+    /// Whether changes in subdirectories are reported.
+    pub recursive: bool,
+    /// How long the watcher waits for the file system to settle before reporting a batch of changes,
+    /// in milliseconds. Saving a world touches hundreds of files, this keeps that down to one batch.
+    pub debounce_ms: u64,
+    /// Glob patterns matched against the path relative to the watched directory. When not empty,
+    /// only matching paths are reported.
+    pub include: Vec<String>,
+    /// Glob patterns for paths that are never reported.
+    pub exclude: Vec<String>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        Self {
+            recursive: true,
+            debounce_ms: 250,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// A running file system watcher. Watching stops when the watcher is stopped or dropped.
+pub struct FileWatcher {
+    watcher: Option<RecommendedWatcher>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FileWatcher {
+    /// Starts watching a directory for changes.
+    ///
+    /// Raw file system events are collected until nothing has changed for the debounce interval
+    /// and then delivered as one batch, with repeated changes to the same path merged: a file that
+    /// is created and written is reported once as created, and a file created and removed again
+    /// within the interval is not reported at all. Changes to the server's trash
+    /// and file versions are never reported.
+    ///
+    /// # Arguments
+    /// * `directory` - The directory to watch.
+    /// * `options` - The filters and debounce interval.
+    /// * `on_events` - Invoked on a background thread with every batch of changes.
+    ///   Returning `false` stops the watcher.
+    ///
+    /// # Errors
+    /// Returns an error if a glob pattern is invalid or the directory cannot be watched.
+    pub fn start(
+        directory: &SandboxedPath,
+        options: &WatchOptions,
+        on_events: impl Fn(&[FileChangeEvent]) -> bool + Send + 'static,
+    ) -> Result<Self, Box<dyn Error>> {
+        let filter = PathFilter::new(directory, options)?;
+        let watched_path = directory.path();
+        let (tx, rx) = channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        let mode = if options.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        watcher.watch(&watched_path, mode)?;
+        info!("Watching {:?} for changes", watched_path);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = Arc::clone(&running);
+        let debounce = Duration::from_millis(options.debounce_ms);
+
+        let handle = thread::spawn(move || {
+            let mut pending: HashMap<PathBuf, FileChangeKind> = HashMap::new();
+            let mut last_event = Instant::now();
+
+            while thread_running.load(Ordering::Relaxed) {
+                // Without pending changes there is nothing to flush, so the worker can wait a while
+                let timeout = if pending.is_empty() {
+                    Duration::from_millis(500)
+                } else {
+                    debounce.saturating_sub(last_event.elapsed())
+                };
+
+                match rx.recv_timeout(timeout) {
+                    Ok(Ok(event)) => {
+                        for (path, kind) in classify_event(&event) {
+                            if !filter.matches(&path) {
+                                continue;
+                            }
+                            merge_change(&mut pending, path, kind);
+                        }
+                        last_event = Instant::now();
+                    }
+                    Ok(Err(err)) => warn!("File watcher error for {:?}: {}", watched_path, err),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                if !pending.is_empty() && last_event.elapsed() >= debounce {
+                    let events = filter.to_events(pending.drain());
+                    if !events.is_empty() && !on_events(&events) {
+                        debug!("File watcher callback for {:?} requested to stop", watched_path);
+                        break;
+                    }
+                }
+            }
+            thread_running.store(false, Ordering::Relaxed);
+            debug!("File watcher for {:?} stopped", watched_path);
+        });
+
+        Ok(Self {
+            watcher: Some(watcher),
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    /// Returns whether the watcher is still delivering events.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Stops the watcher and waits for its background thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        // Dropping the watcher disconnects the channel, which wakes the background thread
+        self.watcher.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("File watcher thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for FileWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Translates a raw notify event into changes of individual paths.
+fn classify_event(event: &notify::Event) -> Vec<(PathBuf, FileChangeKind)> {
+    let kind = match event.kind {
+        EventKind::Create(_) => FileChangeKind::Created,
+        EventKind::Remove(_) => FileChangeKind::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            // A rename within the watched directory is a removal of the old and a creation of the new path
+            return vec![
+                (event.paths[0].clone(), FileChangeKind::Removed),
+                (event.paths[1].clone(), FileChangeKind::Created),
+            ];
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileChangeKind::Removed,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => FileChangeKind::Created,
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            // The direction of the rename is unknown, so check whether the path still exists
+            return event
+                .paths
+                .iter()
+                .map(|path| {
+                    let kind = if path.exists() {
+                        FileChangeKind::Created
+                    } else {
+                        FileChangeKind::Removed
+                    };
+                    (path.clone(), kind)
+                })
+                .collect();
+        }
+        EventKind::Modify(_) => FileChangeKind::Modified,
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => return Vec::new(),
+    };
+    event.paths.iter().map(|path| (path.clone(), kind)).collect()
+}
+
+/// Merges a new change of a path into the changes collected so far.
+fn merge_change(pending: &mut HashMap<PathBuf, FileChangeKind>, path: PathBuf, kind: FileChangeKind) {
+    use FileChangeKind::*;
+    match (pending.get(&path).copied(), kind) {
+        // The path never existed as far as the listener knows
+        (Some(Created), Removed) => {
+            pending.remove(&path);
+        }
+        (Some(Created), Modified) => {}
+        (Some(Removed), Created) => {
+            pending.insert(path, Modified);
+        }
+        _ => {
+            pending.insert(path, kind);
+        }
+    }
+}
+
+/// Decides which paths are reported and converts them to paths relative to the server directory.
+struct PathFilter {
+    base: PathBuf,
+    root: PathBuf,
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl PathFilter {
+    fn new(directory: &SandboxedPath, options: &WatchOptions) -> Result<Self, Box<dyn Error>> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            base: directory.path(),
+            root: directory.root_path().to_path_buf(),
+            include: compile(&options.include)?,
+            exclude: compile(&options.exclude)?,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if is_managed_path(&self.root, path) {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else {
+            return false;
+        };
+        if !self.include.is_empty() && !self.include.iter().any(|pattern| pattern.matches_path(relative)) {
+            return false;
+        }
+        !self.exclude.iter().any(|pattern| pattern.matches_path(relative))
+    }
+
+    fn to_events(&self, changes: impl Iterator<Item = (PathBuf, FileChangeKind)>) -> Vec<FileChangeEvent> {
+        let mut events: Vec<FileChangeEvent> = changes
+            .map(|(path, kind)| {
+                if let Some(parent) = path.parent() {
+                    invalidate_directory_size_cache(parent);
+                }
+                FileChangeEvent {
+                    kind,
+                    is_dir: kind != FileChangeKind::Removed && path.is_dir(),
+                    path: path.strip_prefix(&self.root).map(Path::to_path_buf).unwrap_or(path),
+                }
+            })
+            .collect();
+        events.sort_by(|a, b| a.path.cmp(&b.path));
+        events
+    }
+}
@@ -0,0 +1,62 @@
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// An error accessing a file or directory, keeping the path it happened for.
+///
+/// Unlike a plain `io::Error` it can be serialized, so a listing can tell the user which entries
+/// could not be read and why instead of showing them as empty files.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FsError {
+    /// The path does not exist.
+    NotFound { path: PathBuf },
+    /// The manager is not allowed to access the path.
+    PermissionDenied { path: PathBuf },
+    /// Any other I/O error.
+    Io { path: PathBuf, message: String },
+}
+
+impl FsError {
+    /// Classifies an I/O error that occurred while accessing `path`.
+    pub fn from_io(path: impl AsRef<Path>, err: &io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        match err.kind() {
+            io::ErrorKind::NotFound => FsError::NotFound { path },
+            io::ErrorKind::PermissionDenied => FsError::PermissionDenied { path },
+            _ => FsError::Io {
+                path,
+                message: err.to_string(),
+            },
+        }
+    }
+
+    /// The path the error occurred for.
+    pub fn path(&self) -> &Path {
+        match self {
+            FsError::NotFound { path } | FsError::PermissionDenied { path } | FsError::Io { path, .. } => path,
+        }
+    }
+
+    /// Makes the path of the error relative to a server's root directory.
+    pub(crate) fn relativize(&mut self, root: &Path) {
+        let (FsError::NotFound { path } | FsError::PermissionDenied { path } | FsError::Io { path, .. }) = self;
+        if let Ok(relative) = path.strip_prefix(root) {
+            *path = relative.to_path_buf();
+        }
+    }
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsError::NotFound { path } => write!(f, "{} does not exist", path.display()),
+            FsError::PermissionDenied { path } => write!(f, "Permission denied for {}", path.display()),
+            FsError::Io { path, message } => write!(f, "Failed to access {}: {}", path.display(), message),
+        }
+    }
+}
+
+impl Error for FsError {}
@@ -0,0 +1,309 @@
+use crate::file_hash::{hash_reader, HashAlgorithm};
+use log::{debug, info};
+use serde_json::Value;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// Identifies the manager to download APIs, some of which reject requests without a user agent.
+const USER_AGENT: &str = concat!("obsidian-server-manager/", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait for a connection or for the next bytes of a response.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The size of the buffer downloads are streamed through.
+const DOWNLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A checksum a download is verified against.
+#[derive(Debug, Clone)]
+pub(crate) struct ExpectedHash {
+    pub algorithm: HashAlgorithm,
+    /// The hex encoded hash.
+    pub hash: String,
+}
+
+pub(crate) fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .user_agent(USER_AGENT)
+        .timeout_connect(HTTP_TIMEOUT)
+        .timeout_read(HTTP_TIMEOUT)
+        .build()
+}
+
+/// Whether an address is one of this machine or of a private network, which requests to URLs that
+/// users enter must not reach.
+pub(crate) fn is_internal_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // 100.64.0.0/10 is shared by carrier-grade NAT, like a private network
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_internal_address(IpAddr::V4(mapped)),
+            // fc00::/7 are unique local and fe80::/10 link-local addresses
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Fails unless a URL a user entered is an http or https URL.
+pub(crate) fn check_web_url(url: &str) -> Result<(), Box<dyn Error>> {
+    let scheme = url.trim().split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+    if !matches!(scheme.as_deref(), Some("http" | "https")) {
+        return Err(format!("{:?} is not an http or https URL", url).into());
+    }
+    Ok(())
+}
+
+/// An agent for URLs users enter. Host names only resolve to public addresses, which also holds for
+/// redirects and for names whose addresses change between a check and the request.
+pub(crate) fn public_agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .user_agent(USER_AGENT)
+        .timeout_connect(HTTP_TIMEOUT)
+        .timeout_read(HTTP_TIMEOUT)
+        .resolver(|address: &str| -> io::Result<Vec<SocketAddr>> {
+            let public: Vec<SocketAddr> =
+                address.to_socket_addrs()?.filter(|resolved| !is_internal_address(resolved.ip())).collect();
+            if public.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} is not a public address", address),
+                ));
+            }
+            Ok(public)
+        })
+        .build()
+}
+
+/// Fetches and parses a JSON document.
+pub(crate) fn get_json(url: &str) -> Result<Value, Box<dyn Error>> {
+    debug!("Fetching {}", url);
+    let response = agent().get(url).call()?;
+    Ok(serde_json::from_reader(response.into_reader())?)
+}
+
+/// Fetches and parses a JSON document, passing `query` as URL encoded query parameters.
+pub(crate) fn get_json_with_query(url: &str, query: &[(&str, &str)]) -> Result<Value, Box<dyn Error>> {
+    debug!("Fetching {} with {:?}", url, query);
+    let request = query
+        .iter()
+        .fold(agent().get(url), |request, (name, value)| request.query(name, value));
+    Ok(serde_json::from_reader(request.call()?.into_reader())?)
+}
+
+/// Posts a JSON document and parses the JSON response, sending `headers` along, such as API keys.
+pub(crate) fn post_json(url: &str, headers: &[(&str, &str)], body: &Value) -> Result<Value, Box<dyn Error>> {
+    debug!("Posting to {}", url);
+    let request = headers
+        .iter()
+        .fold(agent().post(url), |request, (name, value)| request.set(name, value))
+        .set("Content-Type", "application/json");
+    Ok(serde_json::from_reader(request.send_string(&body.to_string())?.into_reader())?)
+}
+
+/// Fetches a plain text document, such as Maven metadata or a published checksum.
+pub(crate) fn get_text(url: &str) -> Result<String, Box<dyn Error>> {
+    debug!("Fetching {}", url);
+    Ok(agent().get(url).call()?.into_string()?)
+}
+
+/// The answer of an API that reports unknown keys with a status code rather than an error body.
+#[derive(Debug)]
+pub(crate) enum Lookup {
+    Found(Value),
+    /// The API answered `204 No Content` or `404 Not Found`.
+    NotFound,
+    /// The API answered `429 Too Many Requests`, with the delay of its `Retry-After` header.
+    RateLimited(Option<Duration>),
+}
+
+/// Fetches a JSON document from a lookup API, telling unknown keys and rate limits apart from failures.
+pub(crate) fn lookup_json(url: &str) -> Result<Lookup, Box<dyn Error>> {
+    debug!("Looking up {}", url);
+    match agent().get(url).call() {
+        Ok(response) if response.status() == 204 => Ok(Lookup::NotFound),
+        Ok(response) => Ok(Lookup::Found(serde_json::from_reader(response.into_reader())?)),
+        Err(ureq::Error::Status(404, _)) => Ok(Lookup::NotFound),
+        Err(ureq::Error::Status(429, response)) => Ok(Lookup::RateLimited(
+            response
+                .header("Retry-After")
+                .and_then(|seconds| seconds.trim().parse().ok())
+                .map(Duration::from_secs),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fetches a binary document into memory, such as an image, refusing responses larger than `max_size`.
+pub(crate) fn get_bytes(url: &str, max_size: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+    debug!("Fetching {}", url);
+    let mut bytes = Vec::new();
+    agent()
+        .get(url)
+        .call()?
+        .into_reader()
+        .take(max_size + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > max_size {
+        return Err(format!("The response of {} is larger than {} bytes", url, max_size).into());
+    }
+    Ok(bytes)
+}
+
+/// Downloads a file, verifies its checksum and moves it into place.
+///
+/// The download is written next to the destination with a `.part` extension first, so a failed or
+/// corrupted download never replaces an existing file.
+///
+/// # Arguments
+/// * `url` - Where to download the file from.
+/// * `destination` - Where to place the file.
+/// * `expected` - The checksum the file has to match, if the source publishes one.
+/// * `on_progress` - Called with the bytes downloaded so far and the total size, if known.
+///
+/// # Returns
+/// The size of the downloaded file.
+pub(crate) fn download_file(
+    url: &str,
+    destination: &Path,
+    expected: Option<&ExpectedHash>,
+    on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<u64, Box<dyn Error>> {
+    info!("Downloading {} to {:?}", url, destination);
+    let response = agent().get(url).call()?;
+    let total = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok());
+
+    let mut partial_name = destination.file_name().ok_or("The destination has no file name")?.to_os_string();
+    partial_name.push(".part");
+    let partial = destination.with_file_name(partial_name);
+    let result = write_download(response.into_reader(), &partial, total, on_progress).and_then(|size| {
+        if let Some(expected) = expected {
+            let actual = hash_reader(File::open(&partial)?, expected.algorithm)?;
+            if !actual.eq_ignore_ascii_case(expected.hash.trim()) {
+                return Err(format!(
+                    "The {:?} checksum of {} does not match, expected {} but got {}",
+                    expected.algorithm, url, expected.hash, actual
+                )
+                .into());
+            }
+        }
+        fs::rename(&partial, destination)?;
+        Ok(size)
+    });
+
+    if result.is_err() && partial.exists() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Downloads a file from a URL a user entered through [`public_agent`], refusing responses larger
+/// than `max_size`.
+///
+/// # Returns
+/// The size of the downloaded file.
+pub(crate) fn download_public_file(url: &str, destination: &Path, max_size: u64) -> Result<u64, Box<dyn Error>> {
+    check_web_url(url)?;
+    info!("Downloading {} to {:?}", url, destination);
+    let response = public_agent().get(url.trim()).call()?;
+    let result = write_download(response.into_reader().take(max_size + 1), destination, None, &mut |_, _| {})
+        .and_then(|size| {
+            if size > max_size {
+                return Err(format!("The download of {} is larger than {} bytes", url, max_size).into());
+            }
+            Ok(size)
+        });
+    if result.is_err() && destination.exists() {
+        let _ = fs::remove_file(destination);
+    }
+    result
+}
+
+fn write_download(
+    mut reader: impl Read,
+    path: &Path,
+    total: Option<u64>,
+    on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<u64, Box<dyn Error>> {
+    let mut file = File::create(path)?;
+    let mut buffer = vec![0u8; DOWNLOAD_BUFFER_SIZE];
+    let mut downloaded = 0u64;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read])?;
+        downloaded += read as u64;
+        on_progress(downloaded, total);
+    }
+    file.sync_all()?;
+    Ok(downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(address: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(is_internal_address(address.parse()?))
+    }
+
+    #[test]
+    fn local_and_private_addresses_are_internal() -> Result<(), Box<dyn Error>> {
+        for address in [
+            "127.0.0.1",
+            "127.1.2.3",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ] {
+            assert!(internal(address)?, "{}", address);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn public_addresses_are_not_internal() -> Result<(), Box<dyn Error>> {
+        for address in ["1.1.1.1", "93.184.216.34", "100.128.0.1", "172.32.0.1", "2606:4700::1111"] {
+            assert!(!internal(address)?, "{}", address);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn only_web_urls_are_accepted() {
+        assert!(check_web_url("https://example.com/pack.zip").is_ok());
+        assert!(check_web_url("HTTP://example.com/pack.zip").is_ok());
+        for url in ["file:///etc/passwd", "ftp://example.com/pack.zip", "example.com/pack.zip", "gopher://x"] {
+            assert!(check_web_url(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::archive_extractor::{extract_archive, ExtractionOptions};
+use crate::file_hash::HashAlgorithm;
+use crate::http_client::{download_file, get_json_with_query, ExpectedHash};
+use crate::java_runtimes::{
+    choose_java_runtime, executable_in, find_java_runtimes, inspect_java_runtime, required_java_version, JavaRuntime,
+    JavaRuntimeSource, ServerJavaRuntime,
+};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ADOPTIUM_API: &str = "https://api.adoptium.net/v3";
+
+/// The directory the downloaded runtimes are kept in, one directory per runtime.
+pub const RUNTIMES_DIRECTORY: &str = "runtimes";
+
+/// The Java version downloaded for versions of the game whose requirement is unknown, which are
+/// snapshots newer than any known release.
+const DEFAULT_JAVA_VERSION: u32 = 25;
+
+lazy_static! {
+    /// Held while a runtime is downloaded, so servers provisioned at once share one download.
+    static ref DOWNLOAD_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A Temurin build as published by the Adoptium API.
+#[derive(Debug, Clone, Serialize)]
+pub struct TemurinRelease {
+    pub major: u32,
+    /// The name of the release, like `jdk-21.0.4+7`.
+    pub release_name: String,
+    /// Whether the build is a JRE, a JDK is used where no JRE is published.
+    pub jre: bool,
+    pub url: String,
+    pub file_name: String,
+    pub sha256: Option<String>,
+    pub size: u64,
+}
+
+/// The name Adoptium uses for the operating system the manager runs on.
+fn adoptium_os() -> Result<&'static str, Box<dyn Error>> {
+    match std::env::consts::OS {
+        "linux" => Ok("linux"),
+        "macos" => Ok("mac"),
+        "windows" => Ok("windows"),
+        os => Err(format!("Temurin is not published for {}", os).into()),
+    }
+}
+
+/// The name Adoptium uses for the processor architecture the manager runs on.
+fn adoptium_architecture() -> Result<&'static str, Box<dyn Error>> {
+    match std::env::consts::ARCH {
+        "x86_64" => Ok("x64"),
+        "x86" => Ok("x32"),
+        "aarch64" => Ok("aarch64"),
+        "arm" => Ok("arm"),
+        "powerpc64" => Ok("ppc64le"),
+        architecture => Err(format!("Temurin is not published for {}", architecture).into()),
+    }
+}
+
+/// Looks up the newest Temurin build of a Java version for this machine, preferring a JRE.
+///
+/// # Errors
+/// Returns an error if Adoptium publishes no build of the version for this machine.
+pub fn resolve_temurin_release(major: u32) -> Result<TemurinRelease, Box<dyn Error>> {
+    let url = format!("{}/assets/latest/{}/hotspot", ADOPTIUM_API, major);
+    let (os, architecture) = (adoptium_os()?, adoptium_architecture()?);
+    for image_type in ["jre", "jdk"] {
+        let query = [
+            ("architecture", architecture),
+            ("image_type", image_type),
+            ("os", os),
+            ("vendor", "eclipse"),
+        ];
+        let assets = get_json_with_query(&url, &query)?;
+        let Some(asset) = assets.as_array().and_then(|assets| assets.first()) else {
+            continue;
+        };
+        let package: &Value = &asset["binary"]["package"];
+        let (Some(link), Some(file_name)) = (package["link"].as_str(), package["name"].as_str()) else {
+            continue;
+        };
+        return Ok(TemurinRelease {
+            major,
+            release_name: asset["release_name"].as_str().unwrap_or(file_name).to_string(),
+            jre: image_type == "jre",
+            url: link.to_string(),
+            file_name: file_name.to_string(),
+            sha256: package["checksum"].as_str().map(str::to_string),
+            size: package["size"].as_u64().unwrap_or_default(),
+        });
+    }
+    Err(format!("Temurin {} is not published for {} on {}", major, os, architecture).into())
+}
+
+/// Lists the runtimes downloaded into the runtimes directory.
+pub fn managed_java_runtimes() -> Vec<JavaRuntime> {
+    find_java_runtimes()
+        .into_iter()
+        .filter(|runtime| runtime.source == JavaRuntimeSource::Managed)
+        .collect()
+}
+
+/// Downloads Temurin of a Java version into the runtimes directory, unless it was downloaded before.
+///
+/// The archive is verified against the checksum Adoptium publishes and unpacked next to the other
+/// runtimes under a temporary name, which is only renamed once the runtime is complete.
+///
+/// # Arguments
+/// * `major` - The Java version, like `21`.
+/// * `on_progress` - Called with the bytes downloaded so far and the total size, if known.
+pub fn download_java_runtime(
+    major: u32,
+    on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<JavaRuntime, Box<dyn Error>> {
+    let _guard = DOWNLOAD_LOCK.lock().map_err(|_| "Failed to lock the Java runtime downloads")?;
+    if let Some(runtime) = managed_java_runtimes().into_iter().find(|runtime| runtime.major == major) {
+        return Ok(runtime);
+    }
+
+    let release = resolve_temurin_release(major)?;
+    fs::create_dir_all(RUNTIMES_DIRECTORY)?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let staging = format!(".download-{}-{}", major, nanos);
+    let archive = Path::new(RUNTIMES_DIRECTORY).join(format!(".{}", release.file_name));
+    let result = install_release(&release, &archive, &staging, on_progress);
+
+    let _ = fs::remove_file(&archive);
+    let staging = Path::new(RUNTIMES_DIRECTORY).join(&staging);
+    if staging.exists() {
+        if let Err(e) = fs::remove_dir_all(&staging) {
+            warn!("Failed to remove the partial Java runtime {:?}: {}", staging, e);
+        }
+    }
+    result
+}
+
+fn install_release(
+    release: &TemurinRelease,
+    archive: &Path,
+    staging: &str,
+    on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<JavaRuntime, Box<dyn Error>> {
+    info!("Downloading Temurin {} ({})", release.major, release.release_name);
+    let expected = release.sha256.clone().map(|hash| ExpectedHash {
+        algorithm: HashAlgorithm::Sha256,
+        hash,
+    });
+    download_file(&release.url, archive, expected.as_ref(), on_progress)?;
+
+    let archive_name = archive.file_name().ok_or("The runtime archive has no file name")?;
+    extract_archive(
+        &SandboxedPath::new(RUNTIMES_DIRECTORY, archive_name)?,
+        &SandboxedPath::new(RUNTIMES_DIRECTORY, staging)?,
+        &ExtractionOptions::default(),
+        |_| {},
+    )?;
+
+    // The archives hold a single directory named after the release
+    let staging = Path::new(RUNTIMES_DIRECTORY).join(staging);
+    let home = fs::read_dir(&staging)?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| executable_in(path).is_some())
+        .ok_or_else(|| format!("The archive of {} holds no Java runtime", release.release_name))?;
+    let name = if release.jre { format!("{}-jre", release.release_name) } else { release.release_name.clone() };
+    let target: PathBuf = Path::new(RUNTIMES_DIRECTORY).join(name.replace('+', "_"));
+    if target.exists() {
+        fs::remove_dir_all(&target)?;
+    }
+    fs::rename(&home, &target)?;
+
+    let executable = executable_in(&target).ok_or("The downloaded Java runtime has no java executable")?;
+    let runtime = inspect_java_runtime(&executable, JavaRuntimeSource::Managed)?;
+    info!("Installed Temurin {} to {:?}", runtime.version, target);
+    Ok(runtime)
+}
+
+/// Finds a runtime that can start a version of the game, downloading Temurin if none is installed.
+pub fn ensure_java_runtime(
+    game_version: &str,
+    on_progress: &mut dyn FnMut(u64, Option<u64>),
+) -> Result<JavaRuntime, Box<dyn Error>> {
+    if let Some(runtime) = choose_java_runtime(&find_java_runtimes(), game_version) {
+        return Ok(runtime);
+    }
+    let major = required_java_version(game_version).unwrap_or(DEFAULT_JAVA_VERSION);
+    info!("No installed Java runtime can start Minecraft {}, downloading Java {}", game_version, major);
+    download_java_runtime(major, on_progress)
+}
+
+pub trait ServerJavaDownload {
+    /// Pins the server to a runtime for its version, downloading Temurin if none is installed.
+    ///
+    /// # Errors
+    /// Returns an error if no runtime is installed and none can be downloaded for this machine.
+    fn install_java_runtime(
+        &mut self,
+        on_progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<JavaRuntime, Box<dyn Error>>;
+}
+
+impl ServerJavaDownload for Server<u64> {
+    fn install_java_runtime(
+        &mut self,
+        on_progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<JavaRuntime, Box<dyn Error>> {
+        let runtime = ensure_java_runtime(&self.minecraft_version, on_progress)?;
+        self.set_java_runtime(&runtime.executable)
+    }
+}
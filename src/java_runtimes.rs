@@ -0,0 +1,344 @@
+use crate::java_downloads::RUNTIMES_DIRECTORY;
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::users::require_admin;
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The first version of the game that needs each Java version, oldest first.
+const JAVA_REQUIREMENTS: &[(&str, u32)] = &[("1.0", 8), ("1.17", 16), ("1.18", 17), ("1.20.5", 21), ("26.1", 25)];
+
+/// The directories distributions and installers put runtimes in, each holding one runtime per entry.
+#[cfg(target_os = "linux")]
+const RUNTIME_DIRECTORIES: &[&str] = &["/usr/lib/jvm", "/usr/java", "/opt/java", "/opt/jdk"];
+#[cfg(target_os = "macos")]
+const RUNTIME_DIRECTORIES: &[&str] = &["/Library/Java/JavaVirtualMachines", "/opt/homebrew/opt"];
+#[cfg(windows)]
+const RUNTIME_DIRECTORIES: &[&str] = &[
+    r"C:\Program Files\Java",
+    r"C:\Program Files\Eclipse Adoptium",
+    r"C:\Program Files\Microsoft",
+    r"C:\Program Files\Zulu",
+    r"C:\Program Files\Amazon Corretto",
+    r"C:\Program Files\BellSoft",
+];
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const RUNTIME_DIRECTORIES: &[&str] = &[];
+
+/// The directories below the home directory that version managers keep runtimes in.
+const HOME_RUNTIME_DIRECTORIES: &[&str] = &[".sdkman/candidates/java", ".jdks", ".asdf/installs/java"];
+
+/// The registry keys the Windows installers of Oracle and most other vendors record runtimes under.
+#[cfg(windows)]
+const REGISTRY_KEYS: &[&str] = &[
+    r"HKLM\SOFTWARE\JavaSoft\JDK",
+    r"HKLM\SOFTWARE\JavaSoft\Java Runtime Environment",
+    r"HKLM\SOFTWARE\JavaSoft\JRE",
+    r"HKLM\SOFTWARE\Eclipse Adoptium\JDK",
+    r"HKLM\SOFTWARE\Eclipse Adoptium\JRE",
+];
+
+const JAVA_EXECUTABLE: &str = if cfg!(windows) { "java.exe" } else { "java" };
+
+/// Where a runtime was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JavaRuntimeSource {
+    JavaHome,
+    Path,
+    Directory,
+    Registry,
+    /// Downloaded by the manager into the runtimes directory.
+    Managed,
+    /// Set on a server by hand.
+    Configured,
+}
+
+/// An installed Java runtime.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JavaRuntime {
+    /// The `java` executable, which servers are started with.
+    pub executable: PathBuf,
+    /// The full version, like `21.0.4` or `1.8.0_422`.
+    pub version: String,
+    /// The feature version, `8` for `1.8.0_422`.
+    pub major: u32,
+    pub vendor: Option<String>,
+    pub source: JavaRuntimeSource,
+}
+
+/// The Java version a version of the game needs at least, `None` for snapshots it cannot be told for.
+pub fn required_java_version(game_version: &str) -> Option<u32> {
+    let numbers = |version: &str| -> Option<Vec<u64>> { version.split('.').map(|n| n.parse().ok()).collect() };
+    let game = numbers(game_version)?;
+    JAVA_REQUIREMENTS
+        .iter()
+        .rev()
+        .find(|(first, _)| numbers(first).is_some_and(|first| game >= first))
+        .map(|(_, major)| *major)
+}
+
+/// Checks whether a runtime can start a version of the game.
+///
+/// # Errors
+/// Returns an error naming the Java version needed if the runtime is too old.
+pub fn check_java_compatibility(runtime: &JavaRuntime, game_version: &str) -> Result<(), Box<dyn Error>> {
+    match required_java_version(game_version) {
+        Some(required) if runtime.major < required => Err(format!(
+            "Minecraft {} needs Java {} or newer, {:?} is Java {}",
+            game_version, required, runtime.executable, runtime.major
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// The feature version of a version string, `8` for `1.8.0_422` and `21` for `21.0.4+7`.
+fn major_version(version: &str) -> Option<u32> {
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let first: u32 = parts.next()?.parse().ok()?;
+    if first == 1 {
+        return parts.next()?.parse().ok();
+    }
+    Some(first)
+}
+
+/// Reads the version of a runtime from the `release` file next to its `bin` directory, without
+/// starting it.
+fn read_release_file(home: &Path) -> Option<(String, Option<String>)> {
+    let contents = fs::read_to_string(home.join("release")).ok()?;
+    let value = |key: &str| {
+        contents.lines().find_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            (name.trim() == key).then(|| value.trim().trim_matches('"').to_string())
+        })
+    };
+    Some((value("JAVA_VERSION")?, value("IMPLEMENTOR")))
+}
+
+/// Asks a runtime for its version, which it prints to stderr with its other properties.
+fn query_runtime(executable: &Path) -> Option<(String, Option<String>)> {
+    let output = Command::new(executable)
+        .args(["-XshowSettings:properties", "-version"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stderr);
+    let property = |key: &str| {
+        output.lines().find_map(|line| {
+            let (name, value) = line.split_once(" = ")?;
+            (name.trim() == key).then(|| value.trim().to_string())
+        })
+    };
+    Some((property("java.version")?, property("java.vendor")))
+}
+
+/// Identifies the runtime of a `java` executable.
+///
+/// # Errors
+/// Returns an error if the executable does not exist or does not report a version.
+pub fn inspect_java_runtime(executable: &Path, source: JavaRuntimeSource) -> Result<JavaRuntime, Box<dyn Error>> {
+    let executable = resolve_executable(executable).ok_or_else(|| format!("{:?} does not exist", executable))?;
+    let home = executable.parent().and_then(Path::parent);
+    let (version, vendor) = home
+        .and_then(read_release_file)
+        .or_else(|| query_runtime(&executable))
+        .ok_or_else(|| format!("{:?} did not report a Java version", executable))?;
+    let major = major_version(&version).ok_or_else(|| format!("Unknown Java version {:?}", version))?;
+    Ok(JavaRuntime {
+        executable,
+        version,
+        major,
+        vendor,
+        source,
+    })
+}
+
+/// Resolves a bare `java` through `PATH`, following links so every runtime is found once.
+fn resolve_executable(executable: &Path) -> Option<PathBuf> {
+    if executable.components().count() == 1 {
+        let path = env::var_os("PATH")?;
+        let name = if cfg!(windows) && executable.extension().is_none() {
+            executable.with_extension("exe")
+        } else {
+            executable.to_path_buf()
+        };
+        return env::split_paths(&path)
+            .map(|directory| directory.join(&name))
+            .find(|candidate| candidate.is_file())
+            .and_then(|candidate| fs::canonicalize(candidate).ok());
+    }
+    fs::canonicalize(executable).ok().filter(|executable| executable.is_file())
+}
+
+/// The `java` executable of a runtime directory, which on macOS is inside the bundle.
+pub(crate) fn executable_in(home: &Path) -> Option<PathBuf> {
+    [home.join("bin"), home.join("Contents/Home/bin"), home.join("libexec/openjdk.jdk/Contents/Home/bin")]
+        .into_iter()
+        .map(|bin| bin.join(JAVA_EXECUTABLE))
+        .find(|executable| executable.is_file())
+}
+
+/// The homes of the runtimes the Windows registry knows of.
+#[cfg(windows)]
+fn registry_homes() -> Vec<PathBuf> {
+    let mut homes = Vec::new();
+    for key in REGISTRY_KEYS {
+        let Ok(output) = Command::new("reg").args(["query", key, "/s", "/v", "JavaHome"]).output() else {
+            continue;
+        };
+        // Lines look like `    JavaHome    REG_SZ    C:\Program Files\Java\jdk-21`
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((_, home)) = line.split_once("REG_SZ") {
+                homes.push(PathBuf::from(home.trim()));
+            }
+        }
+    }
+    homes
+}
+
+#[cfg(not(windows))]
+fn registry_homes() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Finds the Java runtimes installed on the machine: those the manager downloaded, the one of
+/// `JAVA_HOME`, the one on `PATH`, those in the usual install directories and, on Windows, those
+/// in the registry.
+///
+/// Runtimes reachable in several ways are listed once, with the first way they were found by.
+/// The newest runtimes come first.
+pub fn find_java_runtimes() -> Vec<JavaRuntime> {
+    let mut candidates: Vec<(PathBuf, JavaRuntimeSource)> = Vec::new();
+    if let Ok(entries) = fs::read_dir(RUNTIMES_DIRECTORY) {
+        for entry in entries.flatten().filter(|entry| !entry.file_name().to_string_lossy().starts_with('.')) {
+            if let Some(executable) = executable_in(&entry.path()) {
+                candidates.push((executable, JavaRuntimeSource::Managed));
+            }
+        }
+    }
+    if let Some(home) = env::var_os("JAVA_HOME") {
+        if let Some(executable) = executable_in(Path::new(&home)) {
+            candidates.push((executable, JavaRuntimeSource::JavaHome));
+        }
+    }
+    candidates.push((PathBuf::from("java"), JavaRuntimeSource::Path));
+
+    let mut directories: Vec<PathBuf> = RUNTIME_DIRECTORIES.iter().map(PathBuf::from).collect();
+    if let Some(home) = env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" }) {
+        directories.extend(HOME_RUNTIME_DIRECTORIES.iter().map(|directory| Path::new(&home).join(directory)));
+    }
+    for directory in directories {
+        let Ok(entries) = fs::read_dir(&directory) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if let Some(executable) = executable_in(&entry.path()) {
+                candidates.push((executable, JavaRuntimeSource::Directory));
+            }
+        }
+    }
+    for home in registry_homes() {
+        if let Some(executable) = executable_in(&home) {
+            candidates.push((executable, JavaRuntimeSource::Registry));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut runtimes = Vec::new();
+    for (executable, source) in candidates {
+        match inspect_java_runtime(&executable, source) {
+            Ok(runtime) => {
+                if seen.insert(runtime.executable.clone()) {
+                    runtimes.push(runtime);
+                }
+            }
+            Err(e) => debug!("Skipping the Java runtime {:?}: {}", executable, e),
+        }
+    }
+    runtimes.sort_by(|a, b| b.major.cmp(&a.major));
+    runtimes
+}
+
+/// Picks the installed runtime for a version of the game: the oldest one that is new enough, which
+/// is what mods of that version were built and tested against.
+pub fn choose_java_runtime(runtimes: &[JavaRuntime], game_version: &str) -> Option<JavaRuntime> {
+    let required = required_java_version(game_version).unwrap_or(0);
+    runtimes
+        .iter()
+        .filter(|runtime| runtime.major >= required)
+        .min_by_key(|runtime| runtime.major)
+        .cloned()
+}
+
+pub trait ServerJavaRuntime {
+    /// Identifies the runtime the server is started with.
+    ///
+    /// # Errors
+    /// Returns an error if the server has no runtime or it cannot be identified.
+    fn java_runtime_info(&self) -> Result<JavaRuntime, Box<dyn Error>>;
+
+    /// Pins the server to a runtime, from its next start. Identifying a runtime runs it, so only
+    /// administrators can pick an executable that is not one of [`find_java_runtimes`].
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not control the server or pick the executable, the
+    /// runtime cannot be identified, is too old for the version of the server, or the server
+    /// cannot be stored.
+    fn set_java_runtime(&mut self, executable: &Path) -> Result<JavaRuntime, Box<dyn Error>>;
+
+    /// Pins the server to the best installed runtime for its version.
+    ///
+    /// # Errors
+    /// Returns an error if no installed runtime is new enough.
+    fn select_java_runtime(&mut self) -> Result<JavaRuntime, Box<dyn Error>>;
+}
+
+impl ServerJavaRuntime for Server<u64> {
+    fn java_runtime_info(&self) -> Result<JavaRuntime, Box<dyn Error>> {
+        let executable = self.java_runtime.as_ref().ok_or("The server has no Java runtime")?;
+        inspect_java_runtime(executable, JavaRuntimeSource::Configured)
+    }
+
+    fn set_java_runtime(&mut self, executable: &Path) -> Result<JavaRuntime, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        let resolved = resolve_executable(executable);
+        let installed = find_java_runtimes()
+            .into_iter()
+            .find(|runtime| resolved.as_ref() == Some(&runtime.executable));
+        let runtime = match installed {
+            Some(runtime) => runtime,
+            None => {
+                require_admin()?;
+                inspect_java_runtime(executable, JavaRuntimeSource::Configured)?
+            }
+        };
+        pin_java_runtime(self, runtime)
+    }
+
+    fn select_java_runtime(&mut self) -> Result<JavaRuntime, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        let runtime = choose_java_runtime(&find_java_runtimes(), &self.minecraft_version).ok_or_else(|| {
+            format!(
+                "No installed Java runtime can start Minecraft {}, it needs Java {}",
+                self.minecraft_version,
+                required_java_version(&self.minecraft_version).unwrap_or(8)
+            )
+        })?;
+        pin_java_runtime(self, runtime)
+    }
+}
+
+fn pin_java_runtime(server: &mut Server<u64>, runtime: JavaRuntime) -> Result<JavaRuntime, Box<dyn Error>> {
+    check_java_compatibility(&runtime, &server.minecraft_version)?;
+    server.java_runtime = Some(runtime.executable.clone());
+    server.update()?;
+    info!("Set the Java runtime of server {:?} to Java {} at {:?}", server.name, runtime.version, runtime.executable);
+    Ok(runtime)
+}
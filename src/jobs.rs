@@ -0,0 +1,635 @@
+use crate::archive_extractor::ExtractionOptions;
+use crate::audit_log::{act_as, acting_user};
+use crate::backups::{add_backup_listener, create_backup, BackupEventKind, BackupOptions, BackupTrigger};
+use crate::curseforge_modpack::{add_modpack_import_listener, import_curseforge_modpack, ModpackImportOptions};
+use crate::event_bus::{publish, PanelEvent};
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_filesystem::ServerFilesystem;
+use crate::world_trim::{trim_world, WorldTrimOptions};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Once};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The most jobs running at once, of all kinds together.
+const MAX_RUNNING_JOBS: usize = 4;
+
+/// How many log lines a job keeps, the oldest are dropped first.
+const MAX_LOG_LINES: usize = 200;
+
+/// How many finished jobs are kept in the history.
+const MAX_JOB_HISTORY: i64 = 500;
+
+/// What the error of a cancelled job says, see [`check_cancelled`].
+const CANCELLED: &str = "The job was cancelled";
+
+static FORWARDER: Once = Once::new();
+
+lazy_static! {
+    static ref ACTIVE_JOBS: Mutex<HashMap<u64, Arc<ActiveJob>>> = Mutex::new(HashMap::new());
+    /// The number of running jobs of each kind.
+    static ref RUNNING: Mutex<HashMap<JobKind, usize>> = Mutex::new(HashMap::new());
+    static ref SLOT_FREED: Condvar = Condvar::new();
+}
+
+thread_local! {
+    static CURRENT_JOB: RefCell<Option<Arc<ActiveJob>>> = const { RefCell::new(None) };
+}
+
+/// What a job does, which decides how many of it run at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    Backup,
+    ArchiveExtraction,
+    ModpackInstall,
+    WorldTrim,
+}
+
+impl JobKind {
+    /// How many jobs of the kind run at once, further ones wait in the queue.
+    pub fn concurrency(&self) -> usize {
+        match self {
+            JobKind::Backup | JobKind::ArchiveExtraction => 2,
+            // Modpack imports report their steps without saying which import they belong to
+            JobKind::ModpackInstall => 1,
+            JobKind::WorldTrim => 1,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Backup => "backup",
+            JobKind::ArchiveExtraction => "archive_extraction",
+            JobKind::ModpackInstall => "modpack_install",
+            JobKind::WorldTrim => "world_trim",
+        }
+    }
+}
+
+impl FromStr for JobKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backup" => Ok(JobKind::Backup),
+            "archive_extraction" => Ok(JobKind::ArchiveExtraction),
+            "modpack_install" => Ok(JobKind::ModpackInstall),
+            "world_trim" => Ok(JobKind::WorldTrim),
+            _ => Err(format!("Unknown job kind {:?}", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for a job of the same kind to finish.
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Completed | JobState::Failed | JobState::Cancelled)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobState {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(JobState::Queued),
+            "running" => Ok(JobState::Running),
+            "completed" => Ok(JobState::Completed),
+            "failed" => Ok(JobState::Failed),
+            "cancelled" => Ok(JobState::Cancelled),
+            _ => Err(format!("Unknown job state {:?}", s).into()),
+        }
+    }
+}
+
+/// A long operation running in the background.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    /// The server the job works on, `None` for a modpack install until its server is created.
+    pub server_id: Option<u64>,
+    /// Who started the job, `None` for the manager itself.
+    pub user_id: Option<u64>,
+    pub description: String,
+    pub state: JobState,
+    /// Between 0 and 100.
+    pub progress: f64,
+    /// What the job did so far, the last [`MAX_LOG_LINES`] lines of it.
+    pub log: VecDeque<String>,
+    /// Why the job failed.
+    pub error: Option<String>,
+    /// In seconds since the Unix epoch.
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+}
+
+/// Which jobs to list, newest first.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JobQuery {
+    pub server_id: Option<u64>,
+    pub kind: Option<JobKind>,
+    pub state: Option<JobState>,
+    pub limit: usize,
+}
+
+impl Default for JobQuery {
+    fn default() -> Self {
+        Self {
+            server_id: None,
+            kind: None,
+            state: None,
+            limit: 50,
+        }
+    }
+}
+
+struct ActiveJob {
+    job: Mutex<Job>,
+    cancelled: AtomicBool,
+}
+
+impl ActiveJob {
+    fn snapshot(&self) -> Option<Job> {
+        self.job.lock().ok().map(|job| job.clone())
+    }
+
+    /// Changes the job and tells the event subscribers about it.
+    fn update(&self, change: impl FnOnce(&mut Job)) {
+        let Ok(mut job) = self.job.lock() else {
+            return;
+        };
+        change(&mut job);
+        publish(PanelEvent::Job(job.clone()));
+    }
+
+    fn set_progress(&self, percent: f64) {
+        let Ok(mut job) = self.job.lock() else {
+            return;
+        };
+        let previous = job.progress;
+        job.progress = percent.clamp(0.0, 100.0);
+        // Extractions report every entry, the subscribers only hear about whole percents
+        if job.progress.floor() != previous.floor() {
+            publish(PanelEvent::Job(job.clone()));
+        }
+    }
+}
+
+/// What a job uses to report how far it got and to notice that it was cancelled. Code running as
+/// part of a job reaches the same through [`report_progress`], [`log_line`] and [`check_cancelled`].
+pub struct JobContext {
+    job: Arc<ActiveJob>,
+}
+
+impl JobContext {
+    /// Sets how far the job got, between 0 and 100.
+    pub fn set_progress(&self, percent: f64) {
+        self.job.set_progress(percent);
+    }
+
+    pub fn log(&self, line: impl Into<String>) {
+        let line = line.into();
+        self.job.update(|job| {
+            if job.log.len() >= MAX_LOG_LINES {
+                job.log.pop_front();
+            }
+            job.log.push_back(line);
+        });
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.job.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fails once the job was cancelled, for the job to stop at a point where it can.
+    pub fn check_cancelled(&self) -> Result<(), Box<dyn Error>> {
+        if self.is_cancelled() {
+            return Err(CANCELLED.into());
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the table holding the history of the jobs. Jobs that were still queued or running
+/// when the manager stopped are marked as failed.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_job_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `jobs` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the job
+            kind TEXT NOT NULL,                                         -- What the job does
+            server_id INTEGER,                                          -- The server the job works on
+            user_id INTEGER,                                            -- The user who started it
+            description TEXT NOT NULL,                                  -- A summary of the job
+            state TEXT NOT NULL,                                        -- queued, running or how it finished
+            progress REAL NOT NULL DEFAULT 0,                           -- Between 0 and 100
+            log TEXT NOT NULL DEFAULT '[]',                             -- The log lines as a JSON array
+            error TEXT,                                                 -- Why the job failed
+            created_at INTEGER NOT NULL,                                -- Unix time the job was started
+            started_at INTEGER,                                         -- Unix time it left the queue
+            finished_at INTEGER                                         -- Unix time it finished
+        );
+        CREATE INDEX IF NOT EXISTS `jobs_server` ON `jobs` (server_id);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+
+    let mut statement = conn.prepare(
+        r#"UPDATE jobs SET state = 'failed', error = 'The manager stopped while the job was running', finished_at = ?
+        WHERE state IN ('queued', 'running')"#,
+    )?;
+    statement.bind((1, now() as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Starts a job in the background, queued until fewer than [`JobKind::concurrency`] jobs of its
+/// kind and [`MAX_RUNNING_JOBS`] jobs overall run. The job runs as the user acting on this
+/// thread, see [`crate::audit_log::act_as`].
+///
+/// `run` fails with the error of [`JobContext::check_cancelled`] to stop a cancelled job, any other
+/// error fails it.
+///
+/// # Errors
+/// Returns an error if the job cannot be stored.
+pub fn spawn_job(
+    kind: JobKind,
+    server_id: Option<u64>,
+    description: impl Into<String>,
+    run: impl FnOnce(&JobContext) -> Result<(), Box<dyn Error>> + Send + 'static,
+) -> Result<Job, Box<dyn Error>> {
+    let user_id = acting_user();
+    let created_at = now();
+    let description = description.into();
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO jobs (kind, server_id, user_id, description, state, created_at)
+        VALUES (?, ?, ?, ?, 'queued', ?)"#,
+    )?;
+    statement.bind((1, kind.as_str()))?;
+    statement.bind((2, server_id.map(|id| id as i64)))?;
+    statement.bind((3, user_id.map(|id| id as i64)))?;
+    statement.bind((4, description.as_str()))?;
+    statement.bind((5, created_at as i64))?;
+    statement.next()?;
+
+    let job = Job {
+        id: last_inserted_id("jobs")?,
+        kind,
+        server_id,
+        user_id,
+        description,
+        state: JobState::Queued,
+        progress: 0.0,
+        log: VecDeque::new(),
+        error: None,
+        created_at,
+        started_at: None,
+        finished_at: None,
+    };
+    let active = Arc::new(ActiveJob {
+        job: Mutex::new(job.clone()),
+        cancelled: AtomicBool::new(false),
+    });
+    ACTIVE_JOBS.lock().map_err(|_| "Failed to lock the jobs")?.insert(job.id, Arc::clone(&active));
+    info!("Queued job {} to {}", job.id, job.description);
+    publish(PanelEvent::Job(job.clone()));
+
+    let job_id = job.id;
+    thread::spawn(move || {
+        let _user = user_id.map(act_as);
+        let result = if claim_slot(kind, &active.cancelled) {
+            active.update(|job| {
+                job.state = JobState::Running;
+                job.started_at = Some(now());
+            });
+            if let Some(job) = active.snapshot() {
+                let _ = store_job(&job);
+            }
+            CURRENT_JOB.with(|current| *current.borrow_mut() = Some(Arc::clone(&active)));
+            let result = run(&JobContext {
+                job: Arc::clone(&active),
+            });
+            CURRENT_JOB.with(|current| *current.borrow_mut() = None);
+            release_slot(kind);
+            result
+        } else {
+            Err(CANCELLED.into())
+        };
+
+        let cancelled = active.cancelled.load(Ordering::SeqCst);
+        active.update(|job| {
+            job.finished_at = Some(now());
+            match result {
+                Ok(()) => {
+                    job.state = JobState::Completed;
+                    job.progress = 100.0;
+                }
+                Err(_) if cancelled => job.state = JobState::Cancelled,
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+        });
+        if let Some(job) = active.snapshot() {
+            match job.state {
+                JobState::Failed => warn!("Job {} failed: {}", job.id, job.error.as_deref().unwrap_or_default()),
+                state => info!("Job {} is {}", job.id, state.as_str()),
+            }
+            if let Err(e) = store_job(&job).and_then(|()| prune_job_history()) {
+                error!("Failed to store job {}: {}", job.id, e);
+            }
+        }
+        if let Ok(mut jobs) = ACTIVE_JOBS.lock() {
+            jobs.remove(&job_id);
+        }
+    });
+    Ok(job)
+}
+
+/// Returns a job, with its current progress if it is still running.
+///
+/// # Errors
+/// Returns an error if there is no job with the id.
+pub fn get_job(job_id: u64) -> Result<Job, Box<dyn Error>> {
+    if let Some(job) = ACTIVE_JOBS.lock().ok().and_then(|jobs| jobs.get(&job_id).and_then(|job| job.snapshot())) {
+        return Ok(job);
+    }
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM jobs WHERE id = ?")?;
+    statement.bind((1, job_id as i64))?;
+    let State::Row = statement.next()? else {
+        return Err(format!("No job with the id {}", job_id).into());
+    };
+    read_job(&statement)
+}
+
+/// Lists jobs, newest first, the running ones with their current progress.
+pub fn list_jobs(query: &JobQuery) -> Result<Vec<Job>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"SELECT * FROM jobs
+        WHERE (?1 IS NULL OR server_id = ?1) AND (?2 IS NULL OR kind = ?2) AND (?3 IS NULL OR state = ?3)
+        ORDER BY id DESC LIMIT ?4"#,
+    )?;
+    statement.bind((1, query.server_id.map(|id| id as i64)))?;
+    statement.bind((2, query.kind.map(|kind| kind.as_str())))?;
+    statement.bind((3, query.state.map(|state| state.as_str())))?;
+    statement.bind((4, query.limit.clamp(1, 500) as i64))?;
+
+    let active = ACTIVE_JOBS.lock().map(|jobs| jobs.clone()).unwrap_or_default();
+    let mut jobs = Vec::new();
+    while let State::Row = statement.next()? {
+        let job = read_job(&statement)?;
+        jobs.push(active.get(&job.id).and_then(|active| active.snapshot()).unwrap_or(job));
+    }
+    Ok(jobs)
+}
+
+/// Cancels a job. A queued job never starts, a running one stops at the next point it checks,
+/// leaving nothing half done behind.
+///
+/// # Errors
+/// Returns an error if the job is not queued or running.
+pub fn cancel_job(job_id: u64) -> Result<(), Box<dyn Error>> {
+    let jobs = ACTIVE_JOBS.lock().map_err(|_| "Failed to lock the jobs")?;
+    let job = jobs.get(&job_id).ok_or_else(|| format!("Job {} is not running", job_id))?;
+    job.cancelled.store(true, Ordering::SeqCst);
+    SLOT_FREED.notify_all();
+    info!("Cancelling job {}", job_id);
+    Ok(())
+}
+
+/// Sets how far the job running on this thread got, between 0 and 100. Outside of a job it does nothing.
+pub(crate) fn report_progress(percent: f64) {
+    with_current_job(|job| job.set_progress(percent));
+}
+
+/// Adds a line to the log of the job running on this thread.
+pub(crate) fn log_line(line: impl Into<String>) {
+    let line = line.into();
+    with_current_job(|job| job.log(line));
+}
+
+/// Fails once the job running on this thread was cancelled. Long operations call it between
+/// steps they can stop after.
+pub(crate) fn check_cancelled() -> Result<(), Box<dyn Error>> {
+    CURRENT_JOB.with(|current| match current.borrow().as_ref() {
+        Some(job) if job.cancelled.load(Ordering::SeqCst) => Err(CANCELLED.into()),
+        _ => Ok(()),
+    })
+}
+
+fn with_current_job(action: impl FnOnce(&JobContext)) {
+    let job = CURRENT_JOB.with(|current| current.borrow().clone());
+    if let Some(job) = job {
+        action(&JobContext { job });
+    }
+}
+
+/// Creates a backup of a server as a job.
+pub fn start_backup_job(server: &Server<u64>, options: BackupOptions) -> Result<Job, Box<dyn Error>> {
+    authorize(server, Capability::ManageBackups)?;
+    forward_progress();
+    let server = server.clone();
+    spawn_job(JobKind::Backup, Some(server.id), format!("Back up {}", server.name), move |job| {
+        let manifest = create_backup(&server, &options, BackupTrigger::Manual)?;
+        job.log(format!("Created the backup {}", manifest.id));
+        Ok(())
+    })
+}
+
+/// Extracts an archive inside a server directory as a job.
+pub fn start_extraction_job(
+    server: &Server<u64>,
+    archive_path: PathBuf,
+    destination_path: PathBuf,
+    options: ExtractionOptions,
+) -> Result<Job, Box<dyn Error>> {
+    let server = server.clone();
+    let description = format!("Extract {} on {}", archive_path.display(), server.name);
+    spawn_job(JobKind::ArchiveExtraction, Some(server.id), description, move |job| {
+        let summary = server.extract_archive(&archive_path, &destination_path, &options, |progress| {
+            job.set_progress(progress.percentage())
+        })?;
+        job.log(format!("Extracted {} entries, skipped {}", summary.extracted, summary.skipped));
+        Ok(())
+    })
+}
+
+/// Imports a CurseForge modpack as a new server, as a job.
+pub fn start_modpack_install_job(options: ModpackImportOptions) -> Result<Job, Box<dyn Error>> {
+    forward_progress();
+    let description = format!("Install the modpack {} as {}", options.archive.display(), options.server.name);
+    spawn_job(JobKind::ModpackInstall, None, description, move |job| {
+        let server = import_curseforge_modpack(&options)?;
+        job.log(format!("Created the server {}", server.name));
+        Ok(())
+    })
+}
+
+/// Trims a world of a server as a job, see [`trim_world`].
+pub fn start_world_trim_job(
+    server: &Server<u64>,
+    world: SandboxedPath,
+    options: WorldTrimOptions,
+) -> Result<Job, Box<dyn Error>> {
+    let description = format!("Trim the world {} of {}", world, server.name);
+    spawn_job(JobKind::WorldTrim, Some(server.id), description, move |job| {
+        let report = trim_world(&world, &options)?;
+        job.log(format!(
+            "Trimmed {} of {} chunks, freeing {} bytes",
+            report.chunks_trimmed, report.chunks_scanned, report.bytes_freed
+        ));
+        Ok(())
+    })
+}
+
+/// Turns the events of backups and modpack imports into the progress of the jobs running them.
+/// The events come from the thread running the job.
+fn forward_progress() {
+    FORWARDER.call_once(|| {
+        add_backup_listener(|event| match event.kind {
+            BackupEventKind::Progress if event.total_bytes > 0 => {
+                report_progress(event.bytes_processed as f64 / event.total_bytes as f64 * 100.0)
+            }
+            BackupEventKind::Progress => {}
+            _ => log_line(format!("Backup {} {:?}", event.backup_id, event.kind)),
+        });
+        add_modpack_import_listener(|event| {
+            // The job gets the server once the import created it
+            if let Some(server_id) = event.server_id {
+                with_current_job(|job| {
+                    if job.job.snapshot().is_some_and(|job| job.server_id.is_none()) {
+                        job.job.update(|job| job.server_id = Some(server_id));
+                    }
+                });
+            }
+            if event.total > 0 {
+                report_progress(event.current as f64 / event.total as f64 * 100.0);
+            }
+            log_line(event.message.clone());
+        });
+    });
+}
+
+/// Waits until a job of a kind may run. Returns `false` if it was cancelled while waiting.
+fn claim_slot(kind: JobKind, cancelled: &AtomicBool) -> bool {
+    let Ok(mut running) = RUNNING.lock() else {
+        return false;
+    };
+    loop {
+        if cancelled.load(Ordering::SeqCst) {
+            return false;
+        }
+        let of_kind = running.get(&kind).copied().unwrap_or(0);
+        if of_kind < kind.concurrency() && running.values().sum::<usize>() < MAX_RUNNING_JOBS {
+            running.insert(kind, of_kind + 1);
+            return true;
+        }
+        running = match SLOT_FREED.wait(running) {
+            Ok(running) => running,
+            Err(_) => return false,
+        };
+    }
+}
+
+fn release_slot(kind: JobKind) {
+    if let Ok(mut running) = RUNNING.lock() {
+        if let Some(count) = running.get_mut(&kind) {
+            *count = count.saturating_sub(1);
+        }
+    }
+    SLOT_FREED.notify_all();
+}
+
+fn store_job(job: &Job) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"UPDATE jobs SET server_id = ?, state = ?, progress = ?, log = ?, error = ?, started_at = ?, finished_at = ?
+        WHERE id = ?"#,
+    )?;
+    statement.bind((1, job.server_id.map(|id| id as i64)))?;
+    statement.bind((2, job.state.as_str()))?;
+    statement.bind((3, job.progress))?;
+    statement.bind((4, serde_json::to_string(&job.log)?.as_str()))?;
+    statement.bind((5, job.error.as_deref()))?;
+    statement.bind((6, job.started_at.map(|at| at as i64)))?;
+    statement.bind((7, job.finished_at.map(|at| at as i64)))?;
+    statement.bind((8, job.id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Keeps the newest [`MAX_JOB_HISTORY`] finished jobs.
+fn prune_job_history() -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"DELETE FROM jobs WHERE state IN ('completed', 'failed', 'cancelled') AND id NOT IN (
+            SELECT id FROM jobs WHERE state IN ('completed', 'failed', 'cancelled') ORDER BY id DESC LIMIT ?
+        )"#,
+    )?;
+    statement.bind((1, MAX_JOB_HISTORY))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn read_job(statement: &sqlite::Statement) -> Result<Job, Box<dyn Error>> {
+    Ok(Job {
+        id: statement.read::<i64, _>("id")? as u64,
+        kind: statement.read::<String, _>("kind")?.parse()?,
+        server_id: statement.read::<Option<i64>, _>("server_id")?.map(|id| id as u64),
+        user_id: statement.read::<Option<i64>, _>("user_id")?.map(|id| id as u64),
+        description: statement.read::<String, _>("description")?,
+        state: statement.read::<String, _>("state")?.parse()?,
+        progress: statement.read::<f64, _>("progress")?,
+        log: serde_json::from_str(&statement.read::<String, _>("log")?)?,
+        error: statement.read::<Option<String>, _>("error")?,
+        created_at: statement.read::<i64, _>("created_at")? as u64,
+        started_at: statement.read::<Option<i64>, _>("started_at")?.map(|at| at as u64),
+        finished_at: statement.read::<Option<i64>, _>("finished_at")?.map(|at| at as u64),
+    })
+}
@@ -0,0 +1,260 @@
+use crate::config::{get_config, set_config, ConfigScope};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+
+/// The setting holding the JVM flag preset of a server.
+const CONFIG_KEY: &str = "jvm_flags";
+
+/// Aikar's flags, the G1 tuning Paper recommends for heaps up to 12 GB.
+const AIKAR_FLAGS: &[&str] = &[
+    "-XX:+UseG1GC",
+    "-XX:+ParallelRefProcEnabled",
+    "-XX:MaxGCPauseMillis=200",
+    "-XX:+UnlockExperimentalVMOptions",
+    "-XX:+DisableExplicitGC",
+    "-XX:+AlwaysPreTouch",
+    "-XX:G1NewSizePercent=30",
+    "-XX:G1MaxNewSizePercent=40",
+    "-XX:G1HeapRegionSize=8M",
+    "-XX:G1ReservePercent=20",
+    "-XX:G1HeapWastePercent=5",
+    "-XX:G1MixedGCCountTarget=4",
+    "-XX:InitiatingHeapOccupancyPercent=15",
+    "-XX:G1MixedGCLiveThresholdPercent=90",
+    "-XX:G1RSetUpdatingPauseTimePercent=5",
+    "-XX:SurvivorRatio=32",
+    "-XX:+PerfDisableSharedMem",
+    "-XX:MaxTenuringThreshold=1",
+    "-Dusing.aikars.flags=https://mcflags.emc.gs",
+    "-Daikars.new.flags=true",
+];
+
+/// The values of Aikar's flags that change for heaps above 12 GB, where the young generation can
+/// be larger and fewer, larger regions keep the overhead down.
+const AIKAR_LARGE_HEAP_FLAGS: &[&str] = &[
+    "-XX:G1NewSizePercent=40",
+    "-XX:G1MaxNewSizePercent=50",
+    "-XX:G1HeapRegionSize=16M",
+    "-XX:G1ReservePercent=15",
+    "-XX:InitiatingHeapOccupancyPercent=20",
+];
+
+/// Options that make the JVM run programs or load code besides the game, which would let anyone
+/// who may change the flags run anything as the manager.
+const FORBIDDEN_OPTIONS: &[&str] = &["OnOutOfMemoryError", "OnError"];
+const FORBIDDEN_PREFIXES: &[&str] = &["-javaagent:", "-agentpath:", "-agentlib:"];
+
+/// The heap from which on the large heap values of Aikar's flags are used, in GB.
+const AIKAR_LARGE_HEAP_GB: u64 = 12;
+
+/// ZGC keeps pauses below a millisecond at any heap size. `ZGenerational` needs Java 21, later
+/// versions only generate generationally and ignore it with a warning.
+const ZGC_FLAGS: &[&str] = &[
+    "-XX:+UseZGC",
+    "-XX:+ZGenerational",
+    "-XX:+AlwaysPreTouch",
+    "-XX:+DisableExplicitGC",
+    "-XX:+PerfDisableSharedMem",
+];
+
+/// Shenandoah collects concurrently like ZGC, and is also available in Java 17 builds of most vendors.
+const SHENANDOAH_FLAGS: &[&str] = &[
+    "-XX:+UseShenandoahGC",
+    "-XX:+AlwaysPreTouch",
+    "-XX:+DisableExplicitGC",
+    "-XX:+PerfDisableSharedMem",
+];
+
+/// A set of garbage collector flags a server is started with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JvmFlagPreset {
+    /// No flags besides the heap sizes, the JVM picks its defaults.
+    #[default]
+    None,
+    Aikar,
+    Zgc,
+    Shenandoah,
+}
+
+impl JvmFlagPreset {
+    /// The flags of the preset for a maximum heap of `max_ram` gigabytes.
+    pub fn flags(&self, max_ram: u64) -> Vec<String> {
+        let mut flags: Vec<String> = match self {
+            JvmFlagPreset::None => Vec::new(),
+            JvmFlagPreset::Aikar => AIKAR_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+            JvmFlagPreset::Zgc => ZGC_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+            JvmFlagPreset::Shenandoah => SHENANDOAH_FLAGS.iter().map(|flag| flag.to_string()).collect(),
+        };
+        match self {
+            JvmFlagPreset::Aikar if max_ram > AIKAR_LARGE_HEAP_GB => {
+                override_flags(&mut flags, AIKAR_LARGE_HEAP_FLAGS.iter().map(|flag| flag.to_string()));
+            }
+            // The collector starts early enough to keep up with allocation before the heap is full
+            JvmFlagPreset::Zgc => flags.push(format!("-XX:SoftMaxHeapSize={}M", max_ram * 1024 * 4 / 5)),
+            _ => {}
+        }
+        flags
+    }
+}
+
+/// The JVM flags of a server: a preset, and changes to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JvmFlagSettings {
+    pub preset: JvmFlagPreset,
+    /// Flags added after those of the preset. A flag the preset has already, like
+    /// `-XX:MaxGCPauseMillis=100`, replaces it.
+    pub extra: Vec<String>,
+    /// Flags of the preset that are left out, by name like `AlwaysPreTouch` or `-Daikars.new.flags`.
+    pub removed: Vec<String>,
+}
+
+impl JvmFlagSettings {
+    /// The flags the server is started with for a maximum heap of `max_ram` gigabytes.
+    pub fn flags(&self, max_ram: u64) -> Vec<String> {
+        let mut flags = self.preset.flags(max_ram);
+        flags.retain(|flag| !self.removed.iter().any(|removed| flag_name(removed) == flag_name(flag)));
+        override_flags(&mut flags, self.extra.iter().cloned());
+        flags
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        for flag in &self.extra {
+            if !flag.starts_with('-') || flag.contains(char::is_whitespace) {
+                return Err(format!("{:?} is not a JVM flag", flag).into());
+            }
+            if flag.starts_with("-Xms") || flag.starts_with("-Xmx") {
+                return Err("The heap sizes are set with the memory settings of the server, not as flags".into());
+            }
+            let runs_code = (flag.starts_with("-XX:") && FORBIDDEN_OPTIONS.contains(&flag_name(flag)))
+                || FORBIDDEN_PREFIXES.iter().any(|prefix| flag.starts_with(prefix));
+            if runs_code {
+                return Err(format!("{:?} runs code besides the game and cannot be added", flag).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What identifies a flag regardless of its value, `G1HeapRegionSize` for `-XX:G1HeapRegionSize=8M`
+/// and `AlwaysPreTouch` for both `-XX:+AlwaysPreTouch` and `-XX:-AlwaysPreTouch`.
+fn flag_name(flag: &str) -> &str {
+    let flag = flag.trim();
+    if let Some(option) = flag.strip_prefix("-XX:") {
+        let option = option.trim_start_matches(['+', '-']);
+        return option.split('=').next().unwrap_or(option);
+    }
+    if !flag.starts_with('-') {
+        return flag;
+    }
+    // `-Dkey=value` and the like, and plain names given for removal
+    flag.split('=').next().unwrap_or(flag)
+}
+
+/// Adds flags, replacing those with the same name in place so the order stays readable.
+fn override_flags(flags: &mut Vec<String>, replacements: impl Iterator<Item = String>) {
+    for replacement in replacements {
+        match flags.iter_mut().find(|flag| flag_name(flag) == flag_name(&replacement)) {
+            Some(flag) => *flag = replacement,
+            None => flags.push(replacement),
+        }
+    }
+}
+
+/// The flag settings of a server with the flags they generate for its heap.
+#[derive(Debug, Clone, Serialize)]
+pub struct JvmFlags {
+    pub settings: JvmFlagSettings,
+    /// The flags passed to Java before the Java arguments of the server, which override them.
+    pub flags: Vec<String>,
+}
+
+/// Reads the JVM flag settings of a server, no preset if none were set.
+pub fn get_jvm_flag_settings(server_id: u64) -> Result<JvmFlagSettings, Box<dyn Error>> {
+    Ok(get_config(ConfigScope::Server(server_id), CONFIG_KEY)?.unwrap_or_default())
+}
+
+/// Stores the JVM flag settings of a server, used from its next start.
+///
+/// # Errors
+/// Returns an error if the acting user may not control the server, an extra flag is not one, sets
+/// the heap size or runs other code, or the settings cannot be stored.
+pub fn set_jvm_flag_settings(server_id: u64, settings: &JvmFlagSettings) -> Result<(), Box<dyn Error>> {
+    settings.validate()?;
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::ControlServer)?;
+    set_config(ConfigScope::Server(server_id), CONFIG_KEY, settings)?;
+    info!("Set the JVM flags of server {} to the {:?} preset", server_id, settings.preset);
+    Ok(())
+}
+
+pub trait ServerJvmFlags {
+    /// The JVM flag settings of the server and the flags they generate for its current heap.
+    fn jvm_flags(&self) -> Result<JvmFlags, Box<dyn Error>>;
+
+    /// Changes the JVM flags of the server, from its next start.
+    fn set_jvm_flags(&self, settings: &JvmFlagSettings) -> Result<JvmFlags, Box<dyn Error>>;
+}
+
+impl ServerJvmFlags for Server<u64> {
+    fn jvm_flags(&self) -> Result<JvmFlags, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        let settings = get_jvm_flag_settings(self.id)?;
+        Ok(JvmFlags {
+            flags: settings.flags(self.max_ram),
+            settings,
+        })
+    }
+
+    fn set_jvm_flags(&self, settings: &JvmFlagSettings) -> Result<JvmFlags, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        set_jvm_flag_settings(self.id, settings)?;
+        Ok(JvmFlags {
+            flags: settings.flags(self.max_ram),
+            settings: settings.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_extra(flag: &str) -> JvmFlagSettings {
+        JvmFlagSettings {
+            extra: vec![flag.to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tuning_flags_are_accepted() {
+        for flag in ["-XX:MaxGCPauseMillis=100", "-XX:+UseStringDeduplication", "-Dfile.encoding=UTF-8"] {
+            assert!(with_extra(flag).validate().is_ok(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn flags_running_other_code_are_rejected() {
+        for flag in [
+            "-XX:OnOutOfMemoryError=sh -c id",
+            "-XX:OnOutOfMemoryError=/tmp/run",
+            "-XX:OnError=/tmp/run",
+            "-javaagent:/tmp/agent.jar",
+            "-agentpath:/tmp/agent.so",
+            "-agentlib:jdwp=transport=dt_socket,server=y,address=5005",
+        ] {
+            assert!(with_extra(flag).validate().is_err(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn heap_sizes_are_rejected() {
+        assert!(with_extra("-Xmx4G").validate().is_err());
+        assert!(with_extra("-Xms1G").validate().is_err());
+    }
+}
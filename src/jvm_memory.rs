@@ -0,0 +1,202 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::ServerProcess;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use sysinfo::System;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// What the JVM needs beyond the heap, for its own code, threads and the native memory of the game.
+const JVM_OVERHEAD_GB: u64 = 1;
+
+/// The heap sizes a server is started with, in whole gigabytes like `-Xms2G -Xmx4G`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemorySettings {
+    /// The heap the JVM reserves up front, passed as `-Xms`.
+    pub min_ram: u64,
+    /// The largest the heap may grow, passed as `-Xmx`.
+    pub max_ram: u64,
+}
+
+/// A rule the heap sizes are derived from the memory of the machine with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MemoryPreset {
+    /// Gives the server all memory but `reserve` gigabytes, which are left to the operating system.
+    LeaveForSystem { reserve: u64 },
+    /// Gives the server a share of the memory, in percent.
+    Share { percent: u8 },
+}
+
+impl Default for MemoryPreset {
+    fn default() -> Self {
+        MemoryPreset::LeaveForSystem { reserve: 2 }
+    }
+}
+
+impl MemoryPreset {
+    /// The heap sizes of this preset on a machine with `total` bytes of memory. Both are the same,
+    /// a heap that never has to grow avoids the pauses of resizing it.
+    pub fn settings(&self, total: u64) -> Result<MemorySettings, Box<dyn Error>> {
+        let total_gb = total / GIB;
+        let heap = match *self {
+            MemoryPreset::LeaveForSystem { reserve } => total_gb.saturating_sub(reserve + JVM_OVERHEAD_GB),
+            MemoryPreset::Share { percent } if percent == 0 || percent > 100 => {
+                return Err(format!("A share of {}% of the memory is not possible", percent).into());
+            }
+            MemoryPreset::Share { percent } => (total_gb * u64::from(percent) / 100).saturating_sub(JVM_OVERHEAD_GB),
+        };
+        if heap == 0 {
+            return Err(format!("{} GB of memory leave no room for a server with this preset", total_gb).into());
+        }
+        Ok(MemorySettings {
+            min_ram: heap,
+            max_ram: heap,
+        })
+    }
+}
+
+/// The memory of the machine running the servers, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SystemMemory {
+    pub total: u64,
+    /// The memory not used by any process right now, including caches the system gives up on demand.
+    pub available: u64,
+}
+
+/// Reads the physical memory of the machine.
+pub fn system_memory() -> SystemMemory {
+    let mut system = System::new();
+    system.refresh_memory();
+    SystemMemory {
+        total: system.total_memory(),
+        available: system.available_memory(),
+    }
+}
+
+/// Heap sizes checked against the memory of the machine.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryCheck {
+    pub settings: MemorySettings,
+    pub system: SystemMemory,
+    /// Problems that do not keep the server from starting, but likely from running well.
+    pub warnings: Vec<String>,
+}
+
+/// Checks heap sizes against the memory of the machine.
+///
+/// # Errors
+/// Returns an error for heap sizes the JVM refuses to start with.
+pub fn check_memory(settings: &MemorySettings) -> Result<MemoryCheck, Box<dyn Error>> {
+    if settings.max_ram == 0 {
+        return Err("The maximum heap has to be at least 1 GB".into());
+    }
+    if settings.min_ram > settings.max_ram {
+        return Err(format!(
+            "The minimum heap of {} GB is larger than the maximum of {} GB, the JVM does not start like that",
+            settings.min_ram, settings.max_ram
+        )
+        .into());
+    }
+
+    let system = system_memory();
+    let total_gb = system.total as f64 / GIB as f64;
+    let needed = (settings.max_ram + JVM_OVERHEAD_GB) * GIB;
+    let mut warnings = Vec::new();
+    if settings.max_ram * GIB > system.total {
+        warnings.push(format!(
+            "The maximum heap of {} GB exceeds the {:.1} GB of physical memory, the system will swap or \
+            kill the server once the heap grows that large",
+            settings.max_ram, total_gb
+        ));
+    } else if needed > system.total {
+        warnings.push(format!(
+            "A heap of {} GB and the memory the JVM needs besides it use up all {:.1} GB of physical memory",
+            settings.max_ram, total_gb
+        ));
+    } else if needed + 2 * GIB > system.total {
+        warnings.push(format!(
+            "A heap of {} GB leaves less than 2 GB of the {:.1} GB of memory to the operating system",
+            settings.max_ram, total_gb
+        ));
+    }
+    if settings.min_ram * GIB > system.available {
+        warnings.push(format!(
+            "Only {:.1} GB of memory are free right now, less than the minimum heap of {} GB",
+            system.available as f64 / GIB as f64,
+            settings.min_ram
+        ));
+    }
+    Ok(MemoryCheck {
+        settings: *settings,
+        system,
+        warnings,
+    })
+}
+
+/// Removes `-Xms` and `-Xmx` from Java arguments, which the heap sizes of the server replace.
+fn strip_heap_arguments(arguments: &str) -> Result<String, Box<dyn Error>> {
+    let arguments = shell_words::split(arguments).map_err(|_| "Invalid Java arguments")?;
+    let kept: Vec<String> = arguments
+        .into_iter()
+        .filter(|argument| !argument.starts_with("-Xms") && !argument.starts_with("-Xmx"))
+        .collect();
+    Ok(shell_words::join(kept))
+}
+
+pub trait ServerMemory {
+    /// The heap sizes the server starts with.
+    fn memory_settings(&self) -> MemorySettings;
+
+    /// Changes the heap sizes the server starts with, from its next start.
+    ///
+    /// `-Xms` and `-Xmx` added to the Java arguments by hand are removed, they were overridden by
+    /// the heap sizes of the server anyway.
+    ///
+    /// # Errors
+    /// Returns an error if the JVM would refuse the heap sizes, or the server cannot be stored.
+    fn set_memory(&mut self, settings: &MemorySettings) -> Result<MemoryCheck, Box<dyn Error>>;
+
+    /// Sets the heap sizes from a preset and the memory of the machine.
+    ///
+    /// # Errors
+    /// Returns an error if the machine has too little memory for the preset, or the server cannot be stored.
+    fn apply_memory_preset(&mut self, preset: &MemoryPreset) -> Result<MemoryCheck, Box<dyn Error>>;
+}
+
+impl ServerMemory for Server<u64> {
+    fn memory_settings(&self) -> MemorySettings {
+        MemorySettings {
+            min_ram: self.min_ram,
+            max_ram: self.max_ram,
+        }
+    }
+
+    fn set_memory(&mut self, settings: &MemorySettings) -> Result<MemoryCheck, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        let check = check_memory(settings)?;
+        self.min_ram = settings.min_ram;
+        self.max_ram = settings.max_ram;
+        if let Some(arguments) = &self.java_arguments {
+            let stripped = strip_heap_arguments(arguments)?;
+            self.java_arguments = if stripped.is_empty() { None } else { Some(stripped) };
+        }
+        self.update()?;
+        info!(
+            "Set the heap of server {:?} to {} - {} GB{}",
+            self.name,
+            settings.min_ram,
+            settings.max_ram,
+            if self.is_server_running() { ", from its next start" } else { "" }
+        );
+        Ok(check)
+    }
+
+    fn apply_memory_preset(&mut self, preset: &MemoryPreset) -> Result<MemoryCheck, Box<dyn Error>> {
+        let settings = preset.settings(system_memory().total)?;
+        self.set_memory(&settings)
+    }
+}
@@ -0,0 +1,315 @@
+use crate::audit_log::acting_user;
+use crate::config::{get_config, set_config, ConfigScope};
+use crate::jvm_flags::get_jvm_flag_settings;
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::users::get_user;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The setting holding the launch template of a server.
+const CONFIG_KEY: &str = "launch_template";
+
+/// The variables a launch template can use.
+const TEMPLATE_VARIABLES: &[&str] = &[
+    "java",
+    "jar",
+    "memory",
+    "extra_args",
+    "minecraft_args",
+    "min_ram",
+    "max_ram",
+    "server_dir",
+];
+
+/// The argument file Forge and NeoForge read the JVM arguments of the user from.
+pub const USER_JVM_ARGS_FILE: &str = "user_jvm_args.txt";
+
+const MANAGED_ARGS_BEGIN: &str = "# BEGIN arguments managed by the server manager";
+const MANAGED_ARGS_END: &str = "# END arguments managed by the server manager";
+
+/// How long a hook may run before it is killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How a server is launched, when the managed command is not enough.
+///
+/// A template is a command line with variables in braces:
+/// * `{java}` - The Java runtime of the server.
+/// * `{jar}` - The start file of the server, the jar or the argument file.
+/// * `{memory}` - The heap sizes, `-Xms2G -Xmx4G`.
+/// * `{extra_args}` - The flags of the JVM flag preset followed by the Java arguments of the server.
+/// * `{minecraft_args}` - The Minecraft arguments of the server.
+/// * `{min_ram}`, `{max_ram}` - The heap sizes in GB.
+/// * `{server_dir}` - The directory of the server.
+///
+/// Variables holding several arguments expand into separate arguments when they stand alone, like
+/// `{java} {memory} -jar {jar} nogui`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LaunchTemplate {
+    /// The command line the server is started with, the managed command if `None`.
+    pub command: Option<String>,
+    /// Shell commands run in the server directory before it starts. A failing one keeps the server
+    /// from starting.
+    pub pre_start: Vec<String>,
+    /// Shell commands run in the server directory after it exited, however it exited.
+    pub post_stop: Vec<String>,
+    /// Environment variables the server is started with, on top of those of the manager.
+    pub environment: BTreeMap<String, String>,
+}
+
+impl LaunchTemplate {
+    /// Forge's way of launching, with the heap and flags written to `user_jvm_args.txt` and the
+    /// class path taken from the argument file the installer generated.
+    pub fn forge() -> Self {
+        LaunchTemplate {
+            command: Some(format!("{{java}} @{} @{{jar}} {{minecraft_args}}", USER_JVM_ARGS_FILE)),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(command) = &self.command {
+            let arguments = shell_words::split(command).map_err(|_| "The launch template is not a valid command line")?;
+            if arguments.is_empty() {
+                return Err("The launch template is empty".into());
+            }
+            for argument in &arguments {
+                for name in variables(argument) {
+                    if !TEMPLATE_VARIABLES.contains(&name) {
+                        return Err(format!("Unknown variable {{{}}} in the launch template", name).into());
+                    }
+                }
+            }
+        }
+        for name in self.environment.keys() {
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(format!("{:?} is not a valid environment variable name", name).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchHook {
+    PreStart,
+    PostStop,
+}
+
+/// The names of the variables in an argument, in order.
+fn variables(argument: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = argument;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        names.push(&rest[start + 1..start + length]);
+        rest = &rest[start + length + 1..];
+    }
+    names
+}
+
+/// The values of the template variables for a server, each as the arguments it expands to.
+fn variable_values(server: &Server<u64>) -> Result<Vec<(&'static str, Vec<String>)>, Box<dyn Error>> {
+    let split = |arguments: &Option<String>, what: &str| -> Result<Vec<String>, Box<dyn Error>> {
+        match arguments {
+            Some(arguments) => Ok(shell_words::split(arguments).map_err(|_| format!("Invalid {}", what))?),
+            None => Ok(Vec::new()),
+        }
+    };
+    let mut extra_args = get_jvm_flag_settings(server.id)?.flags(server.max_ram);
+    extra_args.extend(split(&server.java_arguments, "Java arguments")?);
+    let java = server.java_runtime.clone().unwrap_or_else(|| PathBuf::from("java"));
+    let jar = server.start_script.clone().ok_or("Start script not set")?;
+    Ok(vec![
+        ("java", vec![java.to_string_lossy().to_string()]),
+        ("jar", vec![jar.to_string_lossy().to_string()]),
+        ("memory", memory_arguments(server)),
+        ("extra_args", extra_args),
+        ("minecraft_args", split(&server.minecraft_arguments, "Minecraft arguments")?),
+        ("min_ram", vec![server.min_ram.to_string()]),
+        ("max_ram", vec![server.max_ram.to_string()]),
+        ("server_dir", vec![server.directory.to_string_lossy().to_string()]),
+    ])
+}
+
+fn memory_arguments(server: &Server<u64>) -> Vec<String> {
+    vec![format!("-Xms{}G", server.min_ram), format!("-Xmx{}G", server.max_ram)]
+}
+
+/// Expands a launch template into the program and arguments of the server.
+///
+/// # Errors
+/// Returns an error if the template is not a valid command line or uses unknown variables.
+pub fn render_launch_command(server: &Server<u64>, template: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let values = variable_values(server)?;
+    let value = |name: &str| values.iter().find(|(variable, _)| *variable == name).map(|(_, value)| value);
+    let mut command = Vec::new();
+    for argument in shell_words::split(template).map_err(|_| "The launch template is not a valid command line")? {
+        let names = variables(&argument);
+        if names.len() == 1 && argument == format!("{{{}}}", names[0]) {
+            let expanded = value(names[0]).ok_or_else(|| format!("Unknown variable {{{}}}", names[0]))?;
+            command.extend(expanded.iter().cloned());
+            continue;
+        }
+        let mut rendered = argument.clone();
+        for name in names {
+            let expanded = value(name).ok_or_else(|| format!("Unknown variable {{{}}}", name))?;
+            rendered = rendered.replace(&format!("{{{}}}", name), &expanded.join(" "));
+        }
+        command.push(rendered);
+    }
+    if command.is_empty() {
+        return Err("The launch template expands to an empty command".into());
+    }
+    Ok(command)
+}
+
+/// Builds the process a server is started with from its launch template.
+///
+/// A template reading `user_jvm_args.txt` gets the heap and flags of the server written to it
+/// first, so they are managed the same way as with the managed command.
+pub(crate) fn launch_command(server: &Server<u64>, template: &str) -> Result<Command, Box<dyn Error>> {
+    if template.contains(&format!("@{}", USER_JVM_ARGS_FILE)) {
+        write_user_jvm_args(server)?;
+    }
+    let rendered = render_launch_command(server, template)?;
+    let (program, arguments) = rendered.split_first().ok_or("The launch template expands to an empty command")?;
+    let mut command = Command::new(program);
+    command.args(arguments);
+    command.current_dir(&server.directory);
+    Ok(command)
+}
+
+/// Writes the heap and JVM flags of a server to its `user_jvm_args.txt`, between markers so the
+/// arguments added by hand are kept.
+pub fn write_user_jvm_args(server: &Server<u64>) -> Result<(), Box<dyn Error>> {
+    let path = server.directory.join(USER_JVM_ARGS_FILE);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<String> = Vec::new();
+    let mut managed = false;
+    for line in existing.lines() {
+        if line.trim() == MANAGED_ARGS_BEGIN {
+            managed = true;
+        } else if line.trim() == MANAGED_ARGS_END {
+            managed = false;
+        } else if !managed {
+            lines.push(line.to_string());
+        }
+    }
+    lines.push(MANAGED_ARGS_BEGIN.to_string());
+    lines.extend(memory_arguments(server));
+    lines.extend(get_jvm_flag_settings(server.id)?.flags(server.max_ram));
+    lines.push(MANAGED_ARGS_END.to_string());
+    fs::write(&path, lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+/// Runs the hooks of a server one after another, in the server directory, see [`run_shell_command`].
+///
+/// # Errors
+/// Returns an error for the first hook that fails or runs longer than five minutes, the hooks after
+/// it are not run.
+pub(crate) fn run_launch_hooks(
+    server: &Server<u64>,
+    hook: LaunchHook,
+    commands: &[String],
+) -> Result<(), Box<dyn Error>> {
+    for command in commands {
+        run_shell_command(server, &format!("{:?} hook", hook), command)?;
+    }
+    Ok(())
+}
+
+/// Runs a shell command in the directory of a server. The server is passed to it in the
+/// `SERVER_ID`, `SERVER_NAME` and `SERVER_DIR` environment variables.
+///
+/// # Errors
+/// Returns an error if the command fails or runs longer than five minutes, `what` names it then.
+pub(crate) fn run_shell_command(server: &Server<u64>, what: &str, command: &str) -> Result<(), Box<dyn Error>> {
+    info!("Running the {} of server {:?}: {}", what, server.name, command);
+    let mut process = if cfg!(windows) { Command::new("cmd") } else { Command::new("sh") };
+    process.arg(if cfg!(windows) { "/C" } else { "-c" }).arg(command);
+    let mut child = process
+        .current_dir(&server.directory)
+        .env("SERVER_ID", server.id.to_string())
+        .env("SERVER_NAME", &server.name)
+        .env("SERVER_DIR", &server.directory)
+        .stdin(Stdio::null())
+        .spawn()?;
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > HOOK_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("The {} {:?} did not finish within {:?}", what, command, HOOK_TIMEOUT).into());
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    if !status.success() {
+        return Err(format!("The {} {:?} failed with {}", what, command, status).into());
+    }
+    Ok(())
+}
+
+/// Reads the launch template of a server, the managed command without hooks if none was set.
+///
+/// # Errors
+/// Returns an error if the acting user may not control the server.
+pub fn get_launch_template(server_id: u64) -> Result<LaunchTemplate, Box<dyn Error>> {
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::ControlServer)?;
+    launch_template(server_id)
+}
+
+/// Reads the launch template of a server without checking the acting user, for starting it.
+pub(crate) fn launch_template(server_id: u64) -> Result<LaunchTemplate, Box<dyn Error>> {
+    Ok(get_config(ConfigScope::Server(server_id), CONFIG_KEY)?.unwrap_or_default())
+}
+
+/// Stores the launch template of a server, used from its next start. A custom command or hooks
+/// can only be set by administrators.
+///
+/// # Errors
+/// Returns an error if the command is not a valid command line, uses unknown variables, the acting
+/// user may not set it, or the template cannot be stored.
+pub fn set_launch_template(server_id: u64, template: &LaunchTemplate) -> Result<(), Box<dyn Error>> {
+    template.validate()?;
+    authorize(&Server::<u64>::get_server(server_id)?, Capability::ControlServer)?;
+    // The command and the hooks run as the manager, which is more than any capability on a server allows
+    if template.command.is_some() || !template.pre_start.is_empty() || !template.post_stop.is_empty() {
+        if let Some(user_id) = acting_user() {
+            if !get_user(user_id)?.is_admin {
+                return Err("Only administrators can set a launch command or hooks".into());
+            }
+        }
+    }
+    set_config(ConfigScope::Server(server_id), CONFIG_KEY, template)?;
+    if template.command.is_none() {
+        info!("Server {} is launched with the managed command", server_id);
+    } else {
+        info!("Server {} is launched with a custom template", server_id);
+    }
+    Ok(())
+}
+
+/// Runs the post-stop hooks of a server, logging a failing one since the server is gone either way.
+pub(crate) fn run_post_stop_hooks(server: &Server<u64>, commands: &[String]) {
+    if let Err(e) = run_launch_hooks(server, LaunchHook::PostStop, commands) {
+        warn!("Server {:?}: {}", server.name, e);
+    }
+}
@@ -2,11 +2,102 @@
 #![deny(clippy::expect_used)]
 #![deny(clippy::panic)]
 #![deny(unused_must_use)]
+pub mod alerts;
+pub mod api_tokens;
+pub mod archive_builder;
+pub mod archive_entries;
+pub mod archive_extractor;
+pub mod audit_log;
+pub mod automation;
+pub mod backup_encryption;
+pub mod backup_remotes;
+pub mod backup_restore;
+pub mod backup_snapshots;
+pub mod backups;
+pub mod chunk_pregeneration;
+pub mod chunk_repair;
+pub mod config;
+pub mod console_line;
+pub mod content_updates;
+pub mod crash_analysis;
+pub mod crash_detection;
+pub mod curseforge_modpack;
+pub mod datapacks;
+pub mod discord_bridge;
+pub mod disk_quota;
+pub mod disk_usage;
+pub mod entry_stream;
+pub mod email_notifications;
+pub mod eula;
+pub mod event_bus;
+pub mod extensions;
+pub mod file_diff;
+pub mod file_download;
+pub mod file_hash;
+pub mod file_operations;
+pub mod file_permissions;
+pub mod file_search;
 pub mod file_system_entry;
+pub mod file_upload;
+pub mod file_versions;
+pub mod file_watcher;
+pub mod fs_error;
+pub mod http_client;
+pub mod java_downloads;
+pub mod java_runtimes;
+pub mod jobs;
+pub mod jvm_flags;
+pub mod jvm_memory;
+pub mod launch_templates;
+pub mod loader_installer;
+pub mod log_history;
+pub mod log_search;
+pub mod metrics_history;
+pub mod minecraft_file;
+pub mod mod_dependencies;
+pub mod mod_inventory;
+pub mod modrinth;
+pub mod mojang_versions;
+pub mod nbt;
+pub mod notifications;
+pub mod online_players;
+pub mod panel_import;
+pub mod panel_tls;
+pub mod paper_downloads;
+pub mod permissions;
+pub mod player_data;
+pub mod player_lists;
+pub mod player_profiles;
+pub mod player_sessions;
+pub mod provisioning;
+pub mod rcon;
+pub mod region_file;
+pub mod resource_pack;
+pub mod resource_usage;
+pub mod restart_schedule;
+pub mod sandboxed_path;
+pub mod scheduled_tasks;
+pub mod scripting;
 pub mod server;
 pub mod server_database;
 pub mod server_filesystem;
+pub mod server_import;
+pub mod server_ping;
 pub mod server_process;
 pub mod server_properties;
+pub mod server_properties_file;
+pub mod server_registry;
 pub mod server_status;
+pub mod server_templates;
+pub mod server_trash;
 pub mod start_executable_type;
+pub mod startup_watchdog;
+pub mod text_file;
+pub mod thumbnail;
+pub mod two_factor;
+pub mod users;
+pub mod webhooks;
+pub mod world_settings;
+pub mod world_trim;
+pub mod world_upgrade;
+pub mod worlds;
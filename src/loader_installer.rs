@@ -0,0 +1,452 @@
+use crate::file_hash::HashAlgorithm;
+use crate::http_client::{download_file, get_json, get_text, ExpectedHash};
+use crate::minecraft_file::ModLoader;
+use crate::mojang_versions::download_vanilla_server;
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::ServerProcess;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use regex::Regex;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const FABRIC_META: &str = "https://meta.fabricmc.net/v2";
+const QUILT_META: &str = "https://meta.quiltmc.org/v3";
+const FORGE_MAVEN: &str = "https://maven.minecraftforge.net/net/minecraftforge/forge";
+const FORGE_PROMOTIONS: &str = "https://files.minecraftforge.net/net/minecraftforge/forge/promotions_slim.json";
+const NEOFORGE_MAVEN: &str = "https://maven.neoforged.net/releases/net/neoforged/neoforge";
+/// NeoForge for 1.20.1 was still published as a fork of Forge, under its artifact name and version scheme.
+const NEOFORGE_LEGACY_MAVEN: &str = "https://maven.neoforged.net/releases/net/neoforged/forge";
+const NEOFORGE_LEGACY_VERSION: &str = "1.20.1";
+
+/// The vanilla server jar the loaders launch, next to their own launch jar.
+const VANILLA_JAR: &str = "server.jar";
+
+lazy_static! {
+    static ref MAVEN_VERSION: Option<Regex> = Regex::new(r"<version>([^<]+)</version>").ok();
+    /// The Java argument file a Forge or NeoForge run script starts the server with.
+    static ref ARGUMENT_FILE: Option<Regex> = Regex::new(r#"@(?P<file>libraries[/\\][^\s"]+_args\.txt)"#).ok();
+}
+
+/// A version of a mod loader available for a version of the game.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoaderVersion {
+    pub version: String,
+    /// Whether the loader marks the version as stable, beta versions are only installed when asked for.
+    pub stable: bool,
+}
+
+/// The result of installing a mod loader on a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoaderInstallation {
+    pub loader: ModLoader,
+    pub minecraft_version: String,
+    pub loader_version: String,
+    /// The jar or script the server is started with, relative to the server directory.
+    pub start_file: PathBuf,
+}
+
+/// Lists the versions of a mod loader available for a version of the game, newest first.
+pub fn list_loader_versions(loader: ModLoader, minecraft_version: &str) -> Result<Vec<LoaderVersion>, Box<dyn Error>> {
+    match loader {
+        ModLoader::Fabric => {
+            let versions = get_json(&format!("{}/versions/loader/{}", FABRIC_META, minecraft_version))?;
+            Ok(array(&versions)
+                .iter()
+                .filter_map(|entry| {
+                    Some(LoaderVersion {
+                        version: entry["loader"]["version"].as_str()?.to_string(),
+                        stable: entry["loader"]["stable"].as_bool().unwrap_or(false),
+                    })
+                })
+                .collect())
+        }
+        ModLoader::Quilt => {
+            let versions = get_json(&format!("{}/versions/loader/{}", QUILT_META, minecraft_version))?;
+            Ok(array(&versions)
+                .iter()
+                .filter_map(|entry| entry["loader"]["version"].as_str())
+                .map(|version| LoaderVersion {
+                    version: version.to_string(),
+                    // Quilt has no stable flag, its betas carry a pre-release suffix
+                    stable: !version.contains('-'),
+                })
+                .collect())
+        }
+        ModLoader::Forge => {
+            let promotions = get_json(FORGE_PROMOTIONS)?;
+            let promos = &promotions["promos"];
+            // Forge promotes a recommended build once a version settled, and only a latest one before
+            let promoted = promos[format!("{}-recommended", minecraft_version)]
+                .as_str()
+                .or_else(|| promos[format!("{}-latest", minecraft_version)].as_str());
+            let prefix = format!("{}-", minecraft_version);
+            Ok(sorted_versions(
+                maven_versions(FORGE_MAVEN)?
+                    .iter()
+                    .filter_map(|version| version.strip_prefix(&prefix))
+                    .map(|version| LoaderVersion {
+                        version: version.to_string(),
+                        stable: promoted == Some(version),
+                    })
+                    .collect(),
+            ))
+        }
+        ModLoader::NeoForge if minecraft_version == NEOFORGE_LEGACY_VERSION => {
+            let prefix = format!("{}-", NEOFORGE_LEGACY_VERSION);
+            Ok(sorted_versions(
+                maven_versions(NEOFORGE_LEGACY_MAVEN)?
+                    .iter()
+                    .filter_map(|version| version.strip_prefix(&prefix))
+                    .map(|version| LoaderVersion {
+                        version: version.to_string(),
+                        stable: !version.contains("beta"),
+                    })
+                    .collect(),
+            ))
+        }
+        ModLoader::NeoForge => Ok(sorted_versions(
+            maven_versions(NEOFORGE_MAVEN)?
+                .into_iter()
+                .filter(|version| neoforge_minecraft_version(version).as_deref() == Some(minecraft_version))
+                .map(|version| LoaderVersion {
+                    stable: !version.contains("beta"),
+                    version,
+                })
+                .collect(),
+        )),
+        ModLoader::Unknown => Err("The mod loader to install is unknown".into()),
+    }
+}
+
+/// Maps a NeoForge version to the version of the game it is for, its major and minor number are
+/// the minor and patch version of the game, so `21.1.77` is for 1.21.1 and `21.0.167` for 1.21.
+fn neoforge_minecraft_version(version: &str) -> Option<String> {
+    let mut numbers = version.split(['.', '-']);
+    let major = numbers.next()?.parse::<u32>().ok()?;
+    let minor = numbers.next()?.parse::<u32>().ok()?;
+    Some(match minor {
+        0 => format!("1.{}", major),
+        minor => format!("1.{}.{}", major, minor),
+    })
+}
+
+/// Lists the versions published in the metadata of a Maven artifact.
+fn maven_versions(artifact: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let metadata = get_text(&format!("{}/maven-metadata.xml", artifact))?;
+    let pattern = MAVEN_VERSION.as_ref().ok_or("The Maven version pattern failed to compile")?;
+    Ok(pattern
+        .captures_iter(&metadata)
+        .map(|captures| captures[1].trim().to_string())
+        .collect())
+}
+
+/// Sorts loader versions newest first, comparing their numbers rather than their text.
+fn sorted_versions(mut versions: Vec<LoaderVersion>) -> Vec<LoaderVersion> {
+    versions.sort_by(|a, b| compare_versions(&b.version, &a.version));
+    versions
+}
+
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let numbers = |version: &str| -> Vec<u64> {
+        version
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|number| number.parse().ok())
+            .collect()
+    };
+    numbers(a).cmp(&numbers(b))
+}
+
+/// Picks the requested loader version, or the newest stable one.
+fn choose_loader_version(
+    loader: ModLoader,
+    minecraft_version: &str,
+    requested: Option<&str>,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(requested) = requested {
+        return Ok(requested.to_string());
+    }
+    list_loader_versions(loader, minecraft_version)?
+        .into_iter()
+        .find(|version| version.stable)
+        .map(|version| version.version)
+        .ok_or_else(|| format!("{:?} has no stable version for Minecraft {}", loader, minecraft_version).into())
+}
+
+fn install_fabric(directory: &Path, minecraft_version: &str, loader_version: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let installers = get_json(&format!("{}/versions/installer", FABRIC_META))?;
+    let installer = array(&installers)
+        .iter()
+        .find(|installer| installer["stable"].as_bool().unwrap_or(false))
+        .and_then(|installer| installer["version"].as_str())
+        .ok_or("The Fabric meta lists no stable installer")?
+        .to_string();
+
+    // The Fabric meta builds a launcher jar that loads the loader and its libraries by itself
+    let launcher = PathBuf::from("fabric-server-launch.jar");
+    let url = format!(
+        "{}/versions/loader/{}/{}/{}/server/jar",
+        FABRIC_META, minecraft_version, loader_version, installer
+    );
+    download_file(&url, &directory.join(&launcher), None, &mut |_, _| {})?;
+
+    if !directory.join(VANILLA_JAR).exists() {
+        download_vanilla_server(minecraft_version, &directory.join(VANILLA_JAR))?;
+    }
+    fs::write(
+        directory.join("fabric-server-launcher.properties"),
+        format!("serverJarPath={}\n", VANILLA_JAR),
+    )?;
+    Ok(launcher)
+}
+
+fn install_quilt(
+    directory: &Path,
+    java: &Path,
+    minecraft_version: &str,
+    loader_version: &str,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let installers = get_json(&format!("{}/versions/installer", QUILT_META))?;
+    let installer_url = array(&installers)
+        .first()
+        .and_then(|installer| installer["url"].as_str())
+        .ok_or("The Quilt meta lists no installer")?
+        .to_string();
+
+    run_downloaded_installer(
+        &installer_url,
+        None,
+        java,
+        &[
+            "install".to_string(),
+            "server".to_string(),
+            minecraft_version.to_string(),
+            loader_version.to_string(),
+            "--download-server".to_string(),
+            "--install-dir=.".to_string(),
+        ],
+        directory,
+    )?;
+
+    let launcher = PathBuf::from("quilt-server-launch.jar");
+    if !directory.join(&launcher).exists() {
+        return Err("The Quilt installer did not create quilt-server-launch.jar".into());
+    }
+    Ok(launcher)
+}
+
+fn install_forge(
+    directory: &Path,
+    java: &Path,
+    loader: ModLoader,
+    minecraft_version: &str,
+    loader_version: &str,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let (artifact, name, version) = match loader {
+        ModLoader::NeoForge if minecraft_version == NEOFORGE_LEGACY_VERSION => (
+            NEOFORGE_LEGACY_MAVEN,
+            "forge",
+            format!("{}-{}", minecraft_version, loader_version),
+        ),
+        ModLoader::NeoForge => (NEOFORGE_MAVEN, "neoforge", loader_version.to_string()),
+        _ => (FORGE_MAVEN, "forge", format!("{}-{}", minecraft_version, loader_version)),
+    };
+    let url = format!("{}/{}/{}-{}-installer.jar", artifact, version, name, version);
+    // Maven publishes the checksum of every file next to it
+    let expected = ExpectedHash {
+        algorithm: HashAlgorithm::Sha1,
+        hash: get_text(&format!("{}.sha1", url))?.trim().to_string(),
+    };
+    run_downloaded_installer(&url, Some(&expected), java, &["--installServer".to_string()], directory)?;
+    find_forge_start_file(directory)
+}
+
+/// Finds what the Forge or NeoForge installer left to start the server with.
+///
+/// Since 1.17 the installer generates run scripts that pass a Java argument file holding the class
+/// path, which the server is started with directly so its memory and Java arguments still apply.
+/// Before, it generated a server jar named after the version.
+pub(crate) fn find_forge_start_file(directory: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let script = if cfg!(target_os = "windows") { "run.bat" } else { "run.sh" };
+    if let Ok(contents) = fs::read_to_string(directory.join(script)) {
+        let argument_file = ARGUMENT_FILE
+            .as_ref()
+            .and_then(|pattern| pattern.captures(&contents))
+            .map(|captures| PathBuf::from(&captures["file"]));
+        return Ok(match argument_file {
+            Some(file) if directory.join(&file).exists() => file,
+            _ => {
+                warn!("No argument file found in {:?}, starting the server with the script", script);
+                PathBuf::from(script)
+            }
+        });
+    }
+
+    fs::read_dir(directory)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .find(|name| {
+            name.ends_with(".jar")
+                && (name.starts_with("forge-") || name.starts_with("minecraftforge"))
+                && !name.contains("installer")
+        })
+        .map(PathBuf::from)
+        .ok_or_else(|| "The installer created neither a run script nor a server jar".into())
+}
+
+/// Downloads an installer jar into the server directory, runs it there and removes it again.
+fn run_downloaded_installer(
+    url: &str,
+    expected: Option<&ExpectedHash>,
+    java: &Path,
+    arguments: &[String],
+    directory: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let installer = directory.join("installer.jar");
+    download_file(url, &installer, expected, &mut |_, _| {})?;
+    let result = run_java_installer(java, &installer, arguments, directory);
+    if let Err(e) = fs::remove_file(&installer) {
+        warn!("Failed to remove the installer {:?}: {}", installer, e);
+    }
+    // The Forge installers leave a log next to themselves
+    let _ = fs::remove_file(directory.join("installer.jar.log"));
+    result.map(|_| ())
+}
+
+/// Runs an installer jar headlessly in the server directory and returns its output.
+///
+/// # Errors
+/// Returns an error, including the end of the output, if the installer fails.
+pub(crate) fn run_java_installer(
+    java: &Path,
+    installer: &Path,
+    arguments: &[String],
+    directory: &Path,
+) -> Result<String, Box<dyn Error>> {
+    info!("Running {:?} in {:?}", installer, directory);
+    let output = Command::new(java)
+        .arg("-jar")
+        .arg(installer)
+        .args(arguments)
+        .current_dir(directory)
+        .stdin(Stdio::null())
+        .output()?;
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    debug!("Output of {:?}:\n{}", installer, text);
+    if !output.status.success() {
+        let tail: Vec<&str> = text.lines().rev().take(20).collect();
+        let tail: Vec<&str> = tail.into_iter().rev().collect();
+        return Err(format!("The installer exited with {}:\n{}", output.status, tail.join("\n")).into());
+    }
+    Ok(text)
+}
+
+/// Recognizes the mod loader a server starts with from the start file the installers set up.
+///
+/// # Returns
+/// The loader, `None` for servers without one or started some other way.
+pub fn detect_server_loader(server: &Server<u64>) -> Option<ModLoader> {
+    let start = server.start_script.as_ref()?.to_string_lossy().replace('\\', "/").to_lowercase();
+    let name = start.rsplit('/').next().unwrap_or_default();
+    if name == "fabric-server-launch.jar" {
+        Some(ModLoader::Fabric)
+    } else if name == "quilt-server-launch.jar" {
+        Some(ModLoader::Quilt)
+    } else if start.contains("net/neoforged/") {
+        Some(ModLoader::NeoForge)
+    } else if start.contains("net/minecraftforge/")
+        || name.starts_with("forge-")
+        || name.starts_with("minecraftforge")
+    {
+        Some(ModLoader::Forge)
+    } else if matches!(name, "run.sh" | "run.bat") {
+        // A run script generated by an installer whose argument file was not found
+        let libraries = server.directory.join("libraries").join("net");
+        match (libraries.join("neoforged").is_dir(), libraries.join("minecraftforge").is_dir()) {
+            (true, _) => Some(ModLoader::NeoForge),
+            (false, true) => Some(ModLoader::Forge),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn array(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+pub trait ServerLoaderInstaller {
+    /// Installs a mod loader for a version of the game in the server directory, and makes the
+    /// server start with it.
+    ///
+    /// Fabric is set up from its launcher jar, Quilt, Forge and NeoForge by running their installer
+    /// headlessly with the server's Java runtime.
+    ///
+    /// # Arguments
+    /// * `loader` - The loader to install.
+    /// * `minecraft_version` - The version of the game.
+    /// * `loader_version` - The version of the loader, the newest stable one if `None`.
+    ///
+    /// # Errors
+    /// Returns an error if the server is running, the loader is not supported, or the installation fails.
+    fn install_mod_loader(
+        &mut self,
+        loader: ModLoader,
+        minecraft_version: &str,
+        loader_version: Option<&str>,
+    ) -> Result<LoaderInstallation, Box<dyn Error>>;
+}
+
+impl ServerLoaderInstaller for Server<u64> {
+    fn install_mod_loader(
+        &mut self,
+        loader: ModLoader,
+        minecraft_version: &str,
+        loader_version: Option<&str>,
+    ) -> Result<LoaderInstallation, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        if self.is_server_running() {
+            return Err("The server has to be stopped before installing a mod loader".into());
+        }
+        let loader_version = choose_loader_version(loader, minecraft_version, loader_version)?;
+        let java = self.java_runtime.clone().unwrap_or_else(|| PathBuf::from("java"));
+        fs::create_dir_all(&self.directory)?;
+
+        let start_file = match loader {
+            ModLoader::Fabric => install_fabric(&self.directory, minecraft_version, &loader_version)?,
+            ModLoader::Quilt => install_quilt(&self.directory, &java, minecraft_version, &loader_version)?,
+            ModLoader::Forge | ModLoader::NeoForge => {
+                install_forge(&self.directory, &java, loader, minecraft_version, &loader_version)?
+            }
+            ModLoader::Unknown => return Err("The mod loader to install is unknown".into()),
+        };
+
+        self.start_script = Some(start_file.clone());
+        self.java_runtime = Some(java);
+        self.minecraft_version = minecraft_version.to_string();
+        self.loader_version = Some(loader_version.clone());
+        self.update()?;
+        info!(
+            "Installed {:?} {} for Minecraft {} on server {:?}",
+            loader, loader_version, minecraft_version, self.name
+        );
+        Ok(LoaderInstallation {
+            loader,
+            minecraft_version: minecraft_version.to_string(),
+            loader_version,
+            start_file,
+        })
+    }
+}
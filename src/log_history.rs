@@ -0,0 +1,393 @@
+use crate::console_line::{parse_console_line, strip_ansi, LogLevel};
+use crate::permissions::{authorize, Capability};
+use crate::player_lists::days_from_civil;
+use crate::restart_schedule::civil_from_days;
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use flate2::read::MultiGzDecoder;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The log the running server writes to, the others are archives of earlier runs.
+pub const LATEST_LOG: &str = "latest.log";
+
+const DAY: i64 = 24 * 60 * 60;
+
+const MONTHS: &[&str] = &["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A log in `logs/`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogFile {
+    /// The name of the file in `logs/`, like `2026-10-14-1.log.gz`.
+    pub name: String,
+    /// The size on disk, compressed for archives.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub compressed: bool,
+    /// The day the log starts, `YYYY-MM-DD`, from the name of an archive and from the file times of
+    /// the others.
+    pub date: Option<String>,
+}
+
+/// Which lines of the log history to read.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogQuery {
+    /// The files to read, every log if empty.
+    pub files: Vec<String>,
+    /// The earliest line, in seconds since the Unix epoch on the clock of the server's time zone.
+    /// The game writes its local time without a zone, so a server eight hours ahead of UTC logging
+    /// `12:00:00` is at 12:00 UTC of that day here.
+    pub from: Option<u64>,
+    /// The latest line, on the same clock as `from`.
+    pub to: Option<u64>,
+    /// Lines below this level are skipped. The lines of a stack trace count as the level of the line
+    /// they follow.
+    pub min_level: Option<LogLevel>,
+}
+
+/// A line of the log history.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    /// The log the line is in.
+    pub file: String,
+    /// The line number in the file, from 1.
+    pub line_number: u64,
+    /// Where the line starts in the decompressed file, in bytes.
+    pub offset: u64,
+    /// When the line was logged, on the clock described at [`LogQuery::from`]. Lines without a time
+    /// of their own have the time of the line before them.
+    pub time: Option<u64>,
+    pub level: Option<LogLevel>,
+    /// The line without its colors.
+    pub text: String,
+}
+
+/// Parses the date of an archived log, `2026-10-14-1.log.gz`, into days since the Unix epoch.
+fn date_from_name(name: &str) -> Option<i64> {
+    let numbers: Vec<i64> = name.splitn(4, '-').take(3).map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    match numbers.as_slice() {
+        &[year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => {
+            Some(days_from_civil(year, month, day))
+        }
+        _ => None,
+    }
+}
+
+fn format_day(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days.max(0) as u64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The day a log starts on, in days since the Unix epoch.
+fn log_start_day(name: &str, metadata: Option<&fs::Metadata>) -> Option<i64> {
+    date_from_name(name).or_else(|| {
+        // A log that is not archived yet was started when the file was created
+        let metadata = metadata?;
+        let time = metadata.created().or_else(|_| metadata.modified()).ok()?;
+        Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64 / DAY)
+    })
+}
+
+/// Lists the logs of a server, newest first.
+pub fn list_logs(directory: &Path) -> Result<Vec<LogFile>, Box<dyn Error>> {
+    Ok(scan_logs(directory)?.into_iter().map(|(_, log)| log).collect())
+}
+
+/// Lists the logs of a server, newest first, with the day each starts on.
+fn scan_logs(directory: &Path) -> Result<Vec<(Option<i64>, LogFile)>, Box<dyn Error>> {
+    let folder = directory.join("logs");
+    if !folder.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut logs: Vec<(Option<i64>, LogFile)> = fs::read_dir(folder)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".log") && !name.ends_with(".log.gz") {
+                return None;
+            }
+            let metadata = entry.metadata().ok();
+            let day = log_start_day(&name, metadata.as_ref());
+            Some((
+                day,
+                LogFile {
+                    compressed: name.ends_with(".gz"),
+                    date: day.map(format_day),
+                    size: metadata.as_ref().map(|metadata| metadata.len()).unwrap_or_default(),
+                    modified: metadata.and_then(|metadata| metadata.modified().ok()),
+                    name,
+                },
+            ))
+        })
+        .collect();
+    logs.sort_by(|(a_day, a), (b_day, b)| {
+        // Archives of the same day are numbered in the order they were written
+        (b.name == LATEST_LOG)
+            .cmp(&(a.name == LATEST_LOG))
+            .then(b_day.cmp(a_day))
+            .then_with(|| archive_number(&b.name).cmp(&archive_number(&a.name)))
+            .then_with(|| b.modified.cmp(&a.modified))
+    });
+    Ok(logs)
+}
+
+/// The number of an archive within its day, `3` for `2026-10-14-3.log.gz`.
+fn archive_number(name: &str) -> Option<u64> {
+    name.trim_end_matches(".gz").trim_end_matches(".log").rsplit('-').next()?.parse().ok()
+}
+
+/// Opens a log for reading, decompressing archives on the fly.
+pub(crate) fn open_log(directory: &Path, name: &str) -> Result<Box<dyn BufRead + Send>, Box<dyn Error>> {
+    let path = SandboxedPath::new(directory.join("logs"), name)?;
+    let file = File::open(path.path())?;
+    if name.ends_with(".gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Parses the time of a console line into seconds of the day, with the day if the line has one as
+/// Forge prints it, `14Oct2026 12:34:56.789`.
+fn parse_line_time(timestamp: &str) -> Option<(Option<i64>, i64)> {
+    let mut parts = timestamp.split_whitespace().rev();
+    let time: Vec<i64> = parts
+        .next()?
+        .split('.')
+        .next()?
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let &[hours, minutes, seconds] = time.as_slice() else {
+        return None;
+    };
+    let day = parts.next().and_then(|date| {
+        let day = date.get(..2)?.parse().ok()?;
+        let month = MONTHS.iter().position(|month| date.get(2..5) == Some(*month))? as i64 + 1;
+        let year = date.get(5..)?.parse().ok()?;
+        Some(days_from_civil(year, month, day))
+    });
+    Some((day, hours * 3600 + minutes * 60 + seconds))
+}
+
+/// A log being read.
+struct OpenLog {
+    name: String,
+    reader: Box<dyn BufRead + Send>,
+    line_number: u64,
+    offset: u64,
+    /// The day of the last line, advanced when the time of the day wraps around at midnight.
+    day: Option<i64>,
+    seconds_of_day: Option<i64>,
+    level: Option<LogLevel>,
+}
+
+/// The lines of the logs of a server matching a query, oldest first.
+///
+/// The files are opened one after another and read line by line, so reading the history of a
+/// server with gigabytes of logs holds a single line in memory. Stop iterating to stop reading.
+pub struct LogHistory {
+    directory: PathBuf,
+    /// The logs still to read, with the day each starts on.
+    files: VecDeque<(String, Option<i64>)>,
+    query: LogQuery,
+    current: Option<OpenLog>,
+    /// The line of a log up to which everything was read before, for resuming a read.
+    resume_after: Option<(String, u64)>,
+}
+
+impl LogHistory {
+    /// Starts reading the logs of a server.
+    ///
+    /// # Errors
+    /// Returns an error if the logs cannot be listed or a requested file does not exist.
+    pub fn open(directory: &Path, query: LogQuery) -> Result<Self, Box<dyn Error>> {
+        let logs = scan_logs(directory)?;
+        for name in &query.files {
+            if !logs.iter().any(|(_, log)| &log.name == name) {
+                return Err(format!("There is no log {:?}", name).into());
+            }
+        }
+        let mut files: Vec<(String, Option<i64>)> = logs
+            .into_iter()
+            .rev()
+            .filter(|(_, log)| query.files.is_empty() || query.files.contains(&log.name))
+            .map(|(day, log)| (log.name, day))
+            .collect();
+
+        // A log ends before the next one starts, which skips the archives outside of the range unread
+        let from_day = query.from.map(|from| from as i64 / DAY);
+        let to_day = query.to.map(|to| to as i64 / DAY);
+        let next_days: Vec<Option<i64>> = files.iter().skip(1).map(|(_, day)| *day).chain([None]).collect();
+        let mut index = 0;
+        files.retain(|(_, day)| {
+            let next_day = next_days[index];
+            index += 1;
+            let starts_after = matches!((day, to_day), (Some(day), Some(to)) if *day > to);
+            let ends_before = matches!((next_day, from_day), (Some(next), Some(from)) if next < from);
+            !starts_after && !ends_before
+        });
+        Ok(LogHistory {
+            directory: directory.to_path_buf(),
+            files: files.into(),
+            query,
+            current: None,
+            resume_after: None,
+        })
+    }
+
+    /// Continues a read after a line, skipping the logs before it and the lines up to it.
+    ///
+    /// # Errors
+    /// Returns an error if the log is not among the logs read, such as one deleted since.
+    pub fn resume_after(mut self, file: &str, line_number: u64) -> Result<Self, Box<dyn Error>> {
+        let position = self
+            .files
+            .iter()
+            .position(|(name, _)| name == file)
+            .ok_or_else(|| format!("The log {:?} is gone, start the read anew", file))?;
+        self.files.drain(..position);
+        self.resume_after = Some((file.to_string(), line_number));
+        Ok(self)
+    }
+
+    /// Reads the next line of the current log, `None` at its end.
+    fn next_line(&mut self) -> Option<Result<LogEntry, Box<dyn Error>>> {
+        let log = self.current.as_mut()?;
+        let mut buffer = Vec::new();
+        let read = match log.reader.read_until(b'\n', &mut buffer) {
+            Ok(0) => return None,
+            Ok(read) => read,
+            Err(e) => return Some(Err(format!("Failed to read the log {:?}: {}", log.name, e).into())),
+        };
+        let offset = log.offset;
+        log.offset += read as u64;
+        log.line_number += 1;
+
+        let raw = String::from_utf8_lossy(&buffer);
+        let raw = raw.trim_end_matches(['\r', '\n']);
+        let line = parse_console_line(raw);
+        if line.level.is_some() {
+            log.level = line.level;
+        }
+        if let Some((day, seconds)) = line.timestamp.as_deref().and_then(parse_line_time) {
+            match (day, log.seconds_of_day) {
+                (Some(day), _) => log.day = Some(day),
+                // The time wrapped around at midnight, allowing for lines logged slightly out of order
+                (None, Some(previous)) if seconds + 3600 < previous => log.day = log.day.map(|day| day + 1),
+                _ => {}
+            }
+            log.seconds_of_day = Some(seconds);
+        }
+        let time = match (log.day, log.seconds_of_day) {
+            (Some(day), Some(seconds)) => Some((day * DAY + seconds).max(0) as u64),
+            _ => None,
+        };
+        Some(Ok(LogEntry {
+            file: log.name.clone(),
+            line_number: log.line_number,
+            offset,
+            time,
+            level: log.level,
+            text: strip_ansi(raw),
+        }))
+    }
+
+    fn matches(&self, entry: &LogEntry) -> bool {
+        if let Some(min_level) = self.query.min_level {
+            if entry.level.map_or(true, |level| level < min_level) {
+                return false;
+            }
+        }
+        if self.query.from.is_some() || self.query.to.is_some() {
+            let Some(time) = entry.time else {
+                return false;
+            };
+            if self.query.from.is_some_and(|from| time < from) || self.query.to.is_some_and(|to| time > to) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether the rest of the current log is past the end of the range.
+    fn past_range(&self, entry: &LogEntry) -> bool {
+        // Lines logged out of order only differ by moments, an hour of slack keeps them
+        matches!((entry.time, self.query.to), (Some(time), Some(to)) if time > to + 3600)
+    }
+}
+
+impl Iterator for LogHistory {
+    type Item = Result<LogEntry, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let (name, day) = self.files.pop_front()?;
+                match open_log(&self.directory, &name) {
+                    Ok(reader) => {
+                        self.current = Some(OpenLog {
+                            name,
+                            reader,
+                            line_number: 0,
+                            offset: 0,
+                            day,
+                            seconds_of_day: None,
+                            level: None,
+                        })
+                    }
+                    Err(e) => return Some(Err(format!("Failed to open the log {:?}: {}", name, e).into())),
+                }
+            }
+            match self.next_line() {
+                Some(Ok(entry)) => {
+                    if let Some((file, line_number)) = &self.resume_after {
+                        if entry.file == *file && entry.line_number <= *line_number {
+                            continue;
+                        }
+                    }
+                    if self.past_range(&entry) {
+                        self.current = None;
+                        continue;
+                    }
+                    if self.matches(&entry) {
+                        return Some(Ok(entry));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.current = None;
+                    return Some(Err(e));
+                }
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+/// Browsing the archived logs of servers.
+pub trait ServerLogHistory {
+    /// Lists the logs of the server, newest first.
+    fn list_logs(&self) -> Result<Vec<LogFile>, Box<dyn Error>>;
+
+    /// Reads the lines of the logs of the server matching a query, oldest first.
+    fn read_log_history(&self, query: LogQuery) -> Result<LogHistory, Box<dyn Error>>;
+}
+
+impl ServerLogHistory for Server<u64> {
+    fn list_logs(&self) -> Result<Vec<LogFile>, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        list_logs(&self.directory)
+    }
+
+    fn read_log_history(&self, query: LogQuery) -> Result<LogHistory, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        LogHistory::open(&self.directory, query)
+    }
+}
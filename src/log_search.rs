@@ -0,0 +1,134 @@
+use crate::log_history::{LogEntry, LogHistory, LogQuery};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use log::{debug, info};
+use regex::RegexBuilder;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+
+/// The most matches a single page of a search returns, whatever limit is asked for.
+pub const MAX_SEARCH_RESULTS: usize = 1000;
+
+/// How many lines in the range a single page of a search checks at most, so a pattern that never
+/// matches cannot keep a search decompressing years of logs. The cursor of a page stopped by it
+/// continues the search.
+const MAX_SCANNED_LINES: u64 = 5_000_000;
+
+/// A search through the current and archived logs of a server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogSearchOptions {
+    /// The text to find, a regular expression if `regex` is set.
+    pub pattern: String,
+    pub regex: bool,
+    pub case_sensitive: bool,
+    /// The logs, time range and level to search in.
+    #[serde(flatten)]
+    pub query: LogQuery,
+    /// The most matches to return, at most [`MAX_SEARCH_RESULTS`].
+    pub limit: usize,
+    /// Where the previous page ended, to get the next page.
+    pub cursor: Option<LogCursor>,
+}
+
+impl Default for LogSearchOptions {
+    fn default() -> Self {
+        Self {
+            pattern: String::new(),
+            regex: false,
+            case_sensitive: false,
+            query: LogQuery::default(),
+            limit: 100,
+            cursor: None,
+        }
+    }
+}
+
+/// A line of the logs to continue a search after.
+///
+/// Archiving renames `latest.log` when the server starts again, so a cursor into it only continues
+/// where it left off while the server keeps running.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogCursor {
+    pub file: String,
+    pub line_number: u64,
+}
+
+/// A line matching a search.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogMatch {
+    #[serde(flatten)]
+    pub entry: LogEntry,
+    /// The byte ranges of the matches within the text of the line.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+/// A page of the results of a search, oldest first.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogSearchResults {
+    pub matches: Vec<LogMatch>,
+    /// Where to continue for the next page, `None` once every log was searched.
+    pub next: Option<LogCursor>,
+    pub lines_scanned: u64,
+}
+
+/// Searches the logs of a server for lines matching a pattern.
+///
+/// # Errors
+/// Returns an error if the pattern is empty or not a valid regular expression, or a log cannot be read.
+pub fn search_logs(directory: &Path, options: &LogSearchOptions) -> Result<LogSearchResults, Box<dyn Error>> {
+    if options.pattern.is_empty() {
+        return Err("The search needs a pattern".into());
+    }
+    let pattern = if options.regex {
+        options.pattern.clone()
+    } else {
+        regex::escape(&options.pattern)
+    };
+    let matcher = RegexBuilder::new(&pattern).case_insensitive(!options.case_sensitive).build()?;
+    let limit = options.limit.clamp(1, MAX_SEARCH_RESULTS);
+    info!("Searching the logs in {:?} for {:?}", directory, options.pattern);
+
+    let mut history = LogHistory::open(directory, options.query.clone())?;
+    if let Some(cursor) = &options.cursor {
+        history = history.resume_after(&cursor.file, cursor.line_number)?;
+    }
+    let mut results = LogSearchResults {
+        matches: Vec::new(),
+        next: None,
+        lines_scanned: 0,
+    };
+    for entry in history {
+        let entry = entry?;
+        results.lines_scanned += 1;
+        let ranges: Vec<(usize, usize)> =
+            matcher.find_iter(&entry.text).map(|found| (found.start(), found.end())).collect();
+        let position = LogCursor {
+            file: entry.file.clone(),
+            line_number: entry.line_number,
+        };
+        if !ranges.is_empty() {
+            results.matches.push(LogMatch { entry, ranges });
+        }
+        if results.matches.len() >= limit || results.lines_scanned >= MAX_SCANNED_LINES {
+            debug!("Log search paused after {} lines with {} matches", results.lines_scanned, results.matches.len());
+            results.next = Some(position);
+            break;
+        }
+    }
+    Ok(results)
+}
+
+/// Searching the logs of servers.
+pub trait ServerLogSearch {
+    /// Searches the current and archived logs of the server, a page of matches at a time.
+    fn search_logs(&self, options: &LogSearchOptions) -> Result<LogSearchResults, Box<dyn Error>>;
+}
+
+impl ServerLogSearch for Server<u64> {
+    fn search_logs(&self, options: &LogSearchOptions) -> Result<LogSearchResults, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        search_logs(&self.directory, options)
+    }
+}
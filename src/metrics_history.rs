@@ -0,0 +1,260 @@
+use crate::chunk_pregeneration::measure_tps;
+use crate::online_players::online_player_count;
+use crate::resource_usage::{add_resource_listener, ResourceSample};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::running_server_pids;
+use crate::server_status::ServerStatus;
+use log::{error, info};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The bucket lengths the metrics are kept at, in seconds, with how long each is kept. Every
+/// sample goes into a bucket of each length, which is the downsampling.
+const RESOLUTIONS: &[(u64, u64)] = &[(60, 2 * DAY), (15 * 60, 14 * DAY), (60 * 60, 90 * DAY)];
+
+/// How often the tick rate of the online servers is asked for. It is asked through the console, so
+/// not as often as the process is sampled.
+const TPS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often buckets older than their retention are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+static RECORDER: Once = Once::new();
+
+/// A span of history, with the resolution it is shown at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsRange {
+    /// The last day, by minute.
+    Day,
+    /// The last week, by quarter hour.
+    Week,
+    /// The last 30 days, by hour.
+    Month,
+}
+
+impl MetricsRange {
+    fn span_and_resolution(&self) -> (u64, u64) {
+        match self {
+            MetricsRange::Day => (DAY, 60),
+            MetricsRange::Week => (7 * DAY, 15 * 60),
+            MetricsRange::Month => (30 * DAY, 60 * 60),
+        }
+    }
+}
+
+/// The averages of a server over one bucket. Values are `None` for buckets in which they were not
+/// measured, such as the tick rate of a server that does not answer `tps` or `tick query`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsPoint {
+    /// When the bucket starts, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The CPU usage in percent of one core.
+    pub cpu_percent: Option<f64>,
+    pub memory_bytes: Option<u64>,
+    pub players: Option<f64>,
+    /// The most players online at once.
+    pub players_peak: Option<u64>,
+    pub tps: Option<f64>,
+}
+
+/// Creates the table holding the metrics history of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_metrics_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_metrics` (
+            server_id INTEGER NOT NULL,                                 -- The server the metrics are of
+            resolution INTEGER NOT NULL,                                -- The length of the bucket in seconds
+            bucket INTEGER NOT NULL,                                    -- Unix time the bucket starts
+            cpu_percent REAL NOT NULL DEFAULT 0,                        -- The average CPU usage, percent of one core
+            memory_bytes REAL NOT NULL DEFAULT 0,                       -- The average resident memory
+            players REAL NOT NULL DEFAULT 0,                            -- The average players online
+            players_peak INTEGER NOT NULL DEFAULT 0,                    -- The most players online at once
+            samples INTEGER NOT NULL DEFAULT 0,                         -- The process samples averaged
+            tps REAL NULL,                                              -- The average tick rate, NULL if unknown
+            tps_samples INTEGER NOT NULL DEFAULT 0,                     -- The tick rates averaged
+            PRIMARY KEY (server_id, resolution, bucket)
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Starts recording the metrics history of the servers. Calling it again has no effect.
+///
+/// The usage of the process and the players online are recorded with every sample of the resource
+/// monitor, which has to be started as well. The tick rate is asked for once a minute.
+pub fn start_metrics_recording() {
+    RECORDER.call_once(|| {
+        add_resource_listener(|server_id, sample| {
+            if let Err(e) = record_sample(server_id, sample, online_player_count(server_id) as u64) {
+                error!("Failed to record the metrics of server {}: {}", server_id, e);
+            }
+        });
+        thread::spawn(|| {
+            // The command each server answered, by process so a restarted server is asked anew
+            let mut tps_commands: HashMap<(u64, u64), Option<&'static str>> = HashMap::new();
+            let mut unanswered: Vec<(u64, u64)> = Vec::new();
+            let mut pruned = Instant::now();
+            loop {
+                let running = running_server_pids();
+                tps_commands.retain(|process, _| running.contains(process));
+                unanswered.retain(|process| running.contains(process));
+                for process in running {
+                    if unanswered.contains(&process) {
+                        continue;
+                    }
+                    let Ok(server) = Server::<u64>::get_server(process.0) else {
+                        continue;
+                    };
+                    if server.status != Some(ServerStatus::Online) {
+                        continue;
+                    }
+                    let command = tps_commands.entry(process).or_default();
+                    match measure_tps(&server, command) {
+                        Some(tps) => {
+                            if let Err(e) = record_tps(server.id, tps) {
+                                error!("Failed to record the tick rate of server {}: {}", server.id, e);
+                            }
+                        }
+                        // Neither command is known to the server, there is no use asking again
+                        None if command.is_none() => unanswered.push(process),
+                        None => {}
+                    }
+                }
+                if pruned.elapsed() >= PRUNE_INTERVAL {
+                    pruned = Instant::now();
+                    if let Err(e) = prune_metrics() {
+                        error!("Failed to prune the metrics history: {}", e);
+                    }
+                }
+                thread::sleep(TPS_INTERVAL);
+            }
+        });
+        info!("Started recording the metrics history");
+    });
+}
+
+fn unix_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Adds a sample of the process and the player count to the buckets of every resolution.
+fn record_sample(server_id: u64, sample: &ResourceSample, players: u64) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let at = sample.timestamp / 1000;
+    for (resolution, _) in RESOLUTIONS {
+        let mut statement = conn.prepare(
+            r#"INSERT INTO server_metrics
+            (server_id, resolution, bucket, cpu_percent, memory_bytes, players, players_peak, samples)
+            VALUES (?, ?, ?, ?, ?, ?, ?, 1)
+            ON CONFLICT(server_id, resolution, bucket) DO UPDATE SET
+            cpu_percent = (cpu_percent * samples + excluded.cpu_percent) / (samples + 1),
+            memory_bytes = (memory_bytes * samples + excluded.memory_bytes) / (samples + 1),
+            players = (players * samples + excluded.players) / (samples + 1),
+            players_peak = MAX(players_peak, excluded.players_peak),
+            samples = samples + 1"#,
+        )?;
+        statement.bind((1, server_id as i64))?;
+        statement.bind((2, *resolution as i64))?;
+        statement.bind((3, (at / resolution * resolution) as i64))?;
+        statement.bind((4, f64::from(sample.cpu_percent)))?;
+        statement.bind((5, sample.memory_bytes as f64))?;
+        statement.bind((6, players as f64))?;
+        statement.bind((7, players as i64))?;
+        statement.next()?;
+    }
+    Ok(())
+}
+
+/// Adds a tick rate to the buckets of every resolution.
+fn record_tps(server_id: u64, tps: f64) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let at = unix_seconds();
+    for (resolution, _) in RESOLUTIONS {
+        let mut statement = conn.prepare(
+            r#"INSERT INTO server_metrics (server_id, resolution, bucket, tps, tps_samples) VALUES (?, ?, ?, ?, 1)
+            ON CONFLICT(server_id, resolution, bucket) DO UPDATE SET
+            tps = (COALESCE(tps, 0) * tps_samples + excluded.tps) / (tps_samples + 1),
+            tps_samples = tps_samples + 1"#,
+        )?;
+        statement.bind((1, server_id as i64))?;
+        statement.bind((2, *resolution as i64))?;
+        statement.bind((3, (at / resolution * resolution) as i64))?;
+        statement.bind((4, tps))?;
+        statement.next()?;
+    }
+    Ok(())
+}
+
+/// Removes the buckets older than the retention of their resolution.
+fn prune_metrics() -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let now = unix_seconds();
+    for (resolution, retention) in RESOLUTIONS {
+        let mut statement = conn.prepare(r#"DELETE FROM server_metrics WHERE resolution = ? AND bucket < ?"#)?;
+        statement.bind((1, *resolution as i64))?;
+        statement.bind((2, now.saturating_sub(*retention) as i64))?;
+        statement.next()?;
+    }
+    Ok(())
+}
+
+/// The latest tick rate measured for a server within `max_age`, by minute.
+pub(crate) fn latest_tps(server_id: u64, max_age: Duration) -> Result<Option<f64>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"SELECT tps FROM server_metrics
+        WHERE server_id = ? AND resolution = ? AND bucket >= ? AND tps IS NOT NULL
+        ORDER BY bucket DESC LIMIT 1"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, RESOLUTIONS[0].0 as i64))?;
+    statement.bind((3, unix_seconds().saturating_sub(max_age.as_secs()) as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(statement.read::<Option<f64>, _>("tps")?);
+    }
+    Ok(None)
+}
+
+/// Reads the metrics history of a server, oldest first. Buckets in which the server was not running
+/// are left out, so gaps in the timestamps are the times it was stopped.
+pub fn get_metrics_history(server_id: u64, range: MetricsRange) -> Result<Vec<MetricsPoint>, Box<dyn Error>> {
+    let (span, resolution) = range.span_and_resolution();
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"SELECT * FROM server_metrics WHERE server_id = ? AND resolution = ? AND bucket >= ? ORDER BY bucket"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, resolution as i64))?;
+    statement.bind((3, unix_seconds().saturating_sub(span) as i64))?;
+    let mut points = Vec::new();
+    while let State::Row = statement.next()? {
+        let sampled = statement.read::<i64, _>("samples")? > 0;
+        points.push(MetricsPoint {
+            timestamp: statement.read::<i64, _>("bucket")? as u64,
+            cpu_percent: sampled.then(|| statement.read::<f64, _>("cpu_percent")).transpose()?,
+            memory_bytes: sampled
+                .then(|| statement.read::<f64, _>("memory_bytes").map(|bytes| bytes as u64))
+                .transpose()?,
+            players: sampled.then(|| statement.read::<f64, _>("players")).transpose()?,
+            players_peak: sampled
+                .then(|| statement.read::<i64, _>("players_peak").map(|peak| peak as u64))
+                .transpose()?,
+            tps: statement.read::<Option<f64>, _>("tps")?,
+        });
+    }
+    Ok(points)
+}
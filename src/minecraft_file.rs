@@ -0,0 +1,195 @@
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// The mod loader a mod jar was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModLoader {
+    Forge,
+    NeoForge,
+    Fabric,
+    Quilt,
+    /// A jar in `mods/` whose metadata could not be inspected.
+    Unknown,
+}
+
+impl ModLoader {
+    /// The `loader_type` a server running the loader is stored with, `0` standing for no loader.
+    pub fn loader_type(&self) -> u8 {
+        match self {
+            ModLoader::Fabric => 1,
+            ModLoader::Forge => 2,
+            ModLoader::NeoForge => 3,
+            ModLoader::Quilt => 4,
+            ModLoader::Unknown => 0,
+        }
+    }
+}
+
+/// The meaning of a file or directory for a Minecraft server, used to offer actions that fit the
+/// entry, such as trimming a region file or disabling a mod.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MinecraftCategory {
+    /// A region, entity or POI file of a world (`.mca`, `.mcr`, `.mcc`).
+    Region,
+    /// The `level.dat` of a world or its backup copy.
+    LevelData,
+    /// The data, statistics or advancements of a player.
+    PlayerData,
+    /// A datapack directory or archive.
+    Datapack,
+    /// A resource pack directory or archive.
+    ResourcePack,
+    /// A Bukkit, Spigot, Paper, BungeeCord or Velocity plugin jar.
+    Plugin,
+    /// A mod jar, along with the loader it was written for.
+    Mod { loader: ModLoader },
+    /// A server log, including rotated `.log.gz` files.
+    Log,
+    /// A crash report written by the game or by the JVM.
+    CrashReport,
+}
+
+/// The files inside a jar announcing the loader of a mod, checked in order. NeoForge mods may
+/// ship a `mods.toml` for older Forge versions as well, so `neoforge.mods.toml` is checked first.
+const MOD_LOADER_MARKERS: &[(&str, ModLoader)] = &[
+    ("fabric.mod.json", ModLoader::Fabric),
+    ("quilt.mod.json", ModLoader::Quilt),
+    ("META-INF/neoforge.mods.toml", ModLoader::NeoForge),
+    ("META-INF/mods.toml", ModLoader::Forge),
+    ("mcmod.info", ModLoader::Forge),
+];
+
+/// The descriptors found in plugin jars.
+const PLUGIN_MARKERS: &[&str] = &["plugin.yml", "paper-plugin.yml", "bungee.yml", "velocity-plugin.json"];
+
+/// Recognizes what a file or directory means to a Minecraft server from its name and location.
+///
+/// # Arguments
+/// * `path` - The entry to classify.
+/// * `is_dir` - Whether the entry is a directory.
+/// * `inspect_contents` - Whether jars and packs may be opened to read their metadata. Without it
+///   jars are only classified by the directory they are in.
+///
+/// # Returns
+/// The category of the entry, or `None` if it has no special meaning.
+pub fn classify_minecraft_file(path: &Path, is_dir: bool, inspect_contents: bool) -> Option<MinecraftCategory> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    let parent = path
+        .parent()
+        .and_then(Path::file_name)
+        .map(|parent| parent.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    if is_dir {
+        return match parent.as_str() {
+            "datapacks" => Some(MinecraftCategory::Datapack),
+            "resourcepacks" => Some(MinecraftCategory::ResourcePack),
+            _ if inspect_contents => classify_pack_directory(path),
+            _ => None,
+        };
+    }
+
+    let extension = path.extension().and_then(OsStr::to_str).map(str::to_lowercase).unwrap_or_default();
+    match (name.as_str(), extension.as_str()) {
+        ("level.dat" | "level.dat_old", _) => Some(MinecraftCategory::LevelData),
+        (_, "mca" | "mcr" | "mcc") => Some(MinecraftCategory::Region),
+        _ if matches!(parent.as_str(), "playerdata" | "stats" | "advancements")
+            && matches!(extension.as_str(), "dat" | "dat_old" | "json") =>
+        {
+            Some(MinecraftCategory::PlayerData)
+        }
+        _ if name.starts_with("hs_err_pid") => Some(MinecraftCategory::CrashReport),
+        _ if parent == "crash-reports" => Some(MinecraftCategory::CrashReport),
+        _ if name.ends_with(".log") || name.ends_with(".log.gz") => Some(MinecraftCategory::Log),
+        (_, "jar") => classify_jar(path, &parent, inspect_contents),
+        (_, "zip") => match parent.as_str() {
+            "datapacks" => Some(MinecraftCategory::Datapack),
+            "resourcepacks" => Some(MinecraftCategory::ResourcePack),
+            _ if inspect_contents => classify_pack_archive(path),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Decides whether a jar is a mod or a plugin, preferring its metadata over its location.
+fn classify_jar(path: &Path, parent: &str, inspect_contents: bool) -> Option<MinecraftCategory> {
+    if inspect_contents {
+        match inspect_jar(path) {
+            Ok(Some(category)) => return Some(category),
+            Ok(None) => {}
+            Err(err) => debug!("Failed to inspect jar {:?}: {}", path, err),
+        }
+    }
+    match parent {
+        "mods" => Some(MinecraftCategory::Mod {
+            loader: ModLoader::Unknown,
+        }),
+        "plugins" => Some(MinecraftCategory::Plugin),
+        _ => None,
+    }
+}
+
+fn inspect_jar(path: &Path) -> Result<Option<MinecraftCategory>, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+    for (marker, loader) in MOD_LOADER_MARKERS {
+        if archive.index_for_name(marker).is_some() {
+            return Ok(Some(MinecraftCategory::Mod { loader: *loader }));
+        }
+    }
+    if PLUGIN_MARKERS.iter().any(|marker| archive.index_for_name(marker).is_some()) {
+        return Ok(Some(MinecraftCategory::Plugin));
+    }
+
+    // Core mods and language providers of older Forge versions only declare themselves in the manifest
+    if let Ok(manifest) = archive.by_name("META-INF/MANIFEST.MF") {
+        let mut contents = String::new();
+        manifest.take(64 * 1024).read_to_string(&mut contents)?;
+        if contents.lines().any(|line| line.starts_with("FMLModType") || line.starts_with("FMLCorePlugin")) {
+            return Ok(Some(MinecraftCategory::Mod {
+                loader: ModLoader::Forge,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Tells datapacks and resource packs apart by their top level directory, both have a `pack.mcmeta`.
+fn classify_pack_directory(path: &Path) -> Option<MinecraftCategory> {
+    if !path.join("pack.mcmeta").is_file() {
+        return None;
+    }
+    if path.join("data").is_dir() {
+        Some(MinecraftCategory::Datapack)
+    } else if path.join("assets").is_dir() {
+        Some(MinecraftCategory::ResourcePack)
+    } else {
+        None
+    }
+}
+
+fn classify_pack_archive(path: &Path) -> Option<MinecraftCategory> {
+    // Archives of a whole server can be huge, and packs are rarely larger than this
+    const MAX_PACK_SIZE: u64 = 512 * 1024 * 1024;
+    if fs::metadata(path).map_or(true, |metadata| metadata.len() > MAX_PACK_SIZE) {
+        return None;
+    }
+
+    let archive = zip::ZipArchive::new(BufReader::new(File::open(path).ok()?)).ok()?;
+    archive.index_for_name("pack.mcmeta")?;
+    let has_directory = |directory: &str| archive.file_names().any(|name| name.starts_with(directory));
+    if has_directory("data/") {
+        Some(MinecraftCategory::Datapack)
+    } else if has_directory("assets/") {
+        Some(MinecraftCategory::ResourcePack)
+    } else {
+        None
+    }
+}
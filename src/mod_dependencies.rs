@@ -0,0 +1,478 @@
+use crate::http_client::get_json_with_query;
+use crate::loader_installer::detect_server_loader;
+use crate::minecraft_file::ModLoader;
+use crate::mod_inventory::{scan_inventory, InventoryEntry, InventoryKind};
+use crate::modrinth::{
+    get_project_versions, get_version, list_installed_content, InstalledContent, ModrinthVersion, ServerModrinth,
+};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+const MODRINTH_API: &str = "https://api.modrinth.com/v2";
+
+/// Dependencies on the game, Java or a loader itself, which are not installed as mods.
+const PLATFORM_IDS: &[&str] = &[
+    "minecraft",
+    "java",
+    "fabricloader",
+    "fabric-loader",
+    "quilt_loader",
+    "forge",
+    "neoforge",
+    "fml",
+    "javafml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentIssueKind {
+    /// Several jars declare the same id, the loader refuses to start or picks one at random.
+    DuplicateId,
+    /// A mod written for another loader than the one the server runs.
+    WrongLoader,
+    /// A mod or version that does not support the version of the game of the server.
+    IncompatibleGameVersion,
+    /// A required dependency that is not installed.
+    MissingDependency,
+    /// A version that declares itself incompatible with something installed.
+    Incompatible,
+}
+
+/// A problem with the mods or plugins of a server, found before it starts.
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentIssue {
+    pub kind: ContentIssueKind,
+    /// The jars involved, relative to the server directory, empty for versions not installed yet.
+    pub files: Vec<PathBuf>,
+    pub message: String,
+}
+
+/// A missing dependency of a version to install, with the version that would be installed for it.
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyCandidate {
+    pub project_id: String,
+    pub version: ModrinthVersion,
+}
+
+/// What installing a Modrinth version involves, so users can confirm the dependencies first.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstallPlan {
+    pub version: ModrinthVersion,
+    /// The missing required dependencies, including those of the dependencies.
+    pub dependencies: Vec<DependencyCandidate>,
+    /// The required projects without a version for the loader and version of the game of the server.
+    pub unresolved: Vec<String>,
+    pub issues: Vec<ContentIssue>,
+}
+
+/// The name Modrinth and the descriptors use for a loader.
+fn loader_name(loader: ModLoader) -> &'static str {
+    match loader {
+        ModLoader::Forge => "forge",
+        ModLoader::NeoForge => "neoforge",
+        ModLoader::Fabric => "fabric",
+        ModLoader::Quilt => "quilt",
+        ModLoader::Unknown => "unknown",
+    }
+}
+
+/// Whether a server running `server` loads mods written for `target`.
+fn loads_mods_of(server: ModLoader, target: ModLoader, game_version: &str) -> bool {
+    server == target
+        || (server == ModLoader::Quilt && target == ModLoader::Fabric)
+        // NeoForge for 1.20.1 is a fork of Forge and still loads its mods
+        || (server == ModLoader::NeoForge && target == ModLoader::Forge && game_version == "1.20.1")
+}
+
+/// The numbers of a version, ignoring pre-release and build suffixes, `None` for snapshots.
+fn version_numbers(version: &str) -> Option<Vec<u64>> {
+    let release = version.trim().split(['-', '+']).next()?;
+    release.split('.').map(|number| number.parse().ok()).collect()
+}
+
+fn compare_numbers(a: &[u64], b: &[u64]) -> Ordering {
+    let length = a.len().max(b.len());
+    let padded = |numbers: &[u64]| -> Vec<u64> {
+        numbers.iter().copied().chain(std::iter::repeat(0)).take(length).collect()
+    };
+    padded(a).cmp(&padded(b))
+}
+
+/// Checks a version against a Maven version range as used by Forge, like `[1.20.1,1.21)`.
+///
+/// # Returns
+/// Whether the version is in the range, `None` if the range cannot be understood.
+fn matches_maven_range(range: &str, version: &[u64]) -> Option<bool> {
+    let range: String = range.chars().filter(|c| !c.is_whitespace()).collect();
+    if !range.starts_with(['[', '(']) {
+        // A plain version is a soft requirement, any newer version satisfies it
+        return Some(compare_numbers(version, &version_numbers(&range)?) != Ordering::Less);
+    }
+
+    let mut rest = range.as_str();
+    while let Some(start) = rest.find(['[', '(']) {
+        let end = rest[start..].find([']', ')'])? + start;
+        let (open, close) = (&rest[start..=start], &rest[end..=end]);
+        let bounds = &rest[start + 1..end];
+        let in_range = match bounds.split_once(',') {
+            None => compare_numbers(version, &version_numbers(bounds)?) == Ordering::Equal,
+            Some((lower, upper)) => {
+                let above = match lower {
+                    "" => true,
+                    lower => match compare_numbers(version, &version_numbers(lower)?) {
+                        Ordering::Greater => true,
+                        Ordering::Equal => open == "[",
+                        Ordering::Less => false,
+                    },
+                };
+                let below = match upper {
+                    "" => true,
+                    upper => match compare_numbers(version, &version_numbers(upper)?) {
+                        Ordering::Less => true,
+                        Ordering::Equal => close == "]",
+                        Ordering::Greater => false,
+                    },
+                };
+                above && below
+            }
+        };
+        if in_range {
+            return Some(true);
+        }
+        rest = &rest[end + 1..];
+    }
+    Some(false)
+}
+
+/// Checks a version against a Fabric version predicate, like `>=1.20 <1.21`, `~1.20.1` or `1.20.x`,
+/// where alternatives are separated by `||`.
+///
+/// # Returns
+/// Whether the version matches, `None` if the predicate cannot be understood.
+fn matches_fabric_predicate(predicate: &str, version: &[u64]) -> Option<bool> {
+    let mut any = false;
+    for alternative in predicate.split("||") {
+        let mut all = true;
+        for constraint in alternative.split_whitespace() {
+            all &= matches_fabric_constraint(constraint, version)?;
+        }
+        any |= all;
+    }
+    Some(any)
+}
+
+fn matches_fabric_constraint(constraint: &str, version: &[u64]) -> Option<bool> {
+    if constraint == "*" {
+        return Some(true);
+    }
+    let operator_length = constraint.find(|c: char| c.is_ascii_digit()).unwrap_or(0);
+    let (operator, target) = constraint.split_at(operator_length);
+
+    // Wildcards only compare the leading numbers
+    if let Some(prefix) = target
+        .strip_suffix(".x")
+        .or_else(|| target.strip_suffix(".X"))
+        .or_else(|| target.strip_suffix(".*"))
+    {
+        let prefix = version_numbers(prefix)?;
+        return Some(version.len() >= prefix.len() && version[..prefix.len()] == prefix[..]);
+    }
+
+    let target = version_numbers(target)?;
+    let ordering = compare_numbers(version, &target);
+    Some(match operator {
+        ">=" => ordering != Ordering::Less,
+        "<=" => ordering != Ordering::Greater,
+        ">" => ordering == Ordering::Greater,
+        "<" => ordering == Ordering::Less,
+        "" | "=" => ordering == Ordering::Equal,
+        // The same minor version, or the same major version, and at least the target
+        "~" => ordering != Ordering::Less && version.get(..2) == target.get(..2),
+        "^" => ordering != Ordering::Less && version.first() == target.first(),
+        _ => return None,
+    })
+}
+
+/// Checks whether a mod supports a version of the game, from its dependency on `minecraft`.
+fn supports_game_version(entry: &InventoryEntry, game_version: &str) -> Option<bool> {
+    let InventoryKind::Mod { loader } = entry.kind else {
+        return None;
+    };
+    let game = version_numbers(game_version)?;
+    let requirement = entry
+        .metadata
+        .dependencies
+        .iter()
+        .find(|dependency| dependency.id == "minecraft")?
+        .version
+        .as_deref()?;
+    match loader {
+        ModLoader::Forge | ModLoader::NeoForge => matches_maven_range(requirement, &game),
+        ModLoader::Fabric | ModLoader::Quilt => matches_fabric_predicate(requirement, &game),
+        ModLoader::Unknown => None,
+    }
+}
+
+/// Whether an installed id satisfies a dependency. The Fabric API bundles its modules, which mods
+/// depend on by their own id, as nested jars.
+fn is_satisfied(dependency: &str, installed: &HashSet<String>) -> bool {
+    installed.contains(dependency)
+        || (dependency.starts_with("fabric-") && (installed.contains("fabric-api") || installed.contains("fabric")))
+        || (dependency == "fabric-api" && installed.contains("fabric"))
+}
+
+/// Checks the enabled mods and plugins of a server directory for duplicate ids, mods for another
+/// loader or version of the game, and missing required dependencies.
+///
+/// # Arguments
+/// * `directory` - The server directory.
+/// * `server_loader` - The loader the server runs, the loader of the mods is not checked if `None`.
+/// * `game_version` - The version of the game the server runs.
+pub fn check_content_conflicts(
+    directory: &Path,
+    server_loader: Option<ModLoader>,
+    game_version: &str,
+) -> Result<Vec<ContentIssue>, Box<dyn Error>> {
+    let inventory: Vec<InventoryEntry> = scan_inventory(directory)?.into_iter().filter(|entry| entry.enabled).collect();
+    let is_mod = |entry: &&InventoryEntry| matches!(entry.kind, InventoryKind::Mod { .. });
+    let mut issues = Vec::new();
+
+    // Mods and plugins are looked up by their id and name respectively, in separate namespaces
+    for mods in [true, false] {
+        let entries: Vec<&InventoryEntry> = inventory.iter().filter(|entry| is_mod(entry) == mods).collect();
+        let mut by_id: HashMap<String, Vec<&InventoryEntry>> = HashMap::new();
+        for entry in &entries {
+            if let Some(id) = &entry.metadata.id {
+                by_id.entry(id.to_lowercase()).or_default().push(entry);
+            }
+        }
+        let mut duplicates: Vec<(&String, &Vec<&InventoryEntry>)> =
+            by_id.iter().filter(|(_, entries)| entries.len() > 1).collect();
+        duplicates.sort_by_key(|(id, _)| *id);
+        for (id, entries) in duplicates {
+            issues.push(ContentIssue {
+                kind: ContentIssueKind::DuplicateId,
+                files: entries.iter().map(|entry| entry.file.clone()).collect(),
+                message: format!("{} jars declare the id {:?}", entries.len(), id),
+            });
+        }
+
+        let installed: HashSet<String> = by_id.into_keys().collect();
+        for entry in &entries {
+            let missing = entry.metadata.dependencies.iter().filter(|dependency| {
+                dependency.required
+                    && !PLATFORM_IDS.contains(&dependency.id.as_str())
+                    && !is_satisfied(&dependency.id.to_lowercase(), &installed)
+            });
+            for dependency in missing {
+                issues.push(ContentIssue {
+                    kind: ContentIssueKind::MissingDependency,
+                    files: vec![entry.file.clone()],
+                    message: match &dependency.version {
+                        Some(version) => format!("{} requires {} {}", entry.metadata.name, dependency.id, version),
+                        None => format!("{} requires {}", entry.metadata.name, dependency.id),
+                    },
+                });
+            }
+        }
+    }
+
+    for entry in inventory.iter().filter(is_mod) {
+        let InventoryKind::Mod { loader } = entry.kind else {
+            continue;
+        };
+        if let Some(server_loader) = server_loader {
+            if loader != ModLoader::Unknown && !loads_mods_of(server_loader, loader, game_version) {
+                issues.push(ContentIssue {
+                    kind: ContentIssueKind::WrongLoader,
+                    files: vec![entry.file.clone()],
+                    message: format!(
+                        "{} is a {} mod, the server runs {}",
+                        entry.metadata.name,
+                        loader_name(loader),
+                        loader_name(server_loader)
+                    ),
+                });
+                continue;
+            }
+        }
+        if supports_game_version(entry, game_version) == Some(false) {
+            issues.push(ContentIssue {
+                kind: ContentIssueKind::IncompatibleGameVersion,
+                files: vec![entry.file.clone()],
+                message: format!("{} does not support Minecraft {}", entry.metadata.name, game_version),
+            });
+        }
+    }
+    Ok(issues)
+}
+
+/// Looks up the slugs of Modrinth projects, which usually match the ids mods declare.
+fn project_slugs(project_ids: &[String]) -> Result<HashMap<String, String>, Box<dyn Error>> {
+    if project_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let ids = serde_json::to_string(project_ids)?;
+    let projects = get_json_with_query(&format!("{}/projects", MODRINTH_API), &[("ids", ids.as_str())])?;
+    Ok(projects
+        .as_array()
+        .map(|projects| {
+            projects
+                .iter()
+                .filter_map(|project| {
+                    Some((project["id"].as_str()?.to_string(), project["slug"].as_str()?.to_lowercase()))
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Works out what installing a Modrinth version on a server involves: the missing required
+/// dependencies, transitively, and whether the version fits the loader and version of the game.
+pub fn plan_modrinth_install(server: &Server<u64>, version_id: &str) -> Result<InstallPlan, Box<dyn Error>> {
+    let version = get_version(version_id)?;
+    let server_loader = detect_server_loader(server);
+    let game_version = server.minecraft_version.as_str();
+    let mut issues = Vec::new();
+
+    if !game_version.is_empty() && !version.game_versions.iter().any(|version| version == game_version) {
+        issues.push(ContentIssue {
+            kind: ContentIssueKind::IncompatibleGameVersion,
+            files: Vec::new(),
+            message: format!("{} does not support Minecraft {}", version.name, game_version),
+        });
+    }
+    let version_loaders: Vec<ModLoader> = version
+        .loaders
+        .iter()
+        .filter_map(|loader| serde_json::from_value(serde_json::Value::String(loader.clone())).ok())
+        .collect();
+    if let Some(server_loader) = server_loader {
+        let fits = version_loaders.iter().any(|loader| loads_mods_of(server_loader, *loader, game_version));
+        if !version_loaders.is_empty() && !fits {
+            issues.push(ContentIssue {
+                kind: ContentIssueKind::WrongLoader,
+                files: Vec::new(),
+                message: format!("{} is not available for {}", version.name, loader_name(server_loader)),
+            });
+        }
+    }
+
+    let inventory = scan_inventory(&server.directory)?;
+    let installed_ids: HashSet<String> =
+        inventory.iter().filter_map(|entry| entry.metadata.id.as_ref()).map(|id| id.to_lowercase()).collect();
+    let installed_projects: HashMap<String, InstalledContent> = list_installed_content(server.id)?
+        .into_iter()
+        .map(|content| (content.project_id.clone(), content))
+        .collect();
+
+    let mut dependencies: Vec<DependencyCandidate> = Vec::new();
+    let mut unresolved = Vec::new();
+    let mut visited: HashSet<String> = HashSet::from([version.project_id.clone()]);
+    let mut pending = vec![version.clone()];
+    while let Some(current) = pending.pop() {
+        let incompatible: Vec<String> = current
+            .dependencies
+            .iter()
+            .filter(|dependency| dependency.dependency_type == "incompatible")
+            .filter_map(|dependency| dependency.project_id.clone())
+            .collect();
+        let required: Vec<_> = current
+            .dependencies
+            .iter()
+            .filter(|dependency| dependency.dependency_type == "required")
+            .filter(|dependency| dependency.project_id.as_ref().is_some_and(|id| !visited.contains(id)))
+            .collect();
+        let project_ids: Vec<String> = required
+            .iter()
+            .filter_map(|dependency| dependency.project_id.clone())
+            .chain(incompatible.iter().cloned())
+            .collect();
+        let slugs = project_slugs(&project_ids)?;
+        let is_installed = |project_id: &String| {
+            installed_projects.contains_key(project_id)
+                || slugs.get(project_id).is_some_and(|slug| is_satisfied(slug, &installed_ids))
+        };
+
+        for project_id in incompatible.iter().filter(|project_id| is_installed(project_id)) {
+            let name = slugs.get(project_id).unwrap_or(project_id);
+            issues.push(ContentIssue {
+                kind: ContentIssueKind::Incompatible,
+                files: installed_projects.get(project_id).map(|content| content.file.clone()).into_iter().collect(),
+                message: format!("{} is incompatible with the installed {}", current.name, name),
+            });
+        }
+
+        for dependency in required {
+            let Some(project_id) = dependency.project_id.clone() else {
+                continue;
+            };
+            visited.insert(project_id.clone());
+            if is_installed(&project_id) {
+                continue;
+            }
+            let candidate = match &dependency.version_id {
+                Some(version_id) => Some(get_version(version_id)?),
+                None => {
+                    let game_versions: Vec<String> =
+                        if game_version.is_empty() { Vec::new() } else { vec![game_version.to_string()] };
+                    get_project_versions(&project_id, &version.loaders, &game_versions)?.into_iter().next()
+                }
+            };
+            match candidate {
+                Some(candidate) => {
+                    pending.push(candidate.clone());
+                    dependencies.push(DependencyCandidate {
+                        project_id,
+                        version: candidate,
+                    });
+                }
+                None => unresolved.push(slugs.get(&project_id).cloned().unwrap_or(project_id)),
+            }
+        }
+    }
+
+    Ok(InstallPlan {
+        version,
+        dependencies,
+        unresolved,
+        issues,
+    })
+}
+
+pub trait ServerModDependencies {
+    /// Works out the missing dependencies and problems of installing a Modrinth version.
+    fn plan_modrinth_install(&self, version_id: &str) -> Result<InstallPlan, Box<dyn Error>>;
+
+    /// Installs a planned version along with the dependencies of the plan, dependencies first.
+    fn install_modrinth_plan(&self, plan: &InstallPlan) -> Result<Vec<InstalledContent>, Box<dyn Error>>;
+
+    /// Checks the installed mods and plugins for problems that would keep the server from starting.
+    fn check_content_conflicts(&self) -> Result<Vec<ContentIssue>, Box<dyn Error>>;
+}
+
+impl ServerModDependencies for Server<u64> {
+    fn plan_modrinth_install(&self, version_id: &str) -> Result<InstallPlan, Box<dyn Error>> {
+        plan_modrinth_install(self, version_id)
+    }
+
+    fn install_modrinth_plan(&self, plan: &InstallPlan) -> Result<Vec<InstalledContent>, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        plan.dependencies
+            .iter()
+            .rev()
+            .map(|dependency| &dependency.version)
+            .chain(std::iter::once(&plan.version))
+            .map(|version| self.install_modrinth_version(&version.id, None))
+            .collect()
+    }
+
+    fn check_content_conflicts(&self) -> Result<Vec<ContentIssue>, Box<dyn Error>> {
+        check_content_conflicts(&self.directory, detect_server_loader(self), &self.minecraft_version)
+    }
+}
@@ -0,0 +1,522 @@
+use crate::minecraft_file::ModLoader;
+use crate::modrinth::rename_installed_file;
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use serde_yaml::Value as YamlValue;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Metadata files are small, anything larger is not read.
+const MAX_METADATA_SIZE: u64 = 1024 * 1024;
+
+/// The suffix of jars that are kept in place but not loaded.
+const DISABLED_SUFFIX: &str = ".disabled";
+
+/// The folders the inventory lists.
+const CONTENT_FOLDERS: &[&str] = &["mods", "plugins"];
+
+/// The platform a plugin was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginPlatform {
+    /// Bukkit and its forks, such as Spigot and Paper.
+    Bukkit,
+    /// Paper plugins using the newer `paper-plugin.yml`.
+    Paper,
+    BungeeCord,
+    Velocity,
+}
+
+/// Whether an inventory entry is a mod or a plugin, along with what it runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum InventoryKind {
+    Mod { loader: ModLoader },
+    Plugin { platform: Option<PluginPlatform> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentDependency {
+    pub id: String,
+    /// The accepted versions, in the notation of the loader.
+    pub version: Option<String>,
+    /// Whether the mod or plugin refuses to load without it.
+    pub required: bool,
+}
+
+/// What a mod or plugin declares about itself.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContentMetadata {
+    pub id: Option<String>,
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub authors: Vec<String>,
+    pub dependencies: Vec<ContentDependency>,
+}
+
+/// A jar in `mods/` or `plugins/`.
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryEntry {
+    /// The jar, relative to the server directory.
+    pub file: PathBuf,
+    pub size: u64,
+    /// Whether the jar is loaded, jars ending in `.disabled` are not.
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub kind: InventoryKind,
+    #[serde(flatten)]
+    pub metadata: ContentMetadata,
+}
+
+/// Reads the metadata of a mod or plugin jar.
+///
+/// # Returns
+/// What the jar is and the metadata it declares, `None` if it has no known descriptor.
+pub fn read_jar_metadata(path: &Path) -> Result<Option<(InventoryKind, ContentMetadata)>, Box<dyn Error>> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let mut read = |name: &str| -> Option<String> {
+        let file = archive.by_name(name).ok()?;
+        let mut contents = String::new();
+        file.take(MAX_METADATA_SIZE).read_to_string(&mut contents).ok()?;
+        Some(contents)
+    };
+
+    if let Some(contents) = read("fabric.mod.json") {
+        let metadata = parse_fabric(&serde_json::from_str(&contents)?);
+        return Ok(Some((mod_kind(ModLoader::Fabric), metadata)));
+    }
+    if let Some(contents) = read("quilt.mod.json") {
+        let metadata = parse_quilt(&serde_json::from_str(&contents)?);
+        return Ok(Some((mod_kind(ModLoader::Quilt), metadata)));
+    }
+    // NeoForge mods may ship a `mods.toml` for older Forge versions as well
+    for (descriptor, loader) in [
+        ("META-INF/neoforge.mods.toml", ModLoader::NeoForge),
+        ("META-INF/mods.toml", ModLoader::Forge),
+    ] {
+        if let Some(contents) = read(descriptor) {
+            let jar_version =
+                read("META-INF/MANIFEST.MF").and_then(|manifest| manifest_value(&manifest, "Implementation-Version"));
+            let metadata = parse_mods_toml(&contents.parse::<toml::Table>()?, jar_version);
+            return Ok(Some((mod_kind(loader), metadata)));
+        }
+    }
+    if let Some(contents) = read("mcmod.info") {
+        let metadata = parse_mcmod_info(&serde_json::from_str(&contents)?);
+        return Ok(Some((mod_kind(ModLoader::Forge), metadata)));
+    }
+
+    for (descriptor, platform) in [
+        ("paper-plugin.yml", PluginPlatform::Paper),
+        ("plugin.yml", PluginPlatform::Bukkit),
+        ("bungee.yml", PluginPlatform::BungeeCord),
+    ] {
+        if let Some(contents) = read(descriptor) {
+            let metadata = parse_plugin_yml(&serde_yaml::from_str(&contents)?);
+            return Ok(Some((plugin_kind(platform), metadata)));
+        }
+    }
+    if let Some(contents) = read("velocity-plugin.json") {
+        let metadata = parse_velocity(&serde_json::from_str(&contents)?);
+        return Ok(Some((plugin_kind(PluginPlatform::Velocity), metadata)));
+    }
+    Ok(None)
+}
+
+fn mod_kind(loader: ModLoader) -> InventoryKind {
+    InventoryKind::Mod { loader }
+}
+
+fn plugin_kind(platform: PluginPlatform) -> InventoryKind {
+    InventoryKind::Plugin {
+        platform: Some(platform),
+    }
+}
+
+fn json_string(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::String(value) => Some(value.clone()),
+        JsonValue::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Reads a version requirement, which the descriptors give as a string or a list of alternatives.
+fn json_versions(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::Array(versions) => Some(versions.iter().filter_map(json_string).collect::<Vec<_>>().join(" || ")),
+        value => json_string(value),
+    }
+}
+
+fn parse_fabric(json: &JsonValue) -> ContentMetadata {
+    let authors = json["authors"]
+        .as_array()
+        .map(|authors| {
+            authors
+                .iter()
+                .filter_map(|author| json_string(author).or_else(|| json_string(&author["name"])))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut dependencies = Vec::new();
+    for (field, required) in [("depends", true), ("recommends", false), ("suggests", false)] {
+        if let Some(depends) = json[field].as_object() {
+            dependencies.extend(depends.iter().map(|(id, version)| ContentDependency {
+                id: id.clone(),
+                version: json_versions(version),
+                required,
+            }));
+        }
+    }
+    ContentMetadata {
+        id: json_string(&json["id"]),
+        name: json_string(&json["name"]).or_else(|| json_string(&json["id"])).unwrap_or_default(),
+        version: json_string(&json["version"]),
+        description: json_string(&json["description"]),
+        authors,
+        dependencies,
+    }
+}
+
+fn parse_quilt(json: &JsonValue) -> ContentMetadata {
+    let loader = &json["quilt_loader"];
+    let metadata = &loader["metadata"];
+    let authors = metadata["contributors"]
+        .as_object()
+        .map(|contributors| contributors.keys().cloned().collect())
+        .unwrap_or_default();
+    let dependencies = loader["depends"]
+        .as_array()
+        .map(|depends| {
+            depends
+                .iter()
+                .filter_map(|dependency| match dependency {
+                    JsonValue::String(id) => Some(ContentDependency {
+                        id: id.clone(),
+                        version: None,
+                        required: true,
+                    }),
+                    dependency => Some(ContentDependency {
+                        id: json_string(&dependency["id"])?,
+                        version: json_versions(&dependency["versions"]),
+                        required: !dependency["optional"].as_bool().unwrap_or(false),
+                    }),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ContentMetadata {
+        id: json_string(&loader["id"]),
+        name: json_string(&metadata["name"]).or_else(|| json_string(&loader["id"])).unwrap_or_default(),
+        version: json_string(&loader["version"]),
+        description: json_string(&metadata["description"]),
+        authors,
+        dependencies,
+    }
+}
+
+/// Reads the first mod of a `mods.toml`, jars declaring several mods are listed under the first one.
+fn parse_mods_toml(table: &toml::Table, jar_version: Option<String>) -> ContentMetadata {
+    let text = |value: Option<&toml::Value>| value.and_then(toml::Value::as_str).map(str::to_string);
+    let Some(first) = table.get("mods").and_then(toml::Value::as_array).and_then(|mods| mods.first()) else {
+        return ContentMetadata::default();
+    };
+    let id = text(first.get("modId"));
+    // The version is usually filled in from the manifest of the jar when it is built
+    let version = text(first.get("version")).and_then(|version| match version.as_str() {
+        "${file.jarVersion}" => jar_version.clone(),
+        _ => Some(version),
+    });
+    let dependencies = id
+        .as_deref()
+        .and_then(|id| table.get("dependencies")?.get(id)?.as_array())
+        .map(|dependencies| {
+            dependencies
+                .iter()
+                .filter_map(|dependency| {
+                    // Forge uses `mandatory`, NeoForge replaced it with `type`
+                    let required = match dependency.get("type").and_then(toml::Value::as_str) {
+                        Some(kind) => kind.eq_ignore_ascii_case("required"),
+                        None => dependency.get("mandatory").and_then(toml::Value::as_bool).unwrap_or(true),
+                    };
+                    Some(ContentDependency {
+                        id: text(dependency.get("modId"))?,
+                        version: text(dependency.get("versionRange")),
+                        required,
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ContentMetadata {
+        name: text(first.get("displayName")).or_else(|| id.clone()).unwrap_or_default(),
+        version,
+        description: text(first.get("description")).map(|description| description.trim().to_string()),
+        authors: text(first.get("authors"))
+            .map(|authors| split_authors(&authors))
+            .unwrap_or_default(),
+        dependencies,
+        id,
+    }
+}
+
+/// Reads the `mcmod.info` of mods for Forge before 1.13, a list of mods or an object holding one.
+fn parse_mcmod_info(json: &JsonValue) -> ContentMetadata {
+    let first = match json {
+        JsonValue::Array(mods) => mods.first(),
+        json => json["modList"].as_array().and_then(|mods| mods.first()),
+    };
+    let Some(first) = first else {
+        return ContentMetadata::default();
+    };
+    let strings = |value: &JsonValue| -> Vec<String> {
+        value.as_array().map(|values| values.iter().filter_map(json_string).collect()).unwrap_or_default()
+    };
+    let authors = match strings(&first["authorList"]) {
+        authors if authors.is_empty() => strings(&first["authors"]),
+        authors => authors,
+    };
+    // Dependencies are written as `modid@[version range]`
+    let dependencies = strings(&first["requiredMods"])
+        .into_iter()
+        .map(|dependency| match dependency.split_once('@') {
+            Some((id, version)) => ContentDependency {
+                id: id.to_string(),
+                version: Some(version.to_string()),
+                required: true,
+            },
+            None => ContentDependency {
+                id: dependency,
+                version: None,
+                required: true,
+            },
+        })
+        .collect();
+    ContentMetadata {
+        id: json_string(&first["modid"]),
+        name: json_string(&first["name"]).or_else(|| json_string(&first["modid"])).unwrap_or_default(),
+        version: json_string(&first["version"]),
+        description: json_string(&first["description"]),
+        authors,
+        dependencies,
+    }
+}
+
+fn yaml_string(value: &YamlValue) -> Option<String> {
+    match value {
+        YamlValue::String(value) => Some(value.clone()),
+        YamlValue::Number(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn yaml_strings(value: Option<&YamlValue>) -> Vec<String> {
+    match value {
+        Some(YamlValue::Sequence(values)) => values.iter().filter_map(yaml_string).collect(),
+        Some(value) => yaml_string(value).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+fn parse_plugin_yml(yaml: &YamlValue) -> ContentMetadata {
+    let mut authors = yaml_strings(yaml.get("author"));
+    authors.extend(yaml_strings(yaml.get("authors")));
+    let mut dependencies = Vec::new();
+    for (field, required) in [("depend", true), ("softdepend", false)] {
+        dependencies.extend(yaml_strings(yaml.get(field)).into_iter().map(|id| ContentDependency {
+            id,
+            version: None,
+            required,
+        }));
+    }
+    // Paper plugins declare their dependencies as a map of names per load phase
+    if let Some(YamlValue::Mapping(phases)) = yaml.get("dependencies") {
+        for phase in phases.values() {
+            if let YamlValue::Mapping(plugins) = phase {
+                dependencies.extend(plugins.iter().filter_map(|(id, options)| {
+                    Some(ContentDependency {
+                        id: yaml_string(id)?,
+                        version: None,
+                        required: options.get("required").and_then(YamlValue::as_bool).unwrap_or(true),
+                    })
+                }));
+            }
+        }
+    }
+    let name = yaml.get("name").and_then(yaml_string);
+    ContentMetadata {
+        id: name.clone(),
+        name: name.unwrap_or_default(),
+        version: yaml.get("version").and_then(yaml_string),
+        description: yaml.get("description").and_then(yaml_string),
+        authors,
+        dependencies,
+    }
+}
+
+fn parse_velocity(json: &JsonValue) -> ContentMetadata {
+    let dependencies = json["dependencies"]
+        .as_array()
+        .map(|dependencies| {
+            dependencies
+                .iter()
+                .filter_map(|dependency| {
+                    Some(ContentDependency {
+                        id: json_string(&dependency["id"])?,
+                        version: None,
+                        required: !dependency["optional"].as_bool().unwrap_or(false),
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    ContentMetadata {
+        id: json_string(&json["id"]),
+        name: json_string(&json["name"]).or_else(|| json_string(&json["id"])).unwrap_or_default(),
+        version: json_string(&json["version"]),
+        description: json_string(&json["description"]),
+        authors: json["authors"]
+            .as_array()
+            .map(|authors| authors.iter().filter_map(json_string).collect())
+            .unwrap_or_default(),
+        dependencies,
+    }
+}
+
+fn split_authors(authors: &str) -> Vec<String> {
+    authors
+        .split([',', '&'])
+        .map(str::trim)
+        .filter(|author| !author.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn manifest_value(manifest: &str, key: &str) -> Option<String> {
+    manifest.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        (name.trim() == key).then(|| value.trim().to_string())
+    })
+}
+
+/// Lists the mods and plugins of a server directory with the metadata their jars declare.
+///
+/// Jars without a known descriptor are still listed, named after their file.
+pub fn scan_inventory(directory: &Path) -> Result<Vec<InventoryEntry>, Box<dyn Error>> {
+    let mut inventory = Vec::new();
+    for folder in CONTENT_FOLDERS {
+        let Ok(entries) = fs::read_dir(directory.join(folder)) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            let enabled = !name.ends_with(DISABLED_SUFFIX);
+            if !name.trim_end_matches(DISABLED_SUFFIX).ends_with(".jar") || !path.is_file() {
+                continue;
+            }
+
+            let (kind, metadata) = match read_jar_metadata(&path) {
+                Ok(Some(found)) => found,
+                result => {
+                    if let Err(e) = result {
+                        debug!("Failed to read the metadata of {:?}: {}", path, e);
+                    }
+                    let kind = match *folder {
+                        "mods" => mod_kind(ModLoader::Unknown),
+                        _ => InventoryKind::Plugin { platform: None },
+                    };
+                    let name = name.trim_end_matches(DISABLED_SUFFIX).trim_end_matches(".jar").to_string();
+                    (kind, ContentMetadata { name, ..Default::default() })
+                }
+            };
+            inventory.push(InventoryEntry {
+                file: PathBuf::from(folder).join(&name),
+                size: entry.metadata().map(|metadata| metadata.len()).unwrap_or_default(),
+                enabled,
+                kind,
+                metadata,
+            });
+        }
+    }
+    inventory.sort_by_key(|entry| entry.metadata.name.to_lowercase());
+    Ok(inventory)
+}
+
+/// Enables or disables a mod or plugin by renaming `foo.jar` to `foo.jar.disabled` and back, which
+/// the loaders skip.
+///
+/// # Arguments
+/// * `directory` - The server directory.
+/// * `file` - The jar, enabled or disabled, relative to the server directory as listed in the inventory.
+/// * `enabled` - Whether the jar should be loaded.
+///
+/// # Returns
+/// The path of the jar after the rename, relative to the server directory.
+pub fn set_content_enabled(directory: &Path, file: &Path, enabled: bool) -> Result<PathBuf, Box<dyn Error>> {
+    let current = SandboxedPath::new(directory, file)?;
+    let relative = current.relative_path().to_path_buf();
+    let in_content_folder = relative
+        .parent()
+        .is_some_and(|parent| CONTENT_FOLDERS.iter().any(|folder| parent == Path::new(folder)));
+    let name = relative.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let jar_name = name.trim_end_matches(DISABLED_SUFFIX);
+    if !in_content_folder || !jar_name.ends_with(".jar") {
+        return Err(format!("{:?} is not a jar in mods/ or plugins/", file).into());
+    }
+    if !current.path().is_file() {
+        return Err(format!("{:?} does not exist", file).into());
+    }
+
+    let target_name = if enabled {
+        jar_name.to_string()
+    } else {
+        format!("{}{}", jar_name, DISABLED_SUFFIX)
+    };
+    let target = current.parent().ok_or("The jar has no folder")?.join(&target_name)?;
+    if target.relative_path() == current.relative_path() {
+        return Ok(relative);
+    }
+    if target.path().exists() {
+        return Err(format!("{:?} already exists", target.relative_path()).into());
+    }
+    fs::rename(current.path(), target.path())?;
+    info!("{} {:?}", if enabled { "Enabled" } else { "Disabled" }, relative);
+    Ok(target.relative_path().to_path_buf())
+}
+
+pub trait ServerInventory {
+    /// Lists the mods and plugins installed on the server, read from the metadata inside their jars.
+    fn get_inventory(&self) -> Result<Vec<InventoryEntry>, Box<dyn Error>>;
+
+    /// Enables or disables a mod or plugin without deleting it, taking effect on the next start.
+    ///
+    /// # Returns
+    /// The path of the jar after the rename, relative to the server directory.
+    fn set_content_enabled(&self, file: &Path, enabled: bool) -> Result<PathBuf, Box<dyn Error>>;
+}
+
+impl ServerInventory for Server<u64> {
+    fn get_inventory(&self) -> Result<Vec<InventoryEntry>, Box<dyn Error>> {
+        scan_inventory(&self.directory)
+    }
+
+    fn set_content_enabled(&self, file: &Path, enabled: bool) -> Result<PathBuf, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let current = SandboxedPath::new(&self.directory, file)?;
+        let renamed = set_content_enabled(&self.directory, file, enabled)?;
+        if renamed != current.relative_path() {
+            // Keep the record of jars installed from Modrinth pointing at the jar
+            rename_installed_file(self.id, current.relative_path(), &renamed)?;
+        }
+        Ok(renamed)
+    }
+}
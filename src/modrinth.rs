@@ -0,0 +1,419 @@
+use crate::datapacks::level_name;
+use crate::file_hash::HashAlgorithm;
+use crate::http_client::{download_file, get_json, get_json_with_query, ExpectedHash};
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use log::{info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const MODRINTH_API: &str = "https://api.modrinth.com/v2";
+
+/// The loaders Modrinth lists plugins under, anything else installs into `mods/`.
+const PLUGIN_LOADERS: &[&str] = &["bukkit", "spigot", "paper", "purpur", "folia", "sponge"];
+
+/// A search of the Modrinth projects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModrinthSearch {
+    pub query: String,
+    /// Only lists projects supporting any of these loaders, such as `fabric` or `paper`.
+    pub loaders: Vec<String>,
+    /// Only lists projects supporting any of these versions of the game.
+    pub game_versions: Vec<String>,
+    /// Only lists projects of this type, such as `mod`, `plugin` or `datapack`.
+    pub project_type: Option<String>,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl Default for ModrinthSearch {
+    fn default() -> Self {
+        Self {
+            query: String::new(),
+            loaders: Vec::new(),
+            game_versions: Vec::new(),
+            project_type: None,
+            limit: 20,
+            offset: 0,
+        }
+    }
+}
+
+/// A project found by a search.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModrinthProject {
+    pub project_id: String,
+    pub slug: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub project_type: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub downloads: u64,
+    pub icon_url: Option<String>,
+    /// The categories of the project, which include the loaders it supports.
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// The versions of the game the project supports.
+    #[serde(default)]
+    pub versions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModrinthSearchResults {
+    pub hits: Vec<ModrinthProject>,
+    pub offset: u32,
+    pub limit: u32,
+    pub total_hits: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModrinthFileHashes {
+    pub sha1: Option<String>,
+    pub sha512: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModrinthFile {
+    pub url: String,
+    pub filename: String,
+    /// Whether this is the main file of the version, others are sources or optional extras.
+    #[serde(default)]
+    pub primary: bool,
+    #[serde(default)]
+    pub size: u64,
+    pub hashes: ModrinthFileHashes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModrinthDependency {
+    pub project_id: Option<String>,
+    pub version_id: Option<String>,
+    /// `required`, `optional`, `incompatible` or `embedded`.
+    pub dependency_type: String,
+}
+
+/// A version of a project, with the files it consists of.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModrinthVersion {
+    pub id: String,
+    pub project_id: String,
+    pub name: String,
+    pub version_number: String,
+    /// `release`, `beta` or `alpha`.
+    pub version_type: String,
+    pub loaders: Vec<String>,
+    pub game_versions: Vec<String>,
+    pub files: Vec<ModrinthFile>,
+    #[serde(default)]
+    pub dependencies: Vec<ModrinthDependency>,
+}
+
+impl ModrinthVersion {
+    /// The main file of the version.
+    pub fn primary_file(&self) -> Option<&ModrinthFile> {
+        self.files.iter().find(|file| file.primary).or_else(|| self.files.first())
+    }
+}
+
+/// The folder of a server a version is installed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFolder {
+    Mods,
+    Plugins,
+    /// The `datapacks` folder of the world named in `server.properties`.
+    Datapacks,
+}
+
+impl ContentFolder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentFolder::Mods => "mods",
+            ContentFolder::Plugins => "plugins",
+            ContentFolder::Datapacks => "datapacks",
+        }
+    }
+
+    /// Picks the folder a version belongs in from the loaders it was published for.
+    pub fn for_loaders(loaders: &[String]) -> Self {
+        if loaders.iter().any(|loader| loader == "datapack") {
+            ContentFolder::Datapacks
+        } else if loaders.iter().any(|loader| PLUGIN_LOADERS.contains(&loader.as_str())) {
+            ContentFolder::Plugins
+        } else {
+            ContentFolder::Mods
+        }
+    }
+}
+
+impl FromStr for ContentFolder {
+    type Err = Box<dyn Error>;
+
+    fn from_str(folder: &str) -> Result<Self, Self::Err> {
+        match folder {
+            "mods" => Ok(ContentFolder::Mods),
+            "plugins" => Ok(ContentFolder::Plugins),
+            "datapacks" => Ok(ContentFolder::Datapacks),
+            other => Err(format!("Unknown content folder {:?}", other).into()),
+        }
+    }
+}
+
+/// A Modrinth project installed on a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstalledContent {
+    pub project_id: String,
+    pub version_id: String,
+    pub version_number: String,
+    pub folder: ContentFolder,
+    /// The installed file, relative to the server directory.
+    pub file: PathBuf,
+    pub sha1: String,
+    /// When the version was installed, as stored by the database.
+    pub installed_at: String,
+}
+
+/// Searches the Modrinth projects.
+pub fn search_projects(search: &ModrinthSearch) -> Result<ModrinthSearchResults, Box<dyn Error>> {
+    // Values within a facet group are combined with OR, the groups with AND
+    let mut facets: Vec<Vec<String>> = Vec::new();
+    if !search.loaders.is_empty() {
+        facets.push(search.loaders.iter().map(|loader| format!("categories:{}", loader)).collect());
+    }
+    if !search.game_versions.is_empty() {
+        facets.push(search.game_versions.iter().map(|version| format!("versions:{}", version)).collect());
+    }
+    if let Some(project_type) = &search.project_type {
+        facets.push(vec![format!("project_type:{}", project_type)]);
+    }
+    let facets = if facets.is_empty() { None } else { Some(serde_json::to_string(&facets)?) };
+    let limit = search.limit.clamp(1, 100).to_string();
+    let offset = search.offset.to_string();
+
+    let mut query = vec![
+        ("query", search.query.as_str()),
+        ("limit", limit.as_str()),
+        ("offset", offset.as_str()),
+    ];
+    if let Some(facets) = &facets {
+        query.push(("facets", facets.as_str()));
+    }
+    Ok(serde_json::from_value(get_json_with_query(&format!("{}/search", MODRINTH_API), &query)?)?)
+}
+
+/// Lists the versions of a project, newest first.
+///
+/// # Arguments
+/// * `project` - The id or slug of the project.
+/// * `loaders` - Only lists versions for any of these loaders, all versions if empty.
+/// * `game_versions` - Only lists versions for any of these versions of the game, all versions if empty.
+pub fn get_project_versions(
+    project: &str,
+    loaders: &[String],
+    game_versions: &[String],
+) -> Result<Vec<ModrinthVersion>, Box<dyn Error>> {
+    let loaders = serde_json::to_string(loaders)?;
+    let game_versions = serde_json::to_string(game_versions)?;
+    let mut query = Vec::new();
+    if loaders != "[]" {
+        query.push(("loaders", loaders.as_str()));
+    }
+    if game_versions != "[]" {
+        query.push(("game_versions", game_versions.as_str()));
+    }
+    let url = format!("{}/project/{}/version", MODRINTH_API, project);
+    Ok(serde_json::from_value(get_json_with_query(&url, &query)?)?)
+}
+
+/// Looks up a version by its id.
+pub fn get_version(version_id: &str) -> Result<ModrinthVersion, Box<dyn Error>> {
+    Ok(serde_json::from_value(get_json(&format!("{}/version/{}", MODRINTH_API, version_id))?)?)
+}
+
+/// Creates the table recording the Modrinth projects installed on the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_modrinth_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_modrinth_content` (
+            server_id INTEGER NOT NULL,                                 -- The server the project is installed on
+            project_id TEXT NOT NULL,                                   -- The Modrinth project
+            version_id TEXT NOT NULL,                                   -- The installed version of the project
+            version_number TEXT NOT NULL,                               -- The version as shown to users
+            folder TEXT NOT NULL,                                       -- mods, plugins or datapacks
+            file TEXT NOT NULL,                                         -- The file, relative to the server
+            sha1 TEXT NOT NULL,                                         -- The verified checksum of the file
+            installed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,  -- When the version was installed
+            PRIMARY KEY (server_id, project_id)
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Records the version of a Modrinth project installed on a server, replacing an earlier one.
+pub(crate) fn record_installed_content(server_id: u64, content: &InstalledContent) -> Result<(), Box<dyn Error>> {
+    let query = r#"
+INSERT INTO server_modrinth_content
+    (server_id, project_id, version_id, version_number, folder, file, sha1, installed_at)
+VALUES (?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP)
+ON CONFLICT(server_id, project_id) DO UPDATE SET
+    version_id = excluded.version_id,
+    version_number = excluded.version_number,
+    folder = excluded.folder,
+    file = excluded.file,
+    sha1 = excluded.sha1,
+    installed_at = excluded.installed_at
+"#;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, content.project_id.as_str()))?;
+    statement.bind((3, content.version_id.as_str()))?;
+    statement.bind((4, content.version_number.as_str()))?;
+    statement.bind((5, content.folder.as_str()))?;
+    statement.bind((6, content.file.to_string_lossy().as_ref()))?;
+    statement.bind((7, content.sha1.as_str()))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Follows a recorded file that was renamed, such as a jar that was disabled.
+pub(crate) fn rename_installed_file(server_id: u64, from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare(r#"UPDATE server_modrinth_content SET file = ? WHERE server_id = ? AND file = ?"#)?;
+    statement.bind((1, to.to_string_lossy().as_ref()))?;
+    statement.bind((2, server_id as i64))?;
+    statement.bind((3, from.to_string_lossy().as_ref()))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Lists the Modrinth projects installed on a server.
+pub fn list_installed_content(server_id: u64) -> Result<Vec<InstalledContent>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare(r#"SELECT * FROM server_modrinth_content WHERE server_id = ? ORDER BY folder, file"#)?;
+    statement.bind((1, server_id as i64))?;
+    let mut installed = Vec::new();
+    while let State::Row = statement.next()? {
+        installed.push(InstalledContent {
+            project_id: statement.read::<String, _>("project_id")?,
+            version_id: statement.read::<String, _>("version_id")?,
+            version_number: statement.read::<String, _>("version_number")?,
+            folder: statement.read::<String, _>("folder")?.parse()?,
+            file: PathBuf::from(statement.read::<String, _>("file")?),
+            sha1: statement.read::<String, _>("sha1")?,
+            installed_at: statement.read::<String, _>("installed_at")?,
+        });
+    }
+    Ok(installed)
+}
+
+pub trait ServerModrinth {
+    /// Downloads a version of a Modrinth project into the server and records it.
+    ///
+    /// The file is only placed once its SHA-1 matches the one Modrinth publishes. An older version of
+    /// the same project installed through Modrinth is replaced.
+    ///
+    /// # Arguments
+    /// * `version_id` - The version to install.
+    /// * `folder` - Where to install it, guessed from the loaders of the version if `None`.
+    fn install_modrinth_version(
+        &self,
+        version_id: &str,
+        folder: Option<ContentFolder>,
+    ) -> Result<InstalledContent, Box<dyn Error>>;
+
+    /// Lists the Modrinth projects installed on the server.
+    fn get_installed_content(&self) -> Result<Vec<InstalledContent>, Box<dyn Error>>;
+}
+
+impl ServerModrinth for Server<u64> {
+    fn install_modrinth_version(
+        &self,
+        version_id: &str,
+        folder: Option<ContentFolder>,
+    ) -> Result<InstalledContent, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let version = get_version(version_id)?;
+        let file = version
+            .primary_file()
+            .ok_or_else(|| format!("Version {} has no files", version.version_number))?;
+        // The name comes from the API, it must not reach outside the folder
+        if Path::new(&file.filename).file_name() != Some(OsStr::new(&file.filename)) {
+            return Err(format!("Invalid file name {:?}", file.filename).into());
+        }
+        let sha1 = file
+            .hashes
+            .sha1
+            .clone()
+            .ok_or_else(|| format!("Modrinth publishes no SHA-1 for {}", file.filename))?;
+
+        let folder = folder.unwrap_or_else(|| ContentFolder::for_loaders(&version.loaders));
+        let directory = match folder {
+            ContentFolder::Datapacks => PathBuf::from(level_name(self)).join("datapacks"),
+            folder => PathBuf::from(folder.as_str()),
+        };
+        let destination = SandboxedPath::new(&self.directory, directory.join(&file.filename))?;
+        if let Some(parent) = destination.path().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let expected = ExpectedHash {
+            algorithm: HashAlgorithm::Sha1,
+            hash: sha1.clone(),
+        };
+        download_file(&file.url, &destination.path(), Some(&expected), &mut |_, _| {})?;
+
+        let previous = list_installed_content(self.id)?
+            .into_iter()
+            .find(|installed| installed.project_id == version.project_id);
+        if let Some(previous) = previous.filter(|previous| previous.file != destination.relative_path()) {
+            match SandboxedPath::new(&self.directory, &previous.file) {
+                Ok(old) if old.path().exists() => {
+                    if let Err(e) = fs::remove_file(old.path()) {
+                        warn!("Failed to remove {:?}, replaced by {:?}: {}", previous.file, file.filename, e);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let installed = InstalledContent {
+            project_id: version.project_id.clone(),
+            version_id: version.id.clone(),
+            version_number: version.version_number.clone(),
+            folder,
+            file: destination.relative_path().to_path_buf(),
+            sha1,
+            installed_at: String::new(),
+        };
+        record_installed_content(self.id, &installed)?;
+        info!(
+            "Installed {} {} into {:?} of server {:?}",
+            version.name, version.version_number, installed.file, self.name
+        );
+        Ok(list_installed_content(self.id)?
+            .into_iter()
+            .find(|content| content.project_id == installed.project_id)
+            .unwrap_or(installed))
+    }
+
+    fn get_installed_content(&self) -> Result<Vec<InstalledContent>, Box<dyn Error>> {
+        list_installed_content(self.id)
+    }
+}
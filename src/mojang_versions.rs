@@ -0,0 +1,142 @@
+use crate::http_client::{download_file, get_json};
+use crate::provisioning::JarDownload;
+use lazy_static::lazy_static;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The manifest listing every version of the game.
+const VERSION_MANIFEST_URL: &str = "https://piston-meta.mojang.com/mc/game/version_manifest_v2.json";
+
+/// How long a fetched manifest is reused, new versions are rare enough.
+const MANIFEST_CACHE_DURATION: Duration = Duration::from_secs(10 * 60);
+
+lazy_static! {
+    static ref MANIFEST_CACHE: Mutex<Option<(Instant, VersionManifest)>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionType {
+    Release,
+    Snapshot,
+    OldBeta,
+    OldAlpha,
+}
+
+/// A version of the game as listed in the version manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MinecraftVersion {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub version_type: VersionType,
+    /// The metadata of the version, which holds the download of the server jar.
+    pub url: String,
+    /// The SHA-1 of the metadata.
+    pub sha1: String,
+    /// When the version was released, in ISO 8601.
+    #[serde(rename = "releaseTime")]
+    pub release_time: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatestVersions {
+    pub release: String,
+    pub snapshot: String,
+}
+
+/// The version manifest, listing the newest versions first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionManifest {
+    pub latest: LatestVersions,
+    pub versions: Vec<MinecraftVersion>,
+}
+
+impl VersionManifest {
+    /// Finds a version by its id, where `latest` and `latest-snapshot` stand for the newest ones.
+    pub fn find(&self, id: &str) -> Option<&MinecraftVersion> {
+        let id = match id {
+            "latest" => self.latest.release.as_str(),
+            "latest-snapshot" => self.latest.snapshot.as_str(),
+            id => id,
+        };
+        self.versions.iter().find(|version| version.id == id)
+    }
+}
+
+/// Fetches the version manifest, reusing a recently fetched one.
+pub fn get_version_manifest() -> Result<VersionManifest, Box<dyn Error>> {
+    if let Ok(cache) = MANIFEST_CACHE.lock() {
+        if let Some((fetched, manifest)) = cache.as_ref() {
+            if fetched.elapsed() < MANIFEST_CACHE_DURATION {
+                return Ok(manifest.clone());
+            }
+        }
+    }
+
+    let manifest: VersionManifest = serde_json::from_value(get_json(VERSION_MANIFEST_URL)?)?;
+    if let Ok(mut cache) = MANIFEST_CACHE.lock() {
+        *cache = Some((Instant::now(), manifest.clone()));
+    }
+    Ok(manifest)
+}
+
+/// Lists the versions of the game, newest first.
+///
+/// # Arguments
+/// * `include_snapshots` - Whether to list snapshots besides releases. Beta and alpha versions are
+///   never listed, they have no server jar.
+pub fn list_minecraft_versions(include_snapshots: bool) -> Result<Vec<MinecraftVersion>, Box<dyn Error>> {
+    Ok(get_version_manifest()?
+        .versions
+        .into_iter()
+        .filter(|version| {
+            version.version_type == VersionType::Release
+                || (include_snapshots && version.version_type == VersionType::Snapshot)
+        })
+        .collect())
+}
+
+/// Looks up the server jar of a version, along with the SHA-1 the manifest publishes for it.
+///
+/// # Errors
+/// Returns an error if the version does not exist or has no server jar, which is the case for
+/// the versions before 1.2.5.
+pub fn resolve_vanilla_server(version: &str) -> Result<JarDownload, Box<dyn Error>> {
+    let manifest = get_version_manifest()?;
+    let version = manifest
+        .find(version)
+        .ok_or_else(|| format!("Minecraft version {:?} does not exist", version))?;
+    let metadata = get_json(&version.url)?;
+    let server: &Value = &metadata["downloads"]["server"];
+    let url = server["url"]
+        .as_str()
+        .ok_or_else(|| format!("Minecraft {} has no server jar", version.id))?;
+    Ok(JarDownload {
+        url: url.to_string(),
+        minecraft_version: Some(version.id.clone()),
+        build: None,
+        sha1: server["sha1"].as_str().map(str::to_string),
+        sha256: None,
+        md5: None,
+    })
+}
+
+/// Downloads the vanilla server jar of a version, placing it at `destination` only once its
+/// SHA-1 matches the one of the manifest.
+///
+/// # Returns
+/// The download that was installed.
+pub fn download_vanilla_server(version: &str, destination: &Path) -> Result<JarDownload, Box<dyn Error>> {
+    let jar = resolve_vanilla_server(version)?;
+    let expected = jar
+        .expected_hash()
+        .ok_or_else(|| format!("The manifest has no checksum for the server jar of {}", version))?;
+    let size = download_file(&jar.url, destination, Some(&expected), &mut |_, _| {})?;
+    info!("Installed the vanilla server jar of {} ({} bytes)", version, size);
+    Ok(jar)
+}
@@ -0,0 +1,611 @@
+use crate::text_file::write_file_atomically;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use serde::ser::{SerializeMap, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Compounds and lists nested deeper than this are rejected, as the game does, so a crafted file
+/// cannot overflow the stack.
+const MAX_DEPTH: usize = 512;
+
+/// The largest uncompressed NBT file that is read, far above any `level.dat` or player file.
+const MAX_UNCOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A value of the Named Binary Tag format the game saves worlds and players in.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum NbtTag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    /// The elements of a list all have the same type.
+    List(Vec<NbtTag>),
+    Compound(NbtCompound),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+/// Named tags, in the order they were read so a file is written back the way it was.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NbtCompound {
+    entries: Vec<(String, NbtTag)>,
+}
+
+/// How an NBT file is compressed. `level.dat` and player files are gzip compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NbtCompression {
+    Gzip,
+    Zlib,
+    Uncompressed,
+}
+
+/// An NBT file, its root compound and how it was compressed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NbtFile {
+    /// The name of the root compound, which is empty in the files of the game.
+    pub name: String,
+    pub root: NbtCompound,
+    pub compression: NbtCompression,
+}
+
+impl NbtTag {
+    fn id(&self) -> u8 {
+        match self {
+            NbtTag::Byte(_) => 1,
+            NbtTag::Short(_) => 2,
+            NbtTag::Int(_) => 3,
+            NbtTag::Long(_) => 4,
+            NbtTag::Float(_) => 5,
+            NbtTag::Double(_) => 6,
+            NbtTag::ByteArray(_) => 7,
+            NbtTag::String(_) => 8,
+            NbtTag::List(_) => 9,
+            NbtTag::Compound(_) => 10,
+            NbtTag::IntArray(_) => 11,
+            NbtTag::LongArray(_) => 12,
+        }
+    }
+
+    /// The value of any numeric tag as an integer, the game itself reads numbers regardless of their width.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            NbtTag::Byte(value) => Some(i64::from(*value)),
+            NbtTag::Short(value) => Some(i64::from(*value)),
+            NbtTag::Int(value) => Some(i64::from(*value)),
+            NbtTag::Long(value) => Some(*value),
+            NbtTag::Float(value) => Some(*value as i64),
+            NbtTag::Double(value) => Some(*value as i64),
+            _ => None,
+        }
+    }
+
+    /// The value of any numeric tag as a floating point number.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            NbtTag::Float(value) => Some(f64::from(*value)),
+            NbtTag::Double(value) => Some(*value),
+            tag => tag.as_i64().map(|value| value as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            NbtTag::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[NbtTag]> {
+        match self {
+            NbtTag::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&NbtCompound> {
+        match self {
+            NbtTag::Compound(compound) => Some(compound),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound_mut(&mut self) -> Option<&mut NbtCompound> {
+        match self {
+            NbtTag::Compound(compound) => Some(compound),
+            _ => None,
+        }
+    }
+
+    /// The values of an int array, or of a list of ints as older versions wrote them.
+    pub fn as_int_array(&self) -> Option<Vec<i32>> {
+        match self {
+            NbtTag::IntArray(values) => Some(values.clone()),
+            NbtTag::List(values) => values.iter().map(|value| value.as_i64().map(|value| value as i32)).collect(),
+            _ => None,
+        }
+    }
+}
+
+impl NbtCompound {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&NbtTag> {
+        self.entries.iter().find(|(key, _)| key == name).map(|(_, tag)| tag)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut NbtTag> {
+        self.entries.iter_mut().find(|(key, _)| key == name).map(|(_, tag)| tag)
+    }
+
+    pub fn get_compound(&self, name: &str) -> Option<&NbtCompound> {
+        self.get(name).and_then(NbtTag::as_compound)
+    }
+
+    pub fn get_compound_mut(&mut self, name: &str) -> Option<&mut NbtCompound> {
+        self.get_mut(name).and_then(NbtTag::as_compound_mut)
+    }
+
+    pub fn get_i64(&self, name: &str) -> Option<i64> {
+        self.get(name).and_then(NbtTag::as_i64)
+    }
+
+    pub fn get_f64(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(NbtTag::as_f64)
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(NbtTag::as_str)
+    }
+
+    pub fn get_list(&self, name: &str) -> Option<&[NbtTag]> {
+        self.get(name).and_then(NbtTag::as_list)
+    }
+
+    /// Sets a tag, replacing an existing tag of the same name in place.
+    pub fn insert(&mut self, name: impl Into<String>, tag: NbtTag) {
+        let name = name.into();
+        match self.entries.iter_mut().find(|(key, _)| *key == name) {
+            Some((_, existing)) => *existing = tag,
+            None => self.entries.push((name, tag)),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<NbtTag> {
+        let index = self.entries.iter().position(|(key, _)| key == name)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &NbtTag)> {
+        self.entries.iter().map(|(key, tag)| (key.as_str(), tag))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl serde::Serialize for NbtCompound {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.entries.len()))?;
+        for (key, tag) in &self.entries {
+            map.serialize_entry(key, tag)?;
+        }
+        map.end()
+    }
+}
+
+struct NbtReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> NbtReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.data.len() < length {
+            return Err("The NBT data ends unexpectedly".into());
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Box<dyn Error>> {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, Box<dyn Error>> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, Box<dyn Error>> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, Box<dyn Error>> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    /// Reads an array length, checking that the data is long enough before anything is allocated.
+    fn length(&mut self, element_size: usize) -> Result<usize, Box<dyn Error>> {
+        let length = usize::try_from(self.i32()?).map_err(|_| "The NBT data contains a negative length")?;
+        if length.saturating_mul(element_size) > self.data.len() {
+            return Err("The NBT data ends unexpectedly".into());
+        }
+        Ok(length)
+    }
+
+    fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        let length = u16::from_be_bytes(self.array()?) as usize;
+        decode_modified_utf8(self.take(length)?)
+    }
+
+    fn payload(&mut self, id: u8, depth: usize) -> Result<NbtTag, Box<dyn Error>> {
+        if depth > MAX_DEPTH {
+            return Err("The NBT data is nested too deeply".into());
+        }
+        Ok(match id {
+            1 => NbtTag::Byte(self.u8()? as i8),
+            2 => NbtTag::Short(self.i16()?),
+            3 => NbtTag::Int(self.i32()?),
+            4 => NbtTag::Long(self.i64()?),
+            5 => NbtTag::Float(f32::from_be_bytes(self.array()?)),
+            6 => NbtTag::Double(f64::from_be_bytes(self.array()?)),
+            7 => {
+                let length = self.length(1)?;
+                NbtTag::ByteArray(self.take(length)?.iter().map(|byte| *byte as i8).collect())
+            }
+            8 => NbtTag::String(self.string()?),
+            9 => {
+                let element = self.u8()?;
+                // An empty list may have any element type, even one that can only be read as a list
+                let length = self.length(if element == 0 { 0 } else { 1 })?;
+                if element == 0 && length > 0 {
+                    return Err("The NBT data contains a list of end tags".into());
+                }
+                let mut values = Vec::with_capacity(length.min(self.data.len()));
+                for _ in 0..length {
+                    values.push(self.payload(element, depth + 1)?);
+                }
+                NbtTag::List(values)
+            }
+            10 => NbtTag::Compound(self.compound(depth + 1)?),
+            11 => {
+                let length = self.length(4)?;
+                NbtTag::IntArray((0..length).map(|_| self.i32()).collect::<Result<_, _>>()?)
+            }
+            12 => {
+                let length = self.length(8)?;
+                NbtTag::LongArray((0..length).map(|_| self.i64()).collect::<Result<_, _>>()?)
+            }
+            id => return Err(format!("The NBT data contains an unknown tag type {}", id).into()),
+        })
+    }
+
+    fn compound(&mut self, depth: usize) -> Result<NbtCompound, Box<dyn Error>> {
+        let mut compound = NbtCompound::new();
+        loop {
+            let id = self.u8()?;
+            if id == 0 {
+                return Ok(compound);
+            }
+            let name = self.string()?;
+            let tag = self.payload(id, depth)?;
+            compound.entries.push((name, tag));
+        }
+    }
+}
+
+/// Decodes the modified UTF-8 of Java, which encodes `\0` in two bytes and characters outside the
+/// basic multilingual plane as two encoded surrogates.
+fn decode_modified_utf8(bytes: &[u8]) -> Result<String, Box<dyn Error>> {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Ok(text.to_string());
+    }
+    let mut units = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    let invalid = || "The NBT data contains an invalid string";
+    while let Some(&first) = bytes.get(index) {
+        let continuation = |offset: usize| -> Result<u16, Box<dyn Error>> {
+            match bytes.get(index + offset) {
+                Some(byte) if byte & 0xc0 == 0x80 => Ok(u16::from(byte & 0x3f)),
+                _ => Err(invalid().into()),
+            }
+        };
+        let (unit, length) = if first & 0x80 == 0 {
+            (u16::from(first), 1)
+        } else if first & 0xe0 == 0xc0 {
+            ((u16::from(first & 0x1f) << 6) | continuation(1)?, 2)
+        } else if first & 0xf0 == 0xe0 {
+            ((u16::from(first & 0x0f) << 12) | (continuation(1)? << 6) | continuation(2)?, 3)
+        } else {
+            return Err(invalid().into());
+        };
+        units.push(unit);
+        index += length;
+    }
+    String::from_utf16(&units).map_err(|_| invalid().into())
+}
+
+fn encode_modified_utf8(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for unit in text.encode_utf16() {
+        match unit {
+            0x0001..=0x007f => bytes.push(unit as u8),
+            0x0000 | 0x0080..=0x07ff => {
+                bytes.push(0xc0 | (unit >> 6) as u8);
+                bytes.push(0x80 | (unit & 0x3f) as u8);
+            }
+            _ => {
+                bytes.push(0xe0 | (unit >> 12) as u8);
+                bytes.push(0x80 | ((unit >> 6) & 0x3f) as u8);
+                bytes.push(0x80 | (unit & 0x3f) as u8);
+            }
+        }
+    }
+    bytes
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) -> Result<(), Box<dyn Error>> {
+    let bytes = encode_modified_utf8(text);
+    let length = u16::try_from(bytes.len()).map_err(|_| "A string is too long for NBT")?;
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+fn write_length(out: &mut Vec<u8>, length: usize) -> Result<(), Box<dyn Error>> {
+    let length = i32::try_from(length).map_err(|_| "An array is too long for NBT")?;
+    out.extend_from_slice(&length.to_be_bytes());
+    Ok(())
+}
+
+fn write_payload(out: &mut Vec<u8>, tag: &NbtTag) -> Result<(), Box<dyn Error>> {
+    match tag {
+        NbtTag::Byte(value) => out.push(*value as u8),
+        NbtTag::Short(value) => out.extend_from_slice(&value.to_be_bytes()),
+        NbtTag::Int(value) => out.extend_from_slice(&value.to_be_bytes()),
+        NbtTag::Long(value) => out.extend_from_slice(&value.to_be_bytes()),
+        NbtTag::Float(value) => out.extend_from_slice(&value.to_be_bytes()),
+        NbtTag::Double(value) => out.extend_from_slice(&value.to_be_bytes()),
+        NbtTag::ByteArray(values) => {
+            write_length(out, values.len())?;
+            out.extend(values.iter().map(|value| *value as u8));
+        }
+        NbtTag::String(value) => write_string(out, value)?,
+        NbtTag::List(values) => {
+            let element = values.first().map_or(0, NbtTag::id);
+            if values.iter().any(|value| value.id() != element) {
+                return Err("The elements of an NBT list must all have the same type".into());
+            }
+            out.push(element);
+            write_length(out, values.len())?;
+            for value in values {
+                write_payload(out, value)?;
+            }
+        }
+        NbtTag::Compound(compound) => write_compound(out, compound)?,
+        NbtTag::IntArray(values) => {
+            write_length(out, values.len())?;
+            for value in values {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        NbtTag::LongArray(values) => {
+            write_length(out, values.len())?;
+            for value in values {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_compound(out: &mut Vec<u8>, compound: &NbtCompound) -> Result<(), Box<dyn Error>> {
+    for (name, tag) in &compound.entries {
+        out.push(tag.id());
+        write_string(out, name)?;
+        write_payload(out, tag)?;
+    }
+    out.push(0);
+    Ok(())
+}
+
+/// Parses uncompressed NBT data, which has to start with a named compound.
+///
+/// # Returns
+/// The name of the root compound and the compound.
+pub fn read_nbt(data: &[u8]) -> Result<(String, NbtCompound), Box<dyn Error>> {
+    let mut reader = NbtReader { data };
+    if reader.u8()? != 10 {
+        return Err("The NBT data does not start with a compound".into());
+    }
+    let name = reader.string()?;
+    let root = reader.compound(0)?;
+    Ok((name, root))
+}
+
+/// Encodes a root compound as uncompressed NBT data.
+pub fn write_nbt(name: &str, root: &NbtCompound) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = vec![10];
+    write_string(&mut out, name)?;
+    write_compound(&mut out, root)?;
+    Ok(out)
+}
+
+/// Reads an NBT file, detecting whether it is compressed.
+///
+/// # Errors
+/// Returns an error if the file cannot be read or is not valid NBT.
+pub fn read_nbt_file(path: &Path) -> Result<NbtFile, Box<dyn Error>> {
+    let raw = fs::read(path)?;
+    let mut data = Vec::new();
+    let compression = match raw.as_slice() {
+        [0x1f, 0x8b, ..] => {
+            GzDecoder::new(raw.as_slice()).take(MAX_UNCOMPRESSED_SIZE).read_to_end(&mut data)?;
+            NbtCompression::Gzip
+        }
+        [0x78, ..] => {
+            ZlibDecoder::new(raw.as_slice()).take(MAX_UNCOMPRESSED_SIZE).read_to_end(&mut data)?;
+            NbtCompression::Zlib
+        }
+        _ => NbtCompression::Uncompressed,
+    };
+    let data = if compression == NbtCompression::Uncompressed { &raw } else { &data };
+    let (name, root) = read_nbt(data).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    Ok(NbtFile {
+        name,
+        root,
+        compression,
+    })
+}
+
+/// Writes an NBT file atomically, compressed the way it was read.
+///
+/// # Errors
+/// Returns an error if the data cannot be encoded or the file cannot be written.
+pub fn write_nbt_file(path: &Path, file: &NbtFile) -> Result<(), Box<dyn Error>> {
+    let data = write_nbt(&file.name, &file.root)?;
+    let bytes = match file.compression {
+        NbtCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?
+        }
+        NbtCompression::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?
+        }
+        NbtCompression::Uncompressed => data,
+    };
+    write_file_atomically(path, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> NbtCompound {
+        let mut nested = NbtCompound::new();
+        nested.insert("Name", NbtTag::String("minecraft:overworld".to_string()));
+        let mut root = NbtCompound::new();
+        root.insert("Byte", NbtTag::Byte(-1));
+        root.insert("Short", NbtTag::Short(300));
+        root.insert("Int", NbtTag::Int(-70000));
+        root.insert("Long", NbtTag::Long(i64::MIN));
+        root.insert("Float", NbtTag::Float(0.5));
+        root.insert("Double", NbtTag::Double(-2.25));
+        root.insert("ByteArray", NbtTag::ByteArray(vec![-128, 0, 127]));
+        root.insert("String", NbtTag::String("nul \0 and emoji \u{1f600}".to_string()));
+        root.insert("List", NbtTag::List(vec![NbtTag::Compound(nested.clone()), NbtTag::Compound(nested)]));
+        root.insert("EmptyList", NbtTag::List(Vec::new()));
+        root.insert("IntArray", NbtTag::IntArray(vec![1, -2, 3]));
+        root.insert("LongArray", NbtTag::LongArray(vec![i64::MAX]));
+        root
+    }
+
+    /// A root compound holding a single tag with an empty name.
+    fn with_tag(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![10, 0, 0, id, 0, 0];
+        data.extend_from_slice(payload);
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn nbt_round_trips() -> Result<(), Box<dyn Error>> {
+        let data = write_nbt("", &sample())?;
+        let (name, root) = read_nbt(&data)?;
+        assert_eq!(name, "");
+        assert_eq!(root, sample());
+        Ok(())
+    }
+
+    #[test]
+    fn strings_use_modified_utf8() -> Result<(), Box<dyn Error>> {
+        assert_eq!(encode_modified_utf8("\0"), vec![0xc0, 0x80]);
+        // Characters outside the basic multilingual plane are two surrogates of three bytes each
+        assert_eq!(encode_modified_utf8("\u{1f600}").len(), 6);
+        assert_eq!(decode_modified_utf8(&encode_modified_utf8("a\0\u{1f600}é"))?, "a\0\u{1f600}é");
+        assert!(decode_modified_utf8(&[0xff]).is_err());
+        assert!(decode_modified_utf8(&[0xe0, 0x80]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_data_is_refused() -> Result<(), Box<dyn Error>> {
+        let data = write_nbt("root", &sample())?;
+        for length in 0..data.len() {
+            assert!(read_nbt(&data[..length]).is_err(), "{} of {} bytes", length, data.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_lengths_are_refused_before_allocating() {
+        let huge = i32::MAX.to_be_bytes();
+        for (id, payload) in [(7, huge.to_vec()), (11, huge.to_vec()), (12, huge.to_vec())] {
+            assert!(read_nbt(&with_tag(id, &payload)).is_err(), "{}", id);
+        }
+        let mut list = vec![3];
+        list.extend_from_slice(&huge);
+        assert!(read_nbt(&with_tag(9, &list)).is_err());
+        assert!(read_nbt(&with_tag(7, &(-1i32).to_be_bytes())).is_err());
+        // A string claiming more bytes than there are
+        assert!(read_nbt(&with_tag(8, &[0xff, 0xff, b'a'])).is_err());
+    }
+
+    #[test]
+    fn malformed_data_is_refused() {
+        assert!(read_nbt(&[]).is_err());
+        assert!(read_nbt(&[9, 0, 0, 0]).is_err());
+        assert!(read_nbt(&with_tag(13, &[])).is_err());
+        // A list of end tags can only be empty
+        assert!(read_nbt(&with_tag(9, &[0, 0, 0, 0, 1])).is_err());
+        assert!(read_nbt(&with_tag(9, &[0, 0, 0, 0, 0])).is_ok());
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() -> Result<(), Box<dyn Error>> {
+        let nested = |depth: usize| -> Vec<u8> {
+            let mut payload = Vec::new();
+            for _ in 0..depth {
+                payload.extend_from_slice(&[9, 0, 0, 0, 1]);
+            }
+            payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+            with_tag(9, &payload)
+        };
+        read_nbt(&nested(100))?;
+        assert!(read_nbt(&nested(MAX_DEPTH + 1)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn mixed_lists_are_not_written() {
+        let mut root = NbtCompound::new();
+        root.insert("List", NbtTag::List(vec![NbtTag::Int(1), NbtTag::Long(2)]));
+        assert!(write_nbt("", &root).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::backups::{add_backup_listener, BackupEventKind};
+use crate::crash_detection::add_crash_listener;
+use crate::online_players::{add_player_listener, PlayerEventKind};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Mutex, Once};
+use std::time::SystemTime;
+
+static FORWARDER: Once = Once::new();
+
+type NotificationListener = Box<dyn Fn(&Notification) + Send>;
+
+lazy_static! {
+    static ref NOTIFICATION_LISTENERS: Mutex<Vec<NotificationListener>> = Mutex::new(Vec::new());
+}
+
+/// What a notification is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationEvent {
+    /// A server finished starting and accepts players.
+    ServerStarted,
+    /// A server exited without crashing.
+    ServerStopped,
+    ServerCrashed,
+    PlayerJoined,
+    PlayerLeft,
+    BackupCompleted,
+    /// A backup or its upload to a remote target failed.
+    BackupFailed,
+    /// An alert rule started to match.
+    AlertFired,
+    /// An alert rule that fired stopped matching.
+    AlertResolved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// A message for the users of the manager, delivered by the notification listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    /// The server the notification is about, `None` for the manager as a whole.
+    pub server_id: Option<u64>,
+    pub severity: NotificationSeverity,
+    /// A short summary, like the subject of a mail.
+    pub title: String,
+    pub message: String,
+    pub at: SystemTime,
+}
+
+impl Notification {
+    /// Whether the notification passes the filters of a target, where an empty list of events or
+    /// servers lets every one through. Notifications about the manager itself pass any server filter.
+    pub(crate) fn matches(
+        &self,
+        events: &[NotificationEvent],
+        servers: &[u64],
+        min_severity: NotificationSeverity,
+    ) -> bool {
+        self.severity >= min_severity
+            && (events.is_empty() || events.contains(&self.event))
+            && self
+                .server_id
+                .map_or(true, |server_id| servers.is_empty() || servers.contains(&server_id))
+    }
+}
+
+/// Registers a listener that is invoked with every notification.
+pub fn add_notification_listener(listener: impl Fn(&Notification) + Send + 'static) {
+    if let Ok(mut listeners) = NOTIFICATION_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+/// Turns the crashes, player joins and leaves and backups of the servers into notifications.
+/// Calling it again has no effect.
+pub fn start_event_notifications() {
+    FORWARDER.call_once(|| {
+        add_crash_listener(|report| {
+            let message = match report.restart_delay_seconds {
+                Some(delay) => format!("It is restarted in {} seconds.", delay),
+                None => "It is not restarted.".to_string(),
+            };
+            notify_server(
+                report.server_id,
+                NotificationEvent::ServerCrashed,
+                NotificationSeverity::Critical,
+                "crashed",
+                message,
+            );
+        });
+        add_player_listener(|event| {
+            let (kind, action) = match event.kind {
+                PlayerEventKind::Joined => (NotificationEvent::PlayerJoined, "joined"),
+                PlayerEventKind::Left => (NotificationEvent::PlayerLeft, "left"),
+            };
+            notify_server(
+                event.server_id,
+                kind,
+                NotificationSeverity::Info,
+                &format!("{} {}", action, event.player.name),
+                format!("{} {} the server.", event.player.name, action),
+            );
+        });
+        add_backup_listener(|event| {
+            let error = event.message.clone().unwrap_or_default();
+            let (kind, severity, what, message) = match event.kind {
+                BackupEventKind::Completed => (
+                    NotificationEvent::BackupCompleted,
+                    NotificationSeverity::Info,
+                    "was backed up",
+                    format!("The backup {} was created.", event.backup_id),
+                ),
+                BackupEventKind::Failed => (
+                    NotificationEvent::BackupFailed,
+                    NotificationSeverity::Critical,
+                    "failed to back up",
+                    format!("The backup {} failed: {}", event.backup_id, error),
+                ),
+                BackupEventKind::UploadFailed => (
+                    NotificationEvent::BackupFailed,
+                    NotificationSeverity::Warning,
+                    "failed to upload a backup",
+                    format!("The backup {} could not be uploaded: {}", event.backup_id, error),
+                ),
+                _ => return,
+            };
+            notify_server(event.server_id, kind, severity, what, message);
+        });
+        info!("Started sending notifications for server events");
+    });
+}
+
+/// Sends a notification about a server, titled with its name followed by `what`.
+pub(crate) fn notify_server(
+    server_id: u64,
+    event: NotificationEvent,
+    severity: NotificationSeverity,
+    what: &str,
+    message: String,
+) {
+    let name = Server::<u64>::get_server(server_id)
+        .map(|server| server.name)
+        .unwrap_or_else(|_| format!("#{}", server_id));
+    notify(Notification {
+        event,
+        server_id: Some(server_id),
+        severity,
+        title: format!("Server {} {}", name, what),
+        message,
+        at: SystemTime::now(),
+    });
+}
+
+/// Sends a notification to the notification listeners.
+pub fn notify(notification: Notification) {
+    info!("Notification for server {:?}: {}", notification.server_id, notification.title);
+    match NOTIFICATION_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(&notification)),
+        Err(err) => error!("Failed to notify notification listeners: {}", err),
+    }
+}
@@ -0,0 +1,309 @@
+use crate::console_line::{parse_console_line, ConsoleTag};
+use crate::permissions::{authorize, Capability};
+use crate::rcon::rcon_command;
+use crate::server::Server;
+use crate::server_ping::get_live_status;
+use crate::server_process::{send_server_command, watch_console, ServerProcess};
+use lazy_static::lazy_static;
+use log::{debug, info};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+type PlayerListener = Box<dyn Fn(&PlayerEvent) + Send>;
+
+lazy_static! {
+    static ref ONLINE_PLAYERS: Mutex<HashMap<u64, Vec<OnlinePlayer>>> = Mutex::new(HashMap::new());
+    /// UUIDs the authenticator logged for players who have not finished logging in yet.
+    static ref PENDING_UUIDS: Mutex<HashMap<(u64, String), String>> = Mutex::new(HashMap::new());
+    static ref PLAYER_LISTENERS: Mutex<Vec<PlayerListener>> = Mutex::new(Vec::new());
+    /// `UUID of player Steve is 069a79f4-44e9-4726-a5be-fca90e38aaf5`
+    static ref UUID_MESSAGE: Option<Regex> =
+        Regex::new(r"^UUID of player (?P<player>[A-Za-z0-9_.]+) is (?P<uuid>[0-9a-fA-F-]{32,36})").ok();
+    /// `Steve[/127.0.0.1:51234] logged in with entity id 123 at (0.5, 64.0, 0.5)`
+    static ref LOGIN_MESSAGE: Option<Regex> =
+        Regex::new(r"^(?P<player>[A-Za-z0-9_.]+)\[/?(?P<address>[^\]]*)\] logged in with entity id").ok();
+    static ref DISCONNECT_MESSAGE: Option<Regex> =
+        Regex::new(r"^(?P<player>[A-Za-z0-9_.]+) lost connection: ").ok();
+}
+
+/// A player that is currently on a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct OnlinePlayer {
+    pub name: String,
+    /// `None` if the player was only seen in a query response.
+    pub uuid: Option<String>,
+    /// The address the player connected from, the one of the proxy for servers behind one.
+    pub ip: Option<String>,
+    pub joined_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerEventKind {
+    Joined,
+    Left,
+}
+
+/// A player joined or left a server started by the manager.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerEvent {
+    pub server_id: u64,
+    pub kind: PlayerEventKind,
+    pub player: OnlinePlayer,
+    pub at: SystemTime,
+}
+
+/// An action on an online player.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlayerAction {
+    Kick { reason: Option<String> },
+    Ban { reason: Option<String> },
+    /// Bans the address the player connected from.
+    BanIp { reason: Option<String> },
+    Op,
+    Deop,
+    /// Teleports the player to the spawn of the overworld.
+    TeleportToSpawn,
+}
+
+/// Registers a listener called whenever a player joins or leaves a server.
+pub fn add_player_listener(listener: impl Fn(&PlayerEvent) + Send + 'static) {
+    if let Ok(mut listeners) = PLAYER_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn emit(event: &PlayerEvent) {
+    if let Ok(listeners) = PLAYER_LISTENERS.lock() {
+        for listener in listeners.iter() {
+            listener(event);
+        }
+    }
+}
+
+/// Strips the port from an address as the game logs it, `127.0.0.1:51234` or `[::1]:51234`.
+fn strip_port(address: &str) -> String {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']').to_string()
+}
+
+fn player_joined(server_id: u64, name: &str, ip: Option<String>) {
+    let uuid = PENDING_UUIDS
+        .lock()
+        .ok()
+        .and_then(|mut pending| pending.remove(&(server_id, name.to_lowercase())));
+    let event = {
+        let Ok(mut online) = ONLINE_PLAYERS.lock() else {
+            return;
+        };
+        let players = online.entry(server_id).or_default();
+        // The login line comes before the join message, which adds nothing new
+        if let Some(player) = players.iter_mut().find(|player| player.name.eq_ignore_ascii_case(name)) {
+            player.uuid = player.uuid.take().or(uuid);
+            player.ip = player.ip.take().or(ip);
+            return;
+        }
+        let player = OnlinePlayer {
+            name: name.to_string(),
+            uuid,
+            ip,
+            joined_at: SystemTime::now(),
+        };
+        players.push(player.clone());
+        PlayerEvent {
+            server_id,
+            kind: PlayerEventKind::Joined,
+            player,
+            at: SystemTime::now(),
+        }
+    };
+    debug!("Player {} joined server {}", name, server_id);
+    emit(&event);
+}
+
+fn player_left(server_id: u64, name: &str) {
+    let player = {
+        let Ok(mut online) = ONLINE_PLAYERS.lock() else {
+            return;
+        };
+        let Some(players) = online.get_mut(&server_id) else {
+            return;
+        };
+        let Some(index) = players.iter().position(|player| player.name.eq_ignore_ascii_case(name)) else {
+            return;
+        };
+        players.remove(index)
+    };
+    debug!("Player {} left server {}", name, server_id);
+    emit(&PlayerEvent {
+        server_id,
+        kind: PlayerEventKind::Left,
+        player,
+        at: SystemTime::now(),
+    });
+}
+
+fn track_console_line(server_id: u64, line: &str) {
+    let line = parse_console_line(line);
+    let message = line.message.as_str();
+    let captures = |pattern: &Option<Regex>| pattern.as_ref().and_then(|pattern| pattern.captures(message));
+
+    if let Some(captures) = captures(&UUID_MESSAGE) {
+        if let (Some(player), Some(uuid)) = (captures.name("player"), captures.name("uuid")) {
+            if let Ok(mut pending) = PENDING_UUIDS.lock() {
+                pending.insert((server_id, player.as_str().to_lowercase()), uuid.as_str().to_lowercase());
+            }
+        }
+    } else if let Some(captures) = captures(&LOGIN_MESSAGE) {
+        if let Some(player) = captures.name("player") {
+            let ip = captures.name("address").map(|address| strip_port(address.as_str()));
+            player_joined(server_id, player.as_str(), ip.filter(|ip| !ip.is_empty()));
+        }
+    } else if let Some(captures) = captures(&DISCONNECT_MESSAGE) {
+        if let Some(player) = captures.name("player") {
+            player_left(server_id, player.as_str());
+        }
+    } else if let Some(player) = &line.player {
+        if line.tags.contains(&ConsoleTag::Join) {
+            player_joined(server_id, player, None);
+        } else if line.tags.contains(&ConsoleTag::Leave) {
+            player_left(server_id, player);
+        }
+    }
+}
+
+/// Starts following the console of a server that was just started for players joining and leaving.
+pub(crate) fn track_online_players(server_id: u64) {
+    forget_online_players(server_id);
+    watch_console(server_id, move |line| {
+        track_console_line(server_id, line);
+        true
+    });
+}
+
+/// Forgets the players of a server that exited. They are reported as having left, as a crashed
+/// server never logs them leaving.
+pub(crate) fn forget_online_players(server_id: u64) {
+    if let Ok(mut pending) = PENDING_UUIDS.lock() {
+        pending.retain(|(id, _), _| *id != server_id);
+    }
+    let players = ONLINE_PLAYERS
+        .lock()
+        .ok()
+        .and_then(|mut online| online.remove(&server_id))
+        .unwrap_or_default();
+    for player in players {
+        emit(&PlayerEvent {
+            server_id,
+            kind: PlayerEventKind::Left,
+            player,
+            at: SystemTime::now(),
+        });
+    }
+}
+
+/// The number of players the console of a server reported as online.
+pub(crate) fn online_player_count(server_id: u64) -> usize {
+    ONLINE_PLAYERS
+        .lock()
+        .ok()
+        .and_then(|online| online.get(&server_id).map(Vec::len))
+        .unwrap_or(0)
+}
+
+/// Sends a command through the console of a server the manager started, or over RCON otherwise.
+fn dispatch_command(server: &Server<u64>, command: &str) -> Result<(), Box<dyn Error>> {
+    if server.is_server_running() {
+        send_server_command(server, command)
+    } else {
+        rcon_command(server, command).map(|_| ())
+    }
+}
+
+pub trait ServerOnlinePlayers {
+    /// Lists the players on the server, by joining time.
+    ///
+    /// Players are tracked from the console of servers the manager started. When the status monitor
+    /// has a query response, it adds the players of servers started elsewhere and drops any player
+    /// whose leave message was missed.
+    fn get_online_players(&self) -> Vec<OnlinePlayer>;
+
+    /// Runs an action on a player, through the console or over RCON if the manager did not start the server.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not send commands, the command cannot be sent, or the
+    /// address of the player is not known for [`PlayerAction::BanIp`].
+    fn perform_player_action(&self, player: &str, action: &PlayerAction) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerOnlinePlayers for Server<u64> {
+    fn get_online_players(&self) -> Vec<OnlinePlayer> {
+        let mut players = ONLINE_PLAYERS
+            .lock()
+            .ok()
+            .and_then(|online| online.get(&self.id).cloned())
+            .unwrap_or_default();
+        if let Some(status) = get_live_status(self.id) {
+            if let Some(query) = status.query {
+                players.retain(|player| {
+                    // A query older than the join does not know about the player yet
+                    player.joined_at > status.checked_at || query.players.iter().any(|name| name == &player.name)
+                });
+                for name in query.players {
+                    if !players.iter().any(|player| player.name == name) {
+                        players.push(OnlinePlayer {
+                            name,
+                            uuid: None,
+                            ip: None,
+                            joined_at: status.checked_at,
+                        });
+                    }
+                }
+            }
+        }
+        players.sort_by_key(|player| player.joined_at);
+        players
+    }
+
+    fn perform_player_action(&self, player: &str, action: &PlayerAction) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        if player.is_empty() || player.contains(char::is_whitespace) {
+            return Err(format!("{:?} is not a valid player name", player).into());
+        }
+        // A line break in the reason would end the command and start another
+        let with_reason = |command: String, reason: &Option<String>| match reason {
+            Some(reason) if !reason.trim().is_empty() => {
+                format!("{} {}", command, reason.replace(['\r', '\n'], " ").trim())
+            }
+            _ => command,
+        };
+        let command = match action {
+            PlayerAction::Kick { reason } => with_reason(format!("kick {}", player), reason),
+            PlayerAction::Ban { reason } => with_reason(format!("ban {}", player), reason),
+            PlayerAction::BanIp { reason } => {
+                let ip = self
+                    .get_online_players()
+                    .into_iter()
+                    .find(|online| online.name.eq_ignore_ascii_case(player))
+                    .and_then(|online| online.ip)
+                    .ok_or_else(|| format!("The address of {} is not known", player))?;
+                with_reason(format!("ban-ip {}", ip), reason)
+            }
+            PlayerAction::Op => format!("op {}", player),
+            PlayerAction::Deop => format!("deop {}", player),
+            // Commands from the console and RCON run at the world spawn
+            PlayerAction::TeleportToSpawn => format!("execute in minecraft:overworld run tp {} ~ ~ ~", player),
+        };
+        dispatch_command(self, &command)?;
+        info!("Sent {:?} for {} to server {:?}", command, player, self.name);
+        Ok(())
+    }
+}
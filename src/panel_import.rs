@@ -0,0 +1,735 @@
+use crate::archive_entries::ArchiveFormat;
+use crate::archive_extractor::{extract_archive, ExtractionOptions};
+use crate::backups::BackupOptions;
+use crate::crash_detection::{set_restart_policy, RestartPolicy};
+use crate::launch_templates::{set_launch_template, LaunchTemplate};
+use crate::sandboxed_path::SandboxedPath;
+use crate::scheduled_tasks::{
+    create_scheduled_task, MissedRunPolicy, ScheduledTask, TaskAction, TaskDefinition, TaskTrigger,
+};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_import::{adopt_server, AdoptServerOptions};
+use crate::server_registry::{delete_server, unique_directory, SERVERS_DIRECTORY};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::{Captures, Regex};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Variables Pterodactyl sets for its own use, which mean nothing to the server once it is imported.
+const PTERODACTYL_INTERNAL_VARIABLES: &[&str] =
+    &["STARTUP", "P_SERVER_LOCATION", "P_SERVER_UUID", "P_SERVER_ALLOCATION_LIMIT"];
+
+lazy_static! {
+    /// A variable in a start command: `{{NAME}}` and `{{env.NAME}}` in Pterodactyl, `${NAME}` in a shell.
+    static ref COMMAND_VARIABLE: Option<Regex> =
+        Regex::new(r"\{\{(?:env\.)?([A-Za-z_][A-Za-z0-9_.]*)\}\}|\$\{([A-Za-z_][A-Za-z0-9_]*)\}").ok();
+}
+
+/// A server of another panel, and where its files and settings are.
+///
+/// The files may also be an export archive, like a Pterodactyl backup or an AMP instance backup.
+/// It is extracted into a new directory below [`SERVERS_DIRECTORY`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "panel", rename_all = "snake_case")]
+pub enum PanelSource {
+    /// A server of Pterodactyl, or a fork of it like Pelican.
+    Pterodactyl {
+        /// The volume Wings keeps the files in, `/var/lib/pterodactyl/volumes/<uuid>` by default.
+        files: PathBuf,
+        /// The server as returned by the application API (`/api/application/servers/<id>`) or the
+        /// startup of the client API (`/api/client/servers/<id>/startup`), saved as a file.
+        #[serde(default)]
+        server: Option<PathBuf>,
+        /// The schedules as returned by `/api/client/servers/<id>/schedules?include=tasks`.
+        #[serde(default)]
+        schedules: Option<PathBuf>,
+    },
+    /// An instance of AMP, like `~/.ampdata/instances/Survival01`.
+    Amp { instance: PathBuf },
+    /// An instance of MCSManager, by its configuration in the data directory of the daemon,
+    /// `data/InstanceConfig/<uuid>.json`.
+    McsManager {
+        config: PathBuf,
+        /// The files of the instance, the working directory of its configuration if not set.
+        #[serde(default)]
+        files: Option<PathBuf>,
+    },
+}
+
+impl PanelSource {
+    /// The directory or archive holding the files of the server, if it is given directly.
+    fn files(&self) -> Option<&Path> {
+        match self {
+            PanelSource::Pterodactyl { files, .. } => Some(files),
+            PanelSource::Amp { instance } => Some(instance),
+            PanelSource::McsManager { files, .. } => files.as_deref(),
+        }
+    }
+}
+
+/// How a server of another panel is imported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PanelImportOptions {
+    #[serde(flatten)]
+    pub source: PanelSource,
+    /// The name of the server, the name it had in the other panel if empty.
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub owner: u64,
+    #[serde(default)]
+    pub members: Vec<u64>,
+}
+
+/// An imported server and what became of its settings.
+#[derive(Debug, Clone, Serialize)]
+pub struct PanelImport {
+    pub server: Server<u64>,
+    /// The scheduled tasks created from the schedules of the other panel.
+    pub tasks: Vec<ScheduledTask>,
+    /// What could not be carried over, or was carried over differently.
+    pub warnings: Vec<String>,
+}
+
+/// What was read from the other panel.
+#[derive(Debug, Default)]
+struct PanelSettings {
+    name: Option<String>,
+    directory: PathBuf,
+    start_command: Option<String>,
+    environment: BTreeMap<String, String>,
+    memory_mb: Option<u64>,
+    java_runtime: Option<PathBuf>,
+    java_arguments: Option<String>,
+    minecraft_version: Option<String>,
+    auto_start: bool,
+    restart_on_crash: bool,
+    tasks: Vec<TaskDefinition>,
+    warnings: Vec<String>,
+}
+
+/// What a Java start command was taken apart into.
+#[derive(Debug, Default)]
+struct StartCommand {
+    start_file: Option<PathBuf>,
+    /// The Java runtime, when the command names one by its path.
+    java: Option<PathBuf>,
+    min_ram: Option<u64>,
+    max_ram: Option<u64>,
+    java_arguments: Vec<String>,
+    minecraft_arguments: Vec<String>,
+    /// The command with its variables expanded, when it does not start a jar directly and has
+    /// to be kept as a launch template.
+    custom: Option<String>,
+}
+
+/// Imports a server of Pterodactyl, AMP or MCSManager. The files stay where they are, unless they
+/// come as an export archive, and the server is registered like an adopted one, see
+/// [`adopt_server`].
+///
+/// Besides the files, what the other panel knew is carried over where the manager has a match:
+/// the start command becomes the start file, heap and arguments of the server, or a launch
+/// template when it does not start a jar directly. Environment variables go into the launch
+/// template and schedules become scheduled tasks.
+///
+/// # Errors
+/// Returns an error if the settings of the other panel cannot be read, or the server cannot be
+/// registered. Nothing is left registered then, and an extracted archive is removed again.
+pub fn import_from_panel(options: &PanelImportOptions) -> Result<PanelImport, Box<dyn Error>> {
+    let extracted = match options.source.files() {
+        Some(files) if files.is_file() && ArchiveFormat::from_path(files).is_some() => {
+            Some(extract_export(files, &options.name)?)
+        }
+        _ => None,
+    };
+    let files = extracted.as_deref().map(export_files);
+    let result = read_panel_settings(&options.source, files.as_deref()).and_then(|settings| {
+        info!("Importing {:?} from {:?}", settings.name.as_deref().unwrap_or("a server"), settings.directory);
+        register(options, settings)
+    });
+    if result.is_err() {
+        if let Some(directory) = &extracted {
+            if let Err(e) = fs::remove_dir_all(directory) {
+                warn!("Failed to remove the extracted export {:?}: {}", directory, e);
+            }
+        }
+    }
+    result
+}
+
+/// Extracts an export archive into a new server directory.
+fn extract_export(archive: &Path, name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let name = match name.trim() {
+        "" => archive.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default(),
+        name => name.to_string(),
+    };
+    fs::create_dir_all(SERVERS_DIRECTORY)?;
+    let directory = unique_directory(Path::new(SERVERS_DIRECTORY), &name);
+    let destination = SandboxedPath::new(SERVERS_DIRECTORY, directory.file_name().ok_or("Invalid server name")?)?;
+    let parent = archive.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let source = SandboxedPath::new(parent, archive.file_name().ok_or("Invalid archive path")?)?;
+    extract_archive(&source, &destination, &ExtractionOptions::default(), |_| {})?;
+    Ok(directory)
+}
+
+/// The directory holding the files of an extracted export, which is the single directory an
+/// archive may wrap them in.
+fn export_files(directory: &Path) -> PathBuf {
+    let entries: Vec<PathBuf> = fs::read_dir(directory)
+        .map(|entries| entries.filter_map(Result::ok).map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    match entries.as_slice() {
+        [single] if single.is_dir() => single.clone(),
+        _ => directory.to_path_buf(),
+    }
+}
+
+fn read_panel_settings(source: &PanelSource, extracted: Option<&Path>) -> Result<PanelSettings, Box<dyn Error>> {
+    match source {
+        PanelSource::Pterodactyl { files, server, schedules } => {
+            read_pterodactyl(extracted.unwrap_or(files), server.as_deref(), schedules.as_deref())
+        }
+        PanelSource::Amp { instance } => read_amp(extracted.unwrap_or(instance)),
+        PanelSource::McsManager { config, files } => read_mcsmanager(config, extracted.or(files.as_deref())),
+    }
+}
+
+/// Registers the server and applies the settings of the other panel to it.
+fn register(options: &PanelImportOptions, settings: PanelSettings) -> Result<PanelImport, Box<dyn Error>> {
+    let mut warnings = settings.warnings.clone();
+    let start = match &settings.start_command {
+        Some(command) => parse_start_command(command, &settings.environment)?,
+        None => StartCommand::default(),
+    };
+
+    let max_ram = start.max_ram.or(settings.memory_mb.map(megabytes_to_gigabytes)).unwrap_or(2);
+    // A script started by a custom command is still the start file of the server
+    let start_file = start.start_file.clone().or_else(|| {
+        let arguments = shell_words::split(start.custom.as_deref()?).ok()?;
+        arguments.iter().skip(1).map(PathBuf::from).find(|file| settings.directory.join(file).is_file())
+    });
+    let java_runtime = start.java.clone().or(settings.java_runtime.clone()).filter(|java| {
+        let exists = java.is_file();
+        if !exists {
+            warnings.push(format!("The Java runtime {:?} does not exist here, an installed one is used", java));
+        }
+        exists
+    });
+    let name = match options.name.trim() {
+        "" => settings.name.clone().unwrap_or_default(),
+        name => name.to_string(),
+    };
+    let adopt = AdoptServerOptions {
+        directory: settings.directory.clone(),
+        name,
+        owner: options.owner,
+        members: options.members.clone(),
+        min_ram: start.min_ram.unwrap_or(1).min(max_ram),
+        max_ram,
+        start_file,
+        minecraft_version: settings.minecraft_version.clone(),
+        java_runtime,
+        ..Default::default()
+    };
+    let mut server = adopt_server(&adopt)?;
+
+    match configure(&mut server, &start, &settings, &mut warnings) {
+        Ok(tasks) => {
+            info!("Imported server {:?} with {} scheduled tasks", server.name, tasks.len());
+            Ok(PanelImport { server, tasks, warnings })
+        }
+        Err(e) => {
+            // The files were not touched, so only the registration is undone
+            if let Err(cleanup) = delete_server(server.id, false) {
+                warn!("Failed to remove the half imported server {:?}: {}", server.name, cleanup);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn configure(
+    server: &mut Server<u64>,
+    start: &StartCommand,
+    settings: &PanelSettings,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<ScheduledTask>, Box<dyn Error>> {
+    let mut java_arguments = start.java_arguments.clone();
+    if let Some(arguments) = &settings.java_arguments {
+        let arguments = shell_words::split(arguments).map_err(|_| "The Java arguments of the server are invalid")?;
+        java_arguments.extend(arguments);
+    }
+    server.java_arguments = (!java_arguments.is_empty()).then(|| shell_words::join(&java_arguments));
+    server.minecraft_arguments =
+        (!start.minecraft_arguments.is_empty()).then(|| shell_words::join(&start.minecraft_arguments));
+    server.auto_start = settings.auto_start;
+    server.update()?;
+
+    if start.custom.is_some() || !settings.environment.is_empty() {
+        let template = LaunchTemplate {
+            command: start.custom.clone(),
+            environment: settings.environment.clone(),
+            ..Default::default()
+        };
+        set_launch_template(server.id, &template)?;
+    }
+    if settings.restart_on_crash {
+        let policy = RestartPolicy {
+            enabled: true,
+            ..Default::default()
+        };
+        set_restart_policy(server.id, &policy)?;
+    }
+
+    let mut tasks = Vec::new();
+    for definition in &settings.tasks {
+        match create_scheduled_task(server.id, definition) {
+            Ok(task) => tasks.push(task),
+            Err(e) => warnings.push(format!("The schedule {:?} could not be imported: {}", definition.name, e)),
+        }
+    }
+    Ok(tasks)
+}
+
+/// Takes a start command apart into what the managed command of the manager needs. Commands
+/// that do not start a jar or an argument file with Java are kept whole, as a launch template.
+fn parse_start_command(command: &str, environment: &BTreeMap<String, String>) -> Result<StartCommand, Box<dyn Error>> {
+    let expanded = expand_variables(command, environment);
+    let arguments = shell_words::split(&expanded)
+        .map_err(|_| format!("The start command {:?} is not a valid command line", expanded))?;
+    let mut start = StartCommand::default();
+    let (program, rest) = arguments.split_first().ok_or("The start command is empty")?;
+    let name = Path::new(program).file_stem().map(|stem| stem.to_string_lossy().to_lowercase());
+    if name.as_deref() != Some("java") {
+        start.custom = Some(expanded);
+        return Ok(start);
+    }
+    if program != "java" {
+        start.java = Some(PathBuf::from(program));
+    }
+
+    let mut rest = rest.iter().filter(|argument| !matches!(argument.as_str(), "$@" | "%*"));
+    while let Some(argument) = rest.next() {
+        if let Some(size) = argument.strip_prefix("-Xmx") {
+            start.max_ram = heap_gigabytes(size);
+        } else if let Some(size) = argument.strip_prefix("-Xms") {
+            start.min_ram = heap_gigabytes(size);
+        } else if argument == "-jar" {
+            start.start_file = rest.next().map(PathBuf::from);
+            break;
+        } else if let Some(file) = argument.strip_prefix('@').filter(|file| file.starts_with("libraries")) {
+            // The argument file of Forge and NeoForge, holding the class path and main class
+            start.start_file = Some(PathBuf::from(file));
+            break;
+        } else {
+            start.java_arguments.push(argument.clone());
+        }
+    }
+    if start.start_file.is_none() {
+        start.custom = Some(expanded);
+        return Ok(start);
+    }
+    start.minecraft_arguments = rest.cloned().collect();
+    Ok(start)
+}
+
+/// Replaces the variables of a start command that the environment sets, leaving the others.
+fn expand_variables(command: &str, environment: &BTreeMap<String, String>) -> String {
+    let Some(pattern) = COMMAND_VARIABLE.as_ref() else {
+        return command.to_string();
+    };
+    pattern
+        .replace_all(command, |captures: &Captures| {
+            let name = captures.get(1).or(captures.get(2)).map_or("", |name| name.as_str());
+            match environment.get(name) {
+                Some(value) => value.clone(),
+                None => captures[0].to_string(),
+            }
+        })
+        .to_string()
+}
+
+/// The size of a heap flag like `4096M` or `4G`, rounded up to whole GB.
+fn heap_gigabytes(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) = size.split_at(size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len()));
+    let number: u64 = number.parse().ok()?;
+    let megabytes = match unit.to_ascii_lowercase().as_str() {
+        "g" => number.checked_mul(1024)?,
+        "m" => number,
+        "k" => number / 1024,
+        "" => number / (1024 * 1024),
+        _ => return None,
+    };
+    Some(megabytes_to_gigabytes(megabytes))
+}
+
+fn megabytes_to_gigabytes(megabytes: u64) -> u64 {
+    megabytes.div_ceil(1024).max(1)
+}
+
+fn read_json(path: &Path) -> Result<Value, Box<dyn Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    Ok(serde_json::from_str(&content).map_err(|e| format!("{:?} is not valid JSON: {}", path, e))?)
+}
+
+fn array(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// A value as text, numbers included, since the panels store some numbers as strings.
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(flag) => Some(flag.to_string()),
+        _ => None,
+    }
+}
+
+fn read_pterodactyl(
+    files: &Path,
+    server: Option<&Path>,
+    schedules: Option<&Path>,
+) -> Result<PanelSettings, Box<dyn Error>> {
+    let mut settings = PanelSettings {
+        directory: files.to_path_buf(),
+        ..Default::default()
+    };
+    match server {
+        Some(server) => {
+            let document = read_json(server)?;
+            let attributes = &document["attributes"];
+            settings.name = attributes["name"].as_str().map(str::to_string);
+            settings.memory_mb = attributes["limits"]["memory"].as_u64().filter(|memory| *memory > 0);
+            let container = &attributes["container"];
+            settings.start_command = container["startup_command"]
+                .as_str()
+                .or(document["meta"]["startup_command"].as_str())
+                .map(str::to_string);
+            if let Some(environment) = container["environment"].as_object() {
+                for (name, value) in environment {
+                    if let Some(value) = text(value) {
+                        settings.environment.insert(name.clone(), value);
+                    }
+                }
+            }
+            // The startup of the client API lists the variables of the egg instead
+            for variable in array(&document["data"]) {
+                let variable = &variable["attributes"];
+                let name = variable["env_variable"].as_str();
+                if let (Some(name), Some(value)) = (name, text(&variable["server_value"])) {
+                    settings.environment.insert(name.to_string(), value);
+                }
+            }
+        }
+        None => settings.warnings.push(
+            "Without the server from the Pterodactyl API, the start command and variables are not imported".into(),
+        ),
+    }
+
+    let mut expansion = settings.environment.clone();
+    if let Some(memory) = settings.memory_mb {
+        expansion.entry("SERVER_MEMORY".to_string()).or_insert_with(|| memory.to_string());
+        expansion.insert("server.build.memory".to_string(), memory.to_string());
+    }
+    settings.start_command = settings.start_command.map(|command| expand_variables(&command, &expansion));
+    settings.minecraft_version = ["MINECRAFT_VERSION", "MC_VERSION", "VERSION"]
+        .iter()
+        .find_map(|name| settings.environment.get(*name))
+        .filter(|version| version.starts_with(|c: char| c.is_ascii_digit()))
+        .cloned();
+    settings.environment.retain(|name, _| !PTERODACTYL_INTERNAL_VARIABLES.contains(&name.as_str()));
+
+    if let Some(schedules) = schedules {
+        settings.tasks = pterodactyl_tasks(&read_json(schedules)?, &mut settings.warnings);
+        if !settings.tasks.is_empty() {
+            let warning = "Pterodactyl runs schedules in the timezone of the panel, they now run in UTC";
+            settings.warnings.push(warning.to_string());
+        }
+    }
+    Ok(settings)
+}
+
+fn pterodactyl_tasks(document: &Value, warnings: &mut Vec<String>) -> Vec<TaskDefinition> {
+    let mut definitions = Vec::new();
+    for schedule in array(&document["data"]) {
+        let attributes = &schedule["attributes"];
+        let name = attributes["name"].as_str().unwrap_or("Imported schedule");
+        let cron = &attributes["cron"];
+        let field = |key: &str| text(&cron[key]).unwrap_or_else(|| "*".to_string());
+        let expression = [field("minute"), field("hour"), field("day_of_month"), field("month"), field("day_of_week")]
+            .join(" ");
+        let enabled = attributes["is_active"].as_bool().unwrap_or(true);
+
+        let mut tasks: Vec<&Value> =
+            array(&attributes["relationships"]["tasks"]["data"]).iter().map(|task| &task["attributes"]).collect();
+        tasks.sort_by_key(|task| task["sequence_id"].as_u64().unwrap_or_default());
+        for (index, task) in tasks.iter().enumerate() {
+            let payload = task["payload"].as_str().unwrap_or_default().trim();
+            let action = match (task["action"].as_str().unwrap_or_default(), payload) {
+                ("command", command) => TaskAction::Command {
+                    command: command.to_string(),
+                },
+                ("power", "start") => TaskAction::Start,
+                ("power", "stop" | "kill") => TaskAction::Stop,
+                ("power", "restart") => TaskAction::Restart,
+                ("backup", _) => TaskAction::Backup {
+                    options: BackupOptions::default(),
+                },
+                (action, payload) => {
+                    warnings.push(format!("The {} {:?} task of the schedule {:?} has no match", action, payload, name));
+                    continue;
+                }
+            };
+            if task["time_offset"].as_u64().unwrap_or_default() > 0 {
+                warnings.push(format!(
+                    "Task {} of the schedule {:?} had a delay, it now runs at the time of the schedule",
+                    index + 1,
+                    name
+                ));
+            }
+            definitions.push(TaskDefinition {
+                name: if tasks.len() > 1 { format!("{} ({})", name, index + 1) } else { name.to_string() },
+                trigger: TaskTrigger::Cron {
+                    expression: expression.clone(),
+                },
+                action,
+                enabled,
+                missed_runs: MissedRunPolicy::default(),
+            });
+        }
+    }
+    definitions
+}
+
+/// Reads an AMP instance from the `.kvp` files it keeps its settings in. The game files are in
+/// the `Minecraft` directory of the instance.
+fn read_amp(instance: &Path) -> Result<PanelSettings, Box<dyn Error>> {
+    let mut values = Vec::new();
+    for entry in fs::read_dir(instance)?.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("kvp")) {
+            let content = fs::read_to_string(&path)?;
+            values.extend(content.lines().filter_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                Some((key.trim().to_lowercase(), value.trim().to_string()))
+            }));
+        }
+    }
+    if values.is_empty() {
+        return Err(format!("{:?} is not an AMP instance, it has no .kvp files", instance).into());
+    }
+    // The keys are namespaced by the module, like `Java.MaxHeapSizeMB`, so they are matched by their end
+    let value = |name: &str| -> Option<String> {
+        let name = name.to_lowercase();
+        values
+            .iter()
+            .find(|(key, value)| key.ends_with(&name) && !value.is_empty())
+            .map(|(_, value)| value.clone())
+    };
+
+    let directory = ["Minecraft", "minecraft"]
+        .iter()
+        .map(|name| instance.join(name))
+        .find(|directory| directory.is_dir())
+        .unwrap_or_else(|| instance.to_path_buf());
+    Ok(PanelSettings {
+        name: instance.file_name().map(|name| name.to_string_lossy().to_string()),
+        directory,
+        memory_mb: value("MaxHeapSizeMB").and_then(|memory| memory.parse().ok()),
+        java_runtime: value("JavaVersion").or_else(|| value("JavaPath")).map(PathBuf::from),
+        java_arguments: value("CustomJVMArgs").or_else(|| value("JavaArgs")),
+        minecraft_version: value("SpecificVersion").filter(|version| version.starts_with(|c: char| c.is_ascii_digit())),
+        auto_start: value("AutoStart").is_some_and(|auto_start| auto_start.eq_ignore_ascii_case("true")),
+        warnings: vec!["AMP keeps its schedules in its own database, they are not imported".to_string()],
+        ..Default::default()
+    })
+}
+
+/// Reads an MCSManager instance. The daemon keeps the configuration in `data/InstanceConfig`, the
+/// files in `data/InstanceData` and the schedules in `data/TaskConfig`, named after the instance.
+fn read_mcsmanager(config: &Path, files: Option<&Path>) -> Result<PanelSettings, Box<dyn Error>> {
+    let document = read_json(config)?;
+    let uuid = config.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let data = config.parent().and_then(Path::parent).unwrap_or(Path::new("."));
+    let daemon = data.parent().unwrap_or(Path::new("."));
+    let directory = match files {
+        Some(files) => files.to_path_buf(),
+        None => match document["cwd"].as_str().map(str::trim).filter(|cwd| !cwd.is_empty()) {
+            // Relative directories are resolved from the directory of the daemon, like it does
+            Some(cwd) => daemon.join(cwd),
+            None => data.join("InstanceData").join(&uuid),
+        },
+    };
+
+    let mut settings = PanelSettings {
+        name: document["nickname"].as_str().map(str::to_string),
+        directory,
+        start_command: document["startCommand"].as_str().map(str::to_string).filter(|command| !command.is_empty()),
+        auto_start: document["eventTask"]["autoStart"].as_bool().unwrap_or(false),
+        restart_on_crash: document["eventTask"]["autoRestart"].as_bool().unwrap_or(false),
+        ..Default::default()
+    };
+    if document["type"].as_str().is_some_and(|kind| kind.contains("bedrock")) {
+        settings.warnings.push("The instance is a Bedrock server, which the manager cannot start".into());
+    }
+    for variable in array(&document["docker"]["env"]) {
+        if let Some((name, value)) = variable.as_str().and_then(|variable| variable.split_once('=')) {
+            settings.environment.insert(name.trim().to_string(), value.to_string());
+        }
+    }
+
+    let tasks = data.join("TaskConfig");
+    let mut task_files: Vec<PathBuf> = fs::read_dir(tasks.join(&uuid))
+        .into_iter()
+        .chain(fs::read_dir(&tasks))
+        .flat_map(|entries| entries.filter_map(Result::ok).map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    task_files.sort();
+    for file in task_files {
+        // The directory holds the tasks of every instance, a broken one of another does not matter
+        let task = match read_json(&file) {
+            Ok(task) => task,
+            Err(e) => {
+                warn!("Skipping the MCSManager task file {:?}: {}", file, e);
+                continue;
+            }
+        };
+        let belongs = task["instanceUuid"].as_str() == Some(uuid.as_str())
+            || file.parent().and_then(Path::file_name).is_some_and(|parent| parent.to_string_lossy() == uuid);
+        if belongs {
+            if let Some(definition) = mcsmanager_task(&task, &mut settings.warnings) {
+                settings.tasks.push(definition);
+            }
+        }
+    }
+    Ok(settings)
+}
+
+fn mcsmanager_task(task: &Value, warnings: &mut Vec<String>) -> Option<TaskDefinition> {
+    let name = task["name"].as_str().unwrap_or("Imported task").to_string();
+    let time = text(&task["time"]).unwrap_or_default();
+    let trigger = match task["type"].as_u64() {
+        // An interval in seconds
+        Some(1) => {
+            let seconds: u64 = time.trim().parse().ok()?;
+            let minutes = (seconds / 60).max(1);
+            if seconds % 60 != 0 {
+                warnings.push(format!("The task {:?} ran every {}s, it now runs every {}min", name, seconds, minutes));
+            }
+            TaskTrigger::Interval { minutes }
+        }
+        // A cron expression with seconds in front
+        Some(2) => {
+            let fields: Vec<&str> = time.split_whitespace().collect();
+            let fields = if fields.len() == 6 { &fields[1..] } else { &fields[..] };
+            TaskTrigger::Cron {
+                expression: fields.join(" "),
+            }
+        }
+        _ => {
+            warnings.push(format!("The task {:?} ran once at a set time, it is not imported", name));
+            return None;
+        }
+    };
+    let payload = task["payload"].as_str().unwrap_or_default();
+    let action = match task["action"].as_str().unwrap_or_default() {
+        "command" => TaskAction::Command {
+            command: payload.to_string(),
+        },
+        "start" => TaskAction::Start,
+        "stop" | "kill" => TaskAction::Stop,
+        "restart" => TaskAction::Restart,
+        action => {
+            warnings.push(format!("The {:?} action of the task {:?} has no match", action, name));
+            return None;
+        }
+    };
+    if task["count"].as_i64().is_some_and(|count| count > 0) {
+        warnings.push(format!("The task {:?} was limited to a number of runs, it now runs until removed", name));
+    }
+    Some(TaskDefinition {
+        name,
+        trigger,
+        action,
+        enabled: true,
+        missed_runs: MissedRunPolicy::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(command: &str) -> Result<StartCommand, Box<dyn Error>> {
+        let environment = BTreeMap::from([("SERVER_JARFILE".to_string(), "server.jar".to_string())]);
+        parse_start_command(command, &environment)
+    }
+
+    #[test]
+    fn parses_a_pterodactyl_start_command() -> Result<(), Box<dyn Error>> {
+        let start = parse("java -Xms128M -XX:MaxRAMPercentage=95.0 -Dterminal.jline=false -jar {{SERVER_JARFILE}}")?;
+        assert_eq!(start.start_file, Some(PathBuf::from("server.jar")));
+        assert_eq!(start.min_ram, Some(1));
+        assert_eq!(start.java_arguments, vec!["-XX:MaxRAMPercentage=95.0", "-Dterminal.jline=false"]);
+        assert!(start.minecraft_arguments.is_empty());
+        assert!(start.java.is_none() && start.custom.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_game_arguments_and_java_path() -> Result<(), Box<dyn Error>> {
+        let start = parse("/usr/lib/jvm/java-21/bin/java -Xmx4G -jar paper.jar --nogui")?;
+        assert_eq!(start.java, Some(PathBuf::from("/usr/lib/jvm/java-21/bin/java")));
+        assert_eq!(start.max_ram, Some(4));
+        assert_eq!(start.start_file, Some(PathBuf::from("paper.jar")));
+        assert_eq!(start.minecraft_arguments, vec!["--nogui"]);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_a_forge_argument_file() -> Result<(), Box<dyn Error>> {
+        let start = parse(r#"java @user_jvm_args.txt @libraries/net/minecraftforge/forge/unix_args.txt nogui "$@""#)?;
+        assert_eq!(start.start_file, Some(PathBuf::from("libraries/net/minecraftforge/forge/unix_args.txt")));
+        assert_eq!(start.java_arguments, vec!["@user_jvm_args.txt"]);
+        assert_eq!(start.minecraft_arguments, vec!["nogui"]);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_other_commands_whole() -> Result<(), Box<dyn Error>> {
+        assert_eq!(parse("bash start.sh {{SERVER_JARFILE}}")?.custom.as_deref(), Some("bash start.sh server.jar"));
+        assert_eq!(parse("java -version")?.custom.as_deref(), Some("java -version"));
+        assert!(parse("").is_err());
+        assert!(parse("java \"-jar").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn expands_only_known_variables() {
+        let environment = BTreeMap::from([
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2".to_string()),
+            ("C".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(expand_variables("${A} {{B}} {{env.C}} ${D}", &environment), "1 2 3 ${D}");
+    }
+
+    #[test]
+    fn rounds_heap_sizes_up_to_gigabytes() {
+        assert_eq!(heap_gigabytes("4G"), Some(4));
+        assert_eq!(heap_gigabytes("4096M"), Some(4));
+        assert_eq!(heap_gigabytes("1536m"), Some(2));
+        assert_eq!(heap_gigabytes("512k"), Some(1));
+        assert_eq!(heap_gigabytes("2T"), None);
+        assert_eq!(heap_gigabytes("18446744073709551615G"), None);
+    }
+}
@@ -0,0 +1,467 @@
+use crate::config::{get_config, set_config, ConfigScope};
+use crate::text_file::write_file_atomically;
+use crate::users::require_admin;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt, NewAccount, NewOrder,
+    OrderStatus,
+};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The panel setting holding the certificate and ACME settings.
+const CONFIG_KEY: &str = "tls";
+
+/// Where the certificates issued through ACME and the ACME account are kept.
+pub const CERTIFICATE_DIRECTORY: &str = "certificates";
+
+/// The path the panel's plain HTTP listener serves the HTTP-01 challenges under, followed by the
+/// token, see [`acme_challenge_response`].
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Let's Encrypt certificates last 90 days, they are renewed when a third of that is left.
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+/// How often the certificate is checked for renewal, and user supplied files for changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait after a failed issuance, so a broken setup does not run into the rate limits.
+const RETRY_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+static RENEWAL: Once = Once::new();
+
+lazy_static! {
+    static ref RESOLVER: Arc<CertificateResolver> = Arc::new(CertificateResolver::default());
+    /// The key authorizations of the pending HTTP-01 challenges, by token.
+    static ref CHALLENGES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// How the panel gets its certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum TlsSettings {
+    /// Plain HTTP, for panels behind a reverse proxy or only reachable locally.
+    #[default]
+    Disabled,
+    /// A certificate and key in PEM files, such as from an existing certbot setup. The files are
+    /// read again when they change.
+    Manual { certificate_path: PathBuf, key_path: PathBuf },
+    /// A certificate issued and renewed automatically through ACME with HTTP-01 challenges. The
+    /// domains have to point at this machine, with port 80 reaching the panel.
+    Acme {
+        domains: Vec<String>,
+        /// Where the certificate authority sends expiry warnings.
+        email: String,
+        /// Uses the Let's Encrypt staging environment, which has far higher rate limits, to test the setup.
+        #[serde(default)]
+        staging: bool,
+    },
+}
+
+/// The certificate the panel serves, as shown in the settings.
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateStatus {
+    pub loaded: bool,
+    pub domains: Vec<String>,
+    /// When the certificate was issued through ACME, in seconds since the Unix epoch.
+    pub issued_at: Option<u64>,
+    /// When it will be renewed, for certificates issued through ACME.
+    pub renews_at: Option<u64>,
+    /// Why the last issuance or loading failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// What is stored next to a certificate issued through ACME.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IssuedCertificate {
+    domains: Vec<String>,
+    staging: bool,
+    issued_at: u64,
+}
+
+/// Hands the current certificate to every new TLS connection, so a renewed certificate is used
+/// without restarting the panel.
+#[derive(Debug, Default)]
+pub struct CertificateResolver {
+    certificate: RwLock<Option<Arc<CertifiedKey>>>,
+    /// The modification times of the files the certificate was loaded from.
+    loaded_from: Mutex<Option<(SystemTime, SystemTime)>>,
+    last_error: Mutex<Option<String>>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+}
+
+impl CertificateResolver {
+    fn current(&self) -> Option<Arc<CertifiedKey>> {
+        self.certificate.read().ok()?.clone()
+    }
+
+    fn set(&self, certificate: Option<Arc<CertifiedKey>>) {
+        if let Ok(mut current) = self.certificate.write() {
+            *current = certificate;
+        }
+    }
+
+    fn set_error(&self, message: Option<String>) {
+        if let Ok(mut last_error) = self.last_error.lock() {
+            *last_error = message;
+        }
+    }
+}
+
+/// Returns how the panel gets its certificate, [`TlsSettings::Disabled`] until it is set up.
+pub fn get_tls_settings() -> Result<TlsSettings, Box<dyn Error>> {
+    Ok(get_config(ConfigScope::Panel, CONFIG_KEY)?.unwrap_or_default())
+}
+
+/// Changes how the panel gets its certificate and loads it. A certificate from ACME is issued in
+/// the background by [`start_certificate_renewal`]; the panel keeps serving the previous one until then.
+///
+/// The listener only switches between HTTP and HTTPS when the panel starts.
+///
+/// # Errors
+/// Returns an error if the acting user is not an administrator, user supplied files cannot be
+/// loaded, or the ACME settings are incomplete.
+pub fn set_tls_settings(settings: &TlsSettings) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    match settings {
+        TlsSettings::Disabled => {}
+        TlsSettings::Manual {
+            certificate_path,
+            key_path,
+        } => {
+            load_certified_key(certificate_path, key_path)?;
+        }
+        TlsSettings::Acme { domains, email, .. } => {
+            if domains.is_empty() {
+                return Err("A certificate needs at least one domain".into());
+            }
+            if let Some(domain) = domains.iter().find(|domain| !is_valid_domain(domain)) {
+                return Err(format!("{:?} is not a domain a certificate can be issued for", domain).into());
+            }
+            if !email.contains('@') {
+                return Err("The certificate authority needs an email address".into());
+            }
+        }
+    }
+
+    set_config(ConfigScope::Panel, CONFIG_KEY, settings)?;
+    info!("Changed the TLS settings of the panel to {:?}", settings);
+
+    if let Err(e) = refresh_certificate(settings, false) {
+        warn!("Failed to load the certificate of the panel: {}", e);
+    }
+    Ok(())
+}
+
+/// Builds the TLS configuration for the panel's HTTPS listener, loading the current certificate.
+///
+/// # Returns
+/// `None` if TLS is disabled and the panel serves plain HTTP.
+///
+/// # Errors
+/// Returns an error if the settings cannot be read or the configuration cannot be built.
+pub fn tls_server_config() -> Result<Option<Arc<ServerConfig>>, Box<dyn Error>> {
+    let settings = get_tls_settings()?;
+    if settings == TlsSettings::Disabled {
+        return Ok(None);
+    }
+    if let Err(e) = refresh_certificate(&settings, false) {
+        warn!("Failed to load the certificate of the panel: {}", e);
+    }
+    let mut config = ServerConfig::builder().with_no_client_auth().with_cert_resolver(RESOLVER.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(config)))
+}
+
+/// The response to an HTTP-01 challenge the panel's plain HTTP listener received at
+/// [`ACME_CHALLENGE_PATH`] followed by `token`, `None` for tokens that are not pending.
+pub fn acme_challenge_response(token: &str) -> Option<String> {
+    CHALLENGES.lock().ok()?.get(token).cloned()
+}
+
+/// The certificate the panel serves.
+pub fn get_certificate_status() -> Result<CertificateStatus, Box<dyn Error>> {
+    let loaded = RESOLVER.current().is_some();
+    let last_error = RESOLVER.last_error.lock().ok().and_then(|error| error.clone());
+    let (domains, issued_at) = match (get_tls_settings()?, read_issued()) {
+        (TlsSettings::Acme { .. }, Some(issued)) => (issued.domains, Some(issued.issued_at)),
+        (TlsSettings::Acme { domains, .. }, None) => (domains, None),
+        _ => (Vec::new(), None),
+    };
+    Ok(CertificateStatus {
+        loaded,
+        domains,
+        issued_at,
+        renews_at: issued_at.map(|issued_at| issued_at + RENEW_AFTER.as_secs()),
+        last_error,
+    })
+}
+
+/// Starts keeping the certificate of the panel current. Calling it again has no effect.
+///
+/// Certificates from ACME are issued when there is none for the configured domains, and renewed
+/// 30 days before they expire. User supplied files are loaded again when they change.
+pub fn start_certificate_renewal() {
+    RENEWAL.call_once(|| {
+        thread::spawn(|| loop {
+            let delay = match get_tls_settings().and_then(|settings| refresh_certificate(&settings, true)) {
+                Ok(()) => {
+                    RESOLVER.set_error(None);
+                    CHECK_INTERVAL
+                }
+                Err(e) => {
+                    error!("Failed to renew the certificate of the panel: {}", e);
+                    RESOLVER.set_error(Some(e.to_string()));
+                    RETRY_INTERVAL
+                }
+            };
+            thread::sleep(delay);
+        });
+        info!("Started renewing the certificate of the panel");
+    });
+}
+
+/// Loads the certificate for the settings into the resolver, issuing one through ACME first if
+/// `issue` is set and there is none or it is due for renewal.
+fn refresh_certificate(settings: &TlsSettings, issue: bool) -> Result<(), Box<dyn Error>> {
+    match settings {
+        TlsSettings::Disabled => RESOLVER.set(None),
+        TlsSettings::Manual {
+            certificate_path,
+            key_path,
+        } => {
+            let modified = (fs::metadata(certificate_path)?.modified()?, fs::metadata(key_path)?.modified()?);
+            let unchanged = RESOLVER.loaded_from.lock().is_ok_and(|loaded| *loaded == Some(modified));
+            if !unchanged {
+                RESOLVER.set(Some(Arc::new(load_certified_key(certificate_path, key_path)?)));
+                if let Ok(mut loaded) = RESOLVER.loaded_from.lock() {
+                    *loaded = Some(modified);
+                }
+                info!("Loaded the certificate of the panel from {:?}", certificate_path);
+            }
+        }
+        TlsSettings::Acme {
+            domains,
+            email,
+            staging,
+        } => {
+            let directory = Path::new(CERTIFICATE_DIRECTORY);
+            let (certificate_path, key_path) = (directory.join("panel.crt"), directory.join("panel.key"));
+            let current = read_issued().filter(|issued| issued.domains == *domains && issued.staging == *staging);
+            let fresh = current
+                .as_ref()
+                .is_some_and(|issued| now().saturating_sub(issued.issued_at) < RENEW_AFTER.as_secs());
+            if issue && !fresh {
+                issue_certificate(domains, email, *staging)?;
+                RESOLVER.set(Some(Arc::new(load_certified_key(&certificate_path, &key_path)?)));
+            } else if current.is_some() && RESOLVER.current().is_none() {
+                RESOLVER.set(Some(Arc::new(load_certified_key(&certificate_path, &key_path)?)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Orders a certificate for the domains, answering the HTTP-01 challenges through the panel's
+/// plain HTTP listener, and stores it with its key in [`CERTIFICATE_DIRECTORY`].
+fn issue_certificate(domains: &[String], email: &str, staging: bool) -> Result<(), Box<dyn Error>> {
+    info!("Requesting a certificate for {:?}", domains);
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let result = runtime.block_on(order_certificate(domains, email, staging));
+
+    // Whatever happened, the challenges are no longer answered
+    if let Ok(mut challenges) = CHALLENGES.lock() {
+        challenges.clear();
+    }
+    let (certificate_chain, private_key) = result?;
+
+    let directory = Path::new(CERTIFICATE_DIRECTORY);
+    fs::create_dir_all(directory)?;
+    write_file_atomically(&directory.join("panel.key"), private_key.as_bytes())?;
+    restrict_to_owner(&directory.join("panel.key"));
+    write_file_atomically(&directory.join("panel.crt"), certificate_chain.as_bytes())?;
+    let issued = IssuedCertificate {
+        domains: domains.to_vec(),
+        staging,
+        issued_at: now(),
+    };
+    write_file_atomically(&directory.join("panel.json"), serde_json::to_string_pretty(&issued)?.as_bytes())?;
+    info!("Issued a certificate for {:?}", domains);
+    Ok(())
+}
+
+async fn order_certificate(domains: &[String], email: &str, staging: bool) -> Result<(String, String), Box<dyn Error>> {
+    let account = load_account(email, staging).await?;
+    let identifiers: Vec<Identifier> = domains.iter().map(|domain| Identifier::Dns(domain.clone())).collect();
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &identifiers,
+        })
+        .await?;
+
+    let authorizations = order.authorizations().await?;
+    let mut challenge_urls = Vec::new();
+    for authorization in &authorizations {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => return Err(format!("The authorization of {:?} is {:?}", authorization.identifier, status).into()),
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == ChallengeType::Http01)
+            .ok_or("The certificate authority did not offer an HTTP-01 challenge")?;
+        let key_authorization = order.key_authorization(challenge);
+        if let Ok(mut challenges) = CHALLENGES.lock() {
+            challenges.insert(challenge.token.clone(), key_authorization.as_str().to_string());
+        }
+        challenge_urls.push(challenge.url.clone());
+    }
+    for url in &challenge_urls {
+        order.set_challenge_ready(url).await?;
+    }
+
+    // The certificate authority fetches the challenges from every domain, which takes a few seconds
+    let mut delay = Duration::from_secs(2);
+    let mut ready = false;
+    for _ in 0..10 {
+        tokio::time::sleep(delay).await;
+        let state = order.refresh().await?;
+        match state.status {
+            OrderStatus::Ready => {
+                ready = true;
+                break;
+            }
+            OrderStatus::Invalid => {
+                return Err("The certificate authority could not verify the domains, check that port 80 \
+                    of each of them reaches the panel"
+                    .into())
+            }
+            _ => delay = (delay * 2).min(Duration::from_secs(30)),
+        }
+    }
+    if !ready {
+        return Err("The certificate authority took too long to verify the domains".into());
+    }
+
+    let private_key = rcgen::KeyPair::generate()?;
+    let mut params = rcgen::CertificateParams::new(domains.to_vec())?;
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    let request = params.serialize_request(&private_key)?;
+    order.finalize(request.der()).await?;
+
+    for _ in 0..30 {
+        if let Some(certificate_chain) = order.certificate().await? {
+            return Ok((certificate_chain, private_key.serialize_pem()));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Err("The certificate authority did not hand out the certificate in time".into())
+}
+
+/// Loads the ACME account, registering one the first time or when the email changed.
+async fn load_account(email: &str, staging: bool) -> Result<Account, Box<dyn Error>> {
+    let name = if staging { "acme_account_staging.json" } else { "acme_account.json" };
+    let path = Path::new(CERTIFICATE_DIRECTORY).join(name);
+
+    #[derive(Serialize, Deserialize)]
+    struct StoredAccount {
+        email: String,
+        credentials: AccountCredentials,
+    }
+    if let Ok(stored) = fs::read_to_string(&path) {
+        if let Ok(stored) = serde_json::from_str::<StoredAccount>(&stored) {
+            if stored.email == email {
+                return Ok(Account::from_credentials(stored.credentials).await?);
+            }
+        }
+    }
+
+    let directory = if staging { LetsEncrypt::Staging.url() } else { LetsEncrypt::Production.url() };
+    let contact = format!("mailto:{}", email);
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &[&contact],
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        directory,
+        None,
+    )
+    .await?;
+    fs::create_dir_all(CERTIFICATE_DIRECTORY)?;
+    let stored = StoredAccount {
+        email: email.to_string(),
+        credentials,
+    };
+    write_file_atomically(&path, serde_json::to_string(&stored)?.as_bytes())?;
+    restrict_to_owner(&path);
+    info!("Registered an ACME account for {}", email);
+    Ok(account)
+}
+
+/// Reads a certificate chain and its private key from PEM files.
+fn load_certified_key(certificate_path: &Path, key_path: &Path) -> Result<CertifiedKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(fs::File::open(certificate_path)?);
+    let certificates: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut reader).collect::<Result<_, _>>()?;
+    if certificates.is_empty() {
+        return Err(format!("{:?} contains no certificate", certificate_path).into());
+    }
+    let mut reader = BufReader::new(fs::File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| format!("{:?} contains no private key", key_path))?;
+    let key = any_supported_type(&key).map_err(|e| format!("The key in {:?} cannot be used: {}", key_path, e))?;
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+fn read_issued() -> Option<IssuedCertificate> {
+    let metadata = fs::read_to_string(Path::new(CERTIFICATE_DIRECTORY).join("panel.json")).ok()?;
+    serde_json::from_str(&metadata).ok()
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    domain.len() <= 253
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Keeps private keys readable by the panel only.
+#[cfg(unix)]
+fn restrict_to_owner(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Err(err) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+        warn!("Failed to restrict the permissions of {:?}: {}", path, err);
+    }
+}
+
+#[cfg(not(unix))]
+fn restrict_to_owner(_path: &Path) {}
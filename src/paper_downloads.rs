@@ -0,0 +1,342 @@
+use crate::http_client::{download_file, get_json};
+use crate::permissions::{authorize, Capability};
+use crate::provisioning::JarDownload;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use log::info;
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlite::State;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const PAPER_API: &str = "https://api.papermc.io/v2/projects";
+const PURPUR_API: &str = "https://api.purpurmc.org/v2/purpur";
+
+/// The servers of PaperMC and Purpur whose builds can be installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaperProject {
+    Paper,
+    /// Paper with regionized multithreading, which most plugins do not support yet.
+    Folia,
+    /// A fork of Paper, hosted on its own downloads API.
+    Purpur,
+}
+
+impl PaperProject {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaperProject::Paper => "paper",
+            PaperProject::Folia => "folia",
+            PaperProject::Purpur => "purpur",
+        }
+    }
+}
+
+impl fmt::Display for PaperProject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for PaperProject {
+    type Err = Box<dyn Error>;
+
+    fn from_str(project: &str) -> Result<Self, Self::Err> {
+        match project.trim().to_ascii_lowercase().as_str() {
+            "paper" => Ok(PaperProject::Paper),
+            "folia" => Ok(PaperProject::Folia),
+            "purpur" => Ok(PaperProject::Purpur),
+            other => Err(format!("Unknown server project {:?}", other).into()),
+        }
+    }
+}
+
+/// A build of a project for a version of the game.
+#[derive(Debug, Clone, Serialize)]
+pub struct PaperBuild {
+    pub project: PaperProject,
+    pub version: String,
+    pub build: u32,
+    /// Whether the build is marked as stable. Experimental builds are only used when asked for.
+    pub stable: bool,
+    pub url: String,
+    /// Published by PaperMC.
+    pub sha256: Option<String>,
+    /// Published by Purpur.
+    pub md5: Option<String>,
+}
+
+impl From<PaperBuild> for JarDownload {
+    fn from(build: PaperBuild) -> Self {
+        JarDownload {
+            url: build.url,
+            minecraft_version: Some(build.version),
+            build: Some(build.build.to_string()),
+            sha1: None,
+            sha256: build.sha256,
+            md5: build.md5,
+        }
+    }
+}
+
+/// The build installed on a server, recorded to check for newer builds.
+#[derive(Debug, Clone, Serialize)]
+pub struct InstalledBuild {
+    pub project: PaperProject,
+    pub version: String,
+    pub build: u32,
+    /// The checksum of the installed jar, as published by the project.
+    pub hash: Option<String>,
+    /// When the build was installed, as stored by the database.
+    pub installed_at: String,
+}
+
+/// Lists the versions of the game a project has builds for, newest first.
+pub fn list_project_versions(project: PaperProject) -> Result<Vec<String>, Box<dyn Error>> {
+    let url = match project {
+        PaperProject::Purpur => PURPUR_API.to_string(),
+        project => format!("{}/{}", PAPER_API, project),
+    };
+    let mut versions = string_array(&get_json(&url)?["versions"]);
+    // Both APIs list the oldest version first
+    versions.reverse();
+    Ok(versions)
+}
+
+/// Lists the builds of a project for a version of the game, newest first.
+///
+/// Purpur builds are listed without their checksum, [`resolve_build`] looks it up.
+pub fn list_builds(project: PaperProject, version: &str) -> Result<Vec<PaperBuild>, Box<dyn Error>> {
+    let mut builds: Vec<PaperBuild> = match project {
+        PaperProject::Purpur => {
+            let response = get_json(&format!("{}/{}", PURPUR_API, version))?;
+            string_array(&response["builds"]["all"])
+                .iter()
+                .filter_map(|build| build.parse::<u32>().ok())
+                .map(|build| PaperBuild {
+                    project,
+                    version: version.to_string(),
+                    build,
+                    stable: true,
+                    url: format!("{}/{}/{}/download", PURPUR_API, version, build),
+                    sha256: None,
+                    md5: None,
+                })
+                .collect()
+        }
+        project => {
+            let url = format!("{}/{}/versions/{}/builds", PAPER_API, project, version);
+            let response = get_json(&url)?;
+            response["builds"]
+                .as_array()
+                .map(|builds| builds.iter().filter_map(|build| parse_paper_build(project, version, build)).collect())
+                .unwrap_or_default()
+        }
+    };
+    builds.sort_by(|a, b| b.build.cmp(&a.build));
+    Ok(builds)
+}
+
+fn parse_paper_build(project: PaperProject, version: &str, build: &Value) -> Option<PaperBuild> {
+    let number = build["build"].as_u64()? as u32;
+    let application = &build["downloads"]["application"];
+    let name = application["name"].as_str()?;
+    Some(PaperBuild {
+        project,
+        version: version.to_string(),
+        build: number,
+        stable: build["channel"].as_str() == Some("default"),
+        url: format!(
+            "{}/{}/versions/{}/builds/{}/downloads/{}",
+            PAPER_API, project, version, number, name
+        ),
+        sha256: application["sha256"].as_str().map(str::to_string),
+        md5: None,
+    })
+}
+
+/// Looks up a build of a project, the newest stable one if `build` is `None`.
+///
+/// # Errors
+/// Returns an error if the version or build does not exist, or the version has no stable build.
+pub fn resolve_build(project: PaperProject, version: &str, build: Option<u32>) -> Result<PaperBuild, Box<dyn Error>> {
+    let builds = list_builds(project, version)?;
+    let mut resolved = match build {
+        Some(number) => builds.into_iter().find(|build| build.build == number),
+        None => builds.into_iter().find(|build| build.stable),
+    }
+    .ok_or_else(|| match build {
+        Some(number) => format!("{} {} has no build {}", project, version, number),
+        None => format!("{} {} has no stable build", project, version),
+    })?;
+
+    if project == PaperProject::Purpur {
+        let details = get_json(&format!("{}/{}/{}", PURPUR_API, version, resolved.build))?;
+        if details["result"].as_str().is_some_and(|result| result != "SUCCESS") {
+            return Err(format!("Purpur {} build {} failed to build", version, resolved.build).into());
+        }
+        resolved.md5 = details["md5"].as_str().map(str::to_string);
+    }
+    Ok(resolved)
+}
+
+/// Creates the table recording the builds installed on the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_installed_build_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_installed_build` (
+            server_id INTEGER PRIMARY KEY,                              -- The server the build is installed on
+            project TEXT NOT NULL,                                      -- paper, folia or purpur
+            version TEXT NOT NULL,                                      -- The version of the game
+            build INTEGER NOT NULL,                                     -- The build number
+            hash TEXT,                                                  -- The published checksum of the jar
+            installed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP   -- When the build was installed
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Records the build of a project installed on a server.
+pub(crate) fn record_installed_build(
+    server_id: u64,
+    project: PaperProject,
+    jar: &JarDownload,
+) -> Result<(), Box<dyn Error>> {
+    let query = r#"
+INSERT INTO server_installed_build (server_id, project, version, build, hash, installed_at)
+VALUES (?, ?, ?, ?, ?, CURRENT_TIMESTAMP)
+ON CONFLICT(server_id) DO UPDATE SET
+    project = excluded.project,
+    version = excluded.version,
+    build = excluded.build,
+    hash = excluded.hash,
+    installed_at = excluded.installed_at
+"#;
+    let version = jar.minecraft_version.as_deref().ok_or("The installed jar has no game version")?;
+    let build: u32 = jar.build.as_deref().and_then(|build| build.parse().ok()).ok_or("The installed jar has no build")?;
+    let hash = jar.sha256.as_ref().or(jar.md5.as_ref());
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, project.as_str()))?;
+    statement.bind((3, version))?;
+    statement.bind((4, build as i64))?;
+    statement.bind((5, hash.map(String::as_str)))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Returns the build installed on a server, `None` if it was not installed from a downloads API.
+pub fn get_installed_build(server_id: u64) -> Result<Option<InstalledBuild>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_installed_build WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(Some(InstalledBuild {
+            project: statement.read::<String, _>("project")?.parse()?,
+            version: statement.read::<String, _>("version")?,
+            build: statement.read::<i64, _>("build")? as u32,
+            hash: statement.read::<Option<String>, _>("hash")?,
+            installed_at: statement.read::<String, _>("installed_at")?,
+        }));
+    }
+    Ok(None)
+}
+
+fn string_array(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| match value {
+                    Value::String(value) => Some(value.clone()),
+                    Value::Number(value) => Some(value.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub trait ServerPaperBuild {
+    /// Downloads a build of a project into the server's directory, replacing its jar, and records it.
+    ///
+    /// The jar only replaces the current one once its checksum matches the published one. Takes
+    /// effect on the next start of the server.
+    ///
+    /// # Arguments
+    /// * `project` - The server software to install.
+    /// * `version` - The version of the game.
+    /// * `build` - The build to install, the newest stable one if `None`.
+    fn install_paper_build(
+        &mut self,
+        project: PaperProject,
+        version: &str,
+        build: Option<u32>,
+    ) -> Result<PaperBuild, Box<dyn Error>>;
+
+    /// Returns the build installed on the server.
+    fn get_installed_build(&self) -> Result<Option<InstalledBuild>, Box<dyn Error>>;
+
+    /// Checks for a newer stable build of the installed project and version.
+    ///
+    /// # Returns
+    /// The newer build, `None` if the installed one is the newest or the server was not installed
+    /// from a downloads API.
+    fn check_for_build_update(&self) -> Result<Option<PaperBuild>, Box<dyn Error>>;
+}
+
+impl ServerPaperBuild for Server<u64> {
+    fn install_paper_build(
+        &mut self,
+        project: PaperProject,
+        version: &str,
+        build: Option<u32>,
+    ) -> Result<PaperBuild, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let resolved = resolve_build(project, version, build)?;
+        let download = JarDownload::from(resolved.clone());
+        let expected = download
+            .expected_hash()
+            .ok_or_else(|| format!("{} build {} has no published checksum", project, resolved.build))?;
+
+        let jar = self
+            .start_script
+            .clone()
+            .filter(|script| script.extension().is_some_and(|extension| extension == "jar"))
+            .unwrap_or_else(|| PathBuf::from("server.jar"));
+        download_file(&download.url, &self.directory.join(&jar), Some(&expected), &mut |_, _| {})?;
+        record_installed_build(self.id, project, &download)?;
+
+        self.start_script = Some(jar);
+        self.minecraft_version = resolved.version.clone();
+        self.loader_version = Some(resolved.build.to_string());
+        self.update()?;
+        info!("Installed {} {} build {} on server {:?}", project, version, resolved.build, self.name);
+        Ok(resolved)
+    }
+
+    fn get_installed_build(&self) -> Result<Option<InstalledBuild>, Box<dyn Error>> {
+        get_installed_build(self.id)
+    }
+
+    fn check_for_build_update(&self) -> Result<Option<PaperBuild>, Box<dyn Error>> {
+        let Some(installed) = get_installed_build(self.id)? else {
+            return Ok(None);
+        };
+        let newest = list_builds(installed.project, &installed.version)?
+            .into_iter()
+            .find(|build| build.stable);
+        Ok(newest.filter(|newest| newest.build > installed.build))
+    }
+}
@@ -0,0 +1,290 @@
+use crate::api_tokens::check_token_scope;
+use crate::audit_log::acting_user;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::users::get_user;
+use log::info;
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::error::Error;
+
+/// Something a user can be allowed to do on a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Reading the console output and the logs.
+    ViewConsole,
+    /// Typing commands into the console. The features that send commands of their own, like the
+    /// ban list or backups, are not limited by it.
+    SendCommands,
+    /// Starting, stopping, restarting and killing the server.
+    ControlServer,
+    /// Browsing, editing, uploading and deleting the files of the server, including its configs.
+    EditFiles,
+    /// Creating, restoring and deleting backups.
+    ManageBackups,
+    /// Deciding what the other users may do on the server.
+    ManageUsers,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::ViewConsole,
+        Capability::SendCommands,
+        Capability::ControlServer,
+        Capability::EditFiles,
+        Capability::ManageBackups,
+        Capability::ManageUsers,
+    ];
+}
+
+/// A named set of capabilities, to grant the usual combinations at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Watches the console.
+    Viewer,
+    /// Watches the console and runs commands, without touching files.
+    Moderator,
+    /// Runs the server, everything except managing the other users.
+    Operator,
+    /// Everything, like the owner of the server.
+    Administrator,
+}
+
+impl Role {
+    pub fn capabilities(&self) -> Vec<Capability> {
+        match self {
+            Role::Viewer => vec![Capability::ViewConsole],
+            Role::Moderator => vec![Capability::ViewConsole, Capability::SendCommands],
+            Role::Operator => Capability::ALL.into_iter().filter(|c| *c != Capability::ManageUsers).collect(),
+            Role::Administrator => Capability::ALL.to_vec(),
+        }
+    }
+}
+
+/// What a user may do on a server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerPermissions {
+    pub user_id: u64,
+    pub capabilities: Vec<Capability>,
+}
+
+/// Creates the table holding what the users may do on the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_permission_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_permissions` (
+            server_id INTEGER NOT NULL,                                 -- The server the capabilities are on
+            user_id INTEGER NOT NULL,                                   -- The user who has them
+            capabilities TEXT NOT NULL,                                 -- The capabilities as a JSON array
+            PRIMARY KEY (server_id, user_id)
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Lists the capabilities granted on a server. The owner, members without an entry, and
+/// administrators of the panel are not listed, see [`get_capabilities`].
+pub fn get_server_permissions(server_id: u64) -> Result<Vec<ServerPermissions>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_permissions WHERE server_id = ? ORDER BY user_id")?;
+    statement.bind((1, server_id as i64))?;
+    let mut permissions = Vec::new();
+    while let State::Row = statement.next()? {
+        permissions.push(ServerPermissions {
+            user_id: statement.read::<i64, _>("user_id")? as u64,
+            capabilities: serde_json::from_str(&statement.read::<String, _>("capabilities")?)?,
+        });
+    }
+    Ok(permissions)
+}
+
+/// Replaces what a user may do on a server. Granting nothing locks the user out of the server,
+/// even as a member, until [`reset_capabilities`].
+///
+/// # Errors
+/// Returns an error if the acting user may not manage the users of the server, the user is its
+/// owner or does not exist, or the capabilities cannot be stored.
+pub fn set_capabilities(server_id: u64, user_id: u64, capabilities: &[Capability]) -> Result<(), Box<dyn Error>> {
+    authorize_user_change(server_id, user_id)?;
+    let user = get_user(user_id)?;
+    let mut capabilities = capabilities.to_vec();
+    capabilities.sort_unstable();
+    capabilities.dedup();
+
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO server_permissions (server_id, user_id, capabilities) VALUES (?, ?, ?)
+        ON CONFLICT(server_id, user_id) DO UPDATE SET capabilities = excluded.capabilities"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, user_id as i64))?;
+    statement.bind((3, serde_json::to_string(&capabilities)?.as_str()))?;
+    statement.next()?;
+    info!("User {:?} may now {:?} on server {}", user.username, capabilities, server_id);
+    Ok(())
+}
+
+/// Removes what a user was granted on a server. A member of the server is left with the access
+/// members have without an entry, see [`get_capabilities`].
+///
+/// # Errors
+/// Returns an error if the acting user may not manage the users of the server, or the user is its
+/// owner.
+pub fn reset_capabilities(server_id: u64, user_id: u64) -> Result<(), Box<dyn Error>> {
+    authorize_user_change(server_id, user_id)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM server_permissions WHERE server_id = ? AND user_id = ?")?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, user_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Grants a user the capabilities of a role on a server, replacing those they had. The same checks
+/// as [`set_capabilities`] apply.
+pub fn grant_role(server_id: u64, user_id: u64, role: Role) -> Result<(), Box<dyn Error>> {
+    authorize_user_change(server_id, user_id)?;
+    set_capabilities(server_id, user_id, &role.capabilities())
+}
+
+/// Fails unless the acting user may change what a user may do on a server. The owner always may
+/// do everything, so their access cannot be changed.
+fn authorize_user_change(server_id: u64, user_id: u64) -> Result<(), Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    authorize(&server, Capability::ManageUsers)?;
+    if server.owner == user_id {
+        return Err(format!("The owner of the server {:?} always has every capability", server.name).into());
+    }
+    Ok(())
+}
+
+/// What a user may do on a server.
+///
+/// Administrators of the panel and the owner of the server may do everything. Other users have
+/// the capabilities granted to them. Members of the server who were never granted any keep the
+/// access members had before there were capabilities, those of [`Role::Operator`].
+pub fn get_capabilities(user_id: u64, server: &Server<u64>) -> Result<Vec<Capability>, Box<dyn Error>> {
+    let privileged = server.owner == user_id || get_user(user_id)?.is_admin;
+    let granted = if privileged { None } else { granted_capabilities(server.id, user_id)? };
+    Ok(resolve_capabilities(privileged, server.members.contains(&user_id), granted))
+}
+
+/// The capabilities stored for a user on a server, `None` if they were never granted any.
+fn granted_capabilities(server_id: u64, user_id: u64) -> Result<Option<Vec<Capability>>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare("SELECT capabilities FROM server_permissions WHERE server_id = ? AND user_id = ?")?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, user_id as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(Some(serde_json::from_str(&statement.read::<String, _>("capabilities")?)?));
+    }
+    Ok(None)
+}
+
+/// Decides what a user may do from whether they own the server or administer the panel, are a
+/// member, and what they were granted, see [`get_capabilities`].
+fn resolve_capabilities(privileged: bool, member: bool, granted: Option<Vec<Capability>>) -> Vec<Capability> {
+    if privileged {
+        return Capability::ALL.to_vec();
+    }
+    match granted {
+        Some(granted) => granted,
+        None if member => Role::Operator.capabilities(),
+        None => Vec::new(),
+    }
+}
+
+/// Whether a user may do something on a server.
+pub fn has_capability(user_id: u64, server: &Server<u64>, capability: Capability) -> Result<bool, Box<dyn Error>> {
+    Ok(get_capabilities(user_id, server)?.contains(&capability))
+}
+
+/// Fails unless a user may do something on a server. Handlers call it before doing anything the
+/// user asked for on a server.
+///
+/// # Errors
+/// Returns an error if the user lacks the capability or does not exist.
+pub fn require_capability(user_id: u64, server: &Server<u64>, capability: Capability) -> Result<(), Box<dyn Error>> {
+    if !has_capability(user_id, server, capability)? {
+        return Err(format!("You are not allowed to {} on the server {:?}", describe(capability), server.name).into());
+    }
+    Ok(())
+}
+
+/// Fails unless the user acting on this thread, see [`crate::audit_log::act_as`], may do
+/// something on a server, and the API token they act through allows it. Actions of the manager
+/// itself, without an acting user, are allowed.
+pub(crate) fn authorize(server: &Server<u64>, capability: Capability) -> Result<(), Box<dyn Error>> {
+    match acting_user() {
+        Some(user_id) => {
+            check_token_scope(server, capability)?;
+            require_capability(user_id, server, capability)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Whether the acting user may do anything at all on a server, which is what it takes to see it.
+pub(crate) fn can_see(server: &Server<u64>) -> bool {
+    Capability::ALL.iter().any(|capability| authorize(server, *capability).is_ok())
+}
+
+fn describe(capability: Capability) -> &'static str {
+    match capability {
+        Capability::ViewConsole => "view the console",
+        Capability::SendCommands => "send commands",
+        Capability::ControlServer => "start or stop the server",
+        Capability::EditFiles => "change files",
+        Capability::ManageBackups => "manage backups",
+        Capability::ManageUsers => "manage the users",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owners_and_administrators_may_do_everything() {
+        assert_eq!(resolve_capabilities(true, false, None), Capability::ALL.to_vec());
+        // Granting an owner nothing does not lock them out
+        assert_eq!(resolve_capabilities(true, true, Some(Vec::new())), Capability::ALL.to_vec());
+    }
+
+    #[test]
+    fn granted_capabilities_replace_those_of_members() {
+        let granted = vec![Capability::ViewConsole];
+        assert_eq!(resolve_capabilities(false, true, Some(granted.clone())), granted);
+        assert!(resolve_capabilities(false, true, Some(Vec::new())).is_empty());
+    }
+
+    #[test]
+    fn members_without_an_entry_are_operators() {
+        let capabilities = resolve_capabilities(false, true, None);
+        assert_eq!(capabilities, Role::Operator.capabilities());
+        assert!(!capabilities.contains(&Capability::ManageUsers));
+    }
+
+    #[test]
+    fn other_users_may_do_nothing() {
+        assert!(resolve_capabilities(false, false, None).is_empty());
+    }
+
+    #[test]
+    fn roles_grant_increasing_capabilities() {
+        assert_eq!(Role::Viewer.capabilities(), vec![Capability::ViewConsole]);
+        let moderator = Role::Moderator.capabilities();
+        assert!(moderator.contains(&Capability::SendCommands));
+        assert!(!moderator.contains(&Capability::EditFiles));
+        assert_eq!(Role::Operator.capabilities().len(), Capability::ALL.len() - 1);
+        assert_eq!(Role::Administrator.capabilities(), Capability::ALL.to_vec());
+    }
+}
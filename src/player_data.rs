@@ -0,0 +1,294 @@
+use crate::datapacks::level_name;
+use crate::nbt::{read_nbt_file, write_nbt_file, NbtCompound, NbtFile, NbtTag};
+use crate::online_players::ServerOnlinePlayers;
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use log::info;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+/// A player file of the world, without its contents.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerDataSummary {
+    pub uuid: String,
+    /// The name from `usercache.json`, if the server remembers the player.
+    pub name: Option<String>,
+    pub size: u64,
+    /// When the file was last saved, in seconds since the Unix epoch.
+    pub modified: u64,
+}
+
+/// A stack of items in an inventory.
+#[derive(Debug, Clone, Serialize)]
+pub struct ItemStack {
+    /// The slot of the inventory, see [`PlayerData::inventory`] for the slot numbers.
+    pub slot: Option<i8>,
+    pub id: String,
+    pub count: i32,
+    /// The components of 1.20.5 and later, or the `tag` of older versions, such as enchantments and names.
+    pub data: Option<NbtTag>,
+}
+
+/// The state of a player as saved in `<world>/playerdata/<uuid>.dat`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerData {
+    pub uuid: String,
+    pub name: Option<String>,
+    pub dimension: Option<String>,
+    /// The position as `[x, y, z]`.
+    pub position: Option<[f64; 3]>,
+    /// The rotation as `[yaw, pitch]`.
+    pub rotation: Option<[f64; 2]>,
+    pub health: Option<f64>,
+    pub food_level: Option<i64>,
+    pub xp_level: Option<i64>,
+    /// The progress towards the next level, from 0 to 1.
+    pub xp_progress: Option<f64>,
+    pub xp_total: Option<i64>,
+    /// The game mode, 0 survival, 1 creative, 2 adventure and 3 spectator.
+    pub game_mode: Option<i64>,
+    pub selected_slot: Option<i64>,
+    /// Slots 0 to 8 are the hotbar, 9 to 35 the main inventory, 100 to 103 the armor from boots to
+    /// helmet and -106 the offhand. 1.21.5 and later save armor and the offhand as equipment, they
+    /// are listed here with the same slot numbers.
+    pub inventory: Vec<ItemStack>,
+    pub ender_chest: Vec<ItemStack>,
+}
+
+/// Slots of the equipment compound of 1.21.5 and later, mapped to the inventory slots of older versions.
+const EQUIPMENT_SLOTS: &[(&str, i8)] =
+    &[("feet", 100), ("legs", 101), ("chest", 102), ("head", 103), ("offhand", -106)];
+
+fn read_item(tag: &NbtTag) -> Option<ItemStack> {
+    let item = tag.as_compound()?;
+    let id = item.get_str("id")?.to_string();
+    Some(ItemStack {
+        slot: item.get_i64("Slot").map(|slot| slot as i8),
+        id,
+        // `Count` was a byte before 1.20.5, items without a count are a single item
+        count: item.get_i64("count").or_else(|| item.get_i64("Count")).unwrap_or(1) as i32,
+        data: item.get("components").or_else(|| item.get("tag")).cloned(),
+    })
+}
+
+fn read_items(root: &NbtCompound, name: &str) -> Vec<ItemStack> {
+    root.get_list(name)
+        .map(|items| items.iter().filter_map(read_item).collect())
+        .unwrap_or_default()
+}
+
+fn float_array<const N: usize>(root: &NbtCompound, name: &str) -> Option<[f64; N]> {
+    let values = root.get_list(name)?;
+    let mut array = [0.0; N];
+    for (index, value) in array.iter_mut().enumerate() {
+        *value = values.get(index)?.as_f64()?;
+    }
+    Some(array)
+}
+
+/// Reads the names of the players the server remembers from `usercache.json`.
+fn user_cache(server: &Server<u64>) -> HashMap<String, String> {
+    let Ok(content) = fs::read_to_string(server.directory.join("usercache.json")) else {
+        return HashMap::new();
+    };
+    let Ok(Value::Array(entries)) = serde_json::from_str::<Value>(&content) else {
+        return HashMap::new();
+    };
+    entries
+        .iter()
+        .filter_map(|entry| Some((entry["uuid"].as_str()?.to_lowercase(), entry["name"].as_str()?.to_string())))
+        .collect()
+}
+
+fn is_uuid(uuid: &str) -> bool {
+    uuid.len() == 36
+        && uuid
+            .chars()
+            .enumerate()
+            .all(|(index, c)| matches!(index, 8 | 13 | 18 | 23) == (c == '-') && (c == '-' || c.is_ascii_hexdigit()))
+}
+
+fn player_file(server: &Server<u64>, uuid: &str) -> Result<SandboxedPath, Box<dyn Error>> {
+    if !is_uuid(uuid) {
+        return Err(format!("{:?} is not a UUID", uuid).into());
+    }
+    let file = SandboxedPath::new(
+        &server.directory,
+        PathBuf::from(level_name(server)).join("playerdata").join(format!("{}.dat", uuid.to_lowercase())),
+    )?;
+    if !file.path().is_file() {
+        return Err(format!("The world has no data of the player {}", uuid).into());
+    }
+    Ok(file)
+}
+
+/// Reads the spawn of the overworld from `level.dat`.
+///
+/// 1.21.9 moved it from `SpawnX`, `SpawnY` and `SpawnZ` into a `spawn` compound with a `pos` array.
+fn world_spawn(server: &Server<u64>) -> Result<[f64; 3], Box<dyn Error>> {
+    let level = SandboxedPath::new(&server.directory, PathBuf::from(level_name(server)).join("level.dat"))?;
+    let level = read_nbt_file(&level.path())?;
+    let data = level.root.get_compound("Data").ok_or("level.dat has no Data compound")?;
+    let position = match data.get_compound("spawn").and_then(|spawn| spawn.get("pos")?.as_int_array()) {
+        Some(position) if position.len() == 3 => [position[0], position[1], position[2]],
+        _ => [
+            data.get_i64("SpawnX").ok_or("level.dat has no spawn")? as i32,
+            data.get_i64("SpawnY").ok_or("level.dat has no spawn")? as i32,
+            data.get_i64("SpawnZ").ok_or("level.dat has no spawn")? as i32,
+        ],
+    };
+    // The middle of the block, as the game places players
+    Ok([f64::from(position[0]) + 0.5, f64::from(position[1]), f64::from(position[2]) + 0.5])
+}
+
+pub trait ServerPlayerData {
+    /// Lists the player files of the world, most recently saved first.
+    fn list_player_data(&self) -> Result<Vec<PlayerDataSummary>, Box<dyn Error>>;
+
+    /// Reads the inventory, position, experience and health of a player.
+    fn get_player_data(&self, uuid: &str) -> Result<PlayerData, Box<dyn Error>>;
+
+    /// Empties the inventory of a player who is offline, and the ender chest if `ender_chest` is set.
+    ///
+    /// # Errors
+    /// Returns an error if the player is online, the game would overwrite the file when they leave.
+    fn clear_player_inventory(&self, uuid: &str, ender_chest: bool) -> Result<(), Box<dyn Error>>;
+
+    /// Moves a player who is offline to the spawn of the overworld, where they appear on their next join.
+    ///
+    /// # Errors
+    /// Returns an error if the player is online or `level.dat` has no spawn.
+    fn move_player_to_spawn(&self, uuid: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Loads the file of a player for editing, refusing players who are online.
+fn edit_player_file(server: &Server<u64>, uuid: &str) -> Result<(SandboxedPath, NbtFile), Box<dyn Error>> {
+    let file = player_file(server, uuid)?;
+    // Players seen only in a query response have no UUID, they are matched by name
+    let name = user_cache(server).remove(&uuid.to_lowercase());
+    let online = server.get_online_players().into_iter().any(|player| match player.uuid {
+        Some(online) => online.eq_ignore_ascii_case(uuid),
+        None => name.as_ref().is_some_and(|name| name.eq_ignore_ascii_case(&player.name)),
+    });
+    if online {
+        return Err("The player is online, kick them or wait until they leave".into());
+    }
+    let nbt = read_nbt_file(&file.path())?;
+    Ok((file, nbt))
+}
+
+impl ServerPlayerData for Server<u64> {
+    fn list_player_data(&self) -> Result<Vec<PlayerDataSummary>, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        let directory = SandboxedPath::new(&self.directory, PathBuf::from(level_name(self)).join("playerdata"))?;
+        let Ok(entries) = fs::read_dir(directory.path()) else {
+            return Ok(Vec::new());
+        };
+        let names = user_cache(self);
+        let mut players = Vec::new();
+        for entry in entries.filter_map(Result::ok) {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let Some(uuid) = file_name.strip_suffix(".dat").filter(|uuid| is_uuid(uuid)) else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            players.push(PlayerDataSummary {
+                uuid: uuid.to_string(),
+                name: names.get(&uuid.to_lowercase()).cloned(),
+                size: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs()),
+            });
+        }
+        players.sort_by(|a, b| b.modified.cmp(&a.modified));
+        Ok(players)
+    }
+
+    fn get_player_data(&self, uuid: &str) -> Result<PlayerData, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        let root = read_nbt_file(&player_file(self, uuid)?.path())?.root;
+        let mut inventory = read_items(&root, "Inventory");
+        if let Some(equipment) = root.get_compound("equipment") {
+            for (name, slot) in EQUIPMENT_SLOTS {
+                if let Some(mut item) = equipment.get(name).and_then(read_item) {
+                    item.slot = Some(*slot);
+                    inventory.push(item);
+                }
+            }
+        }
+        // Dimensions were numbers before 1.16
+        let dimension = root.get("Dimension").and_then(|dimension| match dimension {
+            NbtTag::String(dimension) => Some(dimension.clone()),
+            dimension => dimension.as_i64().map(|id| {
+                match id {
+                    -1 => "minecraft:the_nether",
+                    1 => "minecraft:the_end",
+                    _ => "minecraft:overworld",
+                }
+                .to_string()
+            }),
+        });
+        Ok(PlayerData {
+            uuid: uuid.to_lowercase(),
+            name: user_cache(self).remove(&uuid.to_lowercase()),
+            dimension,
+            position: float_array(&root, "Pos"),
+            rotation: float_array(&root, "Rotation"),
+            health: root.get_f64("Health"),
+            food_level: root.get_i64("foodLevel"),
+            xp_level: root.get_i64("XpLevel"),
+            xp_progress: root.get_f64("XpP"),
+            xp_total: root.get_i64("XpTotal"),
+            game_mode: root.get_i64("playerGameType"),
+            selected_slot: root.get_i64("SelectedItemSlot"),
+            inventory,
+            ender_chest: read_items(&root, "EnderItems"),
+        })
+    }
+
+    fn clear_player_inventory(&self, uuid: &str, ender_chest: bool) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let (file, mut nbt) = edit_player_file(self, uuid)?;
+        nbt.root.insert("Inventory", NbtTag::List(Vec::new()));
+        nbt.root.remove("equipment");
+        if ender_chest {
+            nbt.root.insert("EnderItems", NbtTag::List(Vec::new()));
+        }
+        write_nbt_file(&file.path(), &nbt)?;
+        info!("Cleared the inventory of player {} on server {:?}", uuid, self.name);
+        Ok(())
+    }
+
+    fn move_player_to_spawn(&self, uuid: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let spawn = world_spawn(self)?;
+        let (file, mut nbt) = edit_player_file(self, uuid)?;
+        nbt.root.insert("Pos", NbtTag::List(spawn.iter().map(|value| NbtTag::Double(*value)).collect()));
+        nbt.root.insert("Motion", NbtTag::List(vec![NbtTag::Double(0.0); 3]));
+        // The fall distance is a float before 1.21.5 and a double after, the game defaults it to 0
+        nbt.root.remove("FallDistance");
+        nbt.root.remove("fall_distance");
+        // Older versions saved the dimension as a number
+        let dimension = match nbt.root.get("Dimension") {
+            Some(NbtTag::Int(_)) => NbtTag::Int(0),
+            _ => NbtTag::String("minecraft:overworld".to_string()),
+        };
+        nbt.root.insert("Dimension", dimension);
+        // A player riding an entity would be moved back onto it
+        nbt.root.remove("RootVehicle");
+        write_nbt_file(&file.path(), &nbt)?;
+        info!("Moved player {} of server {:?} to the spawn at {:?}", uuid, self.name, spawn);
+        Ok(())
+    }
+}
@@ -0,0 +1,539 @@
+use crate::permissions::{authorize, Capability};
+use crate::player_profiles::lookup_profile_by_name;
+use crate::server::Server;
+use crate::server_process::{send_server_command, ServerProcess};
+use crate::server_properties::ServerProperties;
+use crate::text_file::write_file_atomically;
+use lazy_static::lazy_static;
+use log::info;
+use md5::{Digest, Md5};
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const OPS_FILE: &str = "ops.json";
+const BANNED_PLAYERS_FILE: &str = "banned-players.json";
+const BANNED_IPS_FILE: &str = "banned-ips.json";
+
+/// The source the game records for bans issued from the console.
+const CONSOLE_SOURCE: &str = "Server";
+
+/// The reason the game records for bans issued without one.
+const DEFAULT_BAN_REASON: &str = "Banned by an operator.";
+
+lazy_static! {
+    static ref PLAYER_NAME: Option<Regex> = Regex::new(r"^[A-Za-z0-9_]{1,16}$").ok();
+}
+
+/// An entry of `ops.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Operator {
+    pub uuid: String,
+    pub name: String,
+    /// The permission level from 1 to 4, see `op-permission-level` of `server.properties`.
+    pub level: u8,
+    /// Lets the player join when the server is full.
+    #[serde(default)]
+    pub bypasses_player_limit: bool,
+}
+
+/// An entry of `banned-players.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerBan {
+    pub uuid: String,
+    pub name: String,
+    /// When the ban was issued, in seconds since the Unix epoch.
+    #[serde(with = "ban_date")]
+    pub created: u64,
+    /// Who issued the ban, a player name or `Server` for the console.
+    pub source: String,
+    /// When the ban ends, in seconds since the Unix epoch, `None` for a permanent ban.
+    #[serde(with = "ban_expiry")]
+    pub expires: Option<u64>,
+    pub reason: String,
+}
+
+/// An entry of `banned-ips.json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpBan {
+    pub ip: String,
+    #[serde(with = "ban_date")]
+    pub created: u64,
+    pub source: String,
+    #[serde(with = "ban_expiry")]
+    pub expires: Option<u64>,
+    pub reason: String,
+}
+
+impl PlayerBan {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+}
+
+impl IpBan {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now())
+    }
+}
+
+/// A ban to issue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BanOptions {
+    pub reason: Option<String>,
+    /// When the ban ends, in seconds since the Unix epoch, `None` for a permanent ban.
+    pub expires: Option<u64>,
+}
+
+impl Default for BanOptions {
+    fn default() -> Self {
+        Self {
+            reason: None,
+            expires: None,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+/// Converts days since the Unix epoch to a `(year, month, day)` date of the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The inverse of [`civil_from_days`].
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Formats a time like the game does in its ban lists, `2024-05-01 13:45:00 +0000`.
+fn format_date(seconds: u64) -> String {
+    let seconds = seconds as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86_400));
+    let time = seconds.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} +0000",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+/// Parses a date of a ban list, which the game writes with the offset of the local time zone.
+fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.split_whitespace();
+    let numbers = |part: &str, separator: char| -> Option<Vec<i64>> {
+        part.split(separator).map(|number| number.parse().ok()).collect()
+    };
+    let (day, time) = (numbers(parts.next()?, '-')?, numbers(parts.next()?, ':')?);
+    let (&[year, month, day], &[hours, minutes, seconds]) = (day.as_slice(), time.as_slice()) else {
+        return None;
+    };
+    let offset = match parts.next() {
+        Some(zone) if zone.len() == 5 => {
+            let minutes = zone.get(1..3)?.parse::<i64>().ok()? * 60 + zone.get(3..)?.parse::<i64>().ok()?;
+            if zone.starts_with('-') {
+                -minutes * 60
+            } else {
+                minutes * 60
+            }
+        }
+        _ => 0,
+    };
+    let seconds = days_from_civil(year, month, day) * 86_400 + hours * 3600 + minutes * 60 + seconds - offset;
+    u64::try_from(seconds).ok()
+}
+
+mod ban_date {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(seconds: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::format_date(*seconds))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let date = String::deserialize(deserializer)?;
+        super::parse_date(&date).ok_or_else(|| serde::de::Error::custom(format!("Invalid date {:?}", date)))
+    }
+}
+
+mod ban_expiry {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(expires: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match expires {
+            Some(seconds) => serializer.serialize_str(&super::format_date(*seconds)),
+            None => serializer.serialize_str("forever"),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        let date = String::deserialize(deserializer)?;
+        if date.eq_ignore_ascii_case("forever") {
+            return Ok(None);
+        }
+        super::parse_date(&date)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid date {:?}", date)))
+    }
+}
+
+/// Formats a UUID with the dashes the game writes, it is returned by the Mojang API without them.
+fn hyphenate_uuid(uuid: &str) -> Option<String> {
+    let uuid = uuid.replace('-', "").to_lowercase();
+    if uuid.len() != 32 || !uuid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{}-{}-{}-{}-{}", &uuid[..8], &uuid[8..12], &uuid[12..16], &uuid[16..20], &uuid[20..]))
+}
+
+/// The UUID an offline mode server gives a player, a version 3 UUID of `OfflinePlayer:<name>`.
+pub fn offline_player_uuid(name: &str) -> String {
+    let mut bytes: [u8; 16] = Md5::digest(format!("OfflinePlayer:{}", name).as_bytes()).into();
+    bytes[6] = (bytes[6] & 0x0f) | 0x30;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hyphenate_uuid(&hex).unwrap_or(hex)
+}
+
+/// Looks up the UUID and the correctly cased name of a player.
+///
+/// Online mode servers identify players by their Mojang account, offline mode servers derive the
+/// UUID from the name.
+///
+/// # Errors
+/// Returns an error if the name is not a valid player name or no account has this name.
+pub fn resolve_player(name: &str, online_mode: bool) -> Result<(String, String), Box<dyn Error>> {
+    check_player_name(name)?;
+    if !online_mode {
+        return Ok((offline_player_uuid(name), name.to_string()));
+    }
+    let profile = lookup_profile_by_name(name)
+        .map_err(|e| format!("Failed to look up the player {}: {}", name, e))?
+        .ok_or_else(|| format!("No Minecraft account is named {}", name))?;
+    Ok((profile.uuid, profile.name))
+}
+
+/// The lists can be read by users who may view the console, and changed by those who may send
+/// commands, which could change them anyway.
+/// Fails unless a name is one the game allows, so it cannot carry more than a name into a command.
+fn check_player_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if !PLAYER_NAME.as_ref().is_some_and(|pattern| pattern.is_match(name)) {
+        return Err(format!("{:?} is not a valid player name", name).into());
+    }
+    Ok(())
+}
+
+/// The reason of a ban on a single line, a line break would end the command and start another.
+fn ban_reason(options: &BanOptions) -> Option<String> {
+    options
+        .reason
+        .as_deref()
+        .map(|reason| reason.replace(['\r', '\n'], " ").trim().to_string())
+        .filter(|reason| !reason.is_empty())
+}
+
+pub trait ServerPlayerLists {
+    fn get_operators(&self) -> Result<Vec<Operator>, Box<dyn Error>>;
+
+    /// Makes a player an operator, or changes the level of an existing one.
+    ///
+    /// On a running server this is done with `/op`, which always grants the level set by
+    /// `op-permission-level`. A different level or `bypasses_player_limit` can only be written while
+    /// the server is stopped, as the game overwrites `ops.json` with its own copy.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the player.
+    /// * `level` - The permission level, `None` for the `op-permission-level` of the server.
+    /// * `bypasses_player_limit` - Lets the player join a full server.
+    fn add_operator(
+        &self,
+        name: &str,
+        level: Option<u8>,
+        bypasses_player_limit: bool,
+    ) -> Result<Operator, Box<dyn Error>>;
+
+    fn remove_operator(&self, name: &str) -> Result<(), Box<dyn Error>>;
+
+    fn get_banned_players(&self) -> Result<Vec<PlayerBan>, Box<dyn Error>>;
+
+    /// Bans a player, with `/ban` if the server is running.
+    ///
+    /// # Errors
+    /// Returns an error if the ban expires and the server is running, `/ban` only issues permanent bans.
+    fn ban_player(&self, name: &str, options: &BanOptions) -> Result<PlayerBan, Box<dyn Error>>;
+
+    fn pardon_player(&self, name: &str) -> Result<(), Box<dyn Error>>;
+
+    fn get_banned_ips(&self) -> Result<Vec<IpBan>, Box<dyn Error>>;
+
+    /// Bans an IP address, with `/ban-ip` if the server is running.
+    ///
+    /// # Errors
+    /// Returns an error if the address is invalid, or the ban expires and the server is running.
+    fn ban_ip(&self, ip: &str, options: &BanOptions) -> Result<IpBan, Box<dyn Error>>;
+
+    fn pardon_ip(&self, ip: &str) -> Result<(), Box<dyn Error>>;
+}
+
+fn read_list<T: DeserializeOwned>(server: &Server<u64>, file: &str) -> Result<Vec<T>, Box<dyn Error>> {
+    match fs::read_to_string(server.directory.join(file)) {
+        Ok(content) if content.trim().is_empty() => Ok(Vec::new()),
+        Ok(content) => serde_json::from_str(&content).map_err(|e| format!("Failed to read {}: {}", file, e).into()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write_list<T: serde::Serialize>(server: &Server<u64>, file: &str, entries: &[T]) -> Result<(), Box<dyn Error>> {
+    write_file_atomically(&server.directory.join(file), serde_json::to_string_pretty(entries)?.as_bytes())
+}
+
+fn online_mode(server: &Server<u64>) -> bool {
+    server
+        .load_properties_file()
+        .ok()
+        .and_then(|properties| properties.get("online-mode").map(|mode| mode != "false"))
+        .unwrap_or(true)
+}
+
+fn default_op_level(server: &Server<u64>) -> u8 {
+    server
+        .load_properties_file()
+        .ok()
+        .and_then(|properties| properties.get("op-permission-level").and_then(|level| level.parse().ok()))
+        .filter(|level| (1..=4).contains(level))
+        .unwrap_or(4)
+}
+
+impl ServerPlayerLists for Server<u64> {
+    fn get_operators(&self) -> Result<Vec<Operator>, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        read_list(self, OPS_FILE)
+    }
+
+    fn add_operator(
+        &self,
+        name: &str,
+        level: Option<u8>,
+        bypasses_player_limit: bool,
+    ) -> Result<Operator, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        let default_level = default_op_level(self);
+        let level = level.unwrap_or(default_level);
+        if !(1..=4).contains(&level) {
+            return Err(format!("The operator level has to be between 1 and 4, not {}", level).into());
+        }
+        let mut operators: Vec<Operator> = read_list(self, OPS_FILE)?;
+        let existing = operators.iter().position(|op| op.name.eq_ignore_ascii_case(name));
+        let (uuid, name) = match existing.and_then(|index| operators.get(index)) {
+            Some(op) => (op.uuid.clone(), op.name.clone()),
+            None => resolve_player(name, online_mode(self))?,
+        };
+        let operator = Operator {
+            uuid,
+            name,
+            level,
+            bypasses_player_limit,
+        };
+
+        if self.is_server_running() {
+            if level != default_level || bypasses_player_limit {
+                return Err("The operator level and player limit bypass can only be changed while the server is stopped"
+                    .into());
+            }
+            send_server_command(self, format!("op {}", operator.name))?;
+        } else {
+            match existing {
+                Some(index) => operators[index] = operator.clone(),
+                None => operators.push(operator.clone()),
+            }
+            write_list(self, OPS_FILE, &operators)?;
+        }
+        info!("Made {} an operator of level {} on server {:?}", operator.name, level, self.name);
+        Ok(operator)
+    }
+
+    fn remove_operator(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        check_player_name(name)?;
+        if self.is_server_running() {
+            return send_server_command(self, format!("deop {}", name));
+        }
+        let mut operators: Vec<Operator> = read_list(self, OPS_FILE)?;
+        let count = operators.len();
+        operators.retain(|op| !op.name.eq_ignore_ascii_case(name));
+        if operators.len() == count {
+            return Err(format!("{} is not an operator", name).into());
+        }
+        write_list(self, OPS_FILE, &operators)?;
+        info!("Removed {} from the operators of server {:?}", name, self.name);
+        Ok(())
+    }
+
+    fn get_banned_players(&self) -> Result<Vec<PlayerBan>, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        read_list(self, BANNED_PLAYERS_FILE)
+    }
+
+    fn ban_player(&self, name: &str, options: &BanOptions) -> Result<PlayerBan, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        let running = self.is_server_running();
+        if running && options.expires.is_some() {
+            return Err("Temporary bans can only be issued while the server is stopped".into());
+        }
+        let mut bans: Vec<PlayerBan> = read_list(self, BANNED_PLAYERS_FILE)?;
+        let existing = bans.iter().position(|ban| ban.name.eq_ignore_ascii_case(name));
+        let (uuid, name) = match existing.and_then(|index| bans.get(index)) {
+            Some(ban) => (ban.uuid.clone(), ban.name.clone()),
+            None => resolve_player(name, online_mode(self))?,
+        };
+        let reason = ban_reason(options);
+        let ban = PlayerBan {
+            uuid,
+            name,
+            created: now(),
+            source: CONSOLE_SOURCE.to_string(),
+            expires: options.expires,
+            reason: reason.clone().unwrap_or_else(|| DEFAULT_BAN_REASON.to_string()),
+        };
+
+        if running {
+            send_server_command(self, format!("ban {} {}", ban.name, reason.unwrap_or_default()).trim_end())?;
+        } else {
+            match existing {
+                Some(index) => bans[index] = ban.clone(),
+                None => bans.push(ban.clone()),
+            }
+            write_list(self, BANNED_PLAYERS_FILE, &bans)?;
+        }
+        info!("Banned {} from server {:?}", ban.name, self.name);
+        Ok(ban)
+    }
+
+    fn pardon_player(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        check_player_name(name)?;
+        if self.is_server_running() {
+            return send_server_command(self, format!("pardon {}", name));
+        }
+        let mut bans: Vec<PlayerBan> = read_list(self, BANNED_PLAYERS_FILE)?;
+        let count = bans.len();
+        bans.retain(|ban| !ban.name.eq_ignore_ascii_case(name));
+        if bans.len() == count {
+            return Err(format!("{} is not banned", name).into());
+        }
+        write_list(self, BANNED_PLAYERS_FILE, &bans)?;
+        info!("Pardoned {} on server {:?}", name, self.name);
+        Ok(())
+    }
+
+    fn get_banned_ips(&self) -> Result<Vec<IpBan>, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        read_list(self, BANNED_IPS_FILE)
+    }
+
+    fn ban_ip(&self, ip: &str, options: &BanOptions) -> Result<IpBan, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        let ip = ip.trim().parse::<IpAddr>().map_err(|_| format!("{:?} is not an IP address", ip))?.to_string();
+        let running = self.is_server_running();
+        if running && options.expires.is_some() {
+            return Err("Temporary bans can only be issued while the server is stopped".into());
+        }
+        let reason = ban_reason(options);
+        let ban = IpBan {
+            ip,
+            created: now(),
+            source: CONSOLE_SOURCE.to_string(),
+            expires: options.expires,
+            reason: reason.clone().unwrap_or_else(|| DEFAULT_BAN_REASON.to_string()),
+        };
+
+        if running {
+            send_server_command(self, format!("ban-ip {} {}", ban.ip, reason.unwrap_or_default()).trim_end())?;
+        } else {
+            let mut bans: Vec<IpBan> = read_list(self, BANNED_IPS_FILE)?;
+            bans.retain(|existing| existing.ip != ban.ip);
+            bans.push(ban.clone());
+            write_list(self, BANNED_IPS_FILE, &bans)?;
+        }
+        info!("Banned the IP address {} from server {:?}", ban.ip, self.name);
+        Ok(ban)
+    }
+
+    fn pardon_ip(&self, ip: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        let ip = ip.trim().parse::<IpAddr>().map_err(|_| format!("{:?} is not an IP address", ip))?.to_string();
+        if self.is_server_running() {
+            return send_server_command(self, format!("pardon-ip {}", ip));
+        }
+        let mut bans: Vec<IpBan> = read_list(self, BANNED_IPS_FILE)?;
+        let count = bans.len();
+        bans.retain(|ban| ban.ip != ip);
+        if bans.len() == count {
+            return Err(format!("{} is not banned", ip).into());
+        }
+        write_list(self, BANNED_IPS_FILE, &bans)?;
+        info!("Pardoned the IP address {} on server {:?}", ip, self.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_game_player_names_are_accepted() {
+        for name in ["Notch", "jeb_", "a", "Sixteen_Letters_"] {
+            assert!(check_player_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "Seventeen_Letters", "two words", "name\nop attacker", "@a", "name;stop"] {
+            assert!(check_player_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn ban_reasons_stay_on_one_line() {
+        let options = BanOptions {
+            reason: Some("griefing\nop attacker\r\nstop".to_string()),
+            expires: None,
+        };
+        assert_eq!(ban_reason(&options).as_deref(), Some("griefing op attacker  stop"));
+    }
+
+    #[test]
+    fn blank_ban_reasons_are_left_out() {
+        let options = BanOptions {
+            reason: Some(" \r\n ".to_string()),
+            expires: None,
+        };
+        assert_eq!(ban_reason(&options), None);
+        assert_eq!(ban_reason(&BanOptions::default()), None);
+    }
+}
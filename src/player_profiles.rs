@@ -0,0 +1,267 @@
+use crate::file_hash::{hash_reader, HashAlgorithm};
+use crate::http_client::{get_bytes, lookup_json, Lookup};
+use base64::Engine;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use lru::LruCache;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::io::Cursor;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The directory skin textures are cached in, relative to the working directory like `servers`.
+pub const SKIN_CACHE_DIRECTORY: &str = "cache/skins";
+
+/// How long a looked up profile is reused. Names can change, but rarely.
+const PROFILE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long an unknown name or UUID is remembered, so a typo does not cost a request every time.
+const MISSING_PROFILE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How long to back off when Mojang answers with a rate limit but no `Retry-After` header.
+const DEFAULT_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The number of names and of profiles that are remembered.
+const PROFILE_CACHE_CAPACITY: NonZeroUsize = match NonZeroUsize::new(4096) {
+    Some(capacity) => capacity,
+    None => NonZeroUsize::MIN,
+};
+
+/// Skins are 64x64 PNGs, anything much larger is not a skin.
+const MAX_SKIN_SIZE: u64 = 1024 * 1024;
+
+/// The largest head that is rendered.
+const MAX_HEAD_SIZE: u32 = 512;
+
+/// The classic default skins, for accounts without a skin of their own.
+const STEVE_SKIN: &str =
+    "https://textures.minecraft.net/texture/31f477eb1a7beee631c2ca64d06f8f68fa93a3386d04452ab27f43acdf1b60cb";
+const ALEX_SKIN: &str =
+    "https://textures.minecraft.net/texture/46acd06e8483b176e8ea39fc12fe105eb3a2a4970f5100057e9d84d4b60bdfa7";
+
+struct Cached<T> {
+    value: Option<T>,
+    fetched_at: Instant,
+}
+
+impl<T: Clone> Cached<T> {
+    fn fresh(&self) -> Option<Option<T>> {
+        let ttl = if self.value.is_some() { PROFILE_TTL } else { MISSING_PROFILE_TTL };
+        (self.fetched_at.elapsed() < ttl).then(|| self.value.clone())
+    }
+}
+
+lazy_static! {
+    /// Maps lowercase names to UUIDs.
+    static ref NAME_CACHE: Mutex<LruCache<String, Cached<String>>> =
+        Mutex::new(LruCache::new(PROFILE_CACHE_CAPACITY));
+    static ref PROFILE_CACHE: Mutex<LruCache<String, Cached<PlayerProfile>>> =
+        Mutex::new(LruCache::new(PROFILE_CACHE_CAPACITY));
+    /// Requests are held back until then after Mojang answered with a rate limit.
+    static ref RATE_LIMITED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// The public profile of a Minecraft account.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerProfile {
+    /// The UUID with dashes, as the game writes it.
+    pub uuid: String,
+    pub name: String,
+    pub skin_url: Option<String>,
+    /// Whether the skin uses the slim arms of the Alex model.
+    pub slim: bool,
+    pub cape_url: Option<String>,
+}
+
+/// Normalizes a UUID with or without dashes to the form with dashes.
+fn normalize_uuid(uuid: &str) -> Option<String> {
+    let uuid = uuid.replace('-', "").to_lowercase();
+    if uuid.len() != 32 || !uuid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{}-{}-{}-{}-{}", &uuid[..8], &uuid[8..12], &uuid[12..16], &uuid[16..20], &uuid[20..]))
+}
+
+/// Fetches a Mojang API, unless a rate limit is still in effect.
+fn mojang_lookup(url: &str) -> Result<Option<Value>, Box<dyn Error>> {
+    if let Ok(until) = RATE_LIMITED_UNTIL.lock() {
+        if let Some(until) = until.filter(|until| *until > Instant::now()) {
+            return Err(format!(
+                "Mojang is rate limiting profile lookups, try again in {} seconds",
+                until.duration_since(Instant::now()).as_secs() + 1
+            )
+            .into());
+        }
+    }
+    match lookup_json(url)? {
+        Lookup::Found(value) => Ok(Some(value)),
+        Lookup::NotFound => Ok(None),
+        Lookup::RateLimited(retry_after) => {
+            let backoff = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_BACKOFF);
+            warn!("Mojang rate limited profile lookups, backing off for {:?}", backoff);
+            if let Ok(mut until) = RATE_LIMITED_UNTIL.lock() {
+                *until = Some(Instant::now() + backoff);
+            }
+            Err("Mojang is rate limiting profile lookups, try again later".into())
+        }
+    }
+}
+
+/// Reads the skin and cape from the base64 encoded `textures` property of a session profile.
+fn parse_profile(profile: &Value) -> Option<PlayerProfile> {
+    let textures = profile["properties"]
+        .as_array()?
+        .iter()
+        .find(|property| property["name"] == "textures")
+        .and_then(|property| property["value"].as_str())
+        .and_then(|value| base64::engine::general_purpose::STANDARD.decode(value).ok())
+        .and_then(|json| serde_json::from_slice::<Value>(&json).ok())
+        .unwrap_or_default();
+    let skin = &textures["textures"]["SKIN"];
+    Some(PlayerProfile {
+        uuid: normalize_uuid(profile["id"].as_str()?)?,
+        name: profile["name"].as_str()?.to_string(),
+        skin_url: skin["url"].as_str().map(|url| url.replacen("http://", "https://", 1)),
+        slim: skin["metadata"]["model"] == "slim",
+        cape_url: textures["textures"]["CAPE"]["url"]
+            .as_str()
+            .map(|url| url.replacen("http://", "https://", 1)),
+    })
+}
+
+/// Looks up the profile of an account by its UUID, with or without dashes.
+///
+/// Profiles are cached, so repeated lookups for player lists do not run into the rate limit of Mojang.
+///
+/// # Returns
+/// `None` if no account has this UUID.
+///
+/// # Errors
+/// Returns an error if the UUID is invalid, the request fails or Mojang is rate limiting lookups.
+pub fn lookup_profile(uuid: &str) -> Result<Option<PlayerProfile>, Box<dyn Error>> {
+    let uuid = normalize_uuid(uuid).ok_or_else(|| format!("{:?} is not a UUID", uuid))?;
+    if let Some(profile) = PROFILE_CACHE.lock().ok().and_then(|mut cache| cache.get(&uuid)?.fresh()) {
+        return Ok(profile);
+    }
+    let url = format!("https://sessionserver.mojang.com/session/minecraft/profile/{}", uuid.replace('-', ""));
+    let profile = mojang_lookup(&url)?.as_ref().and_then(parse_profile);
+    if let Ok(mut cache) = PROFILE_CACHE.lock() {
+        cache.put(
+            uuid.clone(),
+            Cached {
+                value: profile.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+    if let (Some(profile), Ok(mut names)) = (&profile, NAME_CACHE.lock()) {
+        names.put(
+            profile.name.to_lowercase(),
+            Cached {
+                value: Some(profile.uuid.clone()),
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+    Ok(profile)
+}
+
+/// Looks up the UUID of an account by its name, case insensitively.
+///
+/// # Returns
+/// The UUID with dashes, `None` if no account has this name.
+pub fn lookup_uuid(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let key = name.to_lowercase();
+    if let Some(uuid) = NAME_CACHE.lock().ok().and_then(|mut cache| cache.get(&key)?.fresh()) {
+        return Ok(uuid);
+    }
+    let url = format!("https://api.mojang.com/users/profiles/minecraft/{}", name);
+    let uuid = mojang_lookup(&url)?.and_then(|profile| normalize_uuid(profile["id"].as_str()?));
+    if let Ok(mut cache) = NAME_CACHE.lock() {
+        cache.put(
+            key,
+            Cached {
+                value: uuid.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+    }
+    Ok(uuid)
+}
+
+/// Looks up the profile of an account by its name, case insensitively.
+pub fn lookup_profile_by_name(name: &str) -> Result<Option<PlayerProfile>, Box<dyn Error>> {
+    match lookup_uuid(name)? {
+        Some(uuid) => lookup_profile(&uuid),
+        None => Ok(None),
+    }
+}
+
+/// Returns the skin texture of an account as a PNG, the default skin if it has none.
+///
+/// Textures never change under their URL, so they are cached on disk indefinitely.
+pub fn get_skin(uuid: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let profile = lookup_profile(uuid)?.ok_or_else(|| format!("No Minecraft account has the UUID {}", uuid))?;
+    let url = profile.skin_url.unwrap_or_else(|| default_skin(&profile.uuid).to_string());
+    let file_name = format!("{}.png", hash_reader(url.as_bytes(), HashAlgorithm::Sha1)?);
+    let cached = PathBuf::from(SKIN_CACHE_DIRECTORY).join(file_name);
+    if let Ok(skin) = fs::read(&cached) {
+        return Ok(skin);
+    }
+    debug!("Downloading the skin of {} from {}", profile.name, url);
+    let skin = get_bytes(&url, MAX_SKIN_SIZE)?;
+    if let Err(e) = fs::create_dir_all(SKIN_CACHE_DIRECTORY).and_then(|_| fs::write(&cached, &skin)) {
+        warn!("Failed to cache the skin {}: {}", url, e);
+    }
+    Ok(skin)
+}
+
+/// The default skin the game picks for an account without one, from the parity of the hash code of the UUID.
+fn default_skin(uuid: &str) -> &'static str {
+    // Java's UUID.hashCode() XORs all four 32 bit words, so its lowest bit is the XOR of their lowest bits,
+    // which are the lowest bits of every eighth hex digit
+    let digits = uuid.replace('-', "");
+    let parity = [7, 15, 23, 31]
+        .iter()
+        .filter_map(|index| digits.get(*index..*index + 1)?.chars().next()?.to_digit(16))
+        .fold(0, |parity, digit| parity ^ (digit & 1));
+    if parity == 1 {
+        ALEX_SKIN
+    } else {
+        STEVE_SKIN
+    }
+}
+
+/// Renders the face of a skin as a square PNG, scaled without smoothing so the pixels stay crisp.
+///
+/// # Arguments
+/// * `skin` - The skin texture, 64x64 or the 64x32 of skins from before 1.8.
+/// * `size` - The edge length of the head in pixels, capped at 512.
+/// * `overlay` - Draws the hat layer over the face.
+pub fn render_head_from_skin(skin: &[u8], size: u32, overlay: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let skin = image::load_from_memory_with_format(skin, ImageFormat::Png)?.to_rgba8();
+    if skin.width() != 64 || (skin.height() != 64 && skin.height() != 32) {
+        return Err(format!("A skin is 64x64 pixels, not {}x{}", skin.width(), skin.height()).into());
+    }
+    let mut face: RgbaImage = imageops::crop_imm(&skin, 8, 8, 8, 8).to_image();
+    if overlay {
+        imageops::overlay(&mut face, &imageops::crop_imm(&skin, 40, 8, 8, 8).to_image(), 0, 0);
+    }
+    let size = size.clamp(8, MAX_HEAD_SIZE);
+    let head = imageops::resize(&face, size, size, FilterType::Nearest);
+    let mut png = Vec::new();
+    DynamicImage::ImageRgba8(head).write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
+
+/// Renders the head of an account as a PNG avatar for player lists.
+pub fn render_head(uuid: &str, size: u32, overlay: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    render_head_from_skin(&get_skin(uuid)?, size, overlay)
+}
@@ -0,0 +1,307 @@
+use crate::online_players::{add_player_listener, PlayerEvent, PlayerEventKind};
+use log::{error, info};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::Serialize;
+use sqlite::State;
+use std::error::Error;
+use std::sync::Once;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static RECORDER: Once = Once::new();
+
+/// A visit of a player to a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerSession {
+    pub player: String,
+    pub uuid: Option<String>,
+    pub ip: Option<String>,
+    /// When the player joined, in seconds since the Unix epoch.
+    pub joined_at: u64,
+    /// When the player left, `None` while the player is still online.
+    pub left_at: Option<u64>,
+    /// How long the session lasted, up to now for a player who is still online.
+    pub duration_seconds: u64,
+}
+
+/// What the sessions of a player on a server add up to.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayerStats {
+    pub player: String,
+    pub uuid: Option<String>,
+    pub sessions: u64,
+    pub playtime_seconds: u64,
+    pub first_seen: u64,
+    /// When the player was last online, now for a player who is online.
+    pub last_seen: u64,
+    pub online: bool,
+}
+
+/// The most players that were online at once within a period.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ConcurrencyPoint {
+    /// The start of the period, in seconds since the Unix epoch.
+    pub start: u64,
+    pub peak: u32,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the tables recording the sessions of the players and how many of them were online.
+///
+/// # Errors
+/// Returns an error if the database connection fails or a table cannot be created.
+pub fn initialize_player_session_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_player_sessions` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the session
+            server_id INTEGER NOT NULL,                                 -- The server the player joined
+            player TEXT NOT NULL,                                       -- The name of the player
+            uuid TEXT,                                                  -- The UUID, if it was logged
+            ip TEXT,                                                    -- The address the player joined from
+            joined_at INTEGER NOT NULL,                                 -- Unix time of the join
+            left_at INTEGER                                             -- Unix time of the leave, NULL while online
+        );
+        CREATE INDEX IF NOT EXISTS `server_player_sessions_player` ON `server_player_sessions` (server_id, player);
+        CREATE TABLE IF NOT EXISTS `server_player_counts` (
+            server_id INTEGER NOT NULL,                                 -- The server the count is of
+            recorded_at INTEGER NOT NULL,                               -- Unix time the count changed
+            online INTEGER NOT NULL                                     -- The players online from then on
+        );
+        CREATE INDEX IF NOT EXISTS `server_player_counts_time` ON `server_player_counts` (server_id, recorded_at);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Starts recording the players joining and leaving the servers. Calling it again has no effect.
+///
+/// Sessions left open by a previous run of the manager are closed at the last change of the player
+/// count that was recorded, the best guess of when the manager stopped watching.
+pub fn start_session_recording() {
+    RECORDER.call_once(|| {
+        if let Err(e) = close_abandoned_sessions() {
+            error!("Failed to close the player sessions of the previous run: {}", e);
+        }
+        add_player_listener(|event| {
+            if let Err(e) = record_player_event(event) {
+                error!("Failed to record the session of {} on server {}: {}", event.player.name, event.server_id, e);
+            }
+        });
+        info!("Started recording player sessions");
+    });
+}
+
+fn close_abandoned_sessions() -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"SELECT sessions.server_id, MAX(MAX(sessions.joined_at), COALESCE(MAX(counts.recorded_at), 0)) AS closed_at
+        FROM server_player_sessions AS sessions
+        LEFT JOIN server_player_counts AS counts ON counts.server_id = sessions.server_id
+        WHERE sessions.left_at IS NULL
+        GROUP BY sessions.server_id"#,
+    )?;
+    let mut abandoned = Vec::new();
+    while let State::Row = statement.next()? {
+        abandoned.push((statement.read::<i64, _>("server_id")?, statement.read::<i64, _>("closed_at")?));
+    }
+
+    for (server_id, closed_at) in abandoned {
+        let mut statement = conn.prepare(
+            r#"UPDATE server_player_sessions SET left_at = MAX(joined_at, ?) WHERE server_id = ? AND left_at IS NULL"#,
+        )?;
+        statement.bind((1, closed_at))?;
+        statement.bind((2, server_id))?;
+        statement.next()?;
+
+        let mut statement =
+            conn.prepare(r#"INSERT INTO server_player_counts (server_id, recorded_at, online) VALUES (?, ?, 0)"#)?;
+        statement.bind((1, server_id))?;
+        statement.bind((2, closed_at))?;
+        statement.next()?;
+        info!("Closed the player sessions of server {} left open by the previous run", server_id);
+    }
+    Ok(())
+}
+
+fn record_player_event(event: &PlayerEvent) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let at = unix_seconds(event.at) as i64;
+    match event.kind {
+        PlayerEventKind::Joined => {
+            let mut statement = conn.prepare(
+                r#"INSERT INTO server_player_sessions (server_id, player, uuid, ip, joined_at) VALUES (?, ?, ?, ?, ?)"#,
+            )?;
+            statement.bind((1, event.server_id as i64))?;
+            statement.bind((2, event.player.name.as_str()))?;
+            statement.bind((3, event.player.uuid.as_deref()))?;
+            statement.bind((4, event.player.ip.as_deref()))?;
+            statement.bind((5, at))?;
+            statement.next()?;
+        }
+        PlayerEventKind::Left => {
+            let query = r#"
+UPDATE server_player_sessions SET left_at = ?, uuid = COALESCE(uuid, ?)
+WHERE id = (
+    SELECT id FROM server_player_sessions
+    WHERE server_id = ? AND player = ? AND left_at IS NULL
+    ORDER BY joined_at DESC LIMIT 1
+)
+"#;
+            let mut statement = conn.prepare(query)?;
+            statement.bind((1, at))?;
+            statement.bind((2, event.player.uuid.as_deref()))?;
+            statement.bind((3, event.server_id as i64))?;
+            statement.bind((4, event.player.name.as_str()))?;
+            statement.next()?;
+        }
+    }
+
+    let query = r#"
+INSERT INTO server_player_counts (server_id, recorded_at, online)
+SELECT ?, ?, COUNT(*) FROM server_player_sessions WHERE server_id = ? AND left_at IS NULL
+"#;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, event.server_id as i64))?;
+    statement.bind((2, at))?;
+    statement.bind((3, event.server_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Lists the sessions of a server, newest first.
+///
+/// # Arguments
+/// * `server_id` - The server to list the sessions of.
+/// * `player` - Only lists the sessions of this player, case insensitively.
+/// * `limit` - The most sessions to return.
+pub fn get_player_sessions(
+    server_id: u64,
+    player: Option<&str>,
+    limit: u32,
+) -> Result<Vec<PlayerSession>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"SELECT * FROM server_player_sessions
+        WHERE server_id = ? AND (? IS NULL OR player = ? COLLATE NOCASE)
+        ORDER BY joined_at DESC LIMIT ?"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, player))?;
+    statement.bind((3, player))?;
+    statement.bind((4, limit as i64))?;
+
+    let now = unix_seconds(SystemTime::now());
+    let mut sessions = Vec::new();
+    while let State::Row = statement.next()? {
+        let joined_at = statement.read::<i64, _>("joined_at")? as u64;
+        let left_at = statement.read::<Option<i64>, _>("left_at")?.map(|left_at| left_at as u64);
+        sessions.push(PlayerSession {
+            player: statement.read::<String, _>("player")?,
+            uuid: statement.read::<Option<String>, _>("uuid")?,
+            ip: statement.read::<Option<String>, _>("ip")?,
+            joined_at,
+            left_at,
+            duration_seconds: left_at.unwrap_or(now).saturating_sub(joined_at),
+        });
+    }
+    Ok(sessions)
+}
+
+/// Computes the playtime and last seen time of every player that joined a server, most playtime first.
+pub fn get_player_stats(server_id: u64) -> Result<Vec<PlayerStats>, Box<dyn Error>> {
+    let query = r#"
+SELECT player, MAX(uuid) AS uuid, COUNT(*) AS sessions,
+    SUM(COALESCE(left_at, ?) - joined_at) AS playtime,
+    MIN(joined_at) AS first_seen, MAX(COALESCE(left_at, ?)) AS last_seen,
+    SUM(left_at IS NULL) AS open_sessions
+FROM server_player_sessions WHERE server_id = ?
+GROUP BY player COLLATE NOCASE
+ORDER BY playtime DESC
+"#;
+    let now = unix_seconds(SystemTime::now()) as i64;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, now))?;
+    statement.bind((2, now))?;
+    statement.bind((3, server_id as i64))?;
+
+    let mut stats = Vec::new();
+    while let State::Row = statement.next()? {
+        stats.push(PlayerStats {
+            player: statement.read::<String, _>("player")?,
+            uuid: statement.read::<Option<String>, _>("uuid")?,
+            sessions: statement.read::<i64, _>("sessions")? as u64,
+            playtime_seconds: statement.read::<i64, _>("playtime")?.max(0) as u64,
+            first_seen: statement.read::<i64, _>("first_seen")? as u64,
+            last_seen: statement.read::<i64, _>("last_seen")? as u64,
+            online: statement.read::<i64, _>("open_sessions")? > 0,
+        });
+    }
+    Ok(stats)
+}
+
+/// Finds the most players online at once in every period since a point in time.
+///
+/// # Arguments
+/// * `server_id` - The server to look at.
+/// * `since` - The start of the first period, in seconds since the Unix epoch.
+/// * `period_seconds` - The length of every period, for example `3600` for one point per hour.
+pub fn get_peak_concurrency(
+    server_id: u64,
+    since: u64,
+    period_seconds: u64,
+) -> Result<Vec<ConcurrencyPoint>, Box<dyn Error>> {
+    if period_seconds == 0 {
+        return Err("The period has to be at least one second long".into());
+    }
+    let conn = create_appdb_connection()?;
+    // The count before the first period carries over into it
+    let mut statement = conn.prepare(
+        r#"SELECT online FROM server_player_counts WHERE server_id = ? AND recorded_at < ?
+        ORDER BY recorded_at DESC LIMIT 1"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, since as i64))?;
+    let mut online = match statement.next()? {
+        State::Row => statement.read::<i64, _>("online")? as u32,
+        State::Done => 0,
+    };
+
+    let now = unix_seconds(SystemTime::now());
+    let periods = now.saturating_sub(since) / period_seconds + 1;
+    let mut statement = conn.prepare(
+        r#"SELECT recorded_at, online FROM server_player_counts WHERE server_id = ? AND recorded_at >= ?
+        ORDER BY recorded_at"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, since as i64))?;
+    let mut points = Vec::new();
+    let mut period = 0;
+    let mut peak = online;
+    while let State::Row = statement.next()? {
+        let index = (statement.read::<i64, _>("recorded_at")? as u64).saturating_sub(since) / period_seconds;
+        // Every period starts with the count the previous one ended with
+        while period < index {
+            points.push(ConcurrencyPoint {
+                start: since + period * period_seconds,
+                peak,
+            });
+            period += 1;
+            peak = online;
+        }
+        online = statement.read::<i64, _>("online")? as u32;
+        peak = peak.max(online);
+    }
+    while period < periods {
+        points.push(ConcurrencyPoint {
+            start: since + period * period_seconds,
+            peak,
+        });
+        period += 1;
+        peak = online;
+    }
+    Ok(points)
+}
@@ -0,0 +1,280 @@
+use crate::eula::write_accepted_eula;
+use crate::extensions::jar_provider;
+use crate::file_hash::HashAlgorithm;
+use crate::http_client::{download_file, ExpectedHash};
+use crate::java_downloads::ServerJavaDownload;
+use crate::mojang_versions::resolve_vanilla_server;
+use crate::paper_downloads::{record_installed_build, resolve_build, PaperProject};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::ServerProcess;
+use crate::server_properties::ServerProperties;
+use crate::server_properties_file::ServerSettings;
+use crate::server_registry::{create_server, delete_server, NewServer};
+use crate::startup_watchdog::ServerStartup;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A listener invoked for every step of a provisioning.
+type ProvisioningListener = Box<dyn Fn(&ProvisioningEvent) + Send>;
+
+/// The name the downloaded server jar is stored under.
+const SERVER_JAR: &str = "server.jar";
+
+lazy_static! {
+    static ref PROVISIONING_LISTENERS: Mutex<Vec<ProvisioningListener>> = Mutex::new(Vec::new());
+}
+
+/// Where the server jar of a new server comes from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JarSource {
+    /// A jar downloaded from any URL, verified if a checksum is given.
+    Url {
+        url: String,
+        sha1: Option<String>,
+        sha256: Option<String>,
+    },
+    /// The vanilla server of a version from the Mojang version manifest, `latest` for the newest release.
+    Vanilla { version: String },
+    /// A build of Paper, Folia or Purpur, the newest stable one if `build` is not set.
+    Paper {
+        project: PaperProject,
+        version: String,
+        build: Option<u32>,
+    },
+    /// A jar from a provider an extension added, see [`crate::extensions::JarProvider`].
+    Extension {
+        provider: String,
+        version: String,
+        #[serde(default)]
+        build: Option<String>,
+    },
+}
+
+/// A resolved server jar, ready to be downloaded.
+#[derive(Debug, Clone, Serialize)]
+pub struct JarDownload {
+    pub url: String,
+    /// The version of the game the jar runs.
+    pub minecraft_version: Option<String>,
+    /// The build of the server software, for sources publishing several builds per version.
+    pub build: Option<String>,
+    pub sha1: Option<String>,
+    pub sha256: Option<String>,
+    pub md5: Option<String>,
+}
+
+impl JarDownload {
+    /// The strongest checksum published for the jar.
+    pub(crate) fn expected_hash(&self) -> Option<ExpectedHash> {
+        [
+            (HashAlgorithm::Sha256, &self.sha256),
+            (HashAlgorithm::Sha1, &self.sha1),
+            (HashAlgorithm::Md5, &self.md5),
+        ]
+        .into_iter()
+        .find_map(|(algorithm, hash)| hash.clone().map(|hash| ExpectedHash { algorithm, hash }))
+    }
+}
+
+impl JarSource {
+    /// Looks up the download of the jar.
+    pub fn resolve(&self) -> Result<JarDownload, Box<dyn Error>> {
+        match self {
+            JarSource::Url { url, sha1, sha256 } => Ok(JarDownload {
+                url: url.clone(),
+                minecraft_version: None,
+                build: None,
+                sha1: sha1.clone(),
+                sha256: sha256.clone(),
+                md5: None,
+            }),
+            JarSource::Vanilla { version } => resolve_vanilla_server(version),
+            JarSource::Paper { project, version, build } => Ok(resolve_build(*project, version, *build)?.into()),
+            JarSource::Extension {
+                provider,
+                version,
+                build,
+            } => jar_provider(provider)?.resolve(version, build.as_deref()),
+        }
+    }
+}
+
+/// Everything needed to create a server from scratch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProvisionOptions {
+    pub server: NewServer,
+    pub jar: JarSource,
+    /// Settings written to `server.properties` before the first start.
+    #[serde(default)]
+    pub settings: ServerSettings,
+    /// Whether the user agreed to the Minecraft EULA, which the server needs to start.
+    #[serde(default)]
+    pub accept_eula: bool,
+    /// Whether to start the server once to generate its world and default files, and stop it again.
+    #[serde(default)]
+    pub initial_boot: bool,
+    /// How long the initial boot may take, in seconds.
+    #[serde(default = "default_boot_timeout")]
+    pub boot_timeout_seconds: u64,
+}
+
+fn default_boot_timeout() -> u64 {
+    600
+}
+
+/// The steps of a provisioning, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisioningStep {
+    /// Registering the server and its directory.
+    Creating,
+    DownloadingJar,
+    /// Finding a Java runtime for the version, and downloading one if none is installed.
+    InstallingJava,
+    /// Writing `server.properties` and `eula.txt`.
+    Configuring,
+    /// Starting the server once to generate the world.
+    InitialBoot,
+    Completed,
+    Failed,
+}
+
+/// The progress of a provisioning, sent to the provisioning listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvisioningEvent {
+    /// The new server, `None` before it was registered.
+    pub server_id: Option<u64>,
+    pub step: ProvisioningStep,
+    /// The progress of the step from 0 to 1, if it can be measured.
+    pub progress: Option<f32>,
+    pub message: String,
+}
+
+/// Registers a listener that is invoked for every step of every provisioning.
+pub fn add_provisioning_listener(listener: impl Fn(&ProvisioningEvent) + Send + 'static) {
+    if let Ok(mut listeners) = PROVISIONING_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn emit(server_id: Option<u64>, step: ProvisioningStep, progress: Option<f32>, message: impl Into<String>) {
+    let event = ProvisioningEvent {
+        server_id,
+        step,
+        progress,
+        message: message.into(),
+    };
+    match PROVISIONING_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(&event)),
+        Err(err) => error!("Failed to notify provisioning listeners: {}", err),
+    }
+}
+
+/// Creates a server from scratch: registers it with a free port, downloads and verifies its jar,
+/// writes its configuration and optionally boots it once to generate the world.
+///
+/// Blocks until the provisioning finished, reporting the progress to the provisioning listeners.
+/// A server whose provisioning fails is removed again.
+///
+/// # Errors
+/// Returns an error if any step fails, the error is also sent as a [`ProvisioningStep::Failed`] event.
+pub fn provision_server(options: &ProvisionOptions) -> Result<Server<u64>, Box<dyn Error>> {
+    emit(None, ProvisioningStep::Creating, None, format!("Creating server {:?}", options.server.name));
+    let mut server = match create_server(&options.server) {
+        Ok(server) => server,
+        Err(e) => {
+            emit(None, ProvisioningStep::Failed, None, e.to_string());
+            return Err(e);
+        }
+    };
+
+    match set_up_server(&mut server, options) {
+        Ok(()) => {
+            emit(Some(server.id), ProvisioningStep::Completed, Some(1.0), "The server is ready");
+            info!("Provisioned server {:?}", server.name);
+            Ok(server)
+        }
+        Err(e) => {
+            emit(Some(server.id), ProvisioningStep::Failed, None, e.to_string());
+            if server.is_server_running() {
+                if let Err(stop) = server.kill_server() {
+                    warn!("Failed to stop the server {:?} that failed to provision: {}", server.name, stop);
+                }
+            }
+            if let Err(cleanup) = delete_server(server.id, true) {
+                warn!("Failed to remove the server {:?} that failed to provision: {}", server.name, cleanup);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn set_up_server(server: &mut Server<u64>, options: &ProvisionOptions) -> Result<(), Box<dyn Error>> {
+    let id = Some(server.id);
+
+    emit(id, ProvisioningStep::DownloadingJar, Some(0.0), "Looking up the server jar");
+    let jar = options.jar.resolve()?;
+    let mut on_progress = |downloaded: u64, total: Option<u64>| {
+        let progress = total.filter(|total| *total > 0).map(|total| downloaded as f32 / total as f32);
+        emit(id, ProvisioningStep::DownloadingJar, progress, format!("Downloaded {} bytes", downloaded));
+    };
+    download_file(
+        &jar.url,
+        &server.directory.join(SERVER_JAR),
+        jar.expected_hash().as_ref(),
+        &mut on_progress,
+    )?;
+
+    emit(id, ProvisioningStep::Configuring, None, "Writing server.properties");
+    // The jar is started from the server directory, so the relative name is enough
+    server.start_script = Some(PathBuf::from(SERVER_JAR));
+    if let Some(version) = jar.minecraft_version.clone() {
+        server.minecraft_version = version;
+    }
+    if let Some(build) = jar.build.clone() {
+        server.loader_version = Some(build);
+    }
+    server.update()?;
+    if server.java_runtime.is_none() {
+        emit(id, ProvisioningStep::InstallingJava, None, "Looking for a Java runtime");
+        let mut on_progress = |downloaded: u64, total: Option<u64>| {
+            let progress = total.filter(|total| *total > 0).map(|total| downloaded as f32 / total as f32);
+            emit(id, ProvisioningStep::InstallingJava, progress, format!("Downloaded {} bytes of Java", downloaded));
+        };
+        let runtime = server.install_java_runtime(&mut on_progress)?;
+        emit(id, ProvisioningStep::InstallingJava, Some(1.0), format!("Using Java {}", runtime.version));
+    }
+    if let JarSource::Paper { project, .. } = &options.jar {
+        record_installed_build(server.id, *project, &jar)?;
+    }
+    // The port was allocated when the server was created
+    let settings = ServerSettings {
+        server_port: None,
+        ..options.settings.clone()
+    };
+    server.update_server_settings(&settings)?;
+    if options.accept_eula {
+        write_accepted_eula(&server.directory)?;
+    }
+
+    if !options.initial_boot {
+        return Ok(());
+    }
+    if !options.accept_eula {
+        emit(id, ProvisioningStep::InitialBoot, None, "Skipping the initial boot, the EULA was not accepted");
+        return Ok(());
+    }
+    emit(id, ProvisioningStep::InitialBoot, None, "Starting the server to generate the world");
+    server.start_server()?;
+    server.wait_until_online(Duration::from_secs(options.boot_timeout_seconds))?;
+    emit(id, ProvisioningStep::InitialBoot, Some(0.9), "Stopping the server");
+    server.stop_server()?;
+    Ok(())
+}
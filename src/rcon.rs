@@ -0,0 +1,329 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_ping::local_host;
+use crate::server_properties::ServerProperties;
+use crate::users::random_token;
+use log::{debug, info};
+use serde_derive::Serialize;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The port the game uses for RCON unless `rcon.port` is set.
+pub const DEFAULT_RCON_PORT: u16 = 25575;
+
+/// How long to wait for the server to answer before giving up.
+const RCON_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest command the game accepts in a single packet.
+const MAX_COMMAND_LENGTH: usize = 1446;
+
+/// The longest packet the game sends or accepts, responses longer than 4096 bytes are split.
+const MAX_PACKET_LENGTH: usize = 4110;
+
+/// The random bytes in generated RCON passwords, which encode to 32 characters.
+const PASSWORD_BYTES: usize = 24;
+
+const PACKET_AUTH: i32 = 3;
+const PACKET_COMMAND: i32 = 2;
+const PACKET_RESPONSE: i32 = 0;
+/// A request type the game does not know, answered with an error under the same id. Sent after a
+/// command, its answer marks the end of a response split into several packets.
+const PACKET_END_MARKER: i32 = 200;
+
+/// A connection to the RCON interface of a server.
+///
+/// Works with any server that has RCON enabled, including servers the manager did not start.
+#[derive(Debug)]
+pub struct RconClient {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+/// Where and how to connect to the RCON interface of a server, as configured in `server.properties`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RconSettings {
+    pub enabled: bool,
+    pub port: u16,
+    #[serde(skip_serializing)]
+    pub password: String,
+}
+
+impl RconClient {
+    /// Connects to an RCON interface and logs in.
+    ///
+    /// # Errors
+    /// Returns an error if the connection fails, the password is rejected, or the server does not answer in time.
+    pub fn connect(address: impl ToSocketAddrs, password: &str) -> Result<Self, Box<dyn Error>> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or("The RCON address did not resolve to any socket address")?;
+        let stream = TcpStream::connect_timeout(&address, RCON_TIMEOUT)?;
+        stream.set_read_timeout(Some(RCON_TIMEOUT))?;
+        stream.set_write_timeout(Some(RCON_TIMEOUT))?;
+        let mut client = Self { stream, next_id: 1 };
+
+        let id = client.send(PACKET_AUTH, password)?;
+        // Some servers send an empty response before the result of the login
+        loop {
+            let (response_id, kind, _) = client.receive()?;
+            if response_id == -1 {
+                return Err(format!("The RCON password for {} was rejected", address).into());
+            }
+            if response_id == id && kind == PACKET_COMMAND {
+                break;
+            }
+        }
+        debug!("Logged in to RCON at {}", address);
+        Ok(client)
+    }
+
+    /// Runs a command and returns what the server answered.
+    ///
+    /// # Errors
+    /// Returns an error if the command is too long, or the connection fails or times out.
+    pub fn command(&mut self, command: &str) -> Result<String, Box<dyn Error>> {
+        if command.len() > MAX_COMMAND_LENGTH {
+            return Err(format!("RCON commands are limited to {} bytes", MAX_COMMAND_LENGTH).into());
+        }
+        let id = self.send(PACKET_COMMAND, command)?;
+        let marker = self.send(PACKET_END_MARKER, "")?;
+
+        let mut response = String::new();
+        loop {
+            let (response_id, kind, body) = self.receive()?;
+            if response_id == marker {
+                return Ok(response);
+            }
+            if response_id == id && kind == PACKET_RESPONSE {
+                response.push_str(&body);
+            }
+        }
+    }
+
+    fn send(&mut self, kind: i32, body: &str) -> Result<i32, Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.stream.write_all(&encode_packet(id, kind, body))?;
+        Ok(id)
+    }
+
+    fn receive(&mut self) -> Result<(i32, i32, String), Box<dyn Error>> {
+        read_packet(&mut self.stream)
+    }
+}
+
+fn encode_packet(id: i32, kind: i32, body: &str) -> Vec<u8> {
+    // The length counts the id, the type, the body and its two terminating null bytes
+    let length = 4 + 4 + body.len() + 2;
+    let mut packet = Vec::with_capacity(4 + length);
+    packet.extend_from_slice(&(length as i32).to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&kind.to_le_bytes());
+    packet.extend_from_slice(body.as_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet
+}
+
+/// Reads a packet, checking its length before anything is allocated.
+///
+/// # Returns
+/// The id, the type and the body of the packet.
+fn read_packet(reader: &mut impl Read) -> Result<(i32, i32, String), Box<dyn Error>> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    let length = i32::from_le_bytes(length);
+    if !(10..=MAX_PACKET_LENGTH as i32).contains(&length) {
+        return Err(format!("Received an RCON packet with an invalid length of {}", length).into());
+    }
+
+    let mut packet = vec![0u8; length as usize];
+    reader.read_exact(&mut packet)?;
+    let id = i32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+    let kind = i32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]);
+    let body = &packet[8..packet.len() - 2];
+    Ok((id, kind, String::from_utf8_lossy(body).into_owned()))
+}
+
+/// Generates a random password for RCON from the random number generator of the operating system.
+fn generate_password() -> String {
+    random_token(PASSWORD_BYTES)
+}
+
+/// The password lets anyone send commands, so everything here needs the acting user to be allowed
+/// to send commands.
+pub trait ServerRcon {
+    /// Reads the RCON settings from `server.properties`.
+    fn get_rcon_settings(&self) -> Result<RconSettings, Box<dyn Error>>;
+
+    /// Enables RCON in `server.properties`, keeping a configured port and password, and generating a
+    /// password if none is set.
+    ///
+    /// The game only reads `server.properties` while starting, a running server has to be restarted
+    /// before RCON is available.
+    fn enable_rcon(&self) -> Result<RconSettings, Box<dyn Error>>;
+
+    /// Connects to the RCON interface of the server, on the address of `server-ip` or localhost.
+    ///
+    /// # Errors
+    /// Returns an error if RCON is disabled or the connection fails.
+    fn connect_rcon(&self) -> Result<RconClient, Box<dyn Error>>;
+
+    /// Runs a single command over RCON and returns the answer of the server.
+    fn send_rcon_command(&self, command: &str) -> Result<String, Box<dyn Error>>;
+}
+
+fn rcon_settings(server: &Server<u64>) -> Result<RconSettings, Box<dyn Error>> {
+    let properties = server.load_properties_file()?;
+    Ok(RconSettings {
+        enabled: properties.get("enable-rcon").is_some_and(|enabled| enabled.trim() == "true"),
+        port: properties
+            .get("rcon.port")
+            .and_then(|port| port.trim().parse().ok())
+            .unwrap_or(DEFAULT_RCON_PORT),
+        password: properties.get("rcon.password").unwrap_or_default().to_string(),
+    })
+}
+
+fn connect(server: &Server<u64>) -> Result<RconClient, Box<dyn Error>> {
+    let settings = rcon_settings(server)?;
+    if !settings.enabled {
+        return Err(format!("RCON is not enabled for server {:?}", server.name).into());
+    }
+    let host = local_host(&server.load_properties_file()?);
+    RconClient::connect(SocketAddr::new(host, settings.port), &settings.password)
+}
+
+/// Runs a single command over RCON without checking what the acting user may do, for the features
+/// that check their own capability, like backups pausing the saves.
+pub(crate) fn rcon_command(server: &Server<u64>, command: &str) -> Result<String, Box<dyn Error>> {
+    connect(server)?.command(command)
+}
+
+impl ServerRcon for Server<u64> {
+    fn get_rcon_settings(&self) -> Result<RconSettings, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        rcon_settings(self)
+    }
+
+    fn enable_rcon(&self) -> Result<RconSettings, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        let mut properties = self.load_properties_file()?;
+        let mut settings = rcon_settings(self)?;
+        // The game refuses to start RCON without a password
+        if settings.password.is_empty() {
+            settings.password = generate_password();
+            properties.set("rcon.password", &settings.password)?;
+        }
+        if properties.get("rcon.port").is_none() {
+            properties.set("rcon.port", &settings.port.to_string())?;
+        }
+        properties.set("enable-rcon", "true")?;
+        self.save_properties_file(&properties)?;
+        settings.enabled = true;
+        info!("Enabled RCON for server {:?} on port {}", self.name, settings.port);
+        Ok(settings)
+    }
+
+    fn connect_rcon(&self) -> Result<RconClient, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        connect(self)
+    }
+
+    fn send_rcon_command(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        rcon_command(self, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn packets_round_trip() -> Result<(), Box<dyn Error>> {
+        let packet = encode_packet(7, PACKET_COMMAND, "list");
+        assert_eq!(packet.len(), 4 + 4 + 4 + 4 + 2);
+        assert_eq!(read_packet(&mut packet.as_slice())?, (7, PACKET_COMMAND, "list".to_string()));
+        assert_eq!(read_packet(&mut encode_packet(-1, 2, "").as_slice())?, (-1, 2, String::new()));
+        Ok(())
+    }
+
+    #[test]
+    fn packets_with_invalid_lengths_are_refused() {
+        for length in [i32::MIN, -1, 0, 9, MAX_PACKET_LENGTH as i32 + 1, i32::MAX] {
+            let mut packet = length.to_le_bytes().to_vec();
+            packet.extend_from_slice(&[0; 16]);
+            assert!(read_packet(&mut packet.as_slice()).is_err(), "{}", length);
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        let packet = encode_packet(1, PACKET_RESPONSE, "There are 0 of a max of 20 players online");
+        for length in 0..packet.len() {
+            assert!(read_packet(&mut &packet[..length]).is_err(), "{} of {} bytes", length, packet.len());
+        }
+    }
+
+    /// Answers a login and one command like the game, splitting the response in two packets.
+    fn serve(listener: TcpListener, password: &str) -> Result<(), Box<dyn Error>> {
+        let (mut stream, _) = listener.accept()?;
+        let (id, kind, body) = read_packet(&mut stream)?;
+        assert_eq!(kind, PACKET_AUTH);
+        if body != password {
+            stream.write_all(&encode_packet(-1, PACKET_COMMAND, ""))?;
+            return Ok(());
+        }
+        stream.write_all(&encode_packet(id, PACKET_COMMAND, ""))?;
+
+        let (command, _, body) = read_packet(&mut stream)?;
+        assert_eq!(body, "list");
+        let (marker, kind, _) = read_packet(&mut stream)?;
+        assert_eq!(kind, PACKET_END_MARKER);
+        stream.write_all(&encode_packet(command, PACKET_RESPONSE, "There are 0 "))?;
+        stream.write_all(&encode_packet(command, PACKET_RESPONSE, "players online"))?;
+        stream.write_all(&encode_packet(marker, PACKET_RESPONSE, "Unknown request c8"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn split_responses_are_joined() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let server = thread::spawn(move || serve(listener, "secret").map_err(|e| e.to_string()));
+        let mut client = RconClient::connect(address, "secret")?;
+        assert_eq!(client.command("list")?, "There are 0 players online");
+        server.join().map_err(|_| "The RCON server panicked")??;
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_passwords_are_rejected() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let server = thread::spawn(move || serve(listener, "secret").map_err(|e| e.to_string()));
+        assert!(RconClient::connect(address, "wrong").is_err());
+        server.join().map_err(|_| "The RCON server panicked")??;
+        Ok(())
+    }
+
+    #[test]
+    fn long_commands_are_not_sent() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let address = listener.local_addr()?;
+        let server = thread::spawn(move || -> Result<(), String> {
+            let (mut stream, _) = listener.accept().map_err(|e| e.to_string())?;
+            let (id, _, _) = read_packet(&mut stream).map_err(|e| e.to_string())?;
+            stream.write_all(&encode_packet(id, PACKET_COMMAND, "")).map_err(|e| e.to_string())
+        });
+        let mut client = RconClient::connect(address, "secret")?;
+        assert!(client.command(&"a".repeat(MAX_COMMAND_LENGTH + 1)).is_err());
+        server.join().map_err(|_| "The RCON server panicked")??;
+        Ok(())
+    }
+}
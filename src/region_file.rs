@@ -0,0 +1,786 @@
+use crate::sandboxed_path::SandboxedPath;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The size of a sector, the unit chunks are allocated in.
+pub const SECTOR_SIZE: u64 = 4096;
+
+/// The number of chunks along each axis of a region.
+pub const REGION_WIDTH: i32 = 32;
+
+/// The number of chunk slots in a region file.
+const CHUNK_SLOTS: usize = 1024;
+
+/// The location and timestamp tables at the start of every region file take up two sectors.
+const HEADER_SECTORS: u32 = 2;
+
+/// The compression of a chunk's data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkCompression {
+    Gzip,
+    Zlib,
+    Uncompressed,
+    /// LZ4, written by servers configured with `region-file-compression=lz4` since 1.20.5.
+    Lz4,
+    /// A compression provided by a mod, identified by a name stored in the chunk.
+    Custom,
+}
+
+impl ChunkCompression {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(ChunkCompression::Gzip),
+            2 => Some(ChunkCompression::Zlib),
+            3 => Some(ChunkCompression::Uncompressed),
+            4 => Some(ChunkCompression::Lz4),
+            127 => Some(ChunkCompression::Custom),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            ChunkCompression::Gzip => 1,
+            ChunkCompression::Zlib => 2,
+            ChunkCompression::Uncompressed => 3,
+            ChunkCompression::Lz4 => 4,
+            ChunkCompression::Custom => 127,
+        }
+    }
+}
+
+/// Something wrong with a chunk that keeps the game from loading it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChunkProblem {
+    /// The chunk claims sectors of the file header.
+    OverlapsHeader,
+    /// The chunk's sectors extend past the end of the file, usually after a crash while saving.
+    PastEndOfFile,
+    /// The chunk shares sectors with another chunk.
+    OverlapsChunk { x: i32, z: i32 },
+    /// The length stored at the start of the chunk does not fit into its sectors.
+    InvalidLength { length: u32 },
+    /// The compression id is not one the game knows.
+    UnknownCompression { id: u8 },
+    /// The chunk is stored in a separate `.mcc` file that is missing.
+    MissingExternalFile,
+    /// The start of the chunk could not be read from the file.
+    Unreadable { message: String },
+    /// The chunk data could not be decompressed.
+    DecompressionFailed { message: String },
+}
+
+/// A populated chunk slot of a region file.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkInfo {
+    /// The chunk's x coordinate in the world.
+    pub x: i32,
+    /// The chunk's z coordinate in the world.
+    pub z: i32,
+    /// The first sector of the chunk's data.
+    pub sector_offset: u32,
+    /// The number of sectors allocated to the chunk.
+    pub sector_count: u8,
+    /// When the game last saved the chunk.
+    pub last_modified: SystemTime,
+    /// The size of the compressed chunk data in bytes, `0` if it could not be read.
+    pub size: u64,
+    /// The compression of the chunk, `None` if it could not be read.
+    pub compression: Option<ChunkCompression>,
+    /// Whether the chunk was too large for the region file and is stored in a `.mcc` file.
+    pub external: bool,
+    /// Everything found to be wrong with the chunk, empty for healthy chunks.
+    pub problems: Vec<ChunkProblem>,
+}
+
+impl ChunkInfo {
+    /// Returns whether the chunk cannot be loaded by the game.
+    pub fn is_corrupted(&self) -> bool {
+        !self.problems.is_empty()
+    }
+}
+
+/// The result of inspecting a region file.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionInfo {
+    /// The region's x coordinate, taken from the `r.<x>.<z>.mca` file name.
+    pub region_x: i32,
+    /// The region's z coordinate, taken from the `r.<x>.<z>.mca` file name.
+    pub region_z: i32,
+    pub file_size: u64,
+    /// The populated chunks, ordered by their position in the region.
+    pub chunks: Vec<ChunkInfo>,
+    /// The number of chunks with at least one problem.
+    pub corrupted_chunks: usize,
+    /// The bytes of sectors no chunk uses, reclaimed when the game rewrites the file.
+    pub unused_bytes: u64,
+}
+
+/// A region file in the Anvil (`.mca`) or McRegion (`.mcr`) format.
+///
+/// Both formats start with a table of 1024 chunk locations followed by a table of 1024 timestamps,
+/// and store every chunk in whole 4 KiB sectors. The chunk data itself is compressed NBT.
+#[derive(Debug, Clone)]
+pub struct RegionFile {
+    path: PathBuf,
+    region_x: i32,
+    region_z: i32,
+    file_size: u64,
+    locations: Vec<u32>,
+    timestamps: Vec<u32>,
+}
+
+impl RegionFile {
+    /// Reads the header of a region file.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read or is smaller than the header. An empty file,
+    /// as left behind by the game for regions without chunks, is read as a region without chunks.
+    pub fn open(file: &SandboxedPath) -> Result<Self, Box<dyn Error>> {
+        Self::open_path(&file.path())
+    }
+
+    pub(crate) fn open_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let (region_x, region_z) = parse_region_coordinates(path)
+            .ok_or_else(|| format!("{:?} is not named like a region file (r.<x>.<z>.mca)", path))?;
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+
+        let mut header = vec![0u8; (HEADER_SECTORS as u64 * SECTOR_SIZE) as usize];
+        if file_size > 0 {
+            if file_size < header.len() as u64 {
+                return Err(format!("{:?} is truncated, its header is incomplete", path).into());
+            }
+            file.read_exact(&mut header)?;
+        }
+
+        let read_table = |table: &[u8]| -> Vec<u32> {
+            table
+                .chunks_exact(4)
+                .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect()
+        };
+        let (location_table, timestamp_table) = header.split_at(SECTOR_SIZE as usize);
+        Ok(Self {
+            path: path.to_path_buf(),
+            region_x,
+            region_z,
+            file_size,
+            locations: read_table(location_table),
+            timestamps: read_table(timestamp_table),
+        })
+    }
+
+    /// The region's coordinates.
+    pub fn coordinates(&self) -> (i32, i32) {
+        (self.region_x, self.region_z)
+    }
+
+    /// Lists the populated chunks and checks their headers for corruption.
+    ///
+    /// # Arguments
+    /// * `verify` - Whether every chunk is also decompressed, which finds corrupted chunk data but
+    ///   reads the whole file.
+    pub fn inspect(&self, verify: bool) -> RegionInfo {
+        let mut chunks: Vec<ChunkInfo> = Vec::new();
+        let mut file = File::open(&self.path).ok();
+
+        for index in 0..CHUNK_SLOTS {
+            let (sector_offset, sector_count) = self.location(index);
+            if sector_offset == 0 && sector_count == 0 {
+                continue;
+            }
+            let (x, z) = self.chunk_coordinates(index);
+            let mut chunk = ChunkInfo {
+                x,
+                z,
+                sector_offset,
+                sector_count,
+                last_modified: UNIX_EPOCH + Duration::from_secs(self.timestamps[index] as u64),
+                size: 0,
+                compression: None,
+                external: false,
+                problems: Vec::new(),
+            };
+
+            if sector_offset < HEADER_SECTORS {
+                chunk.problems.push(ChunkProblem::OverlapsHeader);
+            } else if (sector_offset as u64 + sector_count as u64) * SECTOR_SIZE > self.file_size {
+                chunk.problems.push(ChunkProblem::PastEndOfFile);
+            } else if let Some(file) = file.as_mut() {
+                self.check_chunk(file, &mut chunk, verify);
+            }
+            chunks.push(chunk);
+        }
+
+        // Sort by position in the file so overlapping chunks end up next to each other
+        let mut by_sector: Vec<usize> = (0..chunks.len()).collect();
+        by_sector.sort_by_key(|&index| chunks[index].sector_offset);
+        for pair in by_sector.windows(2) {
+            let (previous, next) = (&chunks[pair[0]], &chunks[pair[1]]);
+            if next.sector_offset < previous.sector_offset + previous.sector_count as u32 {
+                let (x, z) = (previous.x, previous.z);
+                chunks[pair[1]].problems.push(ChunkProblem::OverlapsChunk { x, z });
+            }
+        }
+
+        let used_sectors: u64 = chunks.iter().map(|chunk| chunk.sector_count as u64).sum();
+        let total_sectors = self.file_size / SECTOR_SIZE;
+        let corrupted_chunks = chunks.iter().filter(|chunk| chunk.is_corrupted()).count();
+        debug!(
+            "Inspected {:?}: {} chunks, {} corrupted",
+            self.path,
+            chunks.len(),
+            corrupted_chunks
+        );
+        RegionInfo {
+            region_x: self.region_x,
+            region_z: self.region_z,
+            file_size: self.file_size,
+            corrupted_chunks,
+            unused_bytes: total_sectors.saturating_sub(HEADER_SECTORS as u64 + used_sectors) * SECTOR_SIZE,
+            chunks,
+        }
+    }
+
+    /// Reads and decompresses a chunk, returning its uncompressed NBT data.
+    ///
+    /// # Arguments
+    /// * `x` - The chunk's x coordinate in the world, it has to lie inside this region.
+    /// * `z` - The chunk's z coordinate in the world.
+    ///
+    /// # Errors
+    /// Returns an error if the chunk lies outside of the region, is not populated, or cannot be decompressed.
+    pub fn read_chunk(&self, x: i32, z: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let index = self.chunk_index(x, z)?;
+        let (sector_offset, sector_count) = self.location(index);
+        if sector_offset == 0 && sector_count == 0 {
+            return Err(format!("Chunk {}, {} has not been generated", x, z).into());
+        }
+        if sector_offset < HEADER_SECTORS
+            || (sector_offset as u64 + sector_count as u64) * SECTOR_SIZE > self.file_size
+        {
+            return Err(format!("Chunk {}, {} points outside of the region file", x, z).into());
+        }
+
+        let mut file = File::open(&self.path)?;
+        let (compression, data) = self.read_raw_chunk(&mut file, index, sector_offset, sector_count)?;
+        decompress(compression, &data)
+    }
+
+    /// Removes a chunk from the region, so the game generates it again the next time it is loaded.
+    ///
+    /// Only the chunk's entries in the location and timestamp tables are cleared. Its sectors stay
+    /// in the file until the game rewrites the region, a `.mcc` file of the chunk is removed.
+    ///
+    /// # Returns
+    /// Whether the chunk was populated.
+    ///
+    /// # Errors
+    /// Returns an error if the chunk lies outside of the region or the header cannot be written.
+    pub fn delete_chunk(&mut self, x: i32, z: i32) -> Result<bool, Box<dyn Error>> {
+        let index = self.chunk_index(x, z)?;
+        if self.locations[index] == 0 && self.timestamps[index] == 0 {
+            return Ok(false);
+        }
+        self.write_header_entry(index, 0, 0)?;
+
+        let external = self.external_path(x, z);
+        if external.exists() {
+            fs::remove_file(&external)?;
+        }
+        debug!("Deleted chunk {}, {} from {:?}", x, z, self.path);
+        Ok(true)
+    }
+
+    /// Reads the still compressed data of a populated chunk along with its timestamp.
+    pub(crate) fn read_compressed_chunk(
+        &self,
+        x: i32,
+        z: i32,
+    ) -> Result<(ChunkCompression, Vec<u8>, u32), Box<dyn Error>> {
+        let index = self.chunk_index(x, z)?;
+        let (sector_offset, sector_count) = self.location(index);
+        if sector_offset < HEADER_SECTORS
+            || (sector_offset as u64 + sector_count as u64) * SECTOR_SIZE > self.file_size
+        {
+            return Err(format!("Chunk {}, {} has no readable data", x, z).into());
+        }
+        let mut file = File::open(&self.path)?;
+        let (compression, data) = self.read_raw_chunk(&mut file, index, sector_offset, sector_count)?;
+        Ok((compression, data, self.timestamps[index]))
+    }
+
+    /// Stores already compressed chunk data, replacing the chunk in its slot.
+    ///
+    /// The data is appended to the end of the file instead of reusing the chunk's previous sectors,
+    /// which may be shared with other chunks in a corrupted file. Chunks that do not fit into 255
+    /// sectors are stored in a `.mcc` file, like the game does.
+    pub(crate) fn write_compressed_chunk(
+        &mut self,
+        x: i32,
+        z: i32,
+        compression: ChunkCompression,
+        data: &[u8],
+        timestamp: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        let index = self.chunk_index(x, z)?;
+        let external = self.external_path(x, z);
+        let mut compression_id = compression.id();
+        let mut payload = data;
+        if data.len() as u64 + 5 > u8::MAX as u64 * SECTOR_SIZE {
+            fs::write(&external, data)?;
+            compression_id |= 0x80;
+            payload = &[];
+        } else if external.exists() {
+            fs::remove_file(&external)?;
+        }
+
+        let header_size = HEADER_SECTORS as u64 * SECTOR_SIZE;
+        let sector_offset = self.file_size.div_ceil(SECTOR_SIZE).max(HEADER_SECTORS as u64);
+        let length = payload.len() as u64 + 1;
+        let sector_count = (length + 4).div_ceil(SECTOR_SIZE);
+        let offset = u32::try_from(sector_offset)
+            .ok()
+            .filter(|offset| *offset < 1 << 24)
+            .ok_or_else(|| format!("{:?} has no room for another chunk", self.path))?;
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        if self.file_size < header_size {
+            file.set_len(header_size)?;
+        }
+        let mut sectors = Vec::with_capacity((sector_count * SECTOR_SIZE) as usize);
+        sectors.extend_from_slice(&(length as u32).to_be_bytes());
+        sectors.push(compression_id);
+        sectors.extend_from_slice(payload);
+        sectors.resize((sector_count * SECTOR_SIZE) as usize, 0);
+        file.seek(SeekFrom::Start(sector_offset * SECTOR_SIZE))?;
+        file.write_all(&sectors)?;
+        file.sync_data()?;
+        self.file_size = (sector_offset + sector_count) * SECTOR_SIZE;
+
+        // The header is only updated once the data is on disk, so a crash leaves the previous chunk in place
+        self.write_header_entry(index, (offset << 8) | sector_count as u32, timestamp)?;
+        debug!("Wrote chunk {}, {} to sector {} of {:?}", x, z, offset, self.path);
+        Ok(())
+    }
+
+    /// Rewrites the region file with only the chunks `keep` selects, packed without gaps.
+    ///
+    /// Chunks whose sectors lie outside of the file cannot be copied and are dropped as well. The new
+    /// file is written next to the old one and replaces it once complete. If no chunk is kept the
+    /// region file is removed, and the game starts a new one when the region is generated again.
+    ///
+    /// # Returns
+    /// The size of the rewritten file, `0` if it was removed.
+    pub(crate) fn retain_chunks(&mut self, keep: impl Fn(i32, i32) -> bool) -> Result<u64, Box<dyn Error>> {
+        let mut locations = vec![0u32; CHUNK_SLOTS];
+        let mut timestamps = vec![0u32; CHUNK_SLOTS];
+        let mut kept = Vec::new();
+        let mut dropped = Vec::new();
+        for index in 0..CHUNK_SLOTS {
+            let (sector_offset, sector_count) = self.location(index);
+            if sector_offset == 0 && sector_count == 0 {
+                continue;
+            }
+            let (x, z) = self.chunk_coordinates(index);
+            let readable = sector_offset >= HEADER_SECTORS
+                && (sector_offset as u64 + sector_count as u64) * SECTOR_SIZE <= self.file_size;
+            if keep(x, z) && readable {
+                kept.push(index);
+                continue;
+            }
+            if !readable {
+                warn!("Dropping chunk {}, {} of {:?}, its sectors lie outside of the file", x, z, self.path);
+            }
+            dropped.push((x, z));
+        }
+
+        if kept.is_empty() {
+            fs::remove_file(&self.path)?;
+            self.remove_external_files(&dropped)?;
+            self.locations = locations;
+            self.timestamps = timestamps;
+            self.file_size = 0;
+            info!("Removed {:?}, none of its chunks were kept", self.path);
+            return Ok(0);
+        }
+
+        let mut file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".compact");
+        let temp_path = self.path.with_file_name(file_name);
+        let result = self
+            .write_compacted(&temp_path, &kept, &mut locations, &mut timestamps)
+            .and_then(|file_size| {
+                fs::rename(&temp_path, &self.path)?;
+                Ok(file_size)
+            });
+        if result.is_err() {
+            if let Err(err) = fs::remove_file(&temp_path) {
+                debug!("Failed to remove {:?}: {}", temp_path, err);
+            }
+        }
+        let file_size = result?;
+        self.remove_external_files(&dropped)?;
+
+        debug!("Compacted {:?} from {} to {} bytes", self.path, self.file_size, file_size);
+        self.locations = locations;
+        self.timestamps = timestamps;
+        self.file_size = file_size;
+        Ok(file_size)
+    }
+
+    /// Copies the sectors of the kept chunks into a new region file, filling in their new locations.
+    fn write_compacted(
+        &self,
+        path: &Path,
+        kept: &[usize],
+        locations: &mut [u32],
+        timestamps: &mut [u32],
+    ) -> Result<u64, Box<dyn Error>> {
+        let mut source = File::open(&self.path)?;
+        let mut target = File::create(path)?;
+        let mut next_sector = HEADER_SECTORS;
+        for &index in kept {
+            let (sector_offset, sector_count) = self.location(index);
+            let mut sectors = vec![0u8; sector_count as usize * SECTOR_SIZE as usize];
+            source.seek(SeekFrom::Start(sector_offset as u64 * SECTOR_SIZE))?;
+            source.read_exact(&mut sectors)?;
+            target.seek(SeekFrom::Start(next_sector as u64 * SECTOR_SIZE))?;
+            target.write_all(&sectors)?;
+            locations[index] = (next_sector << 8) | sector_count as u32;
+            timestamps[index] = self.timestamps[index];
+            next_sector += sector_count as u32;
+        }
+
+        let mut header = Vec::with_capacity(HEADER_SECTORS as usize * SECTOR_SIZE as usize);
+        for entry in locations.iter().chain(timestamps.iter()) {
+            header.extend_from_slice(&entry.to_be_bytes());
+        }
+        target.seek(SeekFrom::Start(0))?;
+        target.write_all(&header)?;
+        target.sync_all()?;
+        Ok(next_sector as u64 * SECTOR_SIZE)
+    }
+
+    fn remove_external_files(&self, chunks: &[(i32, i32)]) -> Result<(), Box<dyn Error>> {
+        for &(x, z) in chunks {
+            let external = self.external_path(x, z);
+            if external.exists() {
+                fs::remove_file(&external)?;
+            }
+        }
+        Ok(())
+    }
+
+        fn write_header_entry(&mut self, index: usize, location: u32, timestamp: u32) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new().write(true).open(&self.path)?;
+        file.seek(SeekFrom::Start(index as u64 * 4))?;
+        file.write_all(&location.to_be_bytes())?;
+        file.seek(SeekFrom::Start(SECTOR_SIZE + index as u64 * 4))?;
+        file.write_all(&timestamp.to_be_bytes())?;
+        file.sync_data()?;
+        self.locations[index] = location;
+        self.timestamps[index] = timestamp;
+        Ok(())
+    }
+
+    /// Converts world chunk coordinates into the slot of the chunk in this region.
+    pub(crate) fn chunk_index(&self, x: i32, z: i32) -> Result<usize, Box<dyn Error>> {
+        if x.div_euclid(REGION_WIDTH) != self.region_x || z.div_euclid(REGION_WIDTH) != self.region_z {
+            return Err(format!(
+                "Chunk {}, {} is not part of region {}, {}",
+                x, z, self.region_x, self.region_z
+            )
+            .into());
+        }
+        Ok((x.rem_euclid(REGION_WIDTH) + z.rem_euclid(REGION_WIDTH) * REGION_WIDTH) as usize)
+    }
+
+    fn chunk_coordinates(&self, index: usize) -> (i32, i32) {
+        let index = index as i32;
+        (
+            self.region_x * REGION_WIDTH + index % REGION_WIDTH,
+            self.region_z * REGION_WIDTH + index / REGION_WIDTH,
+        )
+    }
+
+    /// The sector offset and sector count of a chunk slot.
+    fn location(&self, index: usize) -> (u32, u8) {
+        let location = self.locations[index];
+        (location >> 8, (location & 0xFF) as u8)
+    }
+
+    fn check_chunk(&self, file: &mut File, chunk: &mut ChunkInfo, verify: bool) {
+        let index = match self.chunk_index(chunk.x, chunk.z) {
+            Ok(index) => index,
+            Err(_) => return,
+        };
+        match self.read_chunk_header(file, chunk.sector_offset) {
+            Ok((length, compression_id)) => {
+                let external = compression_id & 0x80 != 0;
+                chunk.external = external;
+                let id = compression_id & 0x7F;
+                chunk.compression = ChunkCompression::from_id(id);
+                if chunk.compression.is_none() {
+                    chunk.problems.push(ChunkProblem::UnknownCompression { id });
+                }
+                // The length includes the compression byte, but not the length itself
+                let capacity = chunk.sector_count as u64 * SECTOR_SIZE;
+                if length == 0 || length as u64 + 4 > capacity {
+                    chunk.problems.push(ChunkProblem::InvalidLength { length });
+                    return;
+                }
+                chunk.size = if external {
+                    match fs::metadata(self.external_path(chunk.x, chunk.z)) {
+                        Ok(metadata) => metadata.len(),
+                        Err(_) => {
+                            chunk.problems.push(ChunkProblem::MissingExternalFile);
+                            return;
+                        }
+                    }
+                } else {
+                    length as u64 - 1
+                };
+            }
+            Err(err) => {
+                chunk.problems.push(ChunkProblem::Unreadable {
+                    message: err.to_string(),
+                });
+                return;
+            }
+        }
+
+        if verify && chunk.problems.is_empty() {
+            let result = self
+                .read_raw_chunk(file, index, chunk.sector_offset, chunk.sector_count)
+                .and_then(|(compression, data)| decompress(compression, &data));
+            if let Err(err) = result {
+                chunk.problems.push(ChunkProblem::DecompressionFailed {
+                    message: err.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Reads the length and compression id stored at the start of a chunk.
+    fn read_chunk_header(&self, file: &mut File, sector_offset: u32) -> Result<(u32, u8), Box<dyn Error>> {
+        file.seek(SeekFrom::Start(sector_offset as u64 * SECTOR_SIZE))?;
+        let mut header = [0u8; 5];
+        file.read_exact(&mut header)?;
+        Ok((u32::from_be_bytes([header[0], header[1], header[2], header[3]]), header[4]))
+    }
+
+    /// Reads the still compressed data of a chunk, from the region file or its `.mcc` file.
+    fn read_raw_chunk(
+        &self,
+        file: &mut File,
+        index: usize,
+        sector_offset: u32,
+        sector_count: u8,
+    ) -> Result<(ChunkCompression, Vec<u8>), Box<dyn Error>> {
+        let (x, z) = self.chunk_coordinates(index);
+        let (length, compression_id) = self.read_chunk_header(file, sector_offset)?;
+        let compression = ChunkCompression::from_id(compression_id & 0x7F)
+            .ok_or_else(|| format!("Chunk {}, {} uses the unknown compression {}", x, z, compression_id & 0x7F))?;
+        if length == 0 || length as u64 + 4 > sector_count as u64 * SECTOR_SIZE {
+            return Err(format!("Chunk {}, {} has an invalid length of {} bytes", x, z, length).into());
+        }
+
+        if compression_id & 0x80 != 0 {
+            return Ok((compression, fs::read(self.external_path(x, z))?));
+        }
+        let mut data = vec![0u8; length as usize - 1];
+        file.read_exact(&mut data)?;
+        Ok((compression, data))
+    }
+
+    fn external_path(&self, x: i32, z: i32) -> PathBuf {
+        self.path.with_file_name(format!("c.{}.{}.mcc", x, z))
+    }
+}
+
+/// Decompresses chunk data into NBT.
+fn decompress(compression: ChunkCompression, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut nbt = Vec::new();
+    match compression {
+        ChunkCompression::Gzip => {
+            GzDecoder::new(data).read_to_end(&mut nbt)?;
+        }
+        ChunkCompression::Zlib => {
+            ZlibDecoder::new(data).read_to_end(&mut nbt)?;
+        }
+        ChunkCompression::Uncompressed => nbt.extend_from_slice(data),
+        ChunkCompression::Lz4 | ChunkCompression::Custom => {
+            return Err(format!("{:?} compressed chunks are not supported", compression).into());
+        }
+    }
+    Ok(nbt)
+}
+
+/// Parses the region coordinates from a file name like `r.-1.3.mca`.
+pub(crate) fn parse_region_coordinates(path: &Path) -> Option<(i32, i32)> {
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.split('.');
+    if parts.next()? != "r" {
+        return None;
+    }
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    match parts.next()? {
+        "mca" | "mcr" if parts.next().is_none() => Some((x, z)),
+        _ => None,
+    }
+}
+
+/// Exports a chunk of a region file as an uncompressed NBT file.
+///
+/// # Arguments
+/// * `region` - The region file containing the chunk.
+/// * `x` - The chunk's x coordinate in the world.
+/// * `z` - The chunk's z coordinate in the world.
+/// * `destination` - The NBT file to write.
+///
+/// # Returns
+/// The size of the written NBT data.
+///
+/// # Errors
+/// Returns an error if the chunk cannot be read or the destination cannot be written.
+pub fn export_chunk(
+    region: &SandboxedPath,
+    x: i32,
+    z: i32,
+    destination: &SandboxedPath,
+) -> Result<u64, Box<dyn Error>> {
+    let nbt = RegionFile::open(region)?.read_chunk(x, z)?;
+    if let Some(parent) = destination.path().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(destination.path(), &nbt)?;
+    info!("Exported chunk {}, {} of {} to {}", x, z, region, destination);
+    Ok(nbt.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a region file of seven sectors, with the given chunk locations and the chunk data
+    /// starting at the given sectors.
+    fn write_region(
+        name: &str,
+        locations: &[(usize, u32, u8)],
+        sectors: &[(u32, &[u8])],
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let directory = std::env::temp_dir().join(format!("region-file-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory)?;
+        let mut contents = vec![0u8; 7 * SECTOR_SIZE as usize];
+        for (index, offset, count) in locations {
+            let location = (offset << 8) | *count as u32;
+            contents[index * 4..index * 4 + 4].copy_from_slice(&location.to_be_bytes());
+        }
+        for (sector, data) in sectors {
+            let start = *sector as usize * SECTOR_SIZE as usize;
+            contents[start..start + data.len()].copy_from_slice(data);
+        }
+        let path = directory.join("r.0.0.mca");
+        fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    fn remove_region(path: &Path) {
+        if let Some(directory) = path.parent() {
+            let _ = fs::remove_dir_all(directory);
+        }
+    }
+
+    #[test]
+    fn region_coordinates_are_parsed_from_the_file_name() {
+        assert_eq!(parse_region_coordinates(Path::new("world/region/r.-1.3.mca")), Some((-1, 3)));
+        assert_eq!(parse_region_coordinates(Path::new("r.0.0.mcr")), Some((0, 0)));
+        for name in ["r.0.mca", "r.0.0.mca.bak", "r.a.0.mca", "c.0.0.mcc", "r.0.0.dat", "r.99999999999.0.mca"] {
+            assert_eq!(parse_region_coordinates(Path::new(name)), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn truncated_headers_are_refused() -> Result<(), Box<dyn Error>> {
+        let directory = std::env::temp_dir().join(format!("region-file-truncated-{}", std::process::id()));
+        fs::create_dir_all(&directory)?;
+        let path = directory.join("r.0.0.mca");
+        fs::write(&path, vec![0u8; SECTOR_SIZE as usize + 100])?;
+        let truncated = RegionFile::open_path(&path);
+        fs::write(&path, b"")?;
+        let empty = RegionFile::open_path(&path).map(|region| region.inspect(true).chunks.len());
+        let _ = fs::remove_dir_all(&directory);
+        assert!(truncated.is_err());
+        assert_eq!(empty?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_chunks_are_reported() -> Result<(), Box<dyn Error>> {
+        let path = write_region(
+            "malformed",
+            &[(0, 1, 1), (1, 2, 1), (2, 3, 1), (3, 4, 1), (4, 5, 1), (5, 100, 2), (6, 2, 1), (7, 6, 1)],
+            &[
+                (2, &[0, 0, 0, 4, 3, b'a', b'b', b'c']),
+                (3, &[0xff, 0xff, 0xff, 0xff, 2]),
+                (4, &[0, 0, 0, 5, 9]),
+                (5, &[0, 0, 0, 10, 2, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                (6, &[0, 0, 0, 1, 0x82]),
+            ],
+        )?;
+        let result = RegionFile::open_path(&path).map(|region| (region.inspect(true), region));
+        remove_region(&path);
+        let (info, region) = result?;
+
+        let problems = |x: i32| -> Vec<ChunkProblem> {
+            info.chunks.iter().find(|chunk| chunk.x == x).map(|chunk| chunk.problems.clone()).unwrap_or_default()
+        };
+        assert_eq!(problems(0), vec![ChunkProblem::OverlapsHeader]);
+        assert!(problems(1).is_empty());
+        assert_eq!(problems(2), vec![ChunkProblem::InvalidLength { length: u32::MAX }]);
+        assert_eq!(problems(3), vec![ChunkProblem::UnknownCompression { id: 9 }]);
+        assert!(matches!(problems(4).as_slice(), [ChunkProblem::DecompressionFailed { .. }]));
+        assert_eq!(problems(5), vec![ChunkProblem::PastEndOfFile]);
+        assert_eq!(problems(6), vec![ChunkProblem::OverlapsChunk { x: 1, z: 0 }]);
+        assert_eq!(problems(7), vec![ChunkProblem::MissingExternalFile]);
+        assert_eq!(info.chunks.len(), 8);
+        assert_eq!(info.corrupted_chunks, 7);
+
+        // The file is removed, but chunks that point outside of it are refused before it is opened
+        assert!(region.read_chunk(0, 0).is_err());
+        assert!(region.read_chunk(5, 0).is_err());
+        assert!(region.read_chunk(9, 0).is_err());
+        assert!(region.read_chunk(32, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn chunks_with_invalid_lengths_are_not_read() -> Result<(), Box<dyn Error>> {
+        let path = write_region(
+            "lengths",
+            &[(1, 2, 1), (2, 3, 1), (3, 4, 1)],
+            &[(2, &[0, 0, 0, 4, 3, b'a', b'b', b'c']), (3, &[0xff, 0xff, 0xff, 0xff, 3]), (4, &[0, 0, 0, 0, 3])],
+        )?;
+        let result = RegionFile::open_path(&path)
+            .map(|region| [region.read_chunk(1, 0), region.read_chunk(2, 0), region.read_chunk(3, 0)]);
+        remove_region(&path);
+        let [valid, oversized, empty] = result?;
+        assert_eq!(valid?, b"abc");
+        assert!(oversized.is_err());
+        assert!(empty.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use crate::datapacks::read_pack_mcmeta;
+use crate::disk_quota::available_quota;
+use crate::file_download::FileDownload;
+use crate::file_hash::{hash_file, HashAlgorithm};
+use crate::file_upload::receive_upload;
+use crate::http_client::download_public_file;
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_properties::ServerProperties;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+
+/// The uploaded pack, kept in the server directory so it is backed up and deleted with the server.
+const RESOURCE_PACK_FILE: &str = "resource-pack.zip";
+
+/// The largest pack the game client accepts to download.
+const MAX_RESOURCE_PACK_SIZE: u64 = 250 * 1024 * 1024;
+
+/// Where players download the resource pack from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResourcePackHosting {
+    /// The panel serves the uploaded pack, see [`ServerResourcePack::open_hosted_resource_pack`].
+    ///
+    /// The pack is published at `<base_url>/<sha1>.zip`, so its URL changes with its contents and
+    /// clients never keep a stale copy cached.
+    Panel { base_url: String },
+    /// The pack is hosted somewhere else, it is downloaded once to compute its SHA-1.
+    External { url: String },
+}
+
+/// The client side settings of the resource pack.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourcePackOptions {
+    /// The message shown to players when they are asked to download the pack.
+    pub prompt: Option<String>,
+    /// Disconnects players who decline the pack.
+    pub required: bool,
+}
+
+impl Default for ResourcePackOptions {
+    fn default() -> Self {
+        Self {
+            prompt: None,
+            required: false,
+        }
+    }
+}
+
+/// The resource pack configured in `server.properties`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourcePack {
+    pub url: String,
+    pub sha1: Option<String>,
+    /// Whether the URL points at the pack the panel hosts.
+    pub hosted: bool,
+    /// The size of the hosted pack.
+    pub size: Option<u64>,
+    pub prompt: Option<String>,
+    pub required: bool,
+}
+
+fn hosted_url(base_url: &str, sha1: &str) -> String {
+    format!("{}/{}.zip", base_url.trim_end_matches('/'), sha1)
+}
+
+/// Reads the text of the prompt, which `server.properties` stores as a JSON text component.
+fn prompt_text(value: &str) -> Option<String> {
+    if value.trim().is_empty() {
+        return None;
+    }
+    match serde_json::from_str::<Value>(value) {
+        Ok(Value::String(text)) => Some(text),
+        Ok(component) => component["text"].as_str().map(str::to_string).or_else(|| Some(value.to_string())),
+        Err(_) => Some(value.to_string()),
+    }
+}
+
+pub trait ServerResourcePack {
+    /// Stores an uploaded resource pack and points `server.properties` at it.
+    ///
+    /// # Arguments
+    /// * `reader` - The zip of the pack, which has to contain a `pack.mcmeta`.
+    /// * `base_url` - The URL the panel serves the packs of this server under.
+    /// * `options` - The prompt and whether the pack is required.
+    ///
+    /// # Errors
+    /// Returns an error if the upload is not a resource pack or larger than the client accepts.
+    /// The previous pack is kept in that case.
+    fn upload_resource_pack(
+        &self,
+        reader: impl Read,
+        base_url: &str,
+        options: &ResourcePackOptions,
+    ) -> Result<ResourcePack, Box<dyn Error>>;
+
+    /// Points `server.properties` at a resource pack, hashing it to fill `resource-pack-sha1`.
+    ///
+    /// Players only accept the pack after the server restarts, as the properties are read on start.
+    fn set_resource_pack(
+        &self,
+        hosting: &ResourcePackHosting,
+        options: &ResourcePackOptions,
+    ) -> Result<ResourcePack, Box<dyn Error>>;
+
+    /// The resource pack players are sent, if one is configured.
+    fn get_resource_pack(&self) -> Result<Option<ResourcePack>, Box<dyn Error>>;
+
+    /// Clears the resource pack properties and deletes the hosted pack.
+    fn remove_resource_pack(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Opens the hosted pack for the HTTP handler of the panel.
+    ///
+    /// # Errors
+    /// Returns an error if no pack is hosted or `sha1` is not the one of the current pack, which
+    /// happens when a client requests an old URL.
+    fn open_hosted_resource_pack(
+        &self,
+        sha1: &str,
+        range: Option<&str>,
+        if_range: Option<&str>,
+    ) -> Result<FileDownload, Box<dyn Error>>;
+}
+
+/// The largest pack that fits both the game client and the disk quota of the server.
+fn max_pack_size(server: &Server<u64>) -> Result<u64, Box<dyn Error>> {
+    Ok(available_quota(server.id, &server.directory)?.map_or(MAX_RESOURCE_PACK_SIZE, |available| {
+        available.min(MAX_RESOURCE_PACK_SIZE)
+    }))
+}
+
+fn write_properties(
+    server: &Server<u64>,
+    url: &str,
+    sha1: &str,
+    options: &ResourcePackOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut properties = server.load_properties_file()?;
+    properties.set("resource-pack", url)?;
+    properties.set("resource-pack-sha1", sha1)?;
+    properties.set("require-resource-pack", &options.required.to_string())?;
+    // The prompt is a text component, a JSON string is the plain text form of one
+    let prompt = match &options.prompt {
+        Some(prompt) if !prompt.trim().is_empty() => serde_json::to_string(prompt)?,
+        _ => String::new(),
+    };
+    properties.set("resource-pack-prompt", &prompt)?;
+    server.save_properties_file(&properties)
+}
+
+impl ServerResourcePack for Server<u64> {
+    fn upload_resource_pack(
+        &self,
+        reader: impl Read,
+        base_url: &str,
+        options: &ResourcePackOptions,
+    ) -> Result<ResourcePack, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = SandboxedPath::new(&self.directory, RESOURCE_PACK_FILE)?;
+        // The upload lands next to the current pack, which stays in place until the new one is valid
+        let upload = SandboxedPath::new(&self.directory, format!("{}.upload", RESOURCE_PACK_FILE))?;
+        let result = receive_upload(&upload, reader, Some(max_pack_size(self)?))
+            .and_then(|_| {
+                read_pack_mcmeta(&upload.path(), true).map_err(|e| format!("Not a resource pack: {}", e).into())
+            })
+            .and_then(|_| Ok(fs::rename(upload.path(), file.path())?));
+        if let Err(e) = result {
+            let _ = fs::remove_file(upload.path());
+            return Err(e);
+        }
+        self.set_resource_pack(
+            &ResourcePackHosting::Panel {
+                base_url: base_url.to_string(),
+            },
+            options,
+        )
+    }
+
+    fn set_resource_pack(
+        &self,
+        hosting: &ResourcePackHosting,
+        options: &ResourcePackOptions,
+    ) -> Result<ResourcePack, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = SandboxedPath::new(&self.directory, RESOURCE_PACK_FILE)?;
+        let (url, sha1, size) = match hosting {
+            ResourcePackHosting::Panel { base_url } => {
+                if !file.path().is_file() {
+                    return Err("No resource pack was uploaded".into());
+                }
+                let sha1 = hash_file(&file, HashAlgorithm::Sha1)?;
+                (hosted_url(base_url, &sha1), sha1, Some(fs::metadata(file.path())?.len()))
+            }
+            ResourcePackHosting::External { url } => {
+                let download = SandboxedPath::new(&self.directory, format!("{}.download", RESOURCE_PACK_FILE))?;
+                // The URL comes from the user, so it may only point at a public web server
+                let result = download_public_file(url, &download.path(), max_pack_size(self)?)
+                    .and_then(|_| hash_file(&download, HashAlgorithm::Sha1));
+                let _ = fs::remove_file(download.path());
+                // The hosted copy is no longer served once the pack lives elsewhere
+                if file.path().exists() {
+                    fs::remove_file(file.path())?;
+                }
+                (url.clone(), result?, None)
+            }
+        };
+
+        write_properties(self, &url, &sha1, options)?;
+        info!("Set the resource pack of server {:?} to {} ({})", self.name, url, sha1);
+        Ok(ResourcePack {
+            url,
+            sha1: Some(sha1),
+            hosted: matches!(hosting, ResourcePackHosting::Panel { .. }),
+            size,
+            prompt: options.prompt.clone().filter(|prompt| !prompt.trim().is_empty()),
+            required: options.required,
+        })
+    }
+
+    fn get_resource_pack(&self) -> Result<Option<ResourcePack>, Box<dyn Error>> {
+        let properties = self.load_properties_file()?;
+        let url = properties.get("resource-pack").unwrap_or_default().trim().to_string();
+        if url.is_empty() {
+            return Ok(None);
+        }
+        let sha1 = properties
+            .get("resource-pack-sha1")
+            .map(str::to_string)
+            .filter(|sha1| !sha1.is_empty());
+        let file = SandboxedPath::new(&self.directory, RESOURCE_PACK_FILE)?;
+        let hosted = file.path().is_file()
+            && sha1
+                .as_ref()
+                .is_some_and(|sha1| url.ends_with(&format!("/{}.zip", sha1)));
+        let size = if hosted { Some(fs::metadata(file.path())?.len()) } else { None };
+        Ok(Some(ResourcePack {
+            url,
+            sha1,
+            hosted,
+            size,
+            prompt: properties.get("resource-pack-prompt").and_then(prompt_text),
+            required: properties.get("require-resource-pack") == Some("true"),
+        }))
+    }
+
+    fn remove_resource_pack(&self) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let mut properties = self.load_properties_file()?;
+        for key in ["resource-pack", "resource-pack-sha1", "resource-pack-prompt"] {
+            properties.set(key, "")?;
+        }
+        properties.set("require-resource-pack", "false")?;
+        self.save_properties_file(&properties)?;
+
+        let file = SandboxedPath::new(&self.directory, RESOURCE_PACK_FILE)?;
+        if file.path().exists() {
+            fs::remove_file(file.path())?;
+        }
+        info!("Removed the resource pack of server {:?}", self.name);
+        Ok(())
+    }
+
+    fn open_hosted_resource_pack(
+        &self,
+        sha1: &str,
+        range: Option<&str>,
+        if_range: Option<&str>,
+    ) -> Result<FileDownload, Box<dyn Error>> {
+        let pack = self
+            .get_resource_pack()?
+            .filter(|pack| pack.hosted)
+            .ok_or("The server does not host a resource pack")?;
+        if !pack.sha1.is_some_and(|current| current.eq_ignore_ascii_case(sha1)) {
+            return Err("The resource pack has been replaced".into());
+        }
+        FileDownload::open(&SandboxedPath::new(&self.directory, RESOURCE_PACK_FILE)?, range, if_range)
+    }
+}
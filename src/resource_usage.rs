@@ -0,0 +1,173 @@
+use crate::server_process::running_server_pids;
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde_derive::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// How often the processes of the servers are sampled.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many samples are kept per server, an hour at the sample interval.
+const HISTORY_LENGTH: usize = 720;
+
+static MONITOR: Once = Once::new();
+
+type ResourceListener = Box<dyn Fn(u64, &ResourceSample) + Send>;
+
+lazy_static! {
+    static ref RESOURCE_HISTORY: Mutex<HashMap<u64, VecDeque<ResourceSample>>> = Mutex::new(HashMap::new());
+    static ref RESOURCE_LISTENERS: Mutex<Vec<ResourceListener>> = Mutex::new(Vec::new());
+}
+
+/// The resources a server used at one point in time. A server started through a script counts the
+/// Java process below the script along with it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceSample {
+    /// When the sample was taken, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The CPU time used since the previous sample, in percent of one core, so a server using four
+    /// cores fully is at 400%.
+    pub cpu_percent: f32,
+    /// The same as a share of all cores of the machine, between 0 and 100.
+    pub cpu_percent_total: f32,
+    /// The resident memory in bytes.
+    pub memory_bytes: u64,
+    /// The number of threads, only known on Linux.
+    pub threads: Option<u64>,
+    /// The number of open file descriptors, only known on Linux.
+    pub open_files: Option<u64>,
+}
+
+/// Registers a listener that is invoked with every sample of every running server.
+pub fn add_resource_listener(listener: impl Fn(u64, &ResourceSample) + Send + 'static) {
+    if let Ok(mut listeners) = RESOURCE_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+/// Starts the thread sampling the resource usage of the running servers. Calling it again has no effect.
+pub fn start_resource_monitor() {
+    MONITOR.call_once(|| {
+        thread::spawn(|| {
+            let mut system = System::new();
+            let cores = thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1) as f32;
+            loop {
+                system.refresh_processes_specifics(
+                    ProcessesToUpdate::All,
+                    true,
+                    ProcessRefreshKind::new().with_cpu().with_memory(),
+                );
+                let samples: Vec<(u64, ResourceSample)> = running_server_pids()
+                    .into_iter()
+                    .filter_map(|(server_id, pid)| Some((server_id, sample_process_tree(&system, pid as u32, cores)?)))
+                    .collect();
+                match RESOURCE_LISTENERS.lock() {
+                    Ok(listeners) => {
+                        for (server_id, sample) in &samples {
+                            listeners.iter().for_each(|listener| listener(*server_id, sample));
+                        }
+                    }
+                    Err(err) => error!("Failed to notify resource listeners: {}", err),
+                }
+                if let Ok(mut history) = RESOURCE_HISTORY.lock() {
+                    for (server_id, sample) in samples {
+                        let samples = history.entry(server_id).or_default();
+                        if samples.len() == HISTORY_LENGTH {
+                            samples.pop_front();
+                        }
+                        samples.push_back(sample);
+                    }
+                }
+                thread::sleep(SAMPLE_INTERVAL);
+            }
+        });
+        info!("Started monitoring the resource usage of the servers");
+    });
+}
+
+/// Sums the usage of a process and everything it started.
+fn sample_process_tree(system: &System, root: u32, cores: f32) -> Option<ResourceSample> {
+    let root = Pid::from_u32(root);
+    system.process(root)?;
+    let mut tree = vec![root];
+    let mut index = 0;
+    while index < tree.len() {
+        let parent = tree[index];
+        tree.extend(
+            system
+                .processes()
+                .iter()
+                .filter(|(_, process)| process.parent() == Some(parent))
+                .map(|(pid, _)| *pid),
+        );
+        index += 1;
+    }
+
+    let mut sample = ResourceSample {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        cpu_percent: 0.0,
+        cpu_percent_total: 0.0,
+        memory_bytes: 0,
+        threads: None,
+        open_files: None,
+    };
+    for pid in tree {
+        let Some(process) = system.process(pid) else {
+            continue;
+        };
+        sample.cpu_percent += process.cpu_usage();
+        sample.memory_bytes += process.memory();
+        if let Some(threads) = count_threads(pid.as_u32()) {
+            sample.threads = Some(sample.threads.unwrap_or(0) + threads);
+        }
+        if let Some(open_files) = count_open_files(pid.as_u32()) {
+            sample.open_files = Some(sample.open_files.unwrap_or(0) + open_files);
+        }
+    }
+    sample.cpu_percent_total = sample.cpu_percent / cores;
+    Some(sample)
+}
+
+/// Reads the thread count of a process from `/proc/<pid>/status`.
+fn count_threads(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|threads| threads.trim().parse().ok())
+}
+
+/// Counts the entries of `/proc/<pid>/fd`, which needs the manager to run as the user of the process.
+fn count_open_files(pid: u32) -> Option<u64> {
+    Some(fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count() as u64)
+}
+
+/// The samples of a server from the last hour, oldest first. Samples of earlier runs are kept, so
+/// a graph shows the gap while the server was stopped.
+pub fn get_resource_history(server_id: u64) -> Vec<ResourceSample> {
+    RESOURCE_HISTORY
+        .lock()
+        .ok()
+        .and_then(|history| history.get(&server_id).map(|samples| samples.iter().cloned().collect()))
+        .unwrap_or_default()
+}
+
+/// The latest sample of a server, `None` if it was not running since the manager started.
+pub fn get_resource_usage(server_id: u64) -> Option<ResourceSample> {
+    RESOURCE_HISTORY
+        .lock()
+        .ok()
+        .and_then(|history| history.get(&server_id).and_then(|samples| samples.back().cloned()))
+}
+
+/// Forgets the samples of a server, such as one that was deleted.
+pub fn clear_resource_history(server_id: u64) {
+    if let Ok(mut history) = RESOURCE_HISTORY.lock() {
+        history.remove(&server_id);
+    }
+}
@@ -0,0 +1,521 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::{save_worlds, send_server_command, ServerProcess};
+use log::{error, info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to wait for `save-all flush` to finish before restarting anyway.
+const SAVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How far ahead the next restart is searched for, a schedule matching no date within it never fires.
+const MAX_LOOKAHEAD_MINUTES: u64 = 366 * 24 * 60;
+
+static SCHEDULER: Once = Once::new();
+
+/// When and how a server is restarted on a schedule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RestartSchedule {
+    pub enabled: bool,
+    /// A cron expression with the five fields `minute hour day-of-month month day-of-week`, in UTC.
+    pub cron: String,
+    /// The minutes before a restart at which the players are warned.
+    pub warning_minutes: Vec<u32>,
+    /// Whether warnings are shown as a title on the screen of every player, besides the chat.
+    pub show_title: bool,
+}
+
+impl Default for RestartSchedule {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cron: "0 4 * * *".to_string(),
+            warning_minutes: vec![15, 5, 1],
+            show_title: true,
+        }
+    }
+}
+
+/// A parsed cron expression, matched against times in UTC.
+///
+/// Besides numbers, every field accepts `*`, ranges like `1-5`, steps like `*/15` or `0-30/10`, and
+/// comma separated lists of these. Sunday is both `0` and `7` in the day-of-week field. Like cron,
+/// a day matches either field when both the day of the month and the day of the week are restricted.
+/// `@hourly`, `@daily`, `@midnight`, `@weekly` and `@monthly` are accepted as shortcuts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = Box<dyn Error>;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("Cron expression {:?} needs 5 fields, it has {}", expression, fields.len()).into());
+        };
+
+        let weekdays = parse_field(weekday, 0, 7)?;
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            // Fold the second Sunday onto the first
+            weekdays: (weekdays | (weekdays >> 7)) & 0x7f,
+            days_restricted: day != "*",
+            weekdays_restricted: weekday != "*",
+        })
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl CronSchedule {
+    /// Whether the schedule fires in the minute containing `time`.
+    pub fn matches(&self, time: SystemTime) -> bool {
+        let minutes = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        let days = minutes / (24 * 60);
+        let hour = minutes / 60 % 24;
+        let minute = minutes % 60;
+        self.matches_day(days) && has_bit(self.hours, hour) && has_bit(self.minutes, minute)
+    }
+
+    /// The first minute after `time` the schedule fires in, `None` if it does not fire within a year.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let start = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60 + 1;
+        let end = start + MAX_LOOKAHEAD_MINUTES;
+        let mut minutes = start;
+        while minutes < end {
+            if !self.matches_day(minutes / (24 * 60)) {
+                minutes = (minutes / (24 * 60) + 1) * 24 * 60;
+            } else if !has_bit(self.hours, minutes / 60 % 24) {
+                minutes = (minutes / 60 + 1) * 60;
+            } else if !has_bit(self.minutes, minutes % 60) {
+                minutes += 1;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(minutes * 60));
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, days_since_epoch: u64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+        if !has_bit(self.months, month) {
+            return false;
+        }
+        let day_matches = has_bit(self.days, day);
+        let weekday_matches = has_bit(self.weekdays, weekday);
+        if self.days_restricted && self.weekdays_restricted {
+            day_matches || weekday_matches
+        } else {
+            day_matches && weekday_matches
+        }
+    }
+}
+
+/// Parses a single field of a cron expression into a bit set of the values it matches.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, Box<dyn Error>> {
+    let mut values = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().map_err(|_| format!("Invalid step in {:?}", part))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("The step in {:?} has to be at least 1", part).into());
+        }
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((first, last)) = range.split_once('-') {
+            (parse_value(first, min, max)?, parse_value(last, min, max)?)
+        } else {
+            let value = parse_value(range, min, max)?;
+            // `5/10` means every 10th value starting at 5
+            (value, if part.contains('/') { max } else { value })
+        };
+        if first > last {
+            return Err(format!("The range {:?} is reversed", range).into());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            values |= 1 << value;
+        }
+    }
+    Ok(values)
+}
+
+fn parse_value(value: &str, min: u64, max: u64) -> Result<u64, Box<dyn Error>> {
+    match value.parse::<u64>() {
+        Ok(number) if (min..=max).contains(&number) => Ok(number),
+        _ => Err(format!("{:?} is not a number from {} to {}", value, min, max).into()),
+    }
+}
+
+fn has_bit(set: u64, value: u64) -> bool {
+    set & (1 << value) != 0
+}
+
+/// Converts days since the Unix epoch to a year, month and day of the Gregorian calendar.
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Creates the table holding the restart schedules of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_restart_schedule_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_restart_schedule` (
+            server_id INTEGER PRIMARY KEY,                              -- The server restarted on the schedule
+            enabled INTEGER NOT NULL DEFAULT 0,                         -- Whether the schedule is active
+            cron TEXT NOT NULL,                                         -- The cron expression, in UTC
+            warning_minutes TEXT NOT NULL,                              -- Comma separated minutes to warn at
+            show_title INTEGER NOT NULL DEFAULT 1                       -- Whether warnings are shown as a title
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Reads the restart schedule of a server, the disabled default schedule if none was set.
+pub fn get_restart_schedule(server_id: u64) -> Result<RestartSchedule, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_restart_schedule WHERE server_id = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    if let State::Row = statement.next()? {
+        return read_schedule(&mut statement);
+    }
+    Ok(RestartSchedule::default())
+}
+
+/// Sets the restart schedule of a server.
+///
+/// # Errors
+/// Returns an error if the cron expression is invalid or the schedule cannot be stored.
+pub fn set_restart_schedule(server_id: u64, schedule: &RestartSchedule) -> Result<(), Box<dyn Error>> {
+    CronSchedule::from_str(&schedule.cron)?;
+    let query = r#"
+INSERT INTO server_restart_schedule (server_id, enabled, cron, warning_minutes, show_title)
+VALUES (?, ?, ?, ?, ?)
+ON CONFLICT(server_id) DO UPDATE SET
+    enabled = excluded.enabled,
+    cron = excluded.cron,
+    warning_minutes = excluded.warning_minutes,
+    show_title = excluded.show_title
+"#;
+    let warning_minutes: Vec<String> = schedule.warning_minutes.iter().map(u32::to_string).collect();
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(query)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, schedule.enabled as i64))?;
+    statement.bind((3, schedule.cron.trim()))?;
+    statement.bind((4, warning_minutes.join(",").as_str()))?;
+    statement.bind((5, schedule.show_title as i64))?;
+    statement.next()?;
+    info!("Updated the restart schedule of server {}: {:?}", server_id, schedule);
+    Ok(())
+}
+
+fn read_schedule(statement: &mut sqlite::Statement) -> Result<RestartSchedule, Box<dyn Error>> {
+    Ok(RestartSchedule {
+        enabled: statement.read::<i64, _>("enabled")? != 0,
+        cron: statement.read::<String, _>("cron")?,
+        warning_minutes: statement
+            .read::<String, _>("warning_minutes")?
+            .split(',')
+            .filter_map(|minutes| minutes.trim().parse().ok())
+            .collect(),
+        show_title: statement.read::<i64, _>("show_title")? != 0,
+    })
+}
+
+/// Starts the thread restarting servers on their schedules. Calling it again has no effect.
+///
+/// Only servers that are running when their restart is due are restarted, a stopped server stays stopped.
+pub fn start_restart_scheduler() {
+    SCHEDULER.call_once(|| {
+        thread::spawn(|| loop {
+            let now = SystemTime::now();
+            let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let minute = UNIX_EPOCH + Duration::from_secs(seconds / 60 * 60);
+            if let Err(e) = run_due_schedules(minute) {
+                error!("Failed to run the restart schedules: {}", e);
+            }
+            // Wake up at the start of the next minute, so every minute is handled exactly once
+            let next_minute = minute + Duration::from_secs(60);
+            thread::sleep(next_minute.duration_since(SystemTime::now()).unwrap_or_default());
+        });
+        info!("Started the restart scheduler");
+    });
+}
+
+/// Sends the warnings and starts the restarts due in the given minute.
+fn run_due_schedules(minute: SystemTime) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM server_restart_schedule WHERE enabled = 1"#)?;
+    let mut schedules = Vec::new();
+    while let State::Row = statement.next()? {
+        schedules.push((statement.read::<i64, _>("server_id")? as u64, read_schedule(&mut statement)?));
+    }
+
+    for (server_id, schedule) in schedules {
+        let cron = match CronSchedule::from_str(&schedule.cron) {
+            Ok(cron) => cron,
+            Err(e) => {
+                warn!("Ignoring the restart schedule of server {}: {}", server_id, e);
+                continue;
+            }
+        };
+        let server = match Server::<u64>::get_server(server_id) {
+            Ok(server) if server.is_server_running() => server,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Failed to load server {} for its scheduled restart: {}", server_id, e);
+                continue;
+            }
+        };
+
+        if cron.matches(minute) {
+            thread::spawn(move || restart_on_schedule(server));
+            continue;
+        }
+        for minutes in &schedule.warning_minutes {
+            if cron.matches(minute + Duration::from_secs(*minutes as u64 * 60)) {
+                warn_players(&server, *minutes, schedule.show_title);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Announces an upcoming restart in the chat and, if enabled, on the screen of every player.
+fn warn_players(server: &Server<u64>, minutes: u32, show_title: bool) {
+    let message = match minutes {
+        0 => "The server restarts now".to_string(),
+        1 => "The server restarts in 1 minute".to_string(),
+        minutes => format!("The server restarts in {} minutes", minutes),
+    };
+    let mut commands = vec![format!("say {}", message)];
+    if show_title {
+        commands.push(format!(r#"title @a title {{"text":"{}","color":"gold"}}"#, message));
+    }
+    for command in commands {
+        if let Err(e) = send_server_command(server, command) {
+            warn!("Failed to warn the players of server {:?} about the restart: {}", server.name, e);
+        }
+    }
+}
+
+/// Saves the worlds of a server and restarts it.
+fn restart_on_schedule(mut server: Server<u64>) {
+    info!("Restarting server {:?} on its schedule", server.name);
+    warn_players(&server, 0, false);
+
+    // Wait for the save to finish, so the restart does not interrupt it
+    match save_worlds(&server, SAVE_TIMEOUT) {
+        Ok(true) => {}
+        Ok(false) => warn!("Server {:?} did not confirm saving its worlds, restarting anyway", server.name),
+        Err(e) => warn!("Failed to save server {:?} before restarting it: {}", server.name, e),
+    }
+
+    if let Err(e) = server.restart_server() {
+        error!("Failed to restart server {:?} on its schedule: {}", server.name, e);
+    }
+}
+
+pub trait ServerRestartSchedule {
+    /// Returns the schedule the server is restarted on.
+    fn get_restart_schedule(&self) -> Result<RestartSchedule, Box<dyn Error>>;
+
+    /// Sets the schedule the server is restarted on, validating its cron expression.
+    fn set_restart_schedule(&self, schedule: &RestartSchedule) -> Result<(), Box<dyn Error>>;
+
+    /// Returns when the server is restarted next, `None` if its schedule is disabled.
+    fn get_next_scheduled_restart(&self) -> Result<Option<SystemTime>, Box<dyn Error>>;
+}
+
+impl ServerRestartSchedule for Server<u64> {
+    fn get_restart_schedule(&self) -> Result<RestartSchedule, Box<dyn Error>> {
+        get_restart_schedule(self.id)
+    }
+
+    fn set_restart_schedule(&self, schedule: &RestartSchedule) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        set_restart_schedule(self.id, schedule)
+    }
+
+    fn get_next_scheduled_restart(&self) -> Result<Option<SystemTime>, Box<dyn Error>> {
+        let schedule = get_restart_schedule(self.id)?;
+        if !schedule.enabled {
+            return Ok(None);
+        }
+        Ok(CronSchedule::from_str(&schedule.cron)?.next_after(SystemTime::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const NEW_YEAR_2024: u64 = 1_704_067_200;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn parses_fields_into_bit_sets() -> Result<(), Box<dyn Error>> {
+        let schedule = CronSchedule::from_str("*/15 0-6/3 1,15 * 1-5")?;
+        assert_eq!(schedule.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(schedule.hours, 1 | 1 << 3 | 1 << 6);
+        assert_eq!(schedule.days, 1 << 1 | 1 << 15);
+        assert_eq!(schedule.months, 0b1_1111_1111_1110);
+        assert_eq!(schedule.weekdays, 0b11_1110);
+        assert!(schedule.days_restricted && schedule.weekdays_restricted);
+
+        // A single value with a step runs from that value to the end of the field
+        assert_eq!(CronSchedule::from_str("50/5 * * * *")?.minutes, 1 << 50 | 1 << 55);
+        Ok(())
+    }
+
+    #[test]
+    fn folds_both_sundays_together() -> Result<(), Box<dyn Error>> {
+        assert_eq!(CronSchedule::from_str("0 0 * * 7")?.weekdays, 1);
+        assert_eq!(CronSchedule::from_str("0 0 * * 0")?.weekdays, 1);
+        assert_eq!(CronSchedule::from_str("0 0 * * *")?.weekdays, 0x7f);
+        let next = CronSchedule::from_str("0 0 * * 7")?.next_after(at(NEW_YEAR_2024));
+        assert_eq!(next, Some(at(NEW_YEAR_2024 + 6 * 86_400)));
+        Ok(())
+    }
+
+    #[test]
+    fn expands_shortcuts() -> Result<(), Box<dyn Error>> {
+        for (shortcut, expression) in [
+            ("@hourly", "0 * * * *"),
+            ("@daily", "0 0 * * *"),
+            ("@midnight", "0 0 * * *"),
+            ("@weekly", "0 0 * * 0"),
+            ("@monthly", "0 0 1 * *"),
+        ] {
+            let expanded = CronSchedule::from_str(expression)?;
+            let schedule = CronSchedule::from_str(shortcut)?;
+            assert_eq!(schedule.to_string(), shortcut);
+            assert_eq!(CronSchedule { expression: expression.to_string(), ..schedule }, expanded);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * 32 * *",
+            "* * * 0 *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "1-2-3 * * * *",
+            ", * * * *",
+            "-1 * * * *",
+            "*/x * * * *",
+            "99999999999999999999999 * * * *",
+            "@yearly",
+        ] {
+            assert!(CronSchedule::from_str(expression).is_err(), "{:?} should be rejected", expression);
+        }
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() -> Result<(), Box<dyn Error>> {
+        let schedule = CronSchedule::from_str("0 4 * * *")?;
+        // From 05:00 the next 04:00 is the day after
+        assert_eq!(schedule.next_after(at(NEW_YEAR_2024 + 5 * 3600)), Some(at(NEW_YEAR_2024 + 86_400 + 4 * 3600)));
+        // The minute containing the time itself does not count
+        assert_eq!(schedule.next_after(at(NEW_YEAR_2024 + 4 * 3600)), Some(at(NEW_YEAR_2024 + 86_400 + 4 * 3600)));
+        assert!(schedule.matches(at(NEW_YEAR_2024 + 4 * 3600 + 59)));
+        assert!(!schedule.matches(at(NEW_YEAR_2024 + 4 * 3600 + 60)));
+        Ok(())
+    }
+
+    #[test]
+    fn matches_either_day_when_both_are_restricted() -> Result<(), Box<dyn Error>> {
+        // The 13th or any Friday, the first Friday of 2024 is the 5th
+        let schedule = CronSchedule::from_str("0 0 13 * 5")?;
+        assert_eq!(schedule.next_after(at(NEW_YEAR_2024)), Some(at(NEW_YEAR_2024 + 4 * 86_400)));
+        assert!(schedule.matches(at(NEW_YEAR_2024 + 12 * 86_400)));
+
+        // With the day of the week left open only the 13th matches
+        let schedule = CronSchedule::from_str("0 0 13 * *")?;
+        assert_eq!(schedule.next_after(at(NEW_YEAR_2024)), Some(at(NEW_YEAR_2024 + 12 * 86_400)));
+        Ok(())
+    }
+
+    #[test]
+    fn never_fires_on_impossible_dates() -> Result<(), Box<dyn Error>> {
+        assert_eq!(CronSchedule::from_str("0 0 31 2 *")?.next_after(at(NEW_YEAR_2024)), None);
+        assert_eq!(CronSchedule::from_str("0 0 30 2 *")?.next_after(at(NEW_YEAR_2024)), None);
+        Ok(())
+    }
+
+    #[test]
+    fn converts_days_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        assert_eq!(civil_from_days(10_957), (2000, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+    }
+}
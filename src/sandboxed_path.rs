@@ -0,0 +1,327 @@
+use log::{debug, warn};
+use serde::{Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::Error as IoError;
+use std::path::{Component, Path, PathBuf};
+
+/// A path that has been validated to stay inside a sandbox root directory.
+///
+/// Every user supplied path should be turned into a `SandboxedPath` before touching the file system.
+/// The requested path is always interpreted relative to the root, `..` components are not allowed
+/// to climb above the root, and symbolic links are resolved to make sure their targets do not point
+/// outside of it either.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxedPath {
+    /// The canonicalized root directory of the sandbox.
+    root: PathBuf,
+    /// The normalized path relative to the root.
+    relative: PathBuf,
+}
+
+impl SandboxedPath {
+    /// Creates a sandboxed path for a directory, pointing at the directory itself.
+    ///
+    /// # Arguments
+    /// * `root` - The directory that acts as the sandbox. It must exist.
+    ///
+    /// # Errors
+    /// Returns an error if the root directory cannot be canonicalized.
+    pub fn root(root: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Self::new(root, "")
+    }
+
+    /// Resolves a path relative to a sandbox root.
+    ///
+    /// Leading `/` characters and drive prefixes in `path` are ignored, so `/world` and `world`
+    /// resolve to the same location. The path does not need to exist yet, which allows it to be
+    /// used as the destination of write operations.
+    ///
+    /// # Arguments
+    /// * `root` - The directory that acts as the sandbox. It must exist.
+    /// * `path` - The path to resolve, relative to `root`.
+    ///
+    /// # Errors
+    /// Returns an error if the root cannot be canonicalized, or if the path (or the target of a
+    /// symbolic link along the way) lies outside of the root.
+    pub fn new(root: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let root = fs::canonicalize(root.as_ref())?;
+        let relative = normalize(path.as_ref()).ok_or_else(|| escape_error(path.as_ref()))?;
+        let sandboxed = Self { root, relative };
+        sandboxed.verify()?;
+        Ok(sandboxed)
+    }
+
+    /// Resolves a path relative to this one, staying inside the same sandbox.
+    ///
+    /// # Arguments
+    /// * `path` - The path to append. `..` components may move up, but not above the sandbox root.
+    ///
+    /// # Errors
+    /// Returns an error if the resulting path escapes the sandbox root.
+    pub fn join(&self, path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let relative = normalize(&self.relative.join(path.as_ref())).ok_or_else(|| escape_error(path.as_ref()))?;
+        let sandboxed = Self {
+            root: self.root.clone(),
+            relative,
+        };
+        sandboxed.verify()?;
+        Ok(sandboxed)
+    }
+
+    /// Returns the parent of this path, or `None` if this path is the sandbox root.
+    pub fn parent(&self) -> Option<Self> {
+        if self.is_root() {
+            return None;
+        }
+        Some(Self {
+            root: self.root.clone(),
+            relative: self.relative.parent().map(Path::to_path_buf).unwrap_or_default(),
+        })
+    }
+
+    /// Returns `true` if this path points at the sandbox root itself.
+    pub fn is_root(&self) -> bool {
+        self.relative.as_os_str().is_empty()
+    }
+
+    /// The canonicalized root directory of the sandbox.
+    pub fn root_path(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path relative to the sandbox root.
+    pub fn relative_path(&self) -> &Path {
+        &self.relative
+    }
+
+    /// The absolute path on disk.
+    pub fn path(&self) -> PathBuf {
+        if self.is_root() {
+            self.root.clone()
+        } else {
+            self.root.join(&self.relative)
+        }
+    }
+
+    /// Returns this path if nothing exists at it yet, otherwise the first free sibling named
+    /// `name (1).ext`, `name (2).ext` and so on.
+    ///
+    /// # Errors
+    /// Returns an error if a candidate path cannot be validated against the sandbox.
+    pub fn next_available(&self) -> Result<Self, Box<dyn Error>> {
+        if fs::symlink_metadata(self.path()).is_err() {
+            return Ok(self.clone());
+        }
+
+        let stem = self.relative.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let extension = self.relative.extension().map(|e| e.to_string_lossy().to_string());
+        let parent = self.parent().ok_or_else(|| escape_error(&self.relative))?;
+
+        let mut index = 0;
+        loop {
+            index += 1;
+            let name = match &extension {
+                Some(extension) => format!("{} ({}).{}", stem, index, extension),
+                None => format!("{} ({})", stem, index),
+            };
+            let candidate = parent.join(name)?;
+            if fs::symlink_metadata(candidate.path()).is_err() {
+                return Ok(candidate);
+            }
+        }
+    }
+
+    /// Checks that the path, after resolving symbolic links, is still inside the root.
+    ///
+    /// Paths that do not exist yet are checked through their closest existing ancestor,
+    /// since the remaining components cannot contain symbolic links.
+    fn verify(&self) -> Result<(), Box<dyn Error>> {
+        let mut existing = self.path();
+        while !existing.exists() {
+            // Broken symbolic links do not "exist", but they still must not point outside
+            if fs::symlink_metadata(&existing).is_ok() {
+                break;
+            }
+            match existing.parent() {
+                Some(parent) => existing = parent.to_path_buf(),
+                None => break,
+            }
+        }
+
+        let resolved = match fs::canonicalize(&existing) {
+            Ok(resolved) => resolved,
+            // A broken link cannot be canonicalized, resolve its target manually
+            Err(_) => match fs::read_link(&existing) {
+                Ok(target) => {
+                    let parent = existing.parent().and_then(|parent| fs::canonicalize(parent).ok());
+                    clean(&parent.map(|parent| parent.join(&target)).unwrap_or(target))
+                }
+                Err(err) => return Err(err.into()),
+            },
+        };
+        debug!("Sandboxed path {:?} resolved to {:?}", self.relative, resolved);
+
+        if resolved.starts_with(&self.root) {
+            Ok(())
+        } else {
+            warn!(
+                "Rejected path {:?} resolving to {:?} outside of the sandbox root {:?}",
+                self.relative, resolved, self.root
+            );
+            Err(escape_error(&self.relative))
+        }
+    }
+}
+
+impl fmt::Display for SandboxedPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.relative.display())
+    }
+}
+
+impl Serialize for SandboxedPath {
+    /// Only the relative part is serialized, so the location of the sandbox on disk is never exposed.
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.relative.serialize(serializer)
+    }
+}
+
+/// Lexically normalizes a path relative to the sandbox root.
+///
+/// Returns `None` if a `..` component would climb above the root.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            // Absolute paths are treated as relative to the sandbox root
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+    Some(normalized)
+}
+
+/// Lexically resolves `.` and `..` components of an absolute path without touching the file system.
+fn clean(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                cleaned.pop();
+            }
+            Component::CurDir => {}
+            other => cleaned.push(other.as_os_str()),
+        }
+    }
+    cleaned
+}
+
+fn escape_error(path: &Path) -> Box<dyn Error> {
+    Box::new(IoError::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!("Path {:?} is outside of the server directory", path),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory under the temporary directory, removed again when the test ends.
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Result<Self, Box<dyn Error>> {
+            let path = std::env::temp_dir().join(format!("sandboxed-path-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("root/world"))?;
+            fs::create_dir_all(path.join("outside"))?;
+            Ok(Self(path))
+        }
+
+        fn root(&self) -> PathBuf {
+            self.0.join("root")
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parent_components_cannot_climb_above_the_root() -> Result<(), Box<dyn Error>> {
+        let directory = TestDirectory::new("climb")?;
+        for path in ["..", "../outside", "world/../../outside", "world/../../../etc/passwd"] {
+            assert!(SandboxedPath::new(directory.root(), path).is_err(), "{}", path);
+        }
+        let world = SandboxedPath::new(directory.root(), "world")?;
+        assert!(world.join("../..").is_err());
+        assert!(world.join("../../outside").is_err());
+        let log = SandboxedPath::new(directory.root(), "world/../logs/./latest.log")?;
+        assert_eq!(log.relative_path(), Path::new("logs/latest.log"));
+        assert!(world.join("..")?.is_root());
+        Ok(())
+    }
+
+    #[test]
+    fn absolute_paths_are_relative_to_the_root() -> Result<(), Box<dyn Error>> {
+        let directory = TestDirectory::new("absolute")?;
+        let world = SandboxedPath::new(directory.root(), "/world")?;
+        assert_eq!(world.relative_path(), Path::new("world"));
+        assert_eq!(world.path(), fs::canonicalize(directory.root())?.join("world"));
+        assert!(SandboxedPath::new(directory.root(), "/")?.is_root());
+        assert_eq!(SandboxedPath::new(directory.root(), "/etc/passwd")?.relative_path(), Path::new("etc/passwd"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_cannot_point_outside_of_the_root() -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::symlink;
+
+        let directory = TestDirectory::new("symlinks")?;
+        let root = directory.root();
+        symlink(directory.0.join("outside"), root.join("escape"))?;
+        symlink(directory.0.join("outside/missing"), root.join("broken"))?;
+        symlink("../../outside/missing", root.join("world/relative"))?;
+        symlink("world", root.join("inside"))?;
+
+        assert!(SandboxedPath::new(&root, "escape").is_err());
+        assert!(SandboxedPath::new(&root, "escape/new-file").is_err());
+        assert!(SandboxedPath::new(&root, "broken").is_err());
+        assert!(SandboxedPath::new(&root, "world/relative").is_err());
+        assert!(SandboxedPath::root(&root)?.join("escape").is_err());
+        assert!(SandboxedPath::new(&root, "inside/level.dat").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn next_available_numbers_taken_names() -> Result<(), Box<dyn Error>> {
+        let directory = TestDirectory::new("next-available")?;
+        let root = directory.root();
+        let backup = SandboxedPath::new(&root, "world.zip")?;
+        assert_eq!(backup.next_available()?, backup);
+
+        fs::write(backup.path(), b"")?;
+        let first = backup.next_available()?;
+        assert_eq!(first.relative_path(), Path::new("world (1).zip"));
+        fs::write(first.path(), b"")?;
+        assert_eq!(backup.next_available()?.relative_path(), Path::new("world (2).zip"));
+
+        let world = SandboxedPath::new(&root, "world")?;
+        assert_eq!(world.next_available()?.relative_path(), Path::new("world (1)"));
+        Ok(())
+    }
+}
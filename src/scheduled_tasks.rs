@@ -0,0 +1,744 @@
+use crate::audit_log::acting_user;
+use crate::backups::BackupOptions;
+use crate::jobs::{get_job, start_backup_job, JobState};
+use crate::launch_templates::run_shell_command;
+use crate::online_players::ServerOnlinePlayers;
+use crate::permissions::{authorize, Capability};
+use crate::restart_schedule::{civil_from_days, CronSchedule};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_process::{send_server_command, watch_console, ServerProcess};
+use crate::server_properties::ServerProperties;
+use crate::users::get_user;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How late a run may start before it counts as missed, like when the manager was down.
+const MISSED_AFTER: Duration = Duration::from_secs(2 * 60);
+
+/// How often a task waits on the backup job it started to finish.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The length of an in-game day in ticks, of which there are 20 a second.
+const TICKS_PER_DAY: u64 = 24_000;
+
+/// How long a server gets to answer `time query daytime`.
+const TIME_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The variables a console command of a task can contain, replaced when it is sent. The date and
+/// time are in UTC.
+pub const COMMAND_VARIABLES: [&str; 6] = [
+    "online_players",
+    "max_players",
+    "player_list",
+    "server_name",
+    "date",
+    "time",
+];
+
+static SCHEDULER: Once = Once::new();
+
+lazy_static! {
+    /// The tasks that are running, so a slow run is not started again on top of itself.
+    static ref RUNNING_TASKS: Mutex<HashSet<u64>> = Mutex::new(HashSet::new());
+}
+
+/// When a task runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TaskTrigger {
+    /// A cron expression in UTC, see [`CronSchedule`].
+    Cron { expression: String },
+    /// Every so many minutes, counted from the previous run.
+    Interval { minutes: u64 },
+    /// Every in-game day at a time of day in ticks, 0 at sunrise, 6000 at noon, 13000 at nightfall
+    /// and 18000 at midnight. Only fires while the manager runs the server, and as precisely as
+    /// the server keeps 20 ticks a second.
+    GameTime { daytime: u64 },
+}
+
+impl TaskTrigger {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            TaskTrigger::Cron { expression } => CronSchedule::from_str(expression).map(|_| ()),
+            TaskTrigger::Interval { minutes: 0 } => Err("The interval of a task has to be at least a minute".into()),
+            TaskTrigger::Interval { .. } => Ok(()),
+            TaskTrigger::GameTime { daytime } if *daytime >= TICKS_PER_DAY => {
+                Err(format!("The time of day has to be below {} ticks", TICKS_PER_DAY).into())
+            }
+            TaskTrigger::GameTime { .. } => Ok(()),
+        }
+    }
+
+    /// The first time after `time` the task runs at, `None` if it never does or it depends on the
+    /// time in the game.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        match self {
+            TaskTrigger::Cron { expression } => CronSchedule::from_str(expression).ok()?.next_after(time),
+            TaskTrigger::Interval { minutes } => {
+                let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60 * 60;
+                let next = minutes.checked_mul(60)?.checked_add(seconds)?;
+                UNIX_EPOCH.checked_add(Duration::from_secs(next))
+            }
+            TaskTrigger::GameTime { .. } => None,
+        }
+    }
+}
+
+/// What a task does when it runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TaskAction {
+    /// Creates a backup, as a job so its progress can be followed.
+    Backup {
+        #[serde(default)]
+        options: BackupOptions,
+    },
+    Start,
+    Stop,
+    Restart,
+    /// Types a command into the console of the running server. Variables like `{online_players}`
+    /// are replaced first, see [`COMMAND_VARIABLES`].
+    Command { command: String },
+    /// Runs a shell command in the server directory, only administrators may schedule one.
+    Script { command: String },
+}
+
+impl TaskAction {
+    /// What the acting user needs to schedule the action.
+    pub(crate) fn capability(&self) -> Capability {
+        match self {
+            TaskAction::Backup { .. } => Capability::ManageBackups,
+            TaskAction::Start | TaskAction::Stop | TaskAction::Restart => Capability::ControlServer,
+            TaskAction::Command { .. } => Capability::SendCommands,
+            // Scripts are checked against the panel role, see `validate_task`
+            TaskAction::Script { .. } => Capability::EditFiles,
+        }
+    }
+}
+
+/// What to do about runs that were due while the manager was not running.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedRunPolicy {
+    /// Waits for the next time the task is due.
+    #[default]
+    Skip,
+    /// Runs the task once right away, however many runs were missed.
+    RunOnce,
+}
+
+impl MissedRunPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MissedRunPolicy::Skip => "skip",
+            MissedRunPolicy::RunOnce => "run_once",
+        }
+    }
+}
+
+impl FromStr for MissedRunPolicy {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(MissedRunPolicy::Skip),
+            "run_once" => Ok(MissedRunPolicy::RunOnce),
+            _ => Err(format!("Unknown missed run policy {:?}", s).into()),
+        }
+    }
+}
+
+/// How the last run of a task went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskRunStatus {
+    Running,
+    Succeeded,
+    Failed,
+    /// The run was missed and skipped by the [`MissedRunPolicy`].
+    Missed,
+}
+
+impl TaskRunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskRunStatus::Running => "running",
+            TaskRunStatus::Succeeded => "succeeded",
+            TaskRunStatus::Failed => "failed",
+            TaskRunStatus::Missed => "missed",
+        }
+    }
+}
+
+impl FromStr for TaskRunStatus {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "running" => Ok(TaskRunStatus::Running),
+            "succeeded" => Ok(TaskRunStatus::Succeeded),
+            "failed" => Ok(TaskRunStatus::Failed),
+            "missed" => Ok(TaskRunStatus::Missed),
+            _ => Err(format!("Unknown task run status {:?}", s).into()),
+        }
+    }
+}
+
+/// What a task is, as it is created or changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskDefinition {
+    pub name: String,
+    pub trigger: TaskTrigger,
+    pub action: TaskAction,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub missed_runs: MissedRunPolicy,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// A task run on a server on a schedule.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledTask {
+    pub id: u64,
+    pub server_id: u64,
+    #[serde(flatten)]
+    pub definition: TaskDefinition,
+    /// In seconds since the Unix epoch, `None` once the trigger never fires again, or for times in the game.
+    pub next_run_at: Option<u64>,
+    pub last_run_at: Option<u64>,
+    pub last_status: Option<TaskRunStatus>,
+    /// Why the last run failed, or what it did.
+    pub last_message: Option<String>,
+}
+
+fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+fn to_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the table holding the scheduled tasks of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_scheduled_task_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_scheduled_tasks` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the task
+            server_id INTEGER NOT NULL,                                 -- The server the task runs on
+            name TEXT NOT NULL,                                         -- What the task is called
+            enabled INTEGER NOT NULL DEFAULT 1,                         -- Whether the task runs
+            task_trigger TEXT NOT NULL,                                 -- When it runs, as JSON
+            task_action TEXT NOT NULL,                                  -- What it does, as JSON
+            missed_runs TEXT NOT NULL DEFAULT 'skip',                   -- What to do about missed runs
+            next_run_at INTEGER,                                        -- Unix time it runs next
+            last_run_at INTEGER,                                        -- Unix time it last ran
+            last_status TEXT,                                           -- How the last run went
+            last_message TEXT                                           -- Why it failed or what it did
+        );
+        CREATE INDEX IF NOT EXISTS `server_scheduled_tasks_server` ON `server_scheduled_tasks` (server_id);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Lists the scheduled tasks of a server.
+pub fn list_scheduled_tasks(server_id: u64) -> Result<Vec<ScheduledTask>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_scheduled_tasks WHERE server_id = ? ORDER BY id")?;
+    statement.bind((1, server_id as i64))?;
+    let mut tasks = Vec::new();
+    while let State::Row = statement.next()? {
+        tasks.push(read_task(&statement)?);
+    }
+    Ok(tasks)
+}
+
+/// Returns a scheduled task.
+///
+/// # Errors
+/// Returns an error if there is no task with the id.
+pub fn get_scheduled_task(task_id: u64) -> Result<ScheduledTask, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_scheduled_tasks WHERE id = ?")?;
+    statement.bind((1, task_id as i64))?;
+    let State::Row = statement.next()? else {
+        return Err(format!("No scheduled task with the id {}", task_id).into());
+    };
+    read_task(&statement)
+}
+
+/// Schedules a task on a server.
+///
+/// # Errors
+/// Returns an error if the trigger or action is invalid, or the acting user may not do what the
+/// task does on the server.
+pub fn create_scheduled_task(server_id: u64, definition: &TaskDefinition) -> Result<ScheduledTask, Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    validate_task(&server, definition)?;
+    let next_run_at = definition.trigger.next_after(now()).map(to_seconds);
+
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO server_scheduled_tasks
+        (server_id, name, enabled, task_trigger, task_action, missed_runs, next_run_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, definition.name.trim()))?;
+    statement.bind((3, definition.enabled as i64))?;
+    statement.bind((4, serde_json::to_string(&definition.trigger)?.as_str()))?;
+    statement.bind((5, serde_json::to_string(&definition.action)?.as_str()))?;
+    statement.bind((6, definition.missed_runs.as_str()))?;
+    statement.bind((7, next_run_at.map(|at| at as i64)))?;
+    statement.next()?;
+    let task = get_scheduled_task(last_inserted_id("server_scheduled_tasks")?)?;
+    info!("Scheduled the task {:?} on server {:?}", task.definition.name, server.name);
+    Ok(task)
+}
+
+/// Changes a scheduled task, its next run is worked out again from its trigger.
+///
+/// # Errors
+/// Returns an error if there is no such task, or the new definition is invalid or not allowed.
+pub fn update_scheduled_task(task_id: u64, definition: &TaskDefinition) -> Result<ScheduledTask, Box<dyn Error>> {
+    let task = get_scheduled_task(task_id)?;
+    let server = Server::<u64>::get_server(task.server_id)?;
+    validate_task(&server, definition)?;
+    let next_run_at = definition.trigger.next_after(now()).map(to_seconds);
+
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"UPDATE server_scheduled_tasks SET name = ?, enabled = ?, task_trigger = ?, task_action = ?,
+        missed_runs = ?, next_run_at = ? WHERE id = ?"#,
+    )?;
+    statement.bind((1, definition.name.trim()))?;
+    statement.bind((2, definition.enabled as i64))?;
+    statement.bind((3, serde_json::to_string(&definition.trigger)?.as_str()))?;
+    statement.bind((4, serde_json::to_string(&definition.action)?.as_str()))?;
+    statement.bind((5, definition.missed_runs.as_str()))?;
+    statement.bind((6, next_run_at.map(|at| at as i64)))?;
+    statement.bind((7, task_id as i64))?;
+    statement.next()?;
+    get_scheduled_task(task_id)
+}
+
+/// Turns a scheduled task on or off. A task turned on waits for the next time it is due.
+pub fn set_task_enabled(task_id: u64, enabled: bool) -> Result<ScheduledTask, Box<dyn Error>> {
+    let task = get_scheduled_task(task_id)?;
+    update_scheduled_task(
+        task_id,
+        &TaskDefinition {
+            enabled,
+            ..task.definition
+        },
+    )
+}
+
+/// Removes a scheduled task. A run in progress is not stopped.
+pub fn delete_scheduled_task(task_id: u64) -> Result<(), Box<dyn Error>> {
+    let task = get_scheduled_task(task_id)?;
+    authorize(&Server::<u64>::get_server(task.server_id)?, task.definition.action.capability())?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM server_scheduled_tasks WHERE id = ?")?;
+    statement.bind((1, task_id as i64))?;
+    statement.next()?;
+    info!("Removed the scheduled task {:?} of server {}", task.definition.name, task.server_id);
+    Ok(())
+}
+
+/// Runs a task right away in the background, besides its schedule.
+///
+/// # Errors
+/// Returns an error if there is no such task, the acting user may not run it, or it is running.
+pub fn run_task_now(task_id: u64) -> Result<(), Box<dyn Error>> {
+    let task = get_scheduled_task(task_id)?;
+    authorize(&Server::<u64>::get_server(task.server_id)?, task.definition.action.capability())?;
+    if !claim_task(task_id) {
+        return Err(format!("The task {:?} is already running", task.definition.name).into());
+    }
+    thread::spawn(move || run_task(task));
+    Ok(())
+}
+
+/// Starts the thread running the scheduled tasks when they are due. Calling it again has no effect.
+///
+/// Runs that were due while the manager was not running are skipped or run once, by the
+/// [`MissedRunPolicy`] of each task.
+pub fn start_task_scheduler() {
+    SCHEDULER.call_once(|| {
+        thread::spawn(|| loop {
+            let now = SystemTime::now();
+            let seconds = to_seconds(now);
+            let minute = UNIX_EPOCH + Duration::from_secs(seconds / 60 * 60);
+            if let Err(e) = run_due_tasks(now) {
+                error!("Failed to run the scheduled tasks: {}", e);
+            }
+            if let Err(e) = run_game_time_tasks() {
+                error!("Failed to run the scheduled tasks at times in the game: {}", e);
+            }
+            let next_minute = minute + Duration::from_secs(60);
+            thread::sleep(next_minute.duration_since(SystemTime::now()).unwrap_or_default());
+        });
+        info!("Started the task scheduler");
+    });
+}
+
+fn run_due_tasks(now: SystemTime) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        "SELECT * FROM server_scheduled_tasks WHERE enabled = 1 AND next_run_at <= ? ORDER BY next_run_at",
+    )?;
+    statement.bind((1, to_seconds(now) as i64))?;
+    let mut due = Vec::new();
+    while let State::Row = statement.next()? {
+        due.push(read_task(&statement)?);
+    }
+
+    for task in due {
+        let due_at = UNIX_EPOCH + Duration::from_secs(task.next_run_at.unwrap_or_default());
+        let next_run_at = task.definition.trigger.next_after(now).map(to_seconds);
+        let missed = now.duration_since(due_at).unwrap_or_default() > MISSED_AFTER;
+        if missed && task.definition.missed_runs == MissedRunPolicy::Skip {
+            info!("Skipping the missed run of the task {:?} of server {}", task.definition.name, task.server_id);
+            let message = format!("The run due at {} was missed", task.next_run_at.unwrap_or_default());
+            record_run(task.id, next_run_at, TaskRunStatus::Missed, Some(message))?;
+            continue;
+        }
+        if !claim_task(task.id) {
+            warn!("The task {:?} of server {} is still running, skipping a run", task.definition.name, task.server_id);
+            set_next_run(task.id, next_run_at)?;
+            continue;
+        }
+        set_next_run(task.id, next_run_at)?;
+        thread::spawn(move || run_task(task));
+    }
+    Ok(())
+}
+
+/// Starts the tasks whose time in the game comes within the next minute, after asking every
+/// running server with such a task for its time of day.
+fn run_game_time_tasks() -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        "SELECT * FROM server_scheduled_tasks WHERE enabled = 1 AND next_run_at IS NULL ORDER BY server_id",
+    )?;
+    let mut tasks = Vec::new();
+    while let State::Row = statement.next()? {
+        let task = read_task(&statement)?;
+        if let TaskTrigger::GameTime { daytime } = task.definition.trigger {
+            tasks.push((daytime, task));
+        }
+    }
+
+    let mut daytimes = HashMap::new();
+    for (daytime, task) in tasks {
+        let current = match daytimes.get(&task.server_id) {
+            Some(current) => *current,
+            None => {
+                let current = Server::<u64>::get_server(task.server_id)
+                    .ok()
+                    .filter(|server| server.is_server_running())
+                    .and_then(|server| query_daytime(&server));
+                daytimes.insert(task.server_id, current);
+                current
+            }
+        };
+        let Some(current) = current else {
+            continue;
+        };
+        // A minute is 1200 ticks, the next check catches anything later
+        let ticks = (daytime + TICKS_PER_DAY - current) % TICKS_PER_DAY;
+        if ticks >= 1200 {
+            continue;
+        }
+        // A lagging server can bring the same time into two checks, an in-game day lasts 20 minutes
+        let ran_recently = task.last_run_at.is_some_and(|at| to_seconds(now()).saturating_sub(at) < 5 * 60);
+        if ran_recently || !claim_task(task.id) {
+            continue;
+        }
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(ticks * 50));
+            run_task(task);
+        });
+    }
+    Ok(())
+}
+
+/// Asks a server the manager runs for its time of day in ticks, `None` if it does not answer.
+fn query_daytime(server: &Server<u64>) -> Option<u64> {
+    let (answer, wait_for_answer) = mpsc::channel();
+    watch_console(server.id, move |line| match line.split_once("The time is ") {
+        Some((_, time)) => {
+            let _ = answer.send(time.trim().parse::<u64>().ok());
+            false
+        }
+        None => true,
+    });
+    if let Err(e) = send_server_command(server, "time query daytime") {
+        warn!("Failed to ask server {:?} for its time of day: {}", server.name, e);
+        return None;
+    }
+    wait_for_answer.recv_timeout(TIME_QUERY_TIMEOUT).ok().flatten().map(|time| time % TICKS_PER_DAY)
+}
+
+/// Runs a task that was claimed with [`claim_task`] and records how it went.
+fn run_task(task: ScheduledTask) {
+    info!("Running the task {:?} of server {}", task.definition.name, task.server_id);
+    if let Err(e) = mark_running(task.id) {
+        warn!("Failed to record the start of the task {:?}: {}", task.definition.name, e);
+    }
+    let result = Server::<u64>::get_server(task.server_id).and_then(|server| run_action(server, &task.definition));
+    let (status, message) = match result {
+        Ok(message) => (TaskRunStatus::Succeeded, message),
+        Err(e) => {
+            warn!("The task {:?} of server {} failed: {}", task.definition.name, task.server_id, e);
+            (TaskRunStatus::Failed, Some(e.to_string()))
+        }
+    };
+    // The next run was already set when the run started, keep it
+    let next_run_at = get_scheduled_task(task.id).ok().and_then(|task| task.next_run_at);
+    if let Err(e) = record_run(task.id, next_run_at, status, message) {
+        error!("Failed to record the run of the task {:?}: {}", task.definition.name, e);
+    }
+    if let Ok(mut running) = RUNNING_TASKS.lock() {
+        running.remove(&task.id);
+    }
+}
+
+fn run_action(mut server: Server<u64>, definition: &TaskDefinition) -> Result<Option<String>, Box<dyn Error>> {
+    match &definition.action {
+        TaskAction::Backup { options } => {
+            let job = start_backup_job(&server, options.clone())?;
+            loop {
+                thread::sleep(JOB_POLL_INTERVAL);
+                let job = get_job(job.id)?;
+                match job.state {
+                    JobState::Completed => return Ok(job.log.back().cloned()),
+                    JobState::Failed => return Err(job.error.unwrap_or_default().into()),
+                    JobState::Cancelled => return Err(format!("The backup job {} was cancelled", job.id).into()),
+                    JobState::Queued | JobState::Running => {}
+                }
+            }
+        }
+        TaskAction::Start if server.is_server_running() => Ok(Some("The server was already running".to_string())),
+        TaskAction::Start => server.start_server().map(|_| None),
+        TaskAction::Stop if !server.is_server_running() => Ok(Some("The server was not running".to_string())),
+        TaskAction::Stop => server.stop_server().map(|_| None),
+        TaskAction::Restart => server.restart_server().map(|_| None),
+        TaskAction::Command { command } => {
+            let command = expand_variables(&server, command)?;
+            send_server_command(&server, &command)?;
+            Ok(Some(command))
+        }
+        TaskAction::Script { command } => {
+            run_shell_command(&server, &format!("task {:?}", definition.name), command).map(|_| None)
+        }
+    }
+}
+
+/// Fails unless a task can be scheduled on a server by the acting user.
+fn validate_task(server: &Server<u64>, definition: &TaskDefinition) -> Result<(), Box<dyn Error>> {
+    if definition.name.trim().is_empty() {
+        return Err("A task needs a name".into());
+    }
+    definition.trigger.validate()?;
+    match &definition.action {
+        TaskAction::Command { command } | TaskAction::Script { command } if command.trim().is_empty() => {
+            return Err("The command of a task cannot be empty".into());
+        }
+        TaskAction::Command { command } => {
+            if let Some(name) = variables(command).into_iter().find(|name| !COMMAND_VARIABLES.contains(name)) {
+                return Err(format!("Unknown variable {{{}}} in the command", name).into());
+            }
+        }
+        // A script can run anything the manager can, which is more than any capability on a server allows
+        TaskAction::Script { .. } => {
+            if let Some(user_id) = acting_user() {
+                if !get_user(user_id)?.is_admin {
+                    return Err("Only administrators can schedule scripts".into());
+                }
+            }
+        }
+        _ => {}
+    }
+    authorize(server, definition.action.capability())
+}
+
+/// The names of the variables in a command. Only lowercase names count, so the braces of JSON text
+/// like `tellraw @a {"text":"Hello"}` are left alone.
+pub(crate) fn variables(command: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = command;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        let length = rest.find(|c: char| !(c.is_ascii_lowercase() || c == '_')).unwrap_or(rest.len());
+        if length > 0 && rest[length..].starts_with('}') {
+            names.push(&rest[..length]);
+        }
+    }
+    names
+}
+
+/// Replaces the variables in a console command with what they stand for on a server.
+///
+/// # Errors
+/// Returns an error if the command contains an unknown variable.
+pub fn expand_variables(server: &Server<u64>, command: &str) -> Result<String, Box<dyn Error>> {
+    let names = variables(command);
+    if names.is_empty() {
+        return Ok(command.to_string());
+    }
+    let players = server.get_online_players();
+    let minutes = to_seconds(now()) / 60;
+    let (year, month, day) = civil_from_days(minutes / (24 * 60));
+    let mut expanded = command.to_string();
+    for name in names {
+        let value = match name {
+            "online_players" => players.len().to_string(),
+            "max_players" => server
+                .load_properties_file()
+                .ok()
+                .and_then(|properties| properties.get("max-players").map(str::to_string))
+                .unwrap_or_else(|| "20".to_string()),
+            "player_list" => players.iter().map(|player| player.name.as_str()).collect::<Vec<_>>().join(", "),
+            "server_name" => server.name.clone(),
+            "date" => format!("{:04}-{:02}-{:02}", year, month, day),
+            "time" => format!("{:02}:{:02}", minutes / 60 % 24, minutes % 60),
+            _ => return Err(format!("Unknown variable {{{}}}", name).into()),
+        };
+        expanded = expanded.replace(&format!("{{{}}}", name), &value);
+    }
+    Ok(expanded)
+}
+
+fn claim_task(task_id: u64) -> bool {
+    RUNNING_TASKS.lock().is_ok_and(|mut running| running.insert(task_id))
+}
+
+fn mark_running(task_id: u64) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        "UPDATE server_scheduled_tasks SET last_run_at = ?, last_status = ?, last_message = NULL WHERE id = ?",
+    )?;
+    statement.bind((1, to_seconds(now()) as i64))?;
+    statement.bind((2, TaskRunStatus::Running.as_str()))?;
+    statement.bind((3, task_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn set_next_run(task_id: u64, next_run_at: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("UPDATE server_scheduled_tasks SET next_run_at = ? WHERE id = ?")?;
+    statement.bind((1, next_run_at.map(|at| at as i64)))?;
+    statement.bind((2, task_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn record_run(
+    task_id: u64,
+    next_run_at: Option<u64>,
+    status: TaskRunStatus,
+    message: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    // The time of the run was set when it started, a missed run keeps the time of the last one that ran
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        "UPDATE server_scheduled_tasks SET next_run_at = ?, last_status = ?, last_message = ? WHERE id = ?",
+    )?;
+    statement.bind((1, next_run_at.map(|at| at as i64)))?;
+    statement.bind((2, status.as_str()))?;
+    statement.bind((3, message.as_deref()))?;
+    statement.bind((4, task_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn read_task(statement: &sqlite::Statement) -> Result<ScheduledTask, Box<dyn Error>> {
+    Ok(ScheduledTask {
+        id: statement.read::<i64, _>("id")? as u64,
+        server_id: statement.read::<i64, _>("server_id")? as u64,
+        definition: TaskDefinition {
+            name: statement.read::<String, _>("name")?,
+            trigger: serde_json::from_str(&statement.read::<String, _>("task_trigger")?)?,
+            action: serde_json::from_str(&statement.read::<String, _>("task_action")?)?,
+            enabled: statement.read::<i64, _>("enabled")? != 0,
+            missed_runs: statement.read::<String, _>("missed_runs")?.parse()?,
+        },
+        next_run_at: statement.read::<Option<i64>, _>("next_run_at")?.map(|at| at as u64),
+        last_run_at: statement.read::<Option<i64>, _>("last_run_at")?.map(|at| at as u64),
+        last_status: statement.read::<Option<String>, _>("last_status")?.map(|status| status.parse()).transpose()?,
+        last_message: statement.read::<Option<String>, _>("last_message")?,
+    })
+}
+
+pub trait ServerScheduledTasks {
+    /// Returns the tasks scheduled on the server.
+    fn list_scheduled_tasks(&self) -> Result<Vec<ScheduledTask>, Box<dyn Error>>;
+
+    /// Schedules a task on the server, validating its trigger and action.
+    fn create_scheduled_task(&self, definition: &TaskDefinition) -> Result<ScheduledTask, Box<dyn Error>>;
+}
+
+impl ServerScheduledTasks for Server<u64> {
+    fn list_scheduled_tasks(&self) -> Result<Vec<ScheduledTask>, Box<dyn Error>> {
+        list_scheduled_tasks(self.id)
+    }
+
+    fn create_scheduled_task(&self, definition: &TaskDefinition) -> Result<ScheduledTask, Box<dyn Error>> {
+        create_scheduled_task(self.id, definition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_triggers() {
+        assert!(TaskTrigger::Cron { expression: "*/5 * * * *".to_string() }.validate().is_ok());
+        assert!(TaskTrigger::Cron { expression: "* * * *".to_string() }.validate().is_err());
+        assert!(TaskTrigger::Interval { minutes: 1 }.validate().is_ok());
+        assert!(TaskTrigger::Interval { minutes: 0 }.validate().is_err());
+        assert!(TaskTrigger::GameTime { daytime: 0 }.validate().is_ok());
+        assert!(TaskTrigger::GameTime { daytime: TICKS_PER_DAY - 1 }.validate().is_ok());
+        assert!(TaskTrigger::GameTime { daytime: TICKS_PER_DAY }.validate().is_err());
+    }
+
+    #[test]
+    fn finds_the_next_run() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_704_067_200 + 90);
+        let cron = TaskTrigger::Cron { expression: "0 * * * *".to_string() };
+        assert_eq!(cron.next_after(time), Some(UNIX_EPOCH + Duration::from_secs(1_704_067_200 + 3600)));
+        // Intervals count from the start of the minute
+        let interval = TaskTrigger::Interval { minutes: 10 };
+        assert_eq!(interval.next_after(time), Some(UNIX_EPOCH + Duration::from_secs(1_704_067_200 + 660)));
+        assert_eq!(TaskTrigger::GameTime { daytime: 6000 }.next_after(time), None);
+        assert_eq!(TaskTrigger::Cron { expression: "bad".to_string() }.next_after(time), None);
+    }
+
+    #[test]
+    fn huge_intervals_never_run() {
+        assert_eq!(TaskTrigger::Interval { minutes: u64::MAX }.next_after(SystemTime::now()), None);
+        assert_eq!(TaskTrigger::Interval { minutes: u64::MAX / 60 }.next_after(SystemTime::now()), None);
+    }
+}
@@ -0,0 +1,550 @@
+use crate::backups::{add_backup_listener, BackupEventKind};
+use crate::crash_detection::add_crash_listener;
+use crate::http_client::{check_web_url, public_agent};
+use crate::notifications::{add_notification_listener, NotificationEvent};
+use crate::online_players::{add_player_listener, PlayerEventKind, ServerOnlinePlayers};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_filesystem::ServerFilesystem;
+use crate::server_process::{add_console_listener, send_server_command};
+use crate::text_file::WriteTextOptions;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use regex::Regex;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long one run of a hook may take, not counting a request or command it is waiting on.
+const MAX_RUN_TIME: Duration = Duration::from_secs(10);
+
+/// The number of operations a run may evaluate, which keeps a runaway loop from burning a core
+/// until the deadline.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// The number of HTTP requests a single run may send.
+const MAX_HTTP_REQUESTS: usize = 5;
+
+static HOOKS: Once = Once::new();
+
+lazy_static! {
+    /// The scripts running a hook, a script runs one hook at a time and drops the events in between.
+    static ref RUNNING_SCRIPTS: Mutex<HashSet<u64>> = Mutex::new(HashSet::new());
+    /// The console patterns of the enabled scripts by server, loaded on the first line of a server.
+    static ref CONSOLE_PATTERNS: Mutex<HashMap<u64, Vec<(u64, Regex)>>> = Mutex::new(HashMap::new());
+}
+
+/// An event a script can react to, by defining the function of the hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptHook {
+    /// `on_console_line(line, groups)`, for lines matching the console pattern of the script,
+    /// with the capture groups of the pattern as an array of strings.
+    ConsoleLine,
+    /// `on_player_joined(player)`.
+    PlayerJoined,
+    /// `on_player_left(player)`.
+    PlayerLeft,
+    /// `on_backup_finished(backup_id)`.
+    BackupFinished,
+    /// `on_backup_failed(backup_id, error)`.
+    BackupFailed,
+    /// `on_server_started()`, once the server accepts players.
+    ServerStarted,
+    /// `on_server_stopped()`, after an exit that was not a crash.
+    ServerStopped,
+    /// `on_crash()`.
+    Crash,
+}
+
+impl ScriptHook {
+    pub const ALL: [ScriptHook; 8] = [
+        ScriptHook::ConsoleLine,
+        ScriptHook::PlayerJoined,
+        ScriptHook::PlayerLeft,
+        ScriptHook::BackupFinished,
+        ScriptHook::BackupFailed,
+        ScriptHook::ServerStarted,
+        ScriptHook::ServerStopped,
+        ScriptHook::Crash,
+    ];
+
+    /// The name of the function a script defines to handle the hook.
+    pub fn function(&self) -> &'static str {
+        match self {
+            ScriptHook::ConsoleLine => "on_console_line",
+            ScriptHook::PlayerJoined => "on_player_joined",
+            ScriptHook::PlayerLeft => "on_player_left",
+            ScriptHook::BackupFinished => "on_backup_finished",
+            ScriptHook::BackupFailed => "on_backup_failed",
+            ScriptHook::ServerStarted => "on_server_started",
+            ScriptHook::ServerStopped => "on_server_stopped",
+            ScriptHook::Crash => "on_crash",
+        }
+    }
+
+    fn parameters(&self) -> usize {
+        match self {
+            ScriptHook::ConsoleLine | ScriptHook::BackupFailed => 2,
+            ScriptHook::PlayerJoined | ScriptHook::PlayerLeft | ScriptHook::BackupFinished => 1,
+            ScriptHook::ServerStarted | ScriptHook::ServerStopped | ScriptHook::Crash => 0,
+        }
+    }
+}
+
+/// A script as it is created or changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScriptDefinition {
+    pub name: String,
+    /// The [Rhai](https://rhai.rs) source, defining a function for each [`ScriptHook`] it handles.
+    ///
+    /// Besides the language itself a script can only reach the server it belongs to, through
+    /// `send_command(command)`, `read_file(path)`, `write_file(path, contents)`, `file_exists(path)`,
+    /// `online_players()`, `server_name()`, `http_post(url, body)` and `log(message)`. Paths are
+    /// relative to the server directory and cannot leave it. `http_post` only reaches public
+    /// addresses, not this machine or its private network.
+    pub source: String,
+    /// The regular expression console lines have to match to be passed to `on_console_line`.
+    #[serde(default)]
+    pub console_pattern: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// A script reacting to the events of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct Script {
+    pub id: u64,
+    pub server_id: u64,
+    #[serde(flatten)]
+    pub definition: ScriptDefinition,
+    /// The hooks the script defines a function for.
+    pub hooks: Vec<ScriptHook>,
+    /// In seconds since the Unix epoch.
+    pub last_run_at: Option<u64>,
+    /// Why the last run failed, `None` if it succeeded.
+    pub last_error: Option<String>,
+}
+
+fn to_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the table holding the scripts of the servers.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_script_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_scripts` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the script
+            server_id INTEGER NOT NULL,                                 -- The server the script reacts to
+            name TEXT NOT NULL,                                         -- What the script is called
+            enabled INTEGER NOT NULL DEFAULT 1,                         -- Whether the script runs
+            source TEXT NOT NULL,                                       -- The Rhai source
+            console_pattern TEXT,                                       -- The console lines it is passed
+            last_run_at INTEGER,                                        -- Unix time it last ran
+            last_error TEXT                                             -- Why it last failed
+        );
+        CREATE INDEX IF NOT EXISTS `server_scripts_server` ON `server_scripts` (server_id);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Lists the scripts of a server.
+pub fn list_scripts(server_id: u64) -> Result<Vec<Script>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_scripts WHERE server_id = ? ORDER BY id")?;
+    statement.bind((1, server_id as i64))?;
+    let mut scripts = Vec::new();
+    while let State::Row = statement.next()? {
+        scripts.push(read_script(&statement)?);
+    }
+    Ok(scripts)
+}
+
+/// Returns a script.
+///
+/// # Errors
+/// Returns an error if there is no script with the id.
+pub fn get_script(script_id: u64) -> Result<Script, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_scripts WHERE id = ?")?;
+    statement.bind((1, script_id as i64))?;
+    let State::Row = statement.next()? else {
+        return Err(format!("No script with the id {}", script_id).into());
+    };
+    read_script(&statement)
+}
+
+/// Compiles a script and returns the hooks it handles, without storing it. Useful to check a
+/// script while it is being written.
+///
+/// # Errors
+/// Returns an error if the script does not compile, handles no hook, defines a hook function with
+/// the wrong number of parameters, or handles console lines without a valid pattern.
+pub fn check_script(definition: &ScriptDefinition) -> Result<Vec<ScriptHook>, Box<dyn Error>> {
+    let ast = Engine::new().compile(&definition.source).map_err(|e| format!("The script does not compile: {}", e))?;
+    let mut hooks = Vec::new();
+    for hook in ScriptHook::ALL {
+        let Some(function) = ast.iter_functions().find(|function| function.name == hook.function()) else {
+            continue;
+        };
+        if function.params.len() != hook.parameters() {
+            return Err(format!("{} takes {} parameters", hook.function(), hook.parameters()).into());
+        }
+        hooks.push(hook);
+    }
+    if hooks.is_empty() {
+        let functions: Vec<&str> = ScriptHook::ALL.iter().map(ScriptHook::function).collect();
+        return Err(format!("The script handles none of the hooks, {}", functions.join(", ")).into());
+    }
+    match (&definition.console_pattern, hooks.contains(&ScriptHook::ConsoleLine)) {
+        (Some(pattern), _) => {
+            Regex::new(pattern).map_err(|e| format!("The console pattern is invalid: {}", e))?;
+        }
+        (None, true) => return Err("A script handling console lines needs a console pattern".into()),
+        (None, false) => {}
+    }
+    Ok(hooks)
+}
+
+/// Adds a script to a server.
+///
+/// # Errors
+/// Returns an error if the script is invalid, see [`check_script`], or the acting user may not
+/// send commands and edit files on the server.
+pub fn create_script(server_id: u64, definition: &ScriptDefinition) -> Result<Script, Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    validate_script(&server, definition)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        "INSERT INTO server_scripts (server_id, name, enabled, source, console_pattern) VALUES (?, ?, ?, ?, ?)",
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, definition.name.trim()))?;
+    statement.bind((3, definition.enabled as i64))?;
+    statement.bind((4, definition.source.as_str()))?;
+    statement.bind((5, definition.console_pattern.as_deref()))?;
+    statement.next()?;
+    forget_patterns(server_id);
+    let script = get_script(last_inserted_id("server_scripts")?)?;
+    info!("Added the script {:?} to server {:?}", script.definition.name, server.name);
+    Ok(script)
+}
+
+/// Changes a script, runs that already started finish with the old source.
+///
+/// # Errors
+/// Returns an error if there is no such script, or the new definition is invalid or not allowed.
+pub fn update_script(script_id: u64, definition: &ScriptDefinition) -> Result<Script, Box<dyn Error>> {
+    let script = get_script(script_id)?;
+    let server = Server::<u64>::get_server(script.server_id)?;
+    validate_script(&server, definition)?;
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare("UPDATE server_scripts SET name = ?, enabled = ?, source = ?, console_pattern = ? WHERE id = ?")?;
+    statement.bind((1, definition.name.trim()))?;
+    statement.bind((2, definition.enabled as i64))?;
+    statement.bind((3, definition.source.as_str()))?;
+    statement.bind((4, definition.console_pattern.as_deref()))?;
+    statement.bind((5, script_id as i64))?;
+    statement.next()?;
+    forget_patterns(script.server_id);
+    get_script(script_id)
+}
+
+/// Turns a script on or off.
+pub fn set_script_enabled(script_id: u64, enabled: bool) -> Result<Script, Box<dyn Error>> {
+    let script = get_script(script_id)?;
+    update_script(
+        script_id,
+        &ScriptDefinition {
+            enabled,
+            ..script.definition
+        },
+    )
+}
+
+/// Removes a script. A run in progress finishes.
+pub fn delete_script(script_id: u64) -> Result<(), Box<dyn Error>> {
+    let script = get_script(script_id)?;
+    let server = Server::<u64>::get_server(script.server_id)?;
+    authorize(&server, Capability::EditFiles)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM server_scripts WHERE id = ?")?;
+    statement.bind((1, script_id as i64))?;
+    statement.next()?;
+    forget_patterns(script.server_id);
+    info!("Removed the script {:?} of server {}", script.definition.name, script.server_id);
+    Ok(())
+}
+
+/// Starts passing the events of the servers to their scripts. Calling it again has no effect.
+///
+/// Every hook runs on a thread of its own, with a deadline and a limit on the work it does, so a
+/// script can slow down neither the console nor the other scripts.
+pub fn start_scripting() {
+    HOOKS.call_once(|| {
+        add_console_listener(|server_id, line| {
+            for (script_id, groups) in match_console_line(server_id, line) {
+                let args = vec![Dynamic::from(line.to_string()), Dynamic::from(groups)];
+                thread::spawn(move || run_script(script_id, ScriptHook::ConsoleLine, args));
+            }
+        });
+        add_player_listener(|event| {
+            let hook = match event.kind {
+                PlayerEventKind::Joined => ScriptHook::PlayerJoined,
+                PlayerEventKind::Left => ScriptHook::PlayerLeft,
+            };
+            dispatch(event.server_id, hook, vec![Dynamic::from(event.player.name.clone())]);
+        });
+        add_backup_listener(|event| match event.kind {
+            BackupEventKind::Completed => {
+                dispatch(event.server_id, ScriptHook::BackupFinished, vec![Dynamic::from(event.backup_id.clone())])
+            }
+            BackupEventKind::Failed => {
+                let message = event.message.clone().unwrap_or_default();
+                let args = vec![Dynamic::from(event.backup_id.clone()), Dynamic::from(message)];
+                dispatch(event.server_id, ScriptHook::BackupFailed, args)
+            }
+            _ => {}
+        });
+        add_notification_listener(|notification| {
+            let hook = match notification.event {
+                NotificationEvent::ServerStarted => ScriptHook::ServerStarted,
+                NotificationEvent::ServerStopped => ScriptHook::ServerStopped,
+                _ => return,
+            };
+            if let Some(server_id) = notification.server_id {
+                dispatch(server_id, hook, Vec::new());
+            }
+        });
+        add_crash_listener(|report| dispatch(report.server_id, ScriptHook::Crash, Vec::new()));
+        info!("Started running the script hooks");
+    });
+}
+
+/// Runs a hook of every enabled script of a server that handles it, in the background.
+fn dispatch(server_id: u64, hook: ScriptHook, args: Vec<Dynamic>) {
+    thread::spawn(move || {
+        let scripts = match list_scripts(server_id) {
+            Ok(scripts) => scripts,
+            Err(e) => {
+                error!("Failed to read the scripts of server {}: {}", server_id, e);
+                return;
+            }
+        };
+        for script in scripts {
+            if script.definition.enabled && script.hooks.contains(&hook) {
+                let args = args.clone();
+                thread::spawn(move || run_script(script.id, hook, args));
+            }
+        }
+    });
+}
+
+/// The enabled scripts of a server whose console pattern matches a line, with the capture groups.
+fn match_console_line(server_id: u64, line: &str) -> Vec<(u64, Array)> {
+    let Ok(mut patterns) = CONSOLE_PATTERNS.lock() else {
+        return Vec::new();
+    };
+    let patterns = patterns.entry(server_id).or_insert_with(|| load_patterns(server_id));
+    patterns
+        .iter()
+        .filter_map(|(script_id, pattern)| {
+            let captures = pattern.captures(line)?;
+            let groups = captures
+                .iter()
+                .skip(1)
+                .map(|group| Dynamic::from(group.map(|group| group.as_str().to_string()).unwrap_or_default()))
+                .collect();
+            Some((*script_id, groups))
+        })
+        .collect()
+}
+
+fn load_patterns(server_id: u64) -> Vec<(u64, Regex)> {
+    let scripts = list_scripts(server_id).unwrap_or_else(|e| {
+        error!("Failed to read the scripts of server {}: {}", server_id, e);
+        Vec::new()
+    });
+    scripts
+        .into_iter()
+        .filter(|script| script.definition.enabled && script.hooks.contains(&ScriptHook::ConsoleLine))
+        .filter_map(|script| Some((script.id, Regex::new(script.definition.console_pattern.as_deref()?).ok()?)))
+        .collect()
+}
+
+fn forget_patterns(server_id: u64) {
+    if let Ok(mut patterns) = CONSOLE_PATTERNS.lock() {
+        patterns.remove(&server_id);
+    }
+}
+
+/// Runs a hook of a script unless the script is busy, and records how it went.
+fn run_script(script_id: u64, hook: ScriptHook, args: Vec<Dynamic>) {
+    if !RUNNING_SCRIPTS.lock().is_ok_and(|mut running| running.insert(script_id)) {
+        debug!("The script {} is still running, dropping {:?}", script_id, hook);
+        return;
+    }
+    let result = get_script(script_id).and_then(|script| {
+        let server = Server::<u64>::get_server(script.server_id)?;
+        debug!("Running {} of the script {:?}", hook.function(), script.definition.name);
+        let engine = build_engine(&server, &script.definition.name);
+        let ast: AST = engine.compile(&script.definition.source).map_err(|e| e.to_string())?;
+        engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &ast, hook.function(), args)
+            .map_err(|e| format!("{} failed: {}", hook.function(), e))?;
+        Ok(())
+    });
+    let error = result.err().map(|e| {
+        warn!("The script {} failed: {}", script_id, e);
+        e.to_string()
+    });
+    if let Err(e) = record_run(script_id, error) {
+        error!("Failed to record the run of the script {}: {}", script_id, e);
+    }
+    if let Ok(mut running) = RUNNING_SCRIPTS.lock() {
+        running.remove(&script_id);
+    }
+}
+
+/// Creates the engine a script runs in, with the functions it may call on its server and the
+/// limits of a single run.
+fn build_engine(server: &Server<u64>, name: &str) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(1024 * 1024);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    engine.disable_symbol("eval");
+    let deadline = Instant::now() + MAX_RUN_TIME;
+    engine.on_progress(move |_| (Instant::now() > deadline).then(|| Dynamic::from("The script ran out of time")));
+
+    let prefix = format!("[script {:?} of server {:?}]", name, server.name);
+    let log_prefix = prefix.clone();
+    engine.on_print(move |message| info!("{} {}", prefix, message));
+    engine.register_fn("log", move |message: &str| info!("{} {}", log_prefix, message));
+
+    let copy = server.clone();
+    engine.register_fn("send_command", move |command: &str| -> Result<(), Box<EvalAltResult>> {
+        send_server_command(&copy, command).map_err(|e| e.to_string().into())
+    });
+    let copy = server.clone();
+    engine.register_fn("read_file", move |path: &str| -> Result<String, Box<EvalAltResult>> {
+        copy.read_text_file(path).map(|file| file.content).map_err(|e| e.to_string().into())
+    });
+    let copy = server.clone();
+    engine.register_fn("write_file", move |path: &str, contents: &str| -> Result<(), Box<EvalAltResult>> {
+        copy.write_text_file(path, contents, &WriteTextOptions::default()).map_err(|e| e.to_string().into())
+    });
+    let copy = server.clone();
+    engine.register_fn("file_exists", move |path: &str| {
+        copy.sandbox(path).is_ok_and(|file| file.path().exists())
+    });
+    let copy = server.clone();
+    engine.register_fn("online_players", move || -> Array {
+        copy.get_online_players().into_iter().map(|player| Dynamic::from(player.name)).collect()
+    });
+    let server_name = server.name.clone();
+    engine.register_fn("server_name", move || server_name.clone());
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    engine.register_fn("http_post", move |url: &str, body: Dynamic| -> Result<i64, Box<EvalAltResult>> {
+        if requests.fetch_add(1, Ordering::Relaxed) >= MAX_HTTP_REQUESTS {
+            return Err(format!("A run can send at most {} HTTP requests", MAX_HTTP_REQUESTS).into());
+        }
+        http_post(url, &body).map_err(|e| e.to_string().into())
+    });
+    engine
+}
+
+/// Posts a string as it is, or anything else as JSON, and returns the status code of the response.
+fn http_post(url: &str, body: &Dynamic) -> Result<i64, Box<dyn Error>> {
+    check_web_url(url)?;
+    // The addresses are checked once the host name is resolved, for redirects as well
+    let request = public_agent().post(url);
+    let response = match body.clone().into_string() {
+        Ok(text) => request.set("Content-Type", "text/plain; charset=utf-8").send_string(&text),
+        Err(_) => request.set("Content-Type", "application/json").send_string(&serde_json::to_string(body)?),
+    };
+    match response {
+        Ok(response) => Ok(i64::from(response.status())),
+        Err(ureq::Error::Status(status, _)) => Ok(i64::from(status)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fails unless a script can be added to a server by the acting user. Scripts send commands and
+/// write files, so they need both.
+fn validate_script(server: &Server<u64>, definition: &ScriptDefinition) -> Result<(), Box<dyn Error>> {
+    if definition.name.trim().is_empty() {
+        return Err("A script needs a name".into());
+    }
+    check_script(definition)?;
+    authorize(server, Capability::SendCommands)?;
+    authorize(server, Capability::EditFiles)
+}
+
+fn record_run(script_id: u64, error: Option<String>) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("UPDATE server_scripts SET last_run_at = ?, last_error = ? WHERE id = ?")?;
+    statement.bind((1, to_seconds(SystemTime::now()) as i64))?;
+    statement.bind((2, error.as_deref()))?;
+    statement.bind((3, script_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn read_script(statement: &sqlite::Statement) -> Result<Script, Box<dyn Error>> {
+    let definition = ScriptDefinition {
+        name: statement.read::<String, _>("name")?,
+        source: statement.read::<String, _>("source")?,
+        console_pattern: statement.read::<Option<String>, _>("console_pattern")?,
+        enabled: statement.read::<i64, _>("enabled")? != 0,
+    };
+    Ok(Script {
+        id: statement.read::<i64, _>("id")? as u64,
+        server_id: statement.read::<i64, _>("server_id")? as u64,
+        // A stored script compiled when it was saved, unless the engine changed since
+        hooks: check_script(&definition).unwrap_or_default(),
+        definition,
+        last_run_at: statement.read::<Option<i64>, _>("last_run_at")?.map(|at| at as u64),
+        last_error: statement.read::<Option<String>, _>("last_error")?,
+    })
+}
+
+pub trait ServerScripts {
+    /// Returns the scripts of the server.
+    fn list_scripts(&self) -> Result<Vec<Script>, Box<dyn Error>>;
+
+    /// Adds a script to the server, compiling it first.
+    fn create_script(&self, definition: &ScriptDefinition) -> Result<Script, Box<dyn Error>>;
+}
+
+impl ServerScripts for Server<u64> {
+    fn list_scripts(&self) -> Result<Vec<Script>, Box<dyn Error>> {
+        list_scripts(self.id)
+    }
+
+    fn create_script(&self, definition: &ScriptDefinition) -> Result<Script, Box<dyn Error>> {
+        create_script(self.id, definition)
+    }
+}
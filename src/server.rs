@@ -145,9 +145,9 @@ pub struct Server<T: Identifier> {
     pub owner: u64,
     /// A list of identifiers for each member associated with this server.
     pub members: Vec<u64>,
-    /// Specifies the minimum required RAM (in MB) for the server to function.
+    /// Specifies the minimum required RAM (in GB) for the server to function, passed as `-Xms`.
     pub min_ram: u64,
-    /// Specifies the maximum allowable RAM (in MB) for the server.
+    /// Specifies the maximum allowable RAM (in GB) for the server, passed as `-Xmx`.
     pub max_ram: u64,
     /// Indicates whether the server should automatically start when the system boots.
     pub auto_start: bool,
@@ -1,5 +1,31 @@
+use crate::api_tokens::initialize_api_token_database;
+use crate::audit_log::initialize_audit_log_database;
+use crate::automation::initialize_automation_database;
+use crate::backup_encryption::initialize_backup_encryption_database;
+use crate::backup_remotes::initialize_backup_remote_database;
+use crate::backups::initialize_backup_database;
+use crate::chunk_pregeneration::initialize_pregeneration_database;
+use crate::config::run_migrations;
+use crate::content_updates::initialize_content_rollback_database;
+use crate::crash_detection::initialize_restart_policy_database;
+use crate::disk_quota::initialize_disk_quota_database;
+use crate::email_notifications::initialize_email_target_database;
+use crate::jobs::initialize_job_database;
+use crate::metrics_history::initialize_metrics_database;
+use crate::modrinth::initialize_modrinth_database;
+use crate::paper_downloads::initialize_installed_build_database;
+use crate::permissions::initialize_permission_database;
+use crate::player_sessions::initialize_player_session_database;
+use crate::restart_schedule::initialize_restart_schedule_database;
+use crate::scheduled_tasks::initialize_scheduled_task_database;
+use crate::scripting::initialize_script_database;
 use crate::server::Server;
 use crate::server_status::ServerStatus;
+use crate::server_templates::initialize_server_template_database;
+use crate::two_factor::initialize_two_factor_database;
+use crate::users::initialize_user_database;
+use crate::webhooks::initialize_webhook_database;
+use crate::worlds::initialize_world_generation_database;
 use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
 use log::info;
 use sqlite::State;
@@ -47,6 +73,32 @@ pub fn initialize_server_database() -> Result<(), Box<dyn Error>> {
 "#;
     let conn = create_appdb_connection()?; // Establish a connection to the application database
     conn.execute(query)?; // Execute the SQL query to create the table
+    run_migrations()?; // Bring the schema up to date, moving older settings tables into the config store
+    initialize_disk_quota_database()?; // Create the table holding the disk quotas of the servers
+    initialize_restart_policy_database()?; // Create the table holding the restart policies of the servers
+    initialize_restart_schedule_database()?; // Create the table holding the restart schedules of the servers
+    initialize_installed_build_database()?; // Create the table recording the builds installed on the servers
+    initialize_modrinth_database()?; // Create the table recording the Modrinth projects installed on the servers
+    initialize_content_rollback_database()?; // Create the table remembering the jars replaced by updates
+    initialize_player_session_database()?; // Create the tables recording the sessions of the players
+    initialize_backup_database()?; // Create the table holding the backup schedules of the servers
+    initialize_backup_remote_database()?; // Create the tables holding the remote backup targets and uploads
+    initialize_backup_encryption_database()?; // Create the table holding the backup encryption keys
+    initialize_world_generation_database()?; // Create the table recording how the worlds were generated
+    initialize_pregeneration_database()?; // Create the table holding the chunk pregeneration tasks
+    initialize_metrics_database()?; // Create the table holding the downsampled metrics history
+    initialize_webhook_database()?; // Create the table holding the webhook notification targets
+    initialize_email_target_database()?; // Create the table holding the email notification targets
+    initialize_audit_log_database()?; // Create the table recording the management actions
+    initialize_user_database()?; // Create the tables holding the user accounts and their sessions
+    initialize_two_factor_database()?; // Create the table holding the two-factor secrets of the users
+    initialize_permission_database()?; // Create the table holding what the users may do on the servers
+    initialize_api_token_database()?; // Create the table holding the API tokens of the users
+    initialize_job_database()?; // Create the table holding the history of the background jobs
+    initialize_scheduled_task_database()?; // Create the table holding the scheduled tasks of the servers
+    initialize_automation_database()?; // Create the table holding the automation rules of the servers
+    initialize_script_database()?; // Create the table holding the event scripts of the servers
+    initialize_server_template_database()?; // Create the table holding the saved server templates
 
     // Check if the 'servers' directory exists, if not, create it
     if !Path::exists("servers".as_ref()) {
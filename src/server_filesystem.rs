@@ -1,6 +1,31 @@
-use crate::file_system_entry::FileSystemEntries;
+use crate::archive_builder::{create_archive, ArchiveOptions, ArchiveProgress, ArchiveSummary};
+use crate::archive_entries::ArchiveEntries;
+use crate::audit_log::{audit, summarize_text, AuditAction};
+use crate::archive_extractor::{extract_archive, ExtractionOptions, ExtractionProgress, ExtractionSummary};
+use crate::chunk_repair::{repair_chunks, ChunkRepairOptions, ChunkRepairReport};
+use crate::disk_quota::{available_quota, refresh_quota_usage};
+use crate::disk_usage::{analyze_disk_usage, DiskUsageOptions, DiskUsageReport};
+use crate::entry_stream::{EntryStream, StreamOptions};
+use crate::file_diff::{diff_files, DiffOptions, FileDiff};
+use crate::file_download::FileDownload;
+use crate::file_hash::{find_duplicates, hash_file, DuplicateGroup, HashAlgorithm};
+use crate::file_operations::{FileAction, FileOperationOptions, FileOperationProgress, FileOperationReport, FileOps};
+use crate::file_permissions::{change_mode, change_owner, ModeChange};
+use crate::file_search::{search_files, SearchOptions, SearchResults};
+use crate::file_system_entry::{calculate_directory_size, FileSystemEntries, ListingOptions};
+use crate::file_upload::receive_upload;
+use crate::file_versions::{FileVersion, VersionStore};
+use crate::file_watcher::{FileChangeEvent, FileWatcher, WatchOptions};
+use crate::permissions::{authorize, Capability};
+use crate::region_file::{export_chunk, RegionFile, RegionInfo};
+use crate::sandboxed_path::SandboxedPath;
 use crate::server::Server;
-use log::error;
+use crate::server_trash::{Trash, TRASH_DIRECTORY};
+use crate::server_status::ServerStatus;
+use crate::text_file::{read_text_file, write_text_file, TextFile, WriteTextOptions, DEFAULT_MAX_TEXT_FILE_SIZE};
+use crate::thumbnail::{Thumbnail, ThumbnailCache};
+use crate::world_trim::{trim_world, WorldTrimOptions, WorldTrimReport};
+use log::{error, warn};
 use notify::{RecursiveMode, Watcher};
 use std::error::Error;
 use std::fs;
@@ -23,6 +48,22 @@ pub trait ServerFilesystem {
     /// Calculates and returns the total size of the server's files.
     fn calculate_server_size(&mut self) -> u64;
 
+    /// Analyzes what the disk space of a directory of the server is used for.
+    ///
+    /// # Parameters
+    /// - `subpath`: The directory to analyze, relative to the server's root directory.
+    /// - `options`: The depth of the size tree and the number of largest files to report.
+    ///
+    /// # Returns
+    /// - `Ok(DiskUsageReport)` with the size tree, the largest files, and the totals for worlds,
+    ///   backups, logs and mods.
+    /// - `Err(Box<dyn Error>)` if the path is not a directory inside the server directory.
+    fn analyze_disk_usage(
+        &self,
+        subpath: impl AsRef<Path>,
+        options: &DiskUsageOptions,
+    ) -> Result<DiskUsageReport, Box<dyn Error>>;
+
     /// Removes the server directory and its contents from the file system.
     ///
     /// # Returns
@@ -30,50 +71,485 @@ pub trait ServerFilesystem {
     /// - `Err(Box<dyn Error>)` if an error occurred during the removal process.
     fn remove_server_directory(&self) -> Result<(), Box<dyn Error>>;
 
+    /// Resolves a path relative to the server's root directory, rejecting any path that
+    /// would lead outside of it.
+    ///
+    /// Every file operation on behalf of a user should go through this method.
+    ///
+    /// # Parameters
+    /// - `subpath`: The path relative to the server's root directory.
+    ///
+    /// # Returns
+    /// - `Ok(SandboxedPath)` if the path stays inside the server directory.
+    /// - `Err(Box<dyn Error>)` if the path escapes the server directory or the directory does not exist.
+    fn sandbox(&self, subpath: impl AsRef<Path>) -> Result<SandboxedPath, Box<dyn Error>>;
+
     /// Retrieves the file system entries (files and directories) within a specified subpath.
     ///
+    /// Hidden entries such as dotfiles are left out, use `get_files_paginated` with
+    /// `show_hidden` set to include them.
+    ///
     /// # Parameters
     /// - `subpath`: The path relative to the server's root directory to search for files and directories.
     ///
     /// # Returns
     /// - A `FileSystemEntries` object representing the files and directories found in the specified subpath.
-    fn get_files(&self, subpath: impl AsRef<Path>) -> FileSystemEntries;
+    /// - `Err(Box<dyn Error>)` if the subpath is outside of the server directory, or an `FsError` if it cannot
+    ///   be read. Entries that cannot be read are reported in `errors`.
+    fn get_files(&self, subpath: impl AsRef<Path>) -> Result<FileSystemEntries, Box<dyn Error>>;
+
+    /// Retrieves the file system entries within a specified subpath, with directory sizes
+    /// calculated recursively instead of being reported as `0`.
+    ///
+    /// # Parameters
+    /// - `subpath`: The path relative to the server's root directory to search for files and directories.
+    ///
+    /// # Returns
+    /// - A `FileSystemEntries` object where every directory entry reports the size of its contents.
+    /// - `Err(Box<dyn Error>)` if the subpath is outside of the server directory or cannot be read.
+    fn get_files_with_sizes(&self, subpath: impl AsRef<Path>) -> Result<FileSystemEntries, Box<dyn Error>>;
 
-    /// Retrieves the entries within a specified subpath within an archive.
+    /// Retrieves a single sorted page of the file system entries within a specified subpath.
     ///
     /// # Parameters
+    /// - `subpath`: The path relative to the server's root directory to list.
+    /// - `options`: The sort key, sort direction, offset and limit of the page, and whether hidden entries are shown.
+    ///
+    /// # Returns
+    /// - A `FileSystemEntries` object containing the requested page, along with the total
+    ///   number of entries in the directory.
+    /// - `Err(Box<dyn Error>)` if the subpath is outside of the server directory or cannot be read.
+    fn get_files_paginated(
+        &self,
+        subpath: impl AsRef<Path>,
+        options: &ListingOptions,
+    ) -> Result<FileSystemEntries, Box<dyn Error>>;
+
+    /// Retrieves the entries within a specified subpath within an archive, without extracting it.
+    ///
+    /// # Parameters
+    /// - `archive_path`: The path of the archive, relative to the server's root directory.
     /// - `subpath`: The relative path inside the archive to retrieve entries for.
     ///
     /// # Returns
-    /// - A `FileSystemEntries` object containing the files and directories found in the given subpath of the archive.
-    fn get_archive_entries(&self, subpath: impl AsRef<Path>) -> FileSystemEntries;
+    /// - An `ArchiveEntries` object containing the files and directories found in the given subpath of the archive.
+    /// - `Err(Box<dyn Error>)` if the archive is outside of the server directory, unsupported, or unreadable.
+    fn get_archive_entries(
+        &self,
+        archive_path: impl AsRef<Path>,
+        subpath: impl AsRef<Path>,
+    ) -> Result<ArchiveEntries, Box<dyn Error>>;
+
+    /// Streams the entries of a directory without blocking the async runtime.
+    ///
+    /// Has to be called from within a tokio runtime.
+    ///
+    /// # Parameters
+    /// - `subpath`: The directory to list, relative to the server's root directory.
+    /// - `options`: The concurrency, and whether content sniffing is skipped.
+    ///
+    /// # Returns
+    /// - `Ok(EntryStream)` delivering the entries, their paths relative to the server's root directory.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory.
+    fn stream_files(&self, subpath: impl AsRef<Path>, options: &StreamOptions) -> Result<EntryStream, Box<dyn Error>>;
 
     /// Archives the specified file system paths into a single archive file.
     ///
     /// # Parameters
-    /// - `subpaths`: A vector of paths to include in the archive.
+    /// - `subpaths`: A vector of paths to include in the archive, relative to the server's root directory.
     /// - `archive_path`: The destination path where the archive file will be created.
+    /// - `options`: The archive format and compression level.
+    /// - `on_progress`: A callback invoked after every added entry.
     ///
     /// # Returns
-    /// - `Ok(())` if the paths were successfully archived.
-    /// - `Err(Box<dyn Error>)` if an error occurred.
-    fn archive_paths(&self, subpaths: Vec<PathBuf>, archive_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>>;
+    /// - `Ok(ArchiveSummary)` with the number of entries and bytes archived.
+    /// - `Err(Box<dyn Error>)` if an error occurred, or if the archive would exceed the server's disk quota,
+    ///   in which case it is removed again.
+    fn archive_paths(
+        &self,
+        subpaths: Vec<PathBuf>,
+        archive_path: impl AsRef<Path>,
+        options: &ArchiveOptions,
+        on_progress: impl FnMut(&ArchiveProgress),
+    ) -> Result<ArchiveSummary, Box<dyn Error>>;
 
     /// Extracts the contents of an archive into the specified destination directory.
     ///
     /// # Parameters
-    /// - `archive_path`: The path to the archive to be extracted.
-    /// - `destination_path`: The path to the directory where the contents will be extracted.
+    /// - `archive_path`: The path to the archive to be extracted, relative to the server's root directory.
+    /// - `destination_path`: The path to the directory where the contents will be extracted,
+    ///   relative to the server's root directory.
+    /// - `options`: The policy for files that already exist at the destination.
+    /// - `on_progress`: A callback invoked after every extracted entry.
     ///
     /// # Returns
-    /// - `Ok(())` if the archive was successfully extracted.
-    /// - `Err(Box<dyn Error>)` if an error occurred during extraction.
+    /// - `Ok(ExtractionSummary)` describing what was extracted, skipped, or renamed.
+    /// - `Err(Box<dyn Error>)` if an error occurred during extraction, or if an entry of the
+    ///   archive would be written outside of the destination.
     fn extract_archive(
         &self,
         archive_path: impl AsRef<Path>,
         destination_path: impl AsRef<Path>,
+        options: &ExtractionOptions,
+        on_progress: impl FnMut(&ExtractionProgress),
+    ) -> Result<ExtractionSummary, Box<dyn Error>>;
+
+    /// Copies a file or directory inside the server directory.
+    ///
+    /// # Parameters
+    /// - `source`: The file or directory to copy, relative to the server's root directory.
+    /// - `destination`: The full path of the copy, relative to the server's root directory.
+    /// - `options`: Whether this is a dry run, and how to handle an existing destination.
+    /// - `on_progress`: A callback invoked after every copied file or created directory.
+    ///
+    /// # Returns
+    /// - `Ok(FileOperationReport)` listing the steps that were (or would be) performed.
+    /// - `Err(Box<dyn Error>)` if a path is outside of the server directory or the copy fails.
+    fn copy_path(
+        &self,
+        source: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+        options: FileOperationOptions,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>>;
+
+    /// Moves a file or directory inside the server directory.
+    ///
+    /// # Parameters
+    /// - `source`: The file or directory to move, relative to the server's root directory.
+    /// - `destination`: The full new path, relative to the server's root directory.
+    /// - `options`: Whether this is a dry run, and how to handle an existing destination.
+    /// - `on_progress`: A callback invoked after every step.
+    ///
+    /// # Returns
+    /// - `Ok(FileOperationReport)` listing the steps that were (or would be) performed.
+    /// - `Err(Box<dyn Error>)` if a path is outside of the server directory or the move fails.
+    fn move_path(
+        &self,
+        source: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+        options: FileOperationOptions,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>>;
+
+    /// Renames a file or directory within its current directory.
+    ///
+    /// # Parameters
+    /// - `path`: The file or directory to rename, relative to the server's root directory.
+    /// - `new_name`: The new file name, without any path separators.
+    /// - `options`: Whether this is a dry run, and how to handle an existing file with the new name.
+    ///
+    /// # Returns
+    /// - `Ok(FileOperationReport)` listing the steps that were (or would be) performed.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory or the rename fails.
+    fn rename_path(
+        &self,
+        path: impl AsRef<Path>,
+        new_name: &str,
+        options: FileOperationOptions,
+    ) -> Result<FileOperationReport, Box<dyn Error>>;
+
+    /// Deletes a file or directory inside the server directory by moving it to the trash, where it
+    /// can be restored from. Paths inside the trash are deleted permanently.
+    ///
+    /// # Parameters
+    /// - `path`: The file or directory to delete, relative to the server's root directory.
+    /// - `options`: Whether this is a dry run.
+    /// - `on_progress`: A callback invoked after every removed file or directory.
+    ///
+    /// # Returns
+    /// - `Ok(FileOperationReport)` listing the steps that were (or would be) performed.
+    /// - `Err(Box<dyn Error>)` if the path is the server directory or outside of it, or the deletion fails.
+    fn delete_path(
+        &self,
+        path: impl AsRef<Path>,
+        options: FileOperationOptions,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>>;
+
+    /// Opens a file for streaming download, honoring HTTP `Range` requests.
+    ///
+    /// # Parameters
+    /// - `subpath`: The file to download, relative to the server's root directory.
+    /// - `range`: The value of the `Range` request header, if present.
+    /// - `if_range`: The value of the `If-Range` request header, if present.
+    ///
+    /// # Returns
+    /// - `Ok(FileDownload)` with the status, headers and body of the response.
+    /// - `Err(Box<dyn Error>)` if the file is outside of the server directory, is a directory, or cannot be opened.
+    fn download_file(
+        &self,
+        subpath: impl AsRef<Path>,
+        range: Option<&str>,
+        if_range: Option<&str>,
+    ) -> Result<FileDownload, Box<dyn Error>>;
+
+    /// Writes an uploaded file to the server, within the server's disk quota.
+    ///
+    /// # Parameters
+    /// - `subpath`: The file to create or replace, relative to the server's root directory.
+    /// - `reader`: The body of the upload.
+    /// - `declared_size`: The size announced by the client, for example the `Content-Length` header.
+    ///   Uploads announced larger than the available space are refused before anything is written.
+    ///
+    /// # Returns
+    /// - `Ok(u64)` with the number of bytes written.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory, the upload would
+    ///   exceed the disk quota, or writing fails.
+    fn upload_file(
+        &self,
+        subpath: impl AsRef<Path>,
+        reader: impl Read,
+        declared_size: Option<u64>,
+    ) -> Result<u64, Box<dyn Error>>;
+
+    /// Recursively searches a directory of the server by file name and, optionally, file contents.
+    ///
+    /// # Parameters
+    /// - `subpath`: The directory to search, relative to the server's root directory.
+    /// - `options`: The name and content patterns along with the search limits.
+    ///
+    /// # Returns
+    /// - `Ok(SearchResults)` with the matching entries, their paths relative to the server's root directory.
+    /// - `Err(Box<dyn Error>)` if the directory is outside of the server directory or a pattern is invalid.
+    fn search_files(&self, subpath: impl AsRef<Path>, options: &SearchOptions) -> Result<SearchResults, Box<dyn Error>>;
+
+    /// Reads a text file of the server for editing, detecting its encoding and line endings.
+    ///
+    /// # Parameters
+    /// - `subpath`: The file to read, relative to the server's root directory.
+    ///
+    /// # Returns
+    /// - `Ok(TextFile)` with the contents normalized to `\n` line endings.
+    /// - `Err(Box<dyn Error>)` if the file is outside of the server directory, larger than
+    ///   `DEFAULT_MAX_TEXT_FILE_SIZE`, binary, or cannot be read.
+    fn read_text_file(&self, subpath: impl AsRef<Path>) -> Result<TextFile, Box<dyn Error>>;
+
+    /// Computes the line diff between two files of the server.
+    ///
+    /// # Parameters
+    /// - `old_path`: The original file, relative to the server's root directory.
+    /// - `new_path`: The changed file, relative to the server's root directory.
+    /// - `options`: The number of context lines and the file size limit.
+    ///
+    /// # Returns
+    /// - `Ok(FileDiff)` with the hunks and a unified diff, or only a hash comparison for binary files.
+    /// - `Err(Box<dyn Error>)` if a file is outside of the server directory or cannot be read.
+    fn diff_files(
+        &self,
+        old_path: impl AsRef<Path>,
+        new_path: impl AsRef<Path>,
+        options: &DiffOptions,
+    ) -> Result<FileDiff, Box<dyn Error>>;
+
+    /// Lists the stored previous versions of a file of the server, newest first.
+    ///
+    /// Versions are stored whenever the manager overwrites a file, for example through
+    /// `write_text_file` or the properties editor.
+    ///
+    /// # Parameters
+    /// - `path`: The versioned file, relative to the server's root directory.
+    ///
+    /// # Returns
+    /// - `Ok(Vec<FileVersion>)` with the stored versions, empty if there are none.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory.
+    fn list_file_versions(&self, path: impl AsRef<Path>) -> Result<Vec<FileVersion>, Box<dyn Error>>;
+
+    /// Compares a stored version of a file with its current contents.
+    ///
+    /// # Parameters
+    /// - `path`: The versioned file, relative to the server's root directory.
+    /// - `version_id`: The id of the version, as returned by `list_file_versions`.
+    /// - `options`: The number of context lines and the file size limit.
+    ///
+    /// # Returns
+    /// - `Ok(FileDiff)` from the stored version to the current contents.
+    /// - `Err(Box<dyn Error>)` if the version does not exist.
+    fn diff_file_version(
+        &self,
+        path: impl AsRef<Path>,
+        version_id: &str,
+        options: &DiffOptions,
+    ) -> Result<FileDiff, Box<dyn Error>>;
+
+    /// Rolls a file of the server back to a stored version. The current contents become a new version.
+    ///
+    /// # Parameters
+    /// - `path`: The versioned file, relative to the server's root directory.
+    /// - `version_id`: The id of the version to restore.
+    ///
+    /// # Returns
+    /// - `Ok(())` once the file has been restored.
+    /// - `Err(Box<dyn Error>)` if the version does not exist or the file cannot be written.
+    fn restore_file_version(&self, path: impl AsRef<Path>, version_id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Lists the chunks of a region file of one of the server's worlds and checks them for corruption.
+    ///
+    /// # Parameters
+    /// - `path`: The region file, for example `world/region/r.0.0.mca`.
+    /// - `verify`: Whether every chunk is decompressed as well, which reads the whole file.
+    ///
+    /// # Returns
+    /// - `Ok(RegionInfo)` with the populated chunks, their timestamps, sizes and problems.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory or not a region file.
+    fn inspect_region_file(&self, path: impl AsRef<Path>, verify: bool) -> Result<RegionInfo, Box<dyn Error>>;
+
+    /// Exports a single chunk of a region file as an uncompressed NBT file.
+    ///
+    /// # Parameters
+    /// - `region_path`: The region file containing the chunk.
+    /// - `x`, `z`: The world coordinates of the chunk.
+    /// - `destination`: The NBT file to write, relative to the server's root directory.
+    ///
+    /// # Returns
+    /// - `Ok(u64)` with the size of the exported NBT data.
+    /// - `Err(Box<dyn Error>)` if a path is outside of the server directory or the chunk cannot be read.
+    fn export_chunk(
+        &self,
+        region_path: impl AsRef<Path>,
+        x: i32,
+        z: i32,
+        destination: impl AsRef<Path>,
+    ) -> Result<u64, Box<dyn Error>>;
+
+    /// Scans a world for corrupted chunks and deletes them or restores them from a backup.
+    ///
+    /// # Parameters
+    /// - `world`: The world directory, relative to the server's root directory.
+    /// - `backup`: A copy of the world to restore chunks from, such as an extracted backup.
+    /// - `options`: The action to take. By default nothing is changed and only a report is returned.
+    ///
+    /// # Returns
+    /// - `Ok(ChunkRepairReport)` with every corrupted chunk and what was done with it.
+    /// - `Err(Box<dyn Error>)` if a path is outside of the server directory, or the server is running
+    ///   and the repair is not a dry run.
+    fn repair_world_chunks(
+        &self,
+        world: impl AsRef<Path>,
+        backup: Option<impl AsRef<Path>>,
+        options: &ChunkRepairOptions,
+    ) -> Result<ChunkRepairReport, Box<dyn Error>>;
+
+    /// Removes chunks of a world that are far from a center or were not saved for a long time.
+    ///
+    /// # Parameters
+    /// - `world`: The world directory, relative to the server's root directory.
+    /// - `options`: Which chunks to trim. By default only the savings are estimated.
+    ///
+    /// # Returns
+    /// - `Ok(WorldTrimReport)` with the trimmed chunks and the freed bytes per region file.
+    /// - `Err(Box<dyn Error>)` if the world is outside of the server directory, no criterion is set,
+    ///   or the server is running and the trim is not a dry run.
+    fn trim_world(
+        &self,
+        world: impl AsRef<Path>,
+        options: &WorldTrimOptions,
+    ) -> Result<WorldTrimReport, Box<dyn Error>>;
+
+    /// Computes the hash of a file of the server.
+    ///
+    /// # Parameters
+    /// - `subpath`: The file to hash, relative to the server's root directory.
+    /// - `algorithm`: The hash algorithm to use.
+    ///
+    /// # Returns
+    /// - `Ok(String)` with the hex encoded hash.
+    /// - `Err(Box<dyn Error>)` if the file is outside of the server directory or cannot be read.
+    fn hash_file(&self, subpath: impl AsRef<Path>, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>>;
+
+    /// Finds files with identical contents within directories of the server.
+    ///
+    /// # Parameters
+    /// - `subpaths`: The directories to scan, relative to the server's root directory.
+    /// - `algorithm`: The hash algorithm used to compare files.
+    ///
+    /// # Returns
+    /// - `Ok(Vec<DuplicateGroup>)` with paths relative to the server's root directory.
+    /// - `Err(Box<dyn Error>)` if one of the directories is outside of the server directory.
+    fn find_duplicate_files(
+        &self,
+        subpaths: Vec<PathBuf>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Vec<DuplicateGroup>, Box<dyn Error>>;
+
+    /// Atomically writes a text file of the server.
+    ///
+    /// # Parameters
+    /// - `subpath`: The file to write, relative to the server's root directory.
+    /// - `content`: The new contents of the file.
+    /// - `options`: The encoding and line ending to write, usually the ones returned by `read_text_file`.
+    ///
+    /// # Returns
+    /// - `Ok(())` once the new contents are on disk.
+    /// - `Err(Box<dyn Error>)` if the file is outside of the server directory or cannot be written.
+    fn write_text_file(
+        &self,
+        subpath: impl AsRef<Path>,
+        content: &str,
+        options: &WriteTextOptions,
     ) -> Result<(), Box<dyn Error>>;
 
+    /// Returns a cached thumbnail of an image of the server, such as its icon or a map render.
+    ///
+    /// # Parameters
+    /// - `subpath`: The image, relative to the server's root directory.
+    /// - `max_size`: The maximum width and height of the thumbnail, usually `DEFAULT_THUMBNAIL_SIZE`.
+    ///
+    /// # Returns
+    /// - `Ok(Thumbnail)` pointing to the cached PNG file.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory or is not a supported image.
+    fn get_thumbnail(&self, subpath: impl AsRef<Path>, max_size: u32) -> Result<Thumbnail, Box<dyn Error>>;
+
+    /// Changes the permission bits of a file or directory of the server, like `chmod`.
+    ///
+    /// # Parameters
+    /// - `path`: The file or directory to change, relative to the server's root directory.
+    /// - `mode`: An octal mode such as `755` or a symbolic one such as `u+x`.
+    /// - `recursive`: Whether to change the contents of a directory as well.
+    ///
+    /// # Returns
+    /// - `Ok(u64)` with the number of changed entries.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory, the mode is invalid,
+    ///   or the platform has no Unix permissions.
+    fn chmod_path(&self, path: impl AsRef<Path>, mode: &str, recursive: bool) -> Result<u64, Box<dyn Error>>;
+
+    /// Changes the owner and/or group of a file or directory of the server, like `chown`.
+    ///
+    /// # Parameters
+    /// - `path`: The file or directory to change, relative to the server's root directory.
+    /// - `owner`: The new owning user, by name or id, or `None` to keep it.
+    /// - `group`: The new owning group, by name or id, or `None` to keep it.
+    /// - `recursive`: Whether to change the contents of a directory as well.
+    ///
+    /// # Returns
+    /// - `Ok(u64)` with the number of changed entries.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory or the change is not permitted.
+    fn chown_path(
+        &self,
+        path: impl AsRef<Path>,
+        owner: Option<&str>,
+        group: Option<&str>,
+        recursive: bool,
+    ) -> Result<u64, Box<dyn Error>>;
+
+    /// Watches a directory of the server and reports debounced changes to its files.
+    ///
+    /// # Parameters
+    /// - `subpath`: The directory to watch, relative to the server's root directory.
+    /// - `options`: Whether to watch recursively, the debounce interval and glob filters.
+    /// - `on_events`: Invoked on a background thread with each batch of changes, with paths relative
+    ///   to the server's root directory. Returning `false` stops watching.
+    ///
+    /// # Returns
+    /// - `Ok(FileWatcher)` which keeps watching until it is stopped or dropped.
+    /// - `Err(Box<dyn Error>)` if the directory is outside of the server directory or cannot be watched.
+    fn watch_files(
+        &self,
+        subpath: impl AsRef<Path>,
+        options: &WatchOptions,
+        on_events: impl Fn(&[FileChangeEvent]) -> bool + Send + 'static,
+    ) -> Result<FileWatcher, Box<dyn Error>>;
+
     /// Reads the contents of a log file and provides updates via a callback function whenever the file changes.
     ///
     /// This function monitors the file for changes and reads new content incrementally.
@@ -168,55 +644,428 @@ impl ServerFilesystem for Server<u64> {
     ///
     /// This includes walking through the directory tree and summing the sizes of all the files.
     fn calculate_server_size(&mut self) -> u64 {
-        // Create an iterator over files in the server's directory
-        let files = walkdir::WalkDir::new(self.directory.clone());
-        let mut size = 0;
-
-        // Iterate over each entry, accumulate file sizes, and update the total size
-        for entry in files.into_iter().filter_map(Result::ok) {
-            if let Ok(metadata) = entry.metadata() {
-                if entry.file_type().is_file() {
-                    size += metadata.len();
-                }
-            }
-        }
+        // Walk the directory tree, reusing cached scans of directories that have not changed
+        let size = calculate_directory_size(&self.directory);
         self.size = size;
         size
     }
 
+    fn analyze_disk_usage(
+        &self,
+        subpath: impl AsRef<Path>,
+        options: &DiskUsageOptions,
+    ) -> Result<DiskUsageReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        analyze_disk_usage(&self.sandbox(subpath)?, options)
+    }
+
     fn remove_server_directory(&self) -> Result<(), Box<dyn Error>> {
         fs::remove_dir_all(&self.directory).map_err(|e| e.into())
     }
 
-    fn get_files(&self, subpath: impl AsRef<Path>) -> FileSystemEntries {
-        let mut entries = FileSystemEntries::from(self.directory.join(subpath.as_ref()));
-        if let Some(parent) = entries.parent {
-            entries.parent = parent.strip_prefix(&self.directory).ok().map(|i| i.to_path_buf())
-        }
+    fn sandbox(&self, subpath: impl AsRef<Path>) -> Result<SandboxedPath, Box<dyn Error>> {
+        SandboxedPath::new(&self.directory, subpath)
+    }
 
-        for entry in entries.entries.iter_mut() {
-            if let Some(path) = entry.path.strip_prefix(&self.directory).ok().map(|i| i.to_path_buf()) {
-                entry.path = path;
-            }
-        }
+    fn get_files(&self, subpath: impl AsRef<Path>) -> Result<FileSystemEntries, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let directory = self.sandbox(subpath)?;
+        let entries = FileSystemEntries::try_from(&directory)?;
+        Ok(relativize_entries(entries, directory.root_path()))
+    }
+
+    fn get_files_with_sizes(&self, subpath: impl AsRef<Path>) -> Result<FileSystemEntries, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let directory = self.sandbox(subpath)?;
+        let mut entries = FileSystemEntries::try_from(&directory)?;
 
-        entries
+        // Sizes have to be calculated while the paths are still absolute
+        entries.calculate_directory_sizes();
+        Ok(relativize_entries(entries, directory.root_path()))
     }
 
-    fn get_archive_entries(&self, subpath: impl AsRef<Path>) -> FileSystemEntries {
-        todo!()
+    fn get_files_paginated(
+        &self,
+        subpath: impl AsRef<Path>,
+        options: &ListingOptions,
+    ) -> Result<FileSystemEntries, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let directory = self.sandbox(subpath)?;
+        let entries = FileSystemEntries::list(&directory, options)?;
+        Ok(relativize_entries(entries, directory.root_path()))
+    }
+
+    fn get_archive_entries(
+        &self,
+        archive_path: impl AsRef<Path>,
+        subpath: impl AsRef<Path>,
+    ) -> Result<ArchiveEntries, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        ArchiveEntries::read(&self.sandbox(archive_path)?, subpath)
     }
 
-    fn archive_paths(&self, subpaths: Vec<PathBuf>, archive_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
-        todo!()
+    fn stream_files(&self, subpath: impl AsRef<Path>, options: &StreamOptions) -> Result<EntryStream, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let directory = self.sandbox(subpath)?;
+        Ok(EntryStream::open(&directory, options).relative_to(directory.root_path()))
+    }
+
+    fn archive_paths(
+        &self,
+        subpaths: Vec<PathBuf>,
+        archive_path: impl AsRef<Path>,
+        options: &ArchiveOptions,
+        on_progress: impl FnMut(&ArchiveProgress),
+    ) -> Result<ArchiveSummary, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let sources = subpaths
+            .iter()
+            .map(|subpath| self.sandbox(subpath))
+            .collect::<Result<Vec<_>, _>>()?;
+        let archive = self.sandbox(archive_path)?;
+        if available_quota(self.id, &self.directory)? == Some(0) {
+            return Err("The disk quota of the server is used up, no archive can be created".into());
+        }
+
+        let summary = create_archive(&sources, &archive, options, on_progress)?;
+
+        // The compressed size is only known once the archive exists, so an archive that
+        // pushed the server over its quota is removed again
+        let usage = refresh_quota_usage(self.id, &self.directory)?;
+        if let Some(limit) = usage.limit.filter(|limit| usage.used > *limit) {
+            fs::remove_file(archive.path())?;
+            refresh_quota_usage(self.id, &self.directory)?;
+            return Err(format!("The archive would exceed the disk quota of {} bytes", limit).into());
+        }
+        Ok(summary)
     }
 
     fn extract_archive(
         &self,
         archive_path: impl AsRef<Path>,
         destination_path: impl AsRef<Path>,
+        options: &ExtractionOptions,
+        on_progress: impl FnMut(&ExtractionProgress),
+    ) -> Result<ExtractionSummary, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        extract_archive(
+            &self.sandbox(archive_path)?,
+            &self.sandbox(destination_path)?,
+            options,
+            on_progress,
+        )
+    }
+
+    fn copy_path(
+        &self,
+        source: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+        options: FileOperationOptions,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        FileOps::new(options).copy(&self.sandbox(source)?, &self.sandbox(destination)?, on_progress)
+    }
+
+    fn move_path(
+        &self,
+        source: impl AsRef<Path>,
+        destination: impl AsRef<Path>,
+        options: FileOperationOptions,
+        on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let (source, destination) = (self.sandbox(source)?, self.sandbox(destination)?);
+        let report = FileOps::new(options).move_to(&source, &destination, on_progress)?;
+        if !report.dry_run {
+            audit(self.id, AuditAction::FileMoved, source.to_string(), None, Some(destination.to_string()));
+        }
+        Ok(report)
+    }
+
+    fn rename_path(
+        &self,
+        path: impl AsRef<Path>,
+        new_name: &str,
+        options: FileOperationOptions,
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let path = self.sandbox(path)?;
+        let report = FileOps::new(options).rename(&path, new_name)?;
+        if !report.dry_run {
+            audit(self.id, AuditAction::FileMoved, path.to_string(), None, Some(new_name.to_string()));
+        }
+        Ok(report)
+    }
+
+    fn delete_path(
+        &self,
+        path: impl AsRef<Path>,
+        options: FileOperationOptions,
+        mut on_progress: impl FnMut(&FileOperationProgress),
+    ) -> Result<FileOperationReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let path = self.sandbox(path)?;
+        if path.relative_path().starts_with(TRASH_DIRECTORY) {
+            let report = FileOps::new(options).delete(&path, on_progress)?;
+            if !report.dry_run {
+                let removed = format!("{} files and directories", report.actions.len());
+                audit(self.id, AuditAction::FileDeleted, path.to_string(), Some(removed), None);
+            }
+            return Ok(report);
+        }
+
+        let to = if options.dry_run {
+            if path.is_root() {
+                return Err("The server directory itself cannot be moved to the trash".into());
+            }
+            fs::symlink_metadata(path.path())?;
+            PathBuf::from(TRASH_DIRECTORY)
+        } else {
+            let item = Trash::new(&path).trash(&path)?;
+            let trashed = format!("moved to the trash as {}", item.id);
+            audit(self.id, AuditAction::FileDeleted, path.to_string(), Some(trashed), None);
+            PathBuf::from(TRASH_DIRECTORY).join(item.id)
+        };
+        let from = path.relative_path().to_path_buf();
+        on_progress(&FileOperationProgress {
+            current: from.clone(),
+            items_processed: 1,
+            total_items: 1,
+            bytes_processed: 0,
+            total_bytes: 0,
+        });
+        Ok(FileOperationReport {
+            dry_run: options.dry_run,
+            actions: vec![FileAction::Move { from, to }],
+            bytes: 0,
+        })
+    }
+
+    fn download_file(
+        &self,
+        subpath: impl AsRef<Path>,
+        range: Option<&str>,
+        if_range: Option<&str>,
+    ) -> Result<FileDownload, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        FileDownload::open(&self.sandbox(subpath)?, range, if_range)
+    }
+
+    fn upload_file(
+        &self,
+        subpath: impl AsRef<Path>,
+        reader: impl Read,
+        declared_size: Option<u64>,
+    ) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = self.sandbox(subpath)?;
+
+        // A replaced file frees its own space
+        let replaced = fs::metadata(file.path())
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map_or(0, |metadata| metadata.len());
+        let max_size = available_quota(self.id, &self.directory)?.map(|available| available + replaced);
+        if let (Some(max_size), Some(declared_size)) = (max_size, declared_size) {
+            if declared_size > max_size {
+                return Err(format!(
+                    "The upload of {} bytes would exceed the disk quota, only {} bytes are available",
+                    declared_size, max_size
+                )
+                .into());
+            }
+        }
+
+        let written = receive_upload(&file, reader, max_size)?;
+        if let Err(err) = refresh_quota_usage(self.id, &self.directory) {
+            warn!("Failed to update the disk quota usage of server {}: {}", self.id, err);
+        }
+        let before = (replaced > 0).then(|| format!("{} bytes", replaced));
+        audit(self.id, AuditAction::FileUploaded, file.to_string(), before, Some(format!("{} bytes", written)));
+        Ok(written)
+    }
+
+    fn search_files(&self, subpath: impl AsRef<Path>, options: &SearchOptions) -> Result<SearchResults, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let directory = self.sandbox(subpath)?;
+        let mut results = search_files(&directory, options)?;
+        for result in results.results.iter_mut() {
+            if let Ok(path) = result.entry.path.strip_prefix(directory.root_path()) {
+                result.entry.path = path.to_path_buf();
+            }
+        }
+        Ok(results)
+    }
+
+    fn read_text_file(&self, subpath: impl AsRef<Path>) -> Result<TextFile, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        read_text_file(&self.sandbox(subpath)?, DEFAULT_MAX_TEXT_FILE_SIZE)
+    }
+
+    fn diff_files(
+        &self,
+        old_path: impl AsRef<Path>,
+        new_path: impl AsRef<Path>,
+        options: &DiffOptions,
+    ) -> Result<FileDiff, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        diff_files(&self.sandbox(old_path)?, &self.sandbox(new_path)?, options)
+    }
+
+    fn list_file_versions(&self, path: impl AsRef<Path>) -> Result<Vec<FileVersion>, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = self.sandbox(path)?;
+        VersionStore::new(&file).list(&file)
+    }
+
+    fn diff_file_version(
+        &self,
+        path: impl AsRef<Path>,
+        version_id: &str,
+        options: &DiffOptions,
+    ) -> Result<FileDiff, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = self.sandbox(path)?;
+        VersionStore::new(&file).diff(&file, version_id, options)
+    }
+
+    fn restore_file_version(&self, path: impl AsRef<Path>, version_id: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = self.sandbox(path)?;
+        let before = fs::read(file.path()).ok().map(|content| summarize_text(&String::from_utf8_lossy(&content)));
+        VersionStore::new(&file).restore(&file, version_id)?;
+        let after = format!("Restored version {}", version_id);
+        audit(self.id, AuditAction::FileEdited, file.to_string(), before, Some(after));
+        Ok(())
+    }
+
+    fn inspect_region_file(&self, path: impl AsRef<Path>, verify: bool) -> Result<RegionInfo, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        Ok(RegionFile::open(&self.sandbox(path)?)?.inspect(verify))
+    }
+
+    fn export_chunk(
+        &self,
+        region_path: impl AsRef<Path>,
+        x: i32,
+        z: i32,
+        destination: impl AsRef<Path>,
+    ) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        export_chunk(&self.sandbox(region_path)?, x, z, &self.sandbox(destination)?)
+    }
+
+    fn repair_world_chunks(
+        &self,
+        world: impl AsRef<Path>,
+        backup: Option<impl AsRef<Path>>,
+        options: &ChunkRepairOptions,
+    ) -> Result<ChunkRepairReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        // The game keeps the region files of loaded chunks open and saves over any repair
+        if !options.dry_run && matches!(self.status, Some(ServerStatus::Online | ServerStatus::Starting)) {
+            return Err("Chunks can only be repaired while the server is stopped".into());
+        }
+        let world = self.sandbox(world)?;
+        let backup = backup.map(|backup| self.sandbox(backup)).transpose()?;
+        let report = repair_chunks(&world, backup.as_ref(), options)?;
+        if !options.dry_run {
+            if let Err(err) = refresh_quota_usage(self.id, &self.directory) {
+                warn!("Failed to update the disk quota usage of server {}: {}", self.id, err);
+            }
+        }
+        Ok(report)
+    }
+
+    fn trim_world(
+        &self,
+        world: impl AsRef<Path>,
+        options: &WorldTrimOptions,
+    ) -> Result<WorldTrimReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        if !options.dry_run && matches!(self.status, Some(ServerStatus::Online | ServerStatus::Starting)) {
+            return Err("Worlds can only be trimmed while the server is stopped".into());
+        }
+        let report = trim_world(&self.sandbox(world)?, options)?;
+        if !options.dry_run {
+            if let Err(err) = refresh_quota_usage(self.id, &self.directory) {
+                warn!("Failed to update the disk quota usage of server {}: {}", self.id, err);
+            }
+        }
+        Ok(report)
+    }
+
+    fn hash_file(&self, subpath: impl AsRef<Path>, algorithm: HashAlgorithm) -> Result<String, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        hash_file(&self.sandbox(subpath)?, algorithm)
+    }
+
+    fn find_duplicate_files(
+        &self,
+        subpaths: Vec<PathBuf>,
+        algorithm: HashAlgorithm,
+    ) -> Result<Vec<DuplicateGroup>, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let directories = subpaths
+            .iter()
+            .map(|subpath| self.sandbox(subpath))
+            .collect::<Result<Vec<_>, _>>()?;
+        let root = self.sandbox("")?;
+        let mut groups = find_duplicates(&directories, algorithm)?;
+        for group in groups.iter_mut() {
+            for path in group.paths.iter_mut() {
+                if let Ok(relative) = path.strip_prefix(root.root_path()) {
+                    *path = relative.to_path_buf();
+                }
+            }
+        }
+        Ok(groups)
+    }
+
+    fn write_text_file(
+        &self,
+        subpath: impl AsRef<Path>,
+        content: &str,
+        options: &WriteTextOptions,
     ) -> Result<(), Box<dyn Error>> {
-        todo!()
+        authorize(self, Capability::EditFiles)?;
+        let file = self.sandbox(subpath)?;
+        let before = fs::read(file.path()).ok().map(|content| summarize_text(&String::from_utf8_lossy(&content)));
+        write_text_file(&file, content, options)?;
+        audit(self.id, AuditAction::FileEdited, file.to_string(), before, Some(summarize_text(content)));
+        Ok(())
+    }
+
+    fn get_thumbnail(&self, subpath: impl AsRef<Path>, max_size: u32) -> Result<Thumbnail, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        ThumbnailCache::default().get_or_create(&self.sandbox(subpath)?, max_size)
+    }
+
+    fn chmod_path(&self, path: impl AsRef<Path>, mode: &str, recursive: bool) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        change_mode(&self.sandbox(path)?, &ModeChange::parse(mode)?, recursive)
+    }
+
+    fn chown_path(
+        &self,
+        path: impl AsRef<Path>,
+        owner: Option<&str>,
+        group: Option<&str>,
+        recursive: bool,
+    ) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        change_owner(&self.sandbox(path)?, owner, group, recursive)
+    }
+
+    fn watch_files(
+        &self,
+        subpath: impl AsRef<Path>,
+        options: &WatchOptions,
+        on_events: impl Fn(&[FileChangeEvent]) -> bool + Send + 'static,
+    ) -> Result<FileWatcher, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        FileWatcher::start(&self.sandbox(subpath)?, options, on_events)
     }
 
     fn read_log_file(
@@ -224,8 +1073,10 @@ impl ServerFilesystem for Server<u64> {
         log_path: impl AsRef<Path>,
         on_update: impl Fn(&str) -> bool + Send + Sync + 'static,
     ) -> Result<String, Box<dyn Error>> {
-        // Open the file specified by the path (log_path)
-        let log_path = self.directory.join("logs").join(&log_path);
+        authorize(self, Capability::ViewConsole)?;
+
+        // Open the file specified by the path (log_path), making sure it stays inside the server directory
+        let log_path = self.sandbox(Path::new("logs").join(&log_path))?.path();
         let mut file = File::open(&log_path)?;
 
         // Prepare a buffer (String) to read the file content into
@@ -327,3 +1178,21 @@ impl ServerFilesystem for Server<u64> {
             .unwrap_or(self.directory.clone());
     }
 }
+
+/// Strips the (canonicalized) server directory from the parent and entry paths of a listing,
+/// so that only paths relative to the server root are exposed.
+fn relativize_entries(mut entries: FileSystemEntries, directory: &Path) -> FileSystemEntries {
+    if let Some(parent) = entries.parent {
+        entries.parent = parent.strip_prefix(directory).ok().map(|i| i.to_path_buf())
+    }
+
+    for entry in entries.entries.iter_mut() {
+        // Link targets inside the server directory are shown relative to it as well
+        entry.relativize(directory);
+    }
+    for error in entries.errors.iter_mut() {
+        error.relativize(directory);
+    }
+
+    entries
+}
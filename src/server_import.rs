@@ -0,0 +1,464 @@
+use crate::eula::{read_eula_state, EulaState};
+use crate::java_runtimes::{choose_java_runtime, find_java_runtimes};
+use crate::loader_installer::find_forge_start_file;
+use crate::minecraft_file::ModLoader;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_ping::DEFAULT_SERVER_PORT;
+use crate::server_properties_file::ServerPropertiesFile;
+use crate::server_registry::used_ports;
+use crate::server_status::ServerStatus;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// The files inspected inside a jar are small, anything larger is not read.
+const MAX_ENTRY_SIZE: u64 = 1024 * 1024;
+
+/// The Main-Class of the server jars the manager recognizes, and the software they belong to.
+const KNOWN_MAIN_CLASSES: &[(&str, &str)] = &[
+    ("net.minecraft.server.Main", "Vanilla"),
+    ("net.minecraft.server.MinecraftServer", "Vanilla"),
+    ("net.minecraft.bundler.Main", "Vanilla"),
+    ("io.papermc.paperclip.Main", "Paper"),
+    ("io.papermc.paperclip.Paperclip", "Paper"),
+    ("org.bukkit.craftbukkit.Main", "Spigot"),
+    ("org.bukkit.craftbukkit.bootstrap.Main", "Spigot"),
+    ("net.md_5.bungee.Bootstrap", "BungeeCord"),
+    ("com.velocitypowered.proxy.Velocity", "Velocity"),
+];
+
+/// Forks of Paper ship the same Paperclip launcher, so they are told apart by the jar name.
+const PAPER_FORKS: &[(&str, &str)] = &[("purpur", "Purpur"), ("folia", "Folia"), ("pufferfish", "Pufferfish")];
+
+lazy_static! {
+    /// A version of the game in a file name, like the `1.20.4` of `paper-1.20.4-496.jar`.
+    static ref NAMED_VERSION: Option<Regex> = Regex::new(r"(?:^|[-_])(1\.\d+(?:\.\d+)?)(?:[-_.]|$)").ok();
+    /// The build number following the version in the name of a Paper jar.
+    static ref NAMED_BUILD: Option<Regex> = Regex::new(r"-1\.\d+(?:\.\d+)?-(\d+)\.jar$").ok();
+    /// The version of the game old Paper and Spigot jars put in their manifest, `(MC: 1.16.5)`.
+    static ref MANIFEST_VERSION: Option<Regex> = Regex::new(r"\(MC: ([^)]+)\)").ok();
+}
+
+/// What was found in a server directory the manager did not create.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DetectedServer {
+    /// The file to start the server with, relative to the directory.
+    pub start_file: Option<PathBuf>,
+    /// The software the start file belongs to, like `Paper` or `Vanilla`.
+    pub software: Option<String>,
+    pub loader: Option<ModLoader>,
+    /// The version of the mod loader, or the build of Paper and its forks.
+    pub loader_version: Option<String>,
+    pub minecraft_version: Option<String>,
+    /// The game port from `server.properties`, the default port if it sets none.
+    pub server_port: u16,
+    pub rcon_port: Option<u16>,
+    pub query_port: Option<u16>,
+    pub eula_accepted: bool,
+    /// What could not be detected or will get in the way once the server is registered.
+    pub warnings: Vec<String>,
+}
+
+/// How an existing directory is registered as a server. What is set here wins over what was detected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdoptServerOptions {
+    pub directory: PathBuf,
+    /// The name of the server, the name of the directory if empty.
+    pub name: String,
+    pub owner: u64,
+    pub members: Vec<u64>,
+    /// The minimum RAM in GB, passed to `-Xms`.
+    pub min_ram: u64,
+    /// The maximum RAM in GB, passed to `-Xmx`.
+    pub max_ram: u64,
+    /// The loader type of the server, that of the detected loader if not set.
+    pub loader_type: Option<u8>,
+    /// The file to start the server with, relative to the directory.
+    pub start_file: Option<PathBuf>,
+    pub minecraft_version: Option<String>,
+    /// The Java runtime, the best fitting one installed if not set.
+    pub java_runtime: Option<PathBuf>,
+}
+
+impl Default for AdoptServerOptions {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::new(),
+            name: String::new(),
+            owner: 0,
+            members: Vec::new(),
+            min_ram: 1,
+            max_ram: 2,
+            loader_type: None,
+            start_file: None,
+            minecraft_version: None,
+            java_runtime: None,
+        }
+    }
+}
+
+/// Looks at a server directory to find out how it is started, what it runs and which ports it
+/// uses. Nothing in the directory is changed.
+///
+/// Forge and NeoForge are recognized from their run scripts and libraries, Fabric and Quilt from
+/// their launch jars, and other servers from the manifest of the jars in the directory.
+///
+/// # Errors
+/// Returns an error if the directory does not exist or its `server.properties` cannot be read.
+pub fn detect_server(directory: &Path) -> Result<DetectedServer, Box<dyn Error>> {
+    if !directory.is_dir() {
+        return Err(format!("{:?} is not a directory", directory).into());
+    }
+    let mut detected = DetectedServer {
+        server_port: DEFAULT_SERVER_PORT,
+        ..Default::default()
+    };
+
+    if let Some(loader) = forge_loader(directory) {
+        detect_forge(directory, loader, &mut detected);
+    } else if directory.join("fabric-server-launch.jar").is_file() {
+        detect_fabric(directory, &mut detected);
+    } else if directory.join("quilt-server-launch.jar").is_file() {
+        detect_quilt(directory, &mut detected);
+    } else {
+        detect_jar(directory, &mut detected)?;
+    }
+    if detected.minecraft_version.is_none() {
+        // Paper, Forge and the bundled vanilla jars unpack the game into versions/ or libraries/
+        detected.minecraft_version = newest_directory(&directory.join("versions"))
+            .or_else(|| newest_directory(&directory.join("libraries/net/minecraft/server")))
+            .map(|version| version.split('-').next().unwrap_or_default().to_string());
+    }
+    if detected.start_file.is_none() {
+        detected.warnings.push("No file to start the server with was found".to_string());
+    }
+    if detected.minecraft_version.is_none() {
+        detected.warnings.push("The version of the game could not be detected".to_string());
+    }
+
+    detect_ports(directory, &mut detected)?;
+    detected.eula_accepted = read_eula_state(directory)? == EulaState::Accepted;
+    if !detected.eula_accepted {
+        detected.warnings.push("The EULA is not accepted, the server will not start until it is".to_string());
+    }
+    Ok(detected)
+}
+
+/// Registers an existing server directory as a managed server, without moving or changing any of
+/// its files. The server keeps the ports its `server.properties` sets.
+///
+/// # Errors
+/// Returns an error if the directory is already registered, its game port is used by another
+/// server, no start file was set or detected, or the server cannot be stored.
+pub fn adopt_server(options: &AdoptServerOptions) -> Result<Server<u64>, Box<dyn Error>> {
+    if options.min_ram > options.max_ram {
+        return Err("The minimum RAM cannot be larger than the maximum RAM".into());
+    }
+    let directory = fs::canonicalize(&options.directory)
+        .map_err(|e| format!("Failed to open {:?}: {}", options.directory, e))?;
+    for server in Server::<u64>::get_list_of_servers()? {
+        if fs::canonicalize(&server.directory).is_ok_and(|registered| registered == directory) {
+            return Err(format!("{:?} is already registered as server {:?}", directory, server.name).into());
+        }
+    }
+
+    let detected = detect_server(&directory)?;
+    if let Some(server_id) = used_ports()?.get(&detected.server_port) {
+        return Err(format!(
+            "Port {} of the server is already used by server {}, change it in server.properties first",
+            detected.server_port, server_id
+        )
+        .into());
+    }
+    let start_file = options
+        .start_file
+        .clone()
+        .or_else(|| detected.start_file.clone())
+        .ok_or_else(|| format!("No start file was found in {:?}, one has to be set to adopt it", directory))?;
+    if !directory.join(&start_file).is_file() {
+        return Err(format!("The start file {:?} does not exist in {:?}", start_file, directory).into());
+    }
+
+    let minecraft_version = options
+        .minecraft_version
+        .clone()
+        .or_else(|| detected.minecraft_version.clone())
+        .unwrap_or_default();
+    let java_runtime = options.java_runtime.clone().or_else(|| {
+        choose_java_runtime(&find_java_runtimes(), &minecraft_version).map(|runtime| runtime.executable)
+    });
+    let name = options.name.trim();
+    let name = if name.is_empty() {
+        directory.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+    } else {
+        name.to_string()
+    };
+    if name.is_empty() {
+        return Err("The server name cannot be empty".into());
+    }
+
+    let mut server = Server {
+        name,
+        owner: options.owner,
+        members: options.members.clone(),
+        min_ram: options.min_ram,
+        max_ram: options.max_ram,
+        start_script: Some(start_file),
+        minecraft_version,
+        loader_type: options
+            .loader_type
+            .or_else(|| detected.loader.map(|loader| loader.loader_type()))
+            .unwrap_or_default(),
+        loader_version: detected.loader_version.clone(),
+        java_runtime,
+        directory,
+        status: Some(ServerStatus::Offline),
+        ..Default::default()
+    };
+    server.add()?;
+    for warning in &detected.warnings {
+        warn!("Adopted server {:?}: {}", server.name, warning);
+    }
+    if server.java_runtime.is_none() {
+        warn!("Adopted server {:?}: no installed Java runtime fits it, one has to be set", server.name);
+    }
+    info!(
+        "Adopted server {:?} from {:?}, running {} {} on port {}",
+        server.name,
+        server.directory,
+        detected.software.as_deref().unwrap_or("an unknown server"),
+        server.minecraft_version,
+        detected.server_port
+    );
+    Ok(server)
+}
+
+/// The loader whose libraries a Forge or NeoForge installer left next to its run script.
+fn forge_loader(directory: &Path) -> Option<ModLoader> {
+    if !directory.join("run.sh").is_file() && !directory.join("run.bat").is_file() {
+        // Before 1.17 the installer left a jar named after the version instead
+        let jar = jar_names(directory).into_iter().find(|name| {
+            (name.starts_with("forge-") || name.starts_with("minecraftforge")) && !name.contains("installer")
+        });
+        return jar.map(|_| ModLoader::Forge);
+    }
+    let libraries = directory.join("libraries").join("net");
+    if libraries.join("neoforged").is_dir() {
+        Some(ModLoader::NeoForge)
+    } else if libraries.join("minecraftforge").is_dir() {
+        Some(ModLoader::Forge)
+    } else {
+        None
+    }
+}
+
+fn detect_forge(directory: &Path, loader: ModLoader, detected: &mut DetectedServer) {
+    detected.loader = Some(loader);
+    detected.software = Some(format!("{:?}", loader));
+    match find_forge_start_file(directory) {
+        Ok(start_file) => detected.start_file = Some(start_file),
+        Err(e) => detected.warnings.push(e.to_string()),
+    }
+
+    let neoforge = directory.join("libraries/net/neoforged/neoforge");
+    if let Some(version) = newest_directory(&neoforge) {
+        // NeoForge numbers its versions after the game, 20.4.x is for 1.20.4 and 21.0.x for 1.21
+        let mut parts = version.split('.');
+        if let (Some(major), Some(minor)) = (parts.next(), parts.next()) {
+            detected.minecraft_version =
+                Some(if minor == "0" { format!("1.{}", major) } else { format!("1.{}.{}", major, minor) });
+        }
+        detected.loader_version = Some(version);
+        return;
+    }
+    // Forge, and NeoForge for 1.20.1, name their library directories `<game>-<loader>`
+    let forge = ["libraries/net/minecraftforge/forge", "libraries/net/neoforged/forge"]
+        .iter()
+        .find_map(|library| newest_directory(&directory.join(library)))
+        .or_else(|| {
+            jar_names(directory)
+                .into_iter()
+                .find(|name| name.starts_with("forge-") && !name.contains("installer"))
+                .map(|name| name.trim_start_matches("forge-").trim_end_matches(".jar").replace("-universal", ""))
+        });
+    if let Some((game, loader_version)) = forge.as_deref().and_then(|version| version.split_once('-')) {
+        detected.minecraft_version = Some(game.to_string());
+        detected.loader_version = Some(loader_version.to_string());
+    }
+}
+
+fn detect_fabric(directory: &Path, detected: &mut DetectedServer) {
+    detected.loader = Some(ModLoader::Fabric);
+    detected.software = Some("Fabric".to_string());
+    detected.start_file = Some(PathBuf::from("fabric-server-launch.jar"));
+    // The launcher jar of the Fabric meta records what it was built for
+    if let Some(install) = read_jar_entry(&directory.join("fabric-server-launch.jar"), "install.properties") {
+        let properties = ServerPropertiesFile::parse(&install);
+        detected.loader_version = properties.get("fabric-loader-version").map(str::to_string);
+        detected.minecraft_version = properties.get("game-version").map(str::to_string);
+    }
+    if detected.loader_version.is_none() {
+        detected.loader_version = newest_directory(&directory.join("libraries/net/fabricmc/fabric-loader"));
+    }
+    if detected.minecraft_version.is_none() {
+        detected.minecraft_version = game_jar_version(&directory.join("server.jar"));
+    }
+}
+
+fn detect_quilt(directory: &Path, detected: &mut DetectedServer) {
+    detected.loader = Some(ModLoader::Quilt);
+    detected.software = Some("Quilt".to_string());
+    detected.start_file = Some(PathBuf::from("quilt-server-launch.jar"));
+    detected.loader_version = newest_directory(&directory.join("libraries/org/quiltmc/quilt-loader"));
+    detected.minecraft_version = game_jar_version(&directory.join("server.jar"));
+}
+
+/// Picks the server jar among the jars in the directory, by the main class in their manifest.
+fn detect_jar(directory: &Path, detected: &mut DetectedServer) -> Result<(), Box<dyn Error>> {
+    let mut candidates: Vec<(String, &'static str)> = jar_names(directory)
+        .into_iter()
+        .filter(|name| !name.contains("installer"))
+        .filter_map(|name| {
+            let manifest = read_jar_entry(&directory.join(&name), "META-INF/MANIFEST.MF")?;
+            let main_class = manifest_value(&manifest, "Main-Class")?;
+            let software = KNOWN_MAIN_CLASSES.iter().find(|(class, _)| *class == main_class)?.1;
+            Some((name, software))
+        })
+        .collect();
+    if candidates.len() > 1 {
+        // A common server.jar beats a jar that was left next to it
+        candidates.sort_by_key(|(name, _)| name.as_str() != "server.jar");
+        let names: Vec<&str> = candidates.iter().map(|(name, _)| name.as_str()).collect();
+        detected.warnings.push(format!("Several server jars were found ({}), using {}", names.join(", "), names[0]));
+    }
+    let Some((name, software)) = candidates.into_iter().next() else {
+        // Servers started by a script of their own can still be adopted, the script is run as it is
+        let script = if cfg!(target_os = "windows") { ["start.bat", "run.bat"] } else { ["start.sh", "run.sh"] };
+        if let Some(script) = script.iter().find(|script| directory.join(script).is_file()) {
+            detected.start_file = Some(PathBuf::from(script));
+            detected.warnings.push(format!("No server jar was found, the server is started with {}", script));
+        }
+        return Ok(());
+    };
+
+    let jar = directory.join(&name);
+    let lower = name.to_lowercase();
+    detected.software = Some(match software {
+        "Paper" => PAPER_FORKS
+            .iter()
+            .find(|(prefix, _)| lower.starts_with(prefix))
+            .map_or(software, |(_, fork)| *fork)
+            .to_string(),
+        _ => software.to_string(),
+    });
+    detected.minecraft_version = game_jar_version(&jar).or_else(|| {
+        let manifest = read_jar_entry(&jar, "META-INF/MANIFEST.MF")?;
+        let version = manifest_value(&manifest, "Implementation-Version")?;
+        capture(&MANIFEST_VERSION, &version)
+    });
+    if detected.minecraft_version.is_none() {
+        detected.minecraft_version = capture(&NAMED_VERSION, &lower);
+    }
+    if software == "Paper" {
+        detected.loader_version = capture(&NAMED_BUILD, &lower);
+    }
+    detected.start_file = Some(PathBuf::from(name));
+    Ok(())
+}
+
+fn detect_ports(directory: &Path, detected: &mut DetectedServer) -> Result<(), Box<dyn Error>> {
+    let content = match fs::read_to_string(directory.join("server.properties")) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            detected.warnings.push("There is no server.properties, the game will create it on its first start".into());
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let properties = ServerPropertiesFile::parse(&content);
+    let port = |key: &str| properties.get(key).and_then(|port| port.trim().parse::<u16>().ok());
+    let enabled = |key: &str| properties.get(key).map(str::trim) == Some("true");
+    detected.server_port = port("server-port").unwrap_or(DEFAULT_SERVER_PORT);
+    detected.rcon_port = port("rcon.port").filter(|_| enabled("enable-rcon"));
+    detected.query_port = port("query.port").filter(|_| enabled("enable-query"));
+
+    let used = used_ports()?;
+    for (what, port) in [("RCON", detected.rcon_port), ("query", detected.query_port)] {
+        if let Some(server_id) = port.and_then(|port| used.get(&port)) {
+            detected.warnings.push(format!("The {} port is already used by server {}", what, server_id));
+        }
+    }
+    Ok(())
+}
+
+/// The version of the game a vanilla, Paper or bundled server jar was built for.
+fn game_jar_version(jar: &Path) -> Option<String> {
+    if let Some(version) = read_jar_entry(jar, "version.json") {
+        let version: Value = serde_json::from_str(&version).ok()?;
+        return version["id"].as_str().map(str::to_string);
+    }
+    // The bundler lists the jars it unpacks as `<hash>\t<id>\t<path>`, the game among them
+    let versions = read_jar_entry(jar, "META-INF/versions.list")?;
+    let id = versions.lines().next()?.split('\t').nth(1)?;
+    Some(id.trim_end_matches("-R0.1-SNAPSHOT").to_string())
+}
+
+fn read_jar_entry(jar: &Path, name: &str) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(jar).ok()?)).ok()?;
+    let entry = archive.by_name(name).ok()?;
+    let mut contents = String::new();
+    entry.take(MAX_ENTRY_SIZE).read_to_string(&mut contents).ok()?;
+    Some(contents)
+}
+
+fn manifest_value(manifest: &str, key: &str) -> Option<String> {
+    manifest.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        (name.trim() == key).then(|| value.trim().to_string())
+    })
+}
+
+fn capture(pattern: &Option<Regex>, text: &str) -> Option<String> {
+    Some(pattern.as_ref()?.captures(text)?.get(1)?.as_str().to_string())
+}
+
+/// The names of the jars directly in a directory, sorted.
+fn jar_names(directory: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| entry.path().is_file())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name.to_lowercase().ends_with(".jar"))
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// The subdirectory with the highest version as its name, like the newest library version.
+fn newest_directory(directory: &Path) -> Option<String> {
+    fs::read_dir(directory)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .max_by_key(|name| version_numbers(name))
+}
+
+fn version_numbers(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
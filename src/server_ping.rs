@@ -0,0 +1,510 @@
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_properties::ServerProperties;
+use crate::server_properties_file::ServerPropertiesFile;
+use lazy_static::lazy_static;
+use log::{debug, error, info};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The port the game listens on unless `server-port` is set.
+pub const DEFAULT_SERVER_PORT: u16 = 25565;
+
+/// How long to wait for a server to answer a ping or query.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest status response accepted, which is mostly the base64 encoded favicon.
+const MAX_STATUS_LENGTH: usize = 1024 * 1024;
+
+/// Pings with protocol version -1 ask for the status without claiming to be any game version.
+const STATUS_PROTOCOL_VERSION: i32 = -1;
+
+static MONITOR: Once = Once::new();
+
+lazy_static! {
+    static ref LIVE_STATUS: Mutex<HashMap<u64, LiveServerStatus>> = Mutex::new(HashMap::new());
+}
+
+/// What a server answers to a Server List Ping, like the multiplayer screen of the game shows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerPingResponse {
+    /// The version name, which proxies and server software often replace with their own name.
+    pub version_name: String,
+    pub protocol: i32,
+    pub online_players: u32,
+    pub max_players: u32,
+    /// A few of the online players, servers may hide them or show made up entries.
+    pub player_sample: Vec<PingPlayer>,
+    /// The message of the day without formatting codes.
+    pub motd: String,
+    /// The server icon as a `data:image/png;base64,` URL.
+    pub favicon: Option<String>,
+    /// The round trip time of a ping packet, in milliseconds.
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PingPlayer {
+    pub name: String,
+    pub id: String,
+}
+
+/// What a server answers to a full GS4 query, which needs `enable-query` to be set.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct QueryResponse {
+    pub motd: String,
+    pub game_type: String,
+    pub version: String,
+    /// The server software and its plugins, as reported by Bukkit based servers.
+    pub plugins: String,
+    pub map: String,
+    pub online_players: u32,
+    pub max_players: u32,
+    /// Every online player, unlike the sample of a ping.
+    pub players: Vec<String>,
+    pub host_port: Option<u16>,
+    pub host_ip: String,
+}
+
+/// The last status the monitor fetched for a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveServerStatus {
+    pub checked_at: SystemTime,
+    /// `None` if the server did not answer the ping.
+    pub ping: Option<ServerPingResponse>,
+    /// `None` if the query is disabled or the server did not answer it.
+    pub query: Option<QueryResponse>,
+    /// Why the ping failed.
+    pub error: Option<String>,
+}
+
+/// Pings a server with the Server List Ping protocol of Minecraft 1.7 and later.
+///
+/// # Errors
+/// Returns an error if the server cannot be reached, does not answer in time, or answers with an invalid status.
+pub fn ping(address: SocketAddr) -> Result<ServerPingResponse, Box<dyn Error>> {
+    let mut stream = TcpStream::connect_timeout(&address, PING_TIMEOUT)?;
+    stream.set_read_timeout(Some(PING_TIMEOUT))?;
+    stream.set_write_timeout(Some(PING_TIMEOUT))?;
+
+    // Handshake with the next state set to status, followed by the status request
+    let mut handshake = Vec::new();
+    write_varint(&mut handshake, 0x00);
+    write_varint(&mut handshake, STATUS_PROTOCOL_VERSION);
+    write_string(&mut handshake, &address.ip().to_string());
+    handshake.extend_from_slice(&address.port().to_be_bytes());
+    write_varint(&mut handshake, 1);
+    write_packet(&mut stream, &handshake)?;
+    write_packet(&mut stream, &[0x00])?;
+
+    let status = parse_status(&read_packet(&mut stream)?)?;
+
+    // The server echoes the payload of a ping, which gives the latency
+    let payload = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    let mut ping = vec![0x01];
+    ping.extend_from_slice(&payload.to_be_bytes());
+    let sent = Instant::now();
+    write_packet(&mut stream, &ping)?;
+    let latency_ms = match read_packet(&mut stream) {
+        Ok(_) => sent.elapsed().as_millis() as u64,
+        // Some proxies close the connection instead, the status itself is still valid
+        Err(e) => {
+            debug!("{} did not answer the ping packet: {}", address, e);
+            sent.elapsed().as_millis() as u64
+        }
+    };
+
+    Ok(ping_response(&status, latency_ms))
+}
+
+/// Reads the JSON of a status response packet.
+fn parse_status(response: &[u8]) -> Result<Value, Box<dyn Error>> {
+    let mut cursor = response;
+    if read_varint(&mut cursor)? != 0x00 {
+        return Err("The server answered the status request with an unexpected packet".into());
+    }
+    let length = usize::try_from(read_varint(&mut cursor)?).map_err(|_| "The status response has a negative length")?;
+    let json = cursor.get(..length).ok_or("The status response is truncated")?;
+    Ok(serde_json::from_slice(json)?)
+}
+
+/// Picks what the multiplayer screen shows out of a status, leaving out what is missing.
+fn ping_response(status: &Value, latency_ms: u64) -> ServerPingResponse {
+    let players = &status["players"];
+    ServerPingResponse {
+        version_name: status["version"]["name"].as_str().unwrap_or_default().to_string(),
+        protocol: status["version"]["protocol"].as_i64().unwrap_or(-1) as i32,
+        online_players: players["online"].as_u64().unwrap_or_default() as u32,
+        max_players: players["max"].as_u64().unwrap_or_default() as u32,
+        player_sample: players["sample"]
+            .as_array()
+            .map(|sample| {
+                sample
+                    .iter()
+                    .map(|player| PingPlayer {
+                        name: player["name"].as_str().unwrap_or_default().to_string(),
+                        id: player["id"].as_str().unwrap_or_default().to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        motd: strip_formatting(&chat_to_text(&status["description"])),
+        favicon: status["favicon"].as_str().map(str::to_string),
+        latency_ms,
+    }
+}
+
+/// Fetches the full statistics of a server with the GS4 query protocol.
+///
+/// # Errors
+/// Returns an error if the query port does not answer in time or the answer is malformed.
+pub fn query(address: SocketAddr) -> Result<QueryResponse, Box<dyn Error>> {
+    let bind = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(PING_TIMEOUT))?;
+    socket.connect(address)?;
+    // The game only looks at the lower four bits of every byte of the session id
+    let session_id = (std::process::id() as i32) & 0x0F0F_0F0F;
+    let mut buffer = [0u8; 65_536];
+
+    let mut handshake = vec![0xFE, 0xFD, 0x09];
+    handshake.extend_from_slice(&session_id.to_be_bytes());
+    socket.send(&handshake)?;
+    let length = socket.recv(&mut buffer)?;
+    let challenge = buffer
+        .get(5..length)
+        .map(|challenge| String::from_utf8_lossy(challenge).trim_end_matches('\0').trim().to_string())
+        .ok_or("The query handshake response is truncated")?;
+    let challenge: i32 = challenge
+        .parse()
+        .map_err(|_| format!("Invalid query challenge {:?}", challenge))?;
+
+    // Padding the request to 15 bytes asks for the full statistics instead of the basic ones
+    let mut request = vec![0xFE, 0xFD, 0x00];
+    request.extend_from_slice(&session_id.to_be_bytes());
+    request.extend_from_slice(&challenge.to_be_bytes());
+    request.extend_from_slice(&[0, 0, 0, 0]);
+    socket.send(&request)?;
+    let length = socket.recv(&mut buffer)?;
+    // Skips the type, the session id and the constant `splitnum` padding
+    let data = buffer.get(16..length).ok_or("The query response is truncated")?;
+
+    let mut parts = data.split(|byte| *byte == 0).map(|part| String::from_utf8_lossy(part).into_owned());
+    let mut values = HashMap::new();
+    while let Some(key) = parts.next() {
+        if key.is_empty() {
+            break;
+        }
+        values.insert(key, parts.next().unwrap_or_default());
+    }
+    // The player list follows a `\x01player_\0\0` marker
+    let players: Vec<String> = parts
+        .skip_while(|part| !part.ends_with("player_"))
+        .skip(2)
+        .take_while(|player| !player.is_empty())
+        .collect();
+
+    let mut value = |key: &str| values.remove(key).unwrap_or_default();
+    Ok(QueryResponse {
+        motd: strip_formatting(&value("hostname")),
+        game_type: value("gametype"),
+        version: value("version"),
+        plugins: value("plugins"),
+        map: value("map"),
+        online_players: value("numplayers").parse().unwrap_or_default(),
+        max_players: value("maxplayers").parse().unwrap_or_default(),
+        players,
+        host_port: value("hostport").parse().ok(),
+        host_ip: value("hostip"),
+    })
+}
+
+/// The address to reach a server on from this machine, the one of `server-ip` or localhost.
+pub(crate) fn local_host(properties: &ServerPropertiesFile) -> IpAddr {
+    properties
+        .get("server-ip")
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .filter(|ip| !ip.is_unspecified())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Starts the thread pinging every server on the given interval. Calling it again has no effect.
+///
+/// Servers are pinged whether or not the manager started them, so servers started by other tools
+/// show their players as well.
+pub fn start_status_monitor(interval: Duration) {
+    MONITOR.call_once(|| {
+        thread::spawn(move || loop {
+            match Server::<u64>::get_list_of_servers() {
+                Ok(servers) => {
+                    for server in servers {
+                        let status = server.fetch_live_status();
+                        if let Ok(mut live_status) = LIVE_STATUS.lock() {
+                            live_status.insert(server.id, status);
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to list the servers to ping: {}", e),
+            }
+            thread::sleep(interval);
+        });
+        info!("Started the server status monitor, pinging every {:?}", interval);
+    });
+}
+
+/// Returns the status the monitor last fetched for a server.
+pub fn get_live_status(server_id: u64) -> Option<LiveServerStatus> {
+    LIVE_STATUS
+        .lock()
+        .ok()
+        .and_then(|live_status| live_status.get(&server_id).cloned())
+}
+
+pub trait ServerPing {
+    /// Pings the server on the address and port of its `server.properties`.
+    fn ping_server(&self) -> Result<ServerPingResponse, Box<dyn Error>>;
+
+    /// Queries the server on its query port.
+    ///
+    /// # Errors
+    /// Returns an error if `enable-query` is not set, or the server does not answer.
+    fn query_server(&self) -> Result<QueryResponse, Box<dyn Error>>;
+
+    /// Pings the server, and queries it if the query is enabled.
+    fn fetch_live_status(&self) -> LiveServerStatus;
+
+    /// Returns the status the monitor last fetched, see [`start_status_monitor`].
+    fn get_live_status(&self) -> Option<LiveServerStatus>;
+}
+
+impl ServerPing for Server<u64> {
+    fn ping_server(&self) -> Result<ServerPingResponse, Box<dyn Error>> {
+        let properties = self.load_properties_file()?;
+        let port = properties
+            .get("server-port")
+            .and_then(|port| port.trim().parse().ok())
+            .unwrap_or(DEFAULT_SERVER_PORT);
+        ping(SocketAddr::new(local_host(&properties), port))
+    }
+
+    fn query_server(&self) -> Result<QueryResponse, Box<dyn Error>> {
+        let properties = self.load_properties_file()?;
+        if properties.get("enable-query").map(str::trim) != Some("true") {
+            return Err(format!("The query is not enabled for server {:?}", self.name).into());
+        }
+        // The query listens on the game port unless another one is set
+        let port = ["query.port", "server-port"]
+            .iter()
+            .find_map(|key| properties.get(key).and_then(|port| port.trim().parse().ok()))
+            .unwrap_or(DEFAULT_SERVER_PORT);
+        query(SocketAddr::new(local_host(&properties), port))
+    }
+
+    fn fetch_live_status(&self) -> LiveServerStatus {
+        let (ping, error) = match self.ping_server() {
+            Ok(ping) => (Some(ping), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let query_enabled = self
+            .load_properties_file()
+            .is_ok_and(|properties| properties.get("enable-query").map(str::trim) == Some("true"));
+        let query = if ping.is_some() && query_enabled {
+            self.query_server()
+                .map_err(|e| debug!("Failed to query server {:?}: {}", self.name, e))
+                .ok()
+        } else {
+            None
+        };
+        LiveServerStatus {
+            checked_at: SystemTime::now(),
+            ping,
+            query,
+            error,
+        }
+    }
+
+    fn get_live_status(&self) -> Option<LiveServerStatus> {
+        get_live_status(self.id)
+    }
+}
+
+/// Flattens a chat component, or a plain string in older versions, to its text.
+fn chat_to_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(components) => components.iter().map(chat_to_text).collect(),
+        Value::Object(component) => {
+            let mut text = component.get("text").and_then(Value::as_str).unwrap_or_default().to_string();
+            if let Some(Value::Array(extra)) = component.get("extra") {
+                text.extend(extra.iter().map(chat_to_text));
+            }
+            text
+        }
+        _ => String::new(),
+    }
+}
+
+/// Removes the `§` formatting codes of legacy text.
+fn strip_formatting(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+fn write_varint(buffer: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            buffer.push(value as u8);
+            return;
+        }
+        buffer.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<i32, Box<dyn Error>> {
+    let mut value = 0u32;
+    for position in 0..5 {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7F) as u32) << (7 * position);
+        if byte[0] & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err("VarInt is too long".into())
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    write_varint(buffer, value.len() as i32);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn write_packet(stream: &mut TcpStream, packet: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut framed = Vec::with_capacity(packet.len() + 5);
+    write_varint(&mut framed, packet.len() as i32);
+    framed.extend_from_slice(packet);
+    stream.write_all(&framed)?;
+    Ok(())
+}
+
+fn read_packet(stream: &mut impl Read) -> Result<Vec<u8>, Box<dyn Error>> {
+    let length = read_varint(stream)?;
+    if length <= 0 || length as usize > MAX_STATUS_LENGTH {
+        return Err(format!("Received a packet with an invalid length of {}", length).into());
+    }
+    let mut packet = vec![0u8; length as usize];
+    stream.read_exact(&mut packet)?;
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn varint(value: i32) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        write_varint(&mut buffer, value);
+        buffer
+    }
+
+    /// A status response packet holding some JSON.
+    fn status_packet(json: &str) -> Vec<u8> {
+        let mut packet = vec![0x00];
+        write_string(&mut packet, json);
+        packet
+    }
+
+    #[test]
+    fn varints_round_trip() -> Result<(), Box<dyn Error>> {
+        assert_eq!(varint(0), [0x00]);
+        assert_eq!(varint(127), [0x7f]);
+        assert_eq!(varint(128), [0x80, 0x01]);
+        assert_eq!(varint(25565), [0xdd, 0xc7, 0x01]);
+        assert_eq!(varint(-1), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        for value in [0, 1, 127, 128, 255, 25565, 2097151, i32::MAX, -1, i32::MIN] {
+            assert_eq!(read_varint(&mut varint(value).as_slice())?, value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_varints_are_refused() {
+        assert!(read_varint(&mut [0x80u8, 0x80, 0x80, 0x80, 0x80, 0x01].as_slice()).is_err());
+        assert!(read_varint(&mut [0x80u8].as_slice()).is_err());
+        assert!(read_varint(&mut [0u8; 0].as_slice()).is_err());
+    }
+
+    #[test]
+    fn packets_with_invalid_lengths_are_refused() {
+        for length in [0, -1, i32::MIN, MAX_STATUS_LENGTH as i32 + 1, i32::MAX] {
+            let mut packet = varint(length);
+            packet.extend_from_slice(&[0; 16]);
+            assert!(read_packet(&mut packet.as_slice()).is_err(), "{}", length);
+        }
+        let mut truncated = varint(10);
+        truncated.extend_from_slice(&[0; 9]);
+        assert!(read_packet(&mut truncated.as_slice()).is_err());
+    }
+
+    #[test]
+    fn malformed_status_responses_are_refused() {
+        assert!(parse_status(&[]).is_err());
+        assert!(parse_status(&[0x01, 0x02, b'{', b'}']).is_err());
+        assert!(parse_status(&status_packet("{\"players\":")).is_err());
+        // The JSON claims more bytes than the packet holds
+        assert!(parse_status(&[0x00, 0x7f, b'{', b'}']).is_err());
+        let mut negative = vec![0x00];
+        negative.extend_from_slice(&varint(-1));
+        negative.extend_from_slice(b"{}");
+        assert!(parse_status(&negative).is_err());
+    }
+
+    #[test]
+    fn status_responses_are_parsed() -> Result<(), Box<dyn Error>> {
+        let status = parse_status(&status_packet(
+            &json!({
+                "version": {"name": "Paper 1.21.1", "protocol": 767},
+                "players": {
+                    "online": 2,
+                    "max": 20,
+                    "sample": [{"name": "Steve", "id": "8667ba71-b85a-4004-af54-457a9734eed7"}]
+                },
+                "description": {"text": "§aA ", "extra": [{"text": "Minecraft"}, " §lServer"]},
+                "favicon": "data:image/png;base64,AAAA"
+            })
+            .to_string(),
+        ))?;
+        let response = ping_response(&status, 12);
+        assert_eq!(response.version_name, "Paper 1.21.1");
+        assert_eq!(response.protocol, 767);
+        assert_eq!((response.online_players, response.max_players), (2, 20));
+        assert_eq!(response.player_sample.len(), 1);
+        assert_eq!(response.player_sample[0].name, "Steve");
+        assert_eq!(response.motd, "A Minecraft Server");
+        assert_eq!(response.favicon.as_deref(), Some("data:image/png;base64,AAAA"));
+
+        // Older servers send the description as a string and may leave everything else out
+        let response = ping_response(&parse_status(&status_packet("{\"description\":\"§6Old server\"}"))?, 0);
+        assert_eq!(response.motd, "Old server");
+        assert_eq!((response.protocol, response.online_players), (-1, 0));
+        assert!(response.player_sample.is_empty());
+        Ok(())
+    }
+}
@@ -1,6 +1,18 @@
+use crate::audit_log::{acting_user, audit, AuditAction};
+use crate::crash_detection::{record_crash, ConsoleTail};
+use crate::eula::{read_eula_state, EulaState};
+use crate::event_bus::publish_status;
+use crate::java_runtimes::{check_java_compatibility, ServerJavaRuntime};
+use crate::jvm_flags::get_jvm_flag_settings;
+use crate::jvm_memory::{check_memory, MemorySettings};
+use crate::launch_templates::{launch_command, launch_template, run_launch_hooks, run_post_stop_hooks, LaunchHook};
+use crate::notifications::{notify_server, NotificationEvent, NotificationSeverity};
+use crate::online_players::{forget_online_players, track_online_players};
+use crate::permissions::{authorize, Capability};
 use crate::server::Server;
 use crate::server_database::ServerDatabase;
 use crate::server_status::ServerStatus;
+use crate::startup_watchdog::{begin_startup, detect_startup_complete};
 use crate::start_executable_type::{StartExecutableType, StartExecutableTypeExt};
 use lazy_static::lazy_static;
 use log::{debug, info, warn};
@@ -8,10 +20,11 @@ use std::clone::Clone;
 use std::error::Error;
 use std::io::{BufRead, Error as IoError};
 use std::io::{Read, Write};
-use std::process::{ChildStdin, ChildStdout, Stdio};
-use std::sync::{Arc, Mutex};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug)]
 struct RunningServerProcess {
@@ -23,15 +36,50 @@ struct RunningServerProcess {
     pub stdin: Option<ChildStdin>,
     /// Configuration related to the server's standard output stream.
     pub stdout: Option<ChildStdout>,
+    /// The spawned process, polled by the thread waiting for it to exit.
+    pub child: Arc<Mutex<Child>>,
+    /// Set once a stop or kill was requested, so the exit is not reported as a crash.
+    pub stop_requested: bool,
 }
 
+/// Receives the console lines of a server, and returns `false` once it wants no further lines.
+type ConsoleWatcher = Box<dyn FnMut(&str) -> bool + Send>;
+type ConsoleListener = Box<dyn Fn(u64, &str) + Send>;
+
 lazy_static! {
     static ref RUNNING_SERVERS: Arc<Mutex<Vec<Arc<Mutex<RunningServerProcess>>>>> = Arc::new(Mutex::new(Vec::new()));
+    static ref CONSOLE_WATCHERS: Mutex<Vec<(u64, ConsoleWatcher)>> = Mutex::new(Vec::new());
+    static ref CONSOLE_LISTENERS: Mutex<Vec<ConsoleListener>> = Mutex::new(Vec::new());
 }
 
+/// How long a server gets to save its worlds and exit after the `stop` command before it is killed.
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a killed process to be reaped.
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The message the game prints once `save-all` wrote every world to disk.
+const SAVE_COMPLETE_MESSAGE: &str = "Saved the game";
+
 pub trait ServerProcess {
     fn start_server(&mut self) -> Result<u64, Box<dyn Error>>;
     fn stop_server(&mut self) -> Result<u64, Box<dyn Error>>;
+    /// Stops the server gracefully by sending `stop`, and kills it if it is still running after `timeout`.
+    ///
+    /// Blocks until the process exited and returns its process id.
+    fn stop_server_with_timeout(&mut self, timeout: Duration) -> Result<u64, Box<dyn Error>>;
+    /// Kills the server process immediately, without giving it a chance to save.
+    ///
+    /// Returns the process id of the killed process.
+    fn kill_server(&mut self) -> Result<u64, Box<dyn Error>>;
+    /// Stops the server if it is running and starts it again, returning the new process id.
+    fn restart_server(&mut self) -> Result<u64, Box<dyn Error>>;
+    /// Returns whether a process of the server is currently running.
+    fn is_server_running(&self) -> bool;
+    /// Writes a command to the console of the server on behalf of the acting user.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not send commands, or the server is not running.
     fn send_command_to_server(&self, command: impl AsRef<str>) -> Result<(), Box<dyn Error>>;
     fn get_output(&self) -> Result<String, Box<dyn Error>>;
     fn attach_to_stdout(&self, on_line: impl FnMut(&str) -> bool + Send + Sync + 'static)
@@ -40,6 +88,8 @@ pub trait ServerProcess {
 
 impl ServerProcess for Server<u64> {
     fn start_server(&mut self) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+
         // Check if the server exists in the RUNNING_SERVERS array
         if let Ok(servers) = RUNNING_SERVERS.lock() {
             if servers
@@ -50,6 +100,22 @@ impl ServerProcess for Server<u64> {
             }
             
         }
+
+        // The game writes `eula=false` on its first start and refuses to start until it is accepted
+        if read_eula_state(&self.directory)? == EulaState::NotAccepted {
+            self.status = Some(ServerStatus::EulaRequired);
+            self.update()?;
+            publish_status(self);
+            return Err("The Minecraft EULA has to be accepted before the server can start".into());
+        }
+
+        // A heap larger than the machine starts fine and fails much later, so it is pointed out now
+        let memory = MemorySettings {
+            min_ram: self.min_ram,
+            max_ram: self.max_ram,
+        };
+        check_memory(&memory)?.warnings.iter().for_each(|warning| warn!("Server {:?}: {}", self.name, warning));
+
         // Clone the `start_script` and unwrap it safely; assumes `start_script` is always `Some`.
         let start_script = &self.start_script;
         let start_script = start_script
@@ -59,89 +125,21 @@ impl ServerProcess for Server<u64> {
         // Determine the type of executable based on the script path and handle errors if it fails.
         let start_executable_type = StartExecutableType::from_path(&start_script)?;
 
-        // Select the appropriate command or executable based on the determined type.
-        let program: &str = match start_executable_type {
-            StartExecutableType::Script => {
-                // Choose the shell command based on the current operating system.
-                if cfg!(target_os = "windows") {
-                    "cmd"
-                } else if cfg!(target_os = "linux") {
-                    "sh"
-                } else {
-                    // Return an error if the OS is unsupported for scripting.
-                    return Err(Box::new(IoError::new(
-                        std::io::ErrorKind::Other,
-                        "Unsupported OS for Script type",
-                    )));
-                }
-            }
-            StartExecutableType::Jar => {
-                // Check if Java runtime path is provided, otherwise return an error.
-                if let Some(jr) = &self.java_runtime {
-                    jr.to_str().ok_or_else(|| {
-                        Box::new(IoError::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Invalid Java runtime path",
-                        ))
-                    })?
-                } else {
-                    return Err(Box::new(IoError::new(
-                        std::io::ErrorKind::NotFound,
-                        "Java runtime not set",
-                    )));
-                }
+        // A runtime too old for the game only fails once the jar is loaded, with a class version error
+        if matches!(start_executable_type, StartExecutableType::Jar | StartExecutableType::ArgumentFile) {
+            if let Ok(runtime) = self.java_runtime_info() {
+                check_java_compatibility(&runtime, &self.minecraft_version)?;
             }
-            StartExecutableType::Executable =>
-            // Convert the executable path to a string and handle invalid data.
-            {
-                start_script
-                    .to_str()
-                    .ok_or_else(|| Box::new(IoError::new(std::io::ErrorKind::InvalidData, "Invalid executable path")))?
-            }
-        };
-
-        // Prepare to launch a new process using the determined executable or command.
-        let mut process = std::process::Command::new(program);
+        }
 
-        // Set the working directory for the process.
-        process.current_dir(&self.directory);
+        let launch = launch_template(self.id)?;
+        run_launch_hooks(self, LaunchHook::PreStart, &launch.pre_start)?;
 
-        // Add arguments to the process based on the type of start executable.
-        if start_executable_type == StartExecutableType::Script {
-            process.arg(start_script);
-        } else if start_executable_type == StartExecutableType::Jar {
-            if let Some(java_arg) = &self.java_arguments {
-                // Split Java arguments into separate tokens and handle errors.
-                match shell_words::split(java_arg) {
-                    Ok(args) => process.args(args),
-                    Err(_) => { 
-                        return Err(Box::new(IoError::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Invalid Java arguments",
-                        )))
-                    }
-                };
-            }
-            process.arg(format!("-Xms{}G", self.min_ram));
-            process.arg(format!("-Xmx{}G", self.max_ram));
-            
-
-            // Adding the -jar argument and the start script path to the command.
-            process.arg("-jar");
-            process.arg(start_script);
-            if let Some(minecraft_args) = &self.minecraft_arguments {
-                // Split Minecraft arguments into separate tokens and handle errors.
-                match shell_words::split(minecraft_args) {
-                    Ok(args) => process.args(args),
-                    Err(_) => {
-                        return Err(Box::new(IoError::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Invalid Minecraft arguments",
-                        )))
-                    }
-                };
-            }
-        }
+        let mut process = match &launch.command {
+            Some(template) => launch_command(self, template)?,
+            None => managed_command(self, &start_script, start_executable_type)?,
+        };
+        process.envs(&launch.environment);
 
         info!(
             "Running command: {} {}",
@@ -162,14 +160,19 @@ impl ServerProcess for Server<u64> {
 
         // Retrieve and return the process ID (PID) as a 64-bit integer.
         let pid = child.id();
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let child = Arc::new(Mutex::new(child));
 
         // Add server to the running servers list.
         match RUNNING_SERVERS.lock() {
             Ok(mut servers) => servers.push(Arc::new(Mutex::new(RunningServerProcess {
                 server_id: self.id,
                 pid: pid as u64,
-                stdin: child.stdin.take(),
-                stdout: child.stdout.take(),
+                stdin,
+                stdout,
+                child: Arc::clone(&child),
+                stop_requested: false,
             }))),
             Err(_) => {
                 return Err(Box::new(IoError::new(
@@ -179,89 +182,210 @@ impl ServerProcess for Server<u64> {
             }
         }
 
+        let console_tail = Arc::new(Mutex::new(ConsoleTail::default()));
+        let started_at = SystemTime::now();
+        let tail = Arc::clone(&console_tail);
+        let post_stop = launch.post_stop;
         let mut server_copy = self.clone();
         thread::spawn(move || {
-            // Run a loop while the child process is alive.
+            // Poll the child process until it has terminated, without keeping it locked for a kill.
             loop {
-                if let Ok(status) = child.wait() {
-                    // Exit loop if the process has terminated.
-                    info!("Server {:?} exited with status: {}", &server_copy.name, status);
-                    // remove server from running_server list
-                    if let Ok(mut servers) = RUNNING_SERVERS.lock() {
-                        debug!(
-                            "Removed server with id of {} from the running server list!",
-                            server_copy.id
-                        );
-                        servers.retain(|s| s.lock().map_or(true, |server| server.server_id != server_copy.id));
+                let exit = match child.lock() {
+                    Ok(mut child) => child.try_wait(),
+                    Err(_) => {
+                        warn!("Lost track of the process of server {:?}", &server_copy.name);
+                        break;
                     }
+                };
+                match exit {
+                    Ok(Some(status)) => {
+                        // Exit loop if the process has terminated.
+                        info!("Server {:?} exited with status: {}", &server_copy.name, status);
+                        // remove server from running_server list
+                        let mut stop_requested = false;
+                        if let Ok(mut servers) = RUNNING_SERVERS.lock() {
+                            debug!(
+                                "Removed server with id of {} from the running server list!",
+                                server_copy.id
+                            );
+                            servers.retain(|s| {
+                                s.lock().map_or(true, |server| {
+                                    if server.server_id == server_copy.id {
+                                        stop_requested = server.stop_requested;
+                                        return false;
+                                    }
+                                    true
+                                })
+                            });
+                        }
 
-                    server_copy.status = if status.success() {
-                        Some(ServerStatus::Offline)
-                    } else {
-                        Some(ServerStatus::Crashed)
-                    };
-                    if let Err(e) = server_copy.update() {
-                        warn!("Failed to update server status: {}", e);
+                        // Dropping the watchers lets anyone waiting for a line know that none will come
+                        if let Ok(mut watchers) = CONSOLE_WATCHERS.lock() {
+                            watchers.retain(|(id, _)| *id != server_copy.id);
+                        }
+                        forget_online_players(server_copy.id);
+
+                        // A first start only creates `eula.txt` and exits, waiting for the EULA to be accepted
+                        let eula_state = read_eula_state(&server_copy.directory).unwrap_or(EulaState::Accepted);
+                        server_copy.status = if stop_requested {
+                            Some(ServerStatus::Offline)
+                        } else if eula_state == EulaState::NotAccepted {
+                            info!("Server {:?} is waiting for the Minecraft EULA to be accepted", &server_copy.name);
+                            Some(ServerStatus::EulaRequired)
+                        } else if tail.lock().map_or(!status.success(), |tail| tail.indicates_crash(status.success())) {
+                            Some(ServerStatus::Crashed)
+                        } else {
+                            Some(ServerStatus::Offline)
+                        };
+                        let crash = match (&server_copy.status, tail.lock()) {
+                            (Some(ServerStatus::Crashed), Ok(tail)) => Some(record_crash(
+                                server_copy.id,
+                                &server_copy.directory,
+                                started_at,
+                                status.code(),
+                                &tail,
+                            )),
+                            _ => None,
+                        };
+                        server_copy.pid = None;
+                        if let Err(e) = server_copy.update() {
+                            warn!("Failed to update server status: {}", e);
+                        }
+                        publish_status(&server_copy);
+                        run_post_stop_hooks(&server_copy, &post_stop);
+                        if server_copy.status == Some(ServerStatus::Offline) {
+                            notify_server(
+                                server_copy.id,
+                                NotificationEvent::ServerStopped,
+                                NotificationSeverity::Info,
+                                "stopped",
+                                format!("The server exited with {}.", status),
+                            );
+                        }
+
+                        if let Some(delay) = crash.and_then(|crash| crash.restart_delay_seconds) {
+                            info!("Restarting server {:?} in {} seconds", &server_copy.name, delay);
+                            thread::sleep(Duration::from_secs(delay));
+                            // The server may have been started by hand in the meantime
+                            if !server_copy.is_server_running() {
+                                if let Err(e) = server_copy.start_server() {
+                                    warn!("Failed to restart server {:?}: {}", &server_copy.name, e);
+                                }
+                            }
+                        }
+                        break;
                     }
-                    break;
+                    Ok(None) => {}
+                    Err(err) => warn!("Failed to check whether server {:?} exited: {}", &server_copy.name, err),
                 }
                 // Add a small delay to prevent high CPU usage.
                 thread::sleep(Duration::from_millis(1000));
             }
         });
+        begin_startup(self.id);
+        track_online_players(self.id);
         let mut server_copy = self.clone();
-        self.attach_to_stdout(move |line| {
-            if line.contains("Done") && line.contains(r#"For help, type "help""#) {
+        let mut online = false;
+        read_stdout(self.id, move |line| {
+            // Keep reading after the server is online, the tail of the output is needed if it crashes
+            if let Ok(mut tail) = console_tail.lock() {
+                tail.push(line);
+            }
+            notify_console_watchers(server_copy.id, line);
+            if !online && detect_startup_complete(server_copy.id, line) {
+                online = true;
                 server_copy.status = Some(ServerStatus::Online);
 
                 if let Err(e) = server_copy.update() {
                     warn!("Failed to update server status: {}", e);
                 }
-
-                return false;
+                publish_status(&server_copy);
+                notify_server(
+                    server_copy.id,
+                    NotificationEvent::ServerStarted,
+                    NotificationSeverity::Info,
+                    "started",
+                    "The server is online.".to_string(),
+                );
             }
             true
         })?;
 
         self.status = Some(ServerStatus::Starting);
+        self.pid = Some(pid as u64);
         self.update()?;
+        publish_status(self);
+        audit(self.id, AuditAction::ServerStarted, self.name.clone(), None, Some(format!("Process {}", pid)));
 
         Ok(pid as u64)
     }
 
     fn stop_server(&mut self) -> Result<u64, Box<dyn Error>> {
+        self.stop_server_with_timeout(DEFAULT_STOP_TIMEOUT)
+    }
+
+    fn stop_server_with_timeout(&mut self, timeout: Duration) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        let process = find_running_server(self.id).ok_or("Server is not running")?;
+        let pid = request_stop(&process)?;
+
         self.status = Some(ServerStatus::Stopping);
         self.update()?;
-        todo!()
+        publish_status(self);
+
+        // The server saves its worlds before exiting, which can take a while for large worlds
+        if let Err(e) = send_server_command(self, "stop") {
+            warn!("Failed to send the stop command to server {}: {}", self.id, e);
+        }
+        if !wait_for_exit(self.id, timeout) {
+            warn!("Server {} did not stop within {:?}, killing it", self.id, timeout);
+            kill_process(&process)?;
+            if !wait_for_exit(self.id, KILL_TIMEOUT) {
+                return Err(format!("Server {} is still running after being killed", self.id).into());
+            }
+        }
+
+        // The exit itself was already recorded by the thread waiting for the process
+        self.status = Some(ServerStatus::Offline);
+        self.pid = None;
+        audit(self.id, AuditAction::ServerStopped, self.name.clone(), Some(format!("Process {}", pid)), None);
+        Ok(pid)
     }
 
-    fn send_command_to_server(&self, command: impl AsRef<str>) -> Result<(), Box<dyn Error>> {
-        if let Ok(servers) = RUNNING_SERVERS.lock() {
-            let server = servers
-                .iter()
-                .find(|s| s.lock().map(|server| server.server_id == self.id).unwrap_or(false))
-                .ok_or_else(|| IoError::new(std::io::ErrorKind::NotFound, "Server not found"))?;
+    fn kill_server(&mut self) -> Result<u64, Box<dyn Error>> {
+        authorize(self, Capability::ControlServer)?;
+        let process = find_running_server(self.id).ok_or("Server is not running")?;
+        let pid = request_stop(&process)?;
+        kill_process(&process)?;
+        if !wait_for_exit(self.id, KILL_TIMEOUT) {
+            return Err(format!("Server {} is still running after being killed", self.id).into());
+        }
 
-            if let Ok(mut server) = server.lock() {
-                return if let Some(stdin) = &mut server.stdin {
-                    writeln!(stdin, "{}", command.as_ref())?;
-                    Ok(())
-                } else {
-                    Err(Box::new(IoError::new(
-                        std::io::ErrorKind::BrokenPipe,
-                        "Stdin not available",
-                    )))
-                };
-            }
+        info!("Killed server {} with the process id {}", self.id, pid);
+        self.status = Some(ServerStatus::Offline);
+        self.pid = None;
+        audit(self.id, AuditAction::ServerKilled, self.name.clone(), Some(format!("Process {}", pid)), None);
+        Ok(pid)
+    }
+
+    fn restart_server(&mut self) -> Result<u64, Box<dyn Error>> {
+        if self.is_server_running() {
+            self.stop_server()?;
         }
+        self.start_server()
+    }
 
-        Err(Box::new(IoError::new(
-			std::io::ErrorKind::NotFound,
-			"Unknown error has occurred. Please try again later. If the problem persists, please contact the server administrator.",
-		)))
+    fn is_server_running(&self) -> bool {
+        find_running_server(self.id).is_some()
+    }
+
+    fn send_command_to_server(&self, command: impl AsRef<str>) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        send_server_command(self, command)
     }
 
     fn get_output(&self) -> Result<String, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
         return if let Ok(servers) = RUNNING_SERVERS.lock() {
             let server = servers
                 .iter()
@@ -298,42 +422,298 @@ impl ServerProcess for Server<u64> {
         &self,
         on_line: impl FnMut(&str) -> bool + Send + Sync + 'static,
     ) -> Result<(), Box<dyn Error>> {
-        if let Ok(servers) = RUNNING_SERVERS.lock() {
-            let server = servers
-                .iter()
-                .find(|s| s.lock().map(|server| server.server_id == self.id).unwrap_or(false))
-                .ok_or_else(|| IoError::new(std::io::ErrorKind::NotFound, "Server not found"))?;
+        authorize(self, Capability::ViewConsole)?;
+        read_stdout(self.id, on_line)
+    }
+}
 
-            if let Ok(mut server) = server.lock() {
-                if let Some(stdout) = server.stdout.take() {
-                    let on_line = Arc::new(Mutex::new(on_line));
-                    thread::spawn(move || {
-                        let on_line = Arc::clone(&on_line);
-                        let mut reader = std::io::BufReader::new(stdout);
-                        let mut buffer = String::new();
-
-                        loop {
-                            buffer.clear();
-                            match reader.read_line(&mut buffer) {
-                                Ok(0) => break, // EOF reached
-                                Ok(_) => {
-                                    if let Ok(mut callback) = on_line.lock() {
-                                        let should_continue = callback(buffer.trim_end());
-                                        if !should_continue {
-                                            break;
-                                        }
+/// Hands the output of a server the manager started to a callback on its own thread, until it
+/// returns `false`. Only the first caller gets the output, which is the server start.
+fn read_stdout(
+    server_id: u64,
+    on_line: impl FnMut(&str) -> bool + Send + Sync + 'static,
+) -> Result<(), Box<dyn Error>> {
+    if let Ok(servers) = RUNNING_SERVERS.lock() {
+        let server = servers
+            .iter()
+            .find(|s| s.lock().map(|server| server.server_id == server_id).unwrap_or(false))
+            .ok_or_else(|| IoError::new(std::io::ErrorKind::NotFound, "Server not found"))?;
+
+        if let Ok(mut server) = server.lock() {
+            if let Some(stdout) = server.stdout.take() {
+                let on_line = Arc::new(Mutex::new(on_line));
+                thread::spawn(move || {
+                    let on_line = Arc::clone(&on_line);
+                    let mut reader = std::io::BufReader::new(stdout);
+                    let mut buffer = String::new();
+
+                    loop {
+                        buffer.clear();
+                        match reader.read_line(&mut buffer) {
+                            Ok(0) => break, // EOF reached
+                            Ok(_) => {
+                                if let Ok(mut callback) = on_line.lock() {
+                                    let should_continue = callback(buffer.trim_end());
+                                    if !should_continue {
+                                        break;
                                     }
                                 }
-                                Err(err) => {
-                                    warn!("Error reading stdout: {}", err);
-                                    break;
-                                }
+                            }
+                            Err(err) => {
+                                warn!("Error reading stdout: {}", err);
+                                break;
                             }
                         }
-                    });
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a command to the console of a server the manager started, without checking what the
+/// acting user may do. The callers check it themselves: the ban list, world settings and player
+/// actions check before sending, while backups, scheduled tasks, automations and scripts check when
+/// they are set up and then run without an acting user.
+pub(crate) fn send_server_command(server: &Server<u64>, command: impl AsRef<str>) -> Result<(), Box<dyn Error>> {
+    let server_id = server.id;
+    if let Ok(servers) = RUNNING_SERVERS.lock() {
+        let server = servers
+            .iter()
+            .find(|s| s.lock().map(|server| server.server_id == server_id).unwrap_or(false))
+            .ok_or_else(|| IoError::new(std::io::ErrorKind::NotFound, "Server not found"))?;
+
+        if let Ok(mut server) = server.lock() {
+            return if let Some(stdin) = &mut server.stdin {
+                writeln!(stdin, "{}", command.as_ref())?;
+                // The commands the manager sends itself, like the tick rate probes, would flood the log
+                if acting_user().is_some() {
+                    audit(server_id, AuditAction::CommandSent, command.as_ref(), None, None);
                 }
+                Ok(())
+            } else {
+                Err(Box::new(IoError::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "Stdin not available",
+                )))
+            };
+        }
+    }
+
+    Err(Box::new(IoError::new(
+			std::io::ErrorKind::NotFound,
+			"Unknown error has occurred. Please try again later. If the problem persists, please contact the server administrator.",
+		)))
+}
+
+/// Passes every console line a server started by the manager prints to `watcher`, until it
+/// returns `false` or the server exits.
+pub(crate) fn watch_console(server_id: u64, watcher: impl FnMut(&str) -> bool + Send + 'static) {
+    if let Ok(mut watchers) = CONSOLE_WATCHERS.lock() {
+        watchers.push((server_id, Box::new(watcher)));
+    }
+}
+
+/// Registers a listener that is invoked with the id of the server for every console line a server
+/// started by the manager prints. It runs on the thread reading the console, so it should not block.
+pub fn add_console_listener(listener: impl Fn(u64, &str) + Send + 'static) {
+    if let Ok(mut listeners) = CONSOLE_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+/// Sends `save-all flush` to a server started by the manager and waits for its console to confirm
+/// that every world was written to disk.
+///
+/// # Returns
+/// `false` if the server did not confirm the save within `timeout`.
+///
+/// # Errors
+/// Returns an error if the command cannot be sent.
+pub(crate) fn save_worlds(server: &Server<u64>, timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    let (saved, wait_for_save) = mpsc::channel();
+    watch_console(server.id, move |line| {
+        if line.contains(SAVE_COMPLETE_MESSAGE) {
+            let _ = saved.send(());
+            return false;
+        }
+        true
+    });
+    send_server_command(server, "save-all flush")?;
+    Ok(wait_for_save.recv_timeout(timeout).is_ok())
+}
+
+/// Builds the command the manager starts a server with when it has no launch template.
+fn managed_command(
+    server: &Server<u64>,
+    start_script: &Path,
+    start_executable_type: StartExecutableType,
+) -> Result<Command, Box<dyn Error>> {
+    // Select the appropriate command or executable based on the determined type.
+    let program: &str = match start_executable_type {
+        StartExecutableType::Script => {
+            // Choose the shell command based on the current operating system.
+            if cfg!(target_os = "windows") {
+                "cmd"
+            } else if cfg!(target_os = "linux") {
+                "sh"
+            } else {
+                // Return an error if the OS is unsupported for scripting.
+                return Err(Box::new(IoError::new(
+                    std::io::ErrorKind::Other,
+                    "Unsupported OS for Script type",
+                )));
             }
         }
-        Ok(())
+        StartExecutableType::Jar | StartExecutableType::ArgumentFile => {
+            // Check if Java runtime path is provided, otherwise return an error.
+            if let Some(jr) = &server.java_runtime {
+                jr.to_str().ok_or_else(|| {
+                    Box::new(IoError::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Invalid Java runtime path",
+                    ))
+                })?
+            } else {
+                return Err(Box::new(IoError::new(
+                    std::io::ErrorKind::NotFound,
+                    "Java runtime not set",
+                )));
+            }
+        }
+        StartExecutableType::Executable =>
+        // Convert the executable path to a string and handle invalid data.
+        {
+            start_script
+                .to_str()
+                .ok_or_else(|| Box::new(IoError::new(std::io::ErrorKind::InvalidData, "Invalid executable path")))?
+        }
+    };
+
+    // Prepare to launch a new process using the determined executable or command.
+    let mut process = Command::new(program);
+
+    // Set the working directory for the process.
+    process.current_dir(&server.directory);
+
+    // Add arguments to the process based on the type of start executable.
+    if start_executable_type == StartExecutableType::Script {
+        process.arg(start_script);
+    } else if start_executable_type == StartExecutableType::Jar
+        || start_executable_type == StartExecutableType::ArgumentFile
+    {
+        // The flags of the preset come first, so the Java arguments of the server override them
+        process.args(get_jvm_flag_settings(server.id)?.flags(server.max_ram));
+        if let Some(java_arg) = &server.java_arguments {
+            // Split Java arguments into separate tokens and handle errors.
+            match shell_words::split(java_arg) {
+                Ok(args) => process.args(args),
+                Err(_) => { 
+                    return Err(Box::new(IoError::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Invalid Java arguments",
+                    )))
+                }
+            };
+        }
+        process.arg(format!("-Xms{}G", server.min_ram));
+        process.arg(format!("-Xmx{}G", server.max_ram));
+        
+
+        if start_executable_type == StartExecutableType::ArgumentFile {
+            // The argument file holds the class path and main class, Java expands `@file` itserver.
+            process.arg(format!("@{}", start_script.display()));
+        } else {
+            // Adding the -jar argument and the start script path to the command.
+            process.arg("-jar");
+            process.arg(start_script);
+        }
+        if let Some(minecraft_args) = &server.minecraft_arguments {
+            // Split Minecraft arguments into separate tokens and handle errors.
+            match shell_words::split(minecraft_args) {
+                Ok(args) => process.args(args),
+                Err(_) => {
+                    return Err(Box::new(IoError::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Invalid Minecraft arguments",
+                    )))
+                }
+            };
+        }
+    }
+    Ok(process)
+}
+
+fn notify_console_watchers(server_id: u64, line: &str) {
+    if let Ok(mut watchers) = CONSOLE_WATCHERS.lock() {
+        watchers.retain_mut(|(id, watcher)| *id != server_id || watcher(line));
+    }
+    if let Ok(listeners) = CONSOLE_LISTENERS.lock() {
+        listeners.iter().for_each(|listener| listener(server_id, line));
+    }
+}
+
+/// Finds the running process of a server.
+fn find_running_server(server_id: u64) -> Option<Arc<Mutex<RunningServerProcess>>> {
+    let servers = RUNNING_SERVERS.lock().ok()?;
+    servers
+        .iter()
+        .find(|s| s.lock().map(|server| server.server_id == server_id).unwrap_or(false))
+        .cloned()
+}
+
+/// The servers started by the manager that are running, with the id of their process.
+pub(crate) fn running_server_pids() -> Vec<(u64, u64)> {
+    let Ok(servers) = RUNNING_SERVERS.lock() else {
+        return Vec::new();
+    };
+    servers
+        .iter()
+        .filter_map(|s| s.lock().ok().map(|server| (server.server_id, server.pid)))
+        .collect()
+}
+
+/// Marks a process as being stopped on purpose and returns its process id.
+fn request_stop(process: &Arc<Mutex<RunningServerProcess>>) -> Result<u64, Box<dyn Error>> {
+    let mut process = process
+        .lock()
+        .map_err(|_| IoError::new(std::io::ErrorKind::Other, "Failed to lock the server process"))?;
+    process.stop_requested = true;
+    Ok(process.pid)
+}
+
+fn kill_process(process: &Arc<Mutex<RunningServerProcess>>) -> Result<(), Box<dyn Error>> {
+    let child = process
+        .lock()
+        .map(|process| Arc::clone(&process.child))
+        .map_err(|_| IoError::new(std::io::ErrorKind::Other, "Failed to lock the server process"))?;
+    let mut child = child
+        .lock()
+        .map_err(|_| IoError::new(std::io::ErrorKind::Other, "Failed to lock the child process"))?;
+    match child.kill() {
+        Ok(()) => Ok(()),
+        // The process already exited on its own
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Kills a server that stopped responding, without marking it as stopped on purpose, so the exit
+/// is recorded as a crash.
+pub(crate) fn kill_unresponsive_server(server_id: u64) -> Result<(), Box<dyn Error>> {
+    let process = find_running_server(server_id).ok_or("Server is not running")?;
+    kill_process(&process)
+}
+
+/// Waits until the process of a server was reaped, returning `false` if it is still running after `timeout`.
+fn wait_for_exit(server_id: u64, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while find_running_server(server_id).is_some() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(250));
     }
+    true
 }
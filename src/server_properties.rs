@@ -1,4 +1,8 @@
+use crate::audit_log::{audit, AuditAction};
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
 use crate::server::Server;
+use crate::server_properties_file::{ServerPropertiesFile, ServerSettings};
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::Write;
@@ -18,11 +22,12 @@ pub trait ServerProperties {
     /// Retrieves all properties from the properties file.
     ///
     /// The properties are returned as a key-value pair stored in a `HashMap`.
-    /// Each key and its associated value represent a property stored in the file.
+    /// Each key and its associated value represent a property stored in the file. They include
+    /// secrets like `rcon.password`, so the acting user has to be allowed to edit files.
     ///
     /// # Errors
-    /// Returns an error if the properties file cannot be read (e.g., due to being missing,
-    /// corrupted, or file access errors).
+    /// Returns an error if the acting user may not edit files, or the properties file cannot be
+    /// read (e.g., due to being missing, corrupted, or file access errors).
     ///
     /// # Returns
     /// A `HashMap` containing all properties as key-value pairs.
@@ -37,8 +42,8 @@ pub trait ServerProperties {
     /// - `key`: The key of the property to retrieve.
     ///
     /// # Errors
-    /// Returns an error if there is an issue reading the properties or if the key
-    /// does not exist.
+    /// Returns an error if the acting user may not edit files, there is an issue reading the
+    /// properties or if the key does not exist.
     ///
     /// # Returns
     /// A `String` containing the value of the requested property.
@@ -81,6 +86,42 @@ pub trait ServerProperties {
     /// object.set_property_range(properties)?;
     /// ```
     fn set_property_range(&self, values: HashMap<String, String>) -> Result<(), Box<dyn Error>>;
+
+    /// Loads `server.properties` as an editable file that keeps its comments and unknown properties.
+    /// It does not check the acting user, the features reading a setting from it check their own
+    /// capability.
+    ///
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read.
+    ///
+    /// # Returns
+    /// The parsed file, without any properties if the file does not exist yet.
+    fn load_properties_file(&self) -> Result<ServerPropertiesFile, Box<dyn Error>>;
+
+    /// Writes an edited `server.properties` atomically.
+    ///
+    /// # Parameters
+    /// - `properties`: The file to write, usually loaded with `load_properties_file`.
+    ///
+    /// # Errors
+    /// Returns an error if the file was changed on disk after it was loaded, or writing fails.
+    fn save_properties_file(&self, properties: &ServerPropertiesFile) -> Result<(), Box<dyn Error>>;
+
+    /// Reads the commonly edited settings, such as the port, game mode and view distance, as typed values.
+    ///
+    /// # Errors
+    /// Returns an error if the properties file cannot be read.
+    fn get_server_settings(&self) -> Result<ServerSettings, Box<dyn Error>>;
+
+    /// Validates and applies the settings that are set, leaving every other property unchanged.
+    ///
+    /// # Parameters
+    /// - `settings`: The settings to change, `None` fields are left as they are.
+    ///
+    /// # Errors
+    /// Returns an error if a setting is invalid, in which case nothing is written, or the file
+    /// cannot be read or written.
+    fn update_server_settings(&self, settings: &ServerSettings) -> Result<(), Box<dyn Error>>;
 }
 
 impl ServerProperties for Server<u64> {
@@ -103,39 +144,16 @@ impl ServerProperties for Server<u64> {
     }
 
     fn get_properties(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
-        // Create a mutable HashMap to store key-value pairs read from the file
-        let mut values: HashMap<String, String> = HashMap::new();
-
-        // Define the path to the file "server.properties" within the specified directory
-        let file_path = self.directory.join("server.properties");
+        authorize(self, Capability::EditFiles)?;
 
-        // If the file does not exist, return the empty HashMap
-        if !file_path.exists() {
-            return Ok(values);
-        }
-
-        // Read the contents of the file as a string
-        let file_contents = std::fs::read_to_string(file_path)?;
-
-        // Iterate over each line in the file, splitting it into key-value pairs
-        for line in file_contents.lines() {
-            // Remove any leading or trailing whitespace from the current line
-            let line = line.trim();
-
-            // Skip lines that start with '#' as they are comments
-            if line.starts_with('#') {
-                continue;
-            }
-
-            // Attempt to split the line into a key-value pair at the first '=' character
-            if let Some((key, value)) = line.split_once('=') {
-                // Trim whitespace from both the key and value, and insert them into the HashMap
-                values.insert(key.trim().to_string(), value.trim().to_string());
-            }
-        }
+        // Parse the file with the structured editor so escaped and continued values are read correctly
+        let properties = self.load_properties_file()?;
 
-        // Return the populated HashMap of key-value pairs
-        Ok(values)
+        // Collect the properties into a HashMap, later duplicates win like they do for the server
+        Ok(properties
+            .entries()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect())
     }
 
     fn get_property(&self, key: &str) -> Result<String, Box<dyn Error>> {
@@ -150,32 +168,74 @@ impl ServerProperties for Server<u64> {
     }
 
     fn set_property(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
-        // Retrieve the current server properties as a mutable HashMap
-        let mut properties = self.get_properties()?;
-
-        // Insert the new property key-value pair into the HashMap
-        properties.insert(key.to_string(), value.to_string());
+        // Load the current file, keeping its comments and the order of its properties
+        let mut properties = self.load_properties_file()?;
 
-        // Save the updated properties back to the "server.properties" file in the specified directory
-        save_properties(&properties, self.directory.join("server.properties"))?;
+        // Update the property, values of known properties are validated
+        properties.set(key, value)?;
 
-        // Indicate that the operation completed successfully
-        Ok(())
+        // Write the file back, the previous file is kept as a version so the change can be rolled back
+        self.save_properties_file(&properties)
     }
 
     fn set_property_range(&self, values: HashMap<String, String>) -> Result<(), Box<dyn Error>> {
-        // Retrieve the current server properties as a mutable HashMap
-        let mut properties = self.get_properties()?;
+        // Load the current file, keeping its comments and the order of its properties
+        let mut properties = self.load_properties_file()?;
+
+        // Update every property, the file is only written if all values are valid
+        for (key, value) in &values {
+            properties.set(key, value)?;
+        }
 
-        // Extend the existing properties HashMap with the new key-value pairs
-        properties.extend(values);
+        // Write the file back, the previous file is kept as a version so the change can be rolled back
+        self.save_properties_file(&properties)
+    }
+
+    fn load_properties_file(&self) -> Result<ServerPropertiesFile, Box<dyn Error>> {
+        ServerPropertiesFile::load(&SandboxedPath::new(&self.directory, "server.properties")?)
+    }
 
-        // Save the updated properties back to the "server.properties" file in the specified directory
-        save_properties(&properties, self.directory.join("server.properties"))?;
+    fn save_properties_file(&self, properties: &ServerPropertiesFile) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let file = SandboxedPath::new(&self.directory, "server.properties")?;
+        let previous = ServerPropertiesFile::load(&file).unwrap_or_default();
+        properties.save(&file)?;
 
-        // Indicate that the operation completed successfully
+        // Only the properties that changed are worth keeping in the audit log
+        let (before, after) = changed_properties(&previous, properties);
+        if !before.is_empty() || !after.is_empty() {
+            audit(self.id, AuditAction::ConfigChanged, "server.properties", Some(before), Some(after));
+        }
         Ok(())
     }
+
+    fn get_server_settings(&self) -> Result<ServerSettings, Box<dyn Error>> {
+        Ok(self.load_properties_file()?.settings())
+    }
+
+    fn update_server_settings(&self, settings: &ServerSettings) -> Result<(), Box<dyn Error>> {
+        let mut properties = self.load_properties_file()?;
+        properties.apply(settings)?;
+        self.save_properties_file(&properties)
+    }
+}
+
+/// Lists the properties that differ between two files as `key=value` pairs, once with their old
+/// and once with their new values. A property missing on one side is left out of that side.
+fn changed_properties(previous: &ServerPropertiesFile, current: &ServerPropertiesFile) -> (String, String) {
+    let old: HashMap<&str, &str> = previous.entries().collect();
+    let new: HashMap<&str, &str> = current.entries().collect();
+    let mut keys: Vec<&str> =
+        old.keys().chain(new.keys()).copied().filter(|key| old.get(key) != new.get(key)).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    let side = |values: &HashMap<&str, &str>| {
+        keys.iter()
+            .filter_map(|key| values.get(key).map(|value| format!("{}={}", key, value)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    (side(&old), side(&new))
 }
 
 /// Saves a HashMap of properties to a file in the Minecraft server properties format.
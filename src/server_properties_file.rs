@@ -0,0 +1,592 @@
+use crate::sandboxed_path::SandboxedPath;
+use crate::text_file::{read_text_file, write_text_file, LineEnding, TextEncoding, WriteTextOptions};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// The largest `server.properties` that is loaded, real files are a few kilobytes.
+const MAX_PROPERTIES_SIZE: u64 = 1024 * 1024;
+
+/// The values a known property accepts.
+#[derive(Debug, Clone, Copy)]
+enum PropertyKind {
+    Bool,
+    Integer { min: i64, max: i64 },
+    Port,
+    GameMode,
+    Difficulty,
+}
+
+/// The properties of the vanilla server whose values are checked. Unknown and free text
+/// properties accept any value.
+const KNOWN_PROPERTIES: &[(&str, PropertyKind)] = &[
+    ("accepts-transfers", PropertyKind::Bool),
+    ("allow-flight", PropertyKind::Bool),
+    ("allow-nether", PropertyKind::Bool),
+    ("broadcast-console-to-ops", PropertyKind::Bool),
+    ("broadcast-rcon-to-ops", PropertyKind::Bool),
+    ("difficulty", PropertyKind::Difficulty),
+    ("enable-command-block", PropertyKind::Bool),
+    ("enable-jmx-monitoring", PropertyKind::Bool),
+    ("enable-query", PropertyKind::Bool),
+    ("enable-rcon", PropertyKind::Bool),
+    ("enable-status", PropertyKind::Bool),
+    ("enforce-secure-profile", PropertyKind::Bool),
+    ("enforce-whitelist", PropertyKind::Bool),
+    ("entity-broadcast-range-percentage", PropertyKind::Integer { min: 10, max: 1000 }),
+    ("force-gamemode", PropertyKind::Bool),
+    ("function-permission-level", PropertyKind::Integer { min: 1, max: 4 }),
+    ("gamemode", PropertyKind::GameMode),
+    ("generate-structures", PropertyKind::Bool),
+    ("hardcore", PropertyKind::Bool),
+    ("hide-online-players", PropertyKind::Bool),
+    ("log-ips", PropertyKind::Bool),
+    ("max-chained-neighbor-updates", PropertyKind::Integer { min: -1, max: i32::MAX as i64 }),
+    ("max-players", PropertyKind::Integer { min: 0, max: i32::MAX as i64 }),
+    ("max-tick-time", PropertyKind::Integer { min: -1, max: i64::MAX }),
+    ("max-world-size", PropertyKind::Integer { min: 1, max: 29_999_984 }),
+    ("network-compression-threshold", PropertyKind::Integer { min: -1, max: i32::MAX as i64 }),
+    ("online-mode", PropertyKind::Bool),
+    ("op-permission-level", PropertyKind::Integer { min: 0, max: 4 }),
+    ("player-idle-timeout", PropertyKind::Integer { min: 0, max: i32::MAX as i64 }),
+    ("prevent-proxy-connections", PropertyKind::Bool),
+    ("pvp", PropertyKind::Bool),
+    ("query.port", PropertyKind::Port),
+    ("rate-limit", PropertyKind::Integer { min: 0, max: i32::MAX as i64 }),
+    ("rcon.port", PropertyKind::Port),
+    ("require-resource-pack", PropertyKind::Bool),
+    ("server-port", PropertyKind::Port),
+    ("simulation-distance", PropertyKind::Integer { min: 3, max: 32 }),
+    ("spawn-monsters", PropertyKind::Bool),
+    ("spawn-protection", PropertyKind::Integer { min: 0, max: i32::MAX as i64 }),
+    ("sync-chunk-writes", PropertyKind::Bool),
+    ("use-native-transport", PropertyKind::Bool),
+    ("view-distance", PropertyKind::Integer { min: 3, max: 32 }),
+    ("white-list", PropertyKind::Bool),
+];
+
+/// The game mode new players start in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GameMode {
+    Survival,
+    Creative,
+    Adventure,
+    Spectator,
+}
+
+impl GameMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GameMode::Survival => "survival",
+            GameMode::Creative => "creative",
+            GameMode::Adventure => "adventure",
+            GameMode::Spectator => "spectator",
+        }
+    }
+}
+
+impl FromStr for GameMode {
+    type Err = String;
+
+    /// Parses a game mode by name, or by the numeric id older versions wrote.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "survival" | "0" => Ok(GameMode::Survival),
+            "creative" | "1" => Ok(GameMode::Creative),
+            "adventure" | "2" => Ok(GameMode::Adventure),
+            "spectator" | "3" => Ok(GameMode::Spectator),
+            other => Err(format!("{} is not a game mode", other)),
+        }
+    }
+}
+
+/// The difficulty of the world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Peaceful,
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Difficulty::Peaceful => "peaceful",
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    /// Parses a difficulty by name, or by the numeric id older versions wrote.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "peaceful" | "0" => Ok(Difficulty::Peaceful),
+            "easy" | "1" => Ok(Difficulty::Easy),
+            "normal" | "2" => Ok(Difficulty::Normal),
+            "hard" | "3" => Ok(Difficulty::Hard),
+            other => Err(format!("{} is not a difficulty", other)),
+        }
+    }
+}
+
+/// The commonly edited settings of `server.properties` as typed values.
+///
+/// Every field is optional: when reading, `None` means the property is missing or invalid, and when
+/// applying, `None` leaves the property unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings {
+    pub server_ip: Option<String>,
+    pub server_port: Option<u16>,
+    pub motd: Option<String>,
+    pub max_players: Option<u32>,
+    pub gamemode: Option<GameMode>,
+    pub force_gamemode: Option<bool>,
+    pub difficulty: Option<Difficulty>,
+    pub hardcore: Option<bool>,
+    pub pvp: Option<bool>,
+    pub allow_flight: Option<bool>,
+    pub view_distance: Option<u32>,
+    pub simulation_distance: Option<u32>,
+    pub spawn_protection: Option<u32>,
+    pub level_name: Option<String>,
+    pub level_seed: Option<String>,
+    pub level_type: Option<String>,
+    pub generator_settings: Option<String>,
+    pub generate_structures: Option<bool>,
+    pub online_mode: Option<bool>,
+    pub white_list: Option<bool>,
+    pub enforce_whitelist: Option<bool>,
+    pub enable_command_block: Option<bool>,
+    pub enable_rcon: Option<bool>,
+    pub rcon_port: Option<u16>,
+    pub enable_query: Option<bool>,
+    pub query_port: Option<u16>,
+}
+
+/// A property whose value the server would reject or ignore.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PropertyIssue {
+    pub key: String,
+    pub value: String,
+    pub message: String,
+}
+
+/// A line of the file. Comments, blank lines and untouched properties keep their original text.
+#[derive(Debug, Clone)]
+enum PropertyLine {
+    Other(String),
+    Property {
+        key: String,
+        value: String,
+        /// The original text of the property, `None` once the value was changed.
+        raw: Option<String>,
+    },
+}
+
+/// An editable `server.properties` file.
+///
+/// Edits only rewrite the lines of the properties that changed, so comments, the order of the
+/// properties, and properties this type does not know about, such as those added by mods, survive
+/// a round trip. Values are read and written with the escaping of Java properties files.
+#[derive(Debug, Clone)]
+pub struct ServerPropertiesFile {
+    lines: Vec<PropertyLine>,
+    encoding: TextEncoding,
+    line_ending: LineEnding,
+    last_modified: Option<SystemTime>,
+}
+
+impl Default for ServerPropertiesFile {
+    fn default() -> Self {
+        Self {
+            lines: Vec::new(),
+            encoding: TextEncoding::Utf8,
+            line_ending: LineEnding::Lf,
+            last_modified: None,
+        }
+    }
+}
+
+impl ServerPropertiesFile {
+    /// Parses the contents of a properties file.
+    pub fn parse(content: &str) -> Self {
+        let mut lines = Vec::new();
+        let mut physical_lines = content.lines();
+        while let Some(line) = physical_lines.next() {
+            let mut raw = line.to_string();
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('!') {
+                lines.push(PropertyLine::Other(raw));
+                continue;
+            }
+            // A line ending in an odd number of backslashes continues on the next line
+            while ends_with_continuation(&raw) {
+                match physical_lines.next() {
+                    Some(next) => {
+                        raw.push('\n');
+                        raw.push_str(next);
+                    }
+                    None => break,
+                }
+            }
+            let (key, value) = parse_property(&raw);
+            lines.push(PropertyLine::Property {
+                key,
+                value,
+                raw: Some(raw),
+            });
+        }
+        Self {
+            lines,
+            ..Default::default()
+        }
+    }
+
+    /// Loads a properties file, keeping its encoding and line endings for saving it again.
+    ///
+    /// A missing file is loaded as a file without properties.
+    ///
+    /// # Errors
+    /// Returns an error if the file exists but cannot be read as text.
+    pub fn load(file: &SandboxedPath) -> Result<Self, Box<dyn Error>> {
+        if !file.path().exists() {
+            return Ok(Self::default());
+        }
+        let text = read_text_file(file, MAX_PROPERTIES_SIZE)?;
+        let mut properties = Self::parse(&text.content);
+        properties.encoding = text.encoding;
+        properties.line_ending = text.line_ending;
+        properties.last_modified = Some(text.last_modified);
+        Ok(properties)
+    }
+
+    /// Writes the properties back atomically, storing the previous file as a version.
+    ///
+    /// # Errors
+    /// Returns an error if the file was changed on disk since it was loaded, or writing fails.
+    pub fn save(&self, file: &SandboxedPath) -> Result<(), Box<dyn Error>> {
+        let options = WriteTextOptions {
+            encoding: self.encoding,
+            line_ending: self.line_ending,
+            expected_modified: self.last_modified,
+        };
+        write_text_file(file, &self.to_string(), &options)
+    }
+
+    /// The value of a property.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines.iter().rev().find_map(|line| match line {
+            PropertyLine::Property { key: name, value, .. } if name == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// All properties in the order of the file.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|line| match line {
+            PropertyLine::Property { key, value, .. } => Some((key.as_str(), value.as_str())),
+            PropertyLine::Other(_) => None,
+        })
+    }
+
+    /// Sets a property, appending it to the file if it does not exist yet.
+    ///
+    /// # Errors
+    /// Returns an error if the value is not valid for a known property, for example a port out of range.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let key = key.trim();
+        if key.is_empty() {
+            return Err("The property name cannot be empty".into());
+        }
+        validate_property(key, value).map_err(|message| format!("Invalid value for {}: {}", key, message))?;
+
+        let existing = self.lines.iter_mut().rev().find_map(|line| match line {
+            PropertyLine::Property { key: name, value, raw } if name == key => Some((value, raw)),
+            _ => None,
+        });
+        match existing {
+            Some((existing, _)) if existing.as_str() == value => {}
+            Some((existing, raw)) => {
+                *existing = value.to_string();
+                *raw = None;
+            }
+            None => self.lines.push(PropertyLine::Property {
+                key: key.to_string(),
+                value: value.to_string(),
+                raw: None,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Removes a property.
+    ///
+    /// # Returns
+    /// The removed value, if the property existed.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let value = self.get(key).map(str::to_string);
+        self.lines
+            .retain(|line| !matches!(line, PropertyLine::Property { key: name, .. } if name == key));
+        value
+    }
+
+    /// Reads the commonly edited settings as typed values.
+    pub fn settings(&self) -> ServerSettings {
+        let text = |key: &str| self.get(key).map(str::to_string);
+        let parse_bool = |key: &str| self.get(key).and_then(|value| value.trim().parse::<bool>().ok());
+        let parse_u32 = |key: &str| self.get(key).and_then(|value| value.trim().parse::<u32>().ok());
+        let parse_port = |key: &str| {
+            self.get(key)
+                .and_then(|value| value.trim().parse::<u16>().ok())
+                .filter(|port| *port != 0)
+        };
+        ServerSettings {
+            server_ip: text("server-ip"),
+            server_port: parse_port("server-port"),
+            motd: text("motd"),
+            max_players: parse_u32("max-players"),
+            gamemode: self.get("gamemode").and_then(|value| value.parse().ok()),
+            force_gamemode: parse_bool("force-gamemode"),
+            difficulty: self.get("difficulty").and_then(|value| value.parse().ok()),
+            hardcore: parse_bool("hardcore"),
+            pvp: parse_bool("pvp"),
+            allow_flight: parse_bool("allow-flight"),
+            view_distance: parse_u32("view-distance"),
+            simulation_distance: parse_u32("simulation-distance"),
+            spawn_protection: parse_u32("spawn-protection"),
+            level_name: text("level-name"),
+            level_seed: text("level-seed"),
+            level_type: text("level-type"),
+            generator_settings: text("generator-settings"),
+            generate_structures: parse_bool("generate-structures"),
+            online_mode: parse_bool("online-mode"),
+            white_list: parse_bool("white-list"),
+            enforce_whitelist: parse_bool("enforce-whitelist"),
+            enable_command_block: parse_bool("enable-command-block"),
+            enable_rcon: parse_bool("enable-rcon"),
+            rcon_port: parse_port("rcon.port"),
+            enable_query: parse_bool("enable-query"),
+            query_port: parse_port("query.port"),
+        }
+    }
+
+    /// Applies every setting that is set, leaving the others unchanged.
+    ///
+    /// All values are validated before any is applied, so an invalid setting changes nothing.
+    ///
+    /// # Errors
+    /// Returns an error listing every invalid value.
+    pub fn apply(&mut self, settings: &ServerSettings) -> Result<(), Box<dyn Error>> {
+        let mut values: Vec<(&str, String)> = Vec::new();
+        let mut push = |key: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                values.push((key, value));
+            }
+        };
+        push("server-ip", settings.server_ip.clone());
+        push("server-port", settings.server_port.map(|port| port.to_string()));
+        push("motd", settings.motd.clone());
+        push("max-players", settings.max_players.map(|players| players.to_string()));
+        push("gamemode", settings.gamemode.map(|gamemode| gamemode.as_str().to_string()));
+        push("force-gamemode", settings.force_gamemode.map(|value| value.to_string()));
+        push("difficulty", settings.difficulty.map(|difficulty| difficulty.as_str().to_string()));
+        push("hardcore", settings.hardcore.map(|value| value.to_string()));
+        push("pvp", settings.pvp.map(|value| value.to_string()));
+        push("allow-flight", settings.allow_flight.map(|value| value.to_string()));
+        push("view-distance", settings.view_distance.map(|distance| distance.to_string()));
+        push("simulation-distance", settings.simulation_distance.map(|distance| distance.to_string()));
+        push("spawn-protection", settings.spawn_protection.map(|radius| radius.to_string()));
+        push("level-name", settings.level_name.clone());
+        push("level-seed", settings.level_seed.clone());
+        push("level-type", settings.level_type.clone());
+        push("generator-settings", settings.generator_settings.clone());
+        push("generate-structures", settings.generate_structures.map(|value| value.to_string()));
+        push("online-mode", settings.online_mode.map(|value| value.to_string()));
+        push("white-list", settings.white_list.map(|value| value.to_string()));
+        push("enforce-whitelist", settings.enforce_whitelist.map(|value| value.to_string()));
+        push("enable-command-block", settings.enable_command_block.map(|value| value.to_string()));
+        push("enable-rcon", settings.enable_rcon.map(|value| value.to_string()));
+        push("rcon.port", settings.rcon_port.map(|port| port.to_string()));
+        push("enable-query", settings.enable_query.map(|value| value.to_string()));
+        push("query.port", settings.query_port.map(|port| port.to_string()));
+
+        let invalid: Vec<String> = values
+            .iter()
+            .filter_map(|(key, value)| {
+                validate_property(key, value)
+                    .err()
+                    .map(|message| format!("{}: {}", key, message))
+            })
+            .collect();
+        if !invalid.is_empty() {
+            return Err(format!("Invalid settings: {}", invalid.join(", ")).into());
+        }
+        for (key, value) in values {
+            self.set(key, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Checks every known property for values the server would reject.
+    pub fn validate(&self) -> Vec<PropertyIssue> {
+        self.entries()
+            .filter_map(|(key, value)| {
+                validate_property(key, value).err().map(|message| PropertyIssue {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    message,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Display for ServerPropertiesFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let escape_unicode = self.encoding == TextEncoding::Latin1;
+        for line in &self.lines {
+            match line {
+                PropertyLine::Other(text) | PropertyLine::Property { raw: Some(text), .. } => writeln!(f, "{}", text)?,
+                PropertyLine::Property { key, value, raw: None } => writeln!(
+                    f,
+                    "{}={}",
+                    escape(key, true, escape_unicode),
+                    escape(value, false, escape_unicode)
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks a value against the kind of a known property.
+fn validate_property(key: &str, value: &str) -> Result<(), String> {
+    let Some((_, kind)) = KNOWN_PROPERTIES.iter().find(|(name, _)| *name == key) else {
+        return Ok(());
+    };
+    let value = value.trim();
+    match kind {
+        PropertyKind::Bool => match value {
+            "true" | "false" => Ok(()),
+            _ => Err(format!("{} is not true or false", value)),
+        },
+        PropertyKind::Integer { min, max } => match value.parse::<i64>() {
+            Ok(number) if (*min..=*max).contains(&number) => Ok(()),
+            Ok(number) => Err(format!("{} is not between {} and {}", number, min, max)),
+            Err(_) => Err(format!("{} is not a number", value)),
+        },
+        PropertyKind::Port => match value.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(()),
+            _ => Err(format!("{} is not a port between 1 and 65535", value)),
+        },
+        PropertyKind::GameMode => GameMode::from_str(value).map(|_| ()),
+        PropertyKind::Difficulty => Difficulty::from_str(value).map(|_| ()),
+    }
+}
+
+fn ends_with_continuation(line: &str) -> bool {
+    line.chars().rev().take_while(|c| *c == '\\').count() % 2 == 1
+}
+
+/// Splits a logical line into its unescaped key and value.
+///
+/// The key ends at the first unescaped `=`, `:` or whitespace, which may be surrounded by whitespace.
+fn parse_property(line: &str) -> (String, String) {
+    let line = line.trim_start();
+    let mut key_end = line.len();
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '=' || c == ':' || c.is_whitespace() {
+            key_end = index;
+            break;
+        }
+    }
+
+    let (key, rest) = line.split_at(key_end);
+    let rest = rest.trim_start_matches([' ', '\t', '\x0C']);
+    let rest = rest.strip_prefix(['=', ':']).unwrap_or(rest);
+    let value = rest.trim_start_matches([' ', '\t', '\x0C']);
+    (unescape(key), unescape(value))
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('f') => result.push('\x0C'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    Some(decoded) => result.push(decoded),
+                    None => {
+                        result.push_str("\\u");
+                        result.push_str(&code);
+                    }
+                }
+            }
+            // A continued line, the indentation of the next line is not part of the value
+            Some('\n') => {
+                let rest: String = chars.by_ref().collect();
+                result.push_str(&unescape(rest.trim_start()));
+                break;
+            }
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+    result
+}
+
+/// Escapes a key or value for a properties file.
+///
+/// # Arguments
+/// * `key` - Whether the text is a key, which also needs separators and spaces escaped.
+/// * `escape_unicode` - Whether characters outside of ISO-8859-1 are written as `\uXXXX`.
+fn escape(text: &str, key: bool, escape_unicode: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    for (index, c) in text.chars().enumerate() {
+        match c {
+            '\\' => result.push_str("\\\\"),
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\x0C' => result.push_str("\\f"),
+            ' ' if key || index == 0 => result.push_str("\\ "),
+            '=' | ':' | '#' | '!' if key => {
+                result.push('\\');
+                result.push(c);
+            }
+            c if escape_unicode && c as u32 > 0xFF => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    result.push_str(&format!("\\u{:04x}", unit));
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
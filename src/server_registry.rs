@@ -0,0 +1,295 @@
+use crate::audit_log::acting_user;
+use crate::backups::backup_directory;
+use crate::permissions::{authorize, can_see, Capability};
+use crate::resource_usage::clear_resource_history;
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_ping::{get_live_status, LiveServerStatus, DEFAULT_SERVER_PORT};
+use crate::server_process::ServerProcess;
+use crate::server_properties::ServerProperties;
+use crate::server_status::ServerStatus;
+use crate::startup_watchdog::{get_startup_metrics, StartupMetrics};
+use crate::two_factor::require_reverification;
+use crate::users::get_user;
+use log::{info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+/// The directory every server directory is created in.
+pub const SERVERS_DIRECTORY: &str = "servers";
+
+/// How many ports above the default game port are tried when allocating one.
+const PORT_SEARCH_RANGE: u16 = 1000;
+
+/// The tables holding settings of a server besides the `server` table, cleared when it is deleted.
+const SERVER_SETTING_TABLES: &[&str] = &[
+    "server_automation_rules",
+    "server_backup_encryption",
+    "server_backup_remotes",
+    "server_backup_schedule",
+    "server_backup_uploads",
+    "server_config",
+    "server_content_rollback",
+    "server_disk_quota",
+    "server_installed_build",
+    "server_metrics",
+    "server_modrinth_content",
+    "server_permissions",
+    "server_player_counts",
+    "server_player_sessions",
+    "server_pregeneration",
+    "server_restart_policy",
+    "server_restart_schedule",
+    "server_scheduled_tasks",
+    "server_scripts",
+    "server_world_generation",
+];
+
+/// The settings a new server is created with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NewServer {
+    pub name: String,
+    pub owner: u64,
+    pub members: Vec<u64>,
+    /// The minimum RAM in GB, passed to `-Xms`.
+    pub min_ram: u64,
+    /// The maximum RAM in GB, passed to `-Xmx`.
+    pub max_ram: u64,
+    pub minecraft_version: String,
+    pub loader_type: u8,
+    pub loader_version: Option<String>,
+    pub java_runtime: Option<PathBuf>,
+    /// The game port, a free port is allocated if it is not set.
+    pub port: Option<u16>,
+}
+
+impl Default for NewServer {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            owner: 0,
+            members: Vec::new(),
+            min_ram: 1,
+            max_ram: 2,
+            minecraft_version: String::new(),
+            loader_type: 0,
+            loader_version: None,
+            java_runtime: None,
+            port: None,
+        }
+    }
+}
+
+/// A registered server along with the state the manager keeps about it at runtime.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInstance {
+    pub server: Server<u64>,
+    /// The game port from `server.properties`.
+    pub port: u16,
+    /// Whether the manager started the server and it is still running.
+    pub running: bool,
+    pub startup: Option<StartupMetrics>,
+    /// The last status of the status monitor, which also covers servers the manager did not start.
+    pub live_status: Option<LiveServerStatus>,
+}
+
+impl ServerInstance {
+    fn new(server: Server<u64>) -> Self {
+        Self {
+            port: server_port(&server),
+            running: server.is_server_running(),
+            startup: get_startup_metrics(server.id),
+            live_status: get_live_status(server.id),
+            server,
+        }
+    }
+}
+
+/// Creates a server with its own directory below [`SERVERS_DIRECTORY`] and a `server.properties`
+/// using a port no other server uses.
+///
+/// # Errors
+/// Returns an error if the name is empty, the requested port is taken, or the server cannot be stored.
+pub fn create_server(new_server: &NewServer) -> Result<Server<u64>, Box<dyn Error>> {
+    let name = new_server.name.trim();
+    if name.is_empty() {
+        return Err("The server name cannot be empty".into());
+    }
+    if new_server.min_ram > new_server.max_ram {
+        return Err("The minimum RAM cannot be larger than the maximum RAM".into());
+    }
+    let port = allocate_port(new_server.port)?;
+    let directory = unique_directory(Path::new(SERVERS_DIRECTORY), name);
+    fs::create_dir_all(&directory)?;
+
+    let mut server = Server {
+        name: name.to_string(),
+        owner: new_server.owner,
+        members: new_server.members.clone(),
+        min_ram: new_server.min_ram,
+        max_ram: new_server.max_ram,
+        minecraft_version: new_server.minecraft_version.clone(),
+        loader_type: new_server.loader_type,
+        loader_version: new_server.loader_version.clone(),
+        java_runtime: new_server.java_runtime.clone(),
+        directory,
+        status: Some(ServerStatus::Offline),
+        ..Default::default()
+    };
+    server.add()?;
+
+    let configured = server.create_properties_file().and_then(|_| {
+        let mut properties = server.load_properties_file()?;
+        properties.set("server-port", &port.to_string())?;
+        server.save_properties_file(&properties)
+    });
+    if let Err(e) = configured {
+        // Do not leave a half created server behind
+        if let Err(cleanup) = delete_server(server.id, true) {
+            warn!("Failed to remove the half created server {:?}: {}", server.name, cleanup);
+        }
+        return Err(e);
+    }
+
+    info!("Created server {:?} in {:?} on port {}", server.name, server.directory, port);
+    Ok(server)
+}
+
+/// Lists the registered servers the acting user may do anything on with their runtime state, every
+/// server for administrators.
+pub fn list_server_instances() -> Result<Vec<ServerInstance>, Box<dyn Error>> {
+    Ok(Server::<u64>::get_list_of_servers()?
+        .into_iter()
+        .filter(can_see)
+        .map(ServerInstance::new)
+        .collect())
+}
+
+/// Returns a registered server with its runtime state.
+///
+/// # Errors
+/// Returns an error if there is no such server, or the acting user may not do anything on it.
+pub fn get_server_instance(server_id: u64) -> Result<ServerInstance, Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    if !can_see(&server) {
+        return Err(format!("No server with the id {}", server_id).into());
+    }
+    Ok(ServerInstance::new(server))
+}
+
+/// Removes a server and its settings from the database, and its directory and backups if `delete_files` is set.
+///
+/// # Errors
+/// Returns an error if the acting user is neither its owner nor an administrator, the server is
+/// running or cannot be removed.
+pub fn delete_server(server_id: u64, delete_files: bool) -> Result<(), Box<dyn Error>> {
+    require_reverification()?;
+    let server = Server::<u64>::get_server(server_id)?;
+    authorize(&server, Capability::ControlServer)?;
+    if let Some(user_id) = acting_user() {
+        if user_id != server.owner && !get_user(user_id)?.is_admin {
+            return Err(format!("Only the owner of server {:?} or an administrator can delete it", server.name).into());
+        }
+    }
+    if server.is_server_running() {
+        return Err(format!("Server {:?} has to be stopped before it can be deleted", server.name).into());
+    }
+
+    let conn = create_appdb_connection()?;
+    for table in SERVER_SETTING_TABLES {
+        let mut statement = conn.prepare(format!("DELETE FROM {} WHERE server_id = ?", table))?;
+        statement.bind((1, server_id as i64))?;
+        statement.next()?;
+    }
+    server.remove_from_database()?;
+    clear_resource_history(server_id);
+
+    if delete_files && server.directory.exists() {
+        fs::remove_dir_all(&server.directory)?;
+    }
+    let backups = backup_directory(server_id);
+    if delete_files && backups.exists() {
+        fs::remove_dir_all(&backups)?;
+    }
+    info!("Deleted server {:?}{}", server.name, if delete_files { " and its files" } else { "" });
+    Ok(())
+}
+
+/// Picks the game port for a server.
+///
+/// A requested port is checked against the ports of the other servers and whether it can be bound.
+/// Without one, the first free port from the default game port upwards is used.
+///
+/// # Errors
+/// Returns an error if the requested port is taken, or no port is free.
+pub fn allocate_port(requested: Option<u16>) -> Result<u16, Box<dyn Error>> {
+    let used = used_ports()?;
+    if let Some(port) = requested {
+        if let Some(server_id) = used.get(&port) {
+            return Err(format!("Port {} is already used by server {}", port, server_id).into());
+        }
+        if !is_port_free(port) {
+            return Err(format!("Port {} is already used by another program", port).into());
+        }
+        return Ok(port);
+    }
+
+    (DEFAULT_SERVER_PORT..=DEFAULT_SERVER_PORT.saturating_add(PORT_SEARCH_RANGE))
+        .find(|port| !used.contains_key(port) && is_port_free(*port))
+        .ok_or_else(|| "No free port is left for a new server".into())
+}
+
+/// The game, RCON and query ports of every registered server, mapped to the server using them.
+pub(crate) fn used_ports() -> Result<HashMap<u16, u64>, Box<dyn Error>> {
+    let mut ports = HashMap::new();
+    for server in Server::<u64>::get_list_of_servers()? {
+        ports.insert(server_port(&server), server.id);
+        let Ok(properties) = server.load_properties_file() else {
+            continue;
+        };
+        for (enabled, port) in [("enable-rcon", "rcon.port"), ("enable-query", "query.port")] {
+            if properties.get(enabled).map(str::trim) == Some("true") {
+                if let Some(port) = properties.get(port).and_then(|port| port.trim().parse().ok()) {
+                    ports.insert(port, server.id);
+                }
+            }
+        }
+    }
+    Ok(ports)
+}
+
+fn server_port(server: &Server<u64>) -> u16 {
+    server
+        .load_properties_file()
+        .ok()
+        .and_then(|properties| properties.get("server-port").and_then(|port| port.trim().parse().ok()))
+        .unwrap_or(DEFAULT_SERVER_PORT)
+}
+
+fn is_port_free(port: u16) -> bool {
+    TcpListener::bind(("0.0.0.0", port)).is_ok()
+}
+
+/// A directory below `parent` named after the server, numbered if the name is taken.
+pub(crate) fn unique_directory(parent: &Path, name: &str) -> PathBuf {
+    let slug: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let slug = slug.trim_matches('-');
+    let slug = if slug.is_empty() { "server" } else { slug };
+
+    let mut directory = parent.join(slug);
+    let mut number = 2;
+    while directory.exists() {
+        directory = parent.join(format!("{}-{}", slug, number));
+        number += 1;
+    }
+    directory
+}
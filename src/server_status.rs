@@ -31,6 +31,8 @@ pub enum ServerStatus {
     Deleting,
     /// Indicates a new server instance is being created
     Creating,
+    /// Indicates the server refuses to start until the Minecraft EULA is accepted
+    EulaRequired,
 }
 
 impl Default for ServerStatus {
@@ -72,6 +74,7 @@ impl Serialize for ServerStatus {
             ServerStatus::Reloading => serializer.serialize_str("reloading"),
             ServerStatus::Deleting => serializer.serialize_str("deleting"),
             ServerStatus::Creating => serializer.serialize_str("creating"),
+            ServerStatus::EulaRequired => serializer.serialize_str("eula_required"),
         }
     }
 }
@@ -134,6 +137,7 @@ impl<'de> serde::de::Deserialize<'de> for ServerStatus {
                     "reloading" => Ok(ServerStatus::Reloading),
                     "deleting" => Ok(ServerStatus::Deleting),
                     "creating" => Ok(ServerStatus::Creating),
+                    "eula_required" => Ok(ServerStatus::EulaRequired),
                     // Returns an error if the provided string doesn't match any known server status
                     _ => Err(E::custom(format!("unknown server status: {}", value))),
                 }
@@ -168,6 +172,7 @@ impl Display for ServerStatus {
             ServerStatus::Reloading => "reloading".to_string(),
             ServerStatus::Deleting => "deleting".to_string(),
             ServerStatus::Creating => "creating".to_string(),
+            ServerStatus::EulaRequired => "eula_required".to_string(),
         };
         write!(f, "{}", str)
     }
@@ -199,6 +204,7 @@ impl FromStr for ServerStatus {
             "reloading" => Ok(ServerStatus::Reloading),
             "deleting" => Ok(ServerStatus::Deleting),
             "creating" => Ok(ServerStatus::Creating),
+            "eula_required" => Ok(ServerStatus::EulaRequired),
             _ => Err(()),
         }
     }
@@ -0,0 +1,479 @@
+use crate::audit_log::acting_user;
+use crate::config::{list_config, set_config, ConfigScope};
+use crate::file_versions::VERSIONS_DIRECTORY;
+use crate::java_runtimes::ServerJavaRuntime;
+use crate::jvm_flags::{get_jvm_flag_settings, set_jvm_flag_settings, JvmFlagSettings};
+use crate::launch_templates::{get_launch_template, set_launch_template, LaunchTemplate};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::server_import::detect_server;
+use crate::server_process::ServerProcess;
+use crate::server_properties::ServerProperties;
+use crate::server_registry::{allocate_port, create_server, delete_server, NewServer};
+use crate::server_trash::TRASH_DIRECTORY;
+use crate::users::get_user;
+use crate::worlds::WorldManager;
+use log::{debug, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// The directory the files of the server templates are kept in, one directory per template.
+pub const TEMPLATE_DIRECTORY: &str = "templates";
+
+/// The directories the logs and crash reports of a server are written to.
+const LOG_DIRECTORIES: &[&str] = &["logs", "crash-reports", "debug"];
+
+/// The settings a clone does not take over, two bridges to the same Discord channel would relay
+/// every message twice.
+const UNSHARED_SETTINGS: &[&str] = &["discord_bridge"];
+
+/// How a server is cloned.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CloneOptions {
+    /// The name of the clone, the name of the server with `(copy)` after it if empty.
+    pub name: String,
+    /// The owner of the clone, the owner of the server if not set.
+    pub owner: Option<u64>,
+    /// The game port of the clone, a free port is allocated if it is not set.
+    pub port: Option<u16>,
+    /// Leaves out the worlds, so the clone generates a new one on its first start.
+    pub exclude_worlds: bool,
+    /// Leaves out the logs and crash reports.
+    pub exclude_logs: bool,
+}
+
+/// How a server is saved as a template.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SaveTemplateOptions {
+    pub name: String,
+    pub description: Option<String>,
+    /// Keeps the worlds in the template, so every server made from it starts with them. The logs
+    /// are always left out.
+    pub include_worlds: bool,
+}
+
+/// What a template sets on the servers made from it, besides its files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemplateSettings {
+    pub minecraft_version: String,
+    pub loader_type: u8,
+    pub loader_version: Option<String>,
+    /// The file the servers start with, relative to their directory.
+    pub start_script: Option<PathBuf>,
+    /// The minimum RAM in GB, passed to `-Xms`.
+    pub min_ram: u64,
+    /// The maximum RAM in GB, passed to `-Xmx`.
+    pub max_ram: u64,
+    pub java_arguments: Option<String>,
+    pub minecraft_arguments: Option<String>,
+    pub jvm_flags: JvmFlagSettings,
+    pub launch_template: LaunchTemplate,
+}
+
+/// A saved server new servers can be made from, with its jar, configs, mods and settings.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerTemplate {
+    pub id: u64,
+    /// The server the template was saved from. Who may edit its files may use the template.
+    pub server_id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    /// The server software of the template, like `Paper` or `Fabric`, if it was recognized.
+    pub software: Option<String>,
+    pub settings: TemplateSettings,
+    /// The size of the files of the template in bytes.
+    pub size: u64,
+    /// Unix time the template was saved.
+    pub created_at: u64,
+}
+
+/// Who a server made from a template belongs to.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemplateServerOptions {
+    pub name: String,
+    /// The owner of the server, the acting user if not set.
+    pub owner: Option<u64>,
+    pub members: Vec<u64>,
+    /// The game port, a free port is allocated if it is not set.
+    pub port: Option<u16>,
+}
+
+/// Creates the table holding the server templates.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_server_template_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_templates` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the template
+            server_id INTEGER NOT NULL,                                 -- The server it was saved from
+            name TEXT NOT NULL UNIQUE,                                  -- What the template is called
+            description TEXT,                                           -- What it is for
+            software TEXT,                                              -- The server software, like Paper
+            settings TEXT NOT NULL,                                     -- The server settings, as JSON
+            size INTEGER NOT NULL DEFAULT 0,                            -- The size of its files in bytes
+            created_at INTEGER NOT NULL                                 -- Unix time it was saved
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// The directory the files of a template are kept in.
+pub fn template_directory(template_id: u64) -> PathBuf {
+    Path::new(TEMPLATE_DIRECTORY).join(template_id.to_string())
+}
+
+/// Copies a server into a new one with its own directory and port. The clone gets the files,
+/// start file, memory, arguments and settings of the server, but starts out stopped and without
+/// auto start.
+///
+/// # Errors
+/// Returns an error if the acting user may not edit the files of the server, the worlds are
+/// copied while the server runs, or copying fails. A clone that failed is removed again.
+pub fn clone_server(server_id: u64, options: &CloneOptions) -> Result<Server<u64>, Box<dyn Error>> {
+    let source = Server::<u64>::get_server(server_id)?;
+    authorize(&source, Capability::EditFiles)?;
+    if !options.exclude_worlds && source.is_server_running() {
+        return Err(format!("Server {:?} has to be stopped before its worlds can be cloned", source.name).into());
+    }
+    let name = match options.name.trim() {
+        "" => format!("{} (copy)", source.name),
+        name => name.to_string(),
+    };
+    let mut clone = create_server(&NewServer {
+        name,
+        owner: options.owner.unwrap_or(source.owner),
+        members: source.members.clone(),
+        min_ram: source.min_ram,
+        max_ram: source.max_ram,
+        minecraft_version: source.minecraft_version.clone(),
+        loader_type: source.loader_type,
+        loader_version: source.loader_version.clone(),
+        java_runtime: source.java_runtime.clone(),
+        port: options.port,
+    })?;
+
+    match set_up_clone(&source, &mut clone, options) {
+        Ok(()) => {
+            info!("Cloned server {:?} as {:?}", source.name, clone.name);
+            Ok(clone)
+        }
+        Err(e) => {
+            if let Err(cleanup) = delete_server(clone.id, true) {
+                warn!("Failed to remove the clone {:?} that failed: {}", clone.name, cleanup);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn set_up_clone(source: &Server<u64>, clone: &mut Server<u64>, options: &CloneOptions) -> Result<(), Box<dyn Error>> {
+    let port = game_port(clone)?;
+    let excluded = excluded_paths(source, options.exclude_worlds, options.exclude_logs)?;
+    copy_server_files(&source.directory, &clone.directory, &excluded)?;
+    assign_ports(clone, port)?;
+
+    clone.start_script = source.start_script.clone();
+    clone.java_arguments = source.java_arguments.clone();
+    clone.minecraft_arguments = source.minecraft_arguments.clone();
+    clone.update()?;
+    for (key, value) in list_config(ConfigScope::Server(source.id))? {
+        if !UNSHARED_SETTINGS.contains(&key.as_str()) {
+            set_config(ConfigScope::Server(clone.id), &key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Saves a server as a template: its files without the logs, and without the worlds unless asked
+/// for, along with its start file, memory, arguments, JVM flags and launch template.
+///
+/// # Errors
+/// Returns an error if the name is empty or taken, the acting user may not edit the files of the
+/// server, the worlds are saved while the server runs, or copying fails.
+pub fn save_server_as_template(
+    server_id: u64,
+    options: &SaveTemplateOptions,
+) -> Result<ServerTemplate, Box<dyn Error>> {
+    let server = Server::<u64>::get_server(server_id)?;
+    authorize(&server, Capability::EditFiles)?;
+    let name = options.name.trim();
+    if name.is_empty() {
+        return Err("The template name cannot be empty".into());
+    }
+    if list_server_templates()?.iter().any(|template| template.name == name) {
+        return Err(format!("A template named {:?} already exists", name).into());
+    }
+    if options.include_worlds && server.is_server_running() {
+        return Err(format!("Server {:?} has to be stopped before its worlds can be saved", server.name).into());
+    }
+
+    let settings = TemplateSettings {
+        minecraft_version: server.minecraft_version.clone(),
+        loader_type: server.loader_type,
+        loader_version: server.loader_version.clone(),
+        start_script: server.start_script.clone(),
+        min_ram: server.min_ram,
+        max_ram: server.max_ram,
+        java_arguments: server.java_arguments.clone(),
+        minecraft_arguments: server.minecraft_arguments.clone(),
+        jvm_flags: get_jvm_flag_settings(server.id)?,
+        launch_template: get_launch_template(server.id)?,
+    };
+    let software = detect_server(&server.directory).ok().and_then(|detected| detected.software);
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO server_templates (server_id, name, description, software, settings, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)"#,
+    )?;
+    statement.bind((1, server.id as i64))?;
+    statement.bind((2, name))?;
+    statement.bind((3, options.description.as_deref()))?;
+    statement.bind((4, software.as_deref()))?;
+    statement.bind((5, serde_json::to_string(&settings)?.as_str()))?;
+    statement.bind((6, SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64))?;
+    statement.next()?;
+    let template_id = last_inserted_id("server_templates")?;
+
+    let directory = template_directory(template_id);
+    let copied = excluded_paths(&server, !options.include_worlds, true)
+        .and_then(|excluded| copy_server_files(&server.directory, &directory, &excluded));
+    let size = match copied {
+        Ok(size) => size,
+        Err(e) => {
+            if let Err(cleanup) = remove_template(template_id) {
+                warn!("Failed to remove the template {:?} that failed: {}", name, cleanup);
+            }
+            return Err(e);
+        }
+    };
+    let mut statement = conn.prepare("UPDATE server_templates SET size = ? WHERE id = ?")?;
+    statement.bind((1, size as i64))?;
+    statement.bind((2, template_id as i64))?;
+    statement.next()?;
+    info!("Saved server {:?} as the template {:?} ({} bytes)", server.name, name, size);
+    get_server_template(template_id)
+}
+
+/// Lists the templates by name.
+pub fn list_server_templates() -> Result<Vec<ServerTemplate>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_templates ORDER BY name")?;
+    let mut templates = Vec::new();
+    while let State::Row = statement.next()? {
+        templates.push(read_template(&statement)?);
+    }
+    Ok(templates)
+}
+
+/// Returns a template.
+///
+/// # Errors
+/// Returns an error if there is no template with the id.
+pub fn get_server_template(template_id: u64) -> Result<ServerTemplate, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM server_templates WHERE id = ?")?;
+    statement.bind((1, template_id as i64))?;
+    let State::Row = statement.next()? else {
+        return Err(format!("No server template with the id {}", template_id).into());
+    };
+    read_template(&statement)
+}
+
+/// Removes a template and its files. Servers made from it are not affected.
+///
+/// # Errors
+/// Returns an error if there is no such template, or the acting user may not edit the files of the
+/// server it was saved from.
+pub fn delete_server_template(template_id: u64) -> Result<(), Box<dyn Error>> {
+    authorize_template(&get_server_template(template_id)?)?;
+    remove_template(template_id)
+}
+
+fn remove_template(template_id: u64) -> Result<(), Box<dyn Error>> {
+    let directory = template_directory(template_id);
+    if directory.exists() {
+        fs::remove_dir_all(&directory)?;
+    }
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM server_templates WHERE id = ?")?;
+    statement.bind((1, template_id as i64))?;
+    statement.next()?;
+    info!("Removed the server template {}", template_id);
+    Ok(())
+}
+
+/// Makes a new server from a template, with its own directory and port. It gets the files and
+/// settings of the template, and a Java runtime fitting its version of the game.
+///
+/// # Errors
+/// Returns an error if there is no such template, the acting user may not use it, the server has
+/// no owner, or it cannot be created or its files cannot be copied. A server that failed is
+/// removed again.
+pub fn create_server_from_template(
+    template_id: u64,
+    options: &TemplateServerOptions,
+) -> Result<Server<u64>, Box<dyn Error>> {
+    let template = get_server_template(template_id)?;
+    authorize_template(&template)?;
+    let owner = options
+        .owner
+        .or_else(acting_user)
+        .filter(|owner| *owner != 0)
+        .ok_or("A server made from a template needs an owner")?;
+    get_user(owner)?;
+    let settings = &template.settings;
+    let mut server = create_server(&NewServer {
+        name: options.name.clone(),
+        owner,
+        members: options.members.clone(),
+        min_ram: settings.min_ram,
+        max_ram: settings.max_ram,
+        minecraft_version: settings.minecraft_version.clone(),
+        loader_type: settings.loader_type,
+        loader_version: settings.loader_version.clone(),
+        java_runtime: None,
+        port: options.port,
+    })?;
+
+    match set_up_from_template(&template, &mut server) {
+        Ok(()) => {
+            info!("Created server {:?} from the template {:?}", server.name, template.name);
+            Ok(server)
+        }
+        Err(e) => {
+            if let Err(cleanup) = delete_server(server.id, true) {
+                warn!("Failed to remove the server {:?} that failed: {}", server.name, cleanup);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn set_up_from_template(template: &ServerTemplate, server: &mut Server<u64>) -> Result<(), Box<dyn Error>> {
+    let port = game_port(server)?;
+    copy_server_files(&template_directory(template.id), &server.directory, &HashSet::new())?;
+    assign_ports(server, port)?;
+
+    let settings = &template.settings;
+    server.start_script = settings.start_script.clone();
+    server.java_arguments = settings.java_arguments.clone();
+    server.minecraft_arguments = settings.minecraft_arguments.clone();
+    server.update()?;
+    set_jvm_flag_settings(server.id, &settings.jvm_flags)?;
+    set_launch_template(server.id, &settings.launch_template)?;
+    // The runtime of the saved server may not exist on this machine, so one is picked anew
+    if let Err(e) = server.select_java_runtime() {
+        warn!("Server {:?} has no Java runtime yet: {}", server.name, e);
+    }
+    Ok(())
+}
+
+/// Fails unless the acting user may use a template, which takes being allowed to edit the files of
+/// the server it was saved from. Once that server is gone only administrators may use it.
+fn authorize_template(template: &ServerTemplate) -> Result<(), Box<dyn Error>> {
+    if let Ok(server) = Server::<u64>::get_server(template.server_id) {
+        return authorize(&server, Capability::EditFiles);
+    }
+    match acting_user() {
+        Some(user_id) if !get_user(user_id)?.is_admin => Err(format!(
+            "The server the template {:?} was saved from is gone, only administrators may use it",
+            template.name
+        )
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+fn read_template(statement: &sqlite::Statement) -> Result<ServerTemplate, Box<dyn Error>> {
+    Ok(ServerTemplate {
+        id: statement.read::<i64, _>("id")? as u64,
+        server_id: statement.read::<i64, _>("server_id")? as u64,
+        name: statement.read::<String, _>("name")?,
+        description: statement.read::<Option<String>, _>("description")?,
+        software: statement.read::<Option<String>, _>("software")?,
+        settings: serde_json::from_str(&statement.read::<String, _>("settings")?)?,
+        size: statement.read::<i64, _>("size")? as u64,
+        created_at: statement.read::<i64, _>("created_at")? as u64,
+    })
+}
+
+/// The game port `create_server` allocated, before the copied `server.properties` replaces it.
+fn game_port(server: &Server<u64>) -> Result<u16, Box<dyn Error>> {
+    let properties = server.load_properties_file()?;
+    Ok(properties
+        .get("server-port")
+        .and_then(|port| port.trim().parse().ok())
+        .ok_or("The new server has no port")?)
+}
+
+/// Sets the ports of a copied `server.properties`, so the copy does not collide with the server it
+/// was copied from. The query port follows the game port, RCON gets a free port of its own.
+fn assign_ports(server: &Server<u64>, port: u16) -> Result<(), Box<dyn Error>> {
+    let mut properties = server.load_properties_file().unwrap_or_default();
+    properties.set("server-port", &port.to_string())?;
+    if properties.get("enable-query").map(str::trim) == Some("true") {
+        properties.set("query.port", &port.to_string())?;
+    }
+    server.save_properties_file(&properties)?;
+    if properties.get("enable-rcon").map(str::trim) == Some("true") {
+        properties.set("rcon.port", &allocate_port(None)?.to_string())?;
+        server.save_properties_file(&properties)?;
+    }
+    Ok(())
+}
+
+/// The paths of a server directory that are not copied, relative to it.
+fn excluded_paths(server: &Server<u64>, worlds: bool, logs: bool) -> Result<HashSet<PathBuf>, Box<dyn Error>> {
+    let mut excluded: HashSet<PathBuf> = [TRASH_DIRECTORY, VERSIONS_DIRECTORY].iter().map(PathBuf::from).collect();
+    if logs {
+        excluded.extend(LOG_DIRECTORIES.iter().map(PathBuf::from));
+    }
+    if worlds {
+        for world in WorldManager::new(server)?.list_worlds()? {
+            excluded.extend(world.directories);
+        }
+    }
+    Ok(excluded)
+}
+
+/// Copies the files of a server directory into another, leaving out the excluded paths and the
+/// `session.lock` of the worlds. Symbolic links are not followed, and not copied either.
+///
+/// # Returns
+/// The number of bytes copied.
+fn copy_server_files(source: &Path, destination: &Path, excluded: &HashSet<PathBuf>) -> Result<u64, Box<dyn Error>> {
+    fs::create_dir_all(destination)?;
+    let mut size = 0;
+    let entries = WalkDir::new(source).min_depth(1).follow_links(false).into_iter().filter_entry(|entry| {
+        let relative = entry.path().strip_prefix(source).unwrap_or(entry.path());
+        !excluded.contains(relative)
+    });
+    for entry in entries {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(source)?;
+        let target = destination.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_symlink() {
+            debug!("Not copying the symbolic link {:?}", entry.path());
+        } else if entry.file_name() != "session.lock" {
+            size += fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(size)
+}
@@ -0,0 +1,314 @@
+use crate::file_operations::{move_path, ConflictStrategy, FileOperationOptions, FileOps};
+use crate::file_system_entry::calculate_directory_size;
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_filesystem::ServerFilesystem;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the trash directory inside a server's root directory.
+pub const TRASH_DIRECTORY: &str = ".trash";
+
+/// The default time items stay in the trash before `purge_expired` removes them.
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// The name of the metadata file stored next to every trashed item.
+const METADATA_FILE: &str = "info.json";
+
+/// The name under which the trashed file or directory itself is stored.
+const ITEM_NAME: &str = "item";
+
+/// A file or directory that has been moved to the trash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashItem {
+    /// The identifier used to restore or purge the item.
+    pub id: String,
+    /// The original file name of the item.
+    pub name: String,
+    /// The original path relative to the server's root directory.
+    pub original_path: PathBuf,
+    pub is_dir: bool,
+    /// The size of the item, including the contents of directories.
+    pub size: u64,
+    pub deleted_at: SystemTime,
+}
+
+/// The trash of a single server directory.
+///
+/// Trashed items are stored in `.trash/<id>/item`, with their original location and deletion time
+/// in `.trash/<id>/info.json`. Keeping the trash inside the server directory means items are moved,
+/// not copied, even for multi gigabyte worlds.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: SandboxedPath,
+}
+
+impl Trash {
+    /// Opens the trash of a server directory.
+    ///
+    /// # Arguments
+    /// * `server_root` - Any sandboxed path of the server, only its sandbox root is used.
+    pub fn new(server_root: &SandboxedPath) -> Self {
+        Self {
+            root: SandboxedPath::root(server_root.root_path()).unwrap_or_else(|_| server_root.clone()),
+        }
+    }
+
+    /// Moves a file or directory into the trash.
+    ///
+    /// # Errors
+    /// Returns an error if the path is the server directory itself, is inside the trash, or cannot be moved.
+    pub fn trash(&self, path: &SandboxedPath) -> Result<TrashItem, Box<dyn Error>> {
+        if path.is_root() {
+            return Err("The server directory itself cannot be moved to the trash".into());
+        }
+        if path.relative_path().starts_with(TRASH_DIRECTORY) {
+            return Err("Items in the trash cannot be trashed again, purge them instead".into());
+        }
+
+        let source = path.path();
+        let metadata = fs::symlink_metadata(&source)?;
+        let size = if metadata.is_dir() {
+            calculate_directory_size(&source)
+        } else {
+            metadata.len()
+        };
+
+        let deleted_at = SystemTime::now();
+        let item_directory = self.new_item_directory(deleted_at)?;
+        let id = item_directory
+            .relative_path()
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let item = TrashItem {
+            id,
+            name: source.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default(),
+            original_path: path.relative_path().to_path_buf(),
+            is_dir: metadata.is_dir(),
+            size,
+            deleted_at,
+        };
+
+        fs::create_dir_all(item_directory.path())?;
+        fs::write(item_directory.path().join(METADATA_FILE), serde_json::to_vec_pretty(&item)?)?;
+        if let Err(err) = move_path(&source, &item_directory.path().join(ITEM_NAME)) {
+            // Do not leave an empty trash entry behind
+            if let Err(cleanup_err) = fs::remove_dir_all(item_directory.path()) {
+                warn!("Failed to clean up trash entry {:?}: {}", item_directory.path(), cleanup_err);
+            }
+            return Err(err);
+        }
+
+        info!("Moved {:?} to the trash as {}", item.original_path, item.id);
+        Ok(item)
+    }
+
+    /// Lists all items in the trash, most recently deleted first.
+    ///
+    /// # Errors
+    /// Returns an error if the trash directory exists but cannot be read.
+    pub fn list(&self) -> Result<Vec<TrashItem>, Box<dyn Error>> {
+        let trash_path = self.root.path().join(TRASH_DIRECTORY);
+        if !trash_path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut items = Vec::new();
+        for entry in fs::read_dir(&trash_path)?.flatten() {
+            match read_metadata(&entry.path()) {
+                Ok(item) => items.push(item),
+                Err(err) => warn!("Ignoring unreadable trash entry {:?}: {}", entry.path(), err),
+            }
+        }
+        items.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+        Ok(items)
+    }
+
+    /// Restores an item to its original location.
+    ///
+    /// # Arguments
+    /// * `id` - The identifier of the trashed item.
+    /// * `conflict` - What to do when something new exists at the original location.
+    ///
+    /// # Returns
+    /// The path the item was restored to, relative to the server's root directory,
+    /// or `None` if the conflict strategy is `Skip` and the original location is taken.
+    ///
+    /// # Errors
+    /// Returns an error if the item does not exist, the original location is taken and the
+    /// conflict strategy is `Fail`, or the item could not be moved back.
+    pub fn restore(&self, id: &str, conflict: ConflictStrategy) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let item_directory = self.item_directory(id)?;
+        let item = read_metadata(&item_directory.path())?;
+        let mut target = self.root.join(&item.original_path)?;
+
+        if fs::symlink_metadata(target.path()).is_ok() {
+            match conflict {
+                ConflictStrategy::Fail => {
+                    return Err(format!("{} already exists", target).into());
+                }
+                ConflictStrategy::Skip => return Ok(None),
+                ConflictStrategy::Overwrite => {
+                    FileOps::new(FileOperationOptions::default()).delete(&target, |_| {})?;
+                }
+                ConflictStrategy::Rename => target = target.next_available()?,
+            }
+        }
+
+        move_path(&item_directory.path().join(ITEM_NAME), &target.path())?;
+        fs::remove_dir_all(item_directory.path())?;
+        info!("Restored trash item {} to {:?}", id, target.relative_path());
+        Ok(Some(target.relative_path().to_path_buf()))
+    }
+
+    /// Permanently deletes a single item from the trash.
+    ///
+    /// # Errors
+    /// Returns an error if the item does not exist or cannot be deleted.
+    pub fn purge(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        let item_directory = self.item_directory(id)?;
+        fs::remove_dir_all(item_directory.path())?;
+        info!("Purged trash item {}", id);
+        Ok(())
+    }
+
+    /// Permanently deletes every item in the trash.
+    ///
+    /// # Returns
+    /// The number of purged items.
+    pub fn purge_all(&self) -> Result<usize, Box<dyn Error>> {
+        let items = self.list()?;
+        for item in &items {
+            self.purge(&item.id)?;
+        }
+        Ok(items.len())
+    }
+
+    /// Permanently deletes every item that has been in the trash for longer than the retention period.
+    ///
+    /// # Arguments
+    /// * `retention` - How long items are kept, see [`DEFAULT_TRASH_RETENTION`].
+    ///
+    /// # Returns
+    /// The number of purged items.
+    pub fn purge_expired(&self, retention: Duration) -> Result<usize, Box<dyn Error>> {
+        let now = SystemTime::now();
+        let mut purged = 0;
+        for item in self.list()? {
+            let age = now.duration_since(item.deleted_at).unwrap_or_default();
+            if age > retention {
+                debug!("Trash item {} expired after {:?}", item.id, age);
+                self.purge(&item.id)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Resolves the directory of a trashed item, validating the identifier on the way.
+    fn item_directory(&self, id: &str) -> Result<SandboxedPath, Box<dyn Error>> {
+        if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+            return Err(format!("Invalid trash item id: {:?}", id).into());
+        }
+        let directory = self.root.join(Path::new(TRASH_DIRECTORY).join(id))?;
+        if !directory.path().is_dir() {
+            return Err(format!("Trash item {} does not exist", id).into());
+        }
+        Ok(directory)
+    }
+
+    /// Picks an unused identifier based on the deletion time.
+    fn new_item_directory(&self, deleted_at: SystemTime) -> Result<SandboxedPath, Box<dyn Error>> {
+        let millis = deleted_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut suffix = 0;
+        loop {
+            let id = if suffix == 0 {
+                millis.to_string()
+            } else {
+                format!("{}-{}", millis, suffix)
+            };
+            let candidate = self.root.join(Path::new(TRASH_DIRECTORY).join(id))?;
+            if !candidate.path().exists() {
+                return Ok(candidate);
+            }
+            suffix += 1;
+        }
+    }
+}
+
+fn read_metadata(item_directory: &Path) -> Result<TrashItem, Box<dyn Error>> {
+    let contents = fs::read(item_directory.join(METADATA_FILE))?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Trash operations for a server, so that deleting files from the panel can be undone. They need
+/// the acting user to be allowed to edit files.
+pub trait ServerTrash {
+    /// Moves a file or directory to the server's trash instead of deleting it.
+    ///
+    /// # Parameters
+    /// - `path`: The file or directory to trash, relative to the server's root directory.
+    ///
+    /// # Returns
+    /// - `Ok(TrashItem)` describing the trashed item.
+    /// - `Err(Box<dyn Error>)` if the path is outside of the server directory or cannot be moved.
+    fn trash_path(&self, path: impl AsRef<Path>) -> Result<TrashItem, Box<dyn Error>>;
+
+    /// Lists the items in the server's trash, most recently deleted first.
+    fn list_trash(&self) -> Result<Vec<TrashItem>, Box<dyn Error>>;
+
+    /// Restores a trashed item to its original location.
+    ///
+    /// # Parameters
+    /// - `id`: The identifier of the trashed item.
+    /// - `conflict`: What to do if the original location is taken.
+    ///
+    /// # Returns
+    /// - `Ok(Some(PathBuf))` with the path the item was restored to.
+    /// - `Ok(None)` if the original location is taken and the conflict strategy is `Skip`.
+    fn restore_trash_item(&self, id: &str, conflict: ConflictStrategy) -> Result<Option<PathBuf>, Box<dyn Error>>;
+
+    /// Permanently deletes a single trashed item.
+    fn purge_trash_item(&self, id: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Permanently deletes all trashed items older than the retention period.
+    ///
+    /// # Returns
+    /// - `Ok(usize)` with the number of purged items.
+    fn purge_expired_trash(&self, retention: Duration) -> Result<usize, Box<dyn Error>>;
+}
+
+impl ServerTrash for Server<u64> {
+    fn trash_path(&self, path: impl AsRef<Path>) -> Result<TrashItem, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        let path = self.sandbox(path)?;
+        Trash::new(&path).trash(&path)
+    }
+
+    fn list_trash(&self) -> Result<Vec<TrashItem>, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        Trash::new(&self.sandbox("")?).list()
+    }
+
+    fn restore_trash_item(&self, id: &str, conflict: ConflictStrategy) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        Trash::new(&self.sandbox("")?).restore(id, conflict)
+    }
+
+    fn purge_trash_item(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        Trash::new(&self.sandbox("")?).purge(id)
+    }
+
+    fn purge_expired_trash(&self, retention: Duration) -> Result<usize, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        Trash::new(&self.sandbox("")?).purge_expired(retention)
+    }
+}
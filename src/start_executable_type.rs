@@ -8,6 +8,9 @@ pub enum StartExecutableType {
     Jar,
     Executable,
     Script,
+    /// A Java argument file, such as the `unix_args.txt` generated by the Forge and NeoForge installers,
+    /// passed to the Java runtime as `@file`.
+    ArgumentFile,
 }
 
 impl Default for StartExecutableType {
@@ -27,6 +30,7 @@ impl Serialize for StartExecutableType {
             StartExecutableType::Jar => serializer.serialize_str("jar"),
             StartExecutableType::Executable => serializer.serialize_str("executable"),
             StartExecutableType::Script => serializer.serialize_str("script"),
+            StartExecutableType::ArgumentFile => serializer.serialize_str("argument_file"),
         }
     }
 }
@@ -42,6 +46,7 @@ impl<'de> Deserialize<'de> for StartExecutableType {
             "jar" => Ok(StartExecutableType::Jar),
             "executable" => Ok(StartExecutableType::Executable),
             "script" => Ok(StartExecutableType::Script),
+            "argument_file" => Ok(StartExecutableType::ArgumentFile),
             // Returns an error if the string doesn't match any known type.
             _ => Err(Error::custom("invalid variant")),
         }
@@ -74,6 +79,7 @@ impl StartExecutableTypeExt for StartExecutableType {
             "jar" => Ok(StartExecutableType::Jar),
             "exe" => Ok(StartExecutableType::Executable),
             "sh" | "bat" | "cmd" | "ps1" => Ok(StartExecutableType::Script),
+            "txt" => Ok(StartExecutableType::ArgumentFile),
             "" => Ok(StartExecutableType::Executable), // Default to Executable if no extension is provided.
             // Returns an error for unknown extensions.
             _ => Err(format!("Invalid start executable extension: {:?}", extension).into()),
@@ -0,0 +1,168 @@
+use crate::server::Server;
+use crate::server_process::{kill_unresponsive_server, ServerProcess};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use regex::Regex;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a server may take to print its `Done` line before it is considered hung and killed.
+///
+/// Generous on purpose, the first start of a large modpack generates the spawn area and can take
+/// several minutes.
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+lazy_static! {
+    /// `Done (12.345s)! For help, type "help"` as printed by vanilla and its forks, and
+    /// `Done (1.23s)!` as printed by Velocity. Some locales print a comma as the decimal separator.
+    static ref DONE_LINE: Option<Regex> = Regex::new(r"Done \((?P<seconds>\d+(?:[.,]\d+)?)s\)!").ok();
+    static ref STARTUP_METRICS: Mutex<HashMap<u64, StartupMetrics>> = Mutex::new(HashMap::new());
+}
+
+/// The timing of the last start of a server.
+#[derive(Debug, Clone, Serialize)]
+pub struct StartupMetrics {
+    /// Counts the starts of the server since the manager started.
+    pub attempt: u64,
+    pub started_at: SystemTime,
+    /// When the server printed its `Done` line, `None` while it is still starting.
+    pub online_at: Option<SystemTime>,
+    /// The time from spawning the process until the `Done` line, in milliseconds.
+    pub startup_duration_ms: Option<u64>,
+    /// The startup time the server reported itself, which excludes starting the JVM and loading libraries.
+    pub reported_duration_ms: Option<u64>,
+    /// Whether the server was killed for not finishing its startup within [`STARTUP_TIMEOUT`].
+    pub timed_out: bool,
+}
+
+/// Records the start of a server and starts the thread killing it if it does not finish starting in time.
+pub(crate) fn begin_startup(server_id: u64) {
+    let attempt = match STARTUP_METRICS.lock() {
+        Ok(mut metrics) => {
+            let attempt = metrics.get(&server_id).map_or(1, |last| last.attempt + 1);
+            metrics.insert(
+                server_id,
+                StartupMetrics {
+                    attempt,
+                    started_at: SystemTime::now(),
+                    online_at: None,
+                    startup_duration_ms: None,
+                    reported_duration_ms: None,
+                    timed_out: false,
+                },
+            );
+            attempt
+        }
+        Err(e) => {
+            error!("Failed to record the start of server {}: {}", server_id, e);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        while Instant::now() < deadline {
+            thread::sleep(Duration::from_secs(1));
+            // Stop watching once the server is online, or a newer start replaced this one
+            let still_starting = STARTUP_METRICS.lock().ok().is_some_and(|metrics| {
+                metrics
+                    .get(&server_id)
+                    .is_some_and(|last| last.attempt == attempt && last.online_at.is_none())
+            });
+            if !still_starting {
+                return;
+            }
+        }
+
+        if let Ok(mut metrics) = STARTUP_METRICS.lock() {
+            match metrics.get_mut(&server_id) {
+                Some(last) if last.attempt == attempt && last.online_at.is_none() => last.timed_out = true,
+                _ => return,
+            }
+        }
+        error!(
+            "Server {} did not finish starting within {:?}, killing it",
+            server_id, STARTUP_TIMEOUT
+        );
+        // Killed without a stop request, the exit counts as a crash and the restart policy applies
+        if let Err(e) = kill_unresponsive_server(server_id) {
+            warn!("Failed to kill server {} after its startup timed out: {}", server_id, e);
+        }
+    });
+}
+
+/// Checks a console line of a starting server for its `Done` line, and records the startup time if it is.
+///
+/// Returns whether the line completed the startup.
+pub(crate) fn detect_startup_complete(server_id: u64, line: &str) -> bool {
+    let Some(captures) = DONE_LINE.as_ref().and_then(|done| done.captures(line)) else {
+        return false;
+    };
+    let reported = captures
+        .name("seconds")
+        .and_then(|seconds| seconds.as_str().replace(',', ".").parse::<f64>().ok())
+        .map(|seconds| (seconds * 1000.0) as u64);
+
+    if let Ok(mut metrics) = STARTUP_METRICS.lock() {
+        if let Some(last) = metrics.get_mut(&server_id).filter(|last| last.online_at.is_none()) {
+            let now = SystemTime::now();
+            last.online_at = Some(now);
+            last.startup_duration_ms = now
+                .duration_since(last.started_at)
+                .ok()
+                .map(|duration| duration.as_millis() as u64);
+            last.reported_duration_ms = reported;
+            info!(
+                "Server {} finished starting after {}ms",
+                server_id,
+                last.startup_duration_ms.unwrap_or_default()
+            );
+        }
+    }
+    true
+}
+
+/// Returns the timing of the last start of a server, `None` if it was not started since the manager started.
+pub fn get_startup_metrics(server_id: u64) -> Option<StartupMetrics> {
+    STARTUP_METRICS
+        .lock()
+        .ok()
+        .and_then(|metrics| metrics.get(&server_id).cloned())
+}
+
+pub trait ServerStartup {
+    /// Returns the timing of the last start of the server.
+    fn get_startup_metrics(&self) -> Option<StartupMetrics>;
+
+    /// Blocks until the server finished starting, for actions that must not run while it boots.
+    ///
+    /// # Errors
+    /// Returns an error if the server is not running, exits while starting, or is still starting after `timeout`.
+    fn wait_until_online(&self, timeout: Duration) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerStartup for Server<u64> {
+    fn get_startup_metrics(&self) -> Option<StartupMetrics> {
+        get_startup_metrics(self.id)
+    }
+
+    fn wait_until_online(&self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.is_server_running() {
+                return Err(format!("Server {} is not running", self.id).into());
+            }
+            if get_startup_metrics(self.id).is_some_and(|metrics| metrics.online_at.is_some()) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("Server {} is still starting after {:?}", self.id, timeout).into());
+            }
+            thread::sleep(Duration::from_millis(250));
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use crate::file_system_entry::invalidate_directory_size_cache;
+use crate::file_versions::VersionStore;
+use crate::sandboxed_path::SandboxedPath;
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The largest file `read_text_file` loads by default, larger files are not meant to be edited in a browser.
+pub const DEFAULT_MAX_TEXT_FILE_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The character encoding of a text file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    /// UTF-8 starting with a byte order mark, as written by some Windows editors.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1, the fallback for files that are not valid UTF-8. Older `server.properties` files use it.
+    Latin1,
+}
+
+/// The line ending style of a text file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+}
+
+/// The decoded contents of a text file.
+#[derive(Debug, Clone, Serialize)]
+pub struct TextFile {
+    /// The contents with all line endings normalized to `\n`.
+    pub content: String,
+    /// The detected encoding, pass it back to `write_text_file` to keep the file's encoding.
+    pub encoding: TextEncoding,
+    /// The dominant line ending of the file.
+    pub line_ending: LineEnding,
+    /// The size of the file in bytes.
+    pub size: u64,
+    pub last_modified: SystemTime,
+}
+
+/// Options controlling how a text file is written.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriteTextOptions {
+    /// The encoding to write the contents in.
+    pub encoding: TextEncoding,
+    /// The line ending to write, the contents may use any line ending.
+    pub line_ending: LineEnding,
+    /// When set, the write is refused if the file was modified after this time,
+    /// so two people editing the same file do not silently overwrite each other.
+    pub expected_modified: Option<SystemTime>,
+}
+
+/// Reads a text file, detecting its encoding and line endings.
+///
+/// # Arguments
+/// * `file` - The file to read.
+/// * `max_size` - Files larger than this many bytes are rejected, see [`DEFAULT_MAX_TEXT_FILE_SIZE`].
+///
+/// # Errors
+/// Returns an error if the file is a directory, exceeds `max_size`, looks like a binary file, or cannot be read.
+pub fn read_text_file(file: &SandboxedPath, max_size: u64) -> Result<TextFile, Box<dyn Error>> {
+    let path = file.path();
+    let metadata = fs::metadata(&path)?;
+    if metadata.is_dir() {
+        return Err(format!("{} is a directory", file).into());
+    }
+    if metadata.len() > max_size {
+        return Err(format!("{} is {} bytes, the limit for text files is {} bytes", file, metadata.len(), max_size).into());
+    }
+
+    let bytes = fs::read(&path)?;
+    let encoding = detect_encoding(&bytes);
+    debug!("Detected {:?} encoding for {:?}", encoding, path);
+    let decoded = decode(&bytes, encoding);
+    if encoding != TextEncoding::Utf16Le && encoding != TextEncoding::Utf16Be && decoded.contains('\0') {
+        return Err(format!("{} is not a text file", file).into());
+    }
+
+    let line_ending = detect_line_ending(&decoded);
+    Ok(TextFile {
+        content: normalize_line_endings(&decoded),
+        encoding,
+        line_ending,
+        size: metadata.len(),
+        last_modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+    })
+}
+
+/// Writes a text file atomically.
+///
+/// The contents are written to a temporary file in the same directory, flushed to disk and then
+/// renamed over the original, so a crash or a full disk never leaves a half written config behind.
+/// The permissions of an existing file are kept, and its previous contents are stored as a
+/// version in the server's [`VersionStore`].
+///
+/// # Arguments
+/// * `file` - The file to write. Missing parent directories are created.
+/// * `content` - The new contents, with any line endings.
+/// * `options` - The encoding and line ending to write.
+///
+/// # Errors
+/// Returns an error if the file was modified since `expected_modified`, the contents cannot be
+/// represented in the requested encoding, or writing fails.
+pub fn write_text_file(file: &SandboxedPath, content: &str, options: &WriteTextOptions) -> Result<(), Box<dyn Error>> {
+    let path = file.path();
+    let existing = fs::metadata(&path).ok();
+    if let (Some(expected), Some(metadata)) = (options.expected_modified, &existing) {
+        if metadata.modified().ok() != Some(expected) {
+            return Err(format!("{} was modified by someone else since it was opened", file).into());
+        }
+    }
+
+    let normalized = normalize_line_endings(content);
+    let text = if options.line_ending == LineEnding::Lf {
+        normalized
+    } else {
+        normalized.replace('\n', options.line_ending.as_str())
+    };
+    let bytes = encode(&text, options.encoding)?;
+
+    // Keep the previous contents around so the change can be rolled back
+    if existing.is_some() {
+        if let Err(err) = VersionStore::new(file).snapshot(file) {
+            warn!("Failed to store the previous version of {}: {}", file, err);
+        }
+    }
+
+    write_file_atomically(&path, &bytes)?;
+    info!("Wrote {} bytes to {:?} as {:?}", bytes.len(), path, options.encoding);
+    Ok(())
+}
+
+/// Replaces the contents of a file through a temporary file and a rename, keeping the
+/// permissions of an existing file.
+///
+/// # Errors
+/// Returns an error if the file has no parent directory or writing fails.
+pub(crate) fn write_file_atomically(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    let parent = path.parent().ok_or_else(|| format!("{:?} has no parent directory", path))?;
+    fs::create_dir_all(parent)?;
+    let permissions = fs::metadata(path).ok().map(|metadata| metadata.permissions());
+
+    let temp_path = temp_path_for(path);
+    let result = write_and_rename(&temp_path, path, bytes, permissions);
+    if result.is_err() {
+        if let Err(err) = fs::remove_file(&temp_path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove temporary file {:?}: {}", temp_path, err);
+            }
+        }
+    }
+    result?;
+
+    invalidate_directory_size_cache(parent);
+    Ok(())
+}
+
+fn write_and_rename(
+    temp_path: &Path,
+    path: &Path,
+    bytes: &[u8],
+    permissions: Option<fs::Permissions>,
+) -> Result<(), Box<dyn Error>> {
+    let mut temp_file = File::create(temp_path)?;
+    temp_file.write_all(bytes)?;
+    temp_file.sync_all()?;
+    drop(temp_file);
+    if let Some(permissions) = permissions {
+        fs::set_permissions(temp_path, permissions)?;
+    }
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Builds a hidden temporary file name next to the target, so the rename stays on one filesystem.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()))
+}
+
+/// Detects the encoding of a byte buffer from its byte order mark, falling back to heuristics.
+pub fn detect_encoding(bytes: &[u8]) -> TextEncoding {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return TextEncoding::Utf8Bom;
+    }
+    if bytes.starts_with(&[0xFF, 0xFE]) {
+        return TextEncoding::Utf16Le;
+    }
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        return TextEncoding::Utf16Be;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return TextEncoding::Utf8;
+    }
+
+    // UTF-16 without a byte order mark: ASCII text has a zero byte in every other position
+    if bytes.len() >= 4 && bytes.len() % 2 == 0 {
+        let pairs = bytes.len() / 2;
+        let even_zeros = bytes.iter().step_by(2).filter(|&&byte| byte == 0).count();
+        let odd_zeros = bytes.iter().skip(1).step_by(2).filter(|&&byte| byte == 0).count();
+        if odd_zeros * 10 >= pairs * 9 && even_zeros == 0 {
+            return TextEncoding::Utf16Le;
+        }
+        if even_zeros * 10 >= pairs * 9 && odd_zeros == 0 {
+            return TextEncoding::Utf16Be;
+        }
+    }
+    TextEncoding::Latin1
+}
+
+fn decode(bytes: &[u8], encoding: TextEncoding) -> String {
+    match encoding {
+        TextEncoding::Utf8 => String::from_utf8_lossy(bytes).to_string(),
+        TextEncoding::Utf8Bom => String::from_utf8_lossy(&bytes[3..]).to_string(),
+        TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+            let body = if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+                &bytes[2..]
+            } else {
+                bytes
+            };
+            let units: Vec<u16> = body
+                .chunks_exact(2)
+                .map(|pair| {
+                    if encoding == TextEncoding::Utf16Le {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        // Every byte of ISO-8859-1 maps to the Unicode code point of the same value
+        TextEncoding::Latin1 => bytes.iter().map(|&byte| byte as char).collect(),
+    }
+}
+
+fn encode(text: &str, encoding: TextEncoding) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(match encoding {
+        TextEncoding::Utf8 => text.as_bytes().to_vec(),
+        TextEncoding::Utf8Bom => {
+            let mut bytes = vec![0xEF, 0xBB, 0xBF];
+            bytes.extend_from_slice(text.as_bytes());
+            bytes
+        }
+        TextEncoding::Utf16Le => {
+            let mut bytes = vec![0xFF, 0xFE];
+            bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            bytes
+        }
+        TextEncoding::Utf16Be => {
+            let mut bytes = vec![0xFE, 0xFF];
+            bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+            bytes
+        }
+        TextEncoding::Latin1 => text
+            .chars()
+            .map(|c| u8::try_from(u32::from(c)).map_err(|_| format!("{:?} cannot be written as Latin-1", c)))
+            .collect::<Result<Vec<u8>, _>>()?,
+    })
+}
+
+/// Determines the most common line ending in a text, defaulting to `\n`.
+fn detect_line_ending(text: &str) -> LineEnding {
+    let crlf = text.matches("\r\n").count();
+    let cr = text.matches('\r').count() - crlf;
+    let lf = text.matches('\n').count() - crlf;
+    if crlf > lf && crlf >= cr {
+        LineEnding::CrLf
+    } else if cr > lf && cr > crlf {
+        LineEnding::Cr
+    } else {
+        LineEnding::Lf
+    }
+}
+
+/// Converts `\r\n` and lone `\r` line endings to `\n`.
+fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
@@ -0,0 +1,165 @@
+use crate::file_hash::{hash_reader, HashAlgorithm};
+use crate::file_system_entry::get_mime;
+use crate::sandboxed_path::SandboxedPath;
+use image::imageops::FilterType;
+use image::{GenericImageView, ImageFormat, ImageReader};
+use log::{debug, info, warn};
+use serde_derive::Serialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The directory thumbnails are cached in, relative to the working directory like `servers`.
+pub const THUMBNAIL_CACHE_DIRECTORY: &str = "cache/thumbnails";
+
+/// The default edge length of a thumbnail in pixels.
+pub const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+
+/// The largest edge length a thumbnail may be requested with.
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+
+/// Images larger than this are not decoded, a thumbnail is not worth hundreds of megabytes of memory.
+const MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A rendered thumbnail stored in the cache.
+#[derive(Debug, Clone, Serialize)]
+pub struct Thumbnail {
+    /// The cached PNG file, ready to be streamed as the response body.
+    pub path: PathBuf,
+    /// Always `image/png`, so transparency in server icons is kept.
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    /// The dimensions of the original image.
+    pub source_width: u32,
+    pub source_height: u32,
+}
+
+/// An on-disk cache of image thumbnails.
+///
+/// A thumbnail is rendered on first request and reused until the source image is modified.
+/// Cache files are named after a hash of the source path and size, so renaming a file simply
+/// renders a new thumbnail.
+#[derive(Debug, Clone)]
+pub struct ThumbnailCache {
+    directory: PathBuf,
+}
+
+impl Default for ThumbnailCache {
+    fn default() -> Self {
+        Self::new(THUMBNAIL_CACHE_DIRECTORY)
+    }
+}
+
+impl ThumbnailCache {
+    /// Creates a cache storing its thumbnails in the given directory.
+    pub fn new(directory: impl AsRef<Path>) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the thumbnail of an image, rendering it if it is not cached or out of date.
+    ///
+    /// Images smaller than `max_size` are not scaled up, so a 64x64 `server-icon.png` stays sharp.
+    ///
+    /// # Arguments
+    /// * `image` - The image to render a thumbnail of.
+    /// * `max_size` - The maximum width and height of the thumbnail, capped at 1024 pixels.
+    ///
+    /// # Errors
+    /// Returns an error if the file is not an image, is too large, or cannot be decoded.
+    pub fn get_or_create(&self, image: &SandboxedPath, max_size: u32) -> Result<Thumbnail, Box<dyn Error>> {
+        let source = image.path();
+        let max_size = max_size.clamp(1, MAX_THUMBNAIL_SIZE);
+
+        let is_image = get_mime(&source).is_some_and(|mime| mime.starts_with("image/"));
+        if !is_image {
+            return Err(format!("{} is not an image", image).into());
+        }
+        let metadata = fs::metadata(&source)?;
+        if metadata.len() > MAX_SOURCE_SIZE {
+            return Err(format!("{} is too large to render a thumbnail of", image).into());
+        }
+
+        let cache_path = self.cache_path(&source, max_size)?;
+        let source_modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        if let Some(thumbnail) = read_cached(&cache_path, &source, source_modified) {
+            debug!("Using cached thumbnail {:?} for {:?}", cache_path, source);
+            return Ok(thumbnail);
+        }
+
+        let decoded = ImageReader::open(&source)?.with_guessed_format()?.decode()?;
+        let (source_width, source_height) = decoded.dimensions();
+        let resized = if source_width > max_size || source_height > max_size {
+            decoded.resize(max_size, max_size, FilterType::Lanczos3)
+        } else {
+            decoded
+        };
+
+        fs::create_dir_all(&self.directory)?;
+        // Render into a temporary file so concurrent requests never read a half written thumbnail
+        let temp_path = cache_path.with_extension(format!("{}.tmp", std::process::id()));
+        resized.save_with_format(&temp_path, ImageFormat::Png)?;
+        fs::rename(&temp_path, &cache_path)?;
+        info!("Rendered {}x{} thumbnail of {:?}", resized.width(), resized.height(), source);
+
+        Ok(Thumbnail {
+            path: cache_path,
+            content_type: "image/png".to_string(),
+            width: resized.width(),
+            height: resized.height(),
+            source_width,
+            source_height,
+        })
+    }
+
+    /// Removes cached thumbnails that have not been used for longer than `max_age`.
+    ///
+    /// # Returns
+    /// The number of removed thumbnails.
+    pub fn prune(&self, max_age: std::time::Duration) -> Result<usize, Box<dyn Error>> {
+        if !self.directory.exists() {
+            return Ok(0);
+        }
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.directory)?.flatten() {
+            let accessed = entry.metadata().and_then(|metadata| metadata.accessed().or(metadata.modified()));
+            let expired = accessed.is_ok_and(|accessed| now.duration_since(accessed).unwrap_or_default() > max_age);
+            if expired {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => removed += 1,
+                    Err(err) => warn!("Failed to remove cached thumbnail {:?}: {}", entry.path(), err),
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    fn cache_path(&self, source: &Path, max_size: u32) -> Result<PathBuf, Box<dyn Error>> {
+        let key = format!("{}:{}", fs::canonicalize(source)?.to_string_lossy(), max_size);
+        let hash = hash_reader(key.as_bytes(), HashAlgorithm::Sha256)?;
+        Ok(self.directory.join(format!("{}.png", hash)))
+    }
+}
+
+/// Reads the dimensions of a cached thumbnail, if it exists and is newer than its source.
+fn read_cached(cache_path: &Path, source: &Path, source_modified: SystemTime) -> Option<Thumbnail> {
+    let cache_modified = fs::metadata(cache_path).ok()?.modified().ok()?;
+    if cache_modified < source_modified {
+        return None;
+    }
+    // Only the image headers are read here, not the pixel data
+    let (width, height) = image::image_dimensions(cache_path).ok()?;
+    let (source_width, source_height) = image::image_dimensions(source).ok()?;
+    Some(Thumbnail {
+        path: cache_path.to_path_buf(),
+        content_type: "image/png".to_string(),
+        width,
+        height,
+        source_width,
+        source_height,
+    })
+}
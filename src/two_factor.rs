@@ -0,0 +1,517 @@
+use crate::audit_log::acting_user;
+use crate::file_hash::to_hex;
+use crate::users::{
+    finish_login, get_user, login_keys, login_retry_after, random_token, record_login_failure, Session,
+};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::Serialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name authenticator apps show for the accounts of the panel.
+const ISSUER: &str = "Obsidian Server Portal";
+
+/// The length of a time step, and how many steps before and after the current one are accepted
+/// for clocks that drift.
+const STEP_SECONDS: u64 = 30;
+const ALLOWED_DRIFT_STEPS: u64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// How long a password stays good for finishing a login with a code.
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// How many wrong codes a login may take before the password has to be entered again.
+const MAX_CHALLENGE_ATTEMPTS: u32 = 3;
+
+/// How long after entering a code sensitive actions are allowed without entering another.
+pub const REVERIFICATION_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+    /// The logins waiting for a code, by challenge, with the user, when the password was entered and
+    /// how many wrong codes were entered since.
+    static ref CHALLENGES: Mutex<HashMap<String, (u64, SystemTime, u32)>> = Mutex::new(HashMap::new());
+    /// When the users last entered a code.
+    static ref VERIFIED_AT: Mutex<HashMap<u64, SystemTime>> = Mutex::new(HashMap::new());
+}
+
+/// The two-factor authentication of an account.
+#[derive(Debug, Clone, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    /// Whether sensitive actions, like deleting backups, ask for a code again.
+    pub reverify_sensitive_actions: bool,
+    pub recovery_codes_left: usize,
+}
+
+/// A secret to add to an authenticator app, by scanning the URI as a QR code or typing the secret.
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpEnrollment {
+    /// The secret in base32.
+    pub secret: String,
+    /// The `otpauth://` URI to show as a QR code.
+    pub provisioning_uri: String,
+}
+
+/// Creates the table holding the two-factor secrets of the users.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_two_factor_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `user_two_factor` (
+            user_id INTEGER PRIMARY KEY,                                -- The user the secret belongs to
+            secret TEXT NOT NULL,                                       -- The TOTP secret in base32
+            enabled BOOLEAN NOT NULL DEFAULT 0,                         -- Whether enrollment was confirmed
+            reverify_sensitive BOOLEAN NOT NULL DEFAULT 0,              -- Whether sensitive actions ask for a code
+            recovery_codes TEXT NOT NULL DEFAULT '[]',                  -- The SHA-256 of the unused recovery codes
+            last_step INTEGER NOT NULL DEFAULT 0                        -- The last time step used, against replays
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Starts enrolling a user, replacing any enrollment that was not confirmed yet.
+///
+/// Two-factor authentication is only enabled once a code from the app is confirmed with
+/// [`confirm_enrollment`].
+///
+/// # Errors
+/// Returns an error if the user does not exist, is not the acting user, or already has two-factor
+/// authentication enabled.
+pub fn begin_enrollment(user_id: u64) -> Result<TotpEnrollment, Box<dyn Error>> {
+    require_own_account(user_id)?;
+    let user = get_user(user_id)?;
+    if get_two_factor_status(user_id)?.enabled {
+        return Err("Two-factor authentication is already enabled, disable it first".into());
+    }
+    let mut secret = [0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    let secret = base32_encode(&secret);
+
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO user_two_factor (user_id, secret) VALUES (?, ?)
+        ON CONFLICT(user_id) DO UPDATE
+        SET secret = excluded.secret, enabled = 0, reverify_sensitive = 0, recovery_codes = '[]', last_step = 0"#,
+    )?;
+    statement.bind((1, user_id as i64))?;
+    statement.bind((2, secret.as_str()))?;
+    statement.next()?;
+
+    let label = percent_encode(&format!("{}:{}", ISSUER, user.username));
+    let provisioning_uri = format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits=6&period={}",
+        label,
+        secret,
+        percent_encode(ISSUER),
+        STEP_SECONDS
+    );
+    Ok(TotpEnrollment {
+        secret,
+        provisioning_uri,
+    })
+}
+
+/// Enables two-factor authentication once the user entered a code from their app.
+///
+/// # Returns
+/// The recovery codes, each good for one login without the app. They are only shown now.
+///
+/// # Errors
+/// Returns an error if there is no enrollment to confirm or the code is wrong.
+pub fn confirm_enrollment(user_id: u64, code: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    require_own_account(user_id)?;
+    let secret = read_secret(user_id)?.ok_or("Start the enrollment first")?;
+    if secret.enabled {
+        return Err("Two-factor authentication is already enabled".into());
+    }
+    let step =
+        verify_totp(&secret.secret, code, secret.last_step).ok_or("The code is wrong, check the clock of the device")?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut code = [0u8; 5];
+            OsRng.fill_bytes(&mut code);
+            to_hex(&code)
+        })
+        .collect();
+    let hashes: Vec<String> = codes.iter().map(|code| hash_code(code)).collect();
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare("UPDATE user_two_factor SET enabled = 1, recovery_codes = ?, last_step = ? WHERE user_id = ?")?;
+    statement.bind((1, serde_json::to_string(&hashes)?.as_str()))?;
+    statement.bind((2, step as i64))?;
+    statement.bind((3, user_id as i64))?;
+    statement.next()?;
+    info!("Enabled two-factor authentication for user {}", user_id);
+    Ok(codes)
+}
+
+/// Turns two-factor authentication off, after checking a code or a recovery code. Wrong codes count
+/// as failed logins of the account.
+///
+/// # Errors
+/// Returns an error if it is not enabled, the account is locked out after too many failed logins,
+/// or the code is wrong.
+pub fn disable_two_factor(user_id: u64, code: &str) -> Result<(), Box<dyn Error>> {
+    require_own_account(user_id)?;
+    verify_code_throttled(user_id, code)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM user_two_factor WHERE user_id = ?")?;
+    statement.bind((1, user_id as i64))?;
+    statement.next()?;
+    info!("Disabled two-factor authentication for user {}", user_id);
+    Ok(())
+}
+
+/// Sets whether sensitive actions of a user ask for a code again, see [`require_reverification`].
+///
+/// # Errors
+/// Returns an error if the user does not have two-factor authentication enabled.
+pub fn set_reverify_sensitive_actions(user_id: u64, reverify: bool) -> Result<(), Box<dyn Error>> {
+    require_own_account(user_id)?;
+    if !get_two_factor_status(user_id)?.enabled {
+        return Err("Two-factor authentication is not enabled".into());
+    }
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("UPDATE user_two_factor SET reverify_sensitive = ? WHERE user_id = ?")?;
+    statement.bind((1, reverify as i64))?;
+    statement.bind((2, user_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// The two-factor authentication of a user.
+pub fn get_two_factor_status(user_id: u64) -> Result<TwoFactorStatus, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM user_two_factor WHERE user_id = ?")?;
+    statement.bind((1, user_id as i64))?;
+    if let State::Row = statement.next()? {
+        let codes: Vec<String> = serde_json::from_str(&statement.read::<String, _>("recovery_codes")?)?;
+        return Ok(TwoFactorStatus {
+            enabled: statement.read::<i64, _>("enabled")? != 0,
+            reverify_sensitive_actions: statement.read::<i64, _>("reverify_sensitive")? != 0,
+            recovery_codes_left: codes.len(),
+        });
+    }
+    Ok(TwoFactorStatus {
+        enabled: false,
+        reverify_sensitive_actions: false,
+        recovery_codes_left: 0,
+    })
+}
+
+/// Creates the challenge a login of a user with two-factor authentication continues with, once
+/// the password was checked.
+pub(crate) fn begin_challenge(user_id: u64) -> String {
+    let challenge = random_token(24);
+    if let Ok(mut challenges) = CHALLENGES.lock() {
+        let now = SystemTime::now();
+        challenges.retain(|_, (_, created, _)| now.duration_since(*created).unwrap_or_default() < CHALLENGE_LIFETIME);
+        challenges.insert(challenge.clone(), (user_id, now, 0));
+    }
+    challenge
+}
+
+/// Finishes a login that was answered with [`crate::users::LoginOutcome::TwoFactorRequired`], with
+/// a code from the app or a recovery code.
+///
+/// Wrong codes count as failed logins of the account, and after [`MAX_CHALLENGE_ATTEMPTS`] of them
+/// the challenge is dropped, so the password has to be entered again.
+///
+/// # Errors
+/// Returns an error if the challenge is unknown or expired, the account is locked out after too
+/// many failed logins, or the code is wrong.
+pub fn complete_login(challenge: &str, code: &str, remote_address: Option<&str>) -> Result<Session, Box<dyn Error>> {
+    let user_id = CHALLENGES
+        .lock()
+        .ok()
+        .and_then(|challenges| challenges.get(challenge).copied())
+        .filter(|(_, created, _)| SystemTime::now().duration_since(*created).unwrap_or_default() < CHALLENGE_LIFETIME)
+        .map(|(user_id, _, _)| user_id)
+        .ok_or("The login expired, enter the password again")?;
+    let user = get_user(user_id)?;
+    let keys = login_keys(&user.username, remote_address);
+    if let Some(retry_after) = login_retry_after(&keys) {
+        return Err(format!("Too many failed logins, try again in {} seconds", retry_after.as_secs().max(1)).into());
+    }
+
+    if let Err(e) = verify_code(user_id, code) {
+        record_login_failure(&keys);
+        warn!("Wrong two-factor code for {:?} from {:?}", user.username, remote_address);
+        if let Ok(mut challenges) = CHALLENGES.lock() {
+            let attempts = challenges.get_mut(challenge).map(|(_, _, attempts)| {
+                *attempts += 1;
+                *attempts
+            });
+            if attempts.is_some_and(|attempts| attempts >= MAX_CHALLENGE_ATTEMPTS) {
+                challenges.remove(challenge);
+                return Err("Too many wrong codes, enter the password again".into());
+            }
+        }
+        return Err(e);
+    }
+    if let Ok(mut challenges) = CHALLENGES.lock() {
+        challenges.remove(challenge);
+    }
+    finish_login(user, &keys, remote_address)
+}
+
+/// Records that the user entered a code again, which allows sensitive actions for a few minutes.
+/// Wrong codes count as failed logins of the account.
+///
+/// # Errors
+/// Returns an error if the account is locked out after too many failed logins or the code is wrong.
+pub fn reverify(user_id: u64, code: &str) -> Result<(), Box<dyn Error>> {
+    require_own_account(user_id)?;
+    verify_code_throttled(user_id, code)
+}
+
+/// Fails if a user other than `user_id` is acting, as only the user holds their authenticator app.
+fn require_own_account(user_id: u64) -> Result<(), Box<dyn Error>> {
+    match acting_user() {
+        Some(acting) if acting != user_id => Err("Only the user can change their own two-factor authentication".into()),
+        _ => Ok(()),
+    }
+}
+
+/// Checks a code outside of a login, throttled like the codes of a login, so a session cannot be
+/// used to guess them.
+fn verify_code_throttled(user_id: u64, code: &str) -> Result<(), Box<dyn Error>> {
+    let user = get_user(user_id)?;
+    let keys = login_keys(&user.username, None);
+    if let Some(retry_after) = login_retry_after(&keys) {
+        return Err(format!("Too many failed logins, try again in {} seconds", retry_after.as_secs().max(1)).into());
+    }
+    if let Err(e) = verify_code(user_id, code) {
+        record_login_failure(&keys);
+        warn!("Wrong two-factor code for {:?}", user.username);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Fails if the user acting on this thread asked for sensitive actions to need a recent code and
+/// did not enter one within [`REVERIFICATION_WINDOW`]. Actions without an acting user are allowed.
+///
+/// # Errors
+/// Returns an error asking for a code when one is needed.
+pub fn require_reverification() -> Result<(), Box<dyn Error>> {
+    let Some(user_id) = acting_user() else {
+        return Ok(());
+    };
+    let status = get_two_factor_status(user_id)?;
+    if !status.enabled || !status.reverify_sensitive_actions {
+        return Ok(());
+    }
+    let verified = VERIFIED_AT
+        .lock()
+        .ok()
+        .and_then(|verified| verified.get(&user_id).copied())
+        .is_some_and(|at| SystemTime::now().duration_since(at).unwrap_or_default() < REVERIFICATION_WINDOW);
+    if !verified {
+        return Err("This action needs a code from the authenticator app, enter one to continue".into());
+    }
+    Ok(())
+}
+
+/// Checks a code from the app, or uses up a recovery code, and remembers when it was entered.
+fn verify_code(user_id: u64, code: &str) -> Result<(), Box<dyn Error>> {
+    let secret = read_secret(user_id)?
+        .filter(|secret| secret.enabled)
+        .ok_or("Two-factor authentication is not enabled")?;
+
+    let conn = create_appdb_connection()?;
+    if let Some(step) = verify_totp(&secret.secret, code, secret.last_step) {
+        let mut statement = conn.prepare("UPDATE user_two_factor SET last_step = ? WHERE user_id = ?")?;
+        statement.bind((1, step as i64))?;
+        statement.bind((2, user_id as i64))?;
+        statement.next()?;
+    } else {
+        let hash = hash_code(&code.trim().to_lowercase());
+        if !secret.recovery_codes.contains(&hash) {
+            return Err("The code is wrong".into());
+        }
+        let left: Vec<&String> = secret.recovery_codes.iter().filter(|code| **code != hash).collect();
+        let mut statement = conn.prepare("UPDATE user_two_factor SET recovery_codes = ? WHERE user_id = ?")?;
+        statement.bind((1, serde_json::to_string(&left)?.as_str()))?;
+        statement.bind((2, user_id as i64))?;
+        statement.next()?;
+        warn!("User {} used a recovery code, {} are left", user_id, left.len());
+    }
+
+    if let Ok(mut verified) = VERIFIED_AT.lock() {
+        verified.insert(user_id, SystemTime::now());
+    }
+    Ok(())
+}
+
+struct TotpSecret {
+    secret: String,
+    enabled: bool,
+    /// The hashes of the unused recovery codes.
+    recovery_codes: Vec<String>,
+    last_step: u64,
+}
+
+fn read_secret(user_id: u64) -> Result<Option<TotpSecret>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM user_two_factor WHERE user_id = ?")?;
+    statement.bind((1, user_id as i64))?;
+    if let State::Row = statement.next()? {
+        return Ok(Some(TotpSecret {
+            secret: statement.read::<String, _>("secret")?,
+            enabled: statement.read::<i64, _>("enabled")? != 0,
+            recovery_codes: serde_json::from_str(&statement.read::<String, _>("recovery_codes")?)?,
+            last_step: statement.read::<i64, _>("last_step")? as u64,
+        }));
+    }
+    Ok(None)
+}
+
+/// Whether two-factor authentication is enabled for a user, for the login to ask for a code.
+pub(crate) fn is_two_factor_enabled(user_id: u64) -> Result<bool, Box<dyn Error>> {
+    Ok(get_two_factor_status(user_id)?.enabled)
+}
+
+/// Finds the time step a code is for, near the current one and after the last one used, so a code
+/// cannot be used twice.
+fn verify_totp(secret: &str, code: &str, last_step: u64) -> Option<u64> {
+    let code = code.trim().replace(' ', "");
+    if code.len() != 6 || !code.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let key = base32_decode(secret)?;
+    let current = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP_SECONDS;
+    (current.saturating_sub(ALLOWED_DRIFT_STEPS)..=current + ALLOWED_DRIFT_STEPS)
+        .filter(|step| *step > last_step)
+        .find(|step| totp(&key, *step).is_some_and(|expected| expected == code))
+}
+
+/// The six digit code of a time step, as in RFC 6238.
+fn totp(key: &[u8], step: u64) -> Option<String> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest.last()? & 0x0f) as usize;
+    let bytes: [u8; 4] = digest.get(offset..offset + 4)?.try_into().ok()?;
+    let value = u32::from_be_bytes(bytes) & 0x7fff_ffff;
+    Some(format!("{:06}", value % 1_000_000))
+}
+
+fn hash_code(code: &str) -> String {
+    to_hex(&Sha256::digest(code.as_bytes()))
+}
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in text.trim_end_matches('=').chars() {
+        let value = BASE32_ALPHABET.iter().position(|letter| *letter as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Encodes the characters an `otpauth://` label or parameter cannot contain.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_matches_the_rfc_6238_test_vectors() {
+        // The SHA-1 vectors of RFC 6238 appendix B, cut to six digits
+        let key = b"12345678901234567890";
+        for (time, code) in [
+            (59u64, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(totp(key, time / STEP_SECONDS).as_deref(), Some(code), "{}", time);
+        }
+    }
+
+    #[test]
+    fn codes_are_only_accepted_once() -> Result<(), Box<dyn Error>> {
+        let secret = base32_encode(b"12345678901234567890");
+        let step = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / STEP_SECONDS;
+        let code = totp(b"12345678901234567890", step).ok_or("No code")?;
+        let used = verify_totp(&secret, &code, 0).ok_or("The current code was refused")?;
+        assert!(verify_totp(&secret, &code, used).is_none());
+        assert!(verify_totp(&secret, "12345", 0).is_none());
+        assert!(verify_totp(&secret, "abcdef", 0).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn base32_matches_the_rfc_4648_test_vectors() {
+        for (text, encoded) in [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32_encode(text.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded), Some(text.as_bytes().to_vec()));
+        }
+        assert_eq!(base32_decode("mzxw6==="), Some(b"foo".to_vec()));
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn base32_round_trips_secrets() {
+        let mut secret = [0u8; 20];
+        OsRng.fill_bytes(&mut secret);
+        assert_eq!(base32_decode(&base32_encode(&secret)), Some(secret.to_vec()));
+    }
+}
@@ -0,0 +1,604 @@
+use crate::audit_log::acting_user;
+use crate::file_hash::to_hex;
+use crate::two_factor::{begin_challenge, is_two_factor_enabled};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlite::State;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The name of the cookie carrying the session token.
+pub const SESSION_COOKIE: &str = "obsidian_session";
+
+/// How long a session lasts without being used.
+pub const SESSION_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The name of the account created on the first run.
+pub const DEFAULT_ADMIN_USERNAME: &str = "admin";
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// How many failed logins for an account, or from an address, are allowed within
+/// [`LOGIN_FAILURE_WINDOW`] before further attempts are refused.
+const MAX_LOGIN_FAILURES: usize = 5;
+const LOGIN_FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+lazy_static! {
+    /// The times of the recent failed logins, by lowercased username and by remote address.
+    static ref LOGIN_FAILURES: Mutex<HashMap<String, Vec<SystemTime>>> = Mutex::new(HashMap::new());
+}
+
+/// An account of the panel.
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+    pub is_admin: bool,
+    /// Whether the password has to be changed before anything else is allowed, set for the
+    /// account created on the first run and for passwords set by an administrator.
+    pub must_change_password: bool,
+    /// In seconds since the Unix epoch.
+    pub created_at: u64,
+    pub last_login_at: Option<u64>,
+}
+
+/// A login to the panel.
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    /// The secret identifying the session, only known to the client. The database keeps a hash of it.
+    #[serde(skip_serializing)]
+    pub token: String,
+    pub user: User,
+    /// In seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+impl Session {
+    /// The value of a `Set-Cookie` header handing the session to a browser.
+    pub fn cookie(&self) -> String {
+        format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+            SESSION_COOKIE,
+            self.token,
+            SESSION_LIFETIME.as_secs()
+        )
+    }
+}
+
+/// The value of a `Set-Cookie` header removing the session cookie from a browser, for logging out.
+pub fn clear_session_cookie() -> String {
+    format!("{}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Strict", SESSION_COOKIE)
+}
+
+/// Finds the session token in the value of a `Cookie` header.
+pub fn session_token_from_cookies(header: &str) -> Option<&str> {
+    header.split(';').find_map(|cookie| {
+        let (name, value) = cookie.trim().split_once('=')?;
+        (name == SESSION_COOKIE && !value.is_empty()).then_some(value)
+    })
+}
+
+/// How a login went once the password was right.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum LoginOutcome {
+    LoggedIn { session: Session },
+    /// The account has two-factor authentication, the login continues with a code and the
+    /// challenge, see [`crate::two_factor::complete_login`].
+    TwoFactorRequired { challenge: String },
+}
+
+/// A new account.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+    /// Makes the user choose their own password on their first login.
+    #[serde(default = "default_must_change_password")]
+    pub must_change_password: bool,
+}
+
+fn default_must_change_password() -> bool {
+    true
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Creates the tables holding the accounts and their sessions, and the default administrator on
+/// the first run, see [`ensure_admin_user`].
+///
+/// # Errors
+/// Returns an error if the database connection fails or a table cannot be created.
+pub fn initialize_user_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `users` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the user, the owner of servers
+            username TEXT NOT NULL UNIQUE COLLATE NOCASE,               -- The name to log in with
+            password_hash TEXT NOT NULL,                                -- The Argon2 hash of the password in PHC format
+            is_admin BOOLEAN NOT NULL DEFAULT 0,                        -- Whether the user manages the panel
+            must_change_password BOOLEAN NOT NULL DEFAULT 0,            -- Whether the password has to be changed first
+            created_at INTEGER NOT NULL,                                -- Unix time the account was created
+            last_login_at INTEGER                                       -- Unix time of the last login
+        );
+        CREATE TABLE IF NOT EXISTS `user_sessions` (
+            token_hash TEXT PRIMARY KEY,                                -- The SHA-256 of the session token
+            user_id INTEGER NOT NULL,                                   -- The user who logged in
+            created_at INTEGER NOT NULL,                                -- Unix time of the login
+            expires_at INTEGER NOT NULL                                 -- Unix time the session ends unless used
+        );
+        CREATE INDEX IF NOT EXISTS `user_sessions_user` ON `user_sessions` (user_id);
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    ensure_admin_user()?;
+    Ok(())
+}
+
+/// Creates the default administrator if there are no accounts yet, with a random password that
+/// has to be changed on the first login.
+///
+/// # Returns
+/// The generated password if the account was created. It is also logged, as the only way to get
+/// into a new panel.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the account cannot be stored.
+pub fn ensure_admin_user() -> Result<Option<String>, Box<dyn Error>> {
+    if !list_users()?.is_empty() {
+        return Ok(None);
+    }
+    let password = random_token(12);
+    create_user(&NewUser {
+        username: DEFAULT_ADMIN_USERNAME.to_string(),
+        password: password.clone(),
+        is_admin: true,
+        must_change_password: true,
+    })?;
+    warn!(
+        "Created the user {:?} with the password {:?}, it has to be changed on the first login",
+        DEFAULT_ADMIN_USERNAME, password
+    );
+    Ok(Some(password))
+}
+
+/// Creates an account.
+///
+/// # Returns
+/// The identifier of the new user.
+///
+/// # Errors
+/// Returns an error if the acting user is not an administrator, the username is taken or invalid,
+/// or the password is too short.
+pub fn create_user(user: &NewUser) -> Result<u64, Box<dyn Error>> {
+    require_admin()?;
+    let username = user.username.trim();
+    validate_username(username)?;
+    validate_password(&user.password)?;
+    if find_user(username)?.is_some() {
+        return Err(format!("The username {:?} is already taken", username).into());
+    }
+
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO users (username, password_hash, is_admin, must_change_password, created_at)
+        VALUES (?, ?, ?, ?, ?)"#,
+    )?;
+    statement.bind((1, username))?;
+    statement.bind((2, hash_password(&user.password)?.as_str()))?;
+    statement.bind((3, user.is_admin as i64))?;
+    statement.bind((4, user.must_change_password as i64))?;
+    statement.bind((5, now() as i64))?;
+    statement.next()?;
+    let id = last_inserted_id("users")?;
+    info!("Created the user {:?}", username);
+    Ok(id)
+}
+
+/// Fails unless the acting user is an administrator. Actions without an acting user, like those of
+/// the manager itself, pass.
+pub(crate) fn require_admin() -> Result<(), Box<dyn Error>> {
+    match acting_user() {
+        Some(user_id) if !get_user(user_id)?.is_admin => Err("Only administrators can do this".into()),
+        _ => Ok(()),
+    }
+}
+
+/// Lists the accounts, by username.
+pub fn list_users() -> Result<Vec<User>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM users ORDER BY username COLLATE NOCASE")?;
+    let mut users = Vec::new();
+    while let State::Row = statement.next()? {
+        users.push(read_user(&statement)?);
+    }
+    Ok(users)
+}
+
+/// Returns the account with an identifier.
+///
+/// # Errors
+/// Returns an error if there is no such user.
+pub fn get_user(id: u64) -> Result<User, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM users WHERE id = ?")?;
+    statement.bind((1, id as i64))?;
+    if let State::Row = statement.next()? {
+        return read_user(&statement);
+    }
+    Err(format!("No user with the id {}", id).into())
+}
+
+/// Removes an account and ends its sessions.
+///
+/// # Errors
+/// Returns an error if the acting user is not an administrator, or it is the last administrator,
+/// who could not be replaced.
+pub fn delete_user(id: u64) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    let user = get_user(id)?;
+    if user.is_admin && list_users()?.iter().filter(|other| other.is_admin).count() == 1 {
+        return Err("The last administrator cannot be deleted".into());
+    }
+    let conn = create_appdb_connection()?;
+    for table in ["user_sessions", "user_two_factor", "server_permissions", "api_tokens"] {
+        let mut statement = conn.prepare(format!("DELETE FROM {} WHERE user_id = ?", table))?;
+        statement.bind((1, id as i64))?;
+        statement.next()?;
+    }
+    let mut statement = conn.prepare("DELETE FROM users WHERE id = ?")?;
+    statement.bind((1, id as i64))?;
+    statement.next()?;
+    info!("Deleted the user {:?}", user.username);
+    Ok(())
+}
+
+/// Sets the password of an account for an administrator, who hands it over. The user has to
+/// change it on the next login, and their current sessions end.
+///
+/// # Errors
+/// Returns an error if the acting user is not an administrator, there is no such user or the
+/// password is too short.
+pub fn reset_password(id: u64, password: &str) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    validate_password(password)?;
+    let user = get_user(id)?;
+    store_password(id, password, true)?;
+    end_sessions(id, None)?;
+    info!("Reset the password of the user {:?}", user.username);
+    Ok(())
+}
+
+/// Logs a user in.
+///
+/// After [`MAX_LOGIN_FAILURES`] failed attempts for an account or from an address within 15
+/// minutes, further attempts are refused until the oldest of them is older than that, even with
+/// the right password.
+///
+/// # Arguments
+/// * `username` - The name of the account, case insensitively.
+/// * `password` - Its password.
+/// * `remote_address` - Where the attempt came from, to also limit attempts across accounts.
+///
+/// # Errors
+/// Returns an error if the username or password is wrong or there were too many failed attempts.
+pub fn login(username: &str, password: &str, remote_address: Option<&str>) -> Result<LoginOutcome, Box<dyn Error>> {
+    let keys = login_keys(username, remote_address);
+    if let Some(retry_after) = login_retry_after(&keys) {
+        return Err(format!("Too many failed logins, try again in {} seconds", retry_after.as_secs().max(1)).into());
+    }
+
+    let verified = match find_user(username.trim())? {
+        Some((user, hash)) => verify_password(password, &hash).then_some(user),
+        None => {
+            // Hash anyway, so the time taken does not tell which usernames exist
+            let _ = hash_password(password);
+            None
+        }
+    };
+    let Some(user) = verified else {
+        record_login_failure(&keys);
+        warn!("Failed login as {:?} from {:?}", username, remote_address);
+        return Err("Wrong username or password".into());
+    };
+    if is_two_factor_enabled(user.id)? {
+        return Ok(LoginOutcome::TwoFactorRequired {
+            challenge: begin_challenge(user.id),
+        });
+    }
+    Ok(LoginOutcome::LoggedIn {
+        session: finish_login(user, &keys, remote_address)?,
+    })
+}
+
+/// The keys the failed logins of an account are counted under.
+pub(crate) fn login_keys(username: &str, remote_address: Option<&str>) -> Vec<String> {
+    let mut keys = vec![format!("user:{}", username.trim().to_lowercase())];
+    if let Some(address) = remote_address {
+        keys.push(format!("address:{}", address));
+    }
+    keys
+}
+
+/// Issues the session of a login that passed every check.
+pub(crate) fn finish_login(
+    user: User,
+    keys: &[String],
+    remote_address: Option<&str>,
+) -> Result<Session, Box<dyn Error>> {
+    clear_login_failures(keys);
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("UPDATE users SET last_login_at = ? WHERE id = ?")?;
+    statement.bind((1, now() as i64))?;
+    statement.bind((2, user.id as i64))?;
+    statement.next()?;
+    let mut statement = conn.prepare("DELETE FROM user_sessions WHERE expires_at < ?")?;
+    statement.bind((1, now() as i64))?;
+    statement.next()?;
+
+    info!("User {:?} logged in from {:?}", user.username, remote_address);
+    issue_session(get_user(user.id)?)
+}
+
+/// Returns the session of a token, extending it as it is used.
+///
+/// Users that [must change their password](User::must_change_password) should only be let
+/// through to [`change_password`].
+///
+/// # Errors
+/// Returns an error if the token is unknown or the session expired.
+pub fn authenticate(token: &str) -> Result<Session, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT user_id, expires_at FROM user_sessions WHERE token_hash = ?")?;
+    statement.bind((1, hash_token(token).as_str()))?;
+    let State::Row = statement.next()? else {
+        return Err("Not logged in".into());
+    };
+    let user_id = statement.read::<i64, _>("user_id")? as u64;
+    if (statement.read::<i64, _>("expires_at")? as u64) < now() {
+        logout(token)?;
+        return Err("The session expired, log in again".into());
+    }
+
+    let expires_at = now() + SESSION_LIFETIME.as_secs();
+    let mut statement = conn.prepare("UPDATE user_sessions SET expires_at = ? WHERE token_hash = ?")?;
+    statement.bind((1, expires_at as i64))?;
+    statement.bind((2, hash_token(token).as_str()))?;
+    statement.next()?;
+    Ok(Session {
+        token: token.to_string(),
+        user: get_user(user_id)?,
+        expires_at,
+    })
+}
+
+/// Ends a session.
+pub fn logout(token: &str) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM user_sessions WHERE token_hash = ?")?;
+    statement.bind((1, hash_token(token).as_str()))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Changes the password of the user of a session. Their other sessions end, which logs out
+/// anyone who knew the old password.
+///
+/// # Errors
+/// Returns an error if the session is not valid, the current password is wrong, or the new one
+/// is too short or the same as the current one.
+pub fn change_password(token: &str, current_password: &str, new_password: &str) -> Result<(), Box<dyn Error>> {
+    let session = authenticate(token)?;
+    let (_, hash) = find_user(&session.user.username)?.ok_or("The user no longer exists")?;
+    if !verify_password(current_password, &hash) {
+        return Err("The current password is wrong".into());
+    }
+    validate_password(new_password)?;
+    if new_password == current_password {
+        return Err("The new password has to differ from the current one".into());
+    }
+    store_password(session.user.id, new_password, false)?;
+    end_sessions(session.user.id, Some(token))?;
+    info!("User {:?} changed their password", session.user.username);
+    Ok(())
+}
+
+fn issue_session(user: User) -> Result<Session, Box<dyn Error>> {
+    let token = random_token(32);
+    let expires_at = now() + SESSION_LIFETIME.as_secs();
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare("INSERT INTO user_sessions (token_hash, user_id, created_at, expires_at) VALUES (?, ?, ?, ?)")?;
+    statement.bind((1, hash_token(&token).as_str()))?;
+    statement.bind((2, user.id as i64))?;
+    statement.bind((3, now() as i64))?;
+    statement.bind((4, expires_at as i64))?;
+    statement.next()?;
+    Ok(Session {
+        token,
+        user,
+        expires_at,
+    })
+}
+
+/// Ends every session of a user, except the one with the token `keep`.
+fn end_sessions(user_id: u64, keep: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("DELETE FROM user_sessions WHERE user_id = ? AND token_hash IS NOT ?")?;
+    statement.bind((1, user_id as i64))?;
+    statement.bind((2, keep.map(hash_token).as_deref()))?;
+    statement.next()?;
+    Ok(())
+}
+
+fn store_password(user_id: u64, password: &str, must_change: bool) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("UPDATE users SET password_hash = ?, must_change_password = ? WHERE id = ?")?;
+    statement.bind((1, hash_password(password)?.as_str()))?;
+    statement.bind((2, must_change as i64))?;
+    statement.bind((3, user_id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Finds an account by username, case insensitively, with its password hash.
+fn find_user(username: &str) -> Result<Option<(User, String)>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare("SELECT * FROM users WHERE username = ? COLLATE NOCASE")?;
+    statement.bind((1, username))?;
+    if let State::Row = statement.next()? {
+        let hash = statement.read::<String, _>("password_hash")?;
+        return Ok(Some((read_user(&statement)?, hash)));
+    }
+    Ok(None)
+}
+
+fn read_user(statement: &sqlite::Statement) -> Result<User, Box<dyn Error>> {
+    Ok(User {
+        id: statement.read::<i64, _>("id")? as u64,
+        username: statement.read::<String, _>("username")?,
+        is_admin: statement.read::<i64, _>("is_admin")? != 0,
+        must_change_password: statement.read::<i64, _>("must_change_password")? != 0,
+        created_at: statement.read::<i64, _>("created_at")? as u64,
+        last_login_at: statement.read::<Option<i64>, _>("last_login_at")?.map(|at| at as u64),
+    })
+}
+
+fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format!("Failed to hash the password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+/// Tokens are random enough that a fast hash is enough to keep them out of the database.
+pub(crate) fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub(crate) fn random_token(bytes: usize) -> String {
+    let mut buffer = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buffer);
+    URL_SAFE_NO_PAD.encode(buffer)
+}
+
+fn validate_username(username: &str) -> Result<(), Box<dyn Error>> {
+    let valid = (1..=32).contains(&username.len())
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err("A username has 1 to 32 letters, digits, dots, dashes or underscores".into());
+    }
+    Ok(())
+}
+
+fn validate_password(password: &str) -> Result<(), Box<dyn Error>> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("A password has at least {} characters", MIN_PASSWORD_LENGTH).into());
+    }
+    Ok(())
+}
+
+/// How long until another login may be attempted for any of the keys, `None` if it may now.
+pub(crate) fn login_retry_after(keys: &[String]) -> Option<Duration> {
+    let now = SystemTime::now();
+    let mut failures = LOGIN_FAILURES.lock().ok()?;
+    failures.retain(|_, times| {
+        times.retain(|time| now.duration_since(*time).unwrap_or_default() < LOGIN_FAILURE_WINDOW);
+        !times.is_empty()
+    });
+    keys.iter()
+        .filter_map(|key| failures.get(key))
+        .filter(|times| times.len() >= MAX_LOGIN_FAILURES)
+        .filter_map(|times| times.iter().min())
+        .map(|oldest| LOGIN_FAILURE_WINDOW.saturating_sub(now.duration_since(*oldest).unwrap_or_default()))
+        .max()
+}
+
+pub(crate) fn record_login_failure(keys: &[String]) {
+    if let Ok(mut failures) = LOGIN_FAILURES.lock() {
+        for key in keys {
+            failures.entry(key.clone()).or_default().push(SystemTime::now());
+        }
+    }
+}
+
+fn clear_login_failures(keys: &[String]) {
+    if let Ok(mut failures) = LOGIN_FAILURES.lock() {
+        // A correct password clears the account, but not the address, which may be guessing others
+        if let Some(account) = keys.first() {
+            failures.remove(account);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_against_their_hash() -> Result<(), Box<dyn Error>> {
+        let hash = hash_password("correct horse")?;
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("correct hose", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_are_salted() -> Result<(), Box<dyn Error>> {
+        assert_ne!(hash_password("correct horse")?, hash_password("correct horse")?);
+        Ok(())
+    }
+
+    #[test]
+    fn logins_are_refused_after_too_many_failures() {
+        let keys = login_keys("Throttled", Some("192.0.2.10"));
+        assert_eq!(keys, ["user:throttled", "address:192.0.2.10"]);
+        for _ in 1..MAX_LOGIN_FAILURES {
+            record_login_failure(&keys);
+        }
+        assert_eq!(login_retry_after(&keys), None);
+        record_login_failure(&keys);
+        let retry_after = login_retry_after(&keys);
+        assert!(retry_after.is_some_and(|after| after > Duration::ZERO && after <= LOGIN_FAILURE_WINDOW));
+    }
+
+    #[test]
+    fn failures_from_an_address_count_across_accounts() {
+        for username in ["first", "second", "third", "fourth", "fifth"] {
+            record_login_failure(&login_keys(username, Some("192.0.2.20")));
+        }
+        assert!(login_retry_after(&login_keys("sixth", Some("192.0.2.20"))).is_some());
+        assert_eq!(login_retry_after(&login_keys("sixth", Some("192.0.2.21"))), None);
+    }
+
+    #[test]
+    fn a_correct_password_clears_the_account_but_not_the_address() {
+        let keys = login_keys("cleared", Some("192.0.2.30"));
+        for _ in 0..MAX_LOGIN_FAILURES {
+            record_login_failure(&keys);
+        }
+        clear_login_failures(&keys);
+        assert_eq!(login_retry_after(&keys[..1]), None);
+        assert!(login_retry_after(&keys[1..]).is_some());
+    }
+}
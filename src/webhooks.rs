@@ -0,0 +1,323 @@
+use crate::http_client::agent;
+use crate::notifications::{add_notification_listener, Notification, NotificationEvent, NotificationSeverity};
+use crate::server::Server;
+use crate::server_database::ServerDatabase;
+use crate::users::require_admin;
+use log::{error, info, warn};
+use obsidian_sqlite::{create_appdb_connection, last_inserted_id};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlite::State;
+use std::error::Error;
+use std::sync::mpsc;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The variables a message template can use.
+const TEMPLATE_VARIABLES: &[&str] = &["event", "severity", "server_id", "server_name", "title", "message"];
+
+/// How often a delivery is attempted before the notification is dropped for the target.
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// How long to wait before the first retry, doubled for every further one.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+static DELIVERY: Once = Once::new();
+
+/// The service a webhook posts to, which decides the shape of the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookKind {
+    /// A Discord channel webhook, `https://discord.com/api/webhooks/...`. Posts an embed colored by
+    /// severity, or the rendered template as plain content.
+    Discord,
+    /// A Slack incoming webhook, `https://hooks.slack.com/services/...`.
+    Slack,
+    /// Any endpoint taking the notification as a JSON object.
+    Json,
+}
+
+/// A URL the notifications are posted to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    /// Identifies the target, assigned when it is added.
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    pub kind: WebhookKind,
+    pub url: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// The events posted to the target, every event if empty.
+    #[serde(default)]
+    pub events: Vec<NotificationEvent>,
+    /// The servers whose notifications are posted, every server if empty. Notifications about the
+    /// manager itself are posted either way.
+    #[serde(default)]
+    pub servers: Vec<u64>,
+    /// Notifications below this severity are not posted.
+    #[serde(default = "default_min_severity")]
+    pub min_severity: NotificationSeverity,
+    /// The text posted instead of the default message, with variables in braces: `{event}`,
+    /// `{severity}`, `{server_id}`, `{server_name}`, `{title}` and `{message}`. Sent as the
+    /// `content` of a Discord message, the `text` of a Slack one and the `text` field of a JSON one.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Headers sent with every request, such as the token of a JSON endpoint.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_min_severity() -> NotificationSeverity {
+    NotificationSeverity::Info
+}
+
+impl WebhookTarget {
+    /// Whether the target takes a notification.
+    fn accepts(&self, notification: &Notification) -> bool {
+        self.enabled && notification.matches(&self.events, &self.servers, self.min_severity)
+    }
+}
+
+fn validate_target(target: &WebhookTarget) -> Result<(), Box<dyn Error>> {
+    if !target.url.starts_with("https://") && !target.url.starts_with("http://") {
+        return Err(format!("The webhook URL {:?} needs an http:// or https:// scheme", target.url).into());
+    }
+    if let Some(template) = &target.template {
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(length) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + length];
+            if !TEMPLATE_VARIABLES.contains(&name) {
+                return Err(format!("Unknown variable {{{}}} in the message template", name).into());
+            }
+            rest = &rest[start + length + 1..];
+        }
+    }
+    Ok(())
+}
+
+/// Creates the table holding the webhook targets.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_webhook_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `notification_webhooks` (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,                       -- Identifies the webhook
+            settings TEXT NOT NULL                                      -- The URL, filters and template, as JSON
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+/// Lists the webhook targets.
+pub fn list_webhooks() -> Result<Vec<WebhookTarget>, Box<dyn Error>> {
+    require_admin()?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM notification_webhooks ORDER BY id"#)?;
+    let mut targets = Vec::new();
+    while let State::Row = statement.next()? {
+        targets.push(read_target(&mut statement)?);
+    }
+    Ok(targets)
+}
+
+/// Reads a webhook target.
+pub fn get_webhook(webhook_id: u64) -> Result<WebhookTarget, Box<dyn Error>> {
+    require_admin()?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"SELECT * FROM notification_webhooks WHERE id = ?"#)?;
+    statement.bind((1, webhook_id as i64))?;
+    if let State::Row = statement.next()? {
+        return read_target(&mut statement);
+    }
+    Err(format!("There is no webhook {}", webhook_id).into())
+}
+
+fn read_target(statement: &mut sqlite::Statement) -> Result<WebhookTarget, Box<dyn Error>> {
+    let mut target: WebhookTarget = serde_json::from_str(&statement.read::<String, _>("settings")?)?;
+    target.id = statement.read::<i64, _>("id")? as u64;
+    Ok(target)
+}
+
+/// Adds a webhook target.
+///
+/// # Returns
+/// The identifier of the new target.
+///
+/// # Errors
+/// Returns an error if the URL or template is invalid or the target cannot be stored.
+pub fn add_webhook(target: &WebhookTarget) -> Result<u64, Box<dyn Error>> {
+    require_admin()?;
+    validate_target(target)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"INSERT INTO notification_webhooks (settings) VALUES (?)"#)?;
+    statement.bind((1, serde_json::to_string(target)?.as_str()))?;
+    statement.next()?;
+    let id = last_inserted_id("notification_webhooks")?;
+    info!("Added the {:?} webhook {:?}", target.kind, target.name);
+    Ok(id)
+}
+
+/// Replaces the settings of the webhook target with the identifier of `target`.
+pub fn update_webhook(target: &WebhookTarget) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    validate_target(target)?;
+    get_webhook(target.id)?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"UPDATE notification_webhooks SET settings = ? WHERE id = ?"#)?;
+    statement.bind((1, serde_json::to_string(target)?.as_str()))?;
+    statement.bind((2, target.id as i64))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Removes a webhook target.
+pub fn remove_webhook(webhook_id: u64) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(r#"DELETE FROM notification_webhooks WHERE id = ?"#)?;
+    statement.bind((1, webhook_id as i64))?;
+    statement.next()?;
+    info!("Removed the webhook {}", webhook_id);
+    Ok(())
+}
+
+/// Starts posting the notifications to the webhook targets. Calling it again has no effect.
+pub fn start_webhook_delivery() {
+    DELIVERY.call_once(|| {
+        // The notifications are posted by a thread of their own, so a slow target does not hold up
+        // the thread that noticed the event
+        let (sender, receiver) = mpsc::channel::<Notification>();
+        let sender = Mutex::new(sender);
+        add_notification_listener(move |notification| {
+            if let Ok(sender) = sender.lock() {
+                let _ = sender.send(notification.clone());
+            }
+        });
+        thread::spawn(move || {
+            for notification in receiver {
+                let targets = match list_webhooks() {
+                    Ok(targets) => targets,
+                    Err(e) => {
+                        error!("Failed to read the webhook targets: {}", e);
+                        continue;
+                    }
+                };
+                for target in targets.iter().filter(|target| target.accepts(&notification)) {
+                    if let Err(e) = deliver(target, &notification) {
+                        warn!("Failed to post a notification to the webhook {:?}: {}", target.name, e);
+                    }
+                }
+            }
+        });
+        info!("Started posting notifications to webhooks");
+    });
+}
+
+/// Posts a test notification to a webhook target, whatever its filters are.
+pub fn test_webhook(webhook_id: u64) -> Result<(), Box<dyn Error>> {
+    require_admin()?;
+    let target = get_webhook(webhook_id)?;
+    let notification = Notification {
+        event: NotificationEvent::ServerStarted,
+        server_id: None,
+        severity: NotificationSeverity::Info,
+        title: "Test notification".to_string(),
+        message: format!("The webhook {} is set up.", target.name),
+        at: SystemTime::now(),
+    };
+    post(&target, &payload(&target, &notification))
+}
+
+/// Posts a notification to a target, retrying with a doubling delay.
+fn deliver(target: &WebhookTarget, notification: &Notification) -> Result<(), Box<dyn Error>> {
+    let body = payload(target, notification);
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match post(target, &body) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                warn!("Posting to the webhook {:?} failed, retrying in {:?}: {}", target.name, delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn post(target: &WebhookTarget, body: &Value) -> Result<(), Box<dyn Error>> {
+    let request = target
+        .headers
+        .iter()
+        .fold(agent().post(&target.url), |request, (name, value)| request.set(name, value))
+        .set("Content-Type", "application/json");
+    request.send_string(&body.to_string())?;
+    Ok(())
+}
+
+/// Expands a message template for a notification.
+pub fn render_message(template: &str, notification: &Notification) -> String {
+    let server_name = server_name(notification).unwrap_or_default();
+    let event = serde_json::to_value(notification.event).unwrap_or_default();
+    let severity = serde_json::to_value(notification.severity).unwrap_or_default();
+    template
+        .replace("{event}", event.as_str().unwrap_or_default())
+        .replace("{severity}", severity.as_str().unwrap_or_default())
+        .replace("{server_id}", &notification.server_id.map(|id| id.to_string()).unwrap_or_default())
+        .replace("{server_name}", &server_name)
+        .replace("{title}", &notification.title)
+        .replace("{message}", &notification.message)
+}
+
+fn server_name(notification: &Notification) -> Option<String> {
+    let server_id = notification.server_id?;
+    Server::<u64>::get_server(server_id).ok().map(|server| server.name)
+}
+
+/// The body posted to a target for a notification.
+fn payload(target: &WebhookTarget, notification: &Notification) -> Value {
+    let text = target.template.as_ref().map(|template| render_message(template, notification));
+    match target.kind {
+        WebhookKind::Discord => match text {
+            Some(content) => json!({ "content": content }),
+            None => json!({
+                "embeds": [{
+                    "title": notification.title,
+                    "description": notification.message,
+                    "color": match notification.severity {
+                        NotificationSeverity::Info => 0x3498db,
+                        NotificationSeverity::Warning => 0xf1c40f,
+                        NotificationSeverity::Critical => 0xe74c3c,
+                    },
+                }]
+            }),
+        },
+        WebhookKind::Slack => json!({
+            "text": text.unwrap_or_else(|| format!("*{}*\n{}", notification.title, notification.message)),
+        }),
+        WebhookKind::Json => json!({
+            "event": notification.event,
+            "severity": notification.severity,
+            "server_id": notification.server_id,
+            "server_name": server_name(notification),
+            "title": notification.title,
+            "message": notification.message,
+            "text": text,
+            "timestamp": notification.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }),
+    }
+}
@@ -0,0 +1,350 @@
+use crate::datapacks::level_name;
+use crate::nbt::{read_nbt_file, write_nbt_file, NbtCompound, NbtTag};
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_filesystem::ServerFilesystem;
+use crate::server_process::{send_server_command, ServerProcess};
+use crate::server_properties_file::Difficulty;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+/// The settings of a world that `level.dat` stores and the game can change while it runs.
+///
+/// When applying, `None` and game rules missing from the map leave the setting unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldSettings {
+    pub difficulty: Option<Difficulty>,
+    pub spawn: Option<SpawnPosition>,
+    /// The time of day in ticks, `0` is sunrise and a day lasts `24000` ticks.
+    pub day_time: Option<i64>,
+    pub weather: Option<Weather>,
+    pub world_border: Option<WorldBorder>,
+    /// The game rules by name, like `keepInventory`.
+    pub game_rules: BTreeMap<String, GameRuleValue>,
+}
+
+/// Where players without a bed or respawn anchor spawn.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SpawnPosition {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    /// The direction players face when they spawn, in degrees.
+    #[serde(default)]
+    pub angle: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weather {
+    Clear,
+    Rain,
+    Thunder,
+}
+
+/// The border of the world, a square around its center.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WorldBorder {
+    pub center_x: f64,
+    pub center_z: f64,
+    /// The length of a side of the square, in blocks.
+    pub size: f64,
+    /// How far outside the border players can go before they take damage, in blocks.
+    pub safe_zone: f64,
+    /// The damage per second for every block a player is beyond the safe zone.
+    pub damage_per_block: f64,
+    /// How close to the border the screen of a player turns red, in blocks.
+    pub warning_blocks: i32,
+    /// How many seconds ahead of a shrinking border reaching them players are warned.
+    pub warning_time: i32,
+}
+
+/// The value of a game rule, which is either a switch or a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GameRuleValue {
+    Bool(bool),
+    Int(i64),
+}
+
+impl GameRuleValue {
+    fn from_tag(tag: &NbtTag) -> Option<Self> {
+        match tag {
+            NbtTag::String(value) => match value.as_str() {
+                "true" => Some(GameRuleValue::Bool(true)),
+                "false" => Some(GameRuleValue::Bool(false)),
+                number => number.parse().ok().map(GameRuleValue::Int),
+            },
+            // Newer versions store typed values, with switches as bytes
+            NbtTag::Byte(value) => Some(GameRuleValue::Bool(*value != 0)),
+            tag => tag.as_i64().map(GameRuleValue::Int),
+        }
+    }
+
+    /// The tag of the value, of the same type as the one it replaces.
+    fn to_tag(self, existing: Option<&NbtTag>) -> NbtTag {
+        match (self, existing) {
+            (GameRuleValue::Bool(value), Some(NbtTag::Byte(_))) => NbtTag::Byte(i8::from(value)),
+            (GameRuleValue::Int(value), Some(NbtTag::Int(_))) => NbtTag::Int(value as i32),
+            _ => NbtTag::String(self.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for GameRuleValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameRuleValue::Bool(value) => write!(f, "{}", value),
+            GameRuleValue::Int(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+fn difficulty_from_id(id: i64) -> Option<Difficulty> {
+    match id {
+        0 => Some(Difficulty::Peaceful),
+        1 => Some(Difficulty::Easy),
+        2 => Some(Difficulty::Normal),
+        3 => Some(Difficulty::Hard),
+        _ => None,
+    }
+}
+
+fn difficulty_id(difficulty: Difficulty) -> i8 {
+    match difficulty {
+        Difficulty::Peaceful => 0,
+        Difficulty::Easy => 1,
+        Difficulty::Normal => 2,
+        Difficulty::Hard => 3,
+    }
+}
+
+/// Reads the settings from the `Data` compound of a `level.dat`.
+fn read_settings(data: &NbtCompound) -> WorldSettings {
+    let flag = |name: &str| data.get_i64(name).is_some_and(|value| value != 0);
+    let weather = if flag("thundering") {
+        Weather::Thunder
+    } else if flag("raining") {
+        Weather::Rain
+    } else {
+        Weather::Clear
+    };
+    let border = data.get_f64("BorderSize").map(|size| WorldBorder {
+        center_x: data.get_f64("BorderCenterX").unwrap_or(0.0),
+        center_z: data.get_f64("BorderCenterZ").unwrap_or(0.0),
+        size,
+        safe_zone: data.get_f64("BorderSafeZone").unwrap_or(5.0),
+        damage_per_block: data.get_f64("BorderDamagePerBlock").unwrap_or(0.2),
+        warning_blocks: data.get_f64("BorderWarningBlocks").unwrap_or(5.0) as i32,
+        warning_time: data.get_f64("BorderWarningTime").unwrap_or(15.0) as i32,
+    });
+    let game_rules = data
+        .get_compound("GameRules")
+        .map(|rules| {
+            rules
+                .iter()
+                .filter_map(|(name, tag)| GameRuleValue::from_tag(tag).map(|value| (name.to_string(), value)))
+                .collect()
+        })
+        .unwrap_or_default();
+    WorldSettings {
+        difficulty: data.get_i64("Difficulty").and_then(difficulty_from_id),
+        spawn: read_spawn(data),
+        day_time: data.get_i64("DayTime"),
+        weather: Some(weather),
+        world_border: border,
+        game_rules,
+    }
+}
+
+/// Reads the spawn from `SpawnX`, `SpawnY` and `SpawnZ`, or from the `spawn` compound 1.21.9 moved it to.
+fn read_spawn(data: &NbtCompound) -> Option<SpawnPosition> {
+    if let Some(spawn) = data.get_compound("spawn") {
+        let position = spawn.get("pos").and_then(NbtTag::as_int_array)?;
+        let [x, y, z] = position.as_slice() else {
+            return None;
+        };
+        return Some(SpawnPosition {
+            x: *x,
+            y: *y,
+            z: *z,
+            angle: spawn.get_f64("yaw").unwrap_or(0.0) as f32,
+        });
+    }
+    Some(SpawnPosition {
+        x: data.get_i64("SpawnX")? as i32,
+        y: data.get_i64("SpawnY")? as i32,
+        z: data.get_i64("SpawnZ")? as i32,
+        angle: data.get_f64("SpawnAngle").unwrap_or(0.0) as f32,
+    })
+}
+
+/// Writes the given settings into the `Data` compound of a `level.dat`.
+fn write_settings(data: &mut NbtCompound, settings: &WorldSettings) -> Result<(), Box<dyn Error>> {
+    if let Some(difficulty) = settings.difficulty {
+        data.insert("Difficulty", NbtTag::Byte(difficulty_id(difficulty)));
+    }
+    if let Some(spawn) = settings.spawn {
+        if let Some(compound) = data.get_compound_mut("spawn") {
+            compound.insert("pos", NbtTag::IntArray(vec![spawn.x, spawn.y, spawn.z]));
+            compound.insert("yaw", NbtTag::Float(spawn.angle));
+        } else {
+            data.insert("SpawnX", NbtTag::Int(spawn.x));
+            data.insert("SpawnY", NbtTag::Int(spawn.y));
+            data.insert("SpawnZ", NbtTag::Int(spawn.z));
+            data.insert("SpawnAngle", NbtTag::Float(spawn.angle));
+        }
+    }
+    if let Some(day_time) = settings.day_time {
+        data.insert("DayTime", NbtTag::Long(day_time));
+    }
+    if let Some(weather) = settings.weather {
+        data.insert("raining", NbtTag::Byte(i8::from(weather != Weather::Clear)));
+        data.insert("thundering", NbtTag::Byte(i8::from(weather == Weather::Thunder)));
+        // With no time left the game picks how long the new weather lasts, instead of ending it at once
+        for timer in ["rainTime", "thunderTime", "clearWeatherTime"] {
+            data.insert(timer, NbtTag::Int(0));
+        }
+    }
+    if let Some(border) = settings.world_border {
+        data.insert("BorderCenterX", NbtTag::Double(border.center_x));
+        data.insert("BorderCenterZ", NbtTag::Double(border.center_z));
+        data.insert("BorderSize", NbtTag::Double(border.size));
+        data.insert("BorderSizeLerpTarget", NbtTag::Double(border.size));
+        data.insert("BorderSizeLerpTime", NbtTag::Long(0));
+        data.insert("BorderSafeZone", NbtTag::Double(border.safe_zone));
+        data.insert("BorderDamagePerBlock", NbtTag::Double(border.damage_per_block));
+        data.insert("BorderWarningBlocks", NbtTag::Double(border.warning_blocks as f64));
+        data.insert("BorderWarningTime", NbtTag::Double(border.warning_time as f64));
+    }
+    if !settings.game_rules.is_empty() {
+        if data.get_compound("GameRules").is_none() {
+            data.insert("GameRules", NbtTag::Compound(NbtCompound::new()));
+        }
+        let rules = data.get_compound_mut("GameRules").ok_or("The level.dat has no game rules")?;
+        for (name, value) in &settings.game_rules {
+            let current = rules.get(name).and_then(GameRuleValue::from_tag);
+            if let Some(current) = current.filter(|current| !same_type(*current, *value)) {
+                let message = format!("The game rule {} is set to {}, it cannot be set to {}", name, current, value);
+                return Err(message.into());
+            }
+            let tag = value.to_tag(rules.get(name));
+            rules.insert(name.clone(), tag);
+        }
+    }
+    Ok(())
+}
+
+fn same_type(a: GameRuleValue, b: GameRuleValue) -> bool {
+    std::mem::discriminant(&a) == std::mem::discriminant(&b)
+}
+
+/// The console commands that apply the given settings to a running server.
+fn setting_commands(settings: &WorldSettings) -> Vec<String> {
+    let mut commands = Vec::new();
+    if let Some(difficulty) = settings.difficulty {
+        commands.push(format!("difficulty {}", difficulty.as_str()));
+    }
+    if let Some(spawn) = settings.spawn {
+        commands.push(format!("setworldspawn {} {} {} {}", spawn.x, spawn.y, spawn.z, spawn.angle));
+    }
+    if let Some(day_time) = settings.day_time {
+        commands.push(format!("time set {}", day_time.rem_euclid(24000)));
+    }
+    if let Some(weather) = settings.weather {
+        let weather = match weather {
+            Weather::Clear => "clear",
+            Weather::Rain => "rain",
+            Weather::Thunder => "thunder",
+        };
+        commands.push(format!("weather {}", weather));
+    }
+    if let Some(border) = settings.world_border {
+        commands.push(format!("worldborder center {} {}", border.center_x, border.center_z));
+        commands.push(format!("worldborder set {}", border.size));
+        commands.push(format!("worldborder damage buffer {}", border.safe_zone));
+        commands.push(format!("worldborder damage amount {}", border.damage_per_block));
+        commands.push(format!("worldborder warning distance {}", border.warning_blocks));
+        commands.push(format!("worldborder warning time {}", border.warning_time));
+    }
+    for (name, value) in &settings.game_rules {
+        commands.push(format!("gamerule {} {}", name, value));
+    }
+    commands
+}
+
+/// Game rule names end up in console commands, so only the characters of the game's own are allowed.
+fn validate_game_rules(settings: &WorldSettings) -> Result<(), Box<dyn Error>> {
+    for name in settings.game_rules.keys() {
+        let valid = !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ':' | '.' | '-'));
+        if !valid {
+            return Err(format!("{:?} is not a valid game rule name", name).into());
+        }
+    }
+    Ok(())
+}
+
+pub trait ServerWorldSettings {
+    /// Reads the settings of the world the server loads from its `level.dat`.
+    ///
+    /// A running server writes `level.dat` when it saves, so the settings can be a few minutes old.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not view the console, the world was not generated
+    /// yet or its `level.dat` cannot be read.
+    fn get_world_settings(&self) -> Result<WorldSettings, Box<dyn Error>>;
+
+    /// Changes the settings of the world the server loads.
+    ///
+    /// A stopped server has them written into `level.dat`. A running server would overwrite that
+    /// file the next time it saves, so the changes are sent as console commands instead, and the
+    /// server saves them itself.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not send commands, a game rule name is invalid or a
+    /// value has the wrong type, or if the `level.dat` cannot be written or a command cannot be sent.
+    fn apply_world_settings(&self, settings: &WorldSettings) -> Result<(), Box<dyn Error>>;
+}
+
+impl ServerWorldSettings for Server<u64> {
+    fn get_world_settings(&self) -> Result<WorldSettings, Box<dyn Error>> {
+        authorize(self, Capability::ViewConsole)?;
+        let level = read_nbt_file(&level_file(self)?)?;
+        let data = level.root.get_compound("Data").ok_or("The level.dat has no world data")?;
+        Ok(read_settings(data))
+    }
+
+    fn apply_world_settings(&self, settings: &WorldSettings) -> Result<(), Box<dyn Error>> {
+        authorize(self, Capability::SendCommands)?;
+        validate_game_rules(settings)?;
+        if self.is_server_running() {
+            for command in setting_commands(settings) {
+                send_server_command(self, command)?;
+            }
+            info!("Sent the changed world settings to server {:?}", self.name);
+            return Ok(());
+        }
+
+        let path = level_file(self)?;
+        let mut level = read_nbt_file(&path)?;
+        let data = level.root.get_compound_mut("Data").ok_or("The level.dat has no world data")?;
+        write_settings(data, settings)?;
+        write_nbt_file(&path, &level)?;
+        info!("Updated the world settings in {:?} of server {:?}", path, self.name);
+        Ok(())
+    }
+}
+
+fn level_file(server: &Server<u64>) -> Result<PathBuf, Box<dyn Error>> {
+    let world = level_name(server);
+    let path = server.sandbox(&world)?.path().join("level.dat");
+    if !path.is_file() {
+        return Err(format!("The world {:?} was not generated yet, start the server once first", world).into());
+    }
+    Ok(path)
+}
@@ -0,0 +1,305 @@
+use crate::file_system_entry::{invalidate_directory_size_cache, is_managed_path};
+use crate::jobs::check_cancelled;
+use crate::region_file::{parse_region_coordinates, ChunkInfo, ChunkProblem, RegionFile, SECTOR_SIZE};
+use crate::sandboxed_path::SandboxedPath;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// The directories next to `region/` holding data of the same chunks, trimmed along with it.
+const CHUNK_DATA_DIRECTORIES: &[&str] = &["entities", "poi"];
+
+/// How the distance from the center is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrimShape {
+    /// Keeps a square around the center, like the world border.
+    Square,
+    Circle,
+}
+
+/// Selects the chunks removed by a world trim.
+///
+/// A chunk is trimmed when it matches every criterion that is set, so combining a radius and a
+/// date only trims old chunks far from the center.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldTrimOptions {
+    /// The x block coordinate the radius is measured from, usually the world spawn.
+    pub center_x: i32,
+    /// The z block coordinate the radius is measured from.
+    pub center_z: i32,
+    /// Trims chunks lying completely farther than this many blocks from the center.
+    pub radius: Option<u32>,
+    pub shape: TrimShape,
+    /// Trims chunks the game has not saved since this time.
+    pub not_modified_since: Option<SystemTime>,
+    /// Only estimates the savings, without changing any file.
+    pub dry_run: bool,
+}
+
+impl Default for WorldTrimOptions {
+    fn default() -> Self {
+        Self {
+            center_x: 0,
+            center_z: 0,
+            radius: None,
+            shape: TrimShape::Square,
+            not_modified_since: None,
+            dry_run: true,
+        }
+    }
+}
+
+/// The effect of a trim on a single region file.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegionTrim {
+    /// The region file, relative to the world directory.
+    pub path: PathBuf,
+    pub chunks: usize,
+    pub trimmed_chunks: usize,
+    /// The size of the region file and the `.mcc` files of its chunks.
+    pub size_before: u64,
+    pub size_after: u64,
+    /// Whether the region file is removed because none of its chunks are kept.
+    pub removed: bool,
+}
+
+/// The result of trimming a world, or the estimate of a dry run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WorldTrimReport {
+    pub dry_run: bool,
+    pub regions_scanned: usize,
+    pub chunks_scanned: usize,
+    pub chunks_trimmed: usize,
+    pub regions_removed: usize,
+    pub bytes_freed: u64,
+    /// The region files losing at least one chunk, including entity and POI files.
+    pub regions: Vec<RegionTrim>,
+}
+
+/// Removes chunks from a world by their distance from a center or the time they were last saved.
+///
+/// Trimmed chunks are generated again when a player comes near them. Region files are rewritten
+/// without the trimmed chunks, which is what actually frees the space, and region files without any
+/// kept chunk are removed. The last save time is read from the timestamp table of the terrain
+/// region files, and entity and POI data of trimmed chunks is removed as well.
+/// The server has to be stopped for anything but a dry run.
+///
+/// # Arguments
+/// * `world` - The world directory, the one containing `level.dat`.
+/// * `options` - Which chunks to trim, and whether to only estimate the savings.
+///
+/// # Errors
+/// Returns an error if the world is not a directory, no criterion is set, or a region file cannot
+/// be rewritten. Region files that cannot be read are skipped.
+pub fn trim_world(world: &SandboxedPath, options: &WorldTrimOptions) -> Result<WorldTrimReport, Box<dyn Error>> {
+    let world_path = world.path();
+    if !world_path.is_dir() {
+        return Err(format!("{} is not a directory", world).into());
+    }
+    if options.radius.is_none() && options.not_modified_since.is_none() {
+        return Err("A radius or a date is required to select the chunks to trim".into());
+    }
+
+    let mut report = WorldTrimReport {
+        dry_run: options.dry_run,
+        ..Default::default()
+    };
+    for region_directory in find_region_directories(world.root_path(), &world_path) {
+        let Some(dimension) = region_directory.parent() else {
+            continue;
+        };
+        for path in region_files(&region_directory) {
+            // Every region file is rewritten as a whole, stopping between them leaves the world intact
+            check_cancelled()?;
+            let Some(mut region) = open_region(&path) else {
+                continue;
+            };
+            let chunks = region.inspect(false).chunks;
+            let trimmed: HashSet<(i32, i32)> = chunks
+                .iter()
+                .filter(|chunk| is_trimmed(chunk, options))
+                .map(|chunk| (chunk.x, chunk.z))
+                .collect();
+            report.regions_scanned += 1;
+            report.chunks_scanned += chunks.len();
+            report.chunks_trimmed += trimmed.len();
+            if trimmed.is_empty() {
+                continue;
+            }
+            trim_region(&mut region, &path, &chunks, &trimmed, &world_path, options, &mut report)?;
+
+            // Entity and POI files use the same names as the region files of the terrain
+            let Some(file_name) = path.file_name() else {
+                continue;
+            };
+            for directory in CHUNK_DATA_DIRECTORIES {
+                let data_path = dimension.join(directory).join(file_name);
+                if !data_path.is_file() {
+                    continue;
+                }
+                if let Some(mut data_region) = open_region(&data_path) {
+                    let data_chunks = data_region.inspect(false).chunks;
+                    trim_region(
+                        &mut data_region,
+                        &data_path,
+                        &data_chunks,
+                        &trimmed,
+                        &world_path,
+                        options,
+                        &mut report,
+                    )?;
+                }
+            }
+        }
+    }
+
+    if !options.dry_run && report.chunks_trimmed > 0 {
+        invalidate_directory_size_cache(&world_path);
+    }
+    info!(
+        "{} {} of {} chunks in {}, freeing {} bytes",
+        if options.dry_run { "Would trim" } else { "Trimmed" },
+        report.chunks_trimmed,
+        report.chunks_scanned,
+        world,
+        report.bytes_freed
+    );
+    Ok(report)
+}
+
+/// Estimates and, unless it is a dry run, applies the trim of a single region file.
+fn trim_region(
+    region: &mut RegionFile,
+    path: &Path,
+    chunks: &[ChunkInfo],
+    trimmed: &HashSet<(i32, i32)>,
+    world: &Path,
+    options: &WorldTrimOptions,
+    report: &mut WorldTrimReport,
+) -> Result<(), Box<dyn Error>> {
+    let keep = |chunk: &ChunkInfo| !trimmed.contains(&(chunk.x, chunk.z)) && has_readable_sectors(chunk);
+    let trimmed_chunks = chunks.iter().filter(|chunk| !keep(chunk)).count();
+    if trimmed_chunks == 0 {
+        return Ok(());
+    }
+
+    let file_size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+    let external_size = |chunk: &ChunkInfo| if chunk.external { chunk.size } else { 0 };
+    let size_before = file_size + chunks.iter().map(external_size).sum::<u64>();
+    let removed = !chunks.iter().any(keep);
+    let mut size_after = if removed {
+        0
+    } else {
+        // The kept chunks are packed right behind the two header sectors
+        let sectors: u64 = chunks
+            .iter()
+            .filter(|chunk| keep(chunk))
+            .map(|chunk| chunk.sector_count as u64)
+            .sum();
+        (2 + sectors) * SECTOR_SIZE
+            + chunks
+                .iter()
+                .filter(|chunk| keep(chunk))
+                .map(external_size)
+                .sum::<u64>()
+    };
+
+    if !options.dry_run {
+        let kept_external: u64 = chunks.iter().filter(|chunk| keep(chunk)).map(external_size).sum();
+        size_after = region.retain_chunks(|x, z| !trimmed.contains(&(x, z)))? + kept_external;
+    }
+
+    report.bytes_freed += size_before.saturating_sub(size_after);
+    if removed {
+        report.regions_removed += 1;
+    }
+    report.regions.push(RegionTrim {
+        path: path.strip_prefix(world).unwrap_or(path).to_path_buf(),
+        chunks: chunks.len(),
+        trimmed_chunks,
+        size_before,
+        size_after,
+        removed,
+    });
+    Ok(())
+}
+
+/// Whether a chunk matches every criterion of the trim.
+fn is_trimmed(chunk: &ChunkInfo, options: &WorldTrimOptions) -> bool {
+    let outside = options
+        .radius
+        .map_or(true, |radius| distance_from_center(chunk, options) > radius as f64);
+    let old = options
+        .not_modified_since
+        .map_or(true, |since| chunk.last_modified < since);
+    outside && old
+}
+
+/// The distance in blocks from the center to the closest block of a chunk.
+fn distance_from_center(chunk: &ChunkInfo, options: &WorldTrimOptions) -> f64 {
+    let axis_distance = |chunk: i32, center: i32| {
+        let (first, last) = (chunk as i64 * 16, chunk as i64 * 16 + 15);
+        (first - center as i64).max(center as i64 - last).max(0)
+    };
+    let dx = axis_distance(chunk.x, options.center_x);
+    let dz = axis_distance(chunk.z, options.center_z);
+    match options.shape {
+        TrimShape::Square => dx.max(dz) as f64,
+        TrimShape::Circle => ((dx * dx + dz * dz) as f64).sqrt(),
+    }
+}
+
+/// Whether the sectors of a chunk lie inside the file, which is needed to copy it.
+fn has_readable_sectors(chunk: &ChunkInfo) -> bool {
+    !chunk
+        .problems
+        .iter()
+        .any(|problem| matches!(problem, ChunkProblem::OverlapsHeader | ChunkProblem::PastEndOfFile))
+}
+
+/// Finds the `region` directories of every dimension, skipping the manager's own directories.
+fn find_region_directories(root: &Path, world: &Path) -> Vec<PathBuf> {
+    let mut directories: Vec<PathBuf> = WalkDir::new(world)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| !is_managed_path(root, entry.path()))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_dir() && entry.file_name() == "region")
+        .map(|entry| entry.into_path())
+        .collect();
+    directories.sort();
+    directories
+}
+
+fn region_files(directory: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && parse_region_coordinates(path).is_some())
+            .collect(),
+        Err(err) => {
+            warn!("Failed to read region directory {:?}: {}", directory, err);
+            Vec::new()
+        }
+    };
+    files.sort();
+    files
+}
+
+fn open_region(path: &Path) -> Option<RegionFile> {
+    match RegionFile::open_path(path) {
+        Ok(region) => Some(region),
+        Err(err) => {
+            warn!("Skipping region file {:?}: {}", path, err);
+            None
+        }
+    }
+}
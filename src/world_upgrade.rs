@@ -0,0 +1,262 @@
+use crate::permissions::{authorize, Capability};
+use crate::server::Server;
+use crate::server_process::ServerProcess;
+use crate::start_executable_type::{StartExecutableType, StartExecutableTypeExt};
+use crate::worlds::WorldManager;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many of the last console lines a failed upgrade reports.
+const TAIL_LINES: usize = 20;
+
+/// How long the server gets to shut down after the upgrade, before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+type WorldUpgradeListener = Box<dyn Fn(&WorldUpgradeEvent) + Send>;
+
+lazy_static! {
+    static ref WORLD_UPGRADE_LISTENERS: Mutex<Vec<WorldUpgradeListener>> = Mutex::new(Vec::new());
+}
+
+/// What is upgraded, and with which server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldUpgradeOptions {
+    /// The world to upgrade, the active one if `None`.
+    pub world: Option<String>,
+    /// The name of the upgraded copy, `<world>-upgraded` if `None`.
+    pub copy_name: Option<String>,
+    /// The server jar of the version the world is upgraded to, relative to the server directory.
+    /// The jar the server starts with if `None`, which is right once the server was switched to the
+    /// new version.
+    pub server_jar: Option<PathBuf>,
+    /// Also drops the cached lighting and heightmaps, which the game rebuilds.
+    pub erase_cache: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorldUpgradeState {
+    Copying,
+    Upgrading,
+    Completed,
+    Failed,
+}
+
+/// The progress of a world upgrade, reported to the world upgrade listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorldUpgradeEvent {
+    pub server_id: u64,
+    /// The copy that is upgraded.
+    pub world: String,
+    pub state: WorldUpgradeState,
+    /// The percentage of the dimension that is upgraded, dimensions are upgraded one after another.
+    pub percentage: u32,
+    pub chunks_done: u64,
+    pub chunks_total: u64,
+    /// The error of a failed upgrade, with the last lines of the console.
+    pub message: Option<String>,
+}
+
+/// The outcome of a finished upgrade.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorldUpgradeReport {
+    /// The world that was upgraded, which is left as it was.
+    pub source: String,
+    /// The upgraded copy, which the server can be switched to.
+    pub world: String,
+    pub duration_ms: u64,
+}
+
+/// Registers a listener that is invoked when a world upgrade starts, progresses, completes or fails.
+pub fn add_world_upgrade_listener(listener: impl Fn(&WorldUpgradeEvent) + Send + 'static) {
+    if let Ok(mut listeners) = WORLD_UPGRADE_LISTENERS.lock() {
+        listeners.push(Box::new(listener));
+    }
+}
+
+fn emit(event: WorldUpgradeEvent) {
+    match WORLD_UPGRADE_LISTENERS.lock() {
+        Ok(listeners) => listeners.iter().for_each(|listener| listener(&event)),
+        Err(err) => error!("Failed to notify world upgrade listeners: {}", err),
+    }
+}
+
+/// Reads the chunks from a progress line of the game, like `42% completed (1234 / 2938 chunks)...`.
+fn parse_progress(line: &str) -> Option<(u32, u64, u64)> {
+    let (before, after) = line.split_once("% completed (")?;
+    let percentage = before.rsplit(|c: char| !c.is_ascii_digit()).next()?.parse().ok()?;
+    let (counts, _) = after.split_once(" chunks")?;
+    let (done, total) = counts.split_once(" / ")?;
+    Some((percentage, done.trim().parse().ok()?, total.trim().parse().ok()?))
+}
+
+pub trait ServerWorldUpgrade {
+    /// Upgrades a copy of a world to a newer version of the game, blocking until it is done.
+    ///
+    /// The world is copied first and left untouched, so a failed upgrade or one the new version
+    /// turns out to break loses nothing. The copy is upgraded by starting a server jar with
+    /// `--forceUpgrade`, which converts every chunk up front instead of when players load them. The
+    /// jar is stopped again as soon as the upgrade is done. Progress is reported to the world
+    /// upgrade listeners.
+    ///
+    /// # Errors
+    /// Returns an error if the server is running, the world does not exist, the copy cannot be
+    /// made, the server does not start from a jar, or the upgrade fails. A failed upgrade removes
+    /// nothing, the partially upgraded copy is left for inspection.
+    fn upgrade_world(&self, options: &WorldUpgradeOptions) -> Result<WorldUpgradeReport, Box<dyn Error>>;
+}
+
+impl ServerWorldUpgrade for Server<u64> {
+    fn upgrade_world(&self, options: &WorldUpgradeOptions) -> Result<WorldUpgradeReport, Box<dyn Error>> {
+        authorize(self, Capability::EditFiles)?;
+        if self.is_server_running() {
+            return Err("The server has to be stopped before a world is upgraded".into());
+        }
+        let worlds = WorldManager::new(self)?;
+        let source = options.world.clone().unwrap_or_else(|| worlds.active_world());
+        let copy = options.copy_name.clone().unwrap_or_else(|| format!("{}-upgraded", source));
+        let jar = options
+            .server_jar
+            .clone()
+            .or_else(|| self.start_script.clone())
+            .ok_or("The server has no jar to upgrade the world with")?;
+        let executable = StartExecutableType::from_path(&jar)?;
+        if !matches!(executable, StartExecutableType::Jar | StartExecutableType::ArgumentFile) {
+            return Err(format!("{:?} is not a server jar, worlds are upgraded with the jar of the game", jar).into());
+        }
+
+        let event = |state, message: Option<String>| WorldUpgradeEvent {
+            server_id: self.id,
+            world: copy.clone(),
+            state,
+            percentage: 0,
+            chunks_done: 0,
+            chunks_total: 0,
+            message,
+        };
+        emit(event(WorldUpgradeState::Copying, None));
+        if let Err(e) = worlds.duplicate_world(&source, &copy) {
+            emit(event(WorldUpgradeState::Failed, Some(e.to_string())));
+            return Err(e);
+        }
+
+        let started = Instant::now();
+        info!("Upgrading the world {:?} of server {:?} as {:?} with {:?}", source, self.name, copy, jar);
+        if let Err(e) = run_upgrade(self, &jar, executable, &copy, options.erase_cache) {
+            warn!("Upgrading the world {:?} of server {:?} failed: {}", copy, self.name, e);
+            emit(event(WorldUpgradeState::Failed, Some(e.to_string())));
+            return Err(e);
+        }
+        emit(WorldUpgradeEvent {
+            percentage: 100,
+            ..event(WorldUpgradeState::Completed, None)
+        });
+        let duration_ms = started.elapsed().as_millis() as u64;
+        info!("Upgraded the world {:?} of server {:?} in {} ms", copy, self.name, duration_ms);
+        Ok(WorldUpgradeReport {
+            source,
+            world: copy,
+            duration_ms,
+        })
+    }
+}
+
+/// Starts the jar with `--forceUpgrade` against the copy and follows its console until the upgrade
+/// finished and the jar stopped.
+fn run_upgrade(
+    server: &Server<u64>,
+    jar: &Path,
+    executable: StartExecutableType,
+    world: &str,
+    erase_cache: bool,
+) -> Result<(), Box<dyn Error>> {
+    let java = server.java_runtime.clone().unwrap_or_else(|| PathBuf::from("java"));
+    let mut command = Command::new(java);
+    command.current_dir(&server.directory);
+    if let Some(arguments) = &server.java_arguments {
+        command.args(shell_words::split(arguments).map_err(|_| "Invalid Java arguments")?);
+    }
+    command.arg(format!("-Xms{}G", server.min_ram));
+    command.arg(format!("-Xmx{}G", server.max_ram));
+    if executable == StartExecutableType::ArgumentFile {
+        command.arg(format!("@{}", jar.display()));
+    } else {
+        command.arg("-jar").arg(jar);
+    }
+    // `--world` only overrides `level-name` for this run, server.properties is left alone
+    command.args(["--nogui", "--forceUpgrade", "--world", world]);
+    if erase_cache {
+        command.arg("--eraseCache");
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let stdout = child.stdout.take().ok_or("Failed to read the console of the upgrade")?;
+    let mut stdin = child.stdin.take();
+    let child = Arc::new(Mutex::new(child));
+
+    let mut tail = VecDeque::with_capacity(TAIL_LINES);
+    let mut finished = false;
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line.clone());
+
+        if let Some((percentage, chunks_done, chunks_total)) = parse_progress(&line) {
+            emit(WorldUpgradeEvent {
+                server_id: server.id,
+                world: world.to_string(),
+                state: WorldUpgradeState::Upgrading,
+                percentage,
+                chunks_done,
+                chunks_total,
+                message: None,
+            });
+        }
+        // Vanilla upgrades before it starts and reports the time, Paper upgrades while loading the
+        // worlds, and both go on to start the server
+        let upgraded = line.contains("World optimization finished") || line.contains("Done (");
+        if upgraded && !finished {
+            finished = true;
+            if let Some(mut input) = stdin.take() {
+                let _ = writeln!(input, "stop");
+            }
+            let child = Arc::clone(&child);
+            thread::spawn(move || {
+                thread::sleep(STOP_TIMEOUT);
+                if let Ok(mut child) = child.lock() {
+                    if let Ok(None) = child.try_wait() {
+                        warn!("The server did not stop after upgrading the world, killing it");
+                        let _ = child.kill();
+                    }
+                }
+            });
+        }
+    }
+
+    let status = child.lock().map_err(|_| "Failed to lock the upgrade process")?.wait()?;
+    if !finished {
+        let tail: Vec<String> = tail.into_iter().collect();
+        return Err(format!(
+            "The server exited with {} before the upgrade finished:\n{}",
+            status,
+            tail.join("\n")
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,783 @@
+use crate::archive_builder::{
+    create_archive, estimate_archive_excluding, write_archive, ArchiveOptions, ArchiveProgress, ArchiveSummary,
+};
+use crate::archive_entries::ArchiveFormat;
+use crate::archive_extractor::{extract_archive, ExtractionOptions};
+use crate::backup_restore::{restore_backup, RestoreOptions};
+use crate::backups::{
+    backup_directory, create_backup, delete_backup, list_backups, pause_saving, resume_saving, BackupManifest,
+    BackupOptions, BackupTarget, BackupTrigger, SavePause, EXPORT_DIRECTORY,
+};
+use crate::datapacks::level_name;
+use crate::file_operations::{move_path, FileOperationOptions, FileOps};
+use crate::file_system_entry::calculate_directory_size;
+use crate::nbt::{read_nbt_file, write_nbt_file, NbtTag};
+use crate::permissions::{authorize, Capability};
+use crate::sandboxed_path::SandboxedPath;
+use crate::server::Server;
+use crate::server_filesystem::ServerFilesystem;
+use crate::server_process::ServerProcess;
+use crate::server_properties::ServerProperties;
+use crate::server_properties_file::ServerPropertiesFile;
+use crate::server_trash::TRASH_DIRECTORY;
+use log::{info, warn};
+use obsidian_sqlite::create_appdb_connection;
+use serde_derive::{Deserialize, Serialize};
+use sqlite::State;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The suffixes of the directories Bukkit and its forks split the nether and the end into.
+const DIMENSION_SUFFIXES: &[&str] = &["_nether", "_the_end"];
+
+/// The directories the game keeps the nether and the end in, inside the world or the split off
+/// dimension directory, with the suffix of the directory Bukkit moves them to.
+const DIMENSIONS: &[(&str, &str)] = &[("DIM-1", "_nether"), ("DIM1", "_the_end")];
+
+/// How deep a world may be nested inside an imported archive.
+const MAX_IMPORT_DEPTH: usize = 4;
+
+/// Files that belong to one running copy of a world, and must not be carried over into a duplicate.
+/// Bukkit refuses to load two worlds with the same `uid.dat`.
+const INSTANCE_FILES: &[&str] = &["session.lock", "uid.dat"];
+
+/// Files that mods keep in the world and rebuild when they are missing, such as the level of detail
+/// database of Distant Horizons, matched like [`ArchiveOptions::exclude`].
+const CACHE_PATTERNS: &[&str] = &["*/DistantHorizons.sqlite*", "*/cache"];
+
+/// What an exported world leaves out, and how it is compressed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldExportOptions {
+    /// Leaves out `session.lock`, which belongs to the server holding the world open.
+    pub exclude_session_lock: bool,
+    /// Leaves out the caches mods rebuild on their own, like the database of Distant Horizons.
+    pub exclude_caches: bool,
+    /// Leaves out the `stats` directory with the statistics of every player.
+    pub exclude_player_stats: bool,
+    /// The compression level from `0` (store only) to `9` (smallest), or `None` for the default.
+    pub compression_level: Option<u32>,
+}
+
+impl Default for WorldExportOptions {
+    fn default() -> Self {
+        Self {
+            exclude_session_lock: true,
+            exclude_caches: true,
+            exclude_player_stats: false,
+            compression_level: None,
+        }
+    }
+}
+
+impl WorldExportOptions {
+    fn archive_options(&self) -> ArchiveOptions {
+        let mut exclude = Vec::new();
+        if self.exclude_session_lock {
+            exclude.push("*/session.lock".to_string());
+        }
+        if self.exclude_caches {
+            exclude.extend(CACHE_PATTERNS.iter().map(|pattern| pattern.to_string()));
+        }
+        if self.exclude_player_stats {
+            exclude.push("*/stats".to_string());
+        }
+        ArchiveOptions {
+            format: ArchiveFormat::Zip,
+            compression_level: self.compression_level,
+            exclude,
+        }
+    }
+}
+
+/// How a new world is generated, from `level-seed`, `level-type`, `generator-settings` and
+/// `generate-structures` of `server.properties`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldGeneration {
+    /// The seed, random if it is empty or `None`. Like in the game, text that is not a number is
+    /// hashed into a seed.
+    pub seed: Option<String>,
+    /// The world preset, like `minecraft:flat` or `minecraft:amplified`, or one added by a mod.
+    pub level_type: Option<String>,
+    /// The JSON settings of the presets that take them, like the layers of a flat world.
+    pub generator_settings: Option<String>,
+    pub generate_structures: Option<bool>,
+}
+
+impl WorldGeneration {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(settings) = self.generator_settings.as_deref().filter(|settings| !settings.trim().is_empty()) {
+            serde_json::from_str::<serde_json::Value>(settings)
+                .map_err(|e| format!("The generator settings are not valid JSON: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// The settings with those left out set to what the game uses when `server.properties` lacks them.
+    fn with_defaults(&self) -> Self {
+        Self {
+            seed: self.seed.clone().map(|seed| seed.trim().to_string()).filter(|seed| !seed.is_empty()),
+            level_type: Some(self.level_type.clone().unwrap_or_else(|| "minecraft:normal".to_string())),
+            generator_settings: Some(self.generator_settings.clone().unwrap_or_else(|| "{}".to_string())),
+            generate_structures: Some(self.generate_structures.unwrap_or(true)),
+        }
+    }
+}
+
+/// A world in the server directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct WorldInfo {
+    /// The name of the world directory, which `level-name` refers to.
+    pub name: String,
+    /// The world directory and the dimension directories split off from it, relative to the server directory.
+    pub directories: Vec<PathBuf>,
+    /// Whether the server loads this world, as `level-name` names it.
+    pub active: bool,
+    /// The combined size of the world directories.
+    pub size: u64,
+    /// The name of the world in `level.dat`, which the game shows in the world list.
+    pub level_name: Option<String>,
+    pub seed: Option<i64>,
+    /// The version of the game that last saved the world, like `1.21.4`.
+    pub version: Option<String>,
+    /// When the world was last played, in milliseconds since the Unix epoch.
+    pub last_played: Option<i64>,
+    /// The settings the world was created with, for worlds created by [`WorldManager::create_world`].
+    /// A random seed is left empty here, `seed` holds the one the game picked.
+    pub generation: Option<WorldGeneration>,
+}
+
+/// A world that was archived into a backup and removed from the server directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchivedWorld {
+    pub name: String,
+    /// The backup holding the world, which [`WorldManager::unarchive_world`] restores.
+    pub backup_id: String,
+    pub size: u64,
+    pub archived_at: SystemTime,
+}
+
+/// Finds, creates, copies and switches between the worlds of a server.
+///
+/// A world is a directory in the server directory with a `level.dat`, along with the
+/// `<name>_nether` and `<name>_the_end` directories Bukkit splits off. The server loads the world
+/// `level-name` of `server.properties` names, so switching worlds only takes effect when the
+/// server is started the next time.
+pub struct WorldManager<'a> {
+    server: &'a Server<u64>,
+    root: SandboxedPath,
+}
+
+impl<'a> WorldManager<'a> {
+    /// Opens the worlds of a server, for a user who may edit its files.
+    ///
+    /// # Errors
+    /// Returns an error if the acting user may not edit files, or the server directory does not exist.
+    pub fn new(server: &'a Server<u64>) -> Result<Self, Box<dyn Error>> {
+        authorize(server, Capability::EditFiles)?;
+        Ok(Self {
+            server,
+            root: server.sandbox("")?,
+        })
+    }
+
+    /// The name of the world the server loads.
+    pub fn active_world(&self) -> String {
+        level_name(self.server)
+    }
+
+    /// Lists the worlds in the server directory, the active one first and the others by name.
+    ///
+    /// The active world is listed even if it was not generated yet, with no directories.
+    ///
+    /// # Errors
+    /// Returns an error if the server directory cannot be read.
+    pub fn list_worlds(&self) -> Result<Vec<WorldInfo>, Box<dyn Error>> {
+        let mut names: Vec<String> = fs::read_dir(self.root.path())?
+            .flatten()
+            // Imports are extracted into hidden directories first
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter(|entry| entry.path().join("level.dat").is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        // Split off dimensions have a level.dat of their own, but belong to the world they are named after
+        let all = names.clone();
+        names.retain(|name| {
+            !DIMENSION_SUFFIXES.iter().any(|suffix| {
+                name.strip_suffix(suffix)
+                    .is_some_and(|base| all.iter().any(|other| other == base))
+            })
+        });
+
+        let active = self.active_world();
+        if !names.contains(&active) {
+            names.push(active.clone());
+        }
+        names.sort_by(|a, b| (*b == active).cmp(&(*a == active)).then_with(|| a.cmp(b)));
+        names.iter().map(|name| self.world_info(name, &active)).collect()
+    }
+
+    /// Describes a single world.
+    ///
+    /// # Errors
+    /// Returns an error if the name is not a valid world name, or no world has it.
+    pub fn get_world(&self, name: &str) -> Result<WorldInfo, Box<dyn Error>> {
+        let world = self.existing_world(name)?;
+        self.world_info(&world, &self.active_world())
+    }
+
+    fn world_info(&self, name: &str, active: &str) -> Result<WorldInfo, Box<dyn Error>> {
+        let directories = self.world_directories(name)?;
+        let size = directories
+            .iter()
+            .map(|directory| calculate_directory_size(self.root.path().join(directory)))
+            .sum();
+        let level = read_nbt_file(&self.root.path().join(name).join("level.dat"));
+        if let Err(e) = &level {
+            if !directories.is_empty() {
+                warn!("Failed to read the level.dat of world {:?}: {}", name, e);
+            }
+        }
+        let level = level.ok();
+        let data = level.as_ref().and_then(|level| level.root.get_compound("Data"));
+        Ok(WorldInfo {
+            name: name.to_string(),
+            directories,
+            active: name == active,
+            size,
+            level_name: data.and_then(|data| data.get_str("LevelName")).map(str::to_string),
+            // 1.16 moved the seed from RandomSeed into the world generation settings
+            seed: data.and_then(|data| {
+                data.get_compound("WorldGenSettings")
+                    .and_then(|settings| settings.get_i64("seed"))
+                    .or_else(|| data.get_i64("RandomSeed"))
+            }),
+            version: data
+                .and_then(|data| data.get_compound("Version")?.get_str("Name"))
+                .map(str::to_string),
+            last_played: data.and_then(|data| data.get_i64("LastPlayed")),
+            generation: read_generation(self.server.id, name)?,
+        })
+    }
+
+    /// The directories of a world that exist, the world itself first.
+    fn world_directories(&self, name: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let mut directories = Vec::new();
+        for suffix in [""].iter().chain(DIMENSION_SUFFIXES) {
+            let directory = PathBuf::from(format!("{}{}", name, suffix));
+            if self.root.join(&directory)?.path().is_dir() {
+                directories.push(directory);
+            }
+        }
+        Ok(directories)
+    }
+
+    fn existing_world(&self, name: &str) -> Result<String, Box<dyn Error>> {
+        validate_world_name(name)?;
+        if !self.root.join(name)?.path().join("level.dat").is_file() {
+            return Err(format!("Server {:?} has no world {:?}", self.server.name, name).into());
+        }
+        Ok(name.to_string())
+    }
+
+    /// Fails if a world of this name or one of its dimension directories exists.
+    fn ensure_unused(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        validate_world_name(name)?;
+        if let Some(taken) = self.world_directories(name)?.first() {
+            return Err(format!("{:?} already exists in the server directory", taken).into());
+        }
+        Ok(())
+    }
+
+    /// Makes the server load another world from its next start.
+    ///
+    /// # Errors
+    /// Returns an error if no world has the name, or `server.properties` cannot be written.
+    pub fn switch_world(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        let name = self.existing_world(name)?;
+        self.set_level_name(&name)?;
+        info!("Switched server {:?} to the world {:?}", self.server.name, name);
+        Ok(())
+    }
+
+    /// Makes the server generate a new world the next time it starts, and load it from then on.
+    ///
+    /// The settings are recorded with the world, so [`WorldInfo::generation`] still shows them once
+    /// `server.properties` was changed for another world.
+    ///
+    /// # Arguments
+    /// * `name` - The name of the world directory.
+    /// * `generation` - How the world is generated. Settings left out get the defaults of the game
+    ///   instead of those of the previous world, so a seed left out is a random one.
+    ///
+    /// # Errors
+    /// Returns an error if the name is invalid or taken, the generator settings are not JSON, or
+    /// `server.properties` cannot be written.
+    pub fn create_world(&self, name: &str, generation: &WorldGeneration) -> Result<(), Box<dyn Error>> {
+        self.ensure_unused(name)?;
+        generation.validate()?;
+        let generation = generation.with_defaults();
+        let mut properties = self.server.load_properties_file()?;
+        properties.set("level-name", name)?;
+        properties.set("level-seed", generation.seed.as_deref().unwrap_or_default())?;
+        write_generation(&mut properties, &generation)?;
+        self.server.save_properties_file(&properties)?;
+        record_generation(self.server.id, name, &generation)?;
+        info!("Server {:?} generates the new world {:?} on its next start", self.server.name, name);
+        Ok(())
+    }
+
+    fn set_level_name(&self, name: &str) -> Result<(), Box<dyn Error>> {
+        let mut properties = self.server.load_properties_file()?;
+        properties.set("level-name", name)?;
+        self.server.save_properties_file(&properties)
+    }
+
+    /// Reads how the server generates a world that does not exist yet, from `server.properties`.
+    ///
+    /// # Errors
+    /// Returns an error if `server.properties` cannot be read.
+    pub fn world_generation(&self) -> Result<WorldGeneration, Box<dyn Error>> {
+        let properties = self.server.load_properties_file()?;
+        let text = |key: &str| properties.get(key).map(str::to_string);
+        Ok(WorldGeneration {
+            seed: text("level-seed"),
+            level_type: text("level-type"),
+            generator_settings: text("generator-settings"),
+            generate_structures: properties.get("generate-structures").and_then(|value| value.trim().parse().ok()),
+        })
+    }
+
+    /// Changes how the server generates a world that does not exist yet. Settings left out are
+    /// unchanged, and worlds that were generated already keep their terrain.
+    ///
+    /// # Errors
+    /// Returns an error if the generator settings are not JSON, or `server.properties` cannot be written.
+    pub fn set_world_generation(&self, generation: &WorldGeneration) -> Result<(), Box<dyn Error>> {
+        generation.validate()?;
+        let mut properties = self.server.load_properties_file()?;
+        if let Some(seed) = &generation.seed {
+            properties.set("level-seed", seed.trim())?;
+        }
+        write_generation(&mut properties, generation)?;
+        self.server.save_properties_file(&properties)
+    }
+
+    /// Copies a world with its dimensions under a new name.
+    ///
+    /// The copy gets the new name in its `level.dat`, and leaves out the files tying a world to
+    /// one running copy, so both can be loaded side by side.
+    ///
+    /// # Errors
+    /// Returns an error if the source does not exist, the new name is taken, or the source is the
+    /// world of a running server, which would be copied while chunks are written to it.
+    pub fn duplicate_world(&self, name: &str, new_name: &str) -> Result<WorldInfo, Box<dyn Error>> {
+        let name = self.existing_world(name)?;
+        self.ensure_unused(new_name)?;
+        self.ensure_not_in_use(&name, "duplicated")?;
+
+        let copies = FileOps::new(FileOperationOptions::default());
+        for directory in self.world_directories(&name)? {
+            let suffix = directory.to_string_lossy().strip_prefix(name.as_str()).unwrap_or_default().to_string();
+            let copy = self.root.join(format!("{}{}", new_name, suffix))?;
+            copies.copy(&self.root.join(&directory)?, &copy, |_| {})?;
+            for file in INSTANCE_FILES {
+                let _ = fs::remove_file(copy.path().join(file));
+            }
+        }
+
+        rename_level(&self.root.join(new_name)?.path().join("level.dat"), new_name)?;
+        if let Some(generation) = read_generation(self.server.id, &name)? {
+            record_generation(self.server.id, new_name, &generation)?;
+        }
+        info!("Duplicated the world {:?} of server {:?} as {:?}", name, self.server.name, new_name);
+        self.get_world(new_name)
+    }
+
+    fn ensure_not_in_use(&self, name: &str, action: &str) -> Result<(), Box<dyn Error>> {
+        if name == self.active_world() && self.server.is_server_running() {
+            return Err(format!("The world {:?} is loaded by the running server and cannot be {}", name, action).into());
+        }
+        Ok(())
+    }
+
+    /// Installs a world from an uploaded archive, such as a zipped world folder or a `.mcworld` file.
+    ///
+    /// The world may be nested in folders inside the archive, and may come with the dimensions in
+    /// the world as singleplayer saves them, or split off as Bukkit does. They are rearranged into
+    /// the layout of this server. The world is not switched to.
+    ///
+    /// # Arguments
+    /// * `archive` - The uploaded archive.
+    /// * `name` - The name of the new world directory.
+    ///
+    /// # Errors
+    /// Returns an error if the name is taken, the archive holds no world or several, or the world
+    /// is a Bedrock Edition world or has no valid `level.dat`. Nothing is installed then.
+    pub fn import_world(&self, archive: &SandboxedPath, name: &str) -> Result<WorldInfo, Box<dyn Error>> {
+        self.ensure_unused(name)?;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let staging = self.root.join(format!(".world-import-{}", nanos))?;
+        let result = self.install_world(archive, &staging, name);
+        if staging.path().exists() {
+            if let Err(e) = fs::remove_dir_all(staging.path()) {
+                warn!("Failed to remove the extracted world {:?}: {}", staging.path(), e);
+            }
+        }
+        result?;
+        info!("Imported {} as the world {:?} of server {:?}", archive, name, self.server.name);
+        self.get_world(name)
+    }
+
+    fn install_world(
+        &self,
+        archive: &SandboxedPath,
+        staging: &SandboxedPath,
+        name: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        extract_archive(archive, staging, &ExtractionOptions::default(), |_| {})?;
+        let world = find_world_root(&staging.path())?;
+        validate_level(&world, archive)?;
+
+        let target = self.root.join(name)?.path();
+        // Dimensions are looked up before the world moves, those inside it move along with it
+        let dimensions: Vec<(&str, &str, PathBuf)> = DIMENSIONS
+            .iter()
+            .filter_map(|(directory, suffix)| {
+                let inside = world.join(directory);
+                let split = PathBuf::from(format!("{}{}", world.to_string_lossy(), suffix)).join(directory);
+                [inside, split]
+                    .into_iter()
+                    .find(|source| source.is_dir())
+                    .map(|source| (*directory, *suffix, source))
+            })
+            .collect();
+        if self.splits_dimensions() {
+            for (directory, suffix, source) in &dimensions {
+                move_path(source, &self.root.join(format!("{}{}", name, suffix))?.path().join(directory))?;
+            }
+            move_path(&world, &target)?;
+        } else {
+            move_path(&world, &target)?;
+            for (directory, _, source) in dimensions.iter().filter(|(_, _, source)| !source.starts_with(&world)) {
+                move_path(source, &target.join(directory))?;
+            }
+        }
+
+        for directory in self.world_directories(name)? {
+            for file in INSTANCE_FILES {
+                let _ = fs::remove_file(self.root.path().join(&directory).join(file));
+            }
+        }
+        rename_level(&target.join("level.dat"), name)
+    }
+
+    /// Whether the server keeps the nether and the end in directories of their own, as Bukkit does.
+    fn splits_dimensions(&self) -> bool {
+        let root = self.root.path();
+        root.join("bukkit.yml").is_file() || root.join(format!("{}_nether", self.active_world())).is_dir()
+    }
+
+    /// Estimates the uncompressed size and number of entries of an export of a world, so a client
+    /// can show them before the download starts.
+    ///
+    /// # Errors
+    /// Returns an error if the world does not exist or its directories cannot be read.
+    pub fn estimate_export(&self, name: &str, options: &WorldExportOptions) -> Result<ArchiveSummary, Box<dyn Error>> {
+        let sources = self.export_sources(name)?;
+        estimate_archive_excluding(&sources, &options.archive_options().exclude)
+    }
+
+    /// Writes a zip of a world into a writer, such as the body of a download.
+    ///
+    /// The entries are named after the world directories, like `world/level.dat`, so the zip can be
+    /// imported again with [`WorldManager::import_world`]. A running server has saving paused
+    /// while its active world is read, as it is for a backup.
+    ///
+    /// # Errors
+    /// Returns an error if the world does not exist, or reading it or writing the zip fails.
+    pub fn export_world(
+        &self,
+        name: &str,
+        writer: impl Write,
+        options: &WorldExportOptions,
+        on_progress: impl FnMut(&ArchiveProgress),
+    ) -> Result<ArchiveSummary, Box<dyn Error>> {
+        let sources = self.export_sources(name)?;
+        self.with_saving_paused(name, || write_archive(&sources, writer, &options.archive_options(), on_progress))
+    }
+
+    /// Saves a zip of a world like [`WorldManager::export_world`] into the exports directory of the
+    /// backups of the server, named after the world and the time of the export.
+    ///
+    /// Returns the path of the zip.
+    ///
+    /// # Errors
+    /// Returns an error if the world does not exist, or reading it or writing the zip fails.
+    pub fn export_world_to_backups(
+        &self,
+        name: &str,
+        options: &WorldExportOptions,
+        on_progress: impl FnMut(&ArchiveProgress),
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let sources = self.export_sources(name)?;
+        let directory = backup_directory(self.server.id).join(EXPORT_DIRECTORY);
+        fs::create_dir_all(&directory)?;
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let destination = SandboxedPath::root(&directory)?.join(format!("{}-{}.zip", name, seconds))?;
+        let summary = self.with_saving_paused(name, || {
+            create_archive(&sources, &destination, &options.archive_options(), on_progress)
+        })?;
+        info!(
+            "Exported the world {:?} of server {:?} to {:?}, {} entries",
+            name, self.server.name, destination.path(), summary.entries
+        );
+        Ok(destination.path())
+    }
+
+    fn export_sources(&self, name: &str) -> Result<Vec<SandboxedPath>, Box<dyn Error>> {
+        let name = self.existing_world(name)?;
+        self.world_directories(&name)?
+            .iter()
+            .map(|directory| self.root.join(directory))
+            .collect()
+    }
+
+    /// Runs `read` with saving paused if it reads the active world of a running server.
+    fn with_saving_paused<T>(
+        &self,
+        name: &str,
+        read: impl FnOnce() -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let pause = if name == self.active_world() {
+            pause_saving(self.server, &[BackupTarget::World])
+        } else {
+            SavePause::NotNeeded
+        };
+        let result = read();
+        resume_saving(self.server, pause);
+        result
+    }
+
+    /// Moves a world the server does not load into a backup, freeing its space in the server directory.
+    ///
+    /// # Errors
+    /// Returns an error if the world is the active one or the backup fails. The world is only
+    /// removed once the backup succeeded.
+    pub fn archive_world(&self, name: &str) -> Result<ArchivedWorld, Box<dyn Error>> {
+        let name = self.existing_world(name)?;
+        if name == self.active_world() {
+            return Err(format!("The world {:?} is the active world, switch to another one first", name).into());
+        }
+        let directories = self.world_directories(&name)?;
+        let options = BackupOptions {
+            targets: directories.iter().map(|path| BackupTarget::Path { path: path.clone() }).collect(),
+            ..BackupOptions::default()
+        };
+        let manifest = create_backup(self.server, &options, BackupTrigger::WorldArchive)?;
+        for directory in &directories {
+            fs::remove_dir_all(self.root.join(directory)?.path())?;
+        }
+        info!("Archived the world {:?} of server {:?} as backup {}", name, self.server.name, manifest.id);
+        archived_world(&manifest).ok_or_else(|| "The archive of the world has no directories".into())
+    }
+
+    /// Lists the archived worlds of the server, most recently archived first.
+    pub fn list_archived_worlds(&self) -> Result<Vec<ArchivedWorld>, Box<dyn Error>> {
+        Ok(list_backups(self.server.id)?
+            .iter()
+            .filter(|backup| backup.trigger == BackupTrigger::WorldArchive)
+            .filter_map(archived_world)
+            .collect())
+    }
+
+    /// Moves an archived world back into the server directory and deletes its archive.
+    ///
+    /// Like every restore, this needs the server to be stopped.
+    ///
+    /// # Errors
+    /// Returns an error if the backup is not an archived world, a world of its name exists again,
+    /// or restoring fails.
+    pub fn unarchive_world(&self, backup_id: &str) -> Result<WorldInfo, Box<dyn Error>> {
+        let archived = self
+            .list_archived_worlds()?
+            .into_iter()
+            .find(|world| world.backup_id == backup_id)
+            .ok_or_else(|| format!("Backup {} is not an archived world", backup_id))?;
+        self.ensure_unused(&archived.name)?;
+        let options = RestoreOptions {
+            paths: Vec::new(),
+            clean: false,
+            safety_backup: false,
+            key: None,
+        };
+        restore_backup(self.server, backup_id, &options)?;
+        delete_backup(self.server.id, backup_id)?;
+        info!("Restored the archived world {:?} of server {:?}", archived.name, self.server.name);
+        self.get_world(&archived.name)
+    }
+}
+
+/// Sets the generator properties that are set in `generation`, the seed is left to the caller.
+fn write_generation(properties: &mut ServerPropertiesFile, generation: &WorldGeneration) -> Result<(), Box<dyn Error>> {
+    if let Some(level_type) = &generation.level_type {
+        properties.set("level-type", level_type.trim())?;
+    }
+    if let Some(settings) = &generation.generator_settings {
+        properties.set("generator-settings", settings.trim())?;
+    }
+    if let Some(structures) = generation.generate_structures {
+        properties.set("generate-structures", &structures.to_string())?;
+    }
+    Ok(())
+}
+
+/// Creates the table recording the settings the worlds created by the manager were generated with.
+///
+/// # Errors
+/// Returns an error if the database connection fails or the table cannot be created.
+pub fn initialize_world_generation_database() -> Result<(), Box<dyn Error>> {
+    let query = r#"
+        CREATE TABLE IF NOT EXISTS `server_world_generation` (
+            server_id INTEGER NOT NULL,                                 -- The server the world belongs to
+            world TEXT NOT NULL,                                        -- The name of the world directory
+            generation TEXT NOT NULL,                                   -- The generator settings, as JSON
+            PRIMARY KEY (server_id, world)
+        );
+"#;
+    let conn = create_appdb_connection()?;
+    conn.execute(query)?;
+    Ok(())
+}
+
+fn read_generation(server_id: u64, world: &str) -> Result<Option<WorldGeneration>, Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement =
+        conn.prepare(r#"SELECT generation FROM server_world_generation WHERE server_id = ? AND world = ?"#)?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, world))?;
+    if let State::Row = statement.next()? {
+        return Ok(Some(serde_json::from_str(&statement.read::<String, _>("generation")?)?));
+    }
+    Ok(None)
+}
+
+fn record_generation(server_id: u64, world: &str, generation: &WorldGeneration) -> Result<(), Box<dyn Error>> {
+    let conn = create_appdb_connection()?;
+    let mut statement = conn.prepare(
+        r#"INSERT INTO server_world_generation (server_id, world, generation) VALUES (?, ?, ?)
+        ON CONFLICT(server_id, world) DO UPDATE SET generation = excluded.generation"#,
+    )?;
+    statement.bind((1, server_id as i64))?;
+    statement.bind((2, world))?;
+    statement.bind((3, serde_json::to_string(generation)?.as_str()))?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Sets the name the game shows for a world in its `level.dat`.
+fn rename_level(level_file: &Path, name: &str) -> Result<(), Box<dyn Error>> {
+    let mut level = read_nbt_file(level_file)?;
+    if let Some(data) = level.root.get_compound_mut("Data") {
+        data.insert("LevelName", NbtTag::String(name.to_string()));
+        write_nbt_file(level_file, &level)?;
+    }
+    Ok(())
+}
+
+/// Finds the directory of the world in an extracted archive, leaving out the split off dimensions,
+/// which have a `level.dat` of their own.
+fn find_world_root(extracted: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let candidates: Vec<PathBuf> = walkdir::WalkDir::new(extracted)
+        .max_depth(MAX_IMPORT_DEPTH + 1)
+        .follow_links(false)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file() && entry.file_name() == "level.dat")
+        .filter_map(|entry| entry.path().parent().map(Path::to_path_buf))
+        .collect();
+    let worlds: Vec<&PathBuf> = candidates
+        .iter()
+        .filter(|candidate| {
+            let name = candidate.to_string_lossy();
+            !DIMENSIONS.iter().any(|(_, suffix)| {
+                name.strip_suffix(suffix)
+                    .is_some_and(|base| candidates.iter().any(|other| other.as_os_str() == base))
+            })
+        })
+        .collect();
+    match worlds.as_slice() {
+        [world] => Ok(world.to_path_buf()),
+        [] => Err("The archive contains no world, it has no level.dat".into()),
+        _ => {
+            let names: Vec<String> = worlds
+                .iter()
+                .map(|world| world.strip_prefix(extracted).unwrap_or(world).to_string_lossy().to_string())
+                .collect();
+            Err(format!("The archive contains several worlds, import them one at a time: {:?}", names).into())
+        }
+    }
+}
+
+/// Checks that a world is a Java Edition world with a readable `level.dat`.
+fn validate_level(world: &Path, archive: &SandboxedPath) -> Result<(), Box<dyn Error>> {
+    // Bedrock keeps its chunks in a LevelDB database instead of region files
+    if world.join("db").is_dir() {
+        return Err(format!(
+            "{} is a Bedrock Edition world, which Java Edition servers cannot load. Convert it to a Java \
+            Edition world first, for example with Chunker",
+            archive
+        )
+        .into());
+    }
+    let level = read_nbt_file(&world.join("level.dat")).map_err(|e| format!("The level.dat is invalid: {}", e))?;
+    if level.root.get_compound("Data").is_none() {
+        return Err("The level.dat has no world data".into());
+    }
+    Ok(())
+}
+
+/// The world an archive holds, named by its first directory, which is the world itself.
+fn archived_world(manifest: &BackupManifest) -> Option<ArchivedWorld> {
+    let name = manifest.targets.iter().find_map(|target| match target {
+        BackupTarget::Path { path } => Some(path.to_string_lossy().to_string()),
+        _ => None,
+    })?;
+    Some(ArchivedWorld {
+        name,
+        backup_id: manifest.id.clone(),
+        size: manifest.uncompressed_size,
+        archived_at: manifest.created_at,
+    })
+}
+
+/// Checks that a name is a plain directory name the other files of the server do not use.
+fn validate_world_name(name: &str) -> Result<(), Box<dyn Error>> {
+    let mut components = Path::new(name).components();
+    let is_plain_name = matches!(
+        (components.next(), components.next()),
+        (Some(std::path::Component::Normal(_)), None)
+    );
+    if !is_plain_name || name.starts_with('.') || name != name.trim() {
+        return Err(format!("{:?} is not a valid world name", name).into());
+    }
+    if name == TRASH_DIRECTORY || ["plugins", "mods", "config", "logs", "libraries"].contains(&name) {
+        return Err(format!("{:?} is used by the server and cannot be a world", name).into());
+    }
+    Ok(())
+}
+
+pub trait ServerWorlds {
+    /// Opens the worlds of the server to list, create, switch, duplicate or archive them.
+    fn worlds(&self) -> Result<WorldManager<'_>, Box<dyn Error>>;
+}
+
+impl ServerWorlds for Server<u64> {
+    fn worlds(&self) -> Result<WorldManager<'_>, Box<dyn Error>> {
+        WorldManager::new(self)
+    }
+}